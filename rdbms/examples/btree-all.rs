@@ -2,14 +2,14 @@ use anyhow::Result;
 
 use rdbms::btree::{BTree, SearchMode};
 use rdbms::buffer::{BufferPool, BufferPoolManager};
-use rdbms::disk::{DiskManager, PageId};
+use rdbms::disk::DiskManager;
 
 fn main() -> Result<()> {
     let disk = DiskManager::open("simple.rly")?;
     let pool = BufferPool::new(10);
     let mut bufmgr = BufferPoolManager::new(disk, pool);
 
-    let btree = BTree::new(PageId(0));
+    let btree = BTree::new(bufmgr.meta_page_id());
     let mut iter = btree.search(&mut bufmgr, SearchMode::Start)?;
 
     while let Some((key, value)) = iter.next(&mut bufmgr)? {
@@ -5,11 +5,12 @@ use rdbms::buffer::{BufferPool, BufferPoolManager};
 use rdbms::disk::DiskManager;
 
 fn main() -> Result<()> {
-    let disk = DiskManager::open("test.btr")?;
+    let disk = DiskManager::create("test.btr")?;
     let pool = BufferPool::new(10);
     let mut bufmgr = BufferPoolManager::new(disk, pool);
 
     let btree = BTree::create(&mut bufmgr)?;
+    bufmgr.set_meta_page_id(btree.meta_page_id)?;
 
     btree.insert(&mut bufmgr, b"Kanagawa", b"Yokohama")?;
     btree.insert(&mut bufmgr, b"Osaka", b"Osaka")?;
@@ -8,16 +8,20 @@ use rdbms::disk::DiskManager;
 const NUM_PAIRS: u32 = 1000000;
 
 fn main() -> Result<()> {
-    let disk = DiskManager::open("large.btr")?;
+    let disk = DiskManager::create("large.btr")?;
     let pool = BufferPool::new(100);
     let mut bufmgr = BufferPoolManager::new(disk, pool);
 
-    let btree = BTree::create(&mut bufmgr)?;
-    for i in 1u32..=NUM_PAIRS {
-        let pkey = i.to_be_bytes();
-        let md5 = Md5::digest(&pkey);
-        btree.insert(&mut bufmgr, &md5[..], &pkey[..])?;
-    }
+    // キーの順に並べてから、挿入を繰り返さずにまとめて作る
+    let mut pairs: Vec<_> = (1u32..=NUM_PAIRS)
+        .map(|i| {
+            let pkey = i.to_be_bytes();
+            (Md5::digest(&pkey).to_vec(), pkey.to_vec())
+        })
+        .collect();
+    pairs.sort_unstable();
+    let btree = BTree::bulk_load(&mut bufmgr, pairs)?;
+    bufmgr.set_meta_page_id(btree.meta_page_id)?;
     bufmgr.flush()?;
 
     Ok(())
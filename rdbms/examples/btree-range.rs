@@ -2,15 +2,15 @@ use anyhow::Result;
 
 use rdbms::btree::{BTree, SearchMode};
 use rdbms::buffer::{BufferPool, BufferPoolManager};
-use rdbms::disk::{DiskManager, PageId};
+use rdbms::disk::DiskManager;
 
 fn main() -> Result<()> {
     let disk = DiskManager::open("test.btr")?;
     let pool = BufferPool::new(10);
     let mut bufmgr = BufferPoolManager::new(disk, pool);
 
-    let btree = BTree::new(PageId(0));
-    let mut iter = btree.search(&mut bufmgr, SearchMode::Key(b"Gifu".to_vec()))?;
+    let btree = BTree::new(bufmgr.meta_page_id());
+    let mut iter = btree.search(&mut bufmgr, SearchMode::KeyOrNext(b"Gifu".to_vec()))?;
     while let Some((key, value)) = iter.next(&mut bufmgr)? {
         println!("{:02x?} = {:02x?}", key, value);
     }
@@ -2,22 +2,21 @@ use anyhow::Result;
 
 use rdbms::btree::{BTree, SearchMode};
 use rdbms::buffer::{BufferPool, BufferPoolManager};
-use rdbms::disk::{DiskManager, PageId};
-use rdbms::tuple;
+use rdbms::disk::DiskManager;
+use rdbms::tuple::{self, ColumnType};
 
 fn main() -> Result<()> {
     let disk = DiskManager::open("simple.rly")?;
     let pool = BufferPool::new(10);
     let mut bufmgr = BufferPoolManager::new(disk, pool);
 
-    let btree = BTree::new(PageId(0));
+    let btree = BTree::new(bufmgr.meta_page_id());
     let mut iter = btree.search(&mut bufmgr, SearchMode::Start)?;
 
     while let Some((key, value)) = iter.next(&mut bufmgr)? {
-        let mut record = vec![];
-        tuple::decode(&key, &mut record);
-        tuple::decode(&value, &mut record);
-        println!("{:?}", tuple::Pretty(&record));
+        let mut record = tuple::decode_values(&key, &[ColumnType::Text])?;
+        record.extend(tuple::decode_values(&value, &[ColumnType::Text, ColumnType::Text])?);
+        println!("{:?}", record);
     }
     Ok(())
 }
@@ -3,24 +3,33 @@ use anyhow::Result;
 use rdbms::buffer::{BufferPool, BufferPoolManager};
 use rdbms::disk::{DiskManager, PageId};
 use rdbms::table::SimpleTable;
+use rdbms::tuple::Value;
 
 fn main() -> Result<()> {
-    let disk = DiskManager::open("simple.rly")?;
+    let disk = DiskManager::create("simple.rly")?;
     let pool = BufferPool::new(10);
     let mut bufmgr = BufferPoolManager::new(disk, pool);
 
     let mut table = SimpleTable {
-        meta_page_id: PageId(0),
+        meta_page_id: PageId::INVALID_PAGE_ID,
         num_key_elems: 1,
+        schema: None,
     };
     table.create(&mut bufmgr)?;
+    bufmgr.set_meta_page_id(table.meta_page_id)?;
     dbg!(&table);
 
-    table.insert(&mut bufmgr, &[b"z", b"Alice", b"Smith"])?;
-    table.insert(&mut bufmgr, &[b"x", b"Bob", b"Johnson"])?;
-    table.insert(&mut bufmgr, &[b"y", b"Charlie", b"Williams"])?;
-    table.insert(&mut bufmgr, &[b"w", b"Dave", b"Miller"])?;
-    table.insert(&mut bufmgr, &[b"v", b"Eve", b"Brown"])?;
+    let records = [
+        ["z", "Alice", "Smith"],
+        ["x", "Bob", "Johnson"],
+        ["y", "Charlie", "Williams"],
+        ["w", "Dave", "Miller"],
+        ["v", "Eve", "Brown"],
+    ];
+    for record in records.iter() {
+        let record: Vec<_> = record.iter().map(|elem| Value::Text(elem.to_string())).collect();
+        table.insert_values(&mut bufmgr, &record)?;
+    }
 
     bufmgr.flush()?;
     Ok(())
@@ -2,24 +2,23 @@ use anyhow::Result;
 
 use rdbms::btree::{BTree, SearchMode};
 use rdbms::buffer::{BufferPool, BufferPoolManager};
-use rdbms::disk::{DiskManager, PageId};
-use rdbms::tuple;
+use rdbms::disk::DiskManager;
+use rdbms::tuple::{self, ColumnType, Value};
 
 fn main() -> Result<()> {
     let disk = DiskManager::open("simple.rly")?;
     let pool = BufferPool::new(10);
     let mut bufmgr = BufferPoolManager::new(disk, pool);
 
-    let btree = BTree::new(PageId(0));
+    let btree = BTree::new(bufmgr.meta_page_id());
     let mut search_key = vec![];
-    tuple::encode([b"w"].iter(), &mut search_key);
-    let mut iter = btree.search(&mut bufmgr, SearchMode::Key(search_key))?;
+    tuple::encode_values(&[Value::Text("w".to_string())], &mut search_key);
+    let mut iter = btree.search(&mut bufmgr, SearchMode::KeyOrNext(search_key))?;
 
     while let Some((key, value)) = iter.next(&mut bufmgr)? {
-        let mut record = vec![];
-        tuple::decode(&key, &mut record);
-        tuple::decode(&value, &mut record);
-        println!("{:?}", tuple::Pretty(&record));
+        let mut record = tuple::decode_values(&key, &[ColumnType::Text])?;
+        record.extend(tuple::decode_values(&value, &[ColumnType::Text, ColumnType::Text])?);
+        println!("{:?}", record);
     }
     Ok(())
 }
\ No newline at end of file
@@ -2,25 +2,26 @@ use anyhow::Result;
 
 use rdbms::btree::{BTree, SearchMode};
 use rdbms::buffer::{BufferPool, BufferPoolManager};
-use rdbms::disk::{DiskManager, PageId};
-use rdbms::tuple;
+use rdbms::disk::DiskManager;
+use rdbms::tuple::{self, ColumnType, Value};
 
 fn main() -> Result<()> {
     let disk = DiskManager::open("simple.rly")?;
     let pool = BufferPool::new(10);
     let mut bufmgr = BufferPoolManager::new(disk, pool);
 
-    let btree = BTree::new(PageId(0));
+    let btree = BTree::new(bufmgr.meta_page_id());
 
     // プライマリキー以外での検索では、フルスキャンを行う
     let mut iter = btree.search(&mut bufmgr, SearchMode::Start)?;
 
+    // 3列目(値の2つ目)だけを読み、条件に合うレコードだけをすべて復号する
+    let last_name = Value::Text("Smith".to_string());
     while let Some((key, value)) = iter.next(&mut bufmgr)? {
-        let mut record = vec![];
-        tuple::decode(&key, &mut record);
-        tuple::decode(&value, &mut record);
-        if record[2] == b"Smith" {
-            println!("{:?}", tuple::Pretty(&record));
+        if tuple::decode_nth_value(&value, 1)?.as_ref() == Some(&last_name) {
+            let mut record = tuple::decode_values(&key, &[ColumnType::Text])?;
+            record.extend(tuple::decode_values(&value, &[ColumnType::Text, ColumnType::Text])?);
+            println!("{:?}", record);
         }
     }
     Ok(())
@@ -1,19 +1,57 @@
-use std::cell::{Ref, RefMut};
 use std::convert::identity;
-use std::rc::Rc;
+use std::io;
+use std::mem::size_of;
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
 
 use bincode::Options;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use zerocopy::{AsBytes, ByteSlice};
+use zerocopy::{AsBytes, ByteSlice, ByteSliceMut};
 
-use crate::buffer::{self, Buffer, BufferPoolManager};
-use crate::disk::PageId;
+use crate::buffer::{
+    self, AccessStrategy, BufferPoolManager, PinnedBuffer, Priority, WriteGuard,
+};
+use crate::disk::{PageId, TablespaceId};
+use crate::memcmpable;
+use comparator::KeyComparator;
+use metrics::SharedMetrics;
+use overflow::StoredValue;
 
+pub use batch::BatchResult;
+pub use bulk_load::BulkLoadOptions;
+pub use comparator::{register_comparator, CompareFn, KeyComparatorId};
+pub use dump::{hex_key, tuple_key};
+pub use merge::ConflictPolicy;
+pub use meta::BTreeMeta;
+pub use metrics::OpMetrics;
+pub use sample::SampleStats;
+pub use stats::BTreeStats;
+pub use sync::{SyncBTree, SyncIter};
+pub use tombstone::{RawEntries, RawEntry};
+pub use vacuum::VacuumReport;
+pub use verify::{Violation, VerifyError};
+
+mod batch;
 mod branch;
+mod bulk_load;
+mod comparator;
+mod destroy;
+mod dump;
 mod leaf;
+mod merge;
+mod metrics;
 mod meta;
 mod node;
+mod overflow;
+mod sample;
+mod stats;
+mod sync;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod tombstone;
+mod vacuum;
+mod verify;
 
 #[derive(Serialize, Deserialize)]
 pub struct Pair<'a> {
@@ -26,264 +64,2137 @@ impl<'a> Pair<'a> {
         bincode::options().serialize(self).unwrap()
     }
 
+    // 長さがkey_lenとvalue_lenのペアを符号化した大きさ。長さを表す可変長の整数も含む
+    fn encoded_len(key_len: usize, value_len: usize) -> usize {
+        let prefix_len =
+            |len: usize| bincode::options().serialized_size(&(len as u64)).unwrap() as usize;
+        prefix_len(key_len) + key_len + prefix_len(value_len) + value_len
+    }
+
     fn from_bytes(bytes: &'a [u8]) -> Self {
         bincode::options().deserialize(bytes).unwrap()
     }
+
+    // 壊れているかもしれないページから読むときに使う
+    fn try_from_bytes(bytes: &'a [u8]) -> Option<Self> {
+        bincode::options().deserialize(bytes).ok()
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("duplicate key")]
     DuplicateKey,
+    #[error("{0} is not supported on a btree with non-unique keys")]
+    UnsupportedOnMulti(&'static str),
+    #[error("keys for bulk load must be sorted and unique: {key:02x?} follows {prev_key:02x?}")]
+    UnsortedKeys { prev_key: Vec<u8>, key: Vec<u8> },
+    // maxは収まらなかったページに置けるペアの大きさ。キーと値の長さを表す分も含む
+    #[error("{key_len} byte key and {value_len} byte value do not fit in a page (max {max} bytes)")]
+    TooLargeEntry {
+        key_len: usize,
+        value_len: usize,
+        max: usize,
+    },
+    #[error("btree {0:?} has been destroyed")]
+    Destroyed(PageId),
+    // 新しい版で作ったメタページは、記録の仕方がわからないので読まない
+    #[error("btree {meta_page_id:?} has unsupported meta page version {version}")]
+    UnsupportedMetaVersion { meta_page_id: PageId, version: u64 },
+    #[error("{0} is not supported on a btree with tombstones")]
+    UnsupportedWithTombstones(&'static str),
+    #[error("fill factor must be between 50 and 100 percent: {0}")]
+    InvalidFillFactor(u8),
+    #[error("bulk load fill factor must be greater than 0 and at most 1: {0}")]
+    InvalidLoadFactor(f64),
+    // 開き直す前に、作ったときと同じ番号で比べ方を登録しておかなければならない
+    #[error("key comparator {0:?} is not registered")]
+    UnknownComparator(KeyComparatorId),
+    #[error("key comparator {0:?} is already registered")]
+    ComparatorAlreadyRegistered(KeyComparatorId),
+    #[error("{0} is not supported on a btree with a custom key comparator")]
+    UnsupportedWithComparator(&'static str),
+    // mergeする2つの木で、キーの重複を許すかどうかが違う
+    #[error("key mode mismatch: left is {left:?}, right is {right:?}")]
+    KeyModeMismatch { left: KeyMode, right: KeyMode },
+    // リーフに置いた値の先頭のタグや長さが読めない。ページが壊れている
+    #[error("stored value of {len} bytes is corrupted")]
+    CorruptedValue { len: usize },
     #[error(transparent)]
     Buffer(#[from] buffer::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
 }
 
 #[derive(Debug, Clone)]
 pub enum SearchMode {
     Start,
+    // 最後のペアの後ろ。search_descで後ろから走査するときに使う
+    End,
+    // そのキー以上の最初のペア。そのようなペアがなければ、search_descは何も返さない
+    KeyOrNext(Vec<u8>),
+    // そのキー以下の最後のペア。そのようなペアがなければ、searchは何も返さない
+    KeyOrPrev(Vec<u8>),
+    // 以前の名前。searchではKeyOrNext、search_descではKeyOrPrevとして扱う
+    #[deprecated(note = "use SearchMode::KeyOrNext or SearchMode::KeyOrPrev")]
     Key(Vec<u8>),
 }
 
+// 木をたどるときは、どのキーのモードもそのキー以上の最初のペアを探す
+#[allow(deprecated)]
 impl SearchMode {
-    fn child_page_id(&self, branch: &branch::Branch<impl ByteSlice>) -> PageId {
+    fn child_page_id(&self, branch: &branch::Branch<impl ByteSlice>, cmp: KeyComparator) -> PageId {
         match self {
             SearchMode::Start => branch.child_at(0),
-            SearchMode::Key(key) => branch.search_child(key),
+            SearchMode::End => branch.child_at(branch.num_pairs()),
+            SearchMode::KeyOrNext(key) | SearchMode::KeyOrPrev(key) | SearchMode::Key(key) => {
+                branch.search_child(key, cmp)
+            }
         }
     }
 
-    fn tuple_slot_id(&self, leaf: &leaf::Leaf<impl ByteSlice>) -> Result<usize, usize> {
+    fn tuple_slot_id(
+        &self,
+        leaf: &leaf::Leaf<impl ByteSlice>,
+        cmp: KeyComparator,
+    ) -> Result<usize, usize> {
         match self {
             SearchMode::Start => Err(0),
-            SearchMode::Key(key) => leaf.search_slot_id(key),
+            SearchMode::End => Err(leaf.num_pairs()),
+            SearchMode::KeyOrNext(key) | SearchMode::KeyOrPrev(key) | SearchMode::Key(key) => {
+                leaf.search_slot_id(key, cmp)
+            }
         }
     }
 }
 
 pub struct BTree {
     pub meta_page_id: PageId,
+    // このハンドルと、そこから作ったIterで数える
+    metrics: Arc<SharedMetrics>,
+    // 最後にメタページから読んだ根と形式
+    root: Mutex<Option<CachedRoot>>,
+}
+
+// メタページのgenerationが同じ間は、メタページを借りずにこの根からたどる
+#[derive(Debug, Clone, Copy)]
+struct CachedRoot {
+    generation: u64,
+    root_page_id: PageId,
+    format: Format,
+}
+
+// キーの重複を許すかどうか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyMode {
+    Unique,
+    // 同じキーに複数の値を持てる。同じキーと値の組は1つだけ
+    Multi,
+}
+
+// 木を作るときの設定
+#[derive(Debug, Clone, Copy)]
+pub struct BTreeOptions {
+    pub tablespace_id: TablespaceId,
+    pub key_mode: KeyMode,
+    // ノードを分割したときに左のノードへ詰める百分率。50から100まで
+    // 大きくすると、キーの順に挿入する木の使用率が上がる。右端への追加はいつも詰めて分割する
+    pub leaf_fill: u8,
+    pub branch_fill: u8,
+    // キーの比べ方。重複を許す木では、符号化したキーを比べるのでMEMCMPだけを使える
+    pub comparator: KeyComparatorId,
+}
+
+impl Default for BTreeOptions {
+    fn default() -> Self {
+        Self {
+            tablespace_id: TablespaceId::DEFAULT,
+            key_mode: KeyMode::Unique,
+            leaf_fill: meta::DEFAULT_FILL,
+            branch_fill: meta::DEFAULT_FILL,
+            comparator: KeyComparatorId::MEMCMP,
+        }
+    }
+}
+
+// メタページから読んだ、木のページの形式
+#[derive(Debug, Clone, Copy)]
+struct Format {
+    key_mode: KeyMode,
+    node_version: u64,
+    page_size: usize,
+    // deleteが墓標を残す
+    tombstones: bool,
+    leaf_fill: u8,
+    branch_fill: u8,
+    cmp: KeyComparator,
+}
+
+impl Format {
+    // メタページに記録した比べ方が登録されていなければ、Error::UnknownComparatorを返す
+    fn from_meta(meta: &meta::Meta<impl ByteSlice>, page_size: usize) -> Result<Self, Error> {
+        let key_mode = if meta.is_multi() {
+            KeyMode::Multi
+        } else {
+            KeyMode::Unique
+        };
+        Ok(Self {
+            key_mode,
+            node_version: meta.header.node_version,
+            page_size,
+            tombstones: meta.uses_tombstones(),
+            leaf_fill: meta.leaf_fill(),
+            branch_fill: meta.branch_fill(),
+            cmp: KeyComparator::lookup(meta.comparator())?,
+        })
+    }
+
+    fn uses_overflow(self) -> bool {
+        self.node_version >= 1
+    }
+
+    // これより大きな値はオーバーフローページに置く
+    fn overflow_threshold(self) -> usize {
+        self.page_size / 4
+    }
+
+    // リーフに置く形にした値の長さ
+    fn stored_len(self, value_len: usize) -> usize {
+        if !self.uses_overflow() {
+            value_len
+        } else if value_len <= self.overflow_threshold() {
+            StoredValue::Inline(&[]).to_bytes().len() + value_len
+        } else {
+            let page_id = PageId::INVALID_PAGE_ID;
+            StoredValue::Overflow { page_id, len: value_len }.to_bytes().len()
+        }
+    }
+
+    // 木のキーと値がリーフに収まるか、どのページも書き換える前に確かめる
+    // キーは区切りのキーとして枝にも置くので、枝のペアとしても収まらなければならない
+    // entryは呼び出し側から見たキーと値の長さで、エラーに入れる
+    fn check_fits(self, key: &[u8], value: &[u8], entry: (usize, usize)) -> Result<(), Error> {
+        let body_len = self.page_size - size_of::<node::Header>();
+        let limits = [
+            (
+                Pair::encoded_len(key.len(), self.stored_len(value.len())),
+                leaf::max_pair_size(body_len),
+            ),
+            (
+                Pair::encoded_len(key.len(), size_of::<PageId>()),
+                branch::max_pair_size(body_len),
+            ),
+        ];
+        match limits.iter().find(|(len, max)| len > max) {
+            Some(&(_, max)) => Err(Error::TooLargeEntry {
+                key_len: entry.0,
+                value_len: entry.1,
+                max,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // リーフのキーを、呼び出し側から見たキーにする
+    fn user_key(self, key: &[u8]) -> Vec<u8> {
+        match self.key_mode {
+            KeyMode::Unique => key.to_vec(),
+            KeyMode::Multi => split_multi_key(key).0,
+        }
+    }
+
+    // 木のキーが、呼び出し側から見てkeyのペアのものか
+    // 重複を許す木では、符号化したキーは終わりに印があるので、先頭が一致するかを見ればよい
+    fn has_user_key(self, tree_key: &[u8], key: &[u8]) -> bool {
+        match self.key_mode {
+            KeyMode::Unique => self.cmp.eq(tree_key, key),
+            KeyMode::Multi => tree_key.starts_with(&multi_key(key, &[])),
+        }
+    }
+
+    // リーフのペアを、呼び出し側から見たキーと値にする
+    // オーバーフローページに置いた値は、読み出してつなげる
+    fn load_pair(
+        self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(Vec<u8>, Vec<u8>), Error> {
+        match self.key_mode {
+            KeyMode::Unique => Ok((key.to_vec(), self.load_value(bufmgr, value)?)),
+            KeyMode::Multi => Ok(split_multi_key(key)),
+        }
+    }
+
+    fn load_value(self, bufmgr: &mut BufferPoolManager, value: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![];
+        self.load_value_into(bufmgr, value, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    // リーフに置いた値を読み出して、bytesの後ろにつなげる
+    fn load_value_into(
+        self,
+        bufmgr: &mut BufferPoolManager,
+        value: &[u8],
+        bytes: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        if !self.uses_overflow() {
+            bytes.extend_from_slice(value);
+            return Ok(());
+        }
+        let (mut next_page_id, len) = match StoredValue::from_bytes(value)? {
+            StoredValue::Inline(value) => {
+                bytes.extend_from_slice(value);
+                return Ok(());
+            }
+            StoredValue::Overflow { page_id, len } => (Some(page_id), len),
+        };
+        bytes.reserve(len);
+        let end = bytes.len() + len;
+        // 連なりが短すぎても長すぎても(輪になっていても)、壊れているとみなす
+        while let Some(page_id) = next_page_id {
+            if bytes.len() == end {
+                return Err(Error::CorruptedValue { len });
+            }
+            let buffer = bufmgr.fetch_page_read(page_id)?;
+            let page = overflow::Overflow::new(buffer.data());
+            let data = page.data();
+            let n = data.len().min(end - bytes.len());
+            bytes.extend_from_slice(&data[..n]);
+            next_page_id = page.next_page_id();
+        }
+        if bytes.len() < end {
+            return Err(Error::CorruptedValue { len });
+        }
+        Ok(())
+    }
+
+    // リーフに置いた値がオーバーフローページを使っていれば、それを解放する
+    // 解放したページの数を返す
+    fn free_value(self, bufmgr: &mut BufferPoolManager, value: &[u8]) -> Result<u64, Error> {
+        if !self.uses_overflow() {
+            return Ok(0);
+        }
+        let mut next_page_id = match StoredValue::from_bytes(value)? {
+            StoredValue::Inline(_) => return Ok(0),
+            StoredValue::Overflow { page_id, .. } => Some(page_id),
+        };
+        let mut num_pages = 0;
+        while let Some(page_id) = next_page_id {
+            next_page_id = {
+                let buffer = bufmgr.fetch_page_read(page_id)?;
+                let page = overflow::Overflow::new(buffer.data());
+                page.next_page_id()
+            };
+            bufmgr.free_page(page_id)?;
+            num_pages += 1;
+        }
+        Ok(num_pages)
+    }
+}
+
+// 重複を許す木では、memcmpableで符号化したキーに値をつなげたものを木のキーにし、ペアの値は空にする
+// 符号化したキーは区切りがわかるので、キーの順に並び、同じキーの中では値の順に並ぶ
+fn multi_key(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(memcmpable::encoded_size(key.len()) + value.len());
+    memcmpable::encode(key, &mut bytes);
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+fn split_multi_key(bytes: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut rest = bytes;
+    let mut key = vec![];
+    memcmpable::decode(&mut rest, &mut key);
+    (key, rest.to_vec())
+}
+
+// prefixで始まるどのキーよりも大きい、最小のキー
+// 0xFFだけからなるprefixでは、そのようなキーはない
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xFF {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+// leftより大きくright以下のキーのうち、最も短いもの
+// 枝のノードには、隣り合うリーフを分けられる最短の区切りのキーを置き、1つのノードに多くの子を置けるようにする
+fn separator(left: &[u8], right: &[u8]) -> Vec<u8> {
+    debug_assert!(left < right);
+    let common_len = left.iter().zip(right).take_while(|(l, r)| l == r).count();
+    right[..common_len + 1].to_vec()
+}
+
+// insert_returningで、キーがすでにあったときの振る舞い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnDuplicate {
+    // insertと同じくDuplicateKeyを返す
+    Error,
+    // upsertと同じく値を置き換える
+    Replace,
+    // 何も書き込まない
+    Keep,
+}
+
+// insert_returningで起きたこと
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertOutcome {
+    Inserted,
+    // 置き換える前の値
+    Replaced(Vec<u8>),
+    Kept,
+}
+
+// 書き込むキーがすでにあるかどうかで、どう振る舞うか
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WriteMode {
+    // ないキーだけを加える
+    Insert,
+    // ないキーだけを加え、あるキーはそのままにする
+    InsertOrKeep,
+    // あるキーの値だけを置き換える
+    Update,
+    // あれば置き換え、なければ加える
+    Upsert,
+    // あるキーの値を墓標の番号に置き換え、墓標の印を付ける
+    Tombstone,
+}
+
+// ノードにペアを書き込んだ結果
+#[derive(Debug, PartialEq)]
+enum Insertion {
+    Done,
+    // 値を置き換えるキーがなかった
+    NotFound,
+    // 加えるキーがすでにあったので、そのままにした
+    Kept,
+    // ノードを分割したので、新しいノードへのキーとページIDを親に加える
+    Split(Vec<u8>, PageId),
+}
+
+// リーフの分け方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LeafSplit {
+    // ペアを半分ずつに分ける
+    Half,
+    // 右端のリーフの末尾に加えるので、新しいペアだけを右に置く
+    Append,
+}
+
+// 書き込みで起きたこと
+#[derive(Debug, Default)]
+struct WriteOutcome {
+    // 値を置き換えたら、リーフに置いてあった前の値
+    old_value: Option<Vec<u8>>,
+    leaf_split: Option<LeafSplit>,
+}
+
+// ノードからキーを削除した結果
+#[derive(Debug, PartialEq)]
+enum Deletion {
+    NotFound,
+    Deleted,
+    // 削除してノードの使用量が最低限を下回ったので、親が兄弟とまとめるか兄弟から借りる
+    Underflow,
 }
 
 impl BTree {
     pub fn create(bufmgr: &mut BufferPoolManager) -> Result<Self, Error> {
-        let meta_buffer = bufmgr.create_page()?;
-        let mut meta = meta::Meta::new(meta_buffer.page.borrow_mut() as RefMut<[_]>);
-        let root_buffer = bufmgr.create_page()?;
-        let mut root = node::Node::new(root_buffer.page.borrow_mut() as RefMut<[_]>);
+        Self::create_in(bufmgr, TablespaceId::DEFAULT)
+    }
+
+    // 表領域を指定して作る。分割で増えるページも同じ表領域に置く
+    pub fn create_in(
+        bufmgr: &mut BufferPoolManager,
+        tablespace_id: TablespaceId,
+    ) -> Result<Self, Error> {
+        let options = BTreeOptions {
+            tablespace_id,
+            ..BTreeOptions::default()
+        };
+        Self::create_with(bufmgr, options)
+    }
+
+    // キーの重複を許すかどうかと分割の詰め方はメタページに記録し、作ったあとは変えられない
+    pub fn create_with(
+        bufmgr: &mut BufferPoolManager,
+        options: BTreeOptions,
+    ) -> Result<Self, Error> {
+        let BTreeOptions {
+            tablespace_id,
+            key_mode,
+            leaf_fill,
+            branch_fill,
+            comparator,
+        } = options;
+        for fill in [leaf_fill, branch_fill] {
+            if !(50..=100).contains(&fill) {
+                return Err(Error::InvalidFillFactor(fill));
+            }
+        }
+        KeyComparator::lookup(comparator)?;
+        if key_mode == KeyMode::Multi && comparator != KeyComparatorId::MEMCMP {
+            return Err(Error::UnsupportedOnMulti("custom key comparator"));
+        }
+        let meta_buffer = bufmgr.create_page_in(tablespace_id)?;
+        let mut meta = meta::Meta::new(meta_buffer.data_mut());
+        let root_buffer = bufmgr.create_page_in(tablespace_id)?;
+        let mut root = node::Node::new(root_buffer.data_mut());
         root.initialize_as_leaf();
         let mut leaf = leaf::Leaf::new(root.body);
         leaf.initialize();
         meta.header.root_page_id = root_buffer.page_id;
+        meta.set_multi(key_mode == KeyMode::Multi);
+        meta.header.node_version = meta::NODE_VERSION;
+        meta.header.meta_version = meta::META_VERSION;
+        meta.header.height = 1;
+        meta.header.leaf_fill = leaf_fill;
+        meta.header.branch_fill = branch_fill;
+        meta.header.comparator_id = comparator.0;
+        meta.set_num_entries(0);
+        // メタページは毎回参照するので追い出されにくくする
+        bufmgr.set_priority(meta_buffer.page_id, Priority::Sticky);
         Ok(Self::new(meta_buffer.page_id))
     }
 
+    // cmpの順にキーを並べる木を作る。cmpは組み込みのものか、register_comparatorで登録したもの
+    pub fn create_with_comparator(
+        bufmgr: &mut BufferPoolManager,
+        cmp: KeyComparatorId,
+    ) -> Result<Self, Error> {
+        let options = BTreeOptions {
+            comparator: cmp,
+            ..BTreeOptions::default()
+        };
+        Self::create_with(bufmgr, options)
+    }
+
     pub fn new(meta_page_id: PageId) -> Self {
-        Self { meta_page_id }
+        Self::with_metrics(meta_page_id, Arc::default())
     }
 
-    fn fetch_root_page(&self, bufmgr: &mut BufferPoolManager) -> Result<Rc<Buffer>, Error> {
-        let root_page_id = {
-            let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
-            let meta = meta::Meta::new(meta_buffer.page.borrow() as Ref<[_]>);
-            meta.header.root_page_id
+    // Iterの中から木をたどり直すときに、元のハンドルで数えるようにする
+    fn with_metrics(meta_page_id: PageId, metrics: Arc<SharedMetrics>) -> Self {
+        Self {
+            meta_page_id,
+            metrics,
+            root: Mutex::new(None),
+        }
+    }
+
+    // 新しいページを、メタページと同じ表領域に作る
+    fn create_page(&self, bufmgr: &mut BufferPoolManager) -> Result<PinnedBuffer, Error> {
+        Ok(bufmgr.create_page_in(self.meta_page_id.tablespace_id())?)
+    }
+
+    pub fn key_mode(&self, bufmgr: &mut BufferPoolManager) -> Result<KeyMode, Error> {
+        Ok(self.format(bufmgr)?.key_mode)
+    }
+
+    fn format(&self, bufmgr: &mut BufferPoolManager) -> Result<Format, Error> {
+        Ok(self.cached_root(bufmgr)?.format)
+    }
+
+    // 根を分割したり木を壊したりすればgenerationが変わるので、そのときだけメタページを読み直す
+    // メタページがバッファプールに載っていなければ、読み込んで確かめる
+    fn cached_root(&self, bufmgr: &mut BufferPoolManager) -> Result<CachedRoot, Error> {
+        let cached = *self.root.lock().expect("root cache poisoned");
+        if let Some(cached) = cached {
+            let generation = bufmgr
+                .peek_page(self.meta_page_id)
+                .map(|data| meta::Meta::new(&data[..]).header.generation);
+            if generation == Some(cached.generation) {
+                return Ok(cached);
+            }
+        } else {
+            // 設定は追い出されても残るので、このハンドルで初めて読むときだけでよい
+            bufmgr.set_priority(self.meta_page_id, Priority::Sticky);
+        }
+        let meta_buffer = bufmgr.fetch_page_read(self.meta_page_id)?;
+        let page_size = meta_buffer.data().len();
+        let meta = meta::Meta::new(meta_buffer.data());
+        self.check_meta(&meta)?;
+        let cached = CachedRoot {
+            generation: meta.header.generation,
+            root_page_id: meta.header.root_page_id,
+            format: Format::from_meta(&meta, page_size)?,
         };
-        Ok(bufmgr.fetch_page(root_page_id)?)
+        *self.root.lock().expect("root cache poisoned") = Some(cached);
+        Ok(cached)
     }
 
-    fn search_internal(
+    // 壊した木や、知らない形式のメタページを、そのまま木として読まないようにする
+    fn check_meta(&self, meta: &meta::Meta<impl ByteSlice>) -> Result<(), Error> {
+        if meta.is_destroyed() {
+            return Err(Error::Destroyed(self.meta_page_id));
+        }
+        if meta.header.meta_version > meta::META_VERSION {
+            return Err(Error::UnsupportedMetaVersion {
+                meta_page_id: self.meta_page_id,
+                version: meta.header.meta_version,
+            });
+        }
+        Ok(())
+    }
+
+    fn fetch_root_page(
         &self,
         bufmgr: &mut BufferPoolManager,
-        node_buffer: Rc<Buffer>,
-        search_mode: SearchMode,
-    ) -> Result<Iter, Error> {
-        let node = node::Node::new(node_buffer.page.borrow() as Ref<[_]>);
-        match node::Body::new(node.header.node_type, node.body.as_bytes()) {
-            node::Body::Leaf(leaf) => {
-                let slot_id = search_mode.tuple_slot_id(&leaf).unwrap_or_else(identity);
-                drop(node);
-                Ok(Iter {
-                    buffer: node_buffer,
-                    slot_id,
-                })
+    ) -> Result<(PinnedBuffer, Format), Error> {
+        let cached = self.cached_root(bufmgr)?;
+        Ok((bufmgr.fetch_page(cached.root_page_id)?, cached.format))
+    }
+
+    // 大きな値はオーバーフローページの連なりに書き込み、リーフに置く形にする
+    // 途中でページを作れなければ、それまでに作ったページを解放してから失敗する
+    fn store_value(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        format: Format,
+        value: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        if !format.uses_overflow() {
+            return Ok(value.to_vec());
+        }
+        if value.len() <= format.overflow_threshold() {
+            return Ok(StoredValue::Inline(value).to_bytes());
+        }
+        let mut page_ids = vec![];
+        match self.write_overflow_pages(bufmgr, format, value, &mut page_ids) {
+            Ok(()) => Ok(StoredValue::Overflow {
+                page_id: page_ids[0],
+                len: value.len(),
             }
-            node::Body::Branch(branch) => {
-                let child_page_id = search_mode.child_page_id(&branch);
-                drop(node);
-                drop(node_buffer);
-                let child_node_page = bufmgr.fetch_page(child_page_id)?;
-                self.search_internal(bufmgr, child_node_page, search_mode)
+            .to_bytes()),
+            Err(err) => {
+                // 解放にも失敗したら、元のエラーを返す
+                for page_id in page_ids {
+                    let _ = bufmgr.free_page(page_id);
+                }
+                Err(err)
             }
         }
     }
 
-    pub fn search(
+    // valueを先頭から連なりに書き込む。作ったページはpage_idsに順に足す
+    fn write_overflow_pages(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        format: Format,
+        value: &[u8],
+        page_ids: &mut Vec<PageId>,
+    ) -> Result<(), Error> {
+        let mut chunks = value.chunks(overflow::capacity(format.page_size));
+        let mut buffer = self.create_page(bufmgr)?;
+        page_ids.push(buffer.page_id);
+        let mut chunk = chunks.next().unwrap();
+        loop {
+            let next_buffer = match chunks.next() {
+                Some(next_chunk) => {
+                    let next = self.create_page(bufmgr)?;
+                    page_ids.push(next.page_id);
+                    Some((next, next_chunk))
+                }
+                None => None,
+            };
+            {
+                let mut page = overflow::Overflow::new(buffer.data_mut());
+                page.data_mut()[..chunk.len()].copy_from_slice(chunk);
+                page.set_next_page_id(next_buffer.as_ref().map(|(next, _)| next.page_id));
+            }
+            match next_buffer {
+                Some((next_buffer, next_chunk)) => {
+                    buffer = next_buffer;
+                    chunk = next_chunk;
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // 木のキーに一致するペアの、リーフに置いた値を返す
+    fn stored_value(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let (root_page, format) = self.fetch_root_page(bufmgr)?;
+        let search_mode = SearchMode::KeyOrNext(key.to_vec());
+        let iter = self.search_internal(bufmgr, root_page, search_mode, format, false)?;
+        let cmp = format.cmp;
+        Ok(iter.with_pair(|found, value| cmp.eq(found, key).then(|| value.to_vec())).flatten())
+    }
+
+    fn search_internal(
         &self,
         bufmgr: &mut BufferPoolManager,
+        mut node_buffer: PinnedBuffer,
         search_mode: SearchMode,
+        format: Format,
+        raw: bool,
     ) -> Result<Iter, Error> {
-        let root_page = self.fetch_root_page(bufmgr)?;
-        self.search_internal(bufmgr, root_page, search_mode)
+        let mut branch_pages = 0;
+        loop {
+            let child_page_id = {
+                let node = node::Node::new(node_buffer.data());
+                match node::Body::new(node.header.node_type, node.body.as_bytes()) {
+                    node::Body::Leaf(_) => break,
+                    node::Body::Branch(branch) => search_mode.child_page_id(&branch, format.cmp),
+                }
+            };
+            branch_pages += 1;
+            drop(node_buffer);
+            node_buffer = bufmgr.fetch_page(child_page_id)?;
+        }
+        let slot_id = {
+            let node = node::Node::new(node_buffer.data());
+            let leaf = leaf::Leaf::new(node.body);
+            search_mode.tuple_slot_id(&leaf, format.cmp).unwrap_or_else(identity)
+        };
+        // 全件スキャンではほかのページを追い出さないようにする
+        let strategy = match search_mode {
+            SearchMode::Start | SearchMode::End => AccessStrategy::BulkRead,
+            _ => AccessStrategy::Normal,
+        };
+        #[allow(deprecated)]
+        let origin = match search_mode {
+            SearchMode::Start => Some(vec![]),
+            SearchMode::End => None,
+            SearchMode::KeyOrNext(key) | SearchMode::KeyOrPrev(key) | SearchMode::Key(key) => {
+                Some(key)
+            }
+        };
+        let mut iter = Iter {
+            buffer: node_buffer,
+            slot_id,
+            strategy,
+            format,
+            meta_page_id: self.meta_page_id,
+            version: 0,
+            origin,
+            last: None,
+            last_key: None,
+            raw,
+            metrics: OpMetrics::default(),
+            shared_metrics: self.metrics.clone(),
+        };
+        iter.add_metrics(OpMetrics {
+            branch_pages,
+            leaf_pages: 1,
+            ..OpMetrics::default()
+        });
+        // 探したキーがリーフのどのキーより大きければ、次のリーフの先頭から始める
+        iter.seek_pair(bufmgr)?;
+        Ok(iter)
     }
 
-    fn insert_internal(
+    pub fn search(
         &self,
         bufmgr: &mut BufferPoolManager,
-        buffer: Rc<Buffer>,
-        key: &[u8],
-        value: &[u8],
-    ) -> Result<Option<(Vec<u8>, PageId)>, Error> {
-        let node = node::Node::new(buffer.page.borrow_mut() as RefMut<[_]>);
-        match node::Body::new(node.header.node_type, node.body) {
-            node::Body::Leaf(mut leaf) => {
-                let slot_id = match leaf.search_slot_id(key) {
-                    Ok(_) => return Err(Error::DuplicateKey),
-                    Err(slot_id) => slot_id,
-                };
-                if leaf.insert(slot_id, key, value).is_some() {
-                    buffer.is_dirty.set(true);
-                    Ok(None)
-                } else {
-                    let prev_leaf_page_id = leaf.prev_page_id();
-                    let prev_leaf_buffer = prev_leaf_page_id
-                        .map(|next_leaf_page_id| bufmgr.fetch_page(next_leaf_page_id))
-                        .transpose()?;
-
-                    let new_leaf_buffer = bufmgr.create_page()?;
-
-                    if let Some(prev_leaf_buffer) = prev_leaf_buffer {
-                        let node =
-                            node::Node::new(prev_leaf_buffer.page.borrow_mut() as RefMut<[_]>);
-                        let mut prev_leaf = leaf::Leaf::new(node.body);
-                        prev_leaf.set_next_page_id(Some(new_leaf_buffer.page_id));
-                        prev_leaf_buffer.is_dirty.set(true);
-                    }
-                    leaf.set_prev_page_id(Some(new_leaf_buffer.page_id));
-
-                    let mut new_leaf_node =
-                        node::Node::new(new_leaf_buffer.page.borrow_mut() as RefMut<[_]>);
-                    new_leaf_node.initialize_as_leaf();
-                    let mut new_leaf = leaf::Leaf::new(new_leaf_node.body);
-                    new_leaf.initialize();
-                    let overflow_key = leaf.split_insert(&mut new_leaf, key, value);
-                    new_leaf.set_next_page_id(Some(buffer.page_id));
-                    new_leaf.set_prev_page_id(prev_leaf_page_id);
-                    buffer.is_dirty.set(true);
-                    Ok(Some((overflow_key, new_leaf_buffer.page_id)))
-                }
-            }
-            node::Body::Branch(mut branch) => {
-                let child_idx = branch.search_child_idx(key);
-                let child_page_id = branch.child_at(child_idx);
-                let child_node_buffer = bufmgr.fetch_page(child_page_id)?;
-                if let Some((overflow_key_from_child, overflow_child_page_id)) =
-                    self.insert_internal(bufmgr, child_node_buffer, key, value)?
-                {
-                    if branch
-                        .insert(child_idx, &overflow_key_from_child, overflow_child_page_id)
-                        .is_some()
-                    {
-                        buffer.is_dirty.set(true);
-                        Ok(None)
-                    } else {
-                        let new_branch_buffer = bufmgr.create_page()?;
-                        let mut new_branch_node =
-                            node::Node::new(new_branch_buffer.page.borrow_mut() as RefMut<[_]>);
-                        new_branch_node.initialize_as_branch();
-                        let mut new_branch = branch::Branch::new(new_branch_node.body);
-                        let overflow_key = branch.split_insert(
-                            &mut new_branch,
-                            &overflow_key_from_child,
-                            overflow_child_page_id,
-                        );
-                        buffer.is_dirty.set(true);
-                        new_branch_buffer.is_dirty.set(true);
-                        Ok(Some((overflow_key, new_branch_buffer.page_id)))
-                    }
-                } else {
-                    Ok(None)
+        search_mode: SearchMode,
+    ) -> Result<Iter, Error> {
+        let search_mode = match search_mode {
+            #[allow(deprecated)]
+            SearchMode::Key(key) => SearchMode::KeyOrNext(key),
+            // 後ろからたどって見つけたペアから、前向きにたどり直す
+            SearchMode::KeyOrPrev(key) => {
+                let mut iter = self.search_desc(bufmgr, SearchMode::KeyOrPrev(key))?;
+                if !iter.seek_pair(bufmgr)? {
+                    return self.search(bufmgr, SearchMode::End);
                 }
+                let mut iter = Iter {
+                    buffer: iter.buffer,
+                    slot_id: iter.slot_end - 1,
+                    strategy: iter.strategy,
+                    format: iter.format,
+                    meta_page_id: self.meta_page_id,
+                    version: 0,
+                    origin: None,
+                    last: None,
+                    last_key: None,
+                    raw: false,
+                    metrics: OpMetrics::default(),
+                    shared_metrics: self.metrics.clone(),
+                };
+                iter.origin = iter.with_pair(|key, _| key.to_vec());
+                iter.seek_pair(bufmgr)?;
+                return Ok(iter);
+            }
+            search_mode => search_mode,
+        };
+        let (root_page, format) = self.fetch_root_page(bufmgr)?;
+        // 重複を許す木では、そのキーを持つ最初のペアを探す
+        let search_mode = match (format.key_mode, search_mode) {
+            (KeyMode::Multi, SearchMode::KeyOrNext(key)) => {
+                SearchMode::KeyOrNext(multi_key(&key, &[]))
             }
+            (_, search_mode) => search_mode,
+        };
+        self.search_internal(bufmgr, root_page, search_mode, format, false)
+    }
+
+    // positionで得た位置の次のペアから走査を続ける
+    // その間に挿入や削除でリーフが分割されたり、最後に返したペアが削除されたりしても、
+    // そのキーより後ろのペアを返す
+    pub fn resume(&self, bufmgr: &mut BufferPoolManager, pos: CursorPos) -> Result<Iter, Error> {
+        self.resume_internal(bufmgr, pos, false)
+    }
+
+    fn resume_internal(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        pos: CursorPos,
+        raw: bool,
+    ) -> Result<Iter, Error> {
+        let key = match pos.key {
+            Some(key) => key,
+            None => return self.search(bufmgr, SearchMode::End),
+        };
+        let (root_page, format) = self.fetch_root_page(bufmgr)?;
+        let search_mode = SearchMode::KeyOrNext(key.clone());
+        let mut iter = self.search_internal(bufmgr, root_page, search_mode, format, raw)?;
+        if pos.inclusive {
+            return Ok(iter);
         }
+        // 最後に返したペアが残っていれば、その次から返す
+        if iter.with_pair(|found, _| format.cmp.eq(found, &key)) == Some(true) {
+            iter.slot_id += 1;
+            iter.seek_pair(bufmgr)?;
+        }
+        iter.last_key = Some(key);
+        Ok(iter)
     }
 
-    pub fn insert(
+    // すべてのペアを順に返すIterator。bufmgrを借りたままにするので、
+    // 走査の途中でほかの読み書きをするなら、searchで得たIterにその都度bufmgrを渡す
+    pub fn iter<'a>(&self, bufmgr: &'a mut BufferPoolManager) -> Entries<'a> {
+        let state = match self.search(bufmgr, SearchMode::Start) {
+            Ok(iter) => EntriesState::Cursor(iter),
+            Err(err) => EntriesState::Failed(err),
+        };
+        Entries { bufmgr, state }
+    }
+
+    // キーが一致するペアの値を返す
+    // 読んだバッファは返す前にピン留めを外す
+    pub fn get(
         &self,
         bufmgr: &mut BufferPoolManager,
         key: &[u8],
-        value: &[u8],
-    ) -> Result<(), Error> {
-        let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
-        let mut meta = meta::Meta::new(meta_buffer.page.borrow_mut() as RefMut<[_]>);
-        let root_page_id = meta.header.root_page_id;
-        let root_buffer = bufmgr.fetch_page(root_page_id)?;
-        if let Some((key, child_page_id)) = self.insert_internal(bufmgr, root_buffer, key, value)? {
-            let new_root_buffer = bufmgr.create_page()?;
-            let mut node = node::Node::new(new_root_buffer.page.borrow_mut() as RefMut<[_]>);
-            node.initialize_as_branch();
-            let mut branch = branch::Branch::new(node.body);
-            branch.initialize(&key, child_page_id, root_page_id);
-            meta.header.root_page_id = new_root_buffer.page_id;
-            meta_buffer.is_dirty.set(true);
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let mut value = vec![];
+        Ok(self.get_into(bufmgr, key, &mut value)?.then_some(value))
+    }
+
+    // 値をvalueに書き込み、見つかればtrueを返す。valueを使い回せば、読むたびに確保しなくて済む
+    // valueの前の中身は捨てるので、見つからなければvalueは空になる
+    pub fn get_into(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+        value: &mut Vec<u8>,
+    ) -> Result<bool, Error> {
+        value.clear();
+        // 重複を許す木では、値の最も小さいものを書き込む
+        let mut iter = self.search(bufmgr, SearchMode::KeyOrNext(key.to_vec()))?;
+        let format = iter.format;
+        let loaded = iter.with_user_key(key, |tree_key, stored| match format.key_mode {
+            KeyMode::Unique => format.load_value_into(bufmgr, stored, value),
+            KeyMode::Multi => {
+                value.extend_from_slice(&split_multi_key(tree_key).1);
+                Ok(())
+            }
+        });
+        let found = loaded.transpose()?.is_some();
+        if found {
+            iter.add_metrics(OpMetrics {
+                entries: 1,
+                bytes_copied: value.len() as u64,
+                ..OpMetrics::default()
+            });
         }
-        Ok(())
+        Ok(found)
     }
-}
 
-pub struct Iter {
-    buffer: Rc<Buffer>,
-    slot_id: usize,
-}
+    // キーが一致するペアの値を、値の順にすべて返す。重複を許さない木では多くとも1つ返す
+    // 値は読みながら返し、キーより大きいペアに着いたら次のリーフは読まない
+    pub fn get_all<'a>(
+        &self,
+        bufmgr: &'a mut BufferPoolManager,
+        key: &[u8],
+    ) -> Result<ValuesIter<'a>, Error> {
+        let iter = self.scan_range(bufmgr, Bound::Included(key), Bound::Included(key))?;
+        Ok(ValuesIter { bufmgr, iter })
+    }
 
-impl Iter {
-    fn get(&self) -> Option<(Vec<u8>, Vec<u8>)> {
-        let leaf_node = node::Node::new(self.buffer.page.borrow() as Ref<[_]>);
-        let leaf = leaf::Leaf::new(leaf_node.body);
-        if self.slot_id < leaf.num_pairs() {
-            let pair = leaf.pair_at(self.slot_id);
-            Some((pair.key.to_vec(), pair.value.to_vec()))
-        } else {
-            None
-        }
+    // キーを比べるだけで、値はページから写さず、オーバーフローページも読まない
+    pub fn contains_key(&self, bufmgr: &mut BufferPoolManager, key: &[u8]) -> Result<bool, Error> {
+        let iter = self.search(bufmgr, SearchMode::KeyOrNext(key.to_vec()))?;
+        Ok(iter.with_user_key(key, |_, _| ()).is_some())
     }
 
+    // キーが最も小さいペア。左端の子をたどるだけなので、全体を走査しない
     #[allow(clippy::type_complexity)]
-    pub fn next(
-        &mut self,
+    pub fn first(
+        &self,
         bufmgr: &mut BufferPoolManager,
     ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
-        let value = self.get();
-        self.slot_id += 1;
-        let next_page_id = {
-            let leaf_node = node::Node::new(self.buffer.page.borrow() as Ref<[_]>);
-            let leaf = leaf::Leaf::new(leaf_node.body);
-            if self.slot_id < leaf.num_pairs() {
-                return Ok(value);
-            }
-            leaf.next_page_id()
+        self.search(bufmgr, SearchMode::Start)?.next(bufmgr)
+    }
+
+    // キーが最も大きいペア。右端の子をたどる
+    #[allow(clippy::type_complexity)]
+    pub fn last(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        self.search_desc(bufmgr, SearchMode::End)?.next(bufmgr)
+    }
+
+    // ペアの数を返す。重複を許す木では、同じキーのペアもそれぞれ数える
+    // 数えていない以前の木では、すべてのペアをたどって数える
+    pub fn len(&self, bufmgr: &mut BufferPoolManager) -> Result<u64, Error> {
+        let num_entries = {
+            let meta_buffer = bufmgr.fetch_page_read(self.meta_page_id)?;
+            let meta = meta::Meta::new(meta_buffer.data());
+            self.check_meta(&meta)?;
+            meta.num_entries()
         };
-        if let Some(next_page_id) = next_page_id {
-            self.buffer = bufmgr.fetch_page(next_page_id)?;
-            self.slot_id = 0;
+        if let Some(num_entries) = num_entries {
+            return Ok(num_entries);
         }
-        Ok(value)
+        let mut iter = self.search(bufmgr, SearchMode::Start)?;
+        let mut num_entries = 0;
+        while iter.next(bufmgr)?.is_some() {
+            num_entries += 1;
+        }
+        Ok(num_entries)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use tempfile::tempfile;
+    pub fn is_empty(&self, bufmgr: &mut BufferPoolManager) -> Result<bool, Error> {
+        Ok(self.len(bufmgr)? == 0)
+    }
 
-    use crate::{buffer::BufferPool, disk::DiskManager};
+    // search_modeの位置から、キーの大きい方から順にたどる
+    // KeyOrPrevならそのキー以下のペアから、KeyOrNextならそのキー以上の最初のペアから、
+    // Endなら最後のペアから始める
+    pub fn search_desc(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        search_mode: SearchMode,
+    ) -> Result<RevIter, Error> {
+        let (root_page, format) = self.fetch_root_page(bufmgr)?;
+        #[allow(deprecated)]
+        let search_mode = match search_mode {
+            SearchMode::Key(key) => SearchMode::KeyOrPrev(key),
+            search_mode => search_mode,
+        };
+        // 重複を許す木では、KeyOrNextならそのキーを持つ最初のペアを、
+        // KeyOrPrevならそのキーを持つ最後のペアの後ろを探す
+        let search_mode = match (format.key_mode, search_mode) {
+            (KeyMode::Multi, SearchMode::KeyOrNext(key)) => {
+                SearchMode::KeyOrNext(multi_key(&key, &[]))
+            }
+            (KeyMode::Multi, SearchMode::KeyOrPrev(key)) => {
+                SearchMode::KeyOrPrev(prefix_end(&multi_key(&key, &[])).unwrap())
+            }
+            (_, search_mode) => search_mode,
+        };
+        let iter = self.search_internal(bufmgr, root_page, search_mode.clone(), format, false)?;
+        // 探したキー以上の最初のペアを指すので、探したキーでなければその手前から始める
+        let current = iter.key();
+        // そのキー以上のペアがなければ、左端のリーフの先頭を指して何も返さない
+        if let (SearchMode::KeyOrNext(_), None) = (&search_mode, &current) {
+            drop(iter);
+            let (root_page, format) = self.fetch_root_page(bufmgr)?;
+            let iter = self.search_internal(bufmgr, root_page, SearchMode::Start, format, false)?;
+            return Ok(RevIter {
+                buffer: iter.buffer,
+                slot_end: 0,
+                strategy: iter.strategy,
+                format,
+            });
+        }
+        #[allow(deprecated)]
+        let include_current = match &search_mode {
+            SearchMode::Start | SearchMode::KeyOrNext(_) => current.is_some(),
+            SearchMode::End => false,
+            SearchMode::KeyOrPrev(key) | SearchMode::Key(key) => {
+                format.key_mode == KeyMode::Unique
+                    && current.is_some_and(|current| format.cmp.eq(&current, key))
+            }
+        };
+        Ok(RevIter {
+            buffer: iter.buffer,
+            slot_end: iter.slot_id + include_current as usize,
+            strategy: iter.strategy,
+            format,
+        })
+    }
 
-    use super::*;
-    #[test]
-    fn test() {
-        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
-        let pool = BufferPool::new(10);
-        let mut bufmgr = BufferPoolManager::new(disk, pool);
-        let btree = BTree::create(&mut bufmgr).unwrap();
-        btree
+    // prefixで始まるキーを順にたどる
+    // タプルのキーでは、先頭の要素をtuple::encodeしたものを渡すと、その要素で始まるタプルを得られる
+    pub fn scan_prefix(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        prefix: &[u8],
+    ) -> Result<RangeIter, Error> {
+        // バイト列の順でなければ、prefixで始まるキーが続いて並ぶとは限らない
+        if !self.format(bufmgr)?.cmp.is_memcmp() {
+            return Err(Error::UnsupportedWithComparator("scan_prefix"));
+        }
+        let end = prefix_end(prefix);
+        let end = match &end {
+            Some(end) => Bound::Excluded(&end[..]),
+            None => Bound::Unbounded,
+        };
+        self.scan_range(bufmgr, Bound::Included(prefix), end)
+    }
+
+    // startからendまでのキーを順にたどる
+    // endを越えたキーに着いたら、RangeIterはそこでNoneを返す
+    pub fn scan_range(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Result<RangeIter, Error> {
+        let search_mode = match start {
+            Bound::Included(key) | Bound::Excluded(key) => SearchMode::KeyOrNext(key.to_vec()),
+            Bound::Unbounded => SearchMode::Start,
+        };
+        let mut iter = self.search(bufmgr, search_mode)?;
+        // 重複を許す木では、同じキーのペアが続く
+        if let Bound::Excluded(start) = start {
+            let cmp = iter.format.cmp;
+            while iter.key().is_some_and(|key| cmp.eq(&key, start)) {
+                iter.next(bufmgr)?;
+            }
+        }
+        let end = match end {
+            Bound::Included(key) => Bound::Included(key.to_vec()),
+            Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Ok(RangeIter {
+            iter,
+            end,
+            finished: false,
+        })
+    }
+
+    // 値を置き換えたり、リーフを分割したりしたら、outcomeに記録する
+    #[allow(clippy::too_many_arguments)]
+    fn insert_internal(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        format: Format,
+        buffer: WriteGuard,
+        key: &[u8],
+        value: &[u8],
+        mode: WriteMode,
+        outcome: &mut WriteOutcome,
+    ) -> Result<Insertion, Error> {
+        // 書き換えるとわかるまではdirtyにしないよう、まずは読み込み用に借りる
+        let child = {
+            let node = node::Node::new(buffer.data());
+            let is_leaf = node.header.node_type == node::NODE_TYPE_LEAF;
+            self.add_metrics(OpMetrics {
+                branch_pages: (!is_leaf) as u64,
+                leaf_pages: is_leaf as u64,
+                ..OpMetrics::default()
+            });
+            match node::Body::new(node.header.node_type, node.body) {
+                node::Body::Leaf(leaf) => {
+                    // 墓標はないキーとして扱う
+                    let found = leaf.search_slot_id(key, format.cmp);
+                    let live = found.is_ok_and(|slot_id| !leaf.is_tombstone(slot_id));
+                    match (live, mode) {
+                        (true, WriteMode::Insert) => return Err(Error::DuplicateKey),
+                        (true, WriteMode::InsertOrKeep) => return Ok(Insertion::Kept),
+                        (false, WriteMode::Update | WriteMode::Tombstone) => {
+                            return Ok(Insertion::NotFound)
+                        }
+                        _ => {}
+                    }
+                    None
+                }
+                node::Body::Branch(branch) => {
+                    let child_idx = branch.search_child_idx(key, format.cmp);
+                    Some((child_idx, branch.child_at(child_idx)))
+                }
+            }
+        };
+        let (child_idx, child_page_id) = match child {
+            Some(child) => child,
+            None => {
+                return self.insert_into_leaf(bufmgr, format, buffer, key, value, mode, outcome)
+            }
+        };
+        let child_node_buffer = bufmgr.fetch_page_write(child_page_id)?;
+        let insertion =
+            self.insert_internal(bufmgr, format, child_node_buffer, key, value, mode, outcome)?;
+        let (overflow_key_from_child, overflow_child_page_id) = match insertion {
+            Insertion::Split(key, page_id) => (key, page_id),
+            insertion => return Ok(insertion),
+        };
+        let node = node::Node::new(buffer.data_mut());
+        let mut branch = branch::Branch::new(node.body);
+        if branch
+            .insert(child_idx, &overflow_key_from_child, overflow_child_page_id)
+            .is_some()
+        {
+            Ok(Insertion::Done)
+        } else {
+            let new_branch_buffer = self.create_page(bufmgr)?;
+            let mut new_branch_node = node::Node::new(new_branch_buffer.data_mut());
+            new_branch_node.initialize_as_branch();
+            let mut new_branch = branch::Branch::new(new_branch_node.body);
+            let overflow_key = branch.split_insert(
+                &mut new_branch,
+                &overflow_key_from_child,
+                overflow_child_page_id,
+                format.branch_fill,
+                format.cmp,
+            );
+            Ok(Insertion::Split(overflow_key, new_branch_buffer.page_id))
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn insert_into_leaf(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        format: Format,
+        buffer: WriteGuard,
+        key: &[u8],
+        value: &[u8],
+        mode: WriteMode,
+        outcome: &mut WriteOutcome,
+    ) -> Result<Insertion, Error> {
+        let node = node::Node::new(buffer.data_mut());
+        let mut leaf = leaf::Leaf::new(node.body);
+        // 墓標に挿入するなら、墓標を取り除いてから新しいペアとして挿入する
+        let mut found = leaf.search_slot_id(key, format.cmp);
+        let mut removed = None;
+        if let Ok(slot_id) = found {
+            if leaf.is_tombstone(slot_id) {
+                removed = Some(slot_id);
+                found = Err(slot_id);
+            }
+        }
+        let tombstone = mode == WriteMode::Tombstone;
+        // 収まればその場で置き換え、収まらなければ分割してから挿入し直す
+        // 前のペアや墓標を取り除くのは、収まるとわかるか、分割に使うページを借りてから
+        if let (WriteMode::Update | WriteMode::Upsert | WriteMode::Tombstone, Ok(slot_id)) =
+            (mode, found)
+        {
+            outcome.old_value = Some(leaf.pair_at(slot_id).value.to_vec());
+            if leaf.update(slot_id, value).is_some() {
+                leaf.set_tombstone(slot_id, tombstone);
+                return Ok(Insertion::Done);
+            }
+            removed = Some(slot_id);
+        } else if let Some(slot_id) = removed {
+            if leaf.has_room_replacing(slot_id, key, value) {
+                leaf.remove(slot_id);
+                leaf.insert(slot_id, key, value).expect("room was checked");
+                leaf.set_tombstone(slot_id, tombstone);
+                return Ok(Insertion::Done);
+            }
+        } else {
+            let slot_id = leaf.search_slot_id(key, format.cmp).unwrap_err();
+            if leaf.insert(slot_id, key, value).is_some() {
+                leaf.set_tombstone(slot_id, tombstone);
+                return Ok(Insertion::Done);
+            }
+        }
+        // 分割に使うページを先に借りる。借りられなければ、リーフを書き換えずに失敗する
+        let prev_leaf_page_id = leaf.prev_page_id();
+        let prev_leaf_buffer = prev_leaf_page_id
+            .map(|prev_leaf_page_id| bufmgr.fetch_page_write(prev_leaf_page_id))
+            .transpose()?;
+
+        let new_leaf_buffer = self.create_page(bufmgr)?;
+
+        if let Some(slot_id) = removed {
+            leaf.remove(slot_id);
+        }
+        let slot_id = leaf.search_slot_id(key, format.cmp).unwrap_err();
+
+        if let Some(prev_leaf_buffer) = prev_leaf_buffer {
+            let node = node::Node::new(prev_leaf_buffer.data_mut());
+            let mut prev_leaf = leaf::Leaf::new(node.body);
+            prev_leaf.set_next_page_id(Some(new_leaf_buffer.page_id));
+        }
+        leaf.set_prev_page_id(Some(new_leaf_buffer.page_id));
+
+        let mut new_leaf_node = node::Node::new(new_leaf_buffer.data_mut());
+        new_leaf_node.initialize_as_leaf();
+        let mut new_leaf = leaf::Leaf::new(new_leaf_node.body);
+        new_leaf.initialize();
+        let overflow_key = if leaf.is_append(slot_id) {
+            outcome.leaf_split = Some(LeafSplit::Append);
+            leaf.split_append(&mut new_leaf, key, value, format.cmp)
+        } else {
+            outcome.leaf_split = Some(LeafSplit::Half);
+            leaf.split_insert(&mut new_leaf, key, value, format.leaf_fill, format.cmp)
+        };
+        new_leaf.set_next_page_id(Some(buffer.page_id()));
+        new_leaf.set_prev_page_id(prev_leaf_page_id);
+        // 分割したあとは、どちらのリーフに入ったか探して印を付ける
+        if tombstone {
+            match leaf.search_slot_id(key, format.cmp) {
+                Ok(slot_id) => leaf.set_tombstone(slot_id, true),
+                Err(_) => {
+                    let slot_id = new_leaf.search_slot_id(key, format.cmp).unwrap();
+                    new_leaf.set_tombstone(slot_id, true);
+                }
+            }
+        }
+        Ok(Insertion::Split(overflow_key, new_leaf_buffer.page_id))
+    }
+
+    // 根から書き込み、根が分割されたら新しい根を作る
+    // 置き換えるキーがなくて何も書き込まなかったときも、Keptを返す
+    fn write(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+        mode: WriteMode,
+    ) -> Result<InsertOutcome, Error> {
+        let format = self.format(bufmgr)?;
+        let entry = (key.len(), value.len());
+        let tree_key;
+        let (key, value) = match (format.key_mode, mode) {
+            (KeyMode::Unique, _) => (key, value),
+            (KeyMode::Multi, WriteMode::Insert | WriteMode::InsertOrKeep) => {
+                tree_key = multi_key(key, value);
+                (&tree_key[..], &[][..])
+            }
+            (KeyMode::Multi, WriteMode::Update) => {
+                return Err(Error::UnsupportedOnMulti("update"))
+            }
+            (KeyMode::Multi, WriteMode::Upsert) => {
+                return Err(Error::UnsupportedOnMulti("upsert"))
+            }
+            (KeyMode::Multi, WriteMode::Tombstone) => unreachable!("multi btree has no tombstones"),
+        };
+        format.check_fits(key, value, entry)?;
+        let value = self.store_value(bufmgr, format, value)?;
+        let mut outcome = WriteOutcome::default();
+        let insertion = self.write_pair(bufmgr, key, &value, mode, &mut outcome);
+        // 書き込めなかった値や、置き換えた前の値のオーバーフローページを解放する
+        if let Ok(Insertion::Done) = insertion {
+            self.add_metrics(OpMetrics {
+                bytes_copied: (entry.0 + entry.1) as u64,
+                ..OpMetrics::default()
+            });
+        }
+        match (insertion, outcome.old_value) {
+            (Ok(Insertion::Done), Some(old_value)) => {
+                let loaded = format.load_value(bufmgr, &old_value)?;
+                format.free_value(bufmgr, &old_value)?;
+                Ok(InsertOutcome::Replaced(loaded))
+            }
+            (Ok(Insertion::Done), None) => Ok(InsertOutcome::Inserted),
+            (insertion, _) => {
+                format.free_value(bufmgr, &value)?;
+                insertion.map(|_| InsertOutcome::Kept)
+            }
+        }
+    }
+
+    // リーフに置く形にしたペアを書き込む
+    fn write_pair(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+        mode: WriteMode,
+        outcome: &mut WriteOutcome,
+    ) -> Result<Insertion, Error> {
+        let cached = self.cached_root(bufmgr)?;
+        let root_buffer = bufmgr.fetch_page_write(cached.root_page_id)?;
+        let insertion =
+            self.insert_internal(bufmgr, cached.format, root_buffer, key, value, mode, outcome)?;
+        // 墓標はペアとして数えない
+        let num_entries = match insertion {
+            Insertion::NotFound | Insertion::Kept => 0,
+            _ if mode == WriteMode::Tombstone => -1,
+            _ if outcome.old_value.is_none() => 1,
+            _ => 0,
+        };
+        let (insertion, new_root) = match insertion {
+            Insertion::Split(key, child_page_id) => {
+                let new_root_buffer = self.create_page(bufmgr)?;
+                let mut node = node::Node::new(new_root_buffer.data_mut());
+                node.initialize_as_branch();
+                let mut branch = branch::Branch::new(node.body);
+                branch.initialize(&key, child_page_id, cached.root_page_id);
+                (Insertion::Done, Some(new_root_buffer.page_id))
+            }
+            insertion => (insertion, None),
+        };
+        // ペアの数や分割の回数を書き換える。根を分割したときのほかは、メタページを借りない
+        let leaf_split = outcome.leaf_split;
+        self.update_meta(bufmgr, new_root.is_some(), |meta| {
+            meta.add_num_entries(num_entries);
+            match leaf_split {
+                Some(LeafSplit::Half) => meta.header.half_splits += 1,
+                Some(LeafSplit::Append) => meta.header.append_splits += 1,
+                None => {}
+            }
+            if let Some(new_root) = new_root {
+                meta.set_root_page_id(new_root);
+                meta.add_height(1);
+            }
+        })?;
+        Ok(insertion)
+    }
+
+    // メタページを書き換える
+    // バッファプールに載っていればピン留めせずに書き換え、挿入や削除のたびにメタページを借りないようにする
+    // fetchがtrueなら、載っていても借りて書き換える
+    fn update_meta(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        fetch: bool,
+        f: impl FnOnce(&mut meta::Meta<&mut [u8]>),
+    ) -> Result<(), Error> {
+        if !fetch {
+            if let Some(mut data) = bufmgr.peek_page_mut(self.meta_page_id)? {
+                f(&mut meta::Meta::new(&mut data[..]));
+                return Ok(());
+            }
+        }
+        let meta_buffer = bufmgr.fetch_page_write(self.meta_page_id)?;
+        f(&mut meta::Meta::new(&mut meta_buffer.data_mut()[..]));
+        Ok(())
+    }
+
+    // 空のキーはどのキーよりも小さいキーとして扱い、空の値もほかの値と同じく読み書きできる
+    pub fn insert(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        self.write(bufmgr, key, value, WriteMode::Insert)?;
+        Ok(())
+    }
+
+    // あるキーの値を置き換え、キーがあったかどうかを返す
+    // 重複を許す木ではError::UnsupportedOnMultiを返す。値がキーの一部なので、delete_pairとinsertで置き換える
+    pub fn update(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<bool, Error> {
+        let outcome = self.write(bufmgr, key, value, WriteMode::Update)?;
+        Ok(matches!(outcome, InsertOutcome::Replaced(_)))
+    }
+
+    // あるキーの値を置き換えて前の値を返し、キーがなければ加える
+    // 根から一度だけ降りて、置き換えで収まらなければupdateと同じくリーフを組み直す
+    pub fn upsert(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        match self.write(bufmgr, key, value, WriteMode::Upsert)? {
+            InsertOutcome::Replaced(old_value) => Ok(Some(old_value)),
+            _ => Ok(None),
+        }
+    }
+
+    // insert、upsert、あればそのままにする挿入を、根から一度だけ降りて行う
+    // 重複を許す木では、同じキーと値の組があるかどうかで振る舞いを決め、Replaceは使えない
+    pub fn insert_returning(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+        behavior: OnDuplicate,
+    ) -> Result<InsertOutcome, Error> {
+        let mode = match behavior {
+            OnDuplicate::Error => WriteMode::Insert,
+            OnDuplicate::Replace => WriteMode::Upsert,
+            OnDuplicate::Keep => WriteMode::InsertOrKeep,
+        };
+        self.write(bufmgr, key, value, mode)
+    }
+
+    fn delete_internal(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        buffer: WriteGuard,
+        key: &[u8],
+        cmp: KeyComparator,
+    ) -> Result<Deletion, Error> {
+        let child = {
+            let node = node::Node::new(buffer.data());
+            let is_leaf = node.header.node_type == node::NODE_TYPE_LEAF;
+            self.add_metrics(OpMetrics {
+                branch_pages: (!is_leaf) as u64,
+                leaf_pages: is_leaf as u64,
+                ..OpMetrics::default()
+            });
+            match node::Body::new(node.header.node_type, node.body) {
+                node::Body::Leaf(_) => None,
+                node::Body::Branch(branch) => {
+                    let child_idx = branch.search_child_idx(key, cmp);
+                    Some((child_idx, branch.child_at(child_idx)))
+                }
+            }
+        };
+        let (child_idx, child_page_id) = match child {
+            Some(child) => child,
+            None => return self.delete_from_leaf(buffer, key, cmp),
+        };
+        let child_node_buffer = bufmgr.fetch_page_write(child_page_id)?;
+        match self.delete_internal(bufmgr, child_node_buffer, key, cmp)? {
+            Deletion::Underflow => {}
+            deletion => return Ok(deletion),
+        }
+        let node = node::Node::new(buffer.data_mut());
+        let mut branch = branch::Branch::new(node.body);
+        self.rebalance(bufmgr, &mut branch, child_idx, cmp)?;
+        if branch.is_underflow() {
+            Ok(Deletion::Underflow)
+        } else {
+            Ok(Deletion::Deleted)
+        }
+    }
+
+    fn delete_from_leaf(
+        &self,
+        buffer: WriteGuard,
+        key: &[u8],
+        cmp: KeyComparator,
+    ) -> Result<Deletion, Error> {
+        let slot_id = {
+            let node = node::Node::new(buffer.data());
+            match leaf::Leaf::new(node.body).search_slot_id(key, cmp) {
+                Ok(slot_id) => slot_id,
+                Err(_) => return Ok(Deletion::NotFound),
+            }
+        };
+        let node = node::Node::new(buffer.data_mut());
+        let mut leaf = leaf::Leaf::new(node.body);
+        leaf.remove(slot_id);
+        if leaf.is_underflow() {
+            Ok(Deletion::Underflow)
+        } else {
+            Ok(Deletion::Deleted)
+        }
+    }
+
+    // 使用量が最低限を下回ったchild_idx番目の子を、隣の子とまとめる
+    // まとめると収まらなければ、多い方から少しずつ借りる
+    // 子が1つしかなければ何もせず、このノードの使用量が最低限を下回ったとして親に任せる
+    fn rebalance(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        parent: &mut branch::Branch<impl ByteSliceMut>,
+        child_idx: usize,
+        cmp: KeyComparator,
+    ) -> Result<(), Error> {
+        if parent.num_pairs() == 0 {
+            return Ok(());
+        }
+        let left_idx = child_idx.min(parent.num_pairs() - 1);
+        self.merge_siblings(bufmgr, parent, left_idx, Some(cmp))?;
+        Ok(())
+    }
+
+    // left_idx番目の子とその右隣の子が1つのページに収まれば、まとめて右のページを解放する
+    // 収まらないとき、borrowに比べ方を渡せば、少ない方へペアを移して使用量をならす
+    // まとめたかどうかを返す
+    fn merge_siblings(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        parent: &mut branch::Branch<impl ByteSliceMut>,
+        left_idx: usize,
+        borrow: Option<KeyComparator>,
+    ) -> Result<bool, Error> {
+        let left_page_id = parent.child_at(left_idx);
+        let right_page_id = parent.child_at(left_idx + 1);
+        let merged = {
+            let left_buffer = bufmgr.fetch_page_write(left_page_id)?;
+            let right_buffer = bufmgr.fetch_page_write(right_page_id)?;
+            let left_node = node::Node::new(left_buffer.data_mut());
+            let right_node = node::Node::new(right_buffer.data_mut());
+            let bodies = (
+                node::Body::new(left_node.header.node_type, left_node.body),
+                node::Body::new(right_node.header.node_type, right_node.body),
+            );
+            match bodies {
+                (node::Body::Leaf(mut left), node::Body::Leaf(mut right)) => {
+                    if left.can_merge(&right) {
+                        left.merge(&mut right);
+                        let next_page_id = right.next_page_id();
+                        left.set_next_page_id(next_page_id);
+                        if let Some(next_page_id) = next_page_id {
+                            let next_buffer = bufmgr.fetch_page_write(next_page_id)?;
+                            let node = node::Node::new(next_buffer.data_mut());
+                            leaf::Leaf::new(node.body).set_prev_page_id(Some(left_page_id));
+                        }
+                        true
+                    } else {
+                        if let Some(cmp) = borrow {
+                            borrow_leaf(parent, left_idx, &mut left, &mut right, cmp);
+                        }
+                        false
+                    }
+                }
+                (node::Body::Branch(mut left), node::Body::Branch(mut right)) => {
+                    let separator = parent.pair_at(left_idx).key.to_vec();
+                    if left.can_merge(&separator, &right) {
+                        left.merge(&separator, &mut right);
+                        true
+                    } else {
+                        if borrow.is_some() {
+                            borrow_branch(parent, left_idx, &mut left, &mut right);
+                        }
+                        false
+                    }
+                }
+                _ => unreachable!("siblings must be at the same level"),
+            }
+        };
+        if merged {
+            bufmgr.free_page(right_page_id)?;
+            parent.merge_children(left_idx);
+        }
+        Ok(merged)
+    }
+
+    // キーを削除し、キーがあったかどうかを返す
+    // 重複を許す木では、そのキーのペアをすべて削除する
+    pub fn delete(&self, bufmgr: &mut BufferPoolManager, key: &[u8]) -> Result<bool, Error> {
+        match self.key_mode(bufmgr)? {
+            KeyMode::Unique => self.delete_key(bufmgr, key),
+            KeyMode::Multi => {
+                let values = self.get_all(bufmgr, key)?.collect::<Result<Vec<_>, _>>()?;
+                for value in &values {
+                    self.delete_key(bufmgr, &multi_key(key, value))?;
+                }
+                Ok(!values.is_empty())
+            }
+        }
+    }
+
+    // キーと値がどちらも一致するペアを削除し、あったかどうかを返す
+    pub fn delete_pair(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<bool, Error> {
+        match self.key_mode(bufmgr)? {
+            KeyMode::Unique => {
+                if self.get(bufmgr, key)?.as_deref() != Some(value) {
+                    return Ok(false);
+                }
+                self.delete_key(bufmgr, key)
+            }
+            KeyMode::Multi => self.delete_key(bufmgr, &multi_key(key, value)),
+        }
+    }
+
+    // 木のキーをそのまま削除し、値のオーバーフローページも解放する
+    // 墓標を残す木では、ペアを取り除かずに値を墓標に置き換える
+    fn delete_key(&self, bufmgr: &mut BufferPoolManager, key: &[u8]) -> Result<bool, Error> {
+        let format = self.format(bufmgr)?;
+        let value = match self.stored_value(bufmgr, key)? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        let deleted = if format.tombstones {
+            self.write_tombstone(bufmgr, key)?
+        } else {
+            self.delete_from_tree(bufmgr, key, format.cmp)?
+        };
+        if deleted {
+            format.free_value(bufmgr, &value)?;
+        }
+        Ok(deleted)
+    }
+
+    fn delete_from_tree(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+        cmp: KeyComparator,
+    ) -> Result<bool, Error> {
+        let root_page_id = self.cached_root(bufmgr)?.root_page_id;
+        let root_buffer = bufmgr.fetch_page_write(root_page_id)?;
+        // 根は使用量が最低限を下回ってもそのままにする
+        let deletion = self.delete_internal(bufmgr, root_buffer, key, cmp)?;
+        match deletion {
+            Deletion::NotFound => return Ok(false),
+            Deletion::Deleted => self.update_meta(bufmgr, false, |meta| meta.add_num_entries(-1))?,
+            // 子が1つだけになった根は、使用量が最低限を下回っている
+            Deletion::Underflow => {
+                let meta_buffer = bufmgr.fetch_page_write(self.meta_page_id)?;
+                meta::Meta::new(meta_buffer.data_mut()).add_num_entries(-1);
+                self.collapse_root(bufmgr, &meta_buffer)?;
+            }
+        }
+        Ok(true)
+    }
+
+    // 子が1つだけになった根は、その子に置き換えて木を低くする
+    // 解放したページの数を返す
+    fn collapse_root(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        meta_buffer: &WriteGuard,
+    ) -> Result<u64, Error> {
+        let mut freed = 0;
+        loop {
+            let root_page_id = meta::Meta::new(meta_buffer.data()).header.root_page_id;
+            let only_child = {
+                let root_buffer = bufmgr.fetch_page_read(root_page_id)?;
+                let node = node::Node::new(root_buffer.data());
+                if node.header.node_type != node::NODE_TYPE_BRANCH {
+                    break;
+                }
+                let branch = branch::Branch::new(node.body);
+                if branch.num_pairs() > 0 {
+                    break;
+                }
+                branch.child_at(0)
+            };
+            let mut meta = meta::Meta::new(meta_buffer.data_mut());
+            meta.set_root_page_id(only_child);
+            meta.add_height(-1);
+            bufmgr.free_page(root_page_id)?;
+            freed += 1;
+        }
+        Ok(freed)
+    }
+}
+
+// 隣のリーフから1つずつペアを借りて、使用量が最低限を下回ったリーフを埋める
+// 親の区切りのキーを置き換えられなければ、そこでやめる
+fn borrow_leaf(
+    parent: &mut branch::Branch<impl ByteSliceMut>,
+    left_idx: usize,
+    left: &mut leaf::Leaf<impl ByteSliceMut>,
+    right: &mut leaf::Leaf<impl ByteSliceMut>,
+    cmp: KeyComparator,
+) {
+    if left.is_underflow() {
+        while left.is_underflow() && right.num_pairs() > 1 {
+            let key = cmp.separator(right.pair_at(0).key, right.pair_at(1).key);
+            if parent.update_key(left_idx, &key).is_none() {
+                break;
+            }
+            right.transfer(left);
+        }
+    } else {
+        while right.is_underflow() && left.num_pairs() > 1 {
+            let num_pairs = left.num_pairs();
+            let prev_key = left.pair_at(num_pairs - 2).key;
+            let key = cmp.separator(prev_key, left.pair_at(num_pairs - 1).key);
+            if parent.update_key(left_idx, &key).is_none() {
+                break;
+            }
+            left.transfer_last(right);
+        }
+    }
+}
+
+// 枝のノードでは、親の区切りのキーを下ろし、代わりに隣のノードの端のキーを上げる
+fn borrow_branch(
+    parent: &mut branch::Branch<impl ByteSliceMut>,
+    left_idx: usize,
+    left: &mut branch::Branch<impl ByteSliceMut>,
+    right: &mut branch::Branch<impl ByteSliceMut>,
+) {
+    if left.is_underflow() {
+        while left.is_underflow() && right.num_pairs() > 0 {
+            let separator = parent.pair_at(left_idx).key.to_vec();
+            if parent.update_key(left_idx, right.pair_at(0).key).is_none() {
+                break;
+            }
+            left.borrow_first(&separator, right);
+        }
+    } else {
+        while right.is_underflow() && left.num_pairs() > 0 {
+            let separator = parent.pair_at(left_idx).key.to_vec();
+            let last_key = left.pair_at(left.num_pairs() - 1).key.to_vec();
+            if parent.update_key(left_idx, &last_key).is_none() {
+                break;
+            }
+            right.borrow_last(&separator, left);
+        }
+    }
+}
+
+pub struct Iter {
+    buffer: PinnedBuffer,
+    slot_id: usize,
+    strategy: AccessStrategy,
+    format: Format,
+    meta_page_id: PageId,
+    // bufferのリーフの版。移ったときから変わっていれば、スロットの番号がずれているかもしれない
+    version: u32,
+    // まだ何も返していないときに探し直す位置。このキー以上の最初のペアから返す。Noneなら木の終わり
+    origin: Option<Vec<u8>>,
+    // 最後に返したペアのリーフとスロットと、そのときのリーフの版。delete_currentで削除する
+    last: Option<(PageId, usize, u32)>,
+    // 最後に返したペアの木のキー。positionで返す
+    last_key: Option<Vec<u8>>,
+    // 墓標を飛ばさずに返す。iter_rawで使う
+    raw: bool,
+    metrics: OpMetrics,
+    // 作ったBTreeのハンドルと共有する合計
+    shared_metrics: Arc<SharedMetrics>,
+}
+
+// resumeで走査を再開する位置
+// ページは分割や解放で中身が入れ替わるので、ページIDは覚えずに、キーから探し直す
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CursorPos {
+    // 最後に返したペアの木のキー。まだ何も返していなければ次に返すペアのキーで、
+    // 返すペアもなければNone
+    key: Option<Vec<u8>>,
+    // keyのペアから返すかどうか
+    inclusive: bool,
+}
+
+impl Iter {
+    // 今のペアのキーと、リーフに置いた値をfに渡す
+    fn with_pair<T>(&self, f: impl FnOnce(&[u8], &[u8]) -> T) -> Option<T> {
+        let leaf_node = node::Node::new(self.buffer.data());
+        let leaf = leaf::Leaf::new(leaf_node.body);
+        if self.slot_id < leaf.num_pairs() {
+            let pair = leaf.pair_at(self.slot_id);
+            Some(f(pair.key, pair.value))
+        } else {
+            None
+        }
+    }
+
+    fn key(&self) -> Option<Vec<u8>> {
+        self.with_pair(|key, _| self.format.user_key(key))
+    }
+
+    // 今のペアが呼び出し側から見てkeyのペアなら、with_pairと同じくfに渡す
+    fn with_user_key<T>(&self, key: &[u8], f: impl FnOnce(&[u8], &[u8]) -> T) -> Option<T> {
+        let format = self.format;
+        self.with_pair(|tree_key, value| {
+            format.has_user_key(tree_key, key).then(|| f(tree_key, value))
+        })
+        .flatten()
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn get(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        match self.with_pair(|key, value| (key.to_vec(), value.to_vec())) {
+            Some((key, value)) => Ok(Some(self.format.load_pair(bufmgr, &key, &value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // 次に返すペアのあるリーフまで進む。カーソルから削除して空になったリーフや、墓標は飛ばす
+    // 別のリーフに進んだらtrueを返す
+    fn seek_pair(&mut self, bufmgr: &mut BufferPoolManager) -> Result<bool, Error> {
+        let mut moved = false;
+        loop {
+            let next_page_id = {
+                let leaf_node = node::Node::new(self.buffer.data());
+                let leaf = leaf::Leaf::new(leaf_node.body);
+                self.version = leaf.version();
+                while !self.raw
+                    && self.slot_id < leaf.num_pairs()
+                    && leaf.is_tombstone(self.slot_id)
+                {
+                    self.slot_id += 1;
+                }
+                if self.slot_id < leaf.num_pairs() {
+                    return Ok(moved);
+                }
+                leaf.next_page_id()
+            };
+            let next_page_id = match next_page_id {
+                Some(next_page_id) => next_page_id,
+                None => return Ok(moved),
+            };
+            self.buffer = bufmgr.fetch_page_with_strategy(next_page_id, self.strategy)?;
+            self.slot_id = 0;
+            moved = true;
+            self.add_metrics(OpMetrics {
+                leaf_pages: 1,
+                ..OpMetrics::default()
+            });
+        }
+    }
+
+    // 走査の途中で今のリーフにペアを挿入したり削除したりして、スロットの番号がずれていれば、
+    // 最後に返したペアの次のペアからキーで探し直す。分割でペアがほかのリーフに移っても、
+    // 前からあったペアを飛ばしたり2度返したりしない
+    fn revalidate(&mut self, bufmgr: &mut BufferPoolManager) -> Result<(), Error> {
+        let version = {
+            let leaf_node = node::Node::new(self.buffer.data());
+            leaf::Leaf::new(leaf_node.body).version()
+        };
+        if version == self.version {
+            return Ok(());
+        }
+        let pos = match &self.last_key {
+            Some(key) => CursorPos {
+                key: Some(key.clone()),
+                inclusive: false,
+            },
+            None => CursorPos {
+                key: self.origin.clone(),
+                inclusive: true,
+            },
+        };
+        let btree = BTree::with_metrics(self.meta_page_id, self.shared_metrics.clone());
+        let iter = btree.resume_internal(bufmgr, pos, self.raw)?;
+        // 探し直したページは、共有する合計にはもう数えてある
+        self.metrics += iter.metrics;
+        self.buffer = iter.buffer;
+        self.slot_id = iter.slot_id;
+        self.version = iter.version;
+        Ok(())
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn next(
+        &mut self,
+        bufmgr: &mut BufferPoolManager,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        self.revalidate(bufmgr)?;
+        // 前に返したあとで墓標になったペアは返さない
+        self.seek_pair(bufmgr)?;
+        let value = self.get(bufmgr)?;
+        if let Some((key, value)) = &value {
+            self.add_metrics(OpMetrics {
+                entries: 1,
+                bytes_copied: (key.len() + value.len()) as u64,
+                ..OpMetrics::default()
+            });
+        }
+        self.last = value
+            .as_ref()
+            .map(|_| (self.buffer.page_id, self.slot_id, self.version));
+        if value.is_some() {
+            self.last_key = self.with_pair(|key, _| key.to_vec());
+        }
+        self.slot_id += 1;
+        if self.seek_pair(bufmgr)? {
+            if self.strategy == AccessStrategy::BulkRead {
+                return Ok(value);
+            }
+            // さらに次のリーフを先読みしておく
+            let following_page_id = {
+                let leaf_node = node::Node::new(self.buffer.data());
+                leaf::Leaf::new(leaf_node.body).next_page_id()
+            };
+            if let Some(following_page_id) = following_page_id {
+                bufmgr.prefetch(&[following_page_id])?;
+            }
+        }
+        Ok(value)
+    }
+
+    // nextをn回呼んだのと同じ位置まで進み、飛ばしたペアの数を返す。木の終わりに着けばnより少ない
+    // ペアは読まずにスロットの数だけを数え、残りがリーフに収まらなければ隣のリーフへ移るので、
+    // 読むのはリーフごとに1つのキーだけで済む
+    pub fn skip_entries(
+        &mut self,
+        bufmgr: &mut BufferPoolManager,
+        n: usize,
+    ) -> Result<usize, Error> {
+        self.revalidate(bufmgr)?;
+        self.last = None;
+        let mut skipped = 0;
+        while skipped < n {
+            self.seek_pair(bufmgr)?;
+            let leaf_node = node::Node::new(self.buffer.data());
+            let leaf = leaf::Leaf::new(leaf_node.body);
+            if self.slot_id >= leaf.num_pairs() {
+                break;
+            }
+            let mut last_slot_id = None;
+            while self.slot_id < leaf.num_pairs() && skipped < n {
+                if self.raw || !leaf.is_tombstone(self.slot_id) {
+                    last_slot_id = Some(self.slot_id);
+                    skipped += 1;
+                }
+                self.slot_id += 1;
+            }
+            // positionで続きから走査できるよう、最後に飛ばしたペアのキーを覚えておく
+            if let Some(last_slot_id) = last_slot_id {
+                self.last_key = Some(leaf.pair_at(last_slot_id).key.to_vec());
+            }
+        }
+        self.seek_pair(bufmgr)?;
+        Ok(skipped)
+    }
+
+    // 最後に返したペアの位置。resumeに渡せば、その次のペアから走査を続ける
+    pub fn position(&self) -> CursorPos {
+        match &self.last_key {
+            Some(key) => CursorPos {
+                key: Some(key.clone()),
+                inclusive: false,
+            },
+            None => CursorPos {
+                key: self.with_pair(|key, _| key.to_vec()),
+                inclusive: true,
+            },
+        }
+    }
+
+    // 最後にnextで返したペアを、根からたどり直さずにそのリーフから削除する
+    // 続くnextはその次のペアを返す。まだ返していないか、もう削除していればfalseを返す
+    // 使用量が減ったリーフも空になったリーフもまとめないので、まとめるならあとでvacuumを呼ぶ
+    // 墓標を残す木では根から墓標を書き、分割でずれるかもしれない位置はキーから探し直す
+    pub fn delete_current(&mut self, bufmgr: &mut BufferPoolManager) -> Result<bool, Error> {
+        let (page_id, slot_id, version) = match self.last.take() {
+            Some(last) => last,
+            None => return Ok(false),
+        };
+        let key = self.last_key.clone().unwrap();
+        let btree = BTree::with_metrics(self.meta_page_id, self.shared_metrics.clone());
+        if self.format.tombstones {
+            let deleted = btree.delete_key(bufmgr, &key)?;
+            let pos = CursorPos {
+                key: Some(key),
+                inclusive: false,
+            };
+            let iter = btree.resume(bufmgr, pos)?;
+            self.buffer = iter.buffer;
+            self.slot_id = iter.slot_id;
+            self.version = iter.version;
+            return Ok(deleted);
+        }
+        // 返したあとでそのリーフのスロットがずれていれば、キーから探し直す
+        let moved = {
+            let buffer = bufmgr.fetch_page_read(page_id)?;
+            let node = node::Node::new(buffer.data());
+            node.header.node_type != node::NODE_TYPE_LEAF
+                || leaf::Leaf::new(node.body).version() != version
+        };
+        let (page_id, slot_id) = if moved {
+            let pos = CursorPos {
+                key: Some(key.clone()),
+                inclusive: true,
+            };
+            let iter = btree.resume(bufmgr, pos)?;
+            match iter.with_pair(|found, _| found == &key[..]) {
+                Some(true) => (iter.buffer.page_id, iter.slot_id),
+                _ => return Ok(false),
+            }
+        } else {
+            (page_id, slot_id)
+        };
+        let (value, version) = {
+            let buffer = bufmgr.fetch_page_write(page_id)?;
+            let node = node::Node::new(buffer.data_mut());
+            let mut leaf = leaf::Leaf::new(node.body);
+            let value = leaf.pair_at(slot_id).value.to_vec();
+            let before = leaf.version();
+            leaf.remove(slot_id);
+            (value, (before, leaf.version()))
+        };
+        // 同じリーフの後ろのペアは1つ前のスロットにずれる。自分で動かしたので探し直さない
+        if self.buffer.page_id == page_id && self.version == version.0 {
+            if self.slot_id > slot_id {
+                self.slot_id -= 1;
+            }
+            self.version = version.1;
+        }
+        self.format.free_value(bufmgr, &value)?;
+        let meta_buffer = bufmgr.fetch_page_write(self.meta_page_id)?;
+        meta::Meta::new(meta_buffer.data_mut()).add_num_entries(-1);
+        Ok(true)
+    }
+}
+
+// 前のリーフへたどりながら、キーの大きい方から返す
+pub struct RevIter {
+    buffer: PinnedBuffer,
+    // このリーフで次に返すペアの1つ後ろのスロット
+    slot_end: usize,
+    strategy: AccessStrategy,
+    format: Format,
+}
+
+impl RevIter {
+    // 次に返すペアのあるリーフまで前へたどる。墓標は飛ばし、もうペアがなければfalseを返す
+    fn seek_pair(&mut self, bufmgr: &mut BufferPoolManager) -> Result<bool, Error> {
+        loop {
+            let prev_page_id = {
+                let leaf_node = node::Node::new(self.buffer.data());
+                let leaf = leaf::Leaf::new(leaf_node.body);
+                while self.slot_end > 0 && leaf.is_tombstone(self.slot_end - 1) {
+                    self.slot_end -= 1;
+                }
+                if self.slot_end > 0 {
+                    return Ok(true);
+                }
+                leaf.prev_page_id()
+            };
+            let prev_page_id = match prev_page_id {
+                Some(prev_page_id) => prev_page_id,
+                None => return Ok(false),
+            };
+            self.buffer = bufmgr.fetch_page_with_strategy(prev_page_id, self.strategy)?;
+            let leaf_node = node::Node::new(self.buffer.data());
+            self.slot_end = leaf::Leaf::new(leaf_node.body).num_pairs();
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn next(
+        &mut self,
+        bufmgr: &mut BufferPoolManager,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        if !self.seek_pair(bufmgr)? {
+            return Ok(None);
+        }
+        self.slot_end -= 1;
+        let (key, value) = {
+            let leaf_node = node::Node::new(self.buffer.data());
+            let leaf = leaf::Leaf::new(leaf_node.body);
+            let pair = leaf.pair_at(self.slot_end);
+            (pair.key.to_vec(), pair.value.to_vec())
+        };
+        Ok(Some(self.format.load_pair(bufmgr, &key, &value)?))
+    }
+}
+
+// 終わりのキーまでで止まるIter
+pub struct RangeIter {
+    iter: Iter,
+    end: Bound<Vec<u8>>,
+    finished: bool,
+}
+
+impl RangeIter {
+    #[allow(clippy::type_complexity)]
+    pub fn next(
+        &mut self,
+        bufmgr: &mut BufferPoolManager,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        if self.finished {
+            return Ok(None);
+        }
+        // 範囲を越えたペアは、値を読み出さずに止める
+        self.iter.revalidate(bufmgr)?;
+        self.iter.seek_pair(bufmgr)?;
+        let format = self.iter.format;
+        let in_range = self.iter.with_pair(|key, _| match &self.end {
+            Bound::Included(end) => !format.cmp.lt(end, &format.user_key(key)),
+            Bound::Excluded(end) => format.cmp.lt(&format.user_key(key), end),
+            Bound::Unbounded => true,
+        });
+        let pair = match in_range {
+            Some(true) => self.iter.next(bufmgr)?,
+            _ => None,
+        };
+        // 範囲を越えたら、それより先のリーフは読まない
+        self.finished = pair.is_none();
+        Ok(pair)
+    }
+}
+
+// get_allで、キーが一致するペアの値を返すIterator
+pub struct ValuesIter<'a> {
+    bufmgr: &'a mut BufferPoolManager,
+    iter: RangeIter,
+}
+
+impl<'a> Iterator for ValuesIter<'a> {
+    type Item = Result<Vec<u8>, Error>;
+
+    // 読み込みに失敗したら、エラーを返してから終わる
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.iter.next(self.bufmgr) {
+            Ok(pair) => pair.map(|(_, value)| Ok(value)),
+            Err(err) => {
+                self.iter.finished = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+enum EntriesState {
+    Cursor(Iter),
+    // 走査を始められなかったので、最初にエラーを返す
+    Failed(Error),
+    Finished,
+}
+
+pub struct Entries<'a> {
+    bufmgr: &'a mut BufferPoolManager,
+    state: EntriesState,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>), Error>;
+
+    // 読み込みに失敗したら、エラーを返してから終わる
+    fn next(&mut self) -> Option<Self::Item> {
+        match std::mem::replace(&mut self.state, EntriesState::Finished) {
+            EntriesState::Cursor(mut iter) => match iter.next(self.bufmgr) {
+                Ok(Some(pair)) => {
+                    self.state = EntriesState::Cursor(iter);
+                    Some(Ok(pair))
+                }
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            },
+            EntriesState::Failed(err) => Some(Err(err)),
+            EntriesState::Finished => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::convert::TryInto;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use tempfile::{tempdir, NamedTempFile};
+
+    use crate::buffer::{BufferPool, MockClock};
+    #[cfg(unix)]
+    use crate::disk::MmapDiskManager;
+    use crate::disk::{self, DiskManager, EncryptedDiskManager, MemoryDiskManager, Storage};
+
+    use super::*;
+
+    #[test]
+    fn test() {
+        let disk = MemoryDiskManager::new();
+        let pool = BufferPool::new(10);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        btree
             .insert(&mut bufmgr, &6u64.to_be_bytes(), b"world")
             .unwrap();
         btree
@@ -292,47 +2203,2952 @@ mod tests {
         btree
             .insert(&mut bufmgr, &8u64.to_be_bytes(), b"!")
             .unwrap();
-        btree
-            .insert(&mut bufmgr, &4u64.to_be_bytes(), b",")
+        btree
+            .insert(&mut bufmgr, &4u64.to_be_bytes(), b",")
+            .unwrap();
+        btree.verify(&mut bufmgr).unwrap();
+
+        let (_, value) = btree
+            .search(&mut bufmgr, SearchMode::KeyOrNext(3u64.to_be_bytes().to_vec()))
+            .unwrap()
+            .get(&mut bufmgr)
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"hello", &value[..]);
+        let (_, value) = btree
+            .search(&mut bufmgr, SearchMode::KeyOrNext(8u64.to_be_bytes().to_vec()))
+            .unwrap()
+            .get(&mut bufmgr)
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"!", &value[..]);
+    }
+
+    #[test]
+    fn test_split() {
+        let disk = MemoryDiskManager::new();
+        let pool = BufferPool::new(10);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let long_data_list = [
+            vec![0xC0u8; 1000],
+            vec![0x01u8; 1000],
+            vec![0xCAu8; 1000],
+            vec![0xFEu8; 1000],
+            vec![0xDEu8; 1000],
+            vec![0xADu8; 1000],
+            vec![0xBEu8; 1000],
+            vec![0xAEu8; 1000],
+        ];
+        for data in long_data_list.iter() {
+            btree.insert(&mut bufmgr, data, data).unwrap();
+        }
+        btree.verify(&mut bufmgr).unwrap();
+        for data in long_data_list.iter() {
+            let (k, v) = btree
+                .search(&mut bufmgr, SearchMode::KeyOrNext(data.clone()))
+                .unwrap()
+                .get(&mut bufmgr)
+                .unwrap()
+                .unwrap();
+            assert_eq!(data, &k);
+            assert_eq!(data, &v);
+        }
+    }
+
+    #[test]
+    fn test_append_split() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(64));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        // btree-largeと同じく、u32の主キーを昇順に挿入する
+        for i in 0u32..100_000 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        let stats = btree.stats(&mut bufmgr).unwrap();
+        assert!(stats.avg_leaf_fill > 85.0, "{}", stats);
+        assert_eq!((0, stats.leaf_pages - 1), (stats.half_splits, stats.append_splits));
+        btree.verify(&mut bufmgr).unwrap();
+        for (i, pair) in btree.iter(&mut bufmgr).enumerate() {
+            let (key, value) = pair.unwrap();
+            assert_eq!(&(i as u32).to_be_bytes()[..], key);
+            assert_eq!(&(i as u32).to_le_bytes()[..], value);
+        }
+        assert_eq!(100_000, btree.len(&mut bufmgr).unwrap());
+
+        // 降順に挿入すると、これまでどおり半分ずつに分ける
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for i in (0u32..10_000).rev() {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        let stats = btree.stats(&mut bufmgr).unwrap();
+        assert!(stats.avg_leaf_fill < 60.0, "{}", stats);
+        assert_eq!((stats.leaf_pages - 1, 0), (stats.half_splits, stats.append_splits));
+        btree.verify(&mut bufmgr).unwrap();
+    }
+
+    // 指定したページサイズで、挿入・検索・走査がひととおりできるか確かめる
+    fn check_insert_search(page_size: usize) {
+        let disk = MemoryDiskManager::with_page_size(page_size).unwrap();
+        let pool = BufferPool::new(16).with_page_size(page_size);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        // ページ数が増えるよう、逆順に飛ばしながら挿入する
+        let value_len = page_size / 32;
+        for i in (0u64..1000).rev().filter(|i| i % 2 == 0).chain((0u64..1000).filter(|i| i % 2 == 1)) {
+            btree
+                .insert(&mut bufmgr, &i.to_be_bytes(), &vec![i as u8; value_len])
+                .unwrap();
+        }
+        btree.verify(&mut bufmgr).unwrap();
+        for i in [0u64, 1, 499, 500, 998, 999] {
+            let (key, value) = btree
+                .search(&mut bufmgr, SearchMode::KeyOrNext(i.to_be_bytes().to_vec()))
+                .unwrap()
+                .get(&mut bufmgr)
+                .unwrap()
+                .unwrap();
+            assert_eq!(&i.to_be_bytes(), &key[..]);
+            assert_eq!(vec![i as u8; value_len], value);
+        }
+        let mut iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+        for i in 0u64..1000 {
+            let (key, _) = iter.next(&mut bufmgr).unwrap().unwrap();
+            assert_eq!(&i.to_be_bytes(), &key[..]);
+        }
+        assert!(iter.next(&mut bufmgr).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_page_size() {
+        check_insert_search(512);
+        check_insert_search(16 * 1024);
+    }
+
+    #[test]
+    fn test_encrypted() {
+        // 暗号化するとページサイズが2のべき乗でなくなるが、そのまま木を作れる
+        let disk = EncryptedDiskManager::create(MemoryDiskManager::new(), &[7; 32]).unwrap();
+        let pool = BufferPool::new(8).with_page_size(disk.page_size());
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for i in (0u64..500).rev() {
+            btree
+                .insert(&mut bufmgr, &i.to_be_bytes(), &[i as u8; 64])
+                .unwrap();
+        }
+        let mut iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+        for i in 0u64..500 {
+            let (key, value) = iter.next(&mut bufmgr).unwrap().unwrap();
+            assert_eq!(&i.to_be_bytes(), &key[..]);
+            assert_eq!(&[i as u8; 64], &value[..]);
+        }
+        assert!(iter.next(&mut bufmgr).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_evict_to_memory() {
+        // プールを小さくして、メモリ上のストレージとの間でページを出し入れさせる
+        let mut bufmgr = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(8));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for i in 0u64..500 {
+            btree
+                .insert(&mut bufmgr, &i.to_be_bytes(), &[i as u8; 64])
+                .unwrap();
+        }
+        for i in (0u64..500).rev() {
+            let (key, value) = btree
+                .search(&mut bufmgr, SearchMode::KeyOrNext(i.to_be_bytes().to_vec()))
+                .unwrap()
+                .get(&mut bufmgr)
+                .unwrap()
+                .unwrap();
+            assert_eq!(&i.to_be_bytes(), &key[..]);
+            assert_eq!(&[i as u8; 64], &value[..]);
+        }
+    }
+
+    #[test]
+    fn test_flush_reopen() {
+        let dir = tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let disk = DiskManager::new(data_file).unwrap();
+        let pool = BufferPool::new(10);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for i in 0u64..1000 {
+            btree
+                .insert(&mut bufmgr, &i.to_be_bytes(), &(i * 2).to_be_bytes())
+                .unwrap();
+        }
+        bufmgr.flush().unwrap();
+        let meta_page_id = btree.meta_page_id;
+        drop(bufmgr);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let pool = BufferPool::new(10);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::new(meta_page_id);
+        btree.verify(&mut bufmgr).unwrap();
+        let mut iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+        for i in 0u64..1000 {
+            let (key, value) = iter.next(&mut bufmgr).unwrap().unwrap();
+            assert_eq!(&i.to_be_bytes(), &key[..]);
+            assert_eq!(&(i * 2).to_be_bytes(), &value[..]);
+        }
+        assert!(iter.next(&mut bufmgr).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_meta() {
+        let dir = tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let disk = DiskManager::new(data_file).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let meta = btree.meta(&mut bufmgr).unwrap();
+        let fields = (meta.meta_version, meta.height, meta.num_entries);
+        assert_eq!((meta::META_VERSION, 1, Some(0)), fields);
+        assert_eq!(KeyMode::Unique, meta.key_mode);
+
+        let check = |bufmgr: &mut BufferPoolManager| {
+            let meta = btree.meta(bufmgr).unwrap();
+            let stats = btree.stats(bufmgr).unwrap();
+            assert_eq!((stats.height, Some(stats.num_entries)), (meta.height, meta.num_entries));
+            meta
+        };
+        for i in 0u64..20000 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        assert!(check(&mut bufmgr).height >= 3);
+        // 削除して根が取り除かれると低くなる
+        for i in 10u64..20000 {
+            btree.delete(&mut bufmgr, &i.to_be_bytes()).unwrap();
+        }
+        assert_eq!(1, check(&mut bufmgr).height);
+        for i in 10u64..1000 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        let meta = check(&mut bufmgr);
+        bufmgr.flush().unwrap();
+        drop(bufmgr);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        assert_eq!(meta, check(&mut bufmgr));
+        assert_eq!(meta, btree.meta(&mut bufmgr).unwrap());
+
+        // 以前の形式のメタページでは、根からたどって高さを数える
+        {
+            let meta_buffer = bufmgr.fetch_page_write(btree.meta_page_id).unwrap();
+            let mut meta = meta::Meta::new(meta_buffer.data_mut());
+            meta.header.meta_version = 0;
+            meta.header.height = 0;
+        }
+        assert_eq!(meta.height, check(&mut bufmgr).height);
+        btree.insert(&mut bufmgr, &20000u64.to_be_bytes(), &[0; 100]).unwrap();
+        check(&mut bufmgr);
+
+        // 知らない版のメタページは読まない。書き換えたらgenerationも進め、覚えている形式を捨てさせる
+        {
+            let meta_buffer = bufmgr.fetch_page_write(btree.meta_page_id).unwrap();
+            let mut meta = meta::Meta::new(meta_buffer.data_mut());
+            meta.header.meta_version = meta::META_VERSION + 1;
+            meta.bump_generation();
+        }
+        assert!(matches!(
+            btree.meta(&mut bufmgr),
+            Err(Error::UnsupportedMetaVersion { version, .. }) if version == meta::META_VERSION + 1
+        ));
+        assert!(matches!(
+            btree.get(&mut bufmgr, &0u64.to_be_bytes()),
+            Err(Error::UnsupportedMetaVersion { .. })
+        ));
+    }
+
+    // 小さいプールで大量に挿入し、ファイルに書き出す
+    #[cfg(unix)]
+    fn insert_many(disk: impl Storage + 'static) {
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for i in (0u64..3000).rev() {
+            btree
+                .insert(&mut bufmgr, &i.to_be_bytes(), &[i as u8; 100])
+                .unwrap();
+        }
+        bufmgr.set_meta_page_id(btree.meta_page_id).unwrap();
+        bufmgr.flush().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mmap_backend() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("file");
+        let mmap_path = dir.path().join("mmap");
+        insert_many(DiskManager::create(&file_path).unwrap());
+        insert_many(MmapDiskManager::create(&mmap_path).unwrap());
+        // どちらのバックエンドでも、ファイルの中身は同じになる
+        let file_bytes = std::fs::read(&file_path).unwrap();
+        assert!(file_bytes.len() > 100 * 4096);
+        assert_eq!(file_bytes, std::fs::read(&mmap_path).unwrap());
+
+        let disk = MmapDiskManager::open(&file_path).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::new(bufmgr.meta_page_id());
+        let mut iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+        for i in 0u64..3000 {
+            let (key, value) = iter.next(&mut bufmgr).unwrap().unwrap();
+            assert_eq!(&i.to_be_bytes(), &key[..]);
+            assert_eq!(&[i as u8; 100], &value[..]);
+        }
+        assert!(iter.next(&mut bufmgr).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_tablespaces() {
+        let dir = tempdir().unwrap();
+        let heap_file_path = dir.path().join("heap");
+        let mut disk = DiskManager::create(&heap_file_path).unwrap();
+        let index_ts = disk.create_tablespace("index").unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let table = BTree::create(&mut bufmgr).unwrap();
+        let index = BTree::create_in(&mut bufmgr, index_ts).unwrap();
+        assert_eq!(index_ts, index.meta_page_id.tablespace_id());
+        for i in 0u64..1000 {
+            table
+                .insert(&mut bufmgr, &i.to_be_bytes(), &[0xAA; 100])
+                .unwrap();
+            index
+                .insert(&mut bufmgr, &i.to_be_bytes(), &[0xBB; 100])
+                .unwrap();
+        }
+        bufmgr.set_meta_page_id(table.meta_page_id).unwrap();
+        bufmgr.flush().unwrap();
+        drop(bufmgr);
+
+        // それぞれのファイルには、自分の木のページしか入っていない
+        let contains = |bytes: &[u8], value: &[u8]| bytes.windows(value.len()).any(|w| w == value);
+        let heap_bytes = std::fs::read(&heap_file_path).unwrap();
+        let index_bytes = std::fs::read(dir.path().join("heap.index.tbs")).unwrap();
+        assert!(contains(&heap_bytes, &[0xAA; 100]));
+        assert!(!contains(&heap_bytes, &[0xBB; 100]));
+        assert!(contains(&index_bytes, &[0xBB; 100]));
+        assert!(!contains(&index_bytes, &[0xAA; 100]));
+
+        let disk = DiskManager::open(&heap_file_path).unwrap();
+        assert_eq!(Some(index_ts), disk.tablespace_id("index"));
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        for (btree, value) in [(table, 0xAA), (index, 0xBB)] {
+            let mut iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+            for i in 0u64..1000 {
+                let (key, found) = iter.next(&mut bufmgr).unwrap().unwrap();
+                assert_eq!(&i.to_be_bytes(), &key[..]);
+                assert_eq!(&[value; 100], &found[..]);
+            }
+            assert!(iter.next(&mut bufmgr).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_dirty_pages() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(100));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for i in 0..200u64 {
+            btree
+                .insert(&mut bufmgr, &(i * 2).to_be_bytes(), &[0xAB; 100])
+                .unwrap();
+        }
+        bufmgr.flush().unwrap();
+        let dirty_count = |bufmgr: &BufferPoolManager| {
+            bufmgr.dump().iter().filter(|info| info.is_dirty).count()
+        };
+
+        // 読むだけならdirtyにならない
+        let from = SearchMode::KeyOrNext(100u64.to_be_bytes().to_vec());
+        for search_mode in [SearchMode::Start, from] {
+            let mut iter = btree.search(&mut bufmgr, search_mode).unwrap();
+            while iter.next(&mut bufmgr).unwrap().is_some() {}
+        }
+        assert!(matches!(
+            btree.insert(&mut bufmgr, &100u64.to_be_bytes(), b"dup"),
+            Err(Error::DuplicateKey)
+        ));
+        assert_eq!(0, dirty_count(&bufmgr));
+
+        // 分割しない挿入では葉と、ペアの数を持つメタページだけがdirtyになる
+        btree
+            .insert(&mut bufmgr, &101u64.to_be_bytes(), b"x")
+            .unwrap();
+        let dirty: Vec<_> = bufmgr
+            .dump()
+            .into_iter()
+            .filter(|info| info.is_dirty)
+            .collect();
+        assert_eq!(2, dirty.len());
+        assert!(dirty.iter().any(|info| info.page_id == btree.meta_page_id));
+        let (key, _) = btree
+            .search(&mut bufmgr, SearchMode::KeyOrNext(101u64.to_be_bytes().to_vec()))
+            .unwrap()
+            .get(&mut bufmgr)
+            .unwrap()
+            .unwrap();
+        assert_eq!(&101u64.to_be_bytes(), &key[..]);
+    }
+
+    fn scan(bufmgr: &mut BufferPoolManager, btree: &BTree) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut iter = btree.search(bufmgr, SearchMode::Start).unwrap();
+        let mut pairs = vec![];
+        while let Some((key, value)) = iter.next(bufmgr).unwrap() {
+            pairs.push((key, value));
+        }
+        pairs
+    }
+
+    #[test]
+    fn test_backup() {
+        let dir = tempdir().unwrap();
+        let backup_path = dir.path().join("backup");
+        let disk = DiskManager::create(dir.path().join("heap")).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        bufmgr.set_meta_page_id(btree.meta_page_id).unwrap();
+        // 先頭のページにも書き込みが続くよう、キーを散らして挿入する
+        let key = |i: u64| (i * 7919 % 3000).to_be_bytes();
+        for i in 0..1000 {
+            btree.insert(&mut bufmgr, &key(i), &[i as u8; 100]).unwrap();
+        }
+
+        // 挿入の合間に少しずつコピーする
+        bufmgr.start_backup(&backup_path).unwrap();
+        for i in 1000..3000 {
+            btree.insert(&mut bufmgr, &key(i), &[i as u8; 100]).unwrap();
+            if i % 100 == 0 {
+                bufmgr.backup_step(5).unwrap();
+            }
+        }
+        bufmgr.finish_backup().unwrap();
+        let expected = scan(&mut bufmgr, &btree);
+        assert_eq!(3000, expected.len());
+        // バックアップを終えたあとの変更は含まない
+        btree.insert(&mut bufmgr, &3000u64.to_be_bytes(), b"after").unwrap();
+
+        let disk = DiskManager::open(&backup_path).unwrap();
+        let mut backup = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::new(backup.meta_page_id());
+        assert_eq!(expected, scan(&mut backup, &btree));
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("heap");
+        let disk = DiskManager::create(&path).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        bufmgr.set_meta_page_id(btree.meta_page_id).unwrap();
+        for i in 0..1000u64 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[i as u8; 100]).unwrap();
+        }
+        let expected = scan(&mut bufmgr, &btree);
+        bufmgr.close().unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        let contents = std::fs::read(&path).unwrap();
+
+        // プールより大きな木を読むので、読み出したページの追い出しも起きる
+        let disk = DiskManager::open_readonly(&path).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        assert!(bufmgr.is_read_only());
+        let btree = BTree::new(bufmgr.meta_page_id());
+        assert_eq!(expected, scan(&mut bufmgr, &btree));
+        let mut iter = btree
+            .search(&mut bufmgr, SearchMode::KeyOrNext(500u64.to_be_bytes().to_vec()))
+            .unwrap();
+        let (key, value) = iter.next(&mut bufmgr).unwrap().unwrap();
+        assert_eq!(&500u64.to_be_bytes(), &key[..]);
+        assert_eq!(vec![500u64 as u8; 100], value);
+        drop(iter);
+        let err = btree.insert(&mut bufmgr, b"new", b"value").unwrap_err();
+        assert!(matches!(err, Error::Buffer(buffer::Error::Disk(disk::Error::ReadOnly))));
+        assert_eq!("disk is opened read-only", err.to_string());
+        bufmgr.close().unwrap();
+
+        let after = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.modified().unwrap(), after.modified().unwrap());
+        assert_eq!(contents, std::fs::read(&path).unwrap());
+    }
+
+    #[test]
+    fn test_delete() {
+        let dir = tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let key = |i: u64| (i * 7919 % 2000).to_be_bytes();
+        let value = |i: u64| vec![i as u8; 200];
+        let mut expected = BTreeMap::new();
+        let check = |bufmgr: &mut BufferPoolManager, expected: &BTreeMap<[u8; 8], Vec<u8>>| {
+            let pairs: Vec<_> = expected
+                .iter()
+                .map(|(key, value)| (key.to_vec(), value.clone()))
+                .collect();
+            assert_eq!(pairs, scan(bufmgr, &btree));
+        };
+
+        // 挿入と削除を交互に行う
+        for i in 0..2000 {
+            btree.insert(&mut bufmgr, &key(i), &value(i)).unwrap();
+            expected.insert(key(i), value(i));
+            if i % 3 == 2 {
+                let k = key(i / 2);
+                assert_eq!(expected.remove(&k).is_some(), btree.delete(&mut bufmgr, &k).unwrap());
+            }
+        }
+        check(&mut bufmgr, &expected);
+        // 消したキーも、もともとないキーも、もう一度は消せない
+        let deleted = key(1);
+        assert!(!expected.contains_key(&deleted));
+        assert!(!btree.delete(&mut bufmgr, &deleted).unwrap());
+        assert!(!btree.delete(&mut bufmgr, &5000u64.to_be_bytes()).unwrap());
+        check(&mut bufmgr, &expected);
+
+        // 消したキーを探すと、その次のキーから始まる
+        let (next_key, _) = expected.range(deleted..).next().unwrap();
+        let mut iter = btree
+            .search(&mut bufmgr, SearchMode::KeyOrNext(deleted.to_vec()))
+            .unwrap();
+        assert_eq!(&next_key[..], &iter.next(&mut bufmgr).unwrap().unwrap().0[..]);
+        drop(iter);
+
+        // すべて消すと、メタページと空のリーフだけが残る
+        let keys: Vec<_> = expected.keys().copied().collect();
+        for (n, k) in keys.iter().enumerate() {
+            assert!(btree.delete(&mut bufmgr, k).unwrap());
+            expected.remove(k);
+            if n % 200 == 0 {
+                check(&mut bufmgr, &expected);
+            }
+        }
+        check(&mut bufmgr, &expected);
+        assert_eq!(2, bufmgr.storage_info().unwrap().num_pages);
+
+        // 解放したページを使って挿入し直せる
+        for i in 0..2000 {
+            btree.insert(&mut bufmgr, &key(i), &value(i)).unwrap();
+            expected.insert(key(i), value(i));
+        }
+        check(&mut bufmgr, &expected);
+        for i in 0..2000 {
+            assert!(btree.delete(&mut bufmgr, &key(i)).unwrap());
+        }
+        assert!(scan(&mut bufmgr, &btree).is_empty());
+    }
+
+    #[test]
+    fn test_update() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        assert!(!btree.update(&mut bufmgr, b"missing", b"value").unwrap());
+        let mut expected = BTreeMap::new();
+        for i in 0..500u64 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[i as u8; 100]).unwrap();
+            expected.insert(i.to_be_bytes().to_vec(), vec![i as u8; 100]);
+        }
+        let pairs = |expected: &BTreeMap<Vec<u8>, Vec<u8>>| -> Vec<_> {
+            expected.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+        };
+
+        // 同じ大きさ、小さい値、大きい値で置き換える
+        for i in 0..500u64 {
+            let value = match i % 3 {
+                0 => vec![0xAA; 100],
+                1 => vec![0xBB; 10],
+                _ => vec![0xCC; 300],
+            };
+            assert!(btree.update(&mut bufmgr, &i.to_be_bytes(), &value).unwrap());
+            expected.insert(i.to_be_bytes().to_vec(), value);
+        }
+        assert_eq!(pairs(&expected), scan(&mut bufmgr, &btree));
+        assert!(!btree.update(&mut bufmgr, &500u64.to_be_bytes(), b"value").unwrap());
+
+        // たくさんのペアがあるリーフで、ページの半分近い値に置き換えて分割させる
+        let before = bufmgr.storage_info().unwrap().num_pages;
+        let value = vec![0xDD; 1800];
+        for i in (0..500u64).step_by(7) {
+            assert!(btree.update(&mut bufmgr, &i.to_be_bytes(), &value).unwrap());
+            expected.insert(i.to_be_bytes().to_vec(), value.clone());
+        }
+        assert!(bufmgr.storage_info().unwrap().num_pages > before);
+        assert_eq!(pairs(&expected), scan(&mut bufmgr, &btree));
+        let (_, found) = btree
+            .search(&mut bufmgr, SearchMode::KeyOrNext(490u64.to_be_bytes().to_vec()))
+            .unwrap()
+            .get(&mut bufmgr)
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, found);
+    }
+
+    // 4つのバッファのプールに、キー0..34に100バイトの値を入れた木を作る
+    // リーフはほぼ埋まっていて、400バイトの値を入れると分割が要る
+    pub(super) fn tree_before_split() -> (BufferPoolManager, BTree) {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(4));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for i in 0..34u64 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[i as u8; 100]).unwrap();
+        }
+        (bufmgr, btree)
+    }
+
+    // ほかのページをピン留めしてプールを埋め、分割に使うページを借りられなくする
+    pub(super) fn pin_free_buffers(bufmgr: &mut BufferPoolManager) -> Vec<PinnedBuffer> {
+        (0..3).map(|_| bufmgr.create_page().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_update_without_free_buffer() {
+        let (mut bufmgr, btree) = tree_before_split();
+        let pinned = pin_free_buffers(&mut bufmgr);
+        let key = 3u64.to_be_bytes();
+        assert!(matches!(
+            btree.update(&mut bufmgr, &key, &[0xEE; 400]),
+            Err(Error::Buffer(buffer::Error::NoFreeBuffer { .. }))
+        ));
+        drop(pinned);
+
+        // 失敗しても前の値は残っている
+        assert_eq!(Some(vec![3; 100]), btree.get(&mut bufmgr, &key).unwrap());
+        assert_eq!(34, btree.len(&mut bufmgr).unwrap());
+        btree.verify(&mut bufmgr).unwrap();
+        assert!(btree.update(&mut bufmgr, &key, &[0xEE; 400]).unwrap());
+        assert_eq!(Some(vec![0xEE; 400]), btree.get(&mut bufmgr, &key).unwrap());
+    }
+
+    fn collect_range(
+        bufmgr: &mut BufferPoolManager,
+        btree: &BTree,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Vec<Vec<u8>> {
+        let mut iter = btree.scan_range(bufmgr, start, end).unwrap();
+        let mut keys = vec![];
+        while let Some((key, _)) = iter.next(bufmgr).unwrap() {
+            keys.push(key);
+        }
+        // 一度止まったら、そのあともNoneを返す
+        assert!(iter.next(bufmgr).unwrap().is_none());
+        keys
+    }
+
+    #[test]
+    fn test_scan_range() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        // 1つのリーフに数個しか入らないよう、値を大きくする
+        let keys: Vec<_> = (0..100u64).map(|i| (i * 2).to_be_bytes().to_vec()).collect();
+        for key in &keys {
+            btree.insert(&mut bufmgr, key, &[0; 500]).unwrap();
+        }
+        let expected = |start: Bound<&[u8]>, end: Bound<&[u8]>| -> Vec<Vec<u8>> {
+            let set: BTreeSet<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+            set.range::<[u8], _>((start, end)).map(|key| key.to_vec()).collect()
+        };
+
+        // 2つめのリーフの先頭と、1つめのリーフの末尾のキー
+        let (last, first) = {
+            let iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+            let (last, next_page_id) = {
+                let node = node::Node::new(iter.buffer.data());
+                let leaf = leaf::Leaf::new(node.body);
+                let last = leaf.pair_at(leaf.num_pairs() - 1).key.to_vec();
+                (last, leaf.next_page_id().unwrap())
+            };
+            drop(iter);
+            let buffer = bufmgr.fetch_page(next_page_id).unwrap();
+            let node = node::Node::new(buffer.data());
+            let first = leaf::Leaf::new(node.body).pair_at(0).key.to_vec();
+            (last, first)
+        };
+        for boundary in [&last[..], &first[..]] {
+            for end in [Bound::Included(boundary), Bound::Excluded(boundary)] {
+                for start in [Bound::Unbounded, Bound::Included(&keys[1][..])] {
+                    assert_eq!(expected(start, end), collect_range(&mut bufmgr, &btree, start, end));
+                }
+            }
+        }
+        assert_eq!(
+            expected(Bound::Excluded(&last), Bound::Included(&first)),
+            collect_range(&mut bufmgr, &btree, Bound::Excluded(&last), Bound::Included(&first))
+        );
+        assert_eq!(keys, collect_range(&mut bufmgr, &btree, Bound::Unbounded, Bound::Unbounded));
+
+        // 存在しないキーを境界にする
+        let odd = |i: u64| i.to_be_bytes();
+        for (start, end) in [(odd(11), odd(51)), (odd(0), odd(150)), (odd(195), odd(301))] {
+            for start in [Bound::Included(&start[..]), Bound::Excluded(&start[..])] {
+                for end in [Bound::Included(&end[..]), Bound::Excluded(&end[..])] {
+                    assert_eq!(expected(start, end), collect_range(&mut bufmgr, &btree, start, end));
+                }
+            }
+        }
+
+        // 空の範囲
+        let k = |i: u64| i.to_be_bytes();
+        let (k0, k10, k11, k12, k20, k50, k300) = (k(0), k(10), k(11), k(12), k(20), k(50), k(300));
+        let empty = [
+            (Bound::Included(&k10[..]), Bound::Excluded(&k10[..])),
+            (Bound::Excluded(&k10[..]), Bound::Included(&k10[..])),
+            (Bound::Excluded(&k10[..]), Bound::Excluded(&k12[..])),
+            (Bound::Included(&k11[..]), Bound::Included(&k11[..])),
+            (Bound::Included(&k300[..]), Bound::Unbounded),
+            (Bound::Unbounded, Bound::Excluded(&k0[..])),
+            // 始まりが終わりより後ろ
+            (Bound::Included(&k50[..]), Bound::Included(&k20[..])),
+        ];
+        for (start, end) in empty {
+            assert!(collect_range(&mut bufmgr, &btree, start, end).is_empty());
+        }
+        assert_eq!(
+            vec![k(10).to_vec()],
+            collect_range(&mut bufmgr, &btree, Bound::Included(&k(10)), Bound::Included(&k(10)))
+        );
+    }
+
+    #[test]
+    fn test_scan_range_prefix() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for key in [&b"ab"[..], b"abc", b"abc\0", b"abcd", b"abd"] {
+            btree.insert(&mut bufmgr, key, b"").unwrap();
+        }
+        let keys = |keys: &[&[u8]]| -> Vec<Vec<u8>> { keys.iter().map(|key| key.to_vec()).collect() };
+        assert_eq!(
+            keys(&[b"ab", b"abc"]),
+            collect_range(&mut bufmgr, &btree, Bound::Unbounded, Bound::Included(b"abc"))
+        );
+        assert_eq!(
+            keys(&[b"ab"]),
+            collect_range(&mut bufmgr, &btree, Bound::Unbounded, Bound::Excluded(b"abc"))
+        );
+        assert_eq!(
+            keys(&[b"abc\0", b"abcd"]),
+            collect_range(&mut bufmgr, &btree, Bound::Excluded(b"abc"), Bound::Excluded(b"abd"))
+        );
+        assert_eq!(
+            keys(&[b"abc", b"abc\0"]),
+            collect_range(&mut bufmgr, &btree, Bound::Included(b"abc"), Bound::Included(b"abc\0"))
+        );
+    }
+
+    fn collect_desc(
+        bufmgr: &mut BufferPoolManager,
+        btree: &BTree,
+        search_mode: SearchMode,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut iter = btree.search_desc(bufmgr, search_mode).unwrap();
+        let mut pairs = vec![];
+        while let Some(pair) = iter.next(bufmgr).unwrap() {
+            pairs.push(pair);
+        }
+        pairs
+    }
+
+    #[test]
+    fn test_search_desc() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        assert!(collect_desc(&mut bufmgr, &btree, SearchMode::End).is_empty());
+        assert!(collect_desc(&mut bufmgr, &btree, SearchMode::Start).is_empty());
+
+        // 多くのリーフにまたがるよう、値を大きくして散らした順に挿入する
+        for i in 0..1000u64 {
+            let key = (i * 7919 % 1000 * 2).to_be_bytes();
+            btree.insert(&mut bufmgr, &key, &[i as u8; 300]).unwrap();
+        }
+        let mut descending = scan(&mut bufmgr, &btree);
+        assert_eq!(1000, descending.len());
+        descending.reverse();
+        assert_eq!(descending, collect_desc(&mut bufmgr, &btree, SearchMode::End));
+
+        // あるキーからはそのキーを含めて、ないキーからはその手前から始める
+        let from = |i: u64| SearchMode::KeyOrPrev(i.to_be_bytes().to_vec());
+        assert_eq!(&descending[500..], &collect_desc(&mut bufmgr, &btree, from(998))[..]);
+        assert_eq!(&descending[500..], &collect_desc(&mut bufmgr, &btree, from(999))[..]);
+        assert_eq!(&descending[..], &collect_desc(&mut bufmgr, &btree, from(5000))[..]);
+        assert_eq!(&descending[999..], &collect_desc(&mut bufmgr, &btree, from(0))[..]);
+        assert_eq!(&descending[999..], &collect_desc(&mut bufmgr, &btree, SearchMode::Start)[..]);
+        // 前向きに走査するときには、Endからは何も返さない
+        let mut iter = btree.search(&mut bufmgr, SearchMode::End).unwrap();
+        assert!(iter.next(&mut bufmgr).unwrap().is_none());
+
+        // 後ろから数件だけ読む
+        let mut iter = btree.search_desc(&mut bufmgr, SearchMode::End).unwrap();
+        let latest: Vec<_> = (0..3).map(|_| iter.next(&mut bufmgr).unwrap().unwrap().0).collect();
+        let expected: Vec<_> = [1998u64, 1996, 1994]
+            .iter()
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect();
+        assert_eq!(expected, latest);
+    }
+
+    #[test]
+    fn test_get() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let clock = Rc::new(MockClock::new());
+        bufmgr.enable_leak_detection(clock.clone());
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let key = |i: u64| i.to_be_bytes();
+        assert_eq!(None, btree.get(&mut bufmgr, &key(0)).unwrap());
+        assert!(!btree.contains_key(&mut bufmgr, &key(0)).unwrap());
+
+        for i in (0..1000u64).step_by(2) {
+            btree.insert(&mut bufmgr, &key(i), &[i as u8; 100]).unwrap();
+        }
+        for i in [0u64, 2, 500, 998] {
+            assert_eq!(Some(vec![i as u8; 100]), btree.get(&mut bufmgr, &key(i)).unwrap());
+            assert!(btree.contains_key(&mut bufmgr, &key(i)).unwrap());
+        }
+        // 既存のキーの間と、最大のキーより後ろ
+        for i in [1u64, 501, 999, 5000] {
+            assert_eq!(None, btree.get(&mut bufmgr, &key(i)).unwrap());
+            assert!(!btree.contains_key(&mut bufmgr, &key(i)).unwrap());
+        }
+        // 前方一致するだけのキーは一致しない
+        assert_eq!(None, btree.get(&mut bufmgr, &key(2)[..7]).unwrap());
+        clock.advance(Duration::from_secs(1));
+        assert!(bufmgr.pinned_longer_than(Duration::ZERO).is_empty());
+    }
+
+    fn collect_prefix(
+        bufmgr: &mut BufferPoolManager,
+        btree: &BTree,
+        prefix: &[u8],
+    ) -> Vec<Vec<u8>> {
+        let mut iter = btree.scan_prefix(bufmgr, prefix).unwrap();
+        let mut keys = vec![];
+        while let Some((key, _)) = iter.next(bufmgr).unwrap() {
+            keys.push(key);
+        }
+        keys
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(Some(b"abd".to_vec()), prefix_end(b"abc"));
+        assert_eq!(Some(b"b".to_vec()), prefix_end(b"a\xFF\xFF"));
+        assert_eq!(None, prefix_end(b"\xFF\xFF"));
+        assert_eq!(None, prefix_end(b""));
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let mut keys = vec![];
+        for key in [&b"a"[..], b"ab", b"abc", b"abd", b"b", b"\xFF", b"\xFF\xFF\x01"] {
+            btree.insert(&mut bufmgr, key, b"").unwrap();
+            keys.push(key.to_vec());
+        }
+        let keys_of = |keys: &[&[u8]]| -> Vec<Vec<u8>> {
+            keys.iter().map(|key| key.to_vec()).collect()
+        };
+        // キーそのものも前方一致に含む
+        assert_eq!(keys_of(&[b"ab", b"abc", b"abd"]), collect_prefix(&mut bufmgr, &btree, b"ab"));
+        assert_eq!(keys_of(&[b"abc"]), collect_prefix(&mut bufmgr, &btree, b"abc"));
+        assert_eq!(keys_of(&[b"b"]), collect_prefix(&mut bufmgr, &btree, b"b"));
+        assert!(collect_prefix(&mut bufmgr, &btree, b"abcd").is_empty());
+        assert!(collect_prefix(&mut bufmgr, &btree, b"c").is_empty());
+        assert_eq!(
+            keys_of(&[b"\xFF", b"\xFF\xFF\x01"]),
+            collect_prefix(&mut bufmgr, &btree, b"\xFF")
+        );
+        assert_eq!(keys, collect_prefix(&mut bufmgr, &btree, b""));
+    }
+
+    #[test]
+    fn test_scan_prefix_tuple() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        // 先頭の要素が"ab"のタプルは多くのリーフにまたがる
+        let encode = |elems: &[&[u8]]| {
+            let mut bytes = vec![];
+            crate::tuple::encode(elems.iter(), &mut bytes);
+            bytes
+        };
+        let firsts = [&b"a"[..], b"ab", b"abc", b"b"];
+        for first in firsts {
+            for i in 0..300u64 {
+                let key = encode(&[first, &i.to_be_bytes()]);
+                btree.insert(&mut bufmgr, &key, &[0; 100]).unwrap();
+            }
+        }
+        // 要素の途中までの前方一致にはならないので、"abc"で始まるタプルは含まない
+        for first in firsts {
+            let keys = collect_prefix(&mut bufmgr, &btree, &encode(&[first]));
+            let expected: Vec<_> = (0..300u64)
+                .map(|i| encode(&[first, &i.to_be_bytes()]))
+                .collect();
+            assert_eq!(expected, keys);
+        }
+        // 2つの要素で絞り込む
+        let key = encode(&[b"ab", &7u64.to_be_bytes()]);
+        assert_eq!(vec![key.clone()], collect_prefix(&mut bufmgr, &btree, &key));
+    }
+
+    #[test]
+    fn test_multi() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let clock = Rc::new(MockClock::new());
+        bufmgr.enable_leak_detection(clock.clone());
+        let unique = BTree::create(&mut bufmgr).unwrap();
+        assert_eq!(KeyMode::Unique, unique.key_mode(&mut bufmgr).unwrap());
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            ..BTreeOptions::default()
+        };
+        let btree = BTree::create_with(&mut bufmgr, options).unwrap();
+        assert_eq!(KeyMode::Multi, btree.key_mode(&mut bufmgr).unwrap());
+
+        let key = |i: u64| format!("key{}", i % 10).into_bytes();
+        // 分割が起きるよう、値を長くしておく
+        let value = |i: u64| [&i.to_be_bytes()[..], &[0; 40]].concat();
+        let mut expected = BTreeSet::new();
+        for i in 0..1000u64 {
+            btree.insert(&mut bufmgr, &key(i), &value(i)).unwrap();
+            expected.insert((key(i), value(i)));
+        }
+        assert!(matches!(
+            btree.insert(&mut bufmgr, &key(3), &value(3)),
+            Err(Error::DuplicateKey)
+        ));
+        assert!(matches!(
+            btree.update(&mut bufmgr, &key(3), &value(3)),
+            Err(Error::UnsupportedOnMulti(_))
+        ));
+        assert_eq!(
+            expected.iter().cloned().collect::<Vec<_>>(),
+            scan(&mut bufmgr, &btree)
+        );
+        for k in 0..10u64 {
+            let values: Vec<_> =
+                btree.get_all(&mut bufmgr, &key(k)).unwrap().map(Result::unwrap).collect();
+            let want: Vec<_> = (k..1000).step_by(10).map(value).collect();
+            assert_eq!(want, values);
+        }
+        // 値の最も小さいものを返す
+        assert_eq!(Some(value(3)), btree.get(&mut bufmgr, &key(3)).unwrap());
+        assert_eq!(0, btree.get_all(&mut bufmgr, b"key").unwrap().count());
+
+        // 範囲の端のキーは、そのキーのペアをすべて含むか、すべて除く
+        let keys = collect_range(
+            &mut bufmgr,
+            &btree,
+            Bound::Excluded(&key(3)),
+            Bound::Included(&key(5)),
+        );
+        assert_eq!(200, keys.len());
+        assert_eq!((key(4), key(5)), (keys[0].clone(), keys[199].clone()));
+        assert_eq!(100, collect_prefix(&mut bufmgr, &btree, b"key5").len());
+        let desc = collect_desc(&mut bufmgr, &btree, SearchMode::KeyOrPrev(key(5)));
+        assert_eq!(600, desc.len());
+        assert_eq!((key(5), value(995)), desc[0]);
+
+        // キーと値を指定して1つだけ削除する
+        assert!(btree.delete_pair(&mut bufmgr, &key(7), &value(57)).unwrap());
+        assert!(!btree.delete_pair(&mut bufmgr, &key(7), &value(57)).unwrap());
+        assert!(!btree.delete_pair(&mut bufmgr, &key(7), &value(58)).unwrap());
+        expected.remove(&(key(7), value(57)));
+        let values: Vec<_> =
+            btree.get_all(&mut bufmgr, &key(7)).unwrap().map(Result::unwrap).collect();
+        assert_eq!(99, values.len());
+        assert!(!values.contains(&value(57)));
+        for k in (0..10u64).filter(|&k| k != 7) {
+            assert_eq!(100, btree.get_all(&mut bufmgr, &key(k)).unwrap().count());
+        }
+
+        // キーだけを指定すると、そのキーのペアをすべて削除する
+        assert!(btree.delete(&mut bufmgr, &key(2)).unwrap());
+        assert!(!btree.delete(&mut bufmgr, &key(2)).unwrap());
+        expected.retain(|(k, _)| k != &key(2));
+        assert_eq!(
+            expected.into_iter().collect::<Vec<_>>(),
+            scan(&mut bufmgr, &btree)
+        );
+
+        // メタページに記録しているので、開き直しても重複を許す
+        let reopened = BTree::new(btree.meta_page_id);
+        assert_eq!(KeyMode::Multi, reopened.key_mode(&mut bufmgr).unwrap());
+        clock.advance(Duration::from_secs(1));
+        assert!(bufmgr.pinned_longer_than(Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let clock = Rc::new(MockClock::new());
+        bufmgr.enable_leak_detection(clock.clone());
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+
+        let key = |i: u64| i.to_be_bytes();
+        let sizes = [1024, disk::PAGE_SIZE, 1 << 20];
+        // 大きな値と小さな値を交互に入れる
+        let value = |i: u64| match i % 2 {
+            0 => vec![i as u8; sizes[(i / 2 % 3) as usize]],
+            _ => vec![i as u8; 10],
+        };
+        for i in 0..12u64 {
+            btree.insert(&mut bufmgr, &key(i), &value(i)).unwrap();
+        }
+        for i in 0..12u64 {
+            assert_eq!(Some(value(i)), btree.get(&mut bufmgr, &key(i)).unwrap());
+        }
+        let expected: Vec<_> = (0..12u64).map(|i| (key(i).to_vec(), value(i))).collect();
+        assert_eq!(expected, scan(&mut bufmgr, &btree));
+        let mut desc = collect_desc(&mut bufmgr, &btree, SearchMode::End);
+        desc.reverse();
+        assert_eq!(expected, desc);
+        // キーが重複すれば、書き込んだオーバーフローページを解放する
+        let used_pages = bufmgr.storage_info().unwrap().num_pages;
+        assert!(matches!(
+            btree.insert(&mut bufmgr, &key(4), &value(4)),
+            Err(Error::DuplicateKey)
+        ));
+        assert_eq!(used_pages, bufmgr.storage_info().unwrap().num_pages);
+
+        // 置き換えた前の値と、削除した値のオーバーフローページを解放する
+        assert!(btree.update(&mut bufmgr, &key(4), b"small").unwrap());
+        assert_eq!(Some(b"small".to_vec()), btree.get(&mut bufmgr, &key(4)).unwrap());
+        assert!(btree.update(&mut bufmgr, &key(5), &value(4)).unwrap());
+        assert_eq!(Some(value(4)), btree.get(&mut bufmgr, &key(5)).unwrap());
+        for i in 0..12u64 {
+            assert!(btree.delete(&mut bufmgr, &key(i)).unwrap());
+        }
+        assert_eq!(num_pages, bufmgr.storage_info().unwrap().num_pages);
+        // 解放したページを使い回すので、ページは採番されない
+        let next_page_id = bufmgr.storage_info().unwrap().next_page_id;
+        btree.insert(&mut bufmgr, &key(0), &value(4)).unwrap();
+        assert_eq!(next_page_id, bufmgr.storage_info().unwrap().next_page_id);
+        assert_eq!(Some(value(4)), btree.get(&mut bufmgr, &key(0)).unwrap());
+        clock.advance(Duration::from_secs(1));
+        assert!(bufmgr.pinned_longer_than(Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn test_corrupted_overflow_chain() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let format = btree.format(&mut bufmgr).unwrap();
+        let capacity = overflow::capacity(format.page_size);
+        let page_ids: Vec<_> = (0..2)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        let link = |bufmgr: &mut BufferPoolManager, page_id, next_page_id| {
+            let buffer = bufmgr.fetch_page_write(page_id).unwrap();
+            overflow::Overflow::new(buffer.data_mut()).set_next_page_id(next_page_id);
+        };
+        let stored = |len| {
+            StoredValue::Overflow {
+                page_id: page_ids[0],
+                len,
+            }
+            .to_bytes()
+        };
+        link(&mut bufmgr, page_ids[0], Some(page_ids[1]));
+        link(&mut bufmgr, page_ids[1], None);
+        let value = format.load_value(&mut bufmgr, &stored(2 * capacity)).unwrap();
+        assert_eq!(2 * capacity, value.len());
+
+        // 連なりが長さより先に終われば、短い値を返さない
+        assert!(matches!(
+            format.load_value(&mut bufmgr, &stored(2 * capacity + 1)),
+            Err(Error::CorruptedValue { .. })
+        ));
+        // 長さに達しても続いていれば、輪になっていても止まる
+        assert!(matches!(
+            format.load_value(&mut bufmgr, &stored(capacity)),
+            Err(Error::CorruptedValue { .. })
+        ));
+        link(&mut bufmgr, page_ids[1], Some(page_ids[0]));
+        assert!(matches!(
+            format.load_value(&mut bufmgr, &stored(3 * capacity)),
+            Err(Error::CorruptedValue { .. })
+        ));
+    }
+
+    #[test]
+    fn test_overflow_without_free_buffer() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(4));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        btree.insert(&mut bufmgr, b"small", b"value").unwrap();
+        // メタページと根のほかは1つしか借りられず、連なりの2ページ目を作れない
+        let root_page_id = btree.meta(&mut bufmgr).unwrap().root_page_id;
+        let pinned = [
+            bufmgr.fetch_page(btree.meta_page_id).unwrap(),
+            bufmgr.fetch_page(root_page_id).unwrap(),
+            bufmgr.create_page().unwrap(),
+        ];
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        assert!(matches!(
+            btree.insert(&mut bufmgr, b"large", &vec![1; 3 * disk::PAGE_SIZE]),
+            Err(Error::Buffer(buffer::Error::NoFreeBuffer { .. }))
+        ));
+        // 作りかけの連なりのページは解放してある
+        assert_eq!(num_pages, bufmgr.storage_info().unwrap().num_pages);
+        drop(pinned);
+        btree.insert(&mut bufmgr, b"large", &vec![1; 3 * disk::PAGE_SIZE]).unwrap();
+        assert!(bufmgr.storage_info().unwrap().num_pages > num_pages);
+    }
+
+    #[test]
+    fn test_too_large_entry() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for i in 0u64..100 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[i as u8; 100]).unwrap();
+        }
+        // 大きな値はオーバーフローページに置くので収まる
+        btree.insert(&mut bufmgr, b"large", &vec![1; 5 * disk::PAGE_SIZE]).unwrap();
+        let result = btree.insert(&mut bufmgr, &vec![0xFF; disk::PAGE_SIZE], b"");
+        assert!(
+            matches!(
+                result,
+                Err(Error::TooLargeEntry {
+                    key_len: disk::PAGE_SIZE,
+                    value_len: 0,
+                    ..
+                })
+            ),
+            "{:?}",
+            result
+        );
+        // 値が小さければ、キーは枝のペアとして収まるかで決まる
+        let max = branch::max_pair_size(buffer::PAGE_DATA_SIZE - size_of::<node::Header>());
+        let key_len = (0..disk::PAGE_SIZE)
+            .rev()
+            .find(|&len| Pair::encoded_len(len, size_of::<PageId>()) <= max)
+            .unwrap();
+        match btree.insert(&mut bufmgr, &vec![0xFF; key_len + 1], b"") {
+            Err(Error::TooLargeEntry {
+                key_len: too_large,
+                value_len: 0,
+                max: found,
+            }) => assert_eq!((key_len + 1, max), (too_large, found)),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        btree.verify(&mut bufmgr).unwrap();
+        assert_eq!(101, btree.len(&mut bufmgr).unwrap());
+        btree.insert(&mut bufmgr, &vec![0xFF; key_len], b"").unwrap();
+        btree.insert(&mut bufmgr, &vec![0xFE; key_len], b"").unwrap();
+        btree.verify(&mut bufmgr).unwrap();
+        assert_eq!(103, btree.len(&mut bufmgr).unwrap());
+
+        // 値をそのままリーフに置く木では、値の大きさも確かめる
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        {
+            let meta_buffer = bufmgr.fetch_page_write(btree.meta_page_id).unwrap();
+            meta::Meta::new(meta_buffer.data_mut()).header.node_version = 0;
+        }
+        btree.insert(&mut bufmgr, b"key", b"value").unwrap();
+        let max = match btree.update(&mut bufmgr, b"key", &vec![0; disk::PAGE_SIZE]) {
+            Err(Error::TooLargeEntry { max, .. }) => max,
+            result => panic!("unexpected result: {:?}", result),
+        };
+        let value_len = (0..disk::PAGE_SIZE)
+            .rev()
+            .find(|&len| Pair::encoded_len(3, len) <= max)
             .unwrap();
+        let too_large = vec![0; value_len + 1];
+        for result in [
+            btree.update(&mut bufmgr, b"key", &too_large).map(|_| ()),
+            btree.upsert(&mut bufmgr, b"key", &too_large).map(|_| ()),
+            btree.insert(&mut bufmgr, b"new", &too_large),
+        ] {
+            assert!(matches!(result, Err(Error::TooLargeEntry { .. })), "{:?}", result);
+        }
+        assert_eq!(Some(b"value".to_vec()), btree.get(&mut bufmgr, b"key").unwrap());
+        btree.verify(&mut bufmgr).unwrap();
+        let value = vec![1; value_len];
+        assert!(btree.update(&mut bufmgr, b"key", &value).unwrap());
+        btree.insert(&mut bufmgr, b"new", &value).unwrap();
+        assert_eq!(Some(value), btree.get(&mut bufmgr, b"key").unwrap());
+        btree.verify(&mut bufmgr).unwrap();
+    }
+
+    #[test]
+    fn test_delete_current() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let value = |i: u64| match i % 500 {
+            // オーバーフローページに置く値も混ぜる
+            0 => vec![i as u8; 3 * disk::PAGE_SIZE],
+            _ => vec![i as u8; 50],
+        };
+        for i in 0u64..2000 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &value(i)).unwrap();
+        }
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        // 3の倍数と、いくつかのリーフをまるごと空にする範囲を削除する
+        let expired = |i: u64| i % 3 == 0 || (600..900).contains(&i);
+
+        let mut iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+        assert!(!iter.delete_current(&mut bufmgr).unwrap());
+        let mut seen = vec![];
+        while let Some((key, found)) = iter.next(&mut bufmgr).unwrap() {
+            let i = u64::from_be_bytes(key[..].try_into().unwrap());
+            assert_eq!(value(i), found);
+            seen.push(i);
+            if expired(i) {
+                assert!(iter.delete_current(&mut bufmgr).unwrap());
+                assert!(!iter.delete_current(&mut bufmgr).unwrap());
+            }
+        }
+        drop(iter);
+        // 削除しても、どのペアも読み飛ばさない
+        assert_eq!((0u64..2000).collect::<Vec<_>>(), seen);
+
+        btree.verify(&mut bufmgr).unwrap();
+        let survivors: Vec<_> = (0u64..2000).filter(|&i| !expired(i)).collect();
+        assert_eq!(survivors.len() as u64, btree.len(&mut bufmgr).unwrap());
+        let keys: Vec<_> = scan(&mut bufmgr, &btree)
+            .into_iter()
+            .map(|(key, _)| u64::from_be_bytes(key[..].try_into().unwrap()))
+            .collect();
+        assert_eq!(survivors, keys);
+        assert!(btree.get(&mut bufmgr, &1500u64.to_be_bytes()).unwrap().is_none());
+        // 削除した値のオーバーフローページは解放する
+        assert!(bufmgr.storage_info().unwrap().num_pages < num_pages);
+
+        // 空にしたリーフはvacuumでまとめる
+        assert!(btree.vacuum(&mut bufmgr).unwrap().pages_freed > 0);
+        btree.verify(&mut bufmgr).unwrap();
+        assert_eq!(survivors.len() as u64, btree.len(&mut bufmgr).unwrap());
+    }
+
+    #[test]
+    fn test_node_version_0() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        // 版を記録する前に作った木では、値をそのままリーフに置く
+        {
+            let meta_buffer = bufmgr.fetch_page_write(btree.meta_page_id).unwrap();
+            meta::Meta::new(meta_buffer.data_mut()).header.node_version = 0;
+        }
+        btree.insert(&mut bufmgr, b"key", b"value").unwrap();
+        assert_eq!(Some(b"value".to_vec()), btree.get(&mut bufmgr, b"key").unwrap());
+        let iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+        assert_eq!(Some(b"value".to_vec()), iter.with_pair(|_, value| value.to_vec()));
+        drop(iter);
+        assert!(btree.update(&mut bufmgr, b"key", b"other").unwrap());
+        assert!(btree.delete(&mut bufmgr, b"key").unwrap());
+        assert_eq!(None, btree.get(&mut bufmgr, b"key").unwrap());
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let key = |i: u64| (i * 7).to_be_bytes().to_vec();
+        let value = |i: u64| match i {
+            // オーバーフローページに置く値も混ぜる
+            500 => vec![0xAB; 3 * disk::PAGE_SIZE],
+            _ => vec![i as u8; 1 + (i % 50) as usize],
+        };
+        let pairs: Vec<_> = (0..5000u64).map(|i| (key(i), value(i))).collect();
+
+        let mut bufmgr = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let inserted = BTree::create(&mut bufmgr).unwrap();
+        bufmgr.reset_stats();
+        let access_seq = bufmgr.stats().access_seq;
+        for (key, value) in &pairs {
+            inserted.insert(&mut bufmgr, key, value).unwrap();
+        }
+        let insert_accesses = bufmgr.stats().access_seq - access_seq;
+
+        let access_seq = bufmgr.stats().access_seq;
+        let loaded = BTree::bulk_load(&mut bufmgr, pairs.clone()).unwrap();
+        let bulk_accesses = bufmgr.stats().access_seq - access_seq;
+        // 挿入のたびに根から降りないので、読み書きするページはずっと少ない
+        assert!(
+            bulk_accesses * 10 < insert_accesses,
+            "bulk load accessed {} pages, inserts accessed {}",
+            bulk_accesses,
+            insert_accesses
+        );
+
+        loaded.verify(&mut bufmgr).unwrap();
+        inserted.verify(&mut bufmgr).unwrap();
+        assert_eq!(pairs, scan(&mut bufmgr, &loaded));
+        assert_eq!(pairs, scan(&mut bufmgr, &inserted));
+        let mut desc = collect_desc(&mut bufmgr, &loaded, SearchMode::End);
+        desc.reverse();
+        assert_eq!(pairs, desc);
+        for i in [0u64, 1, 500, 2500, 4999] {
+            assert_eq!(Some(value(i)), loaded.get(&mut bufmgr, &key(i)).unwrap());
+            // 既存のキーの間
+            assert_eq!(None, loaded.get(&mut bufmgr, &(i * 7 + 1).to_be_bytes()).unwrap());
+        }
+        // 作ったあとも挿入や削除ができる
+        for i in 0..5000u64 {
+            loaded.insert(&mut bufmgr, &(i * 7 + 3).to_be_bytes(), b"new").unwrap();
+        }
+        for i in (0..5000u64).step_by(2) {
+            assert!(loaded.delete(&mut bufmgr, &key(i)).unwrap());
+        }
+        assert_eq!(7500, scan(&mut bufmgr, &loaded).len());
+        assert_eq!(Some(value(501)), loaded.get(&mut bufmgr, &key(501)).unwrap());
+
+        // 空の入力からは空の木を作る
+        let empty = BTree::bulk_load(&mut bufmgr, vec![]).unwrap();
+        assert!(scan(&mut bufmgr, &empty).is_empty());
+        empty.insert(&mut bufmgr, b"key", b"value").unwrap();
+        assert_eq!(1, scan(&mut bufmgr, &empty).len());
+    }
+
+    #[test]
+    fn test_bulk_load_fill_factor() {
+        let pairs: Vec<_> = (0..2000u64)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 20]))
+            .collect();
+        let mut bufmgr = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let mut num_pages = vec![];
+        for &fill_factor in &[1.0, 0.5] {
+            let before = bufmgr.storage_info().unwrap().num_pages;
+            let options = BulkLoadOptions {
+                fill_factor,
+                ..BulkLoadOptions::default()
+            };
+            let btree = BTree::bulk_load_with(&mut bufmgr, pairs.clone(), options).unwrap();
+            assert_eq!(pairs, scan(&mut bufmgr, &btree));
+            num_pages.push(bufmgr.storage_info().unwrap().num_pages - before);
+        }
+        // 半分しか詰めなければ、リーフはおよそ2倍になる
+        assert!(2 * num_pages[1] > 3 * num_pages[0], "{:?}", num_pages);
+
+        // どのノードにも入らない割合でも、リーフには1つ、枝には2つずつ詰めて作り終える
+        let options = BulkLoadOptions {
+            fill_factor: 0.001,
+            ..BulkLoadOptions::default()
+        };
+        let btree = BTree::bulk_load_with(&mut bufmgr, pairs[..100].to_vec(), options).unwrap();
+        btree.verify(&mut bufmgr).unwrap();
+        assert_eq!(pairs[..100].to_vec(), scan(&mut bufmgr, &btree));
+        let stats = btree.stats(&mut bufmgr).unwrap();
+        assert_eq!((100, 8), (stats.leaf_pages, stats.height));
+
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        for fill_factor in [0.0, -0.5, 1.5, f64::NAN] {
+            let options = BulkLoadOptions {
+                fill_factor,
+                ..BulkLoadOptions::default()
+            };
+            assert!(matches!(
+                BTree::bulk_load_with(&mut bufmgr, pairs.clone(), options),
+                Err(Error::InvalidLoadFactor(_))
+            ));
+        }
+        assert_eq!(num_pages, bufmgr.storage_info().unwrap().num_pages);
+    }
+
+    // allocationsの数だけページを採番したら、それより後の採番に失敗するストレージ
+    #[derive(Debug)]
+    struct LimitedStorage {
+        disk: MemoryDiskManager,
+        allocations: usize,
+    }
+
+    impl Storage for LimitedStorage {
+        fn read_page_data(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
+            self.disk.read_page_data(page_id, data)
+        }
+
+        fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
+            self.disk.write_page_data(page_id, data)
+        }
+
+        fn page_size(&self) -> usize {
+            self.disk.page_size()
+        }
+
+        fn allocate_page(&mut self) -> io::Result<PageId> {
+            if self.allocations == 0 {
+                return Err(io::Error::from(io::ErrorKind::OutOfMemory));
+            }
+            self.allocations -= 1;
+            self.disk.allocate_page()
+        }
+
+        fn deallocate_page(&mut self, page_id: PageId) -> io::Result<()> {
+            self.disk.deallocate_page(page_id)
+        }
+
+        fn meta_page_id(&self) -> PageId {
+            self.disk.meta_page_id()
+        }
+
+        fn set_meta_page_id(&mut self, meta_page_id: PageId) -> io::Result<()> {
+            self.disk.set_meta_page_id(meta_page_id)
+        }
+
+        fn num_pages(&self) -> u64 {
+            self.disk.num_pages()
+        }
+
+        fn next_page_id(&self) -> PageId {
+            self.disk.next_page_id()
+        }
+
+        fn file_size_bytes(&self) -> io::Result<u64> {
+            self.disk.file_size_bytes()
+        }
+
+        fn free_page_ids(&mut self) -> io::Result<Vec<PageId>> {
+            self.disk.free_page_ids()
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn sync_data(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_bulk_load_branch_error() {
+        let pairs: Vec<_> = (0..2000u64)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 20]))
+            .collect();
+        let mut bufmgr = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let btree = BTree::bulk_load(&mut bufmgr, pairs.clone()).unwrap();
+        let leaf_pages = btree.stats(&mut bufmgr).unwrap().leaf_pages as usize;
+
+        // メタページとリーフは採番できても枝を採番できなければ、リーフも解放する
+        let disk = LimitedStorage {
+            disk: MemoryDiskManager::new(),
+            allocations: 1 + leaf_pages,
+        };
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        assert!(matches!(BTree::bulk_load(&mut bufmgr, pairs), Err(Error::Buffer(_))));
+        assert_eq!(0, bufmgr.storage_info().unwrap().num_pages);
+    }
+
+    #[test]
+    fn test_bulk_load_unsorted() {
+        let mut bufmgr = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        let key = |i: u64| i.to_be_bytes().to_vec();
+        for unsorted in [vec![1u64, 3, 2], vec![1, 2, 2]] {
+            let mut pairs: Vec<_> = (0..1000u64)
+                .map(|i| (key(i), vec![0; disk::PAGE_SIZE / 2]))
+                .collect();
+            pairs.extend(unsorted.iter().map(|&i| (key(1000 + i), vec![])));
+            match BTree::bulk_load(&mut bufmgr, pairs) {
+                Err(Error::UnsortedKeys { prev_key, key: found }) => {
+                    assert_eq!(key(1000 + unsorted[1]), prev_key);
+                    assert_eq!(key(1000 + unsorted[2]), found);
+                }
+                result => panic!("unexpected result: {:?}", result.map(|_| ())),
+            }
+            // 作りかけのリーフとオーバーフローページは解放する
+            assert_eq!(num_pages, bufmgr.storage_info().unwrap().num_pages);
+        }
+    }
+
+    // 木をたどって、ノードのキーの範囲と使用量、リーフのつながりを確かめ、木の高さを返す
+    fn check_tree(bufmgr: &mut BufferPoolManager, btree: &BTree) -> usize {
+        btree.verify(bufmgr).unwrap();
+        let root_page_id = {
+            let meta_buffer = bufmgr.fetch_page_read(btree.meta_page_id).unwrap();
+            let meta = meta::Meta::new(meta_buffer.data());
+            meta.header.root_page_id
+        };
+        let mut leaves = vec![];
+        let height = check_node(bufmgr, root_page_id, (None, None), true, &mut leaves);
+        for (i, &page_id) in leaves.iter().enumerate() {
+            let buffer = bufmgr.fetch_page_read(page_id).unwrap();
+            let node = node::Node::new(buffer.data());
+            let leaf = leaf::Leaf::new(node.body);
+            assert_eq!(i.checked_sub(1).map(|prev| leaves[prev]), leaf.prev_page_id());
+            assert_eq!(leaves.get(i + 1).copied(), leaf.next_page_id());
+        }
+        height
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn check_node(
+        bufmgr: &mut BufferPoolManager,
+        page_id: PageId,
+        (lower, upper): (Option<Vec<u8>>, Option<Vec<u8>>),
+        is_root: bool,
+        leaves: &mut Vec<PageId>,
+    ) -> usize {
+        let in_range = |key: &[u8]| {
+            lower.as_deref().map_or(true, |lower| lower <= key)
+                && upper.as_deref().map_or(true, |upper| key < upper)
+        };
+        let children = {
+            let buffer = bufmgr.fetch_page_read(page_id).unwrap();
+            let node = node::Node::new(buffer.data());
+            let body = node::Body::new(node.header.node_type, node.body);
+            match body {
+                node::Body::Leaf(leaf) => {
+                    assert!(is_root || !leaf.is_underflow(), "leaf {:?} underflows", page_id);
+                    let keys: Vec<_> = (0..leaf.num_pairs()).map(|i| leaf.pair_at(i).key).collect();
+                    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                    assert!(keys.iter().all(|key| in_range(key)));
+                    leaves.push(page_id);
+                    return 1;
+                }
+                node::Body::Branch(branch) => {
+                    assert!(is_root || !branch.is_underflow(), "branch {:?} underflows", page_id);
+                    assert!(branch.num_pairs() > 0);
+                    let mut bounds = vec![lower.clone()];
+                    for i in 0..branch.num_pairs() {
+                        let key = branch.pair_at(i).key;
+                        assert!(in_range(key));
+                        bounds.push(Some(key.to_vec()));
+                    }
+                    bounds.push(upper.clone());
+                    (0..=branch.num_pairs())
+                        .map(|i| (branch.child_at(i), bounds[i].clone(), bounds[i + 1].clone()))
+                        .collect::<Vec<_>>()
+                }
+            }
+        };
+        let heights: Vec<_> = children
+            .into_iter()
+            .map(|(child, lower, upper)| check_node(bufmgr, child, (lower, upper), false, leaves))
+            .collect();
+        assert!(heights.windows(2).all(|w| w[0] == w[1]));
+        heights[0] + 1
+    }
+
+    #[test]
+    fn test_delete_rebalance() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        // 枝のノードが3段以上になるよう、キーを長くする
+        let key = |i: u64| [&i.to_be_bytes()[..], &[0; 56]].concat();
+        let value = |i: u64| vec![i as u8; 16];
+        let mut expected = BTreeMap::new();
+        // 昇順に挿入するとリーフが埋まったままになるので、降順に挿入して半分ずつに分ける
+        for i in (0..5000u64).rev() {
+            btree.insert(&mut bufmgr, &key(i), &value(i)).unwrap();
+            expected.insert(key(i), value(i));
+        }
+        let pairs = |expected: &BTreeMap<Vec<u8>, Vec<u8>>| -> Vec<_> {
+            expected.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+        };
+        assert_eq!(pairs(&expected), scan(&mut bufmgr, &btree));
+        let full_height = check_tree(&mut bufmgr, &btree);
+        assert!(full_height >= 3);
+        let full_pages = bufmgr.storage_info().unwrap().num_pages;
+
+        // 1つおきに削除する
+        for i in (0..5000u64).step_by(2) {
+            assert!(btree.delete(&mut bufmgr, &key(i)).unwrap());
+            expected.remove(&key(i));
+            if i % 500 == 0 {
+                assert_eq!(pairs(&expected), scan(&mut bufmgr, &btree));
+                check_tree(&mut bufmgr, &btree);
+            }
+        }
+        assert_eq!(pairs(&expected), scan(&mut bufmgr, &btree));
+        check_tree(&mut bufmgr, &btree);
+        assert!(bufmgr.storage_info().unwrap().num_pages <= full_pages);
+
+        // 残りも削除すると、木は空のリーフ1つに戻る
+        for i in (1..5000u64).step_by(2) {
+            assert!(btree.delete(&mut bufmgr, &key(i)).unwrap());
+            expected.remove(&key(i));
+            if i % 500 == 1 {
+                assert_eq!(pairs(&expected), scan(&mut bufmgr, &btree));
+                check_tree(&mut bufmgr, &btree);
+            }
+        }
+        assert!(scan(&mut bufmgr, &btree).is_empty());
+        assert_eq!(1, check_tree(&mut bufmgr, &btree));
+        assert_eq!(num_pages, bufmgr.storage_info().unwrap().num_pages);
+    }
+
+    #[test]
+    fn test_delete_rebalance_random() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        // 値の大きさを変えて、まとめられずに借りる場合も起こす
+        let key = |i: u64| (i * 7919 % 3000).to_be_bytes();
+        let value = |i: u64| vec![i as u8; 10 + (i * 31 % 300) as usize];
+        let mut expected = BTreeMap::new();
+        for i in 0..3000u64 {
+            btree.insert(&mut bufmgr, &key(i), &value(i)).unwrap();
+            expected.insert(key(i).to_vec(), value(i));
+        }
+        for i in 0..3000u64 {
+            let k = key(i * 13 % 3000);
+            assert_eq!(expected.remove(&k[..]).is_some(), btree.delete(&mut bufmgr, &k).unwrap());
+            if i % 300 == 0 {
+                let pairs: Vec<_> = expected.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                assert_eq!(pairs, scan(&mut bufmgr, &btree));
+                check_tree(&mut bufmgr, &btree);
+            }
+        }
+        assert!(scan(&mut bufmgr, &btree).is_empty());
+        assert_eq!(1, check_tree(&mut bufmgr, &btree));
+    }
+
+    #[test]
+    fn test_len() {
+        let dir = tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let disk = DiskManager::new(data_file).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        assert_eq!(0, btree.len(&mut bufmgr).unwrap());
+        assert!(btree.is_empty(&mut bufmgr).unwrap());
+        for i in 0u64..1000 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), b"value").unwrap();
+        }
+        // 重複したキーの挿入や値の置き換えでは数は変わらない
+        assert!(btree.insert(&mut bufmgr, &0u64.to_be_bytes(), b"value").is_err());
+        assert!(btree.update(&mut bufmgr, &1u64.to_be_bytes(), b"other").unwrap());
+        assert_eq!(1000, btree.len(&mut bufmgr).unwrap());
+        for i in (0u64..1000).step_by(3) {
+            assert!(btree.delete(&mut bufmgr, &i.to_be_bytes()).unwrap());
+        }
+        assert!(!btree.delete(&mut bufmgr, &0u64.to_be_bytes()).unwrap());
+        assert_eq!(666, btree.len(&mut bufmgr).unwrap());
+        bufmgr.flush().unwrap();
+        let meta_page_id = btree.meta_page_id;
+        drop(bufmgr);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::new(meta_page_id);
+        assert_eq!(666, btree.len(&mut bufmgr).unwrap());
+        for i in (0u64..1000).filter(|i| i % 3 != 0) {
+            assert!(btree.delete(&mut bufmgr, &i.to_be_bytes()).unwrap());
+        }
+        assert!(btree.is_empty(&mut bufmgr).unwrap());
+
+        // 重複を許す木では、ペアをそれぞれ数える
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            ..BTreeOptions::default()
+        };
+        let multi = BTree::create_with(&mut bufmgr, options).unwrap();
+        for i in 0u64..10 {
+            multi.insert(&mut bufmgr, b"key", &i.to_be_bytes()).unwrap();
+        }
+        assert_eq!(10, multi.len(&mut bufmgr).unwrap());
+        assert!(multi.delete_pair(&mut bufmgr, b"key", &3u64.to_be_bytes()).unwrap());
+        assert_eq!(9, multi.len(&mut bufmgr).unwrap());
+        assert!(multi.delete(&mut bufmgr, b"key").unwrap());
+        assert_eq!(0, multi.len(&mut bufmgr).unwrap());
+
+        let pairs = (0u64..500).map(|i| (i.to_be_bytes().to_vec(), vec![]));
+        let loaded = BTree::bulk_load(&mut bufmgr, pairs).unwrap();
+        assert_eq!(500, loaded.len(&mut bufmgr).unwrap());
+    }
+
+    #[test]
+    fn test_len_random() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let mut x = 0x9E37_79B9_7F4A_7C15u64;
+        let mut random = || {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        for i in 0..5000 {
+            let key = (random() % 1000).to_be_bytes();
+            match random() % 3 {
+                0 => {
+                    btree.delete(&mut bufmgr, &key).unwrap();
+                }
+                1 => {
+                    btree.update(&mut bufmgr, &key, b"updated").unwrap();
+                }
+                _ => match btree.insert(&mut bufmgr, &key, b"value") {
+                    Ok(()) | Err(Error::DuplicateKey) => {}
+                    Err(err) => panic!("{}", err),
+                },
+            }
+            if i % 500 == 0 {
+                let count = scan(&mut bufmgr, &btree).len() as u64;
+                assert_eq!(count, btree.len(&mut bufmgr).unwrap());
+            }
+        }
+        let count = scan(&mut bufmgr, &btree).len() as u64;
+        assert_eq!(count, btree.len(&mut bufmgr).unwrap());
+    }
+
+    #[test]
+    fn test_len_uncounted() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        // ペアの数を記録する前に作った木では、たどって数える
+        {
+            let meta_buffer = bufmgr.fetch_page_write(btree.meta_page_id).unwrap();
+            let mut meta = meta::Meta::new(meta_buffer.data_mut());
+            meta.header.flags = 0;
+            meta.header.num_entries = 0;
+        }
+        for i in 0u64..100 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), b"value").unwrap();
+        }
+        btree.delete(&mut bufmgr, &0u64.to_be_bytes()).unwrap();
+        assert_eq!(99, btree.len(&mut bufmgr).unwrap());
+        assert!(!btree.is_empty(&mut bufmgr).unwrap());
+    }
+
+    #[test]
+    fn test_upsert() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let key = |i: u64| (i * 3).to_be_bytes();
+        let mut expected = BTreeMap::new();
+        // 新しいキー
+        for i in 0..500u64 {
+            assert_eq!(None, btree.upsert(&mut bufmgr, &key(i), &[i as u8; 10]).unwrap());
+            expected.insert(key(i).to_vec(), vec![i as u8; 10]);
+        }
+        // 同じ大きさの値で置き換える
+        for i in (0..500u64).step_by(2) {
+            let old_value = btree.upsert(&mut bufmgr, &key(i), &[0xEE; 10]).unwrap();
+            assert_eq!(Some(vec![i as u8; 10]), old_value);
+            expected.insert(key(i).to_vec(), vec![0xEE; 10]);
+        }
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        // 大きな値で置き換えて、リーフを分割させる
+        for i in (0..500u64).step_by(3) {
+            let value = vec![i as u8; 200];
+            let old_value = btree.upsert(&mut bufmgr, &key(i), &value).unwrap();
+            assert_eq!(expected.insert(key(i).to_vec(), value), old_value);
+        }
+        assert!(bufmgr.storage_info().unwrap().num_pages > num_pages);
+        // オーバーフローページに置く値と、間の新しいキーも混ぜる
+        for i in (0..500u64).step_by(50) {
+            let value = vec![0xAB; 3 * disk::PAGE_SIZE];
+            let old_value = btree.upsert(&mut bufmgr, &key(i), &value).unwrap();
+            assert_eq!(expected.insert(key(i).to_vec(), value), old_value);
+            let new_key = (i * 3 + 1).to_be_bytes();
+            assert_eq!(None, btree.upsert(&mut bufmgr, &new_key, b"new").unwrap());
+            expected.insert(new_key.to_vec(), b"new".to_vec());
+        }
+        let old_value = btree.upsert(&mut bufmgr, &key(0), b"small").unwrap();
+        assert_eq!(expected.insert(key(0).to_vec(), b"small".to_vec()), old_value);
+
+        btree.verify(&mut bufmgr).unwrap();
+        let pairs: Vec<_> = expected.into_iter().collect();
+        assert_eq!(pairs, scan(&mut bufmgr, &btree));
+        assert_eq!(pairs.len() as u64, btree.len(&mut bufmgr).unwrap());
+
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            ..BTreeOptions::default()
+        };
+        let multi = BTree::create_with(&mut bufmgr, options).unwrap();
+        assert!(matches!(
+            multi.upsert(&mut bufmgr, b"key", b"value"),
+            Err(Error::UnsupportedOnMulti("upsert"))
+        ));
+    }
+
+    #[test]
+    fn test_insert_returning() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let key = |i: u64| (i * 2).to_be_bytes();
+        let behaviors = [OnDuplicate::Error, OnDuplicate::Replace, OnDuplicate::Keep];
+        for i in 0..300u64 {
+            let behavior = behaviors[i as usize % 3];
+            let outcome = btree.insert_returning(&mut bufmgr, &key(i), &[i as u8; 10], behavior);
+            assert_eq!(InsertOutcome::Inserted, outcome.unwrap());
+        }
+
+        // あるキーには、それぞれの振る舞いで書き込む
+        assert!(matches!(
+            btree.insert_returning(&mut bufmgr, &key(0), b"new", OnDuplicate::Error),
+            Err(Error::DuplicateKey)
+        ));
+        let outcome = btree.insert_returning(&mut bufmgr, &key(1), b"new", OnDuplicate::Keep);
+        assert_eq!(InsertOutcome::Kept, outcome.unwrap());
+        assert_eq!(Some(vec![1; 10]), btree.get(&mut bufmgr, &key(1)).unwrap());
+        let outcome = btree.insert_returning(&mut bufmgr, &key(2), b"new", OnDuplicate::Replace);
+        assert_eq!(InsertOutcome::Replaced(vec![2; 10]), outcome.unwrap());
+        assert_eq!(Some(b"new".to_vec()), btree.get(&mut bufmgr, &key(2)).unwrap());
+        assert_eq!(300, btree.len(&mut bufmgr).unwrap());
 
-        let (_, value) = btree
-            .search(&mut bufmgr, SearchMode::Key(3u64.to_be_bytes().to_vec()))
-            .unwrap()
-            .get()
-            .unwrap();
-        assert_eq!(b"hello", &value[..]);
-        let (_, value) = btree
-            .search(&mut bufmgr, SearchMode::Key(8u64.to_be_bytes().to_vec()))
-            .unwrap()
-            .get()
-            .unwrap();
-        assert_eq!(b"!", &value[..]);
+        // 大きな値で置き換えて、リーフを分割させる
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        for i in (3..300u64).step_by(2) {
+            let value = vec![0xEE; 300];
+            let behavior = OnDuplicate::Replace;
+            let outcome = btree.insert_returning(&mut bufmgr, &key(i), &value, behavior);
+            assert_eq!(InsertOutcome::Replaced(vec![i as u8; 10]), outcome.unwrap());
+        }
+        assert!(bufmgr.storage_info().unwrap().num_pages > num_pages);
+        // ないキーは、どの振る舞いでも加える
+        for (i, &behavior) in behaviors.iter().enumerate() {
+            let new_key = (i as u64 * 2 + 1).to_be_bytes();
+            let outcome = btree.insert_returning(&mut bufmgr, &new_key, b"odd", behavior);
+            assert_eq!(InsertOutcome::Inserted, outcome.unwrap());
+        }
+        assert_eq!(303, btree.len(&mut bufmgr).unwrap());
+        assert_eq!(Some(vec![0xEE; 300]), btree.get(&mut bufmgr, &key(299)).unwrap());
+        assert_eq!(Some(vec![4; 10]), btree.get(&mut bufmgr, &key(4)).unwrap());
+        btree.verify(&mut bufmgr).unwrap();
+
+        // 重複を許す木では、同じキーと値の組だけを重複として扱う
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            ..BTreeOptions::default()
+        };
+        let multi = BTree::create_with(&mut bufmgr, options).unwrap();
+        let outcome = multi.insert_returning(&mut bufmgr, b"key", b"a", OnDuplicate::Keep);
+        assert_eq!(InsertOutcome::Inserted, outcome.unwrap());
+        let outcome = multi.insert_returning(&mut bufmgr, b"key", b"a", OnDuplicate::Keep);
+        assert_eq!(InsertOutcome::Kept, outcome.unwrap());
+        let outcome = multi.insert_returning(&mut bufmgr, b"key", b"b", OnDuplicate::Error);
+        assert_eq!(InsertOutcome::Inserted, outcome.unwrap());
+        assert!(matches!(
+            multi.insert_returning(&mut bufmgr, b"key", b"b", OnDuplicate::Error),
+            Err(Error::DuplicateKey)
+        ));
+        assert!(matches!(
+            multi.insert_returning(&mut bufmgr, b"key", b"c", OnDuplicate::Replace),
+            Err(Error::UnsupportedOnMulti("upsert"))
+        ));
+        assert_eq!(2, multi.len(&mut bufmgr).unwrap());
     }
 
     #[test]
-    fn test_split() {
-        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
-        let pool = BufferPool::new(10);
+    fn test_duplicate_key() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for i in (0..1000u64).map(|i| i * 2) {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[i as u8; 100]).unwrap();
+        }
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        for i in [0u64, 500, 1998] {
+            let key = i.to_be_bytes();
+            // オーバーフローページに置く値でも、書き込んだページは解放する
+            for value in [vec![0xEE; 100], vec![0xEE; 3 * disk::PAGE_SIZE]] {
+                assert!(matches!(
+                    btree.insert(&mut bufmgr, &key, &value),
+                    Err(Error::DuplicateKey)
+                ));
+            }
+            assert_eq!(Some(vec![i as u8; 100]), btree.get(&mut bufmgr, &key).unwrap());
+        }
+        assert_eq!(num_pages, bufmgr.storage_info().unwrap().num_pages);
+        assert_eq!(1000, btree.len(&mut bufmgr).unwrap());
+        btree.verify(&mut bufmgr).unwrap();
+
+        // 同じ位置の前後には挿入できる
+        for i in [1u64, 499, 501, 1999] {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), b"new").unwrap();
+        }
+        btree.verify(&mut bufmgr).unwrap();
+        let (start, end) = (498u64.to_be_bytes(), 502u64.to_be_bytes());
+        let keys = collect_range(&mut bufmgr, &btree, Bound::Included(&start), Bound::Included(&end));
+        let expected: Vec<_> = (498..=502u64).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(expected, keys);
+    }
+
+    // リーフを隣のページIDでたどる。forwardでなければ最後のリーフから前にたどる
+    fn walk_leaves(bufmgr: &mut BufferPoolManager, btree: &BTree, forward: bool) -> Vec<PageId> {
+        let mode = if forward { SearchMode::Start } else { SearchMode::End };
+        let mut page_id = Some(btree.search(bufmgr, mode).unwrap().buffer.page_id);
+        let mut leaves = vec![];
+        while let Some(current) = page_id {
+            leaves.push(current);
+            let buffer = bufmgr.fetch_page_read(current).unwrap();
+            let node = node::Node::new(buffer.data());
+            let leaf = leaf::Leaf::new(node.body);
+            page_id = if forward { leaf.next_page_id() } else { leaf.prev_page_id() };
+        }
+        leaves
+    }
+
+    #[test]
+    fn test_leaf_chain() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        // 前からも後ろからも分割させる
+        let keys: Vec<_> = (0..3000u64).map(|i| (i * 7919 % 3000).to_be_bytes()).collect();
+        let check = |bufmgr: &mut BufferPoolManager| {
+            let forward = walk_leaves(bufmgr, &btree, true);
+            let mut backward = walk_leaves(bufmgr, &btree, false);
+            backward.reverse();
+            assert_eq!(forward, backward);
+            forward.len()
+        };
+        for key in &keys {
+            btree.insert(&mut bufmgr, key, &[0; 50]).unwrap();
+        }
+        let num_leaves = check(&mut bufmgr);
+        assert!(num_leaves > 10);
+
+        // まとめられたリーフは、鎖から外れる
+        for key in keys.iter().filter(|key| key[7] % 4 != 0) {
+            btree.delete(&mut bufmgr, key).unwrap();
+        }
+        assert!(check(&mut bufmgr) < num_leaves);
+        for key in keys.iter().filter(|key| key[7] % 4 == 0) {
+            btree.delete(&mut bufmgr, key).unwrap();
+        }
+        assert_eq!(1, check(&mut bufmgr));
+    }
+
+    #[test]
+    fn test_separator() {
+        assert_eq!(b"abd".to_vec(), separator(b"abc", b"abd"));
+        assert_eq!(b"abz".to_vec(), separator(b"abcdef", b"abzzzz"));
+        assert_eq!(b"b".to_vec(), separator(b"abc", b"b"));
+        assert_eq!(b"ac".to_vec(), separator(b"ab\xFF\xFF", b"ac"));
+        // 一方がもう一方の前方一致なら、1バイトだけ長くする
+        assert_eq!(b"abc".to_vec(), separator(b"ab", b"abcd"));
+        assert_eq!(b"ab\x00".to_vec(), separator(b"ab", b"ab\x00\x00"));
+        assert_eq!(b"a".to_vec(), separator(b"", b"a"));
+    }
+
+    // 枝のノードに置いた区切りのキーを集める
+    fn separators(bufmgr: &mut BufferPoolManager, btree: &BTree) -> Vec<Vec<u8>> {
+        let root_page_id = {
+            let meta_buffer = bufmgr.fetch_page_read(btree.meta_page_id).unwrap();
+            let meta = meta::Meta::new(meta_buffer.data());
+            meta.header.root_page_id
+        };
+        let mut page_ids = vec![root_page_id];
+        let mut keys = vec![];
+        while let Some(page_id) = page_ids.pop() {
+            let buffer = bufmgr.fetch_page_read(page_id).unwrap();
+            let node = node::Node::new(buffer.data());
+            let body = node::Body::new(node.header.node_type, node.body);
+            if let node::Body::Branch(branch) = body {
+                for i in 0..branch.num_pairs() {
+                    keys.push(branch.pair_at(i).key.to_vec());
+                }
+                page_ids.extend((0..=branch.num_pairs()).map(|i| branch.child_at(i)));
+            }
+        }
+        keys
+    }
+
+    #[test]
+    fn test_separator_prefix_keys() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        // "a"と"b"だけからなる8文字までのキー。多くのキーがほかのキーの前方一致になる
+        let mut keys: Vec<Vec<u8>> = vec![];
+        for len in 1..=8 {
+            for bits in 0..(1u32 << len) {
+                keys.push((0..len).map(|i| b"ab"[(bits >> i) as usize & 1]).collect());
+            }
+        }
+        let num_keys = keys.len();
+        let mut expected = BTreeMap::new();
+        for i in 0..num_keys {
+            let key = &keys[i * 97 % num_keys];
+            btree.insert(&mut bufmgr, key, &[key.len() as u8; 200]).unwrap();
+            expected.insert(key.clone(), vec![key.len() as u8; 200]);
+        }
+        btree.verify(&mut bufmgr).unwrap();
+        let separators = separators(&mut bufmgr, &btree);
+        assert!(separators.len() > 10);
+        assert_eq!(
+            expected.clone().into_iter().collect::<Vec<_>>(),
+            scan(&mut bufmgr, &btree)
+        );
+        for key in &keys {
+            assert_eq!(expected.get(key), btree.get(&mut bufmgr, key).unwrap().as_ref());
+            // 区切りのキーのすぐ上や下の、ないキー
+            let mut longer = key.clone();
+            longer.push(0);
+            assert_eq!(None, btree.get(&mut bufmgr, &longer).unwrap());
+        }
+        for separator in &separators {
+            let next = expected.range(separator.clone()..).next().map(|(k, _)| k.clone());
+            let found = btree
+                .search(&mut bufmgr, SearchMode::KeyOrNext(separator.clone()))
+                .unwrap()
+                .get(&mut bufmgr)
+                .unwrap()
+                .map(|(key, _)| key);
+            assert_eq!(next, found);
+        }
+
+        // 削除してリーフをまとめたり借りたりしても、区切りのキーは正しい
+        for key in keys.iter().filter(|key| key.len() % 3 != 0) {
+            assert!(btree.delete(&mut bufmgr, key).unwrap());
+            expected.remove(key);
+        }
+        btree.verify(&mut bufmgr).unwrap();
+        assert_eq!(expected.into_iter().collect::<Vec<_>>(), scan(&mut bufmgr, &btree));
+    }
+
+    #[test]
+    fn test_separator_height() {
+        use md5::{Digest, Md5};
+
+        let page_size = 512;
+        let disk = MemoryDiskManager::with_page_size(page_size).unwrap();
+        let pool = BufferPool::new(16).with_page_size(page_size);
         let mut bufmgr = BufferPoolManager::new(disk, pool);
+        // ハッシュ値のようなキーに、グループごとに共通の長い前置きを付ける
+        let key = |i: u64| {
+            let mut key = vec![(i % 4) as u8; 16];
+            key.extend_from_slice(&Md5::digest(&i.to_be_bytes()));
+            key
+        };
+        let mut pairs: Vec<_> = (0..20000u64).map(|i| (key(i), vec![])).collect();
+        pairs.sort();
+        let options = BulkLoadOptions::default();
+        let btree = BTree::bulk_load_with(&mut bufmgr, pairs.clone(), options).unwrap();
+        btree.verify(&mut bufmgr).unwrap();
+        let stats = btree.stats(&mut bufmgr).unwrap();
+
+        // キーをそのまま区切りにすると、枝のノード1つに置ける子の数
+        let full_fanout = {
+            let mut page = vec![0u8; page_size];
+            let mut node = node::Node::new(&mut page[..]);
+            node.initialize_as_branch();
+            let mut branch = branch::Branch::new(node.body);
+            branch.initialize_with_child(PageId(0));
+            let mut fanout = 1;
+            while branch
+                .append_within(&pairs[0].0, PageId(fanout), options.fill_factor)
+                .is_some()
+            {
+                fanout += 1;
+            }
+            fanout
+        };
+        let mut full_height = 1;
+        let mut num_nodes = stats.leaf_pages;
+        while num_nodes > 1 {
+            num_nodes = num_nodes.div_ceil(full_fanout);
+            full_height += 1;
+        }
+        assert!(
+            stats.height < full_height,
+            "height {} is not less than {} with full keys",
+            stats.height,
+            full_height
+        );
+        assert!(separators(&mut bufmgr, &btree).iter().all(|key| key.len() < 32));
+
+        // 挿入で分割した木でも、区切りのキーは短い
+        let inserted = BTree::create(&mut bufmgr).unwrap();
+        for i in 0..5000u64 {
+            inserted.insert(&mut bufmgr, &key(i), b"").unwrap();
+        }
+        inserted.verify(&mut bufmgr).unwrap();
+        let separators = separators(&mut bufmgr, &inserted);
+        let avg_len = separators.iter().map(|key| key.len()).sum::<usize>() / separators.len();
+        assert!(avg_len < 20, "average separator length is {}", avg_len);
+        for i in [0u64, 1, 4999] {
+            assert_eq!(Some(vec![]), inserted.get(&mut bufmgr, &key(i)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_entries() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
         let btree = BTree::create(&mut bufmgr).unwrap();
-        let long_data_list = vec![
-            vec![0xC0u8; 1000],
-            vec![0x01u8; 1000],
-            vec![0xCAu8; 1000],
-            vec![0xFEu8; 1000],
-            vec![0xDEu8; 1000],
-            vec![0xADu8; 1000],
-            vec![0xBEu8; 1000],
-            vec![0xAEu8; 1000],
-        ];
-        for data in long_data_list.iter() {
-            btree.insert(&mut bufmgr, &data, &data).unwrap();
+        assert_eq!(0, btree.iter(&mut bufmgr).count());
+        let pairs: Vec<_> = (0..2000u64)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 40]))
+            .collect();
+        for (key, value) in &pairs {
+            btree.insert(&mut bufmgr, key, value).unwrap();
         }
-        for data in long_data_list.iter() {
-            let (k, v) = btree.search(&mut bufmgr, SearchMode::Key(data.clone())).unwrap().get().unwrap();
-            assert_eq!(data, &k);
-            assert_eq!(data, &v);
+        let entries: Vec<_> = btree.iter(&mut bufmgr).collect::<Result<_, _>>().unwrap();
+        assert_eq!(pairs, entries);
+        let even = btree
+            .iter(&mut bufmgr)
+            .map(Result::unwrap)
+            .filter(|(_, value)| value[0] % 2 == 0)
+            .count();
+        assert_eq!(1000, even);
+
+        // 最初のリーフのペアだけを取り出すなら、そのあとのリーフは読まない
+        let height = btree.stats(&mut bufmgr).unwrap().height as u64;
+        let access_seq = bufmgr.stats().access_seq;
+        let first: Vec<_> = btree.iter(&mut bufmgr).take(10).map(Result::unwrap).collect();
+        assert_eq!(&pairs[..10], &first[..]);
+        // 根から最初のリーフまでのページ
+        assert_eq!(height, bufmgr.stats().access_seq - access_seq);
+
+        // 読み込みに失敗したら、エラーを返して終わる
+        let broken = BTree::new(PageId(100_000));
+        let mut iter = broken.iter(&mut bufmgr);
+        assert!(matches!(iter.next(), Some(Err(Error::Buffer(_)))));
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_first_last() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        assert_eq!(None, btree.first(&mut bufmgr).unwrap());
+        assert_eq!(None, btree.last(&mut bufmgr).unwrap());
+
+        btree.insert(&mut bufmgr, b"only", b"one").unwrap();
+        let only = Some((b"only".to_vec(), b"one".to_vec()));
+        assert_eq!(only, btree.first(&mut bufmgr).unwrap());
+        assert_eq!(only, btree.last(&mut bufmgr).unwrap());
+        btree.delete(&mut bufmgr, b"only").unwrap();
+
+        // 昇順に挿入すると、最大のキーはいつも最後の分割でできたリーフにある
+        let value = |i: u64| vec![i as u8; 200];
+        for i in 1u64..=500 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &value(i)).unwrap();
+            let last = btree.last(&mut bufmgr).unwrap();
+            assert_eq!(Some((i.to_be_bytes().to_vec(), value(i))), last);
+        }
+        assert!(btree.stats(&mut bufmgr).unwrap().leaf_pages > 1);
+        let first = btree.first(&mut bufmgr).unwrap();
+        assert_eq!(Some((1u64.to_be_bytes().to_vec(), value(1))), first);
+
+        // 端のペアを消すと、その隣が端になる
+        btree.delete(&mut bufmgr, &1u64.to_be_bytes()).unwrap();
+        btree.delete(&mut bufmgr, &500u64.to_be_bytes()).unwrap();
+        let (first, _) = btree.first(&mut bufmgr).unwrap().unwrap();
+        let (last, _) = btree.last(&mut bufmgr).unwrap().unwrap();
+        assert_eq!(2u64.to_be_bytes().to_vec(), first);
+        assert_eq!(499u64.to_be_bytes().to_vec(), last);
+    }
+
+    #[test]
+    fn test_key_or_next_prev() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let key = |i: u64| i.to_be_bytes().to_vec();
+        // 10から1000までの10の倍数。値を大きくして、いくつものリーフに分ける
+        let keys: Vec<u64> = (1..=100).map(|i| i * 10).collect();
+        for &i in &keys {
+            btree.insert(&mut bufmgr, &key(i), &[0; 200]).unwrap();
+        }
+        let first_key = |bufmgr: &mut BufferPoolManager, search_mode| {
+            let mut iter = btree.search(bufmgr, search_mode).unwrap();
+            let first = iter.next(bufmgr).unwrap().map(|(k, _)| k);
+            let second = iter.next(bufmgr).unwrap().map(|(k, _)| k);
+            (first, second)
+        };
+        // 一致するキー、キーの間、最小より前、最大より後ろ。リーフの境目もまたぐ
+        for target in 0..=1010u64 {
+            let next = keys.iter().position(|&i| i >= target);
+            let prev = keys.iter().rposition(|&i| i <= target);
+            let expected = |idx: Option<usize>| match idx {
+                Some(idx) => (keys.get(idx).map(|&i| key(i)), keys.get(idx + 1).map(|&i| key(i))),
+                None => (None, None),
+            };
+            let found = first_key(&mut bufmgr, SearchMode::KeyOrNext(key(target)));
+            assert_eq!(expected(next), found, "next of {}", target);
+            let found = first_key(&mut bufmgr, SearchMode::KeyOrPrev(key(target)));
+            assert_eq!(expected(prev), found, "prev of {}", target);
+
+            let search_mode = SearchMode::KeyOrNext(key(target));
+            let mut desc = btree.search_desc(&mut bufmgr, search_mode).unwrap();
+            let found = desc.next(&mut bufmgr).unwrap().map(|(k, _)| k);
+            assert_eq!(next.map(|idx| key(keys[idx])), found, "desc next of {}", target);
+            let search_mode = SearchMode::KeyOrPrev(key(target));
+            let mut desc = btree.search_desc(&mut bufmgr, search_mode).unwrap();
+            let found = desc.next(&mut bufmgr).unwrap().map(|(k, _)| k);
+            assert_eq!(prev.map(|idx| key(keys[idx])), found, "desc prev of {}", target);
+        }
+        assert!(btree.stats(&mut bufmgr).unwrap().leaf_pages > 4);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_key_or_next_prev_multi() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            ..BTreeOptions::default()
+        };
+        let btree = BTree::create_with(&mut bufmgr, options).unwrap();
+        for (key, value) in [(b"a", b"1"), (b"b", b"1"), (b"b", b"2"), (b"d", b"1")] {
+            btree.insert(&mut bufmgr, key, value).unwrap();
+        }
+        let seek = |bufmgr: &mut BufferPoolManager, search_mode| {
+            btree.search(bufmgr, search_mode).unwrap().next(bufmgr).unwrap()
+        };
+        let pair = |key: &[u8], value: &[u8]| Some((key.to_vec(), value.to_vec()));
+        // 同じキーのペアのうち、KeyOrNextは最初の、KeyOrPrevは最後のペアを指す
+        assert_eq!(pair(b"b", b"1"), seek(&mut bufmgr, SearchMode::KeyOrNext(b"b".to_vec())));
+        assert_eq!(pair(b"b", b"2"), seek(&mut bufmgr, SearchMode::KeyOrPrev(b"b".to_vec())));
+        assert_eq!(pair(b"b", b"2"), seek(&mut bufmgr, SearchMode::KeyOrPrev(b"c".to_vec())));
+        assert_eq!(None, seek(&mut bufmgr, SearchMode::KeyOrPrev(b"0".to_vec())));
+        // 以前の名前は、前向きにはKeyOrNextと同じ
+        assert_eq!(pair(b"b", b"1"), seek(&mut bufmgr, SearchMode::Key(b"b".to_vec())));
+        let search_mode = SearchMode::KeyOrNext(b"c".to_vec());
+        let mut desc = btree.search_desc(&mut bufmgr, search_mode).unwrap();
+        assert_eq!(pair(b"d", b"1"), desc.next(&mut bufmgr).unwrap());
+        assert_eq!(pair(b"b", b"2"), desc.next(&mut bufmgr).unwrap());
+    }
+
+    // 1ページ分を読み、次のページを読むための位置を返す
+    #[allow(clippy::type_complexity)]
+    fn read_page(
+        bufmgr: &mut BufferPoolManager,
+        btree: &BTree,
+        pos: Option<CursorPos>,
+        page_len: usize,
+    ) -> (Vec<(Vec<u8>, Vec<u8>)>, CursorPos) {
+        let mut iter = match pos {
+            // 位置は呼び出し側に渡して、あとで受け取り直すものとして扱う
+            Some(pos) => {
+                let bytes = bincode::options().serialize(&pos).unwrap();
+                let pos = bincode::options().deserialize(&bytes).unwrap();
+                btree.resume(bufmgr, pos).unwrap()
+            }
+            None => btree.search(bufmgr, SearchMode::Start).unwrap(),
+        };
+        let mut pairs = vec![];
+        while pairs.len() < page_len {
+            match iter.next(bufmgr).unwrap() {
+                Some(pair) => pairs.push(pair),
+                None => break,
+            }
+        }
+        (pairs, iter.position())
+    }
+
+    #[test]
+    fn test_resume() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let key = |i: u64| (i * 1000).to_be_bytes().to_vec();
+        for i in 0..10000 {
+            btree.insert(&mut bufmgr, &key(i), &[0; 20]).unwrap();
+        }
+        let mut expected: BTreeSet<_> = (0..10000).map(key).collect();
+        let mut returned = vec![];
+        let mut pos = None;
+        for page in 0u64.. {
+            let (pairs, next_pos) = read_page(&mut bufmgr, &btree, pos, 100);
+            if pairs.is_empty() {
+                break;
+            }
+            let last = u64::from_be_bytes(pairs.last().unwrap().0[..].try_into().unwrap());
+            returned.extend(pairs.into_iter().map(|(key, _)| key));
+            pos = Some(next_pos);
+            // 加えたペアを読み続けて終わらなくならないよう、終わりの近くでは書き換えない
+            if last > 9_000_000 {
+                continue;
+            }
+            // 最後に返したペアをときどき削除し、まだ返していないペアも削除する
+            if page % 2 == 0 {
+                btree.delete(&mut bufmgr, &last.to_be_bytes()).unwrap();
+            }
+            for deleted in [last + 1000, last + 50000] {
+                if btree.delete(&mut bufmgr, &deleted.to_be_bytes()).unwrap() {
+                    expected.remove(&deleted.to_be_bytes()[..]);
+                }
+            }
+            // 返したペアの後ろに加えたものは返り、前に加えたものは返らない
+            let insert = |bufmgr: &mut BufferPoolManager, i: u64| {
+                match btree.insert(bufmgr, &i.to_be_bytes(), &[1; 20]) {
+                    Ok(()) | Err(Error::DuplicateKey) => {}
+                    Err(err) => panic!("{}", err),
+                }
+            };
+            for inserted in [last + 1, last + 15 + page % 5] {
+                insert(&mut bufmgr, inserted);
+                expected.insert(inserted.to_be_bytes().to_vec());
+            }
+            insert(&mut bufmgr, last - 5);
+            // リーフを分割させるよう、まだ返していない範囲にまとめて加える
+            if page % 10 == 0 {
+                for i in 1..200 {
+                    let inserted = last + 20000 + i;
+                    insert(&mut bufmgr, inserted);
+                    expected.insert(inserted.to_be_bytes().to_vec());
+                }
+            }
+        }
+        assert!(btree.stats(&mut bufmgr).unwrap().half_splits > 0);
+        // 重複も抜けもなく、キーの順に返す
+        assert!(returned.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(expected.into_iter().collect::<Vec<_>>(), returned);
+    }
+
+    #[test]
+    fn test_resume_multi() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            ..BTreeOptions::default()
+        };
+        let btree = BTree::create_with(&mut bufmgr, options).unwrap();
+        let mut expected = vec![];
+        for i in 0..300u64 {
+            // 同じキーのペアがページの境目をまたぐ
+            let pair = (format!("key{}", i / 150).into_bytes(), i.to_be_bytes().to_vec());
+            btree.insert(&mut bufmgr, &pair.0, &pair.1).unwrap();
+            expected.push(pair);
+        }
+        let (mut returned, mut pos) = read_page(&mut bufmgr, &btree, None, 100);
+        loop {
+            let (pairs, next_pos) = read_page(&mut bufmgr, &btree, Some(pos), 100);
+            if pairs.is_empty() {
+                break;
+            }
+            returned.extend(pairs);
+            pos = next_pos;
+        }
+        assert_eq!(expected, returned);
+
+        // 何も返していない走査の位置からは、次に返すはずのペアから始める
+        let iter = btree.search(&mut bufmgr, SearchMode::KeyOrNext(b"key1".to_vec())).unwrap();
+        let mut iter = btree.resume(&mut bufmgr, iter.position()).unwrap();
+        assert_eq!(Some(expected[150].clone()), iter.next(&mut bufmgr).unwrap());
+        let iter = btree.search(&mut bufmgr, SearchMode::KeyOrNext(b"key2".to_vec())).unwrap();
+        let mut iter = btree.resume(&mut bufmgr, iter.position()).unwrap();
+        assert_eq!(None, iter.next(&mut bufmgr).unwrap());
+    }
+
+    #[test]
+    fn test_skip_entries() {
+        use md5::{Digest, Md5};
+
+        // btree-largeと同じく、主キーのmd5をキーにした100万個のペアをまとめて作る
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(100));
+        let mut pairs: Vec<_> = (1u32..=1_000_000)
+            .map(|i| {
+                let pkey = i.to_be_bytes();
+                (Md5::digest(&pkey).to_vec(), pkey.to_vec())
+            })
+            .collect();
+        pairs.sort_unstable();
+        let btree = BTree::bulk_load(&mut bufmgr, pairs.iter().cloned()).unwrap();
+        let mut iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+        bufmgr.reset_stats();
+        assert_eq!(500_000, iter.skip_entries(&mut bufmgr, 500_000).unwrap());
+        // 読むのはリーフごとに1度だけ
+        let stats = bufmgr.stats();
+        let leaf_pages = btree.stats(&mut bufmgr).unwrap().leaf_pages;
+        assert!(stats.hits + stats.misses <= leaf_pages / 2 + 1, "{:?}", stats);
+        let pos = iter.position();
+        assert_eq!(Some(pairs[500_000].clone()), iter.next(&mut bufmgr).unwrap());
+        let mut iter = btree.resume(&mut bufmgr, pos).unwrap();
+        assert_eq!(Some(pairs[500_000].clone()), iter.next(&mut bufmgr).unwrap());
+        // 途中から飛ばし、木の終わりを越えれば飛ばせた数だけ返す
+        let search_mode = SearchMode::KeyOrNext(pairs[999_000].0.clone());
+        let mut iter = btree.search(&mut bufmgr, search_mode).unwrap();
+        assert_eq!(0, iter.skip_entries(&mut bufmgr, 0).unwrap());
+        assert_eq!(999, iter.skip_entries(&mut bufmgr, 999).unwrap());
+        assert_eq!(Some(pairs[999_999].clone()), iter.next(&mut bufmgr).unwrap());
+        let search_mode = SearchMode::KeyOrNext(pairs[999_990].0.clone());
+        let mut iter = btree.search(&mut bufmgr, search_mode).unwrap();
+        assert_eq!(10, iter.skip_entries(&mut bufmgr, 100).unwrap());
+        assert_eq!(None, iter.next(&mut bufmgr).unwrap());
+
+        // 墓標は数えずに飛ばす
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        btree.set_tombstone_mode(&mut bufmgr, true).unwrap();
+        for i in 0u64..1000 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[0; 50]).unwrap();
+        }
+        for i in (0u64..1000).filter(|i| i % 3 != 0) {
+            btree.delete(&mut bufmgr, &i.to_be_bytes()).unwrap();
+        }
+        let mut iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+        assert_eq!(100, iter.skip_entries(&mut bufmgr, 100).unwrap());
+        let (key, _) = iter.next(&mut bufmgr).unwrap().unwrap();
+        assert_eq!(300u64.to_be_bytes().to_vec(), key);
+        assert_eq!(233, iter.skip_entries(&mut bufmgr, 1000).unwrap());
+        assert_eq!(None, iter.next(&mut bufmgr).unwrap());
+    }
+
+    #[test]
+    fn test_get_all() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            ..BTreeOptions::default()
+        };
+        let btree = BTree::create_with(&mut bufmgr, options).unwrap();
+        let value = |i: u64| [&i.to_be_bytes()[..], &[0; 40]].concat();
+        btree.insert(&mut bufmgr, b"b", &value(0)).unwrap();
+        // キーの前に、そのキーで始まるキーを挟む
+        for i in 0..500 {
+            btree.insert(&mut bufmgr, b"c", &value(i)).unwrap();
+            btree.insert(&mut bufmgr, b"c\0", &value(i)).unwrap();
+            btree.insert(&mut bufmgr, b"cc", &value(i)).unwrap();
+        }
+        let get_all = |bufmgr: &mut BufferPoolManager, key: &[u8]| {
+            btree.get_all(bufmgr, key).unwrap().collect::<Result<Vec<_>, _>>().unwrap()
+        };
+        assert!(get_all(&mut bufmgr, b"a").is_empty());
+        assert_eq!(vec![value(0)], get_all(&mut bufmgr, b"b"));
+        assert!(btree.stats(&mut bufmgr).unwrap().leaf_pages > 10);
+        let values = get_all(&mut bufmgr, b"c");
+        assert_eq!((0..500).map(value).collect::<Vec<_>>(), values);
+        assert!(get_all(&mut bufmgr, b"").is_empty());
+
+        // 重複を許さない木でも同じように呼べる
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        btree.insert(&mut bufmgr, b"ab", b"1").unwrap();
+        btree.insert(&mut bufmgr, b"abc", b"2").unwrap();
+        let get_all = |bufmgr: &mut BufferPoolManager, key: &[u8]| {
+            btree.get_all(bufmgr, key).unwrap().collect::<Result<Vec<_>, _>>().unwrap()
+        };
+        assert!(get_all(&mut bufmgr, b"a").is_empty());
+        assert_eq!(vec![b"1".to_vec()], get_all(&mut bufmgr, b"ab"));
+        assert_eq!(vec![b"2".to_vec()], get_all(&mut bufmgr, b"abc"));
+    }
+
+    #[test]
+    fn test_compact_into() {
+        let mut src = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let btree = BTree::create(&mut src).unwrap();
+        for i in 0..20000u64 {
+            let key = (i * 7919 % 20000).to_be_bytes();
+            btree.insert(&mut src, &key, &[i as u8; 50]).unwrap();
+        }
+        // まとめずに10個に9個を削除して、リーフを疎らにする
+        let mut iter = btree.search(&mut src, SearchMode::Start).unwrap();
+        while let Some((key, _)) = iter.next(&mut src).unwrap() {
+            if u64::from_be_bytes(key[..].try_into().unwrap()) % 10 != 0 {
+                assert!(iter.delete_current(&mut src).unwrap());
+            }
+        }
+        let pairs = scan(&mut src, &btree);
+        let src_pages = src.storage_info().unwrap().num_pages;
+
+        let mut dst = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let options = BulkLoadOptions {
+            fill_factor: 0.7,
+            ..BulkLoadOptions::default()
+        };
+        let compacted = btree.compact_into_with(&mut src, &mut dst, options).unwrap();
+        compacted.verify(&mut dst).unwrap();
+        assert_eq!(pairs, scan(&mut dst, &compacted));
+        assert_eq!(2000, compacted.len(&mut dst).unwrap());
+        let dst_pages = dst.storage_info().unwrap().num_pages;
+        assert!(dst_pages * 4 < src_pages, "{} pages from {}", dst_pages, src_pages);
+        let stats = compacted.stats(&mut dst).unwrap();
+        // 最後のリーフのほかは、詰める割合まで埋まる
+        assert!((60.0..=72.0).contains(&stats.avg_leaf_fill), "{}", stats);
+        // 元の木はそのまま
+        btree.verify(&mut src).unwrap();
+        assert_eq!(pairs, scan(&mut src, &btree));
+
+        // 重複を許す木は、重複を許す木に作り直す
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            ..BTreeOptions::default()
+        };
+        let multi = BTree::create_with(&mut src, options).unwrap();
+        for i in 0..1000u64 {
+            multi.insert(&mut src, &(i % 7).to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        let mut dst = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let compacted = multi.compact_into(&mut src, &mut dst).unwrap();
+        compacted.verify(&mut dst).unwrap();
+        assert_eq!(KeyMode::Multi, compacted.key_mode(&mut dst).unwrap());
+        assert_eq!(1000, compacted.len(&mut dst).unwrap());
+        assert_eq!(scan(&mut src, &multi), scan(&mut dst, &compacted));
+        assert_eq!(143, compacted.get_all(&mut dst, &3u64.to_be_bytes()).unwrap().count());
+
+        // キーの比べ方と、分割するときに詰める割合も元の木に合わせる
+        let options = BTreeOptions {
+            comparator: KeyComparatorId::CASE_INSENSITIVE_ASCII,
+            leaf_fill: 100,
+            branch_fill: 70,
+            ..BTreeOptions::default()
+        };
+        let btree = BTree::create_with(&mut src, options).unwrap();
+        for key in [&b"B"[..], b"a", b"c", b"D"] {
+            btree.insert(&mut src, key, key).unwrap();
+        }
+        let mut dst = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let compacted = btree.compact_into(&mut src, &mut dst).unwrap();
+        compacted.verify(&mut dst).unwrap();
+        let keys: Vec<_> = scan(&mut dst, &compacted).into_iter().map(|(key, _)| key).collect();
+        assert_eq!(vec![b"a".to_vec(), b"B".to_vec(), b"c".to_vec(), b"D".to_vec()], keys);
+        assert_eq!(Some(b"D".to_vec()), compacted.get(&mut dst, b"d").unwrap());
+        let meta = compacted.meta(&mut dst).unwrap();
+        assert_eq!((100, 70), (meta.leaf_fill, meta.branch_fill));
+    }
+
+    // 左端のリーフから順に、各リーフの最初と最後のペアを返す
+    #[allow(clippy::type_complexity)]
+    fn leaf_edges(
+        bufmgr: &mut BufferPoolManager,
+        btree: &BTree,
+    ) -> Vec<((Vec<u8>, Vec<u8>), (Vec<u8>, Vec<u8>))> {
+        let mut page_id = btree.meta(bufmgr).unwrap().root_page_id;
+        let mut edges = vec![];
+        loop {
+            let buffer = bufmgr.fetch_page(page_id).unwrap();
+            let node = node::Node::new(buffer.data());
+            let next_page_id = match node::Body::new(node.header.node_type, node.body) {
+                node::Body::Branch(branch) => Some(branch.child_at(0)),
+                node::Body::Leaf(leaf) => {
+                    let pair = |slot_id| {
+                        let pair = leaf.pair_at(slot_id);
+                        (pair.key.to_vec(), pair.value.to_vec())
+                    };
+                    edges.push((pair(0), pair(leaf.num_pairs() - 1)));
+                    leaf.next_page_id()
+                }
+            };
+            match next_page_id {
+                Some(next_page_id) => page_id = next_page_id,
+                None => return edges,
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_key_value() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        for node_version in [meta::NODE_VERSION, 0] {
+            let btree = BTree::create(&mut bufmgr).unwrap();
+            {
+                let meta_buffer = bufmgr.fetch_page_write(btree.meta_page_id).unwrap();
+                meta::Meta::new(meta_buffer.data_mut()).header.node_version = node_version;
+            }
+            // 値の空のペアがリーフの境目に来るよう、値を長くして何度も分割させる
+            let value = |i: u64| if i % 3 == 0 { vec![] } else { vec![1; 200] };
+            for i in (0u64..200).rev() {
+                btree.insert(&mut bufmgr, &i.to_be_bytes(), &value(i)).unwrap();
+            }
+            let edges = leaf_edges(&mut bufmgr, &btree);
+            assert!(edges.len() > 5);
+            let empty = btree.stored_value(&mut bufmgr, &0u64.to_be_bytes()).unwrap().unwrap();
+            assert!(edges.iter().any(|(first, last)| first.1 == empty || last.1 == empty));
+            // 空のキーはどのキーよりも小さい
+            btree.insert(&mut bufmgr, b"", b"").unwrap();
+            assert!(matches!(btree.insert(&mut bufmgr, b"", b"x"), Err(Error::DuplicateKey)));
+            btree.verify(&mut bufmgr).unwrap();
+            assert_eq!(Some(vec![]), btree.get(&mut bufmgr, b"").unwrap());
+            assert_eq!(Some((vec![], vec![])), btree.first(&mut bufmgr).unwrap());
+            let pairs = scan(&mut bufmgr, &btree);
+            assert_eq!(201, pairs.len());
+            for (i, (key, found)) in pairs[1..].iter().enumerate() {
+                assert_eq!((&(i as u64).to_be_bytes()[..], &value(i as u64)), (&key[..], found));
+            }
+            let desc = collect_desc(&mut bufmgr, &btree, SearchMode::KeyOrPrev(vec![]));
+            assert_eq!(vec![(vec![], vec![])], desc);
+            assert_eq!(201, collect_prefix(&mut bufmgr, &btree, b"").len());
+            assert!(btree.update(&mut bufmgr, b"", b"value").unwrap());
+            assert_eq!(Some(b"value".to_vec()), btree.get(&mut bufmgr, b"").unwrap());
+            assert!(btree.delete(&mut bufmgr, b"").unwrap());
+            for i in (0u64..200).step_by(3) {
+                assert_eq!(Some(vec![]), btree.get(&mut bufmgr, &i.to_be_bytes()).unwrap());
+                assert!(btree.delete(&mut bufmgr, &i.to_be_bytes()).unwrap());
+            }
+            btree.verify(&mut bufmgr).unwrap();
+            assert_eq!(None, btree.get(&mut bufmgr, b"").unwrap());
+            assert_eq!(133, btree.len(&mut bufmgr).unwrap());
+        }
+
+        // 重複を許す木でも、空のキーと空の値を区別なく扱う
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            ..BTreeOptions::default()
+        };
+        let btree = BTree::create_with(&mut bufmgr, options).unwrap();
+        for (key, value) in [(&b""[..], &b""[..]), (b"", b"a"), (b"a", b"")] {
+            btree.insert(&mut bufmgr, key, value).unwrap();
+        }
+        let values: Vec<_> = btree.get_all(&mut bufmgr, b"").unwrap().map(Result::unwrap).collect();
+        assert_eq!(vec![vec![], b"a".to_vec()], values);
+        assert!(btree.delete_pair(&mut bufmgr, b"", b"").unwrap());
+        let expected = vec![(vec![], b"a".to_vec()), (b"a".to_vec(), vec![])];
+        assert_eq!(expected, scan(&mut bufmgr, &btree));
+    }
+
+    #[test]
+    fn test_fill_factor() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(64));
+        // 先に最大のキーを置いておき、どの挿入も右端への追加にならないようにする
+        let mut build = |fill: u8| {
+            let options = BTreeOptions {
+                leaf_fill: fill,
+                branch_fill: fill,
+                ..BTreeOptions::default()
+            };
+            let btree = BTree::create_with(&mut bufmgr, options).unwrap();
+            btree.insert(&mut bufmgr, &u64::MAX.to_be_bytes(), &[0; 100]).unwrap();
+            for i in 0u64..20000 {
+                btree.insert(&mut bufmgr, &i.to_be_bytes(), &[0; 100]).unwrap();
+            }
+            btree.verify(&mut bufmgr).unwrap();
+            assert_eq!(fill, btree.meta(&mut bufmgr).unwrap().leaf_fill);
+            assert_eq!(20001, btree.iter(&mut bufmgr).count());
+            btree.stats(&mut bufmgr).unwrap()
+        };
+        let half = build(50);
+        let seventy = build(70);
+        let full = build(100);
+        assert!(seventy.leaf_pages * 10 > full.leaf_pages * 13, "{} {}", seventy, full);
+        assert!(half.leaf_pages * 10 > seventy.leaf_pages * 13, "{} {}", half, seventy);
+        assert!(seventy.branch_pages > full.branch_pages, "{} {}", seventy, full);
+        assert!(full.avg_leaf_fill > 95.0, "{}", full);
+
+        // 範囲外の値は、ページを作る前に断る
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        for (leaf_fill, branch_fill) in [(49, 50), (50, 101), (0, 100)] {
+            let options = BTreeOptions {
+                leaf_fill,
+                branch_fill,
+                ..BTreeOptions::default()
+            };
+            assert!(matches!(
+                BTree::create_with(&mut bufmgr, options),
+                Err(Error::InvalidFillFactor(_))
+            ));
+        }
+        assert_eq!(num_pages, bufmgr.storage_info().unwrap().num_pages);
+
+        // 既定では半分ずつに分ける
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let meta = btree.meta(&mut bufmgr).unwrap();
+        assert_eq!((50, 50), (meta.leaf_fill, meta.branch_fill));
+    }
+
+    #[test]
+    fn test_get_into() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(32));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        // オーバーフローページに置く値と、リーフに置く値
+        let long = (0..10000u32).map(|i| i as u8).collect::<Vec<_>>();
+        btree.insert(&mut bufmgr, b"long", &long).unwrap();
+        btree.insert(&mut bufmgr, b"short", b"abc").unwrap();
+        btree.insert(&mut bufmgr, b"empty", b"").unwrap();
+        for i in 0u64..1000 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[1; 20]).unwrap();
+        }
+
+        let mut value = vec![];
+        for _ in 0..2 {
+            assert!(btree.get_into(&mut bufmgr, b"long", &mut value).unwrap());
+            assert_eq!(long, value);
+            // 前の値より短い値でも、前の値は残らない
+            assert!(btree.get_into(&mut bufmgr, b"short", &mut value).unwrap());
+            assert_eq!(b"abc".to_vec(), value);
+            assert!(btree.get_into(&mut bufmgr, b"empty", &mut value).unwrap());
+            assert!(value.is_empty());
+            assert!(btree.get_into(&mut bufmgr, &7u64.to_be_bytes(), &mut value).unwrap());
+            assert_eq!(vec![1; 20], value);
+            value.extend_from_slice(b"garbage");
+            assert!(!btree.get_into(&mut bufmgr, b"missing", &mut value).unwrap());
+            assert!(value.is_empty());
+        }
+        assert_eq!(Some(long.clone()), btree.get(&mut bufmgr, b"long").unwrap());
+
+        for key in [&b"long"[..], b"short", b"empty", &999u64.to_be_bytes()] {
+            assert!(btree.contains_key(&mut bufmgr, key).unwrap());
+        }
+        for key in [&b"lon"[..], b"longer", b"", &1000u64.to_be_bytes()] {
+            assert!(!btree.contains_key(&mut bufmgr, key).unwrap());
+        }
+        // 値のオーバーフローページは読まない
+        bufmgr.reset_stats();
+        btree.get(&mut bufmgr, b"long").unwrap();
+        let get_stats = bufmgr.stats();
+        bufmgr.reset_stats();
+        btree.contains_key(&mut bufmgr, b"long").unwrap();
+        let contains_stats = bufmgr.stats();
+        assert!(
+            contains_stats.hits + contains_stats.misses + 2 < get_stats.hits + get_stats.misses
+        );
+
+        // 重複を許す木では、キーの先頭が一致するだけのペアを取り違えない
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            ..BTreeOptions::default()
+        };
+        let multi = BTree::create_with(&mut bufmgr, options).unwrap();
+        for (key, value) in [(&b"ab"[..], &b"1"[..]), (b"b", b"22"), (b"b", b"1")] {
+            multi.insert(&mut bufmgr, key, value).unwrap();
+        }
+        assert!(!multi.contains_key(&mut bufmgr, b"a").unwrap());
+        assert!(!multi.get_into(&mut bufmgr, b"a", &mut value).unwrap());
+        assert!(multi.contains_key(&mut bufmgr, b"b").unwrap());
+        value = b"longer value".to_vec();
+        assert!(multi.get_into(&mut bufmgr, b"b", &mut value).unwrap());
+        assert_eq!(b"1".to_vec(), value);
+
+        // 墓標はないペアとして扱う
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        btree.set_tombstone_mode(&mut bufmgr, true).unwrap();
+        btree.insert(&mut bufmgr, b"a", b"1").unwrap();
+        btree.insert(&mut bufmgr, b"b", b"2").unwrap();
+        btree.delete(&mut bufmgr, b"a").unwrap();
+        assert!(!btree.contains_key(&mut bufmgr, b"a").unwrap());
+        assert!(!btree.get_into(&mut bufmgr, b"a", &mut value).unwrap());
+        assert!(btree.contains_key(&mut bufmgr, b"b").unwrap());
+    }
+
+    #[test]
+    fn test_iter_with_inserts() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(64));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let key = |i: u64| i.to_be_bytes();
+        let num = |key: &[u8]| u64::from_be_bytes(key.try_into().unwrap());
+        for i in 0u64..3000 {
+            btree.insert(&mut bufmgr, &key(i * 100), &[0; 100]).unwrap();
+        }
+        let half_splits = btree.stats(&mut bufmgr).unwrap().half_splits;
+
+        // 返したペアのリーフに、そのキーより前のペアを挿入してスロットをずらし、分割させる
+        // 後ろに挿入したペアは、返すかどうかはリーフのどこに入ったかによる
+        let mut iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+        let mut yielded = vec![];
+        let mut inserted_after = vec![];
+        while let Some((found, _)) = iter.next(&mut bufmgr).unwrap() {
+            let k = num(&found);
+            yielded.push(k);
+            for j in 1..=4 {
+                if k >= j && k % 100 != j {
+                    btree.insert(&mut bufmgr, &key(k - j), &[1; 100]).unwrap();
+                }
+            }
+            if k % 100 == 0 {
+                btree.insert(&mut bufmgr, &key(k + 50), &[2; 100]).unwrap();
+                inserted_after.push(k + 50);
+            }
+        }
+        assert!(yielded.windows(2).all(|pair| pair[0] < pair[1]));
+        let existing: Vec<_> = yielded.iter().copied().filter(|k| k % 100 == 0).collect();
+        assert_eq!((0u64..3000).map(|i| i * 100).collect::<Vec<_>>(), existing);
+        assert!(yielded.iter().all(|k| k % 100 == 0 || inserted_after.contains(k)));
+        assert!(btree.stats(&mut bufmgr).unwrap().half_splits > half_splits + 100);
+        btree.verify(&mut bufmgr).unwrap();
+
+        // 何も返す前に、最初に返すペアの前へ挿入しても、探したキー以上のペアから返す
+        let search_mode = SearchMode::KeyOrNext(key(150000).to_vec());
+        let mut iter = btree.search(&mut bufmgr, search_mode).unwrap();
+        for i in 1..=40u64 {
+            btree.insert(&mut bufmgr, &key(150000 - 5 - i), &[3; 100]).unwrap();
+        }
+        let (found, _) = iter.next(&mut bufmgr).unwrap().unwrap();
+        assert_eq!(150000, num(&found));
+        drop(iter);
+
+        // 返したペアが分割で別のリーフに移っても、delete_currentはそのペアを削除する
+        let search_mode = SearchMode::KeyOrNext(key(200000).to_vec());
+        let mut iter = btree.search(&mut bufmgr, search_mode).unwrap();
+        assert_eq!(200000, num(&iter.next(&mut bufmgr).unwrap().unwrap().0));
+        for i in 1..=40u64 {
+            btree.insert(&mut bufmgr, &key(200000 - 5 - i), &[3; 100]).unwrap();
+        }
+        assert!(iter.delete_current(&mut bufmgr).unwrap());
+        // 走査で2000xxの前に挿入した4つのペアの先頭から続ける
+        assert_eq!(200046, num(&iter.next(&mut bufmgr).unwrap().unwrap().0));
+        assert_eq!(None, btree.get(&mut bufmgr, &key(200000)).unwrap());
+        assert!(btree.get(&mut bufmgr, &key(200000 - 6)).unwrap().is_some());
+        btree.verify(&mut bufmgr).unwrap();
+    }
+
+    #[test]
+    fn test_comparator() {
+        let page_size = 512;
+        let disk = MemoryDiskManager::with_page_size(page_size).unwrap();
+        let pool = BufferPool::new(64).with_page_size(page_size);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let btree =
+            BTree::create_with_comparator(&mut bufmgr, KeyComparatorId::CASE_INSENSITIVE_ASCII)
+                .unwrap();
+        btree.insert(&mut bufmgr, b"ABC", b"upper").unwrap();
+        assert!(matches!(btree.insert(&mut bufmgr, b"abc", b"lower"), Err(Error::DuplicateKey)));
+        assert_eq!(Some(b"upper".to_vec()), btree.get(&mut bufmgr, b"aBc").unwrap());
+
+        // 大文字と小文字を混ぜたキーで、リーフと枝を分割させる
+        for i in 0u64..2000 {
+            let key = if i % 2 == 0 { format!("key{:05}", i) } else { format!("KEY{:05}", i) };
+            btree.insert(&mut bufmgr, key.as_bytes(), &[0; 20]).unwrap();
+        }
+        btree.verify(&mut bufmgr).unwrap();
+        assert!(btree.meta(&mut bufmgr).unwrap().height >= 3);
+        let keys: Vec<_> = btree.iter(&mut bufmgr).map(|pair| pair.unwrap().0).collect();
+        let lower = |key: &Vec<u8>| key.to_ascii_lowercase();
+        assert!(keys.windows(2).all(|pair| lower(&pair[0]) < lower(&pair[1])));
+        assert_eq!(2001, keys.len());
+        assert!(btree.contains_key(&mut bufmgr, b"Key00101").unwrap());
+
+        // 範囲も大文字と小文字を区別せずに比べる
+        let mut iter = btree
+            .scan_range(&mut bufmgr, Bound::Excluded(b"KEY00100"), Bound::Included(b"key00103"))
+            .unwrap();
+        let mut found = vec![];
+        while let Some((key, _)) = iter.next(&mut bufmgr).unwrap() {
+            found.push(String::from_utf8(key).unwrap());
+        }
+        assert_eq!(vec!["KEY00101", "key00102", "KEY00103"], found);
+        drop(iter);
+        assert!(matches!(
+            btree.scan_prefix(&mut bufmgr, b"key"),
+            Err(Error::UnsupportedWithComparator(_))
+        ));
+        assert!(btree.delete(&mut bufmgr, b"key00101").unwrap());
+        assert!(!btree.contains_key(&mut bufmgr, b"KEY00101").unwrap());
+
+        // 開き直しても同じ比べ方を使う。登録した比べ方も選べる
+        let reopened = BTree::new(btree.meta_page_id);
+        assert_eq!(Some(b"upper".to_vec()), reopened.get(&mut bufmgr, b"abc").unwrap());
+        let reverse = KeyComparatorId(101);
+        register_comparator(reverse, |a, b| b.cmp(a)).unwrap();
+        let btree = BTree::create_with_comparator(&mut bufmgr, reverse).unwrap();
+        for i in 0u64..100 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[]).unwrap();
+        }
+        assert_eq!(reverse, btree.meta(&mut bufmgr).unwrap().comparator);
+        let reopened = BTree::new(btree.meta_page_id);
+        let first = reopened.first(&mut bufmgr).unwrap().unwrap().0;
+        assert_eq!(99u64.to_be_bytes().to_vec(), first);
+
+        // 登録していない比べ方の木は開けない
+        {
+            let meta_buffer = bufmgr.fetch_page(btree.meta_page_id).unwrap();
+            let mut meta = meta::Meta::new(meta_buffer.data_mut());
+            meta.header.comparator_id = 4242;
+            meta.bump_generation();
+        }
+        assert!(matches!(
+            reopened.get(&mut bufmgr, &1u64.to_be_bytes()),
+            Err(Error::UnknownComparator(KeyComparatorId(4242)))
+        ));
+        assert!(matches!(
+            reopened.insert(&mut bufmgr, &1000u64.to_be_bytes(), &[]),
+            Err(Error::UnknownComparator(_))
+        ));
+        assert!(matches!(
+            BTree::create_with_comparator(&mut bufmgr, KeyComparatorId(4242)),
+            Err(Error::UnknownComparator(_))
+        ));
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            comparator: KeyComparatorId::CASE_INSENSITIVE_ASCII,
+            ..BTreeOptions::default()
+        };
+        assert!(matches!(
+            BTree::create_with(&mut bufmgr, options),
+            Err(Error::UnsupportedOnMulti(_))
+        ));
+    }
+
+    #[test]
+    fn test_root_cache() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(256));
+        let fetches = |bufmgr: &BufferPoolManager| bufmgr.stats().hits + bufmgr.stats().misses;
+        let num_entries = 10000u64;
+
+        // 1つのハンドルで挿入すれば、根を探すためにメタページを読むのは根が変わったときだけ
+        // 作り直したハンドルは毎回メタページを読むので、挿入ごとに1回多く読む
+        // ペアの数や分割の回数を書き換えるときも、根を分割したときのほかはメタページを借りない
+        let cached = BTree::create(&mut bufmgr).unwrap();
+        let meta_access = |bufmgr: &BufferPoolManager| {
+            let frames = bufmgr.dump();
+            let frame = frames.iter().find(|frame| frame.page_id == cached.meta_page_id);
+            frame.map(|frame| frame.last_access)
+        };
+        bufmgr.reset_stats();
+        let mut meta_fetches = 0;
+        for i in 0..num_entries {
+            let last_access = meta_access(&bufmgr);
+            cached.insert(&mut bufmgr, &i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+            meta_fetches += (meta_access(&bufmgr) != last_access) as u64;
+        }
+        let cached_fetches = fetches(&bufmgr);
+        let fresh_meta_page_id = BTree::create(&mut bufmgr).unwrap().meta_page_id;
+        bufmgr.reset_stats();
+        for i in 0..num_entries {
+            let fresh = BTree::new(fresh_meta_page_id);
+            fresh.insert(&mut bufmgr, &i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+        }
+        let height = cached.meta(&mut bufmgr).unwrap().height as u64;
+        assert!(height >= 2);
+        // 最初に根を読むときと、根を分割するたびに書き換えて読み直すときだけ
+        assert!(meta_fetches < 2 * height, "{} meta page fetches", meta_fetches);
+        assert_eq!(Some(num_entries), cached.meta(&mut bufmgr).unwrap().num_entries);
+        assert!(fetches(&bufmgr) - cached_fetches >= num_entries - height);
+
+        // 読むだけなら、根からリーフまでのページのほかは借りない
+        bufmgr.reset_stats();
+        for i in 0..num_entries {
+            assert!(cached.contains_key(&mut bufmgr, &i.to_be_bytes()).unwrap());
         }
-    }   
-}
\ No newline at end of file
+        assert_eq!(num_entries * height, fetches(&bufmgr));
+
+        // ほかのハンドルで根を分割しても、新しい根からたどる
+        let other = BTree::new(cached.meta_page_id);
+        let mut i = num_entries;
+        while other.meta(&mut bufmgr).unwrap().height as u64 == height {
+            other.insert(&mut bufmgr, &i.to_be_bytes(), &i.to_be_bytes()).unwrap();
+            i += 1;
+        }
+        for key in (0..i).step_by(97) {
+            let value = cached.get(&mut bufmgr, &key.to_be_bytes()).unwrap();
+            assert_eq!(Some(key.to_be_bytes().to_vec()), value);
+        }
+        let first = cached.first(&mut bufmgr).unwrap().map(|pair| pair.0);
+        assert_eq!(Some(0u64.to_be_bytes().to_vec()), first);
+        cached.insert(&mut bufmgr, &i.to_be_bytes(), &[]).unwrap();
+        assert!(other.contains_key(&mut bufmgr, &i.to_be_bytes()).unwrap());
+
+        // 削除で根が子に置き換わっても同じ
+        for key in 100..=i {
+            assert!(other.delete(&mut bufmgr, &key.to_be_bytes()).unwrap());
+        }
+        assert_eq!(1, other.meta(&mut bufmgr).unwrap().height);
+        assert_eq!(100, cached.iter(&mut bufmgr).count());
+        assert!(!cached.contains_key(&mut bufmgr, &5000u64.to_be_bytes()).unwrap());
+        cached.verify(&mut bufmgr).unwrap();
+
+        // 壊した木は、先に根を読んでいたハンドルからも読めない
+        other.destroy(&mut bufmgr).unwrap();
+        assert!(matches!(cached.get(&mut bufmgr, &0u64.to_be_bytes()), Err(Error::Destroyed(_))));
+    }
+}
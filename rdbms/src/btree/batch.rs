@@ -0,0 +1,322 @@
+use super::{
+    leaf, multi_key, node, BTree, Error, Format, KeyComparator, KeyMode, OpMetrics,
+    WriteMode, WriteOutcome,
+};
+use crate::buffer::{BufferPoolManager, WriteGuard};
+
+// insert_batchで挿入した結果
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BatchResult {
+    pub inserted: usize,
+    // すでにあったので挿入しなかったペアの、並べ替えたあとのentriesでの位置
+    pub duplicates: Vec<usize>,
+    // 根からリーフまで降りた回数
+    pub descents: usize,
+}
+
+impl BTree {
+    // entriesをキーの順に並べ替えてから挿入する
+    // 同じリーフに入るペアは一度降りただけでまとめて挿入し、リーフに収まらないペアだけinsertと同じく分割しながら挿入する
+    // すでにあるキーや、entriesの中で2つめ以降の同じキーは挿入せず、duplicatesに記録して続ける
+    pub fn insert_batch(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        entries: &mut [(Vec<u8>, Vec<u8>)],
+    ) -> Result<BatchResult, Error> {
+        let format = self.format(bufmgr)?;
+        // 安定な並べ替えなので、同じキーは先に渡したペアを挿入する
+        match format.key_mode {
+            KeyMode::Unique => entries.sort_by(|(a, _), (b, _)| format.cmp.compare(a, b)),
+            KeyMode::Multi => entries.sort(),
+        }
+        let mut result = BatchResult::default();
+        // リーフに直接挿入して、まだメタページに数えていないペアの数
+        let mut uncounted = 0;
+        let inserted = self.insert_sorted(bufmgr, format, entries, &mut result, &mut uncounted);
+        // 途中で失敗しても、それまでに挿入したペアは数える
+        let counted = self.count_entries(bufmgr, &mut uncounted);
+        inserted?;
+        counted?;
+        Ok(result)
+    }
+
+    fn insert_sorted(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        format: Format,
+        entries: &[(Vec<u8>, Vec<u8>)],
+        result: &mut BatchResult,
+        uncounted: &mut i64,
+    ) -> Result<(), Error> {
+        // 今挿入しているリーフと、そのリーフに入るキーの上限
+        let mut target: Option<(WriteGuard, Option<Vec<u8>>)> = None;
+        for (idx, (key, value)) in entries.iter().enumerate() {
+            let entry = (key.len(), value.len());
+            let tree_key;
+            let (key, value) = match format.key_mode {
+                KeyMode::Unique => (&key[..], &value[..]),
+                KeyMode::Multi => {
+                    tree_key = multi_key(key, value);
+                    (&tree_key[..], &[][..])
+                }
+            };
+            format.check_fits(key, value, entry)?;
+            // 今のリーフに入らないキーになったら、根から降り直す
+            let beyond = |upper: &Option<Vec<u8>>| {
+                upper.as_ref().is_some_and(|upper| !format.cmp.lt(key, upper))
+            };
+            if target.as_ref().is_some_and(|(_, upper)| beyond(upper)) {
+                target = None;
+            }
+            let (buffer, upper) = match target.take() {
+                Some(target) => target,
+                None => {
+                    result.descents += 1;
+                    self.find_leaf_for_write(bufmgr, key, format.cmp)?
+                }
+            };
+            let found = {
+                let node = node::Node::new(buffer.data());
+                match node::Body::new(node.header.node_type, node.body) {
+                    node::Body::Leaf(leaf) => {
+                        let found = leaf.search_slot_id(key, format.cmp);
+                        (found, found.is_ok_and(|slot_id| leaf.is_tombstone(slot_id)))
+                    }
+                    node::Body::Branch(_) => unreachable!(),
+                }
+            };
+            let slot_id = match found {
+                (Ok(_), false) => {
+                    result.duplicates.push(idx);
+                    target = Some((buffer, upper));
+                    continue;
+                }
+                // 墓標のキーは、insertと同じく墓標を取り除いてから挿入する
+                (Ok(_), true) => None,
+                (Err(slot_id), _) => Some(slot_id),
+            };
+            let value = self.store_value(bufmgr, format, value)?;
+            result.inserted += 1;
+            let fits = slot_id.is_some_and(|slot_id| {
+                let node = node::Node::new(buffer.data_mut());
+                let mut leaf = leaf::Leaf::new(node.body);
+                leaf.insert(slot_id, key, &value).is_some()
+            });
+            if fits {
+                *uncounted += 1;
+                self.add_metrics(OpMetrics {
+                    bytes_copied: (entry.0 + entry.1) as u64,
+                    ..OpMetrics::default()
+                });
+                target = Some((buffer, upper));
+                continue;
+            }
+            // 収まらなければ根から挿入し直して分割し、次のペアは分割したあとの木を降り直す
+            drop(buffer);
+            self.count_entries(bufmgr, uncounted)?;
+            result.descents += 1;
+            let mut outcome = WriteOutcome::default();
+            let insertion = self.write_pair(bufmgr, key, &value, WriteMode::Insert, &mut outcome);
+            if let Err(err) = insertion {
+                format.free_value(bufmgr, &value)?;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn count_entries(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        uncounted: &mut i64,
+    ) -> Result<(), Error> {
+        if *uncounted > 0 {
+            let delta = *uncounted;
+            self.update_meta(bufmgr, false, |meta| meta.add_num_entries(delta))?;
+            *uncounted = 0;
+        }
+        Ok(())
+    }
+
+    // keyの入るリーフを書き込み用に借り、枝からわかるそのリーフのキーの上限を添えて返す
+    fn find_leaf_for_write(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+        cmp: KeyComparator,
+    ) -> Result<(WriteGuard, Option<Vec<u8>>), Error> {
+        let mut page_id = self.cached_root(bufmgr)?.root_page_id;
+        // 深い枝ほど上限は小さいので、見つかるたびに置き換える
+        let mut upper = None;
+        loop {
+            let buffer = bufmgr.fetch_page_write(page_id)?;
+            let child_page_id = {
+                let node = node::Node::new(buffer.data());
+                let is_leaf = node.header.node_type == node::NODE_TYPE_LEAF;
+                self.add_metrics(OpMetrics {
+                    branch_pages: (!is_leaf) as u64,
+                    leaf_pages: is_leaf as u64,
+                    ..OpMetrics::default()
+                });
+                match node::Body::new(node.header.node_type, node.body) {
+                    node::Body::Leaf(_) => None,
+                    node::Body::Branch(branch) => {
+                        let child_idx = branch.search_child_idx(key, cmp);
+                        if child_idx < branch.num_pairs() {
+                            upper = Some(branch.pair_at(child_idx).key.to_vec());
+                        }
+                        Some(branch.child_at(child_idx))
+                    }
+                }
+            };
+            match child_page_id {
+                Some(child_page_id) => page_id = child_page_id,
+                None => return Ok((buffer, upper)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::BTreeOptions;
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::MemoryDiskManager;
+
+    fn scan(bufmgr: &mut BufferPoolManager, btree: &BTree) -> Vec<(Vec<u8>, Vec<u8>)> {
+        btree.iter(bufmgr).map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_insert_batch() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for i in (0..2000u64).step_by(2) {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[0; 100]).unwrap();
+        }
+        let leaf_pages = btree.stats(&mut bufmgr).unwrap().leaf_pages;
+        // 逆順に渡しても、並べ替えてから挿入する
+        let mut entries: Vec<_> = (0..600u64)
+            .rev()
+            .map(|i| ((i * 2 + 1).to_be_bytes().to_vec(), vec![1; 100]))
+            .collect();
+        let result = btree.insert_batch(&mut bufmgr, &mut entries).unwrap();
+        assert_eq!(600, result.inserted);
+        assert!(result.duplicates.is_empty());
+        assert_eq!(1u64.to_be_bytes().to_vec(), entries[0].0);
+        let stats = btree.stats(&mut bufmgr).unwrap();
+        assert!(stats.leaf_pages > leaf_pages);
+        // 降りるのは、最初のリーフと分割のたびと、リーフを移るたびだけ
+        let splits = (stats.half_splits + stats.append_splits) as usize;
+        assert!(result.descents <= 1 + splits * 2 + leaf_pages as usize, "{:?}", result);
+        assert!(result.descents < 600 / 4, "{:?}", result);
+        btree.verify(&mut bufmgr).unwrap();
+        assert_eq!(1600, btree.len(&mut bufmgr).unwrap());
+        let expected: Vec<_> = (0..2000u64)
+            .filter(|i| i % 2 == 0 || *i < 1200)
+            .map(|i| (i.to_be_bytes().to_vec(), vec![(i % 2) as u8; 100]))
+            .collect();
+        assert_eq!(expected, scan(&mut bufmgr, &btree));
+    }
+
+    #[test]
+    fn test_insert_batch_split() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        btree.insert(&mut bufmgr, &0u64.to_be_bytes(), &[0; 200]).unwrap();
+        btree.insert(&mut bufmgr, &1000u64.to_be_bytes(), &[0; 200]).unwrap();
+        // 1つのリーフの中の隙間に、2度分割するだけのペアを挿入する
+        let mut entries: Vec<_> =
+            (1..=30u64).map(|i| (i.to_be_bytes().to_vec(), vec![1; 200])).collect();
+        let result = btree.insert_batch(&mut bufmgr, &mut entries).unwrap();
+        assert_eq!(30, result.inserted);
+        let stats = btree.stats(&mut bufmgr).unwrap();
+        assert_eq!((2, 3), (stats.height, stats.leaf_pages));
+        assert_eq!(2, stats.half_splits + stats.append_splits);
+        // 最初に一度降り、分割するたびに挿入し直すのと、分割したあとのリーフに降りるので2回ずつ
+        assert_eq!(5, result.descents);
+        btree.verify(&mut bufmgr).unwrap();
+        assert_eq!(32, btree.len(&mut bufmgr).unwrap());
+        let keys: Vec<_> = scan(&mut bufmgr, &btree).into_iter().map(|(key, _)| key).collect();
+        let expected: Vec<_> =
+            (0..=30u64).chain([1000]).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(expected, keys);
+    }
+
+    #[test]
+    fn test_insert_batch_duplicates() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for key in [&b"b"[..], b"d"] {
+            btree.insert(&mut bufmgr, key, b"old").unwrap();
+        }
+        let mut entries = vec![
+            (b"d".to_vec(), b"new".to_vec()),
+            (b"c".to_vec(), b"first".to_vec()),
+            (b"a".to_vec(), b"new".to_vec()),
+            (b"c".to_vec(), b"second".to_vec()),
+            (b"b".to_vec(), b"new".to_vec()),
+        ];
+        let result = btree.insert_batch(&mut bufmgr, &mut entries).unwrap();
+        assert_eq!(2, result.inserted);
+        assert_eq!(vec![1, 3, 4], result.duplicates);
+        let duplicates: Vec<_> = result.duplicates.iter().map(|&i| &entries[i]).collect();
+        assert_eq!(
+            vec![
+                &(b"b".to_vec(), b"new".to_vec()),
+                &(b"c".to_vec(), b"second".to_vec()),
+                &(b"d".to_vec(), b"new".to_vec()),
+            ],
+            duplicates
+        );
+        let expected = vec![
+            (b"a".to_vec(), b"new".to_vec()),
+            (b"b".to_vec(), b"old".to_vec()),
+            (b"c".to_vec(), b"first".to_vec()),
+            (b"d".to_vec(), b"old".to_vec()),
+        ];
+        assert_eq!(expected, scan(&mut bufmgr, &btree));
+        assert_eq!(4, btree.len(&mut bufmgr).unwrap());
+
+        // 重複を許す木では、キーと値の組が同じものだけを重複とみなす
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            ..BTreeOptions::default()
+        };
+        let btree = BTree::create_with(&mut bufmgr, options).unwrap();
+        btree.insert(&mut bufmgr, b"a", b"1").unwrap();
+        let mut entries = vec![
+            (b"a".to_vec(), b"2".to_vec()),
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"a".to_vec(), b"2".to_vec()),
+        ];
+        let result = btree.insert_batch(&mut bufmgr, &mut entries).unwrap();
+        assert_eq!((1, vec![0, 2]), (result.inserted, result.duplicates));
+        assert_eq!(2, btree.len(&mut bufmgr).unwrap());
+    }
+
+    #[test]
+    fn test_insert_batch_error() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        btree.insert(&mut bufmgr, b"a", b"old").unwrap();
+        // 並べ替えると最後になる大きすぎるキーで失敗しても、それまでのペアは数える
+        let mut entries: Vec<_> = (0..10u8).map(|i| (vec![b'b', i], vec![i; 10])).collect();
+        entries.push((vec![b'z'; 4096], vec![]));
+        btree.reset_op_metrics();
+        assert!(matches!(
+            btree.insert_batch(&mut bufmgr, &mut entries),
+            Err(Error::TooLargeEntry { .. })
+        ));
+        // リーフに直接挿入したペアも、コピーしたバイト数に数える
+        assert_eq!(10 * 12, btree.op_metrics().bytes_copied);
+        assert_eq!(11, btree.len(&mut bufmgr).unwrap());
+        assert_eq!(11, scan(&mut bufmgr, &btree).len());
+        btree.verify(&mut bufmgr).unwrap();
+    }
+}
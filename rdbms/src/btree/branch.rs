@@ -2,7 +2,7 @@ use std::mem::size_of;
 
 use zerocopy::{AsBytes, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified};
 
-use super::Pair;
+use super::{KeyComparator, Pair};
 use crate::bsearch::binary_search_by;
 use crate::disk::PageId;
 use crate::slotted::{self, Slotted};
@@ -13,6 +13,12 @@ pub struct Header {
     right_child: PageId,
 }
 
+// lenバイトの枝のノードに置けるペアの大きさ
+pub fn max_pair_size(len: usize) -> usize {
+    let capacity = len - size_of::<Header>() - size_of::<slotted::Header>();
+    capacity / 2 - size_of::<slotted::Pointer>()
+}
+
 pub struct Branch<B> {
     header: LayoutVerified<B, Header>,
     body: Slotted<B>,
@@ -30,19 +36,19 @@ impl<B: ByteSlice> Branch<B> {
         self.body.num_slots()
     }
 
-    pub fn search_slot_id(&self, key: &[u8]) -> Result<usize, usize> {
+    pub fn search_slot_id(&self, key: &[u8], cmp: KeyComparator) -> Result<usize, usize> {
         binary_search_by(self.num_pairs(), |slot_id| {
-            self.pair_at(slot_id).key.cmp(&key)
+            cmp.compare(self.pair_at(slot_id).key, key)
         })
     }
 
-    pub fn search_child(&self, key: &[u8]) -> PageId {
-        let child_idx = self.search_child_idx(key);
+    pub fn search_child(&self, key: &[u8], cmp: KeyComparator) -> PageId {
+        let child_idx = self.search_child_idx(key, cmp);
         self.child_at(child_idx)
     }
 
-    pub fn search_child_idx(&self, key: &[u8]) -> usize {
-        match self.search_slot_id(key) {
+    pub fn search_child_idx(&self, key: &[u8], cmp: KeyComparator) -> usize {
+        match self.search_slot_id(key, cmp) {
             Ok(slot_id) => slot_id + 1,
             Err(slot_id) => slot_id,
         }
@@ -56,13 +62,47 @@ impl<B: ByteSlice> Branch<B> {
         }
     }
 
-    pub fn pair_at(&self, slot_id: usize) -> Pair {
+    pub fn pair_at(&self, slot_id: usize) -> Pair<'_> {
         Pair::from_bytes(&self.body[slot_id])
     }
 
+    // スロットが食い違っておらず、どのスロットもキーと子のページIDの組として読めるか確かめる
+    pub fn check(&self) -> Result<(), String> {
+        self.body.check()?;
+        for slot_id in 0..self.num_pairs() {
+            match Pair::try_from_bytes(&self.body[slot_id]) {
+                Some(pair) if pair.value.len() == size_of::<PageId>() => {}
+                _ => return Err(format!("slot {} is not a key and a child", slot_id)),
+            }
+        }
+        Ok(())
+    }
+
     pub fn max_pair_size(&self) -> usize {
         self.body.capacity() / 2 - size_of::<slotted::Pointer>()
     }
+
+    // どんな区切りのキーを加えても分割しないならtrue
+    pub fn has_room_for_any(&self) -> bool {
+        self.max_pair_size() + size_of::<slotted::Pointer>() <= self.body.free_space()
+    }
+
+    // 使う領域が容量の1/4を下回っている
+    pub fn is_underflow(&self) -> bool {
+        4 * self.body.used_space() < self.body.capacity()
+    }
+
+    // 区切りのkeyを挟んで、右隣のノードの子をすべて移せるならtrue
+    pub fn can_merge(&self, key: &[u8], right: &Branch<impl ByteSlice>) -> bool {
+        let pair_size = Pair {
+            key,
+            value: self.header.right_child.as_bytes(),
+        }
+        .to_bytes()
+        .len();
+        self.body.used_space() + size_of::<slotted::Pointer>() + pair_size + right.body.used_space()
+            <= self.body.capacity()
+    }
 }
 
 impl<B: ByteSliceMut> Branch<B> {
@@ -73,6 +113,31 @@ impl<B: ByteSliceMut> Branch<B> {
         self.header.right_child = right_child;
     }
 
+    // 子を1つだけ持つノードにする。append_withinで右に子を加えていく
+    pub fn initialize_with_child(&mut self, child: PageId) {
+        self.body.initialize();
+        self.header.right_child = child;
+    }
+
+    // 右端に子を加える。keyは今の右端の子のどのキーよりも大きく、childのキー以下にする
+    // 使う領域がfill_factorの割合を越えるならNoneを返す
+    #[must_use = "append may fail"]
+    pub fn append_within(&mut self, key: &[u8], child: PageId, fill_factor: f64) -> Option<()> {
+        let right_child = self.header.right_child;
+        let pair_size = Pair {
+            key,
+            value: right_child.as_bytes(),
+        }
+        .to_bytes()
+        .len();
+        if !self.body.fits_within(pair_size, fill_factor) {
+            return None;
+        }
+        self.insert(self.num_pairs(), key, right_child)?;
+        self.header.right_child = child;
+        Some(())
+    }
+
     pub fn fill_right_child(&mut self) -> Vec<u8> {
         let last_id = self.num_pairs() - 1;
         let Pair { key, value } = self.pair_at(last_id);
@@ -96,38 +161,115 @@ impl<B: ByteSliceMut> Branch<B> {
         Some(())
     }
 
-    fn is_half_full(&self) -> bool {
-        2 * self.body.free_space() < self.body.capacity()
+    // 子を指したまま、区切りのキーを置き換える。収まらなければNoneを返す
+    #[must_use = "update may fail"]
+    pub fn update_key(&mut self, slot_id: usize, key: &[u8]) -> Option<()> {
+        let child = self.child_at(slot_id);
+        let pair_bytes = Pair {
+            key,
+            value: child.as_bytes(),
+        }
+        .to_bytes();
+        assert!(pair_bytes.len() <= self.max_pair_size());
+        self.body.resize(slot_id, pair_bytes.len())?;
+        self.body[slot_id].copy_from_slice(&pair_bytes);
+        Some(())
+    }
+
+    // child_idx番目の子とその右隣の子の間のキーを取り除き、2つの子をchild_idx番目の子にまとめる
+    pub fn merge_children(&mut self, child_idx: usize) {
+        let child = self.child_at(child_idx);
+        self.body.remove(child_idx);
+        if child_idx == self.num_pairs() {
+            self.header.right_child = child;
+        } else {
+            let key = self.pair_at(child_idx).key.to_vec();
+            let pair_bytes = Pair {
+                key: &key,
+                value: child.as_bytes(),
+            }
+            .to_bytes();
+            self.body[child_idx].copy_from_slice(&pair_bytes);
+        }
+    }
+
+    // 区切りのkeyを挟んで、右隣のノードの子をすべて右端に加える
+    pub fn merge(&mut self, key: &[u8], right: &mut Branch<impl ByteSliceMut>) {
+        let right_child = self.header.right_child;
+        self.insert(self.num_pairs(), key, right_child)
+            .expect("merged branch must have space");
+        let num_pairs = right.num_pairs();
+        right
+            .body
+            .transfer_front(&mut self.body, num_pairs)
+            .expect("merged branch must have space");
+        self.header.right_child = right.header.right_child;
+    }
+
+    // 区切りのkeyを挟んで、右隣のノードの最初の子を右端に移す
+    // 新しい区切りのキーは、右隣のノードの最初のキーになる
+    pub fn borrow_first(&mut self, key: &[u8], right: &mut Branch<impl ByteSliceMut>) {
+        let right_child = self.header.right_child;
+        self.insert(self.num_pairs(), key, right_child)
+            .expect("underflowed branch must have space");
+        self.header.right_child = right.child_at(0);
+        right.body.remove(0);
+    }
+
+    // 区切りのkeyを挟んで、左隣のノードの右端の子を先頭に移す
+    // 新しい区切りのキーは、左隣のノードの最後のキーになる
+    pub fn borrow_last(&mut self, key: &[u8], left: &mut Branch<impl ByteSliceMut>) {
+        self.insert(0, key, left.header.right_child)
+            .expect("underflowed branch must have space");
+        left.fill_right_child();
+    }
+
+    // 使う領域が容量のfill%を越えている
+    fn is_filled(&self, fill: u8) -> bool {
+        100 * self.body.free_space() < self.body.capacity() * (100 - fill as usize)
     }
 
+    // リーフと同じく、new_branchが容量のfill%を越えるか次のペアが入らなくなるまで移す
     pub fn split_insert(
         &mut self,
         new_branch: &mut Branch<impl ByteSliceMut>,
         new_key: &[u8],
         new_page_id: PageId,
+        fill: u8,
+        cmp: KeyComparator,
     ) -> Vec<u8> {
         new_branch.body.initialize();
+        let new_len = Pair {
+            key: new_key,
+            value: new_page_id.as_bytes(),
+        }
+        .to_bytes()
+        .len();
+        let mut inserted = false;
         loop {
-            if new_branch.is_half_full() {
-                let index = self
-                    .search_slot_id(new_key)
-                    .expect_err("key must be unique");
-                self.insert(index, new_key, new_page_id)
-                    .expect("old branch must have space");
+            let next_is_new =
+                !inserted && (self.num_pairs() == 0 || cmp.lt(new_key, self.pair_at(0).key));
+            let next_len = if next_is_new { new_len } else { self.body[0].len() };
+            let has_room = next_len + size_of::<slotted::Pointer>() <= new_branch.body.free_space();
+            if new_branch.is_filled(fill) || !has_room {
                 break;
             }
-            if self.pair_at(0).key < new_key {
-                self.transfer(new_branch);
-            } else {
+            if next_is_new {
                 new_branch
                     .insert(new_branch.num_pairs(), new_key, new_page_id)
                     .expect("new branch must have space");
-                while !new_branch.is_half_full() {
-                    self.transfer(new_branch);
-                }
-                break;
+                inserted = true;
+            } else {
+                self.transfer(new_branch);
             }
         }
+        if !inserted {
+            let index = self
+                .search_slot_id(new_key, cmp)
+                .expect_err("key must be unique");
+            self.insert(index, new_key, new_page_id)
+                .expect("old branch must have space");
+        }
         new_branch.fill_right_child()
     }
 
@@ -150,15 +292,70 @@ mod tests {
         let mut data = vec![0u8; 100];
         let mut branch = Branch::new(data.as_mut_slice());
         branch.initialize(&5u64.to_be_bytes(), PageId(1), PageId(2));
+        assert_eq!(max_pair_size(100), branch.max_pair_size());
         branch.insert(1, &8u64.to_be_bytes(), PageId(3)).unwrap();
         branch.insert(2, &11u64.to_be_bytes(), PageId(4)).unwrap();
-        assert_eq!(PageId(1), branch.search_child(&1u64.to_be_bytes()));
-        assert_eq!(PageId(3), branch.search_child(&5u64.to_be_bytes()));
-        assert_eq!(PageId(3), branch.search_child(&6u64.to_be_bytes()));
-        assert_eq!(PageId(4), branch.search_child(&8u64.to_be_bytes()));
-        assert_eq!(PageId(4), branch.search_child(&10u64.to_be_bytes()));
-        assert_eq!(PageId(2), branch.search_child(&11u64.to_be_bytes()));
-        assert_eq!(PageId(2), branch.search_child(&12u64.to_be_bytes()));
+        assert_eq!(PageId(1), branch.search_child(&1u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(3), branch.search_child(&5u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(3), branch.search_child(&6u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(4), branch.search_child(&8u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(4), branch.search_child(&10u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(2), branch.search_child(&11u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(2), branch.search_child(&12u64.to_be_bytes(), KeyComparator::MEMCMP));
+    }
+
+    #[test]
+    fn test_append() {
+        let mut data = vec![0u8; 100];
+        let mut branch = Branch::new(data.as_mut_slice());
+        branch.initialize_with_child(PageId(1));
+        assert_eq!(PageId(1), branch.search_child(&1u64.to_be_bytes(), KeyComparator::MEMCMP));
+        branch.append_within(&5u64.to_be_bytes(), PageId(2), 1.0).unwrap();
+        branch.append_within(&8u64.to_be_bytes(), PageId(3), 1.0).unwrap();
+        assert_eq!(PageId(1), branch.search_child(&4u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(2), branch.search_child(&5u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(3), branch.search_child(&9u64.to_be_bytes(), KeyComparator::MEMCMP));
+        // 使う領域が半分を越えるので加えない
+        assert_eq!(None, branch.append_within(&11u64.to_be_bytes(), PageId(4), 0.5));
+        assert_eq!(2, branch.num_pairs());
+        assert_eq!(PageId(3), branch.search_child(&12u64.to_be_bytes(), KeyComparator::MEMCMP));
+    }
+
+    #[test]
+    fn test_merge() {
+        let key = |i: u64| i.to_be_bytes();
+        let mut left_data = vec![0u8; 200];
+        let mut left = Branch::new(left_data.as_mut_slice());
+        left.initialize(&key(5), PageId(1), PageId(2));
+        let mut right_data = vec![0u8; 200];
+        let mut right = Branch::new(right_data.as_mut_slice());
+        right.initialize(&key(20), PageId(3), PageId(4));
+        right.insert(1, &key(30), PageId(5)).unwrap();
+
+        // 区切りのキーは10。借りたあとは、右のノードの最初のキーだった20になる
+        left.borrow_first(&key(10), &mut right);
+        assert_eq!(PageId(3), left.search_child(&key(15), KeyComparator::MEMCMP));
+        assert_eq!(&key(30), right.pair_at(0).key);
+        right.borrow_last(&key(20), &mut left);
+        assert_eq!(PageId(3), right.search_child(&key(15), KeyComparator::MEMCMP));
+        assert_eq!(&key(5), left.pair_at(0).key);
+        assert_eq!(PageId(2), left.search_child(&key(9), KeyComparator::MEMCMP));
+
+        assert!(left.can_merge(&key(10), &right));
+        left.merge(&key(10), &mut right);
+        assert_eq!(4, left.num_pairs());
+        for (k, page_id) in [(1, 1), (5, 2), (10, 3), (20, 5), (30, 4)] {
+            assert_eq!(PageId(page_id), left.search_child(&key(k), KeyComparator::MEMCMP));
+        }
+
+        left.update_key(1, &key(11)).unwrap();
+        assert_eq!(PageId(2), left.search_child(&key(10), KeyComparator::MEMCMP));
+        left.merge_children(1);
+        assert_eq!(3, left.num_pairs());
+        assert_eq!(PageId(2), left.search_child(&key(15), KeyComparator::MEMCMP));
+        assert_eq!(PageId(5), left.search_child(&key(20), KeyComparator::MEMCMP));
+        left.merge_children(2);
+        assert_eq!(PageId(5), left.search_child(&key(30), KeyComparator::MEMCMP));
     }
 
     #[test]
@@ -171,19 +368,21 @@ mod tests {
 
         let mut data2 = vec![0u8; 100];
         let mut branch2 = Branch::new(data2.as_mut_slice());
-        let mid_key = branch.split_insert(&mut branch2, &10u64.to_be_bytes(), PageId(5));
+        let new_key = 10u64.to_be_bytes();
+        let mid_key =
+            branch.split_insert(&mut branch2, &new_key, PageId(5), 50, KeyComparator::MEMCMP);
         assert_eq!(&8u64.to_be_bytes(), mid_key.as_slice());
 
         assert_eq!(2, branch.num_pairs());
         assert_eq!(1, branch2.num_pairs());
 
-        assert_eq!(PageId(1), branch2.search_child(&1u64.to_be_bytes()));
-        assert_eq!(PageId(3), branch2.search_child(&5u64.to_be_bytes()));
-        assert_eq!(PageId(3), branch2.search_child(&6u64.to_be_bytes()));
+        assert_eq!(PageId(1), branch2.search_child(&1u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(3), branch2.search_child(&5u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(3), branch2.search_child(&6u64.to_be_bytes(), KeyComparator::MEMCMP));
 
-        assert_eq!(PageId(5), branch.search_child(&9u64.to_be_bytes()));
-        assert_eq!(PageId(4), branch.search_child(&10u64.to_be_bytes()));
-        assert_eq!(PageId(2), branch.search_child(&11u64.to_be_bytes()));
-        assert_eq!(PageId(2), branch.search_child(&12u64.to_be_bytes()));
+        assert_eq!(PageId(5), branch.search_child(&9u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(4), branch.search_child(&10u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(2), branch.search_child(&11u64.to_be_bytes(), KeyComparator::MEMCMP));
+        assert_eq!(PageId(2), branch.search_child(&12u64.to_be_bytes(), KeyComparator::MEMCMP));
     }
 }
@@ -0,0 +1,278 @@
+use super::{
+    branch, leaf, meta, multi_key, node, BTree, BTreeOptions, Error, Format, KeyComparatorId,
+    KeyMode,
+};
+use crate::buffer::{BufferPoolManager, PinnedBuffer};
+use crate::disk::{PageId, TablespaceId};
+
+#[derive(Debug, Clone, Copy)]
+pub struct BulkLoadOptions {
+    pub tablespace_id: TablespaceId,
+    // ノードに詰める領域の割合。あとから挿入するなら、すぐに分割しないよう余裕を残しておく
+    pub fill_factor: f64,
+    // 重複を許す木では、キーと値の組の順に並べて渡す
+    pub key_mode: KeyMode,
+    // キーはこの比べ方の順に並べて渡す
+    pub comparator: KeyComparatorId,
+    // 作ったあとに挿入してノードを分割するときに、左のノードへ詰める百分率
+    pub leaf_fill: u8,
+    pub branch_fill: u8,
+}
+
+impl Default for BulkLoadOptions {
+    fn default() -> Self {
+        Self {
+            tablespace_id: TablespaceId::DEFAULT,
+            fill_factor: 0.9,
+            key_mode: KeyMode::Unique,
+            comparator: KeyComparatorId::MEMCMP,
+            leaf_fill: meta::DEFAULT_FILL,
+            branch_fill: meta::DEFAULT_FILL,
+        }
+    }
+}
+
+// 整列済みのペアから木を作る
+// リーフを左から順に詰めてから、枝のノードを下の段から作るので、挿入を繰り返すより読み書きするページが少ない
+impl BTree {
+    pub fn bulk_load(
+        bufmgr: &mut BufferPoolManager,
+        iter: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<Self, Error> {
+        Self::bulk_load_with(bufmgr, iter, BulkLoadOptions::default())
+    }
+
+    // キーは昇順で重複しないこと。そうでなければ、作りかけのページを解放してError::UnsortedKeysを返す
+    // 重複を許す木では、キーと値の組が昇順で重複しないこと
+    pub fn bulk_load_with(
+        bufmgr: &mut BufferPoolManager,
+        iter: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+        options: BulkLoadOptions,
+    ) -> Result<Self, Error> {
+        let mut iter = iter.into_iter();
+        Self::bulk_load_from(bufmgr, options, |_| Ok(iter.next()))
+    }
+
+    // nextが返すペアを詰める。nextにもbufmgrを渡すので、同じバッファプールの木を読みながら作れる
+    // nextがエラーを返したら、作りかけのページを解放してそのエラーを返す
+    pub(super) fn bulk_load_from(
+        bufmgr: &mut BufferPoolManager,
+        options: BulkLoadOptions,
+        next: impl FnMut(&mut BufferPoolManager) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error>,
+    ) -> Result<Self, Error> {
+        if !(options.fill_factor > 0.0 && options.fill_factor <= 1.0) {
+            return Err(Error::InvalidLoadFactor(options.fill_factor));
+        }
+        let btree_options = BTreeOptions {
+            tablespace_id: options.tablespace_id,
+            key_mode: options.key_mode,
+            comparator: options.comparator,
+            leaf_fill: options.leaf_fill,
+            branch_fill: options.branch_fill,
+        };
+        let btree = Self::create_with(bufmgr, btree_options)?;
+        let format = btree.format(bufmgr)?;
+        let root_page_id = {
+            let meta_buffer = bufmgr.fetch_page_read(btree.meta_page_id)?;
+            let meta = meta::Meta::new(meta_buffer.data());
+            meta.header.root_page_id
+        };
+        // 段ごとの、各ノードの最初のキーとページID。最初のノードのキーは使わない
+        let mut levels = vec![vec![(vec![], root_page_id)]];
+        let loaded = btree
+            .load_leaves(bufmgr, format, next, options.fill_factor, &mut levels[0])
+            .and_then(|num_entries| {
+                btree.load_root(bufmgr, options.fill_factor, &mut levels, num_entries)
+            });
+        if let Err(err) = loaded {
+            // 片付けに失敗しても、元のエラーを返す
+            let _ = btree.free_levels(bufmgr, format, &levels);
+            return Err(err);
+        }
+        Ok(btree)
+    }
+
+    // リーフの段から根まで枝の段を作り、メタページに根を記録する
+    fn load_root(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        fill_factor: f64,
+        levels: &mut Vec<Vec<(Vec<u8>, PageId)>>,
+        num_entries: u64,
+    ) -> Result<(), Error> {
+        while let Some(children) = levels.last().filter(|level| level.len() > 1) {
+            let mut parents = vec![];
+            let loaded = self.load_branches(bufmgr, children, fill_factor, &mut parents);
+            levels.push(parents);
+            loaded?;
+        }
+        let meta_buffer = bufmgr.fetch_page_write(self.meta_page_id)?;
+        let mut meta = meta::Meta::new(meta_buffer.data_mut());
+        meta.set_root_page_id(levels[levels.len() - 1][0].1);
+        meta.header.height = levels.len() as u64;
+        meta.set_num_entries(num_entries);
+        Ok(())
+    }
+
+    // 詰めたペアの数を返す
+    fn load_leaves(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        format: Format,
+        mut next: impl FnMut(&mut BufferPoolManager) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error>,
+        fill_factor: f64,
+        leaves: &mut Vec<(Vec<u8>, PageId)>,
+    ) -> Result<u64, Error> {
+        let mut buffer = bufmgr.fetch_page(leaves[0].1)?;
+        let mut prev_key: Option<Vec<u8>> = None;
+        let mut num_entries = 0;
+        while let Some((key, value)) = next(bufmgr)? {
+            let entry = (key.len(), value.len());
+            let (key, value) = match format.key_mode {
+                KeyMode::Unique => (key, value),
+                KeyMode::Multi => (multi_key(&key, &value), vec![]),
+            };
+            let unsorted = |prev_key: &&Vec<u8>| !format.cmp.lt(prev_key, &key);
+            if let Some(prev_key) = prev_key.as_ref().filter(unsorted) {
+                let prev_key = format.user_key(prev_key);
+                let key = format.user_key(&key);
+                return Err(Error::UnsortedKeys { prev_key, key });
+            }
+            format.check_fits(&key, &value, entry)?;
+            let value = self.store_value(bufmgr, format, &value)?;
+            let appended = {
+                let node = node::Node::new(buffer.data_mut());
+                let mut leaf = leaf::Leaf::new(node.body);
+                // 空のリーフには、詰める割合を超えても加える
+                let fill_factor = if leaf.num_pairs() == 0 { 1.0 } else { fill_factor };
+                leaf.append_within(&key, &value, fill_factor)
+            };
+            if appended.is_none() {
+                let new_buffer = self.create_page(bufmgr)?;
+                {
+                    let mut node = node::Node::new(new_buffer.data_mut());
+                    node.initialize_as_leaf();
+                    let mut leaf = leaf::Leaf::new(node.body);
+                    leaf.initialize();
+                    leaf.set_prev_page_id(Some(buffer.page_id));
+                    leaf.append_within(&key, &value, 1.0).expect("new leaf must have space");
+                }
+                {
+                    let node = node::Node::new(buffer.data_mut());
+                    leaf::Leaf::new(node.body).set_next_page_id(Some(new_buffer.page_id));
+                }
+                // 空のリーフには必ず加えられるので、前のリーフにはペアがある
+                let prev_key = prev_key.as_deref().expect("previous leaf must have a pair");
+                leaves.push((format.cmp.separator(prev_key, &key), new_buffer.page_id));
+                buffer = new_buffer;
+            }
+            prev_key = Some(key);
+            num_entries += 1;
+        }
+        Ok(num_entries)
+    }
+
+    // 子の段から、その親の段をparentsに作る
+    // どの枝にも子を2つ以上持たせるので、親の段は子の段より必ず小さくなる
+    fn load_branches(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        children: &[(Vec<u8>, PageId)],
+        fill_factor: f64,
+        parents: &mut Vec<(Vec<u8>, PageId)>,
+    ) -> Result<(), Error> {
+        let mut buffer: Option<PinnedBuffer> = None;
+        for (key, child) in children {
+            if let Some(buffer) = &buffer {
+                let node = node::Node::new(buffer.data_mut());
+                let mut branch = branch::Branch::new(node.body);
+                // 子が1つだけの枝には、詰める割合を超えても加える
+                // キーは枝に置けることをcheck_fitsで確かめてあるので、空の枝には必ず加えられる
+                let fill_factor = if branch.num_pairs() == 0 { 1.0 } else { fill_factor };
+                if branch.append_within(key, *child, fill_factor).is_some() {
+                    continue;
+                }
+            }
+            let new_buffer = self.create_page(bufmgr)?;
+            {
+                let mut node = node::Node::new(new_buffer.data_mut());
+                node.initialize_as_branch();
+                branch::Branch::new(node.body).initialize_with_child(*child);
+            }
+            parents.push((key.clone(), new_buffer.page_id));
+            buffer = Some(new_buffer);
+        }
+        Ok(())
+    }
+
+    // すべてのペアを読み、dst_bufmgrに詰めた新しい木を作る。元の木は書き換えない
+    // 削除を繰り返してリーフが疎らになった木を、別のファイルに作り直すときに使う
+    pub fn compact_into(
+        &self,
+        src_bufmgr: &mut BufferPoolManager,
+        dst_bufmgr: &mut BufferPoolManager,
+    ) -> Result<BTree, Error> {
+        self.compact_into_with(src_bufmgr, dst_bufmgr, BulkLoadOptions::default())
+    }
+
+    // キーの重複を許すかどうかとキーの比べ方、分割するときに詰める割合は、optionsによらず元の木に合わせる
+    pub fn compact_into_with(
+        &self,
+        src_bufmgr: &mut BufferPoolManager,
+        dst_bufmgr: &mut BufferPoolManager,
+        options: BulkLoadOptions,
+    ) -> Result<BTree, Error> {
+        // 元の木の比べ方の順に読むので、新しい木も同じ比べ方で詰める
+        let format = self.format(src_bufmgr)?;
+        let options = BulkLoadOptions {
+            key_mode: format.key_mode,
+            comparator: format.cmp.id,
+            leaf_fill: format.leaf_fill,
+            branch_fill: format.branch_fill,
+            ..options
+        };
+        let mut scan_error = None;
+        let pairs = self.iter(src_bufmgr).map_while(|pair| match pair {
+            Ok(pair) => Some(pair),
+            Err(err) => {
+                scan_error = Some(err);
+                None
+            }
+        });
+        let btree = Self::bulk_load_with(dst_bufmgr, pairs, options)?;
+        // 途中までしか読めなければ、作った木を捨てる
+        if let Some(err) = scan_error {
+            let _ = btree.destroy(dst_bufmgr);
+            return Err(err);
+        }
+        Ok(btree)
+    }
+
+    // 作りかけの木を、値のオーバーフローページとメタページも含めて解放する
+    fn free_levels(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        format: Format,
+        levels: &[Vec<(Vec<u8>, PageId)>],
+    ) -> Result<(), Error> {
+        for &(_, page_id) in levels[1..].iter().flatten() {
+            bufmgr.free_page(page_id)?;
+        }
+        for &(_, page_id) in &levels[0] {
+            let values: Vec<_> = {
+                let buffer = bufmgr.fetch_page_read(page_id)?;
+                let node = node::Node::new(buffer.data());
+                let leaf = leaf::Leaf::new(node.body);
+                (0..leaf.num_pairs())
+                    .map(|slot_id| leaf.pair_at(slot_id).value.to_vec())
+                    .collect()
+            };
+            for value in &values {
+                format.free_value(bufmgr, value)?;
+            }
+            bufmgr.free_page(page_id)?;
+        }
+        bufmgr.free_page(self.meta_page_id)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+use super::{separator, Error};
+
+// キーの順を決める関数。同じ木では、いつも同じ順を返さなければならない
+pub type CompareFn = fn(&[u8], &[u8]) -> Ordering;
+
+// 比べ方の番号。メタページに記録し、開き直したときに同じ比べ方を選ぶ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyComparatorId(pub u32);
+
+impl KeyComparatorId {
+    // バイト列をそのまま比べる。以前に作った木もこれになる
+    pub const MEMCMP: Self = Self(0);
+    // ASCIIの大文字と小文字を区別しない
+    pub const CASE_INSENSITIVE_ASCII: Self = Self(1);
+}
+
+fn compare_case_insensitive_ascii(a: &[u8], b: &[u8]) -> Ordering {
+    a.iter()
+        .map(u8::to_ascii_lowercase)
+        .cmp(b.iter().map(u8::to_ascii_lowercase))
+}
+
+// 利用者が加えた比べ方。組み込みの比べ方はここに入れない
+fn registry() -> &'static RwLock<HashMap<KeyComparatorId, CompareFn>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<KeyComparatorId, CompareFn>>> = OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+// 比べ方を番号で使えるようにする。木を開く前に、作ったときと同じ番号で登録しておく
+// 組み込みの番号や、登録済みの番号はもう使えない
+pub fn register_comparator(id: KeyComparatorId, compare: CompareFn) -> Result<(), Error> {
+    if builtin(id).is_some() {
+        return Err(Error::ComparatorAlreadyRegistered(id));
+    }
+    let mut registry = registry().write().unwrap();
+    if registry.contains_key(&id) {
+        return Err(Error::ComparatorAlreadyRegistered(id));
+    }
+    registry.insert(id, compare);
+    Ok(())
+}
+
+fn builtin(id: KeyComparatorId) -> Option<CompareFn> {
+    match id {
+        KeyComparatorId::MEMCMP => Some(memcmp),
+        KeyComparatorId::CASE_INSENSITIVE_ASCII => Some(compare_case_insensitive_ascii),
+        _ => None,
+    }
+}
+
+// 木のキーの比べ方。探すとき、挿入する位置を決めるとき、区切りのキーを作るときに使う
+#[derive(Debug, Clone, Copy)]
+pub struct KeyComparator {
+    pub id: KeyComparatorId,
+    compare: CompareFn,
+}
+
+impl KeyComparator {
+    pub const MEMCMP: Self = Self {
+        id: KeyComparatorId::MEMCMP,
+        compare: memcmp,
+    };
+
+    pub fn lookup(id: KeyComparatorId) -> Result<Self, Error> {
+        let compare = match builtin(id) {
+            Some(compare) => compare,
+            None => *registry()
+                .read()
+                .unwrap()
+                .get(&id)
+                .ok_or(Error::UnknownComparator(id))?,
+        };
+        Ok(Self { id, compare })
+    }
+
+    pub fn is_memcmp(self) -> bool {
+        self.id == KeyComparatorId::MEMCMP
+    }
+
+    pub fn compare(self, a: &[u8], b: &[u8]) -> Ordering {
+        (self.compare)(a, b)
+    }
+
+    pub fn eq(self, a: &[u8], b: &[u8]) -> bool {
+        self.compare(a, b) == Ordering::Equal
+    }
+
+    pub fn lt(self, a: &[u8], b: &[u8]) -> bool {
+        self.compare(a, b) == Ordering::Less
+    }
+
+    // leftより大きくright以下の区切りのキー
+    // バイト列の順でなければ、短くしたキーが間に入るとは限らないので、rightをそのまま使う
+    pub fn separator(self, left: &[u8], right: &[u8]) -> Vec<u8> {
+        debug_assert!(self.lt(left, right));
+        if self.is_memcmp() {
+            separator(left, right)
+        } else {
+            right.to_vec()
+        }
+    }
+}
+
+fn memcmp(a: &[u8], b: &[u8]) -> Ordering {
+    a.cmp(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comparator() {
+        let cmp = KeyComparator::lookup(KeyComparatorId::CASE_INSENSITIVE_ASCII).unwrap();
+        assert!(cmp.eq(b"ABC", b"abc"));
+        assert!(cmp.lt(b"abc", b"ABD"));
+        assert!(cmp.lt(b"a", b"Z") && KeyComparator::MEMCMP.lt(b"Z", b"a"));
+        assert_eq!(b"ABD".to_vec(), cmp.separator(b"abc", b"ABD"));
+        assert_eq!(b"abd".to_vec(), KeyComparator::MEMCMP.separator(b"abc", b"abde"));
+
+        // 逆順に並べる比べ方を登録する
+        let id = KeyComparatorId(100);
+        assert!(matches!(KeyComparator::lookup(id), Err(Error::UnknownComparator(_))));
+        register_comparator(id, |a, b| b.cmp(a)).unwrap();
+        assert!(KeyComparator::lookup(id).unwrap().lt(b"b", b"a"));
+        assert!(matches!(
+            register_comparator(id, |a, b| a.cmp(b)),
+            Err(Error::ComparatorAlreadyRegistered(_))
+        ));
+        assert!(matches!(
+            register_comparator(KeyComparatorId::MEMCMP, |a, b| a.cmp(b)),
+            Err(Error::ComparatorAlreadyRegistered(_))
+        ));
+    }
+}
@@ -0,0 +1,141 @@
+use super::{meta, node, BTree, Error};
+use crate::buffer::{AccessStrategy, BufferPoolManager};
+
+impl BTree {
+    // 木のすべてのページを値のオーバーフローページも含めて解放し、解放したページの数を返す
+    // メタページには先に壊した印を書いて書き出し、最後に解放するので、
+    // あとから同じIDで開くとError::Destroyedになる。途中で失敗しても、解放したページを指す木は残らない
+    pub fn destroy(self, bufmgr: &mut BufferPoolManager) -> Result<u64, Error> {
+        let format = self.format(bufmgr)?;
+        let root_page_id = {
+            let meta_buffer = bufmgr.fetch_page_write(self.meta_page_id)?;
+            let mut meta = meta::Meta::new(meta_buffer.data_mut());
+            let root_page_id = meta.header.root_page_id;
+            meta.set_destroyed();
+            root_page_id
+        };
+        // 解放するとバッファプールの内容は捨てるので、印は先に書き出しておく
+        bufmgr.flush_page(self.meta_page_id)?;
+        let mut num_pages = 0;
+        let mut level = vec![root_page_id];
+        while !level.is_empty() {
+            let mut children = vec![];
+            for page_id in level {
+                let values = {
+                    let strategy = AccessStrategy::BulkRead;
+                    let buffer = bufmgr.fetch_page_with_strategy(page_id, strategy)?;
+                    let node = node::Node::new(buffer.data());
+                    let body = node::Body::new(node.header.node_type, node.body);
+                    match body {
+                        // 墓標の値はオーバーフローページを指さない
+                        node::Body::Leaf(leaf) => (0..leaf.num_pairs())
+                            .filter(|&slot_id| !leaf.is_tombstone(slot_id))
+                            .map(|slot_id| leaf.pair_at(slot_id).value.to_vec())
+                            .collect(),
+                        node::Body::Branch(branch) => {
+                            children.extend((0..=branch.num_pairs()).map(|i| branch.child_at(i)));
+                            vec![]
+                        }
+                    }
+                };
+                for value in &values {
+                    num_pages += format.free_value(bufmgr, value)?;
+                }
+                bufmgr.free_page(page_id)?;
+                num_pages += 1;
+            }
+            level = children;
+        }
+        bufmgr.free_page(self.meta_page_id)?;
+        Ok(num_pages + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::buffer::{self, BufferPool, IoOp};
+    use crate::disk::{DiskManager, MemoryDiskManager, PageId, Storage};
+
+    // 値の大きなペアもあるいくつもの段の木を作り、そのメタページのIDを返す
+    // キーを長くして、枝のノードも分割させる
+    fn create_tree(bufmgr: &mut BufferPoolManager) -> PageId {
+        let btree = BTree::create(bufmgr).unwrap();
+        for i in 0u64..1000 {
+            let key = format!("{:0200}", i);
+            let len = if i % 100 == 0 { 10000 } else { 100 };
+            btree.insert(bufmgr, key.as_bytes(), &vec![i as u8; len]).unwrap();
+        }
+        assert!(btree.stats(bufmgr).unwrap().height > 2);
+        btree.meta_page_id
+    }
+
+    fn check_destroyed(disk: impl Storage + 'static) {
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        let meta_page_id = create_tree(&mut bufmgr);
+        let info = bufmgr.storage_info().unwrap();
+        let expected = info.num_pages - num_pages;
+        let next_page_id = info.next_page_id;
+
+        let freed = BTree::new(meta_page_id).destroy(&mut bufmgr).unwrap();
+        assert_eq!(expected, freed);
+        assert_eq!(num_pages, bufmgr.storage_info().unwrap().num_pages);
+
+        // 壊した木は、バッファプールに載っていなくても開けない
+        bufmgr.flush().unwrap();
+        let btree = BTree::new(meta_page_id);
+        for _ in 0..2 {
+            assert!(matches!(btree.get(&mut bufmgr, b"key"), Err(Error::Destroyed(_))));
+            let inserted = btree.insert(&mut bufmgr, b"key", b"value");
+            assert!(matches!(inserted, Err(Error::Destroyed(_))));
+            assert!(matches!(btree.len(&mut bufmgr), Err(Error::Destroyed(_))));
+            assert!(btree.verify(&mut bufmgr).is_err());
+            bufmgr.resize(1).unwrap();
+            bufmgr.resize(10).unwrap();
+        }
+
+        // 解放したページを、ファイルを伸ばさずに使い回す
+        let mut page_ids = HashSet::new();
+        for _ in 0..freed {
+            let page_id = bufmgr.create_page().unwrap().page_id;
+            assert!(page_id.to_u64() < next_page_id.to_u64());
+            page_ids.insert(page_id);
+        }
+        assert_eq!(freed as usize, page_ids.len());
+        assert!(page_ids.contains(&meta_page_id));
+        assert_eq!(next_page_id, bufmgr.storage_info().unwrap().next_page_id);
+    }
+
+    #[test]
+    fn test_destroy() {
+        check_destroyed(MemoryDiskManager::new());
+        let (data_file, _) = NamedTempFile::new().unwrap().into_parts();
+        check_destroyed(DiskManager::new(data_file).unwrap());
+    }
+
+    #[test]
+    fn test_destroy_error() {
+        let mut bufmgr = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let meta_page_id = create_tree(&mut bufmgr);
+        // 最初に入れた大きな値のオーバーフローページを先に解放しておき、途中で失敗させる
+        bufmgr.free_page(PageId(2)).unwrap();
+        let freed_before = bufmgr.storage_info().unwrap().num_pages;
+        assert!(matches!(
+            BTree::new(meta_page_id).destroy(&mut bufmgr),
+            Err(Error::Buffer(buffer::Error::Io { op: IoOp::Free, .. }))
+        ));
+        assert!(bufmgr.storage_info().unwrap().num_pages < freed_before);
+
+        // 失敗しても、解放したページを指したまま木を開けることはない
+        bufmgr.flush().unwrap();
+        bufmgr.resize(1).unwrap();
+        let btree = BTree::new(meta_page_id);
+        assert!(matches!(btree.get(&mut bufmgr, b"key"), Err(Error::Destroyed(_))));
+        assert!(matches!(btree.insert(&mut bufmgr, b"key", b""), Err(Error::Destroyed(_))));
+    }
+}
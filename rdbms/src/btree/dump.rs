@@ -0,0 +1,329 @@
+use std::io::Write;
+
+use super::{meta, node, BTree, Error};
+use crate::buffer::{AccessStrategy, BufferPoolManager};
+use crate::disk::PageId;
+use crate::tuple;
+
+// 16進で表示するキーのバイト数。長いキーはここで切る
+const MAX_KEY_BYTES: usize = 16;
+
+// キーを16進で表示する
+pub fn hex_key(key: &[u8]) -> String {
+    let mut s: String = key
+        .iter()
+        .take(MAX_KEY_BYTES)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    if key.len() > MAX_KEY_BYTES {
+        s.push_str("..");
+    }
+    s
+}
+
+// tuple::encodeしたキーを、要素に分けて表示する
+// 枝の区切りのキーは要素の途中で切れていることがあるので、その残りは16進で添える
+pub fn tuple_key(key: &[u8]) -> String {
+    let mut elems = vec![];
+    let rest = tuple::decode_prefix(key, &mut elems);
+    let mut s = format!("{:?}", tuple::Pretty(&elems));
+    if !rest.is_empty() {
+        s.push_str(" + ");
+        s.push_str(&hex_key(rest));
+    }
+    s
+}
+
+// 表示するために読んだノード
+enum NodeSummary {
+    Branch {
+        page_id: PageId,
+        keys: Vec<Vec<u8>>,
+        children: Vec<PageId>,
+    },
+    Leaf {
+        page_id: PageId,
+        prev_page_id: Option<PageId>,
+        next_page_id: Option<PageId>,
+        num_pairs: usize,
+        first_key: Option<Vec<u8>>,
+        last_key: Option<Vec<u8>>,
+    },
+}
+
+fn page_label(page_id: Option<PageId>) -> String {
+    match page_id {
+        Some(page_id) => page_id.to_u64().to_string(),
+        None => "-".to_string(),
+    }
+}
+
+// Graphvizのレコードのラベルで特別な意味を持つ文字を、そのまま表示させる
+fn escape_record(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "{}|<>\"\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl BTree {
+    // 根から1段ずつ、各ページのIDとキーを表示する
+    pub fn dump(&self, bufmgr: &mut BufferPoolManager, out: &mut impl Write) -> Result<(), Error> {
+        self.dump_with(bufmgr, out, hex_key)
+    }
+
+    // キーの表示をformat_keyで変えて表示する
+    pub fn dump_with(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        out: &mut impl Write,
+        format_key: impl Fn(&[u8]) -> String,
+    ) -> Result<(), Error> {
+        for (depth, level) in self.summarize(bufmgr)?.iter().enumerate() {
+            writeln!(out, "level {}", depth)?;
+            for node in level {
+                match node {
+                    NodeSummary::Branch {
+                        page_id,
+                        keys,
+                        children,
+                    } => {
+                        let keys: Vec<_> = keys.iter().map(|key| format_key(key)).collect();
+                        let children: Vec<_> =
+                            children.iter().map(|&child| page_label(Some(child))).collect();
+                        writeln!(
+                            out,
+                            "  branch {}: keys [{}], children [{}]",
+                            page_id.to_u64(),
+                            keys.join(", "),
+                            children.join(", ")
+                        )?;
+                    }
+                    NodeSummary::Leaf {
+                        page_id,
+                        prev_page_id,
+                        next_page_id,
+                        num_pairs,
+                        first_key,
+                        last_key,
+                    } => {
+                        write!(
+                            out,
+                            "  leaf {}: prev {}, next {}, {} pairs",
+                            page_id.to_u64(),
+                            page_label(*prev_page_id),
+                            page_label(*next_page_id),
+                            num_pairs
+                        )?;
+                        if let (Some(first_key), Some(last_key)) = (first_key, last_key) {
+                            let (first_key, last_key) = (format_key(first_key), format_key(last_key));
+                            write!(out, ", keys {} .. {}", first_key, last_key)?;
+                        }
+                        writeln!(out)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Graphvizのdot形式で表示する。リーフの兄弟のつながりは点線で描く
+    pub fn dump_dot(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        out: &mut impl Write,
+    ) -> Result<(), Error> {
+        self.dump_dot_with(bufmgr, out, hex_key)
+    }
+
+    pub fn dump_dot_with(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        out: &mut impl Write,
+        format_key: impl Fn(&[u8]) -> String,
+    ) -> Result<(), Error> {
+        writeln!(out, "digraph btree {{")?;
+        writeln!(out, "  node [shape=record];")?;
+        for level in self.summarize(bufmgr)? {
+            for node in level {
+                match node {
+                    NodeSummary::Branch {
+                        page_id,
+                        keys,
+                        children,
+                    } => {
+                        let page_id = page_id.to_u64();
+                        let mut fields = vec!["<c0>".to_string()];
+                        for (i, key) in keys.iter().enumerate() {
+                            fields.push(escape_record(&format_key(key)));
+                            fields.push(format!("<c{}>", i + 1));
+                        }
+                        writeln!(out, "  p{} [label=\"{}\"];", page_id, fields.join("|"))?;
+                        for (i, child) in children.iter().enumerate() {
+                            writeln!(out, "  p{}:c{} -> p{};", page_id, i, child.to_u64())?;
+                        }
+                    }
+                    NodeSummary::Leaf {
+                        page_id,
+                        next_page_id,
+                        num_pairs,
+                        first_key,
+                        last_key,
+                        ..
+                    } => {
+                        let page_id = page_id.to_u64();
+                        let mut fields =
+                            vec![format!("leaf {}", page_id), format!("{} pairs", num_pairs)];
+                        if let (Some(first_key), Some(last_key)) = (first_key, last_key) {
+                            fields.push(escape_record(&format_key(&first_key)));
+                            fields.push(escape_record(&format_key(&last_key)));
+                        }
+                        writeln!(out, "  p{} [label=\"{}\"];", page_id, fields.join("|"))?;
+                        if let Some(next_page_id) = next_page_id {
+                            writeln!(
+                                out,
+                                "  p{} -> p{} [style=dashed, constraint=false];",
+                                page_id,
+                                next_page_id.to_u64()
+                            )?;
+                        }
+                    }
+                }
+            }
+        }
+        writeln!(out, "}}")?;
+        Ok(())
+    }
+
+    // 根から1段ずつ、各段のノードを左から順に読む
+    fn summarize(&self, bufmgr: &mut BufferPoolManager) -> Result<Vec<Vec<NodeSummary>>, Error> {
+        let root_page_id = {
+            let meta_buffer = bufmgr.fetch_page_read(self.meta_page_id)?;
+            let meta = meta::Meta::new(meta_buffer.data());
+            self.check_meta(&meta)?;
+            meta.header.root_page_id
+        };
+        let mut levels = vec![];
+        let mut level = vec![root_page_id];
+        while !level.is_empty() {
+            let mut children = vec![];
+            let mut nodes = vec![];
+            for page_id in level {
+                let buffer = bufmgr.fetch_page_with_strategy(page_id, AccessStrategy::BulkRead)?;
+                let node = node::Node::new(buffer.data());
+                let body = node::Body::new(node.header.node_type, node.body);
+                let summary = match body {
+                    node::Body::Branch(branch) => {
+                        let child_ids: Vec<_> =
+                            (0..=branch.num_pairs()).map(|i| branch.child_at(i)).collect();
+                        children.extend_from_slice(&child_ids);
+                        NodeSummary::Branch {
+                            page_id,
+                            keys: (0..branch.num_pairs())
+                                .map(|slot_id| branch.pair_at(slot_id).key.to_vec())
+                                .collect(),
+                            children: child_ids,
+                        }
+                    }
+                    node::Body::Leaf(leaf) => {
+                        let num_pairs = leaf.num_pairs();
+                        let key_at = |slot_id: usize| leaf.pair_at(slot_id).key.to_vec();
+                        NodeSummary::Leaf {
+                            page_id,
+                            prev_page_id: leaf.prev_page_id(),
+                            next_page_id: leaf.next_page_id(),
+                            num_pairs,
+                            first_key: (num_pairs > 0).then(|| key_at(0)),
+                            last_key: num_pairs.checked_sub(1).map(key_at),
+                        }
+                    }
+                };
+                nodes.push(summary);
+            }
+            levels.push(nodes);
+            level = children;
+        }
+        Ok(levels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::MemoryDiskManager;
+
+    fn dump_to_string(bufmgr: &mut BufferPoolManager, btree: &BTree) -> String {
+        let mut out = vec![];
+        btree.dump(bufmgr, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_dump() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let expected = "level 0\n  leaf 1: prev -, next -, 0 pairs\n";
+        assert_eq!(expected, dump_to_string(&mut bufmgr, &btree));
+
+        for i in 0u64..40 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[0; 200]).unwrap();
+        }
+        let expected = "\
+level 0
+  branch 3: keys [0000000000000012, 0000000000000024], children [2, 4, 1]
+level 1
+  leaf 2: prev -, next 4, 18 pairs, keys 0000000000000000 .. 0000000000000011
+  leaf 4: prev 2, next 1, 18 pairs, keys 0000000000000012 .. 0000000000000023
+  leaf 1: prev 4, next -, 4 pairs, keys 0000000000000024 .. 0000000000000027
+";
+        assert_eq!(expected, dump_to_string(&mut bufmgr, &btree));
+
+        let mut out = vec![];
+        btree.dump_dot(&mut bufmgr, &mut out).unwrap();
+        let expected = r#"digraph btree {
+  node [shape=record];
+  p3 [label="<c0>|0000000000000012|<c1>|0000000000000024|<c2>"];
+  p3:c0 -> p2;
+  p3:c1 -> p4;
+  p3:c2 -> p1;
+  p2 [label="leaf 2|18 pairs|0000000000000000|0000000000000011"];
+  p2 -> p4 [style=dashed, constraint=false];
+  p4 [label="leaf 4|18 pairs|0000000000000012|0000000000000023"];
+  p4 -> p1 [style=dashed, constraint=false];
+  p1 [label="leaf 1|4 pairs|0000000000000024|0000000000000027"];
+}
+"#;
+        assert_eq!(expected, String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn test_dump_tuple_key() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        for i in 0..40 {
+            let mut key = vec![];
+            tuple::encode([&b"user"[..], format!("{:02}", i).as_bytes()].iter(), &mut key);
+            btree.insert(&mut bufmgr, &key, &[0; 200]).unwrap();
+        }
+        let mut out = vec![];
+        btree.dump_with(&mut bufmgr, &mut out, tuple_key).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        // 区切りのキーは2つめの要素の途中で切れる
+        let separator = r#"keys [Tuple("user" [75, 73, 65, 72]) + 3138, "#;
+        assert!(out.lines().nth(1).unwrap().contains(separator), "{}", out);
+        let leaf = r#"keys Tuple("user" [75, 73, 65, 72], "00" [30, 30]) .. "#;
+        assert!(out.lines().nth(3).unwrap().contains(leaf), "{}", out);
+
+        // 特別な文字はエスケープする
+        let mut out = vec![];
+        btree.dump_dot_with(&mut bufmgr, &mut out, |_| "<a|b>".to_string()).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains(r#"\<a\|b\>"#));
+    }
+}
@@ -2,7 +2,7 @@ use std::mem::size_of;
 
 use zerocopy::{AsBytes, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified};
 
-use super::Pair;
+use super::{KeyComparator, Pair};
 use crate::bsearch::binary_search_by;
 use crate::disk::PageId;
 use crate::slotted::{self, Slotted};
@@ -14,6 +14,12 @@ pub struct Header {
     next_page_id: PageId,
 }
 
+// lenバイトのリーフに置けるペアの大きさ
+pub fn max_pair_size(len: usize) -> usize {
+    let capacity = len - size_of::<Header>() - size_of::<slotted::Header>();
+    capacity / 2 - size_of::<slotted::Pointer>()
+}
+
 pub struct Leaf<B> {
     header: LayoutVerified<B, Header>,
     body: Slotted<B>,
@@ -39,25 +45,83 @@ impl<B: ByteSlice> Leaf<B> {
         self.body.num_slots()
     }
 
-    pub fn search_slot_id(&self, key: &[u8]) -> Result<usize, usize> {
+    // ペアを加えたり取り除いたりして、スロットの番号がずれるたびに変わる
+    pub fn version(&self) -> u32 {
+        self.body.version()
+    }
+
+    pub fn search_slot_id(&self, key: &[u8], cmp: KeyComparator) -> Result<usize, usize> {
         binary_search_by(self.num_pairs(), |slot_id| {
-            self.pair_at(slot_id).key.cmp(&key)
+            cmp.compare(self.pair_at(slot_id).key, key)
         })
     }
 
     #[cfg(test)]
-    pub fn search_pair(&self, key: &[u8]) -> Option<Pair> {
-        let slot_id = self.search_slot_id(key).ok()?;
+    pub fn search_pair(&self, key: &[u8]) -> Option<Pair<'_>> {
+        let slot_id = self.search_slot_id(key, KeyComparator::MEMCMP).ok()?;
         Some(self.pair_at(slot_id))
     }
 
-    pub fn pair_at(&self, slot_id: usize) -> Pair {
+    pub fn pair_at(&self, slot_id: usize) -> Pair<'_> {
         Pair::from_bytes(&self.body[slot_id])
     }
 
+    // 墓標のペアでは、値に墓標の番号を置く
+    pub fn is_tombstone(&self, slot_id: usize) -> bool {
+        self.body.is_tombstone(slot_id)
+    }
+
+    pub fn num_tombstones(&self) -> usize {
+        (0..self.num_pairs()).filter(|&slot_id| self.is_tombstone(slot_id)).count()
+    }
+
+    // スロットが食い違っておらず、どのスロットもペアとして読めるか確かめる
+    pub fn check(&self) -> Result<(), String> {
+        self.body.check()?;
+        for slot_id in 0..self.num_pairs() {
+            if Pair::try_from_bytes(&self.body[slot_id]).is_none() {
+                return Err(format!("slot {} is not a pair", slot_id));
+            }
+        }
+        Ok(())
+    }
+
     pub fn max_pair_size(&self) -> usize {
         self.body.capacity() / 2 - size_of::<slotted::Pointer>()
     }
+
+    // 使っている領域の割合
+    pub fn occupancy(&self) -> f64 {
+        self.body.used_space() as f64 / self.body.capacity() as f64
+    }
+
+    // 使う領域が容量の1/4を下回っている
+    // 分割したノードは半分ほど使っているので、少し削除しただけではまとめない
+    pub fn is_underflow(&self) -> bool {
+        4 * self.body.used_space() < self.body.capacity()
+    }
+
+    // ペアを加えても分割しないならtrue
+    pub fn has_room(&self, key: &[u8], value: &[u8]) -> bool {
+        let pair_bytes = Pair { key, value }.to_bytes();
+        pair_bytes.len() + size_of::<slotted::Pointer>() <= self.body.free_space()
+    }
+
+    // slot_idのペアを取り除いてから加えても分割しないならtrue
+    pub fn has_room_replacing(&self, slot_id: usize, key: &[u8], value: &[u8]) -> bool {
+        let pair_bytes = Pair { key, value }.to_bytes();
+        pair_bytes.len() <= self.body.free_space() + self.body[slot_id].len()
+    }
+
+    // 右端のリーフの末尾に加えるならtrue。昇順に挿入するとこうなる
+    pub fn is_append(&self, slot_id: usize) -> bool {
+        self.next_page_id().is_none() && slot_id == self.num_pairs()
+    }
+
+    // 右隣のリーフのペアをすべて移せるならtrue
+    pub fn can_merge(&self, right: &Leaf<impl ByteSlice>) -> bool {
+        self.body.used_space() + right.body.used_space() <= self.body.capacity()
+    }
 }
 
 impl<B: ByteSliceMut> Leaf<B> {
@@ -85,47 +149,130 @@ impl<B: ByteSliceMut> Leaf<B> {
         Some(())
     }
 
-    fn is_half_full(&self) -> bool {
-        2 * self.body.free_space() < self.body.capacity()
+    // 値を置き換える。ペアが大きくなってページに収まらなければNoneを返す
+    #[must_use = "update may fail"]
+    pub fn update(&mut self, slot_id: usize, value: &[u8]) -> Option<()> {
+        let key = self.pair_at(slot_id).key.to_vec();
+        let pair = Pair { key: &key, value };
+        let pair_bytes = pair.to_bytes();
+        assert!(pair_bytes.len() <= self.max_pair_size());
+        self.body.resize(slot_id, pair_bytes.len())?;
+        self.body[slot_id].copy_from_slice(&pair_bytes);
+        Some(())
+    }
+
+    pub fn remove(&mut self, slot_id: usize) {
+        self.body.remove(slot_id);
+    }
+
+    pub fn set_tombstone(&mut self, slot_id: usize, tombstone: bool) {
+        self.body.set_tombstone(slot_id, tombstone);
+    }
+
+    // 末尾にペアを加える。使う領域がfill_factorの割合を越えるならNoneを返す
+    // 空のリーフには必ず加える
+    #[must_use = "append may fail"]
+    pub fn append_within(&mut self, key: &[u8], value: &[u8], fill_factor: f64) -> Option<()> {
+        let pair_size = Pair { key, value }.to_bytes().len();
+        if self.num_pairs() > 0 && !self.body.fits_within(pair_size, fill_factor) {
+            return None;
+        }
+        self.insert(self.num_pairs(), key, value)
+    }
+
+    // 使う領域が容量のfill%を越えている
+    fn is_filled(&self, fill: u8) -> bool {
+        100 * self.body.free_space() < self.body.capacity() * (100 - fill as usize)
+    }
+
+    // lenバイトのスロットを加えられる
+    fn has_room_for(&self, len: usize) -> bool {
+        len + size_of::<slotted::Pointer>() <= self.body.free_space()
     }
 
+    // 新しいペアを含めて小さいキーから順にnew_leafへ移し、new_leafが容量のfill%を越えるか、
+    // 次のペアが入らなくなったら残りをこのリーフに残す。fillが50なら半分ずつに分ける
+    // 移したペアと残したペアを分ける区切りのキーを返す
     pub fn split_insert(
         &mut self,
         new_leaf: &mut Leaf<impl ByteSliceMut>,
         new_key: &[u8],
         new_value: &[u8],
+        fill: u8,
+        cmp: KeyComparator,
     ) -> Vec<u8> {
         new_leaf.initialize();
+        let new_len = Pair { key: new_key, value: new_value }.to_bytes().len();
+        let mut inserted = false;
         loop {
-            if new_leaf.is_half_full() {
-                let index = self
-                    .search_slot_id(new_key)
-                    .expect_err("key must be unique");
-                self.insert(index, new_key, new_value)
-                    .expect("old leaf must have space");
+            let next_is_new =
+                !inserted && (self.num_pairs() == 0 || cmp.lt(new_key, self.pair_at(0).key));
+            let next_len = if next_is_new { new_len } else { self.body[0].len() };
+            if new_leaf.is_filled(fill) || !new_leaf.has_room_for(next_len) {
                 break;
             }
-            if self.pair_at(0).key < new_key {
-                self.transfer(new_leaf);
-            } else {
+            if next_is_new {
                 new_leaf
                     .insert(new_leaf.num_pairs(), new_key, new_value)
                     .expect("new leaf must have space");
-                while !new_leaf.is_half_full() {
-                    self.transfer(new_leaf);
-                }
-                break;
+                inserted = true;
+            } else {
+                self.transfer(new_leaf);
             }
         }
-        self.pair_at(0).key.to_vec()
+        if !inserted {
+            let index = self
+                .search_slot_id(new_key, cmp)
+                .expect_err("key must be unique");
+            self.insert(index, new_key, new_value)
+                .expect("old leaf must have space");
+        }
+        let last_key = new_leaf.pair_at(new_leaf.num_pairs() - 1).key;
+        cmp.separator(last_key, self.pair_at(0).key)
+    }
+
+    // ペアをすべてnew_leafに移し、新しいペアだけを残す
+    // 昇順に挿入したときに使えば、分割したリーフを半分空けたままにしない
+    pub fn split_append(
+        &mut self,
+        new_leaf: &mut Leaf<impl ByteSliceMut>,
+        new_key: &[u8],
+        new_value: &[u8],
+        cmp: KeyComparator,
+    ) -> Vec<u8> {
+        new_leaf.initialize();
+        new_leaf.merge(self);
+        self.insert(0, new_key, new_value)
+            .expect("old leaf must have space");
+        let last_key = new_leaf.pair_at(new_leaf.num_pairs() - 1).key;
+        cmp.separator(last_key, new_key)
     }
 
     pub fn transfer(&mut self, dest: &mut Leaf<impl ByteSliceMut>) {
         let next_index = dest.num_pairs();
         assert!(dest.body.insert(next_index, self.body[0].len()).is_some());
         dest.body[next_index].copy_from_slice(&self.body[0]);
+        dest.body.set_tombstone(next_index, self.body.is_tombstone(0));
         self.body.remove(0);
     }
+
+    // 末尾のペアをdestの先頭に移す
+    pub fn transfer_last(&mut self, dest: &mut Leaf<impl ByteSliceMut>) {
+        let last_index = self.num_pairs() - 1;
+        assert!(dest.body.insert(0, self.body[last_index].len()).is_some());
+        dest.body[0].copy_from_slice(&self.body[last_index]);
+        dest.body.set_tombstone(0, self.body.is_tombstone(last_index));
+        self.body.remove(last_index);
+    }
+
+    // 右隣のリーフのペアをすべて末尾に移す。つながりは呼び出し側で付け替える
+    pub fn merge(&mut self, right: &mut Leaf<impl ByteSliceMut>) {
+        let num_pairs = right.num_pairs();
+        right
+            .body
+            .transfer_front(&mut self.body, num_pairs)
+            .expect("merged leaf must have space");
+    }
 }
 
 #[cfg(test)]
@@ -138,18 +285,20 @@ mod tests {
         let mut leaf_page = Leaf::new(page_data.as_mut_slice());
         leaf_page.initialize();
 
-        let id = leaf_page.search_slot_id(b"deadbeef").unwrap_err();
+        assert_eq!(max_pair_size(100), leaf_page.max_pair_size());
+
+        let id = leaf_page.search_slot_id(b"deadbeef", KeyComparator::MEMCMP).unwrap_err();
         assert_eq!(0, id);
         leaf_page.insert(id, b"deadbeef", b"world").unwrap();
         assert_eq!(b"deadbeef", leaf_page.pair_at(0).key);
 
-        let id = leaf_page.search_slot_id(b"facebook").unwrap_err();
+        let id = leaf_page.search_slot_id(b"facebook", KeyComparator::MEMCMP).unwrap_err();
         assert_eq!(1, id);
         leaf_page.insert(id, b"facebook", b"!").unwrap();
         assert_eq!(b"deadbeef", leaf_page.pair_at(0).key);
         assert_eq!(b"facebook", leaf_page.pair_at(1).key);
 
-        let id = leaf_page.search_slot_id(b"beefdead").unwrap_err();
+        let id = leaf_page.search_slot_id(b"beefdead", KeyComparator::MEMCMP).unwrap_err();
         assert_eq!(0, id);
         leaf_page.insert(id, b"beefdead", b"hello").unwrap();
         assert_eq!(b"beefdead", leaf_page.pair_at(0).key);
@@ -161,25 +310,93 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_leaf_update() {
+        let mut page_data = vec![0; 100];
+        let mut leaf_page = Leaf::new(page_data.as_mut_slice());
+        leaf_page.initialize();
+        leaf_page.insert(0, b"deadbeef", b"world").unwrap();
+        leaf_page.insert(1, b"facebook", b"!").unwrap();
+        leaf_page.insert(2, b"feedface", &[1; 10]).unwrap();
+        leaf_page.update(0, b"hello").unwrap();
+        leaf_page.update(1, b"").unwrap();
+        leaf_page.update(0, b"hello, world").unwrap();
+        assert_eq!(&b"hello, world"[..], leaf_page.search_pair(b"deadbeef").unwrap().value);
+        assert_eq!(&b""[..], leaf_page.search_pair(b"facebook").unwrap().value);
+        assert_eq!(&[1; 10][..], leaf_page.search_pair(b"feedface").unwrap().value);
+        // 空きが足りなければ、何も変えない
+        assert!(leaf_page.update(1, &[0; 24]).is_none());
+        assert_eq!(&b""[..], leaf_page.search_pair(b"facebook").unwrap().value);
+        assert_eq!(&[1; 10][..], leaf_page.search_pair(b"feedface").unwrap().value);
+    }
+
+    #[test]
+    fn test_leaf_merge() {
+        let mut left_data = vec![0; 100];
+        let mut left = Leaf::new(left_data.as_mut_slice());
+        left.initialize();
+        let mut right_data = vec![0; 100];
+        let mut right = Leaf::new(right_data.as_mut_slice());
+        right.initialize();
+        assert!(left.is_underflow());
+        left.insert(0, b"a", b"1").unwrap();
+        right.insert(0, b"b", b"2").unwrap();
+        right.insert(1, b"c", b"3").unwrap();
+        right.insert(2, b"d", b"4").unwrap();
+        assert!(!right.is_underflow());
+
+        right.transfer(&mut left);
+        assert_eq!(b"b", left.pair_at(1).key);
+        left.transfer_last(&mut right);
+        assert_eq!(b"b", right.pair_at(0).key);
+        assert!(left.can_merge(&right));
+        left.merge(&mut right);
+        assert_eq!(0, right.num_pairs());
+        let keys: Vec<_> = (0..left.num_pairs()).map(|i| left.pair_at(i).key.to_vec()).collect();
+        assert_eq!(vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()], keys);
+    }
+
     #[test]
     fn test_leaf_split_insert() {
         let mut page_data = vec![0; 62];
         let mut leaf_page = Leaf::new(page_data.as_mut_slice());
         leaf_page.initialize();
-        let id = leaf_page.search_slot_id(b"deadbeef").unwrap_err();
+        let id = leaf_page.search_slot_id(b"deadbeef", KeyComparator::MEMCMP).unwrap_err();
         leaf_page.insert(id, b"deadbeef", b"world").unwrap();
-        let id = leaf_page.search_slot_id(b"facebook").unwrap_err();
+        let id = leaf_page.search_slot_id(b"facebook", KeyComparator::MEMCMP).unwrap_err();
         leaf_page.insert(id, b"facebook", b"!").unwrap();
-        let id = leaf_page.search_slot_id(b"beefdead").unwrap_err();
+        let id = leaf_page.search_slot_id(b"beefdead", KeyComparator::MEMCMP).unwrap_err();
         assert!(leaf_page.insert(id, b"beefdead", b"hello").is_none());
 
         let mut leaf_page = Leaf::new(page_data.as_mut_slice());
         let mut new_page_data = vec![0; 62];
         let mut new_leaf_page = Leaf::new(new_page_data.as_mut_slice());
-        leaf_page.split_insert(&mut new_leaf_page, b"beefdead", b"hello");
+        let cmp = KeyComparator::MEMCMP;
+        leaf_page.split_insert(&mut new_leaf_page, b"beefdead", b"hello", 50, cmp);
         assert_eq!(
             &b"world"[..],
             new_leaf_page.search_pair(b"deadbeef").unwrap().value
         );
     }
+
+    #[test]
+    fn test_leaf_split_append() {
+        let mut page_data = vec![0; 62];
+        let mut leaf_page = Leaf::new(page_data.as_mut_slice());
+        leaf_page.initialize();
+        leaf_page.insert(0, b"deadbeef", b"world").unwrap();
+        leaf_page.insert(1, b"facebook", b"!").unwrap();
+        assert!(leaf_page.insert(2, b"feedface", b"hello").is_none());
+        assert!(leaf_page.is_append(2));
+        assert!(!leaf_page.is_append(1));
+
+        let mut new_page_data = vec![0; 62];
+        let mut new_leaf_page = Leaf::new(new_page_data.as_mut_slice());
+        let cmp = KeyComparator::MEMCMP;
+        let key = leaf_page.split_append(&mut new_leaf_page, b"feedface", b"hello", cmp);
+        assert_eq!(b"fe".to_vec(), key);
+        assert_eq!(2, new_leaf_page.num_pairs());
+        assert_eq!(1, leaf_page.num_pairs());
+        assert_eq!(&b"hello"[..], leaf_page.pair_at(0).value);
+    }
 }
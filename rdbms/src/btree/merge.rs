@@ -0,0 +1,205 @@
+use std::cmp::Ordering;
+use std::mem;
+
+use super::{BTree, BulkLoadOptions, Error, KeyMode, SearchMode};
+use crate::buffer::BufferPoolManager;
+
+// 2つの木に同じキーがあったとき、どちらのペアを残すか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    TakeLeft,
+    TakeRight,
+    // Error::DuplicateKeyを返して、作りかけの木を捨てる
+    Error,
+}
+
+impl BTree {
+    // 2つの木を先頭から並べて読み、すべてのペアを詰めた新しい木を作る。元の木は書き換えない
+    // それぞれの木のペアを1つずつしか持たないので、木が大きくてもメモリは増えない
+    // 重複を許す木どうしでは、キーと値の組が同じペアを同じキーとみなす
+    pub fn merge(
+        bufmgr: &mut BufferPoolManager,
+        left: &BTree,
+        right: &BTree,
+        on_conflict: ConflictPolicy,
+    ) -> Result<BTree, Error> {
+        let key_mode = left.key_mode(bufmgr)?;
+        let right_key_mode = right.key_mode(bufmgr)?;
+        if right_key_mode != key_mode {
+            return Err(Error::KeyModeMismatch {
+                left: key_mode,
+                right: right_key_mode,
+            });
+        }
+        // 新しい木も同じ比べ方で作り、その順に並べて詰める
+        let cmp = left.format(bufmgr)?.cmp;
+        if right.format(bufmgr)?.cmp.id != cmp.id {
+            return Err(Error::UnsupportedWithComparator("merge with another key comparator"));
+        }
+        let mut left_iter = left.search(bufmgr, SearchMode::Start)?;
+        let mut right_iter = right.search(bufmgr, SearchMode::Start)?;
+        let mut left_pair = left_iter.next(bufmgr)?;
+        let mut right_pair = right_iter.next(bufmgr)?;
+        let options = BulkLoadOptions {
+            key_mode,
+            comparator: cmp.id,
+            ..BulkLoadOptions::default()
+        };
+        Self::bulk_load_from(bufmgr, options, |bufmgr| {
+            let order = match (&left_pair, &right_pair) {
+                (None, None) => return Ok(None),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(left), Some(right)) => match key_mode {
+                    KeyMode::Unique => cmp.compare(&left.0, &right.0),
+                    KeyMode::Multi => left.cmp(right),
+                },
+            };
+            let pair = match order {
+                Ordering::Less => mem::replace(&mut left_pair, left_iter.next(bufmgr)?),
+                Ordering::Greater => mem::replace(&mut right_pair, right_iter.next(bufmgr)?),
+                Ordering::Equal => {
+                    let left = mem::replace(&mut left_pair, left_iter.next(bufmgr)?);
+                    let right = mem::replace(&mut right_pair, right_iter.next(bufmgr)?);
+                    match on_conflict {
+                        ConflictPolicy::TakeLeft => left,
+                        ConflictPolicy::TakeRight => right,
+                        ConflictPolicy::Error => return Err(Error::DuplicateKey),
+                    }
+                }
+            };
+            Ok(pair)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::BTreeOptions;
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::MemoryDiskManager;
+
+    fn create(bufmgr: &mut BufferPoolManager, keys: impl Iterator<Item = u64>, value: u8) -> BTree {
+        let btree = BTree::create(bufmgr).unwrap();
+        for key in keys {
+            btree.insert(bufmgr, &key.to_be_bytes(), &[value; 50]).unwrap();
+        }
+        btree
+    }
+
+    fn scan(bufmgr: &mut BufferPoolManager, btree: &BTree) -> Vec<(u64, u8)> {
+        btree
+            .iter(bufmgr)
+            .map(|pair| {
+                let (key, value) = pair.unwrap();
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&key);
+                (u64::from_be_bytes(bytes), value[0])
+            })
+            .collect()
+    }
+
+    fn merge(
+        bufmgr: &mut BufferPoolManager,
+        left: impl Iterator<Item = u64>,
+        right: impl Iterator<Item = u64>,
+        on_conflict: ConflictPolicy,
+    ) -> Result<Vec<(u64, u8)>, Error> {
+        let left = create(bufmgr, left, 1);
+        let right = create(bufmgr, right, 2);
+        let merged = BTree::merge(bufmgr, &left, &right, on_conflict)?;
+        merged.verify(bufmgr).unwrap();
+        assert_eq!(merged.len(bufmgr).unwrap(), scan(bufmgr, &merged).len() as u64);
+        Ok(scan(bufmgr, &merged))
+    }
+
+    #[test]
+    fn test_merge() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        // 範囲の重ならない木
+        let merged = merge(&mut bufmgr, 0..1000, 1000..2000, ConflictPolicy::Error).unwrap();
+        let expected: Vec<_> = (0..2000).map(|i| (i, if i < 1000 { 1 } else { 2 })).collect();
+        assert_eq!(expected, merged);
+
+        // キーが互い違いの木
+        let evens = (0..2000).step_by(2);
+        let odds = (1..2000).step_by(2);
+        let merged = merge(&mut bufmgr, odds, evens, ConflictPolicy::Error).unwrap();
+        let expected: Vec<_> = (0..2000).map(|i| (i, if i % 2 == 1 { 1 } else { 2 })).collect();
+        assert_eq!(expected, merged);
+
+        // キーの重なる木
+        let left = create(&mut bufmgr, 0..1500, 1);
+        let right = create(&mut bufmgr, 1000..2000, 2);
+        let policies = [(ConflictPolicy::TakeLeft, 1), (ConflictPolicy::TakeRight, 2)];
+        for (on_conflict, overlap) in policies {
+            let merged = BTree::merge(&mut bufmgr, &left, &right, on_conflict).unwrap();
+            let expected: Vec<_> = (0..2000)
+                .map(|i| match i {
+                    0..=999 => (i, 1),
+                    1000..=1499 => (i, overlap),
+                    _ => (i, 2),
+                })
+                .collect();
+            assert_eq!(expected, scan(&mut bufmgr, &merged));
+        }
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        assert!(matches!(
+            BTree::merge(&mut bufmgr, &left, &right, ConflictPolicy::Error),
+            Err(Error::DuplicateKey)
+        ));
+        // 作りかけの木のページは解放する
+        assert_eq!(num_pages, bufmgr.storage_info().unwrap().num_pages);
+        // 元の木はそのまま
+        assert_eq!((0..1500).map(|i| (i, 1)).collect::<Vec<_>>(), scan(&mut bufmgr, &left));
+
+        // 片方が空の木
+        let merged = merge(&mut bufmgr, 0..0, 0..100, ConflictPolicy::Error).unwrap();
+        assert_eq!((0..100).map(|i| (i, 2)).collect::<Vec<_>>(), merged);
+        let merged = merge(&mut bufmgr, 0..100, 0..0, ConflictPolicy::Error).unwrap();
+        assert_eq!((0..100).map(|i| (i, 1)).collect::<Vec<_>>(), merged);
+        assert!(merge(&mut bufmgr, 0..0, 0..0, ConflictPolicy::Error).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_merge_multi() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let multi = |bufmgr: &mut BufferPoolManager, values: &[&[u8]]| {
+            let options = BTreeOptions {
+                key_mode: KeyMode::Multi,
+                ..BTreeOptions::default()
+            };
+            let btree = BTree::create_with(bufmgr, options).unwrap();
+            for value in values {
+                btree.insert(bufmgr, b"key", value).unwrap();
+            }
+            btree
+        };
+        let left = multi(&mut bufmgr, &[b"1", b"3"]);
+        let right = multi(&mut bufmgr, &[b"2", b"3"]);
+        let merged = BTree::merge(&mut bufmgr, &left, &right, ConflictPolicy::TakeLeft).unwrap();
+        assert_eq!(KeyMode::Multi, merged.key_mode(&mut bufmgr).unwrap());
+        let values: Vec<_> =
+            merged.get_all(&mut bufmgr, b"key").unwrap().map(Result::unwrap).collect();
+        assert_eq!(vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()], values);
+
+        let unique = BTree::create(&mut bufmgr).unwrap();
+        assert!(matches!(
+            BTree::merge(&mut bufmgr, &left, &unique, ConflictPolicy::TakeLeft),
+            Err(Error::KeyModeMismatch {
+                left: KeyMode::Multi,
+                right: KeyMode::Unique
+            })
+        ));
+        assert!(matches!(
+            BTree::merge(&mut bufmgr, &unique, &left, ConflictPolicy::TakeLeft),
+            Err(Error::KeyModeMismatch {
+                left: KeyMode::Unique,
+                right: KeyMode::Multi
+            })
+        ));
+    }
+}
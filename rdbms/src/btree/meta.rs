@@ -1,11 +1,66 @@
-use zerocopy::{AsBytes, ByteSlice, FromBytes, LayoutVerified};
+use zerocopy::{AsBytes, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified};
 
+use super::{node, BTree, Error, KeyComparatorId, KeyMode};
+use crate::buffer::BufferPoolManager;
 use crate::disk::PageId;
 
 #[derive(Debug, FromBytes, AsBytes)]
 #[repr(C)]
 pub struct Header {
     pub root_page_id: PageId,
+    // 以前のメタページではこのフィールドは0になっている
+    pub flags: u64,
+    pub node_version: u64,
+    // FLAG_COUNTEDが立っているときだけ正しい
+    pub num_entries: u64,
+    // リーフを分割した回数。以前のメタページでは0から数え始める
+    pub half_splits: u64,
+    pub append_splits: u64,
+    // メタページの形式の版。以前のメタページでは0になっていて、木の高さを記録していない
+    pub meta_version: u64,
+    // 根だけの木は1
+    pub height: u64,
+    // 次に削除したペアの墓標に記録する番号。以前のメタページでは0から数え始める
+    pub tombstone_seq: u64,
+    // 分割したときに左のノードへ詰める百分率。以前のメタページでは0で、半分ずつに分ける
+    pub leaf_fill: u8,
+    pub branch_fill: u8,
+    pub _reserved: [u8; 2],
+    // キーの比べ方の番号。以前のメタページでは0で、バイト列をそのまま比べる
+    pub comparator_id: u32,
+    // 根のページや木の形式を変えるたびに増やす。ハンドルはこれが変わるまで、読んだ根と形式を使い回す
+    pub generation: u64,
+}
+
+// ノードの形式の版
+// 0: 値をそのままリーフに置く
+// 1: 値の前にタグを置き、大きな値はオーバーフローページに置く
+pub const NODE_VERSION: u64 = 1;
+
+// メタページの形式の版
+// 0: 木の高さを記録しない
+// 1: 木の高さを記録する
+// 2: キーの比べ方の番号と、根や形式を変えるたびに増やすgenerationを記録する
+pub const META_VERSION: u64 = 2;
+
+// キーの重複を許す木
+const FLAG_MULTI: u64 = 1;
+// ペアの数を数えている木。以前に作った木では立っていない
+const FLAG_COUNTED: u64 = 2;
+// 木を壊したあとのメタページ。解放したページも先頭の8バイトのほかは残る
+const FLAG_DESTROYED: u64 = 4;
+// deleteでペアを取り除かず、墓標に置き換える木
+const FLAG_TOMBSTONES: u64 = 8;
+
+// 分割したときに左のノードへ詰める百分率の既定値
+pub const DEFAULT_FILL: u8 = 50;
+
+fn fill_or_default(fill: u8) -> u8 {
+    if fill == 0 {
+        DEFAULT_FILL
+    } else {
+        fill
+    }
 }
 
 pub struct Meta<B> {
@@ -19,4 +74,157 @@ impl<B: ByteSlice> Meta<B> {
             LayoutVerified::new_from_prefix(bytes).expect("meta page must be aligned");
         Self { header, _unused }
     }
+
+    pub fn is_multi(&self) -> bool {
+        self.header.flags & FLAG_MULTI != 0
+    }
+
+    pub fn is_destroyed(&self) -> bool {
+        self.header.flags & FLAG_DESTROYED != 0
+    }
+
+    pub fn uses_tombstones(&self) -> bool {
+        self.header.flags & FLAG_TOMBSTONES != 0
+    }
+
+    // ペアの数を数えていなければNoneを返す
+    pub fn num_entries(&self) -> Option<u64> {
+        if self.header.flags & FLAG_COUNTED != 0 {
+            Some(self.header.num_entries)
+        } else {
+            None
+        }
+    }
+
+    pub fn leaf_fill(&self) -> u8 {
+        fill_or_default(self.header.leaf_fill)
+    }
+
+    pub fn branch_fill(&self) -> u8 {
+        fill_or_default(self.header.branch_fill)
+    }
+
+    pub fn comparator(&self) -> KeyComparatorId {
+        KeyComparatorId(self.header.comparator_id)
+    }
+
+    // 高さを記録していない形式ならNoneを返す
+    pub fn height(&self) -> Option<u64> {
+        if self.header.meta_version >= 1 {
+            Some(self.header.height)
+        } else {
+            None
+        }
+    }
+}
+
+impl<B: ByteSliceMut> Meta<B> {
+    pub fn set_multi(&mut self, multi: bool) {
+        if multi {
+            self.header.flags |= FLAG_MULTI;
+        } else {
+            self.header.flags &= !FLAG_MULTI;
+        }
+    }
+
+    pub fn set_destroyed(&mut self) {
+        self.header.flags |= FLAG_DESTROYED;
+        self.bump_generation();
+    }
+
+    pub fn set_tombstones(&mut self, tombstones: bool) {
+        if tombstones {
+            self.header.flags |= FLAG_TOMBSTONES;
+        } else {
+            self.header.flags &= !FLAG_TOMBSTONES;
+        }
+        self.bump_generation();
+    }
+
+    pub fn set_root_page_id(&mut self, root_page_id: PageId) {
+        self.header.root_page_id = root_page_id;
+        self.bump_generation();
+    }
+
+    // ほかのハンドルが覚えている根と形式を読み直させる
+    pub fn bump_generation(&mut self) {
+        self.header.generation = self.header.generation.wrapping_add(1);
+    }
+
+    pub fn set_num_entries(&mut self, num_entries: u64) {
+        self.header.flags |= FLAG_COUNTED;
+        self.header.num_entries = num_entries;
+    }
+
+    // ペアを加えたり削除したりした数を反映する。数えていない木では何もしない
+    pub fn add_num_entries(&mut self, delta: i64) {
+        if let Some(num_entries) = self.num_entries() {
+            self.header.num_entries = (num_entries as i64 + delta) as u64;
+        }
+    }
+
+    // 根を分割したり、子が1つの根を取り除いたりしたら呼ぶ。高さを記録していない木では何もしない
+    pub fn add_height(&mut self, delta: i64) {
+        if let Some(height) = self.height() {
+            self.header.height = (height as i64 + delta) as u64;
+        }
+    }
+}
+
+// メタページに記録した木の性質
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BTreeMeta {
+    pub meta_version: u64,
+    pub node_version: u64,
+    pub root_page_id: PageId,
+    // 以前の形式のメタページでは、根から左端のリーフまでたどって数える
+    pub height: usize,
+    // ペアの数を数えていない木ではNone
+    pub num_entries: Option<u64>,
+    pub key_mode: KeyMode,
+    // deleteが墓標を残すかどうか
+    pub tombstones: bool,
+    pub leaf_fill: u8,
+    pub branch_fill: u8,
+    pub comparator: KeyComparatorId,
+}
+
+impl BTree {
+    pub fn meta(&self, bufmgr: &mut BufferPoolManager) -> Result<BTreeMeta, Error> {
+        let mut meta = {
+            let meta_buffer = bufmgr.fetch_page_read(self.meta_page_id)?;
+            let meta = Meta::new(meta_buffer.data());
+            self.check_meta(&meta)?;
+            BTreeMeta {
+                meta_version: meta.header.meta_version,
+                node_version: meta.header.node_version,
+                root_page_id: meta.header.root_page_id,
+                height: meta.height().unwrap_or_default() as usize,
+                num_entries: meta.num_entries(),
+                key_mode: if meta.is_multi() {
+                    KeyMode::Multi
+                } else {
+                    KeyMode::Unique
+                },
+                tombstones: meta.uses_tombstones(),
+                leaf_fill: meta.leaf_fill(),
+                branch_fill: meta.branch_fill(),
+                comparator: meta.comparator(),
+            }
+        };
+        if meta.height == 0 {
+            let mut page_id = meta.root_page_id;
+            loop {
+                meta.height += 1;
+                let buffer = bufmgr.fetch_page(page_id)?;
+                let node = node::Node::new(buffer.data());
+                let child_page_id = match node::Body::new(node.header.node_type, node.body) {
+                    node::Body::Branch(branch) => branch.child_at(0),
+                    node::Body::Leaf(_) => break,
+                };
+                page_id = child_page_id;
+            }
+        }
+        Ok(meta)
+    }
 }
@@ -0,0 +1,203 @@
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{BTree, Iter, RangeIter};
+
+// 読み書きでたどったノードの数と、写したバイト数
+// Iterでは作ってからの合計、BTreeではそのハンドルで最後にリセットしてからの合計を数える
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpMetrics {
+    pub branch_pages: u64,
+    pub leaf_pages: u64,
+    // 呼び出し側に返したペアの数
+    pub entries: u64,
+    // 返したキーと値や、書き込んだキーと値のバイト数
+    pub bytes_copied: u64,
+}
+
+impl AddAssign for OpMetrics {
+    fn add_assign(&mut self, other: Self) {
+        self.branch_pages += other.branch_pages;
+        self.leaf_pages += other.leaf_pages;
+        self.entries += other.entries;
+        self.bytes_copied += other.bytes_copied;
+    }
+}
+
+// ハンドルとそこから作ったIterで共有する合計
+// ハンドルをほかのスレッドに渡せるよう、アトミックに数える
+#[derive(Debug, Default)]
+pub(super) struct SharedMetrics {
+    branch_pages: AtomicU64,
+    leaf_pages: AtomicU64,
+    entries: AtomicU64,
+    bytes_copied: AtomicU64,
+}
+
+impl SharedMetrics {
+    fn counters(&self) -> [&AtomicU64; 4] {
+        [&self.branch_pages, &self.leaf_pages, &self.entries, &self.bytes_copied]
+    }
+
+    fn get(&self) -> OpMetrics {
+        let [branch_pages, leaf_pages, entries, bytes_copied] =
+            self.counters().map(|counter| counter.load(Ordering::Relaxed));
+        OpMetrics {
+            branch_pages,
+            leaf_pages,
+            entries,
+            bytes_copied,
+        }
+    }
+
+    fn reset(&self) {
+        for counter in self.counters() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn add(&self, delta: OpMetrics) {
+        let deltas = [delta.branch_pages, delta.leaf_pages, delta.entries, delta.bytes_copied];
+        for (counter, delta) in self.counters().iter().zip(deltas) {
+            if delta > 0 {
+                counter.fetch_add(delta, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+impl BTree {
+    // このハンドルとそこから作ったIterでの読み書きの合計。同じ木でもBTree::newで作り直せば別に数える
+    pub fn op_metrics(&self) -> OpMetrics {
+        self.metrics.get()
+    }
+
+    pub fn reset_op_metrics(&self) {
+        self.metrics.reset();
+    }
+
+    pub(super) fn add_metrics(&self, delta: OpMetrics) {
+        self.metrics.add(delta);
+    }
+}
+
+impl Iter {
+    // 探し始めてからの合計。木をたどったページも含む
+    pub fn metrics(&self) -> OpMetrics {
+        self.metrics
+    }
+
+    pub(super) fn add_metrics(&mut self, delta: OpMetrics) {
+        self.metrics += delta;
+        self.shared_metrics.add(delta);
+    }
+}
+
+impl RangeIter {
+    // 範囲の外のペアは返さないので、entriesに数えない
+    pub fn metrics(&self) -> OpMetrics {
+        self.iter.metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::ops::Bound;
+
+    use super::super::{BulkLoadOptions, SearchMode};
+    use super::*;
+    use crate::buffer::{BufferPool, BufferPoolManager};
+    use crate::disk::MemoryDiskManager;
+
+    #[test]
+    fn test_metrics() {
+        let page_size = 512;
+        let disk = MemoryDiskManager::with_page_size(page_size).unwrap();
+        let pool = BufferPool::new(64).with_page_size(page_size);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        // 1ページにペアが13ほど入るので、1000個のペアは3段になる
+        let pairs = (0u64..1000).map(|i| (i.to_be_bytes().to_vec(), vec![i as u8; 16]));
+        let options = BulkLoadOptions {
+            fill_factor: 1.0,
+            ..BulkLoadOptions::default()
+        };
+        let btree = BTree::bulk_load_with(&mut bufmgr, pairs, options).unwrap();
+        assert_eq!(3, btree.meta(&mut bufmgr).unwrap().height);
+        btree.reset_op_metrics();
+
+        // 2つ目のリーフの最後のペアから、3つ目のリーフの途中までを読む
+        let first_leaf_len = {
+            let mut iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+            let mut len = 0u64;
+            while iter.metrics().leaf_pages == 1 {
+                iter.next(&mut bufmgr).unwrap();
+                len += 1;
+            }
+            len
+        };
+        btree.reset_op_metrics();
+        let start = (2 * first_leaf_len - 1).to_be_bytes();
+        let end = (2 * first_leaf_len + 2).to_be_bytes();
+        let range = (Bound::Included(&start[..]), Bound::Included(&end[..]));
+        let mut iter = btree.scan_range(&mut bufmgr, range.0, range.1).unwrap();
+        let mut pairs = vec![];
+        while let Some((key, _)) = iter.next(&mut bufmgr).unwrap() {
+            pairs.push(u64::from_be_bytes(key[..].try_into().unwrap()));
+        }
+        assert_eq!((2 * first_leaf_len - 1..=2 * first_leaf_len + 2).collect::<Vec<_>>(), pairs);
+        let expected = OpMetrics {
+            branch_pages: 2,
+            leaf_pages: 2,
+            entries: 4,
+            bytes_copied: 4 * (8 + 16),
+        };
+        assert_eq!(expected, iter.metrics());
+        assert_eq!(expected, btree.op_metrics());
+        drop(iter);
+
+        // getとinsertも、根からリーフまでたどったページを数える
+        btree.reset_op_metrics();
+        btree.get(&mut bufmgr, &500u64.to_be_bytes()).unwrap().unwrap();
+        btree.get(&mut bufmgr, &5000u64.to_be_bytes()).unwrap();
+        let expected = OpMetrics {
+            branch_pages: 4,
+            leaf_pages: 2,
+            entries: 1,
+            bytes_copied: 16,
+        };
+        assert_eq!(expected, btree.op_metrics());
+        btree.reset_op_metrics();
+        btree.insert(&mut bufmgr, &5000u64.to_be_bytes(), &[0; 10]).unwrap();
+        let expected = OpMetrics {
+            branch_pages: 2,
+            leaf_pages: 1,
+            entries: 0,
+            bytes_copied: 8 + 10,
+        };
+        assert_eq!(expected, btree.op_metrics());
+
+        // deleteとinsert_batchも、たどったページを数える
+        btree.reset_op_metrics();
+        assert!(btree.delete(&mut bufmgr, &5000u64.to_be_bytes()).unwrap());
+        // 値のオーバーフローページを解放するため、先に値を読むので2度たどる
+        let deleted = btree.op_metrics();
+        assert_eq!((4, 2), (deleted.branch_pages, deleted.leaf_pages));
+        btree.reset_op_metrics();
+        let mut entries = vec![(5000u64.to_be_bytes().to_vec(), vec![0; 10])];
+        btree.insert_batch(&mut bufmgr, &mut entries).unwrap();
+        assert_eq!(expected, btree.op_metrics());
+
+        // 別のハンドルでは数えない
+        let other = BTree::new(btree.meta_page_id);
+        other.get(&mut bufmgr, &500u64.to_be_bytes()).unwrap();
+        assert_eq!(expected, btree.op_metrics());
+        assert_eq!(1, other.op_metrics().leaf_pages);
+
+        // 数えていても、ハンドルはほかのスレッドに渡せる
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+        assert_send_sync(&btree);
+        let metrics = std::thread::spawn(move || btree.op_metrics()).join().unwrap();
+        assert_eq!(expected, metrics);
+    }
+}
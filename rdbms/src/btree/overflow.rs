@@ -0,0 +1,135 @@
+use std::convert::TryInto;
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified};
+
+use super::Error;
+use crate::disk::PageId;
+
+// リーフに置く値の前に付けるタグ
+const TAG_INLINE: u8 = 0;
+const TAG_OVERFLOW: u8 = 1;
+
+// リーフに置く値。大きな値はオーバーフローページの連なりに置き、先頭のページIDと長さだけを持つ
+#[derive(Debug, PartialEq)]
+pub enum StoredValue<'a> {
+    Inline(&'a [u8]),
+    Overflow { page_id: PageId, len: usize },
+}
+
+impl<'a> StoredValue<'a> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            StoredValue::Inline(value) => [&[TAG_INLINE][..], value].concat(),
+            StoredValue::Overflow { page_id, len } => {
+                let mut bytes = vec![TAG_OVERFLOW];
+                bytes.extend_from_slice(&page_id.to_u64().to_be_bytes());
+                bytes.extend_from_slice(&(*len as u64).to_be_bytes());
+                bytes
+            }
+        }
+    }
+
+    // 知らないタグや途中で切れたバイト列は、壊れたページから読んだものなのでエラーにする
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, Error> {
+        let corrupted = || Error::CorruptedValue { len: bytes.len() };
+        match bytes.split_first() {
+            Some((&TAG_INLINE, value)) => Ok(StoredValue::Inline(value)),
+            Some((&TAG_OVERFLOW, rest)) if rest.len() == 16 => {
+                let page_id = u64::from_be_bytes(rest[..8].try_into().unwrap());
+                let len = u64::from_be_bytes(rest[8..].try_into().unwrap());
+                Ok(StoredValue::Overflow {
+                    page_id: PageId(page_id),
+                    len: len as usize,
+                })
+            }
+            _ => Err(corrupted()),
+        }
+    }
+}
+
+#[derive(Debug, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct Header {
+    next_page_id: PageId,
+}
+
+// オーバーフローページ。値の一部を持ち、続きのページを指す
+pub struct Overflow<B> {
+    header: LayoutVerified<B, Header>,
+    body: B,
+}
+
+impl<B: ByteSlice> Overflow<B> {
+    pub fn new(bytes: B) -> Self {
+        let (header, body) =
+            LayoutVerified::new_from_prefix(bytes).expect("overflow header must be aligned");
+        Self { header, body }
+    }
+
+    pub fn next_page_id(&self) -> Option<PageId> {
+        self.header.next_page_id.valid()
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.body
+    }
+}
+
+impl<B: ByteSliceMut> Overflow<B> {
+    pub fn set_next_page_id(&mut self, next_page_id: Option<PageId>) {
+        self.header.next_page_id = next_page_id.into()
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.body
+    }
+}
+
+// 1ページに置ける値のバイト数
+pub fn capacity(page_size: usize) -> usize {
+    page_size - size_of::<Header>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stored_value() {
+        for value in [
+            StoredValue::Inline(b""),
+            StoredValue::Inline(b"hello"),
+            StoredValue::Overflow {
+                page_id: PageId(42),
+                len: 1 << 20,
+            },
+        ] {
+            assert_eq!(value, StoredValue::from_bytes(&value.to_bytes()).unwrap());
+        }
+        // 壊れたバイト列ではパニックせずにエラーを返す
+        let overflow = StoredValue::Overflow {
+            page_id: PageId(42),
+            len: 1,
+        }
+        .to_bytes();
+        for bytes in [&[][..], &[7, 0, 0], &overflow[..16]] {
+            assert!(matches!(
+                StoredValue::from_bytes(bytes),
+                Err(Error::CorruptedValue { len }) if len == bytes.len()
+            ));
+        }
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut page_data = vec![0; 100];
+        let mut page = Overflow::new(page_data.as_mut_slice());
+        page.set_next_page_id(None);
+        page.data_mut().fill(7);
+        assert_eq!(None, page.next_page_id());
+        assert_eq!(capacity(100), page.data().len());
+        page.set_next_page_id(Some(PageId(3)));
+        assert_eq!(Some(PageId(3)), page.next_page_id());
+    }
+}
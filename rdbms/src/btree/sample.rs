@@ -0,0 +1,197 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{meta, node, BTree, Error};
+use crate::buffer::{AccessStrategy, BufferPoolManager};
+
+// いくつかのリーフから見積もった木の大きさ
+// 根からリーフまで子を無作為に選んでたどり、通った枝の子の数の積をそのリーフの重みにする
+// リーフのペアの数に重みを掛けた値の平均は、ペアの総数の偏りのない推定になる
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleStats {
+    pub sampled_leaves: usize,
+    pub estimated_entries: u64,
+    // 推定したペアの数の、およそ95%の信頼区間
+    pub entries_low: u64,
+    pub entries_high: u64,
+    pub estimated_leaves: u64,
+    pub avg_entries_per_leaf: f64,
+    // 読んだリーフの中での最小と最大のキー
+    pub min_key: Option<Vec<u8>>,
+    pub max_key: Option<Vec<u8>>,
+}
+
+// 標本を選ぶための小さな疑似乱数(splitmix64)。同じシードからは同じ列を返す
+pub(super) struct Rng(pub(super) u64);
+
+impl Rng {
+    pub(super) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    pub(super) fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+impl BTree {
+    // n_leaves本の根からリーフへの道をたどって見積もる。シードは時刻から決める
+    pub fn sample(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        n_leaves: usize,
+    ) -> Result<SampleStats, Error> {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or_default();
+        self.sample_with_seed(bufmgr, n_leaves, seed)
+    }
+
+    // 同じシードで同じ木を見積もれば、同じ結果を返す
+    // 読んだページでほかのページを追い出さないよう、少数のバッファを使い回して読む
+    pub fn sample_with_seed(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        n_leaves: usize,
+        seed: u64,
+    ) -> Result<SampleStats, Error> {
+        let root_page_id = {
+            let meta_buffer = bufmgr.fetch_page_read(self.meta_page_id)?;
+            let meta = meta::Meta::new(meta_buffer.data());
+            self.check_meta(&meta)?;
+            meta.header.root_page_id
+        };
+        let format = self.format(bufmgr)?;
+        let mut rng = Rng(seed);
+        let n_leaves = n_leaves.max(1);
+        let mut entries_estimates = Vec::with_capacity(n_leaves);
+        let mut leaves_estimate = 0.0;
+        let mut total_pairs = 0;
+        let mut min_key: Option<Vec<u8>> = None;
+        let mut max_key: Option<Vec<u8>> = None;
+        for _ in 0..n_leaves {
+            let mut page_id = root_page_id;
+            let mut weight = 1.0;
+            loop {
+                let buffer = bufmgr.fetch_page_with_strategy(page_id, AccessStrategy::BulkRead)?;
+                let node = node::Node::new(buffer.data());
+                let body = node::Body::new(node.header.node_type, node.body);
+                match body {
+                    node::Body::Branch(branch) => {
+                        let num_children = branch.num_pairs() + 1;
+                        weight *= num_children as f64;
+                        page_id = branch.child_at(rng.below(num_children));
+                    }
+                    node::Body::Leaf(leaf) => {
+                        // 墓標は見えないペアなので数えない
+                        let num_pairs = leaf.num_pairs() - leaf.num_tombstones();
+                        total_pairs += num_pairs;
+                        entries_estimates.push(num_pairs as f64 * weight);
+                        leaves_estimate += weight;
+                        let mut live = (0..leaf.num_pairs()).filter(|&i| !leaf.is_tombstone(i));
+                        if let Some(first) = live.next() {
+                            let last = live.next_back().unwrap_or(first);
+                            let first_key = format.user_key(leaf.pair_at(first).key);
+                            let last_key = format.user_key(leaf.pair_at(last).key);
+                            let cmp = format.cmp;
+                            if min_key.as_ref().map_or(true, |min| cmp.lt(&first_key, min)) {
+                                min_key = Some(first_key);
+                            }
+                            if max_key.as_ref().map_or(true, |max| cmp.lt(max, &last_key)) {
+                                max_key = Some(last_key);
+                            }
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+        let n = entries_estimates.len() as f64;
+        let mean = entries_estimates.iter().sum::<f64>() / n;
+        // 標本が1つなら、ばらつきはわからないので幅を持たせない
+        let stderr = if entries_estimates.len() > 1 {
+            let variance =
+                entries_estimates.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0);
+            (variance / n).sqrt()
+        } else {
+            0.0
+        };
+        Ok(SampleStats {
+            sampled_leaves: entries_estimates.len(),
+            estimated_entries: mean.round() as u64,
+            entries_low: (mean - 1.96 * stderr).max(0.0).round() as u64,
+            entries_high: (mean + 1.96 * stderr).round() as u64,
+            estimated_leaves: (leaves_estimate / n).round() as u64,
+            avg_entries_per_leaf: total_pairs as f64 / n,
+            min_key,
+            max_key,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::MemoryDiskManager;
+
+    #[test]
+    fn test_sample() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(32));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let stats = btree.sample_with_seed(&mut bufmgr, 10, 1).unwrap();
+        assert_eq!((0, 0, 0, 1), (
+            stats.estimated_entries,
+            stats.entries_low,
+            stats.entries_high,
+            stats.estimated_leaves
+        ));
+        assert_eq!(None, stats.min_key);
+
+        // キーの順を散らして、リーフごとのペアの数にばらつきを持たせる
+        let num_entries = 20000u64;
+        for i in 0..num_entries {
+            let key = (i * 7919 % num_entries).to_be_bytes();
+            btree.insert(&mut bufmgr, &key, &[0; 100]).unwrap();
+        }
+        let tree_stats = btree.stats(&mut bufmgr).unwrap();
+        assert!(tree_stats.height >= 3);
+        let n_leaves = (tree_stats.leaf_pages as usize / 20).max(1);
+
+        let stats = btree.sample_with_seed(&mut bufmgr, n_leaves, 42).unwrap();
+        assert_eq!(n_leaves, stats.sampled_leaves);
+        let error = (stats.estimated_entries as f64 - num_entries as f64).abs();
+        assert!(error < num_entries as f64 * 0.2, "{:?}", stats);
+        assert!(stats.entries_low <= stats.estimated_entries);
+        assert!(stats.estimated_entries <= stats.entries_high);
+        let leaf_error = (stats.estimated_leaves as f64 - tree_stats.leaf_pages as f64).abs();
+        assert!(leaf_error < tree_stats.leaf_pages as f64 * 0.2, "{:?}", stats);
+        let min_key = u64::from_be_bytes(stats.min_key.clone().unwrap()[..].try_into().unwrap());
+        let max_key = u64::from_be_bytes(stats.max_key.clone().unwrap()[..].try_into().unwrap());
+        assert!(min_key <= max_key && max_key < num_entries);
+
+        // 同じシードなら同じ結果になる
+        assert_eq!(stats, btree.sample_with_seed(&mut bufmgr, n_leaves, 42).unwrap());
+        assert!(btree.sample(&mut bufmgr, n_leaves).is_ok());
+
+        // 墓標は数えず、最小と最大のキーにも使わない
+        btree.set_tombstone_mode(&mut bufmgr, true).unwrap();
+        for i in (0..num_entries).step_by(2) {
+            btree.delete(&mut bufmgr, &i.to_be_bytes()).unwrap();
+        }
+        let stats = btree.sample_with_seed(&mut bufmgr, n_leaves, 42).unwrap();
+        let live_entries = (num_entries / 2) as f64;
+        let error = (stats.estimated_entries as f64 - live_entries).abs();
+        assert!(error < live_entries * 0.2, "{:?}", stats);
+        let min_key = u64::from_be_bytes(stats.min_key.unwrap()[..].try_into().unwrap());
+        let max_key = u64::from_be_bytes(stats.max_key.unwrap()[..].try_into().unwrap());
+        assert!(min_key % 2 == 1 && max_key % 2 == 1);
+    }
+}
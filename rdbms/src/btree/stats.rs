@@ -0,0 +1,155 @@
+use std::fmt;
+
+use super::{meta, node, BTree, Error};
+use crate::buffer::{AccessStrategy, BufferPoolManager};
+
+// 木の形と、リーフの使用率
+// 使用率は、リーフの領域のうちペアとそのポインタが使っている割合を百分率で表す
+// 分割の回数はメタページに記録したもので、作ってからの通算
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BTreeStats {
+    pub height: usize,
+    pub branch_pages: u64,
+    pub leaf_pages: u64,
+    // 墓標は数えず、tombstonesに数える
+    pub num_entries: u64,
+    pub tombstones: u64,
+    pub avg_leaf_fill: f64,
+    pub min_leaf_fill: f64,
+    pub max_leaf_fill: f64,
+    // ペアを分けた分割(既定では半分ずつ)と、右端のリーフの末尾に加えるときの分割の回数
+    pub half_splits: u64,
+    pub append_splits: u64,
+}
+
+impl fmt::Display for BTreeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "height:       {}", self.height)?;
+        writeln!(f, "branch pages: {}", self.branch_pages)?;
+        writeln!(f, "leaf pages:   {}", self.leaf_pages)?;
+        writeln!(f, "entries:      {}", self.num_entries)?;
+        write!(
+            f,
+            "leaf fill:    {:.1}% (min {:.1}%, max {:.1}%)",
+            self.avg_leaf_fill, self.min_leaf_fill, self.max_leaf_fill
+        )?;
+        write!(
+            f,
+            "\nleaf splits:  {} (half {}, append {})",
+            self.half_splits + self.append_splits,
+            self.half_splits,
+            self.append_splits
+        )
+    }
+}
+
+impl BTree {
+    // 根から1段ずつたどって、すべてのノードを数える
+    // 大きな木でもほかのページを追い出さないよう、少数のバッファを使い回して読む
+    pub fn stats(&self, bufmgr: &mut BufferPoolManager) -> Result<BTreeStats, Error> {
+        let (root_page_id, half_splits, append_splits) = {
+            let meta_buffer = bufmgr.fetch_page_read(self.meta_page_id)?;
+            let meta = meta::Meta::new(meta_buffer.data());
+            self.check_meta(&meta)?;
+            (meta.header.root_page_id, meta.header.half_splits, meta.header.append_splits)
+        };
+        let mut stats = BTreeStats {
+            height: 0,
+            branch_pages: 0,
+            leaf_pages: 0,
+            num_entries: 0,
+            tombstones: 0,
+            avg_leaf_fill: 0.0,
+            min_leaf_fill: 0.0,
+            max_leaf_fill: 0.0,
+            half_splits,
+            append_splits,
+        };
+        let mut total_fill = 0.0;
+        let mut min_fill = f64::INFINITY;
+        let mut max_fill: f64 = 0.0;
+        let mut level = vec![root_page_id];
+        while !level.is_empty() {
+            stats.height += 1;
+            let mut children = vec![];
+            for page_id in level {
+                let buffer = bufmgr.fetch_page_with_strategy(page_id, AccessStrategy::BulkRead)?;
+                let node = node::Node::new(buffer.data());
+                let body = node::Body::new(node.header.node_type, node.body);
+                match body {
+                    node::Body::Leaf(leaf) => {
+                        let fill = leaf.occupancy() * 100.0;
+                        stats.leaf_pages += 1;
+                        let tombstones = leaf.num_tombstones() as u64;
+                        stats.num_entries += leaf.num_pairs() as u64 - tombstones;
+                        stats.tombstones += tombstones;
+                        total_fill += fill;
+                        min_fill = min_fill.min(fill);
+                        max_fill = max_fill.max(fill);
+                    }
+                    node::Body::Branch(branch) => {
+                        stats.branch_pages += 1;
+                        children.extend((0..=branch.num_pairs()).map(|i| branch.child_at(i)));
+                    }
+                }
+            }
+            level = children;
+        }
+        stats.avg_leaf_fill = total_fill / stats.leaf_pages as f64;
+        stats.min_leaf_fill = min_fill;
+        stats.max_leaf_fill = max_fill;
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::BulkLoadOptions;
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::MemoryDiskManager;
+
+    #[test]
+    fn test_stats() {
+        let page_size = 512;
+        let disk = MemoryDiskManager::with_page_size(page_size).unwrap();
+        let pool = BufferPool::new(64).with_page_size(page_size);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let stats = btree.stats(&mut bufmgr).unwrap();
+        assert_eq!(1, stats.height);
+        assert_eq!((0, 1, 0), (stats.branch_pages, stats.leaf_pages, stats.num_entries));
+
+        // 1ページにペアが13ほど入るので、1000個のペアは3段になる
+        let pairs = (0u64..1000).map(|i| (i.to_be_bytes().to_vec(), i.to_be_bytes().to_vec()));
+        let options = BulkLoadOptions {
+            fill_factor: 1.0,
+            ..BulkLoadOptions::default()
+        };
+        let btree = BTree::bulk_load_with(&mut bufmgr, pairs, options).unwrap();
+        let stats = btree.stats(&mut bufmgr).unwrap();
+        assert_eq!(3, stats.height);
+        assert_eq!(3, btree.meta(&mut bufmgr).unwrap().height);
+        assert_eq!(1000, stats.num_entries);
+        // 2つの木のメタページと、空の木のリーフのほかはすべて数える
+        assert_eq!(
+            bufmgr.storage_info().unwrap().num_pages,
+            stats.branch_pages + stats.leaf_pages + 3
+        );
+        assert!(stats.min_leaf_fill <= stats.avg_leaf_fill + 1e-9);
+        assert!(stats.avg_leaf_fill <= stats.max_leaf_fill + 1e-9);
+        assert!(stats.avg_leaf_fill > 90.0);
+        assert!(stats.max_leaf_fill <= 100.0);
+        assert!(stats.to_string().starts_with("height:       3\n"));
+
+        // 数えても、よく使うページをバッファプールから追い出さない
+        let key = 500u64.to_be_bytes();
+        for _ in 0..3 {
+            btree.get(&mut bufmgr, &key).unwrap();
+        }
+        btree.stats(&mut bufmgr).unwrap();
+        bufmgr.reset_stats();
+        btree.get(&mut bufmgr, &key).unwrap();
+        assert_eq!(0, bufmgr.stats().misses);
+    }
+}
@@ -0,0 +1,795 @@
+use std::collections::VecDeque;
+use std::convert::identity;
+use std::sync::{RwLockReadGuard, RwLockWriteGuard};
+
+use super::overflow::{self, StoredValue};
+use super::{
+    branch, leaf, meta, node, Error, Format, Insertion, KeyComparator, KeyMode, SearchMode,
+};
+use crate::buffer::{Page, SyncBuffer, SyncBufferPoolManager};
+use crate::checksum::CHECKSUM_SIZE;
+use crate::disk::PageId;
+
+// バイト列の順の木だけを開くので、いつもそのまま比べる
+const MEMCMP: KeyComparator = KeyComparator::MEMCMP;
+
+// 末尾のチェックサムを除いたページの中身
+fn data(page: &Page) -> &[u8] {
+    &page[..page.len() - CHECKSUM_SIZE]
+}
+
+fn data_mut(page: &mut Page) -> &mut [u8] {
+    let len = page.len() - CHECKSUM_SIZE;
+    &mut page[..len]
+}
+
+fn read(buffer: &SyncBuffer) -> RwLockReadGuard<'_, Page> {
+    buffer.page.read().expect("page lock poisoned")
+}
+
+fn write(buffer: &SyncBuffer) -> RwLockWriteGuard<'_, Page> {
+    buffer.page.write().expect("page lock poisoned")
+}
+
+// リーフに置く形にした値。オーバーフローページを使うなら、その先頭をpage_idにする
+fn encode_value(format: Format, value: &[u8], page_id: PageId) -> Vec<u8> {
+    if !format.uses_overflow() {
+        return value.to_vec();
+    }
+    if value.len() <= format.overflow_threshold() {
+        return StoredValue::Inline(value).to_bytes();
+    }
+    StoredValue::Overflow {
+        page_id,
+        len: value.len(),
+    }
+    .to_bytes()
+}
+
+// ペアを加えても、このノードは分割しない
+// そうなら子を借りたところで、親から上の借りたページを返してよい
+fn has_room(format: Format, page: &Page, key: &[u8], value: &[u8]) -> bool {
+    let node = node::Node::new(data(page));
+    let body = node::Body::new(node.header.node_type, node.body);
+    match body {
+        node::Body::Leaf(leaf) => {
+            let value = encode_value(format, value, PageId::INVALID_PAGE_ID);
+            leaf.has_room(key, &value)
+        }
+        node::Body::Branch(branch) => branch.has_room_for_any(),
+    }
+}
+
+// SyncBufferPoolManagerの上で、複数のスレッドから同時に読み書きできる木
+// ページの形式はBTreeと同じなので、書き出したあとはBTreeとしても読める
+// ページを解放できないので削除はできない。キーの重複を許す木も扱わない
+//
+// 読み込みは根から共有のラッチで子を借りてから親を返す
+// 書き込みは排他のラッチで子を借り、子が分割しないとわかれば親から上を返す
+// ラッチは下か、分割するときのリーフの左隣にしか進まないので、行き詰まらない
+pub struct SyncBTree {
+    pub meta_page_id: PageId,
+}
+
+impl SyncBTree {
+    // ペアの数は数えない。数える木に挿入するときは、挿入し終えてからメタページを借り直して数を書き換える
+    pub fn create(bufmgr: &SyncBufferPoolManager) -> Result<Self, Error> {
+        let meta_buffer = bufmgr.create_page()?;
+        let root_buffer = bufmgr.create_page()?;
+        {
+            let mut root_page = write(&root_buffer);
+            let mut root = node::Node::new(data_mut(&mut root_page));
+            root.initialize_as_leaf();
+            leaf::Leaf::new(root.body).initialize();
+        }
+        bufmgr.mark_dirty(&root_buffer);
+        {
+            let mut meta_page = write(&meta_buffer);
+            let mut meta = meta::Meta::new(data_mut(&mut meta_page));
+            meta.set_root_page_id(root_buffer.page_id);
+            meta.header.flags = 0;
+            meta.header.node_version = meta::NODE_VERSION;
+            meta.header.meta_version = meta::META_VERSION;
+            meta.header.height = 1;
+        }
+        bufmgr.mark_dirty(&meta_buffer);
+        Ok(Self::new(meta_buffer.page_id))
+    }
+
+    pub fn new(meta_page_id: PageId) -> Self {
+        Self { meta_page_id }
+    }
+
+    fn check_format(
+        &self,
+        meta: &meta::Meta<&[u8]>,
+        page_size: usize,
+    ) -> Result<Format, Error> {
+        if meta.is_destroyed() {
+            return Err(Error::Destroyed(self.meta_page_id));
+        }
+        if meta.header.meta_version > meta::META_VERSION {
+            return Err(Error::UnsupportedMetaVersion {
+                meta_page_id: self.meta_page_id,
+                version: meta.header.meta_version,
+            });
+        }
+        let format = Format::from_meta(meta, page_size)?;
+        if format.key_mode == KeyMode::Multi {
+            return Err(Error::UnsupportedOnMulti("SyncBTree"));
+        }
+        // 上限のキーとの比較もバイト列の順で行うので、ほかの比べ方の木は開かない
+        if !format.cmp.is_memcmp() {
+            return Err(Error::UnsupportedWithComparator("SyncBTree"));
+        }
+        // 墓標を読み飛ばさないので、墓標を残す木は開かない
+        if format.tombstones {
+            return Err(Error::UnsupportedWithTombstones("SyncBTree"));
+        }
+        Ok(format)
+    }
+
+    fn format(&self, bufmgr: &SyncBufferPoolManager) -> Result<Format, Error> {
+        let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
+        let meta_page = read(&meta_buffer);
+        let meta = meta::Meta::new(data(&meta_page));
+        self.check_format(&meta, data(&meta_page).len())
+    }
+
+    // keyを含むリーフを共有のラッチで借り、fに渡す。keyがNoneなら左端のリーフを借りる
+    // fには、そのリーフのキーの上限となる区切りのキーも渡す。右端のリーフならNone
+    fn with_leaf<T>(
+        &self,
+        bufmgr: &SyncBufferPoolManager,
+        key: Option<&[u8]>,
+        f: impl FnOnce(&leaf::Leaf<&[u8]>, Option<Vec<u8>>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
+        let meta_page = read(&meta_buffer);
+        let root_page_id = {
+            let meta = meta::Meta::new(data(&meta_page));
+            self.check_format(&meta, data(&meta_page).len())?;
+            meta.header.root_page_id
+        };
+        let root_buffer = bufmgr.fetch_page(root_page_id)?;
+        let root_page = read(&root_buffer);
+        drop(meta_page);
+        self.read_node(bufmgr, root_page, key, None, f)
+    }
+
+    fn read_node<T>(
+        &self,
+        bufmgr: &SyncBufferPoolManager,
+        page: RwLockReadGuard<'_, Page>,
+        key: Option<&[u8]>,
+        upper: Option<Vec<u8>>,
+        f: impl FnOnce(&leaf::Leaf<&[u8]>, Option<Vec<u8>>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let (child_page_id, upper) = {
+            let node = node::Node::new(data(&page));
+            let body = node::Body::new(node.header.node_type, node.body);
+            match body {
+                node::Body::Leaf(leaf) => return f(&leaf, upper),
+                node::Body::Branch(branch) => {
+                    let child_idx = key.map_or(0, |key| branch.search_child_idx(key, MEMCMP));
+                    let upper = if child_idx < branch.num_pairs() {
+                        Some(branch.pair_at(child_idx).key.to_vec())
+                    } else {
+                        upper
+                    };
+                    (branch.child_at(child_idx), upper)
+                }
+            }
+        };
+        let child_buffer = bufmgr.fetch_page(child_page_id)?;
+        let child_page = read(&child_buffer);
+        drop(page);
+        self.read_node(bufmgr, child_page, key, upper, f)
+    }
+
+    // page_idのリーフから左へたどり、最初に見つかったペアのキーを返す
+    // 右のリーフを持ったまま左を借りるので、その間に左隣が変わることはない
+    fn last_key_from(
+        &self,
+        bufmgr: &SyncBufferPoolManager,
+        page_id: Option<PageId>,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let page_id = match page_id {
+            Some(page_id) => page_id,
+            None => return Ok(None),
+        };
+        let buffer = bufmgr.fetch_page(page_id)?;
+        let page = read(&buffer);
+        let node = node::Node::new(data(&page));
+        let leaf = leaf::Leaf::new(node.body);
+        match leaf.num_pairs().checked_sub(1) {
+            Some(slot_id) => Ok(Some(leaf.pair_at(slot_id).key.to_vec())),
+            None => self.last_key_from(bufmgr, leaf.prev_page_id()),
+        }
+    }
+
+    fn load_value(
+        bufmgr: &SyncBufferPoolManager,
+        format: Format,
+        value: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        if !format.uses_overflow() {
+            return Ok(value.to_vec());
+        }
+        let (mut next_page_id, len) = match StoredValue::from_bytes(value)? {
+            StoredValue::Inline(value) => return Ok(value.to_vec()),
+            StoredValue::Overflow { page_id, len } => (Some(page_id), len),
+        };
+        let mut bytes = Vec::with_capacity(len);
+        while let Some(page_id) = next_page_id {
+            let buffer = bufmgr.fetch_page(page_id)?;
+            let page = read(&buffer);
+            let overflow = overflow::Overflow::new(data(&page));
+            let chunk = overflow.data();
+            let n = chunk.len().min(len - bytes.len());
+            bytes.extend_from_slice(&chunk[..n]);
+            next_page_id = overflow.next_page_id();
+        }
+        Ok(bytes)
+    }
+
+    pub fn get(
+        &self,
+        bufmgr: &SyncBufferPoolManager,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let format = self.format(bufmgr)?;
+        let value = self.with_leaf(bufmgr, Some(key), |leaf, _| {
+            let slot_id = leaf.search_slot_id(key, MEMCMP).ok();
+            Ok(slot_id.map(|slot_id| leaf.pair_at(slot_id).value.to_vec()))
+        })?;
+        value.map(|value| Self::load_value(bufmgr, format, &value)).transpose()
+    }
+
+    // 返したイテレータは、次のリーフに進むたびに根からたどり直す
+    // 途中で挿入されたペアは、まだ読んでいない範囲にあれば返す
+    pub fn search(
+        &self,
+        bufmgr: &SyncBufferPoolManager,
+        search_mode: SearchMode,
+    ) -> Result<SyncIter, Error> {
+        let format = self.format(bufmgr)?;
+        #[allow(deprecated)]
+        let (target, done) = match search_mode {
+            SearchMode::Start => (None, false),
+            SearchMode::End => (None, true),
+            SearchMode::KeyOrNext(key) | SearchMode::Key(key) => (Some(key), false),
+            SearchMode::KeyOrPrev(key) => {
+                let found = self.with_leaf(bufmgr, Some(&key), |leaf, _| {
+                    match leaf.search_slot_id(&key, MEMCMP) {
+                        Ok(slot_id) => Ok(Some(leaf.pair_at(slot_id).key.to_vec())),
+                        Err(0) => self.last_key_from(bufmgr, leaf.prev_page_id()),
+                        Err(slot_id) => Ok(Some(leaf.pair_at(slot_id - 1).key.to_vec())),
+                    }
+                })?;
+                let done = found.is_none();
+                (found, done)
+            }
+        };
+        Ok(SyncIter {
+            meta_page_id: self.meta_page_id,
+            format,
+            target,
+            last_key: None,
+            pairs: VecDeque::new(),
+            done,
+        })
+    }
+
+    pub fn insert(
+        &self,
+        bufmgr: &SyncBufferPoolManager,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<(), Error> {
+        self.format(bufmgr)?.check_fits(key, value, (key.len(), value.len()))?;
+        let meta_buffer = bufmgr.fetch_page(self.meta_page_id)?;
+        let meta_page = write(&meta_buffer);
+        let (root_page_id, format, counted) = {
+            let meta = meta::Meta::new(data(&meta_page));
+            let format = self.check_format(&meta, data(&meta_page).len())?;
+            (meta.header.root_page_id, format, meta.num_entries().is_some())
+        };
+        let root_buffer = bufmgr.fetch_page(root_page_id)?;
+        let root_page = write(&root_buffer);
+        let root_has_room = has_room(format, &root_page, key, value);
+        let mut meta_page = Some(meta_page);
+        let insertion = {
+            let mut release = || meta_page = None;
+            if root_has_room {
+                release();
+            }
+            self.insert_node(bufmgr, format, &root_buffer, root_page, key, value, &mut release)?
+        };
+        let (key, child_page_id) = match insertion {
+            Insertion::Split(key, child_page_id) => (key, child_page_id),
+            _ => {
+                // 木のページはすべて返したので、メタページを借り直してもラッチの順は崩れない
+                if counted {
+                    let mut meta_page = meta_page.unwrap_or_else(|| write(&meta_buffer));
+                    meta::Meta::new(data_mut(&mut meta_page)).add_num_entries(1);
+                    bufmgr.mark_dirty(&meta_buffer);
+                }
+                return Ok(());
+            }
+        };
+        let mut meta_page = meta_page.expect("root split must keep the meta page");
+        let new_root_buffer = bufmgr.create_page()?;
+        {
+            let mut new_root_page = write(&new_root_buffer);
+            let mut node = node::Node::new(data_mut(&mut new_root_page));
+            node.initialize_as_branch();
+            branch::Branch::new(node.body).initialize(&key, child_page_id, root_page_id);
+        }
+        bufmgr.mark_dirty(&new_root_buffer);
+        let mut meta = meta::Meta::new(data_mut(&mut meta_page));
+        meta.set_root_page_id(new_root_buffer.page_id);
+        meta.add_height(1);
+        if counted {
+            meta.add_num_entries(1);
+        }
+        bufmgr.mark_dirty(&meta_buffer);
+        Ok(())
+    }
+
+    // 排他のラッチで借りたノードに挿入する
+    // 子が分割しないとわかったら、releaseでこのノードから上の借りたページを返す
+    #[allow(clippy::too_many_arguments)]
+    fn insert_node(
+        &self,
+        bufmgr: &SyncBufferPoolManager,
+        format: Format,
+        buffer: &SyncBuffer,
+        page: RwLockWriteGuard<'_, Page>,
+        key: &[u8],
+        value: &[u8],
+        release: &mut dyn FnMut(),
+    ) -> Result<Insertion, Error> {
+        let child = {
+            let node = node::Node::new(data(&page));
+            let body = node::Body::new(node.header.node_type, node.body);
+            match body {
+                node::Body::Leaf(_) => None,
+                node::Body::Branch(branch) => {
+                    let child_idx = branch.search_child_idx(key, MEMCMP);
+                    Some((child_idx, branch.child_at(child_idx)))
+                }
+            }
+        };
+        let (child_idx, child_page_id) = match child {
+            Some(child) => child,
+            None => return self.insert_into_leaf(bufmgr, format, buffer, page, key, value),
+        };
+        let child_buffer = bufmgr.fetch_page(child_page_id)?;
+        let child_page = write(&child_buffer);
+        let child_has_room = has_room(format, &child_page, key, value);
+        let mut page = Some(page);
+        let insertion = {
+            let mut release_path = || {
+                page = None;
+                release();
+            };
+            if child_has_room {
+                release_path();
+            }
+            self.insert_node(
+                bufmgr,
+                format,
+                &child_buffer,
+                child_page,
+                key,
+                value,
+                &mut release_path,
+            )?
+        };
+        let (overflow_key_from_child, overflow_child_page_id) = match insertion {
+            Insertion::Split(key, page_id) => (key, page_id),
+            insertion => return Ok(insertion),
+        };
+        let mut page = page.expect("split must not reach a released node");
+        bufmgr.mark_dirty(buffer);
+        let node = node::Node::new(data_mut(&mut page));
+        let mut branch = branch::Branch::new(node.body);
+        if branch
+            .insert(child_idx, &overflow_key_from_child, overflow_child_page_id)
+            .is_some()
+        {
+            return Ok(Insertion::Done);
+        }
+        let new_branch_buffer = bufmgr.create_page()?;
+        let overflow_key = {
+            let mut new_branch_page = write(&new_branch_buffer);
+            let mut new_branch_node = node::Node::new(data_mut(&mut new_branch_page));
+            new_branch_node.initialize_as_branch();
+            let mut new_branch = branch::Branch::new(new_branch_node.body);
+            branch.split_insert(
+                &mut new_branch,
+                &overflow_key_from_child,
+                overflow_child_page_id,
+                format.branch_fill,
+                format.cmp,
+            )
+        };
+        bufmgr.mark_dirty(&new_branch_buffer);
+        Ok(Insertion::Split(overflow_key, new_branch_buffer.page_id))
+    }
+
+    fn insert_into_leaf(
+        &self,
+        bufmgr: &SyncBufferPoolManager,
+        format: Format,
+        buffer: &SyncBuffer,
+        mut page: RwLockWriteGuard<'_, Page>,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Insertion, Error> {
+        let found = {
+            let node = node::Node::new(data(&page));
+            leaf::Leaf::new(node.body).search_slot_id(key, MEMCMP)
+        };
+        let slot_id = match found {
+            Ok(_) => return Err(Error::DuplicateKey),
+            Err(slot_id) => slot_id,
+        };
+        // 重複していないとわかってから、オーバーフローページに書き込む
+        let value = self.store_value(bufmgr, format, value)?;
+        bufmgr.mark_dirty(buffer);
+        let node = node::Node::new(data_mut(&mut page));
+        let mut leaf = leaf::Leaf::new(node.body);
+        if leaf.insert(slot_id, key, &value).is_some() {
+            return Ok(Insertion::Done);
+        }
+        // 分割したリーフは左隣に置くので、左隣のリーフも書き換える
+        let prev_leaf_page_id = leaf.prev_page_id();
+        let prev_leaf_buffer = prev_leaf_page_id
+            .map(|prev_leaf_page_id| bufmgr.fetch_page(prev_leaf_page_id))
+            .transpose()?;
+        let new_leaf_buffer = bufmgr.create_page()?;
+        if let Some(prev_leaf_buffer) = &prev_leaf_buffer {
+            let mut prev_leaf_page = write(prev_leaf_buffer);
+            let node = node::Node::new(data_mut(&mut prev_leaf_page));
+            leaf::Leaf::new(node.body).set_next_page_id(Some(new_leaf_buffer.page_id));
+            bufmgr.mark_dirty(prev_leaf_buffer);
+        }
+        leaf.set_prev_page_id(Some(new_leaf_buffer.page_id));
+        let overflow_key = {
+            let mut new_leaf_page = write(&new_leaf_buffer);
+            let mut new_leaf_node = node::Node::new(data_mut(&mut new_leaf_page));
+            new_leaf_node.initialize_as_leaf();
+            let mut new_leaf = leaf::Leaf::new(new_leaf_node.body);
+            new_leaf.initialize();
+            // 分割の回数は、メタページを持ち続けないよう数えない
+            let overflow_key = if leaf.is_append(slot_id) {
+                leaf.split_append(&mut new_leaf, key, &value, format.cmp)
+            } else {
+                leaf.split_insert(&mut new_leaf, key, &value, format.leaf_fill, format.cmp)
+            };
+            new_leaf.set_next_page_id(Some(buffer.page_id));
+            new_leaf.set_prev_page_id(prev_leaf_page_id);
+            overflow_key
+        };
+        bufmgr.mark_dirty(&new_leaf_buffer);
+        Ok(Insertion::Split(overflow_key, new_leaf_buffer.page_id))
+    }
+
+    // 大きな値はオーバーフローページの連なりに書き込む
+    // 新しいページは他のスレッドから見えないので、リーフを持ったままでも借りてよい
+    fn store_value(
+        &self,
+        bufmgr: &SyncBufferPoolManager,
+        format: Format,
+        value: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        if !format.uses_overflow() || value.len() <= format.overflow_threshold() {
+            return Ok(encode_value(format, value, PageId::INVALID_PAGE_ID));
+        }
+        let mut buffers = vec![];
+        for _ in value.chunks(overflow::capacity(format.page_size)) {
+            match bufmgr.create_page() {
+                Ok(buffer) => buffers.push(buffer),
+                Err(err) => {
+                    // 作ったページを返してから解放する。解放にも失敗したら、元のエラーを返す
+                    let page_ids: Vec<_> = buffers.drain(..).map(|buffer| buffer.page_id).collect();
+                    for page_id in page_ids {
+                        let _ = bufmgr.free_page(page_id);
+                    }
+                    return Err(err.into());
+                }
+            }
+        }
+        let chunks = value.chunks(overflow::capacity(format.page_size));
+        for (i, (buffer, chunk)) in buffers.iter().zip(chunks).enumerate() {
+            {
+                let mut page = write(buffer);
+                let mut overflow = overflow::Overflow::new(data_mut(&mut page));
+                overflow.data_mut()[..chunk.len()].copy_from_slice(chunk);
+                overflow.set_next_page_id(buffers.get(i + 1).map(|next| next.page_id));
+            }
+            bufmgr.mark_dirty(buffer);
+        }
+        Ok(encode_value(format, value, buffers[0].page_id))
+    }
+}
+
+// SyncBTree::searchが返すイテレータ。ページを借りたままにはしない
+pub struct SyncIter {
+    meta_page_id: PageId,
+    format: Format,
+    // 次に読むリーフを探すキー。Noneなら左端のリーフを読む
+    target: Option<Vec<u8>>,
+    // 返したペアの最後のキー。リーフを読み直すときは、これより後ろのペアだけを返す
+    last_key: Option<Vec<u8>>,
+    pairs: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl SyncIter {
+    #[allow(clippy::type_complexity)]
+    pub fn next(
+        &mut self,
+        bufmgr: &SyncBufferPoolManager,
+    ) -> Result<Option<(Vec<u8>, Vec<u8>)>, Error> {
+        loop {
+            if let Some((key, value)) = self.pairs.pop_front() {
+                let value = SyncBTree::load_value(bufmgr, self.format, &value)?;
+                return Ok(Some((key, value)));
+            }
+            if self.done {
+                return Ok(None);
+            }
+            let target = self.target.take();
+            let last_key = self.last_key.as_deref();
+            let btree = SyncBTree::new(self.meta_page_id);
+            let (pairs, upper) = btree.with_leaf(bufmgr, target.as_deref(), |leaf, upper| {
+                let start = match &target {
+                    Some(target) => leaf.search_slot_id(target, MEMCMP).unwrap_or_else(identity),
+                    None => 0,
+                };
+                let pairs: VecDeque<_> = (start..leaf.num_pairs())
+                    .map(|slot_id| leaf.pair_at(slot_id))
+                    .filter(|pair| last_key.map_or(true, |last_key| pair.key > last_key))
+                    .map(|pair| (pair.key.to_vec(), pair.value.to_vec()))
+                    .collect();
+                Ok((pairs, upper))
+            })?;
+            if let Some((key, _)) = pairs.back() {
+                self.last_key = Some(key.clone());
+            }
+            self.pairs = pairs;
+            self.done = upper.is_none();
+            self.target = upper;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::btree::BTree;
+    use crate::buffer::{self, BufferPool, BufferPoolManager, OnPoolFull};
+    use crate::disk::{DiskManager, PAGE_SIZE};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    fn value_of(key: u64) -> Vec<u8> {
+        vec![key as u8; 200]
+    }
+
+    #[test]
+    fn test_concurrent_insert_scan() {
+        assert_send_sync::<SyncBTree>();
+
+        const NUM_WRITERS: u64 = 4;
+        const NUM_READERS: usize = 2;
+        const KEYS_PER_WRITER: u64 = 1000;
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("heap");
+        let disk = DiskManager::create(&path).unwrap();
+        let bufmgr = Arc::new(SyncBufferPoolManager::new(disk, 64));
+        bufmgr.set_on_pool_full(OnPoolFull::Wait {
+            timeout: Duration::from_secs(10),
+        });
+        let btree = Arc::new(SyncBTree::create(&bufmgr).unwrap());
+
+        let writers: Vec<_> = (0..NUM_WRITERS)
+            .map(|t| {
+                let bufmgr = Arc::clone(&bufmgr);
+                let btree = Arc::clone(&btree);
+                thread::spawn(move || {
+                    // スレッドごとに重ならない範囲のキーを、交互に近い順で挿入する
+                    for i in 0..KEYS_PER_WRITER {
+                        let key = i * NUM_WRITERS + t;
+                        btree.insert(&bufmgr, &key.to_be_bytes(), &value_of(key)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..NUM_READERS)
+            .map(|_| {
+                let bufmgr = Arc::clone(&bufmgr);
+                let btree = Arc::clone(&btree);
+                thread::spawn(move || {
+                    let mut prev_count = 0;
+                    for _ in 0..20 {
+                        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+                        let mut prev_key = None;
+                        let mut count = 0;
+                        while let Some((key, value)) = iter.next(&bufmgr).unwrap() {
+                            assert!(prev_key.as_ref() < Some(&key));
+                            let n = u64::from_be_bytes(key[..].try_into().unwrap());
+                            assert_eq!(value_of(n), value);
+                            prev_key = Some(key);
+                            count += 1;
+                        }
+                        // 削除はしないので、前に見えたペアはすべて見える
+                        assert!(count >= prev_count);
+                        prev_count = count;
+                    }
+                })
+            })
+            .collect();
+        for handle in writers.into_iter().chain(readers) {
+            handle.join().unwrap();
+        }
+
+        let total = NUM_WRITERS * KEYS_PER_WRITER;
+        let mut iter = btree.search(&bufmgr, SearchMode::Start).unwrap();
+        let mut count = 0;
+        while let Some((key, _)) = iter.next(&bufmgr).unwrap() {
+            assert_eq!(count, u64::from_be_bytes(key[..].try_into().unwrap()));
+            count += 1;
+        }
+        assert_eq!(total, count);
+        assert_eq!(Some(value_of(7)), btree.get(&bufmgr, &7u64.to_be_bytes()).unwrap());
+        assert!(matches!(
+            btree.insert(&bufmgr, &7u64.to_be_bytes(), b"dup"),
+            Err(Error::DuplicateKey)
+        ));
+        bufmgr.flush().unwrap();
+        drop(bufmgr);
+
+        // 書き出した木はBTreeとしても読める
+        let disk = DiskManager::open(&path).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(64));
+        let btree = BTree::new(btree.meta_page_id);
+        btree.verify(&mut bufmgr).unwrap();
+        assert_eq!(total, btree.len(&mut bufmgr).unwrap());
+        let height = btree.stats(&mut bufmgr).unwrap().height;
+        assert!(height > 2);
+        assert_eq!(height, btree.meta(&mut bufmgr).unwrap().height);
+    }
+
+    #[test]
+    fn test_search() {
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::create(dir.path().join("heap")).unwrap();
+        let bufmgr = SyncBufferPoolManager::new(disk, 16);
+        let btree = SyncBTree::create(&bufmgr).unwrap();
+        let collect = |search_mode| {
+            let mut iter = btree.search(&bufmgr, search_mode).unwrap();
+            let mut keys = vec![];
+            while let Some((key, _)) = iter.next(&bufmgr).unwrap() {
+                keys.push(u64::from_be_bytes(key[..].try_into().unwrap()));
+            }
+            keys
+        };
+        assert!(collect(SearchMode::Start).is_empty());
+        let key = |n: u64| n.to_be_bytes().to_vec();
+        assert!(collect(SearchMode::KeyOrPrev(key(10))).is_empty());
+
+        for i in (10u64..200).step_by(10) {
+            btree.insert(&bufmgr, &key(i), &[0; 300]).unwrap();
+        }
+        // 大きな値はオーバーフローページに置く
+        btree.insert(&bufmgr, &key(5), &[1; 5000]).unwrap();
+        assert_eq!(Some(vec![1; 5000]), btree.get(&bufmgr, &key(5)).unwrap());
+        assert_eq!(None, btree.get(&bufmgr, &key(6)).unwrap());
+
+        assert_eq!(vec![190], collect(SearchMode::KeyOrNext(key(181))));
+        assert!(collect(SearchMode::KeyOrNext(key(191))).is_empty());
+        assert_eq!(20, collect(SearchMode::Start).len());
+        assert_eq!(19, collect(SearchMode::KeyOrPrev(key(10))).len());
+        assert_eq!(vec![180, 190], collect(SearchMode::KeyOrPrev(key(189))));
+        assert_eq!(vec![190], collect(SearchMode::KeyOrPrev(key(1000))));
+        assert!(collect(SearchMode::KeyOrPrev(key(4))).is_empty());
+        assert!(collect(SearchMode::End).is_empty());
+        // リーフの先頭より前のキーは、左隣のリーフの最後のペアから返す
+        for i in (10u64..200).step_by(10) {
+            assert_eq!(Some(&(i - 10).max(5)), collect(SearchMode::KeyOrPrev(key(i - 1))).first());
+        }
+    }
+
+    #[test]
+    fn test_overflow_without_free_buffer() {
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::create(dir.path().join("heap")).unwrap();
+        let bufmgr = SyncBufferPoolManager::new(disk, 4);
+        let btree = SyncBTree::create(&bufmgr).unwrap();
+        btree.insert(&bufmgr, b"small", b"value").unwrap();
+        // メタページと根のほかは1つしか借りられず、連なりの2ページ目を作れない
+        let meta_buffer = bufmgr.fetch_page(btree.meta_page_id).unwrap();
+        let root_page_id = meta::Meta::new(data(&read(&meta_buffer))).header.root_page_id;
+        let pinned = [
+            meta_buffer,
+            bufmgr.fetch_page(root_page_id).unwrap(),
+            bufmgr.create_page().unwrap(),
+        ];
+        let large = vec![1; 3 * PAGE_SIZE];
+        assert!(matches!(
+            btree.insert(&bufmgr, b"large", &large),
+            Err(Error::Buffer(buffer::Error::NoFreeBuffer { .. }))
+        ));
+        // 作りかけの連なりのページは解放したので、次に作るページで使う
+        let last_page_id = pinned[2].page_id;
+        drop(pinned);
+        assert_eq!(last_page_id.0 + 1, bufmgr.create_page().unwrap().page_id.0);
+        assert_eq!(None, btree.get(&bufmgr, b"large").unwrap());
+    }
+
+    #[test]
+    fn test_counted_insert_with_flush() {
+        // 数える木に挿入しながら、別のスレッドで書き出し続けても行き詰まらない
+        const NUM_WRITERS: u64 = 4;
+        const KEYS_PER_WRITER: u64 = 500;
+        let dir = tempdir().unwrap();
+        let disk = DiskManager::create(dir.path().join("heap")).unwrap();
+        let bufmgr = Arc::new(SyncBufferPoolManager::new(disk, 32));
+        bufmgr.set_on_pool_full(OnPoolFull::Wait {
+            timeout: Duration::from_secs(10),
+        });
+        let btree = Arc::new(SyncBTree::create(&bufmgr).unwrap());
+        {
+            let meta_buffer = bufmgr.fetch_page(btree.meta_page_id).unwrap();
+            let mut meta_page = write(&meta_buffer);
+            meta::Meta::new(data_mut(&mut meta_page)).set_num_entries(0);
+            bufmgr.mark_dirty(&meta_buffer);
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let flusher = {
+            let bufmgr = Arc::clone(&bufmgr);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                while !stop.load(Ordering::Acquire) {
+                    bufmgr.flush().unwrap();
+                }
+            })
+        };
+        let writers: Vec<_> = (0..NUM_WRITERS)
+            .map(|t| {
+                let bufmgr = Arc::clone(&bufmgr);
+                let btree = Arc::clone(&btree);
+                thread::spawn(move || {
+                    for i in 0..KEYS_PER_WRITER {
+                        let key = i * NUM_WRITERS + t;
+                        btree.insert(&bufmgr, &key.to_be_bytes(), &value_of(key)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in writers {
+            handle.join().unwrap();
+        }
+        stop.store(true, Ordering::Release);
+        flusher.join().unwrap();
+
+        let meta_buffer = bufmgr.fetch_page(btree.meta_page_id).unwrap();
+        let meta_page = read(&meta_buffer);
+        let meta = meta::Meta::new(data(&meta_page));
+        assert_eq!(Some(NUM_WRITERS * KEYS_PER_WRITER), meta.num_entries());
+        assert!(meta.height().unwrap() > 1);
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use super::sample::Rng;
+use super::{BTree, Error, VerifyError};
+use crate::buffer::BufferPoolManager;
+
+// 木に行う操作。ファジングの入力から作っても、random_opsで作ってもよい
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Insert(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    Get(Vec<u8>),
+    ScanRange(Bound<Vec<u8>>, Bound<Vec<u8>>),
+}
+
+// 再生で見つけた食い違い。indexは何番目の操作か
+#[derive(Debug, thiserror::Error)]
+pub enum ReplayError {
+    #[error("op {index} {op:?}: expected {expected}, got {actual}")]
+    Mismatch {
+        index: usize,
+        op: Op,
+        expected: String,
+        actual: String,
+    },
+    #[error("op {index} {op:?}: {source}")]
+    Tree {
+        index: usize,
+        op: Op,
+        source: Error,
+    },
+    #[error("after op {index}: {source}")]
+    Verify { index: usize, source: VerifyError },
+}
+
+// verifyを呼ぶ間隔の既定値
+pub const DEFAULT_VERIFY_EVERY: usize = 100;
+
+// 木と同じ操作をBTreeMapにも行い、読んだ結果を比べる
+// キーの重複を許さない木だけを扱う
+#[derive(Debug, Clone)]
+pub struct Replayer {
+    shadow: BTreeMap<Vec<u8>, Vec<u8>>,
+    // この数の操作ごとにverifyを呼ぶ。0なら最後にだけ呼ぶ
+    verify_every: usize,
+    applied: usize,
+}
+
+impl Replayer {
+    // 空の木に対して再生する
+    pub fn new(verify_every: usize) -> Self {
+        Self {
+            shadow: BTreeMap::new(),
+            verify_every,
+            applied: 0,
+        }
+    }
+
+    // 木に入っているはずのペア
+    pub fn shadow(&self) -> &BTreeMap<Vec<u8>, Vec<u8>> {
+        &self.shadow
+    }
+
+    // opsを順に再生し、最後にペアの数と木の形も確かめる。続けて呼べば続きから再生する
+    pub fn apply(
+        &mut self,
+        bufmgr: &mut BufferPoolManager,
+        btree: &BTree,
+        ops: &[Op],
+    ) -> Result<(), ReplayError> {
+        for op in ops {
+            self.apply_op(bufmgr, btree, op)?;
+            self.applied += 1;
+            if self.verify_every > 0 && self.applied % self.verify_every == 0 {
+                self.verify(bufmgr, btree)?;
+            }
+        }
+        self.verify(bufmgr, btree)?;
+        let index = self.applied;
+        let len = btree.len(bufmgr).map_err(|source| ReplayError::Verify {
+            index,
+            source: source.into(),
+        })?;
+        if len != self.shadow.len() as u64 {
+            return Err(ReplayError::Mismatch {
+                index,
+                op: Op::ScanRange(Bound::Unbounded, Bound::Unbounded),
+                expected: format!("len {}", self.shadow.len()),
+                actual: format!("len {}", len),
+            });
+        }
+        self.apply_op(bufmgr, btree, &Op::ScanRange(Bound::Unbounded, Bound::Unbounded))
+    }
+
+    fn apply_op(
+        &mut self,
+        bufmgr: &mut BufferPoolManager,
+        btree: &BTree,
+        op: &Op,
+    ) -> Result<(), ReplayError> {
+        let index = self.applied;
+        let tree_error = |source| ReplayError::Tree {
+            index,
+            op: op.clone(),
+            source,
+        };
+        let (expected, actual) = match op {
+            Op::Insert(key, value) => {
+                let result = btree.insert(bufmgr, key, value);
+                let exists = self.shadow.contains_key(key);
+                match result {
+                    Ok(()) if !exists => {
+                        self.shadow.insert(key.clone(), value.clone());
+                        return Ok(());
+                    }
+                    Err(Error::DuplicateKey) if exists => return Ok(()),
+                    Ok(()) => ("DuplicateKey".to_string(), "Ok".to_string()),
+                    Err(Error::DuplicateKey) => ("Ok".to_string(), "DuplicateKey".to_string()),
+                    Err(err) => return Err(tree_error(err)),
+                }
+            }
+            Op::Delete(key) => {
+                let deleted = btree.delete(bufmgr, key).map_err(tree_error)?;
+                let expected = self.shadow.remove(key).is_some();
+                (format!("{}", expected), format!("{}", deleted))
+            }
+            Op::Get(key) => {
+                let value = btree.get(bufmgr, key).map_err(tree_error)?;
+                (format!("{:?}", self.shadow.get(key)), format!("{:?}", value.as_ref()))
+            }
+            Op::ScanRange(start, end) => {
+                let range = (as_slice_bound(start), as_slice_bound(end));
+                let mut iter = btree.scan_range(bufmgr, range.0, range.1).map_err(tree_error)?;
+                let mut pairs = vec![];
+                while let Some(pair) = iter.next(bufmgr).map_err(tree_error)? {
+                    pairs.push(pair);
+                }
+                // BTreeMap::rangeは逆向きの範囲で止まるので、1つずつ範囲に入るか調べる
+                let expected: Vec<_> = self
+                    .shadow
+                    .iter()
+                    .filter(|(key, _)| in_range(key, range))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                (format!("{:?}", expected), format!("{:?}", pairs))
+            }
+        };
+        if expected == actual {
+            Ok(())
+        } else {
+            Err(ReplayError::Mismatch {
+                index,
+                op: op.clone(),
+                expected,
+                actual,
+            })
+        }
+    }
+
+    fn verify(&self, bufmgr: &mut BufferPoolManager, btree: &BTree) -> Result<(), ReplayError> {
+        btree.verify(bufmgr).map_err(|source| ReplayError::Verify {
+            index: self.applied,
+            source,
+        })
+    }
+}
+
+fn as_slice_bound(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn in_range(key: &[u8], (start, end): (Bound<&[u8]>, Bound<&[u8]>)) -> bool {
+    let after_start = match start {
+        Bound::Included(start) => key >= start,
+        Bound::Excluded(start) => key > start,
+        Bound::Unbounded => true,
+    };
+    let before_end = match end {
+        Bound::Included(end) => key <= end,
+        Bound::Excluded(end) => key < end,
+        Bound::Unbounded => true,
+    };
+    after_start && before_end
+}
+
+// 空の木にopsを再生し、DEFAULT_VERIFY_EVERYの操作ごとに木の形を確かめる
+pub fn apply_ops(
+    bufmgr: &mut BufferPoolManager,
+    btree: &BTree,
+    ops: &[Op],
+) -> Result<(), ReplayError> {
+    Replayer::new(DEFAULT_VERIFY_EVERY).apply(bufmgr, btree, ops)
+}
+
+// 同じシードからは同じ列を返す
+// 重複や削除が起きるよう、キーは少ない種類から選ぶ。値にはオーバーフローページに置く大きさも混ぜる
+pub fn random_ops(seed: u64, len: usize, page_size: usize) -> Vec<Op> {
+    let mut rng = Rng(seed);
+    let key = |rng: &mut Rng| {
+        let len = 1 + rng.below(4);
+        (0..len).map(|_| b'a' + rng.below(8) as u8).collect::<Vec<_>>()
+    };
+    (0..len)
+        .map(|i| match rng.below(10) {
+            0..=4 => {
+                let key = key(&mut rng);
+                let value_len = if rng.below(50) == 0 {
+                    page_size + rng.below(2 * page_size)
+                } else {
+                    rng.below(page_size / 8)
+                };
+                Op::Insert(key, vec![i as u8; value_len])
+            }
+            5..=6 => Op::Delete(key(&mut rng)),
+            7..=8 => Op::Get(key(&mut rng)),
+            _ => {
+                let bound = |rng: &mut Rng| match rng.below(3) {
+                    0 => Bound::Included(key(rng)),
+                    1 => Bound::Excluded(key(rng)),
+                    _ => Bound::Unbounded,
+                };
+                Op::ScanRange(bound(&mut rng), bound(&mut rng))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::MemoryDiskManager;
+
+    #[test]
+    fn test_replay() {
+        let page_size = 512;
+        let disk = MemoryDiskManager::with_page_size(page_size).unwrap();
+        let pool = BufferPool::new(16).with_page_size(page_size);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+
+        // 同じシードからは同じ操作の列を作る
+        let ops = random_ops(42, 5000, page_size);
+        assert_eq!(ops, random_ops(42, 5000, page_size));
+        assert_ne!(ops, random_ops(43, 5000, page_size));
+
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let mut replayer = Replayer::new(50);
+        replayer.apply(&mut bufmgr, &btree, &ops[..2500]).unwrap();
+        replayer.apply(&mut bufmgr, &btree, &ops[2500..]).unwrap();
+        assert!(!replayer.shadow().is_empty());
+        assert!(btree.meta(&mut bufmgr).unwrap().height >= 2);
+
+        // 木が影と食い違えば、何番目の操作かを返す
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        btree.insert(&mut bufmgr, b"a", b"stale").unwrap();
+        let ops = [Op::Get(b"b".to_vec()), Op::Get(b"a".to_vec())];
+        match apply_ops(&mut bufmgr, &btree, &ops) {
+            Err(ReplayError::Mismatch { index, .. }) => assert_eq!(1, index),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        let ops = [Op::Insert(b"a".to_vec(), vec![])];
+        assert!(matches!(
+            apply_ops(&mut bufmgr, &btree, &ops),
+            Err(ReplayError::Mismatch { index: 0, .. })
+        ));
+    }
+}
@@ -0,0 +1,383 @@
+use std::convert::TryInto;
+use std::mem::size_of;
+
+use super::{
+    leaf, meta, node, BTree, EntriesState, Error, Insertion, Iter, OpMetrics, WriteMode,
+    WriteOutcome,
+};
+use crate::buffer::{AccessStrategy, BufferPoolManager};
+
+// iter_rawで返すペア
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RawEntry {
+    Live(Vec<u8>, Vec<u8>),
+    // 削除したキーと、削除したときに墓標に記録した番号
+    Tombstone(Vec<u8>, u64),
+}
+
+// 墓標のペアの値から、記録した番号を読む
+pub(super) fn tombstone_seq(value: &[u8]) -> u64 {
+    u64::from_be_bytes(value[..size_of::<u64>()].try_into().unwrap())
+}
+
+impl BTree {
+    // 有効にすると、deleteはペアを取り除かずに値を墓標に置き換える
+    // 墓標のキーはget、search、iterなどからは見えず、insertすれば新しいペアとして加わる
+    // 無効に戻しても、残っている墓標はvacuum_tombstonesで取り除くまで読み飛ばす
+    pub fn set_tombstone_mode(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        enabled: bool,
+    ) -> Result<(), Error> {
+        let meta_buffer = bufmgr.fetch_page_write(self.meta_page_id)?;
+        let mut meta = meta::Meta::new(meta_buffer.data_mut());
+        self.check_meta(&meta)?;
+        if enabled && meta.is_multi() {
+            return Err(Error::UnsupportedOnMulti("tombstones"));
+        }
+        meta.set_tombstones(enabled);
+        Ok(())
+    }
+
+    // 次に削除したペアの墓標に記録する番号。削除するたびに1つ増える
+    // ここで得た番号をvacuum_tombstonesに渡せば、それより前に削除したペアの墓標だけを取り除く
+    pub fn tombstone_seq(&self, bufmgr: &mut BufferPoolManager) -> Result<u64, Error> {
+        let meta_buffer = bufmgr.fetch_page_read(self.meta_page_id)?;
+        let meta = meta::Meta::new(meta_buffer.data());
+        self.check_meta(&meta)?;
+        Ok(meta.header.tombstone_seq)
+    }
+
+    // 墓標も含めてすべてのペアを順に返すIterator
+    pub fn iter_raw<'a>(&self, bufmgr: &'a mut BufferPoolManager) -> RawEntries<'a> {
+        let state = match self.search_raw(bufmgr) {
+            Ok(iter) => EntriesState::Cursor(iter),
+            Err(err) => EntriesState::Failed(err),
+        };
+        RawEntries { bufmgr, state }
+    }
+
+    // searchは先頭の墓標を飛ばすので、左端のリーフまで自分でたどる
+    fn search_raw(&self, bufmgr: &mut BufferPoolManager) -> Result<Iter, Error> {
+        let (mut buffer, format) = self.fetch_root_page(bufmgr)?;
+        let mut branch_pages = 0;
+        loop {
+            let child_page_id = {
+                let node = node::Node::new(buffer.data());
+                match node::Body::new(node.header.node_type, node.body) {
+                    node::Body::Branch(branch) => branch.child_at(0),
+                    node::Body::Leaf(_) => break,
+                }
+            };
+            branch_pages += 1;
+            buffer = bufmgr.fetch_page_with_strategy(child_page_id, AccessStrategy::BulkRead)?;
+        }
+        let version = {
+            let node = node::Node::new(buffer.data());
+            leaf::Leaf::new(node.body).version()
+        };
+        let mut iter = Iter {
+            buffer,
+            slot_id: 0,
+            strategy: AccessStrategy::BulkRead,
+            format,
+            meta_page_id: self.meta_page_id,
+            version,
+            origin: Some(vec![]),
+            last: None,
+            last_key: None,
+            raw: true,
+            metrics: OpMetrics::default(),
+            shared_metrics: self.metrics.clone(),
+        };
+        iter.add_metrics(OpMetrics {
+            branch_pages,
+            leaf_pages: 1,
+            ..OpMetrics::default()
+        });
+        Ok(iter)
+    }
+
+    // あるキーの値を、次の番号を記録した墓標に置き換える。墓標のほうが大きければリーフを分割する
+    pub(super) fn write_tombstone(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        key: &[u8],
+    ) -> Result<bool, Error> {
+        let format = self.format(bufmgr)?;
+        let seq = self.tombstone_seq(bufmgr)?.to_be_bytes();
+        format.check_fits(key, &seq, (key.len(), 0))?;
+        let mut outcome = WriteOutcome::default();
+        let insertion = self.write_pair(bufmgr, key, &seq, WriteMode::Tombstone, &mut outcome)?;
+        if insertion == Insertion::NotFound {
+            return Ok(false);
+        }
+        let meta_buffer = bufmgr.fetch_page_write(self.meta_page_id)?;
+        meta::Meta::new(meta_buffer.data_mut()).header.tombstone_seq += 1;
+        Ok(true)
+    }
+}
+
+impl Iter {
+    // 墓標を飛ばさないIterで、次のペアか墓標を返す
+    fn next_raw(&mut self, bufmgr: &mut BufferPoolManager) -> Result<Option<RawEntry>, Error> {
+        self.revalidate(bufmgr)?;
+        self.seek_pair(bufmgr)?;
+        let tombstone = {
+            let leaf_node = node::Node::new(self.buffer.data());
+            let leaf = leaf::Leaf::new(leaf_node.body);
+            if self.slot_id < leaf.num_pairs() && leaf.is_tombstone(self.slot_id) {
+                let pair = leaf.pair_at(self.slot_id);
+                Some(RawEntry::Tombstone(pair.key.to_vec(), tombstone_seq(pair.value)))
+            } else {
+                None
+            }
+        };
+        match tombstone {
+            Some(entry) => {
+                if let RawEntry::Tombstone(key, _) = &entry {
+                    self.add_metrics(OpMetrics {
+                        entries: 1,
+                        bytes_copied: (key.len() + size_of::<u64>()) as u64,
+                        ..OpMetrics::default()
+                    });
+                }
+                self.slot_id += 1;
+                self.seek_pair(bufmgr)?;
+                Ok(Some(entry))
+            }
+            None => Ok(self.next(bufmgr)?.map(|(key, value)| RawEntry::Live(key, value))),
+        }
+    }
+}
+
+pub struct RawEntries<'a> {
+    bufmgr: &'a mut BufferPoolManager,
+    state: EntriesState,
+}
+
+impl<'a> Iterator for RawEntries<'a> {
+    type Item = Result<RawEntry, Error>;
+
+    // 読み込みに失敗したら、エラーを返してから終わる
+    fn next(&mut self) -> Option<Self::Item> {
+        match std::mem::replace(&mut self.state, EntriesState::Finished) {
+            EntriesState::Cursor(mut iter) => match iter.next_raw(self.bufmgr) {
+                Ok(Some(entry)) => {
+                    self.state = EntriesState::Cursor(iter);
+                    Some(Ok(entry))
+                }
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            },
+            EntriesState::Failed(err) => Some(Err(err)),
+            EntriesState::Finished => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::tests::{pin_free_buffers, tree_before_split};
+    use crate::btree::{BTreeOptions, KeyMode, SearchMode};
+    use crate::buffer::{self, BufferPool};
+    use crate::disk::MemoryDiskManager;
+
+    fn keys(bufmgr: &mut BufferPoolManager, btree: &BTree) -> Vec<u64> {
+        btree.iter(bufmgr).map(|pair| key_of(&pair.unwrap().0)).collect()
+    }
+
+    fn key_of(bytes: &[u8]) -> u64 {
+        u64::from_be_bytes(bytes.try_into().unwrap())
+    }
+
+    fn raw_entries(bufmgr: &mut BufferPoolManager, btree: &BTree) -> Vec<RawEntry> {
+        btree.iter_raw(bufmgr).map(Result::unwrap).collect()
+    }
+
+    fn tombstones(bufmgr: &mut BufferPoolManager, btree: &BTree) -> Vec<(u64, u64)> {
+        raw_entries(bufmgr, btree)
+            .into_iter()
+            .filter_map(|entry| match entry {
+                RawEntry::Tombstone(key, seq) => Some((key_of(&key), seq)),
+                RawEntry::Live(..) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        btree.set_tombstone_mode(&mut bufmgr, true).unwrap();
+        assert!(btree.meta(&mut bufmgr).unwrap().tombstones);
+        for i in 0u64..2000 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[1; 100]).unwrap();
+        }
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        // 偶数のキーを、前半と後半に分けて削除する
+        for i in (0u64..1000).step_by(2) {
+            assert!(btree.delete(&mut bufmgr, &i.to_be_bytes()).unwrap());
+        }
+        let cutoff = btree.tombstone_seq(&mut bufmgr).unwrap();
+        assert_eq!(500, cutoff);
+        for i in (1000u64..2000).step_by(2) {
+            assert!(btree.delete(&mut bufmgr, &i.to_be_bytes()).unwrap());
+        }
+        assert!(!btree.delete(&mut bufmgr, &0u64.to_be_bytes()).unwrap());
+        assert_eq!(1000, btree.tombstone_seq(&mut bufmgr).unwrap());
+        // 墓標はページに残るので、ページは増えない
+        assert_eq!(num_pages, bufmgr.storage_info().unwrap().num_pages);
+        btree.verify(&mut bufmgr).unwrap();
+
+        // 墓標はどの読み方でも見えない
+        let odds: Vec<_> = (1u64..2000).step_by(2).collect();
+        assert_eq!(odds, keys(&mut bufmgr, &btree));
+        assert_eq!(1000, btree.len(&mut bufmgr).unwrap());
+        assert_eq!(None, btree.get(&mut bufmgr, &10u64.to_be_bytes()).unwrap());
+        assert_eq!(1, key_of(&btree.first(&mut bufmgr).unwrap().unwrap().0));
+        let search = SearchMode::KeyOrNext(10u64.to_be_bytes().to_vec());
+        let mut iter = btree.search(&mut bufmgr, search).unwrap();
+        assert_eq!(11, key_of(&iter.next(&mut bufmgr).unwrap().unwrap().0));
+        let search = SearchMode::KeyOrPrev(10u64.to_be_bytes().to_vec());
+        let mut iter = btree.search_desc(&mut bufmgr, search).unwrap();
+        assert_eq!(9, key_of(&iter.next(&mut bufmgr).unwrap().unwrap().0));
+        assert_eq!(7, key_of(&iter.next(&mut bufmgr).unwrap().unwrap().0));
+        let stats = btree.stats(&mut bufmgr).unwrap();
+        assert_eq!((1000, 1000), (stats.num_entries, stats.tombstones));
+
+        // iter_rawは墓標と、削除した順の番号を返す
+        let entries = raw_entries(&mut bufmgr, &btree);
+        assert_eq!(2000, entries.len());
+        assert_eq!(RawEntry::Tombstone(0u64.to_be_bytes().to_vec(), 0), entries[0]);
+        assert_eq!(RawEntry::Live(1u64.to_be_bytes().to_vec(), vec![1; 100]), entries[1]);
+        let expected: Vec<_> = (0u64..2000).step_by(2).zip(0..).collect();
+        assert_eq!(expected, tombstones(&mut bufmgr, &btree));
+
+        // 墓標のキーには挿入し直せるが、置き換えはできない
+        btree.insert(&mut bufmgr, &0u64.to_be_bytes(), &[2; 100]).unwrap();
+        assert!(matches!(
+            btree.insert(&mut bufmgr, &0u64.to_be_bytes(), &[3; 100]),
+            Err(Error::DuplicateKey)
+        ));
+        assert_eq!(Some(vec![2; 100]), btree.get(&mut bufmgr, &0u64.to_be_bytes()).unwrap());
+        assert!(!btree.update(&mut bufmgr, &2u64.to_be_bytes(), &[2; 100]).unwrap());
+        assert_eq!(None, btree.upsert(&mut bufmgr, &4u64.to_be_bytes(), &[2; 100]).unwrap());
+        let mut entries = vec![(6u64.to_be_bytes().to_vec(), vec![2; 100])];
+        assert_eq!(1, btree.insert_batch(&mut bufmgr, &mut entries).unwrap().inserted);
+        assert_eq!(1003, btree.len(&mut bufmgr).unwrap());
+        assert_eq!(997, tombstones(&mut bufmgr, &btree).len());
+        btree.verify(&mut bufmgr).unwrap();
+
+        // cutoffより前に削除したペアの墓標だけを取り除く
+        let report = btree.vacuum(&mut bufmgr).unwrap();
+        assert_eq!((1003, 0, 997), (
+            report.entries_kept,
+            report.tombstones_purged,
+            report.tombstones_kept
+        ));
+        let report = btree.vacuum_tombstones(&mut bufmgr, cutoff).unwrap();
+        assert_eq!((1003, 497, 500), (
+            report.entries_kept,
+            report.tombstones_purged,
+            report.tombstones_kept
+        ));
+        let remaining = tombstones(&mut bufmgr, &btree);
+        assert!(remaining.iter().all(|&(key, seq)| key >= 1000 && seq >= cutoff));
+        let report = btree.vacuum_tombstones(&mut bufmgr, u64::MAX).unwrap();
+        assert_eq!((1003, 500, 0), (
+            report.entries_kept,
+            report.tombstones_purged,
+            report.tombstones_kept
+        ));
+        assert!(report.pages_freed > 0);
+        btree.verify(&mut bufmgr).unwrap();
+        let stats = btree.stats(&mut bufmgr).unwrap();
+        assert_eq!((1003, 0), (stats.num_entries, stats.tombstones));
+        assert_eq!(1003, raw_entries(&mut bufmgr, &btree).len());
+        assert_eq!(keys(&mut bufmgr, &btree).len() as u64, btree.len(&mut bufmgr).unwrap());
+
+        // 無効にすれば、deleteはペアを取り除く
+        btree.set_tombstone_mode(&mut bufmgr, false).unwrap();
+        assert!(btree.delete(&mut bufmgr, &1u64.to_be_bytes()).unwrap());
+        assert!(tombstones(&mut bufmgr, &btree).is_empty());
+        assert_eq!(1002, btree.len(&mut bufmgr).unwrap());
+    }
+
+    #[test]
+    fn test_insert_over_tombstone_without_free_buffer() {
+        let (mut bufmgr, btree) = tree_before_split();
+        btree.set_tombstone_mode(&mut bufmgr, true).unwrap();
+        let key = 3u64.to_be_bytes();
+        assert!(btree.delete(&mut bufmgr, &key).unwrap());
+        let seq = btree.tombstone_seq(&mut bufmgr).unwrap();
+        let pinned = pin_free_buffers(&mut bufmgr);
+        assert!(matches!(
+            btree.insert(&mut bufmgr, &key, &[2; 400]),
+            Err(Error::Buffer(buffer::Error::NoFreeBuffer { .. }))
+        ));
+        drop(pinned);
+
+        // 失敗しても墓標は番号もそのまま残り、キーは見えない
+        assert_eq!(vec![(3, 0)], tombstones(&mut bufmgr, &btree));
+        assert_eq!(seq, btree.tombstone_seq(&mut bufmgr).unwrap());
+        assert_eq!(None, btree.get(&mut bufmgr, &key).unwrap());
+        // 借りられるようになれば、墓標を置き換えて挿入できる
+        btree.insert(&mut bufmgr, &key, &[2; 400]).unwrap();
+        assert!(tombstones(&mut bufmgr, &btree).is_empty());
+    }
+
+    #[test]
+    fn test_tombstones_split() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        btree.set_tombstone_mode(&mut bufmgr, true).unwrap();
+        // 空の値より墓標のほうが大きいので、削除するとリーフがあふれて分割する
+        for i in 0u64..2000 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), b"").unwrap();
+        }
+        let leaf_pages = btree.stats(&mut bufmgr).unwrap().leaf_pages;
+        let mut iter = btree.search(&mut bufmgr, SearchMode::Start).unwrap();
+        let mut deleted = 0;
+        while let Some((key, _)) = iter.next(&mut bufmgr).unwrap() {
+            if key_of(&key) % 2 == 0 {
+                assert!(iter.delete_current(&mut bufmgr).unwrap());
+                deleted += 1;
+            }
+        }
+        assert_eq!(1000, deleted);
+        drop(iter);
+        for i in (1u64..2000).step_by(2) {
+            assert!(btree.delete(&mut bufmgr, &i.to_be_bytes()).unwrap());
+        }
+        btree.verify(&mut bufmgr).unwrap();
+        let stats = btree.stats(&mut bufmgr).unwrap();
+        assert!(stats.leaf_pages > leaf_pages);
+        assert_eq!((0, 2000), (stats.num_entries, stats.tombstones));
+        assert!(btree.is_empty(&mut bufmgr).unwrap());
+        assert!(keys(&mut bufmgr, &btree).is_empty());
+        assert_eq!(None, btree.last(&mut bufmgr).unwrap());
+        let tombstone_keys: Vec<_> =
+            tombstones(&mut bufmgr, &btree).into_iter().map(|(key, _)| key).collect();
+        assert_eq!((0u64..2000).collect::<Vec<_>>(), tombstone_keys);
+
+        let report = btree.vacuum_tombstones(&mut bufmgr, u64::MAX).unwrap();
+        assert_eq!((0, 2000), (report.entries_kept, report.tombstones_purged));
+        assert_eq!(1, btree.stats(&mut bufmgr).unwrap().leaf_pages);
+        assert!(raw_entries(&mut bufmgr, &btree).is_empty());
+        btree.verify(&mut bufmgr).unwrap();
+
+        let options = BTreeOptions {
+            key_mode: KeyMode::Multi,
+            ..BTreeOptions::default()
+        };
+        let multi = BTree::create_with(&mut bufmgr, options).unwrap();
+        assert!(matches!(
+            multi.set_tombstone_mode(&mut bufmgr, true),
+            Err(Error::UnsupportedOnMulti(_))
+        ));
+    }
+}
@@ -0,0 +1,184 @@
+use super::{branch, leaf, meta, node, tombstone, BTree, Error, KeyComparator};
+use crate::buffer::{BufferPoolManager, WriteGuard};
+
+// 掃除で解放したページの数と、残ったペアの数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VacuumReport {
+    pub pages_freed: u64,
+    // 墓標は数えない
+    pub entries_kept: u64,
+    pub tombstones_purged: u64,
+    pub tombstones_kept: u64,
+}
+
+impl BTree {
+    // 1つのページに収まる隣り合った子をまとめ、空いたページを解放する
+    // 空のリーフも隣とまとめて兄弟の連なりと親から外し、子が1つだけの根は取り除いて木を低くする
+    // 根のほかの枝は、まとめられなければ隣から借りて、子が1つだけにならないようにする
+    // 墓標はどれも取り除かずに残す
+    pub fn vacuum(&self, bufmgr: &mut BufferPoolManager) -> Result<VacuumReport, Error> {
+        self.vacuum_tombstones(bufmgr, 0)
+    }
+
+    // 番号がcutoffより小さい墓標を取り除いてから、vacuumと同じくページをまとめる
+    pub fn vacuum_tombstones(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        cutoff: u64,
+    ) -> Result<VacuumReport, Error> {
+        let meta_buffer = bufmgr.fetch_page_write(self.meta_page_id)?;
+        let root_page_id = {
+            let meta = meta::Meta::new(meta_buffer.data());
+            self.check_meta(&meta)?;
+            meta.header.root_page_id
+        };
+        let root_buffer = bufmgr.fetch_page_write(root_page_id)?;
+        let mut report = VacuumReport {
+            pages_freed: 0,
+            entries_kept: 0,
+            tombstones_purged: 0,
+            tombstones_kept: 0,
+        };
+        let cmp = self.format(bufmgr)?.cmp;
+        self.vacuum_node(bufmgr, root_buffer, cutoff, cmp, &mut report)?;
+        report.pages_freed += self.collapse_root(bufmgr, &meta_buffer)?;
+        Ok(report)
+    }
+
+    // 子を先に掃除してから、その子どうしをまとめる
+    fn vacuum_node(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        buffer: WriteGuard,
+        cutoff: u64,
+        cmp: KeyComparator,
+        report: &mut VacuumReport,
+    ) -> Result<(), Error> {
+        let node = node::Node::new(buffer.data_mut());
+        if node.header.node_type == node::NODE_TYPE_LEAF {
+            let mut leaf = leaf::Leaf::new(node.body);
+            for slot_id in (0..leaf.num_pairs()).rev() {
+                if !leaf.is_tombstone(slot_id) {
+                    report.entries_kept += 1;
+                } else if tombstone::tombstone_seq(leaf.pair_at(slot_id).value) < cutoff {
+                    leaf.remove(slot_id);
+                    report.tombstones_purged += 1;
+                } else {
+                    report.tombstones_kept += 1;
+                }
+            }
+            return Ok(());
+        }
+        let mut branch = branch::Branch::new(node.body);
+        for child_idx in 0..=branch.num_pairs() {
+            let child_buffer = bufmgr.fetch_page_write(branch.child_at(child_idx))?;
+            self.vacuum_node(bufmgr, child_buffer, cutoff, cmp, report)?;
+        }
+        // まとめられない子は隣から借りて、子が1つだけの枝を残さない
+        let mut left_idx = 0;
+        while left_idx < branch.num_pairs() {
+            if self.merge_siblings(bufmgr, &mut branch, left_idx, Some(cmp))? {
+                report.pages_freed += 1;
+            } else {
+                left_idx += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::BulkLoadOptions;
+    use crate::buffer::BufferPool;
+    use crate::disk::{MemoryDiskManager, PageId};
+
+    fn scan(bufmgr: &mut BufferPoolManager, btree: &BTree) -> Vec<(Vec<u8>, Vec<u8>)> {
+        btree.iter(bufmgr).collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = BTree::create(&mut bufmgr).unwrap();
+        let report = btree.vacuum(&mut bufmgr).unwrap();
+        assert_eq!((0, 0), (report.pages_freed, report.entries_kept));
+
+        for i in 0u64..5000 {
+            btree.insert(&mut bufmgr, &i.to_be_bytes(), &[i as u8; 100]).unwrap();
+        }
+        for i in (0u64..5000).filter(|i| i % 10 != 0) {
+            assert!(btree.delete(&mut bufmgr, &i.to_be_bytes()).unwrap());
+        }
+        let pairs = scan(&mut bufmgr, &btree);
+        let num_pages = bufmgr.storage_info().unwrap().num_pages;
+        let height = btree.stats(&mut bufmgr).unwrap().height;
+
+        let report = btree.vacuum(&mut bufmgr).unwrap();
+        assert!(report.pages_freed > 0);
+        assert_eq!(500, report.entries_kept);
+        assert_eq!(
+            num_pages - report.pages_freed,
+            bufmgr.storage_info().unwrap().num_pages
+        );
+        btree.verify(&mut bufmgr).unwrap();
+        assert!(btree.stats(&mut bufmgr).unwrap().height <= height);
+        assert_eq!(pairs, scan(&mut bufmgr, &btree));
+        assert_eq!(500, btree.len(&mut bufmgr).unwrap());
+
+        // 掃除したあとも挿入と削除ができる
+        for i in 0u64..500 {
+            btree.insert(&mut bufmgr, &(i * 10 + 1).to_be_bytes(), b"value").unwrap();
+        }
+        for i in 0u64..500 {
+            assert!(btree.delete(&mut bufmgr, &(i * 10).to_be_bytes()).unwrap());
+        }
+        btree.verify(&mut bufmgr).unwrap();
+        assert_eq!(500, btree.len(&mut bufmgr).unwrap());
+    }
+
+    // 根のほかで、子が1つしかない枝の数
+    fn single_child_branches(bufmgr: &mut BufferPoolManager, page_id: PageId, root: bool) -> usize {
+        let children: Vec<_> = {
+            let buffer = bufmgr.fetch_page_read(page_id).unwrap();
+            let node = node::Node::new(buffer.data());
+            if node.header.node_type == node::NODE_TYPE_LEAF {
+                return 0;
+            }
+            let branch = branch::Branch::new(node.body);
+            (0..=branch.num_pairs()).map(|child_idx| branch.child_at(child_idx)).collect()
+        };
+        let own = (!root && children.len() == 1) as usize;
+        own + children
+            .into_iter()
+            .map(|child| single_child_branches(bufmgr, child, false))
+            .sum::<usize>()
+    }
+
+    #[test]
+    fn test_vacuum_single_child_branches() {
+        let disk = MemoryDiskManager::with_page_size(256).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(16).with_page_size(256));
+        // 枝を詰めておき、子が1つになった枝を隣にまとめられないようにする
+        let options = BulkLoadOptions {
+            fill_factor: 1.0,
+            ..BulkLoadOptions::default()
+        };
+        let pairs = (0u64..20000).map(|i| (i.to_be_bytes().to_vec(), vec![]));
+        let btree = BTree::bulk_load_with(&mut bufmgr, pairs, options).unwrap();
+        btree.set_tombstone_mode(&mut bufmgr, true).unwrap();
+        for i in (8000u64..9600).filter(|i| i % 97 != 0) {
+            assert!(btree.delete(&mut bufmgr, &i.to_be_bytes()).unwrap());
+        }
+        let pairs = scan(&mut bufmgr, &btree);
+        let seq = btree.tombstone_seq(&mut bufmgr).unwrap();
+        let report = btree.vacuum_tombstones(&mut bufmgr, seq).unwrap();
+        assert_eq!(0, report.tombstones_kept);
+        btree.verify(&mut bufmgr).unwrap();
+        assert_eq!(pairs, scan(&mut bufmgr, &btree));
+        let root_page_id = btree.meta(&mut bufmgr).unwrap().root_page_id;
+        assert_eq!(0, single_child_branches(&mut bufmgr, root_page_id, true));
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::HashSet;
+
+use super::{meta, node, BTree, Error, KeyComparator};
+use crate::buffer::BufferPoolManager;
+use crate::disk::PageId;
+
+// 木が守るべき決まりのうち、破られていたもの
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum Violation {
+    #[error("unknown node type {0:02x?}")]
+    UnknownNodeType([u8; 8]),
+    #[error("broken slots: {0}")]
+    BrokenSlots(String),
+    #[error("key at slot {0} is not greater than the previous key")]
+    UnsortedKeys(usize),
+    #[error("key at slot {0} is out of the bounds given by the parent")]
+    KeyOutOfBounds(usize),
+    #[error("leaf is at depth {depth} but other leaves are at depth {expected}")]
+    UnevenDepth { depth: usize, expected: usize },
+    #[error("page is reachable from more than one parent")]
+    SharedPage,
+    #[error("{link} sibling is {actual:?} but the leaf order says {expected:?}")]
+    BrokenSiblingChain {
+        link: &'static str,
+        expected: Option<PageId>,
+        actual: Option<PageId>,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    #[error("page {page_id:?}: {violation}")]
+    Corrupted { page_id: PageId, violation: Violation },
+    #[error(transparent)]
+    Tree(#[from] Error),
+}
+
+fn corrupted(page_id: PageId, violation: Violation) -> VerifyError {
+    VerifyError::Corrupted { page_id, violation }
+}
+
+// 根からたどったノードの決まりを確かめながら、リーフを順に集める
+struct Verifier {
+    cmp: KeyComparator,
+    visited: HashSet<PageId>,
+    leaf_depth: Option<usize>,
+    leaves: Vec<(PageId, Option<PageId>, Option<PageId>)>,
+}
+
+impl Verifier {
+    // キーがlower以上upper未満に収まっているか確かめる
+    fn verify_node(
+        &mut self,
+        bufmgr: &mut BufferPoolManager,
+        page_id: PageId,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+        depth: usize,
+    ) -> Result<(), VerifyError> {
+        if !self.visited.insert(page_id) {
+            return Err(corrupted(page_id, Violation::SharedPage));
+        }
+        let cmp = self.cmp;
+        let in_bounds = |key: &[u8]| {
+            lower.map_or(true, |lower| !cmp.lt(key, lower))
+                && upper.map_or(true, |upper| cmp.lt(key, upper))
+        };
+        let children = {
+            let buffer = bufmgr.fetch_page_read(page_id).map_err(Error::from)?;
+            let node = node::Node::new(buffer.data());
+            let node_type = node.header.node_type;
+            if node_type != node::NODE_TYPE_LEAF && node_type != node::NODE_TYPE_BRANCH {
+                return Err(corrupted(page_id, Violation::UnknownNodeType(node_type)));
+            }
+            let body = node::Body::new(node_type, node.body);
+            match body {
+                node::Body::Leaf(leaf) => {
+                    leaf.check()
+                        .map_err(|err| corrupted(page_id, Violation::BrokenSlots(err)))?;
+                    for slot_id in 0..leaf.num_pairs() {
+                        let key = leaf.pair_at(slot_id).key;
+                        if slot_id > 0 && !cmp.lt(leaf.pair_at(slot_id - 1).key, key) {
+                            return Err(corrupted(page_id, Violation::UnsortedKeys(slot_id)));
+                        }
+                        if !in_bounds(key) {
+                            return Err(corrupted(page_id, Violation::KeyOutOfBounds(slot_id)));
+                        }
+                    }
+                    let expected = *self.leaf_depth.get_or_insert(depth);
+                    if depth != expected {
+                        return Err(corrupted(page_id, Violation::UnevenDepth { depth, expected }));
+                    }
+                    self.leaves.push((page_id, leaf.prev_page_id(), leaf.next_page_id()));
+                    return Ok(());
+                }
+                node::Body::Branch(branch) => {
+                    branch.check()
+                        .map_err(|err| corrupted(page_id, Violation::BrokenSlots(err)))?;
+                    let mut keys = vec![];
+                    for slot_id in 0..branch.num_pairs() {
+                        let key = branch.pair_at(slot_id).key;
+                        if keys.last().is_some_and(|prev: &Vec<u8>| !cmp.lt(prev, key)) {
+                            return Err(corrupted(page_id, Violation::UnsortedKeys(slot_id)));
+                        }
+                        if !in_bounds(key) {
+                            return Err(corrupted(page_id, Violation::KeyOutOfBounds(slot_id)));
+                        }
+                        keys.push(key.to_vec());
+                    }
+                    let children: Vec<_> =
+                        (0..=branch.num_pairs()).map(|i| branch.child_at(i)).collect();
+                    (keys, children)
+                }
+            }
+        };
+        let (keys, children) = children;
+        for (i, &child) in children.iter().enumerate() {
+            // i番目の子のキーは、i-1番目のキー以上、i番目のキー未満
+            let child_lower = if i == 0 { lower } else { Some(&keys[i - 1][..]) };
+            let child_upper = keys.get(i).map(|key| &key[..]).or(upper);
+            self.verify_node(bufmgr, child, child_lower, child_upper, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    // 隣のリーフへのページIDが、根からたどった順と一致するか確かめる
+    fn verify_chain(&self) -> Result<(), VerifyError> {
+        for (i, &(page_id, prev_page_id, next_page_id)) in self.leaves.iter().enumerate() {
+            let expected_prev = i.checked_sub(1).map(|prev| self.leaves[prev].0);
+            let expected_next = self.leaves.get(i + 1).map(|leaf| leaf.0);
+            for (link, expected, actual) in [
+                ("prev", expected_prev, prev_page_id),
+                ("next", expected_next, next_page_id),
+            ] {
+                if expected != actual {
+                    let violation = Violation::BrokenSiblingChain {
+                        link,
+                        expected,
+                        actual,
+                    };
+                    return Err(corrupted(page_id, violation));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BTree {
+    // 木の構造が正しいか確かめ、壊れていればそのページと破られていた決まりを返す
+    // スロットを読む前にその食い違いを確かめるので、壊れたページでもpanicしない
+    pub fn verify(&self, bufmgr: &mut BufferPoolManager) -> Result<(), VerifyError> {
+        let root_page_id = {
+            let meta_buffer = bufmgr.fetch_page_read(self.meta_page_id).map_err(Error::from)?;
+            let meta = meta::Meta::new(meta_buffer.data());
+            self.check_meta(&meta)?;
+            meta.header.root_page_id
+        };
+        let mut verifier = Verifier {
+            cmp: self.format(bufmgr)?.cmp,
+            visited: HashSet::new(),
+            leaf_depth: None,
+            leaves: vec![],
+        };
+        verifier.verify_node(bufmgr, root_page_id, None, None, 0)?;
+        verifier.verify_chain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use zerocopy::AsBytes;
+
+    use super::super::leaf;
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::MemoryDiskManager;
+
+    fn build(bufmgr: &mut BufferPoolManager) -> BTree {
+        let btree = BTree::create(bufmgr).unwrap();
+        for i in 0u64..2000 {
+            btree.insert(bufmgr, &i.to_be_bytes(), &[i as u8; 32]).unwrap();
+        }
+        btree.verify(bufmgr).unwrap();
+        btree
+    }
+
+    // 根から最初のリーフまで、たどったページIDを返す
+    fn leftmost_path(bufmgr: &mut BufferPoolManager, btree: &BTree) -> Vec<PageId> {
+        let mut page_id = {
+            let meta_buffer = bufmgr.fetch_page_read(btree.meta_page_id).unwrap();
+            let meta = meta::Meta::new(meta_buffer.data());
+            meta.header.root_page_id
+        };
+        let mut path = vec![page_id];
+        loop {
+            let buffer = bufmgr.fetch_page_read(page_id).unwrap();
+            let node = node::Node::new(buffer.data());
+            let body = node::Body::new(node.header.node_type, node.body);
+            match body {
+                node::Body::Leaf(_) => return path,
+                node::Body::Branch(branch) => page_id = branch.child_at(0),
+            }
+            path.push(page_id);
+        }
+    }
+
+    fn find_violation(bufmgr: &mut BufferPoolManager, btree: &BTree) -> (PageId, Violation) {
+        match btree.verify(bufmgr) {
+            Err(VerifyError::Corrupted { page_id, violation }) => (page_id, violation),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_verify_corrupted() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = build(&mut bufmgr);
+        let path = leftmost_path(&mut bufmgr, &btree);
+        assert!(path.len() >= 2);
+        let leaf_page_id = *path.last().unwrap();
+
+        // 最初のキーの先頭のバイトを書き換えて、親の区切りのキーより大きくする
+        let first_key_offset = {
+            let buffer = bufmgr.fetch_page_read(leaf_page_id).unwrap();
+            let node = node::Node::new(buffer.data());
+            let leaf = leaf::Leaf::new(node.body);
+            let offset = leaf.pair_at(0).key.as_ptr() as usize - buffer.data().as_ptr() as usize;
+            offset
+        };
+        {
+            let buffer = bufmgr.fetch_page_write(leaf_page_id).unwrap();
+            buffer.data_mut()[first_key_offset] = 0xFF;
+        }
+        let (page_id, violation) = find_violation(&mut bufmgr, &btree);
+        assert_eq!(leaf_page_id, page_id);
+        assert_eq!(Violation::KeyOutOfBounds(0), violation);
+        {
+            let buffer = bufmgr.fetch_page_write(leaf_page_id).unwrap();
+            buffer.data_mut()[first_key_offset] = 0;
+        }
+        btree.verify(&mut bufmgr).unwrap();
+
+        // 2番めのキー1の末尾のバイトを書き換えて、最初のキー0と同じにする
+        let second_key_end = {
+            let buffer = bufmgr.fetch_page_read(leaf_page_id).unwrap();
+            let node = node::Node::new(buffer.data());
+            let leaf = leaf::Leaf::new(node.body);
+            let key = leaf.pair_at(1).key;
+            assert_eq!(&1u64.to_be_bytes(), key);
+            let offset = key.as_ptr() as usize - buffer.data().as_ptr() as usize + key.len() - 1;
+            offset
+        };
+        {
+            let buffer = bufmgr.fetch_page_write(leaf_page_id).unwrap();
+            buffer.data_mut()[second_key_end] = 0;
+        }
+        assert_eq!(
+            (leaf_page_id, Violation::UnsortedKeys(1)),
+            find_violation(&mut bufmgr, &btree)
+        );
+        {
+            let buffer = bufmgr.fetch_page_write(leaf_page_id).unwrap();
+            buffer.data_mut()[second_key_end] = 1;
+        }
+
+        // スロットのヘッダを壊すと、ペアを読む前に見つかる
+        let slotted_offset = size_of::<node::Header>() + size_of::<leaf::Header>();
+        {
+            let buffer = bufmgr.fetch_page_write(leaf_page_id).unwrap();
+            buffer.data_mut()[slotted_offset + 2] ^= 0x10;
+        }
+        let (page_id, violation) = find_violation(&mut bufmgr, &btree);
+        assert_eq!(leaf_page_id, page_id);
+        assert!(matches!(violation, Violation::BrokenSlots(_)), "{:?}", violation);
+        {
+            let buffer = bufmgr.fetch_page_write(leaf_page_id).unwrap();
+            buffer.data_mut()[slotted_offset + 2] ^= 0x10;
+        }
+
+        // 隣のリーフへのページIDを壊す
+        {
+            let buffer = bufmgr.fetch_page_write(leaf_page_id).unwrap();
+            let node = node::Node::new(buffer.data_mut());
+            leaf::Leaf::new(node.body).set_next_page_id(None);
+        }
+        let (page_id, violation) = find_violation(&mut bufmgr, &btree);
+        assert_eq!(leaf_page_id, page_id);
+        assert!(matches!(violation, Violation::BrokenSiblingChain { link: "next", .. }));
+
+        // ノードの種類を壊す
+        {
+            let buffer = bufmgr.fetch_page_write(leaf_page_id).unwrap();
+            buffer.data_mut()[0] = b'X';
+        }
+        let (page_id, violation) = find_violation(&mut bufmgr, &btree);
+        assert_eq!(leaf_page_id, page_id);
+        assert!(matches!(violation, Violation::UnknownNodeType(_)));
+    }
+
+    #[test]
+    fn test_verify_shared_page() {
+        let disk = MemoryDiskManager::new();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(10));
+        let btree = build(&mut bufmgr);
+        // 根の最後の子を根そのものにして、循環させる
+        let root_page_id = leftmost_path(&mut bufmgr, &btree)[0];
+        {
+            let buffer = bufmgr.fetch_page_write(root_page_id).unwrap();
+            let right_child_offset = size_of::<node::Header>();
+            buffer.data_mut()[right_child_offset..right_child_offset + size_of::<PageId>()]
+                .copy_from_slice(root_page_id.as_bytes());
+        }
+        assert_eq!(
+            (root_page_id, Violation::SharedPage),
+            find_violation(&mut bufmgr, &btree)
+        );
+    }
+}
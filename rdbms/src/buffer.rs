@@ -1,102 +1,413 @@
-use std::cell::{Cell, RefCell};
-use std::collections::HashMap;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::io;
-use std::ops::{Index, IndexMut};
+use std::ops::{AddAssign, Deref, Index, IndexMut};
 use std::rc::Rc;
+use std::time::Duration;
 
-use crate::disk::{DiskManager, PageId, PAGE_SIZE};
+use crate::checksum::CHECKSUM_SIZE;
+use crate::disk::{
+    self, AlignedBuffer, ChecksumMismatch, PageId, Storage, SyncMode, TablespaceId, PAGE_SIZE,
+};
+
+mod backup;
+mod dump;
+mod guard;
+mod leak;
+mod partitioned;
+mod policy;
+mod sync;
+
+use backup::BackupTracker;
+pub use dump::{FrameInfo, FrameTable};
+pub use guard::{ReadGuard, WriteGuard};
+#[cfg(any(test, feature = "testing"))]
+pub use leak::MockClock;
+pub use leak::{Clock, PinInfo, SystemClock};
+use leak::{LeakDetector, PinRecord};
+pub use partitioned::PartitionedBufferPoolManager;
+pub use policy::{ClockSweep, EvictionPolicy, Lru, DEFAULT_MAX_USAGE_COUNT, STICKY_PASSES};
+pub use self::sync::{
+    BackgroundWriter, BackgroundWriterStats, SyncBuffer, SyncBufferPoolManager,
+    BACKGROUND_WRITER_MAX_PAGES,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    #[error("failed to {op} {}: {source}", page_label(*.page_id))]
+    Io {
+        page_id: PageId,    // SyncのときはINVALID_PAGE_ID
+        op: IoOp,
+        source: io::Error,
+    },
+    // リーク検出が有効なら、もっとも長くピン留めされているページを添える
+    #[error("no free buffer available in buffer pool{}", oldest_pin_message(.oldest_pin))]
+    NoFreeBuffer { oldest_pin: Option<PageId> },
+    #[error("buffer for page {0:?} is pinned")]
+    PinnedBuffer(PageId),
+    #[error("checksum mismatch on page {page_id:?}")]
+    ChecksumMismatch { page_id: PageId },
     #[error(transparent)]
-    Io(#[from] io::Error),
-    #[error("no free buffer available in buffer pool")]
-    NoFreeBuffer,
+    Disk(#[from] disk::Error),
+}
+
+// 失敗したディスク操作
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IoOp {
+    Read,
+    Write,
+    Sync,
+    Allocate,
+    Free,
+    Stat,
+    Backup,
+    Compact,
+}
+
+impl fmt::Display for IoOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            IoOp::Read => "read",
+            IoOp::Write => "write",
+            IoOp::Sync => "sync",
+            IoOp::Allocate => "allocate a page in",
+            IoOp::Free => "free",
+            IoOp::Stat => "stat",
+            IoOp::Backup => "back up",
+            IoOp::Compact => "compact",
+        };
+        f.write_str(op)
+    }
+}
+
+fn page_label(page_id: PageId) -> String {
+    match page_id.valid() {
+        Some(page_id) => format!("page {}", page_id.to_u64()),
+        None => "heap file".to_string(),
+    }
+}
+
+fn oldest_pin_message(oldest_pin: &Option<PageId>) -> String {
+    match oldest_pin {
+        Some(page_id) => format!(" (oldest pin: page {})", page_id.to_u64()),
+        None => String::new(),
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct BufferId(pub usize);
+
+// ページの大きさはストレージごとに決まるので、実行時に確保する
+// O_DIRECTでもそのまま読み書きできるよう、先頭を揃えておく
+pub type Page = AlignedBuffer;
+
+// デフォルトのページサイズのとき、末尾のチェックサムを除いた利用できる領域のサイズ
+pub const PAGE_DATA_SIZE: usize = PAGE_SIZE - CHECKSUM_SIZE;
+
+// ゼロ埋めしたページを確保する
+fn new_page(page_size: usize) -> Page {
+    AlignedBuffer::new(page_size)
+}
+
+// ページを書き出す。チェックサムはストレージ側で付ける
+fn write_page(disk: &mut dyn Storage, page_id: PageId, page: &[u8]) -> Result<(), Error> {
+    disk.write_page_data(page_id, page).map_err(|source| Error::Io {
+        page_id,
+        op: IoOp::Write,
+        source,
+    })
+}
+
+// ページを読み出す。チェックサムが合わなければストレージがエラーを返す
+fn read_page(disk: &mut dyn Storage, page_id: PageId, page: &mut [u8]) -> Result<(), Error> {
+    disk.read_page_data(page_id, page).map_err(|source| {
+        if source.get_ref().is_some_and(|err| err.is::<ChecksumMismatch>()) {
+            Error::ChecksumMismatch { page_id }
+        } else if source.get_ref().is_some_and(|err| err.is::<disk::Error>()) {
+            Error::Disk(source.into())
+        } else {
+            Error::Io {
+                page_id,
+                op: IoOp::Read,
+                source,
+            }
+        }
+    })
 }
 
-#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Hash)]
-pub struct BufferId(usize);
+fn allocate_page(disk: &mut dyn Storage, tablespace_id: TablespaceId) -> Result<PageId, Error> {
+    disk.allocate_page_in(tablespace_id).map_err(|source| Error::Io {
+        page_id: PageId::INVALID_PAGE_ID,
+        op: IoOp::Allocate,
+        source,
+    })
+}
 
-pub type Page = [u8; PAGE_SIZE];
+fn sync_disk(disk: &mut dyn Storage, mode: SyncMode) -> Result<(), Error> {
+    let result = match mode {
+        SyncMode::All => disk.sync(),
+        SyncMode::Data => disk.sync_data(),
+    };
+    result.map_err(|source| Error::Io {
+        page_id: PageId::INVALID_PAGE_ID,
+        op: IoOp::Sync,
+        source,
+    })
+}
 
 // バッファ
 #[derive(Debug)]
 pub struct Buffer {
     pub page_id: PageId,
     pub page: RefCell<Page>,
-    pub is_dirty: Cell<bool>,
+    is_dirty: Cell<bool>,       // BufferPoolManager::mark_dirtyで立てる
+    pin_count: Cell<usize>,     // 貸し出しているPinnedBufferの数
+    pins: RefCell<Vec<PinRecord>>,  // リーク検出が有効なときのピン留めの記録
 }
 
-impl Default for Buffer {
-    fn default() -> Self {
+impl Buffer {
+    pub fn pin_count(&self) -> usize {
+        self.pin_count.get()
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.is_dirty.get()
+    }
+
+    // チェックサムを除いたページの中身
+    pub fn data(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.page.borrow(), |page| {
+            let len = page.len() - CHECKSUM_SIZE;
+            &page[..len]
+        })
+    }
+
+    pub fn data_mut(&self) -> RefMut<'_, [u8]> {
+        RefMut::map(self.page.borrow_mut(), |page| {
+            let len = page.len() - CHECKSUM_SIZE;
+            &mut page[..len]
+        })
+    }
+
+    // ページの中身のコピー
+    pub fn snapshot(&self) -> Page {
+        self.page.borrow().clone()
+    }
+
+    fn new(page_size: usize) -> Self {
         Self {
             page_id: Default::default(),
-            page: RefCell::new([0u8; PAGE_SIZE]),
+            page: RefCell::new(new_page(page_size)),
             is_dirty: Cell::new(false),
+            pin_count: Cell::new(0),
+            pins: RefCell::new(vec![]),
+        }
+    }
+
+    // ページを持たない、ゼロ埋めした状態に戻す
+    fn reset(&mut self) {
+        self.page_id = PageId::INVALID_PAGE_ID;
+        self.page.get_mut().fill(0);
+        self.is_dirty.set(false);
+        self.pin_count.set(0);
+        self.pins.get_mut().clear();
+    }
+}
+
+// 貸し出したバッファ
+// 保持している間はバッファがピン留めされ、dropするとピン留めが外れる
+#[derive(Debug)]
+pub struct PinnedBuffer {
+    buffer: Rc<Buffer>,
+    pin_id: Option<u64>,
+}
+
+impl PinnedBuffer {
+    fn new(buffer: &Rc<Buffer>, record: Option<PinRecord>) -> Self {
+        buffer.pin_count.set(buffer.pin_count.get() + 1);
+        let pin_id = record.map(|record| {
+            let pin_id = record.pin_id;
+            buffer.pins.borrow_mut().push(record);
+            pin_id
+        });
+        Self {
+            buffer: Rc::clone(buffer),
+            pin_id,
+        }
+    }
+
+    // 明示的にピン留めを外す。selfを消費するので二重に外すことはできない
+    pub fn unpin(self) {}
+}
+
+impl Deref for PinnedBuffer {
+    type Target = Buffer;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buffer
+    }
+}
+
+impl Drop for PinnedBuffer {
+    fn drop(&mut self) {
+        self.buffer.pin_count.set(self.buffer.pin_count.get() - 1);
+        if let Some(pin_id) = self.pin_id {
+            self.buffer
+                .pins
+                .borrow_mut()
+                .retain(|record| record.pin_id != pin_id);
         }
     }
 }
 
 // フレーム
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Frame {
     usage_count: u64,       // usage_count: バッファの利用回数
     buffer: Rc<Buffer>,
+    priority: Priority,
+    sticky_passes: u8,      // Stickyなフレームを、usage_countが0の状態で素通りした回数
+    last_access: u64,       // 最後に利用されたときのBufferPool全体での通し番号
+}
+
+impl Frame {
+    fn new(page_size: usize) -> Self {
+        Self {
+            usage_count: 0,
+            buffer: Rc::new(Buffer::new(page_size)),
+            priority: Priority::Normal,
+            sticky_passes: 0,
+            last_access: 0,
+        }
+    }
+}
+
+// フレームの追い出されにくさ
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum Priority {
+    #[default]
+    Normal,
+    // BTreeのメタページなど、毎回参照されるページ向け
+    // Clock-sweepで2周余分に見逃されてから追い出される
+    Sticky,
+}
+
+impl Frame {
+    pub fn usage_count(&self) -> u64 {
+        self.usage_count
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn last_access(&self) -> u64 {
+        self.last_access
+    }
+
+    // バッファが貸出中かどうか
+    pub fn is_pinned(&self) -> bool {
+        self.buffer.pin_count.get() > 0
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.buffer.is_dirty.get()
+    }
 }
 
 // バッファプール
 #[derive(Debug)]
 pub struct BufferPool {
     buffers: Vec<Frame>,
-    next_victim_id: BufferId,
+    policy: Box<dyn EvictionPolicy>,
+    access_seq: u64,    // バッファが利用されるたびに増える通し番号
+    // 一度もページを保持していない(あるいは無効になった)フレーム
+    // 空いているうちはClock-sweepを回さずにここから使う
+    free_list: Vec<BufferId>,
+    sweeps: u64,        // EvictionPolicyに捨てるバッファを選ばせた回数
+    page_size: usize,   // 各フレームが持つページの大きさ
 }
 
 impl BufferPool {
 
     pub fn new(pool_size: usize) -> Self {
+        Self::new_with_policy(pool_size, ClockSweep::default())
+    }
+
+    pub fn new_with_policy(pool_size: usize, policy: impl EvictionPolicy + 'static) -> Self {
         let mut buffers = vec![];
-        buffers.resize_with(pool_size, Default::default);
-        let next_victim_id = BufferId::default();
+        buffers.resize_with(pool_size, || Frame::new(PAGE_SIZE));
         Self {
             buffers,
-            next_victim_id,
+            policy: Box::new(policy),
+            access_seq: 0,
+            // 先頭のフレームから使うよう、逆順に積む
+            free_list: (0..pool_size).rev().map(BufferId).collect(),
+            sweeps: 0,
+            page_size: PAGE_SIZE,
+        }
+    }
+
+    // フレームの持つページの大きさを変える。ストレージのページサイズと揃える必要がある
+    // 暗号化したストレージでは、2のべき乗からENCRYPTION_OVERHEADを引いた大きさになる
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        if let Err(err) = disk::check_page_size(page_size) {
+            if disk::check_page_size(page_size + disk::ENCRYPTION_OVERHEAD).is_err() {
+                panic!("{}", err);
+            }
         }
+        self.page_size = page_size;
+        for frame in self.buffers.iter_mut() {
+            *frame = Frame::new(page_size);
+        }
+        self
     }
 
     pub fn size(&self) -> usize {
         self.buffers.len()
     }
-    // Clock-sweep(どのバッファを捨てるか決めるアルゴリズム)
-    // PostgreSQLでも採用されているアルゴリズム
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
     fn evict(&mut self) -> Option<BufferId> {
-        let pool_size = self.size();
-        let mut consecutive_pinned = 0;
-
-        let victim_id = loop {
-            let next_victim_id = self.next_victim_id;
-            let frame = &mut self[next_victim_id];
-            if frame.usage_count == 0 {
-                break self.next_victim_id;
-            }
-            // バッファが貸出中かどうか
-            if Rc::get_mut(&mut frame.buffer).is_some() {
-                // 貸出中でなければデクリメント
-                frame.usage_count -= 1;
-                consecutive_pinned = 0;
-            } else {
-                // 貸出中
-                consecutive_pinned += 1;
-                if consecutive_pinned >= pool_size {
-                    return None;
-                }
+        if let Some(buffer_id) = self.pop_free() {
+            return Some(buffer_id);
+        }
+        self.sweeps += 1;
+        self.policy.pick_victim(&mut self.buffers)
+    }
+
+    fn pop_free(&mut self) -> Option<BufferId> {
+        while let Some(buffer_id) = self.free_list.pop() {
+            let frame = &self.buffers[buffer_id.0];
+            if frame.buffer.page_id.valid().is_none() && !frame.is_pinned() {
+                return Some(buffer_id);
             }
-            self.next_victim_id = self.increment_id(self.next_victim_id);
-        };
-        Some(victim_id)
+        }
+        None
+    }
+
+    // ページを保持しなくなったフレームを空きに戻す
+    fn release(&mut self, buffer_id: BufferId) {
+        self.free_list.push(buffer_id);
+    }
+
+    fn touch(&mut self, buffer_id: BufferId) {
+        self.access_seq += 1;
+        self.buffers[buffer_id.0].last_access = self.access_seq;
+        self.policy.touch(&mut self.buffers, buffer_id);
     }
 
-    fn increment_id(&self, buffer_id: BufferId) -> BufferId {
-        BufferId((buffer_id.0 + 1) % self.size())
+    fn resize(&mut self, pool_size: usize) {
+        let old_size = self.buffers.len();
+        let page_size = self.page_size;
+        self.buffers.resize_with(pool_size, || Frame::new(page_size));
+        self.free_list.retain(|buffer_id| buffer_id.0 < pool_size);
+        self.free_list.extend((old_size..pool_size).rev().map(BufferId));
+        self.policy.resize(pool_size);
     }
 }
 
@@ -114,157 +425,1993 @@ impl IndexMut<BufferId> for BufferPool {
     }
 }
 
+// 大きなスキャンでリングとして使い回すバッファの数
+pub const BULK_READ_RING_SIZE: usize = 8;
+
+// ページの読み込み方
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AccessStrategy {
+    Normal,
+    // 大きなスキャン向け。少数のバッファだけを使い回し、ほかのページを追い出さない
+    BulkRead,
+}
+
+// すべてのバッファが貸出中だったときの振る舞い
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum OnPoolFull {
+    // Error::NoFreeBufferを返す
+    #[default]
+    Fail,
+    // フレームを追加する。プールのサイズがmaxに達したら失敗する
+    // 追加したフレームは自動では減らない。BufferPoolManagerではshrink_grown_framesで元に戻せる
+    // SyncBufferPoolManagerでは最大で使ったサイズのまま残る
+    Grow { max: usize },
+    // ほかのスレッドがバッファを返すまで最大timeoutだけ待つ
+    // SyncBufferPoolManager向け。シングルスレッドのBufferPoolManagerではFailと同じ
+    Wait { timeout: Duration },
+}
+
+// バッファプールの統計情報
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct BufferPoolStats {
+    pub hits: u64,          // バッファプール内にページが見つかった回数
+    pub misses: u64,        // ディスクからページを読み込んだ回数
+    pub evictions: u64,     // ページを保持しているバッファを追い出した回数
+    pub dirty_writes: u64,  // 追い出し時にdirtyなページを書き出した回数
+    pub clean_evictions: u64,   // evictionsのうち、cleanなバッファを追い出した回数
+    pub dirty_evictions: u64,   // evictionsのうち、dirtyなバッファを追い出した回数
+    pub writes: u64,        // ディスクにページを書き出した回数
+    pub access_seq: u64,    // 最新のアクセスの通し番号(Frame::last_access)。reset_statsでは戻らない
+    pub sweeps: u64,        // 空きフレームがなく、EvictionPolicyで捨てるバッファを選んだ回数
+}
+
+impl AddAssign for BufferPoolStats {
+    fn add_assign(&mut self, other: Self) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.evictions += other.evictions;
+        self.dirty_writes += other.dirty_writes;
+        self.clean_evictions += other.clean_evictions;
+        self.dirty_evictions += other.dirty_evictions;
+        self.writes += other.writes;
+        self.access_seq = self.access_seq.max(other.access_seq);
+        self.sweeps += other.sweeps;
+    }
+}
+
+// ストレージの大きさと、バッファプールにキャッシュしているページの数
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct StorageInfo {
+    pub num_pages: u64,         // 使用中のページの数。解放したページは数えない
+    pub next_page_id: PageId,   // 空きページがないときに、次に採番するページID
+    pub file_size_bytes: u64,   // ストレージが使っているバイト数
+    pub cached_pages: usize,    // バッファプールに載っているページの数
+    pub dirty_pages: usize,     // そのうち、まだ書き出していないページの数
+}
+
+impl BufferPoolStats {
+    fn count_eviction(&mut self, is_dirty: bool) {
+        self.evictions += 1;
+        if is_dirty {
+            self.dirty_evictions += 1;
+        } else {
+            self.clean_evictions += 1;
+        }
+    }
+}
+
 // バッファプールマネージャ
 #[derive(Debug)]
 pub struct BufferPoolManager {
     // バッファプール内に必要なページのキャッシュがない場合、ディスクマネージャを呼び出して
     // ヒープファイルからデータを読み込む
-    disk: DiskManager,
+    disk: Box<dyn Storage>,
     pool: BufferPool,
     page_table: HashMap<PageId, BufferId>,      // ページテーブル: ページIDとバッファIDの対応表
+    dirty: Rc<RefCell<BTreeSet<BufferId>>>,     // dirtyなバッファの集合。WriteGuardと共有する
+    sticky_pages: HashSet<PageId>,              // Priority::Stickyを指定されたページ
+    stats: BufferPoolStats,
+    // BulkReadで使い回しているバッファと、そこに読み込んだページ
+    ring: Vec<(BufferId, PageId)>,
+    next_ring_idx: usize,
+    on_pool_full: OnPoolFull,
+    // Growで増やす前のプールのサイズ。resizeで変わる
+    base_pool_size: usize,
+    sync_mode: SyncMode,
+    leak_detector: Option<LeakDetector>,
+    // 実行中のオンラインバックアップ
+    backup: BackupTracker,
 }
 
 impl BufferPoolManager {
-    pub fn new(disk: DiskManager, pool: BufferPool) -> Self {
+    pub fn new(disk: impl Storage + 'static, pool: BufferPool) -> Self {
+        assert_eq!(
+            disk.page_size(),
+            pool.page_size(),
+            "buffer pool page size must match the storage"
+        );
         let page_table = HashMap::new();
+        let base_pool_size = pool.size();
         Self {
-            disk,
+            disk: Box::new(disk),
             pool,
             page_table,
+            dirty: Default::default(),
+            sticky_pages: HashSet::new(),
+            stats: Default::default(),
+            ring: vec![],
+            next_ring_idx: 0,
+            on_pool_full: OnPoolFull::default(),
+            base_pool_size,
+            sync_mode: SyncMode::default(),
+            leak_detector: None,
+            backup: BackupTracker::default(),
+        }
+    }
+
+    pub fn set_on_pool_full(&mut self, on_pool_full: OnPoolFull) {
+        self.on_pool_full = on_pool_full;
+    }
+
+    // flush時の永続化の方法を切り替える
+    pub fn set_sync_mode(&mut self, sync_mode: SyncMode) {
+        self.sync_mode = sync_mode;
+    }
+
+    // ストレージを書き込めないように開いたかどうか
+    pub fn is_read_only(&self) -> bool {
+        self.disk.is_read_only()
+    }
+
+    // 書き込めないストレージでは、dirtyにしたページを追い出すときではなく、変更する前に失敗させる
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.is_read_only() {
+            return Err(Error::Disk(disk::Error::ReadOnly));
+        }
+        Ok(())
+    }
+
+    // ストレージのヘッダに記録されたメタページのID
+    pub fn meta_page_id(&self) -> PageId {
+        self.disk.meta_page_id()
+    }
+
+    pub fn set_meta_page_id(&mut self, meta_page_id: PageId) -> Result<(), Error> {
+        self.check_writable()?;
+        self.disk
+            .set_meta_page_id(meta_page_id)
+            .map_err(|source| Error::Io {
+                page_id: PageId::INVALID_PAGE_ID,
+                op: IoOp::Write,
+                source,
+            })
+    }
+
+    // ピン留めの漏れの検出を有効にする。以降に貸し出したバッファについて、貸し出した時刻を記録する
+    // デバッグビルドではバックトレースも記録するので遅くなる
+    pub fn enable_leak_detection(&mut self, clock: Rc<dyn Clock>) {
+        self.leak_detector = Some(LeakDetector::new(clock));
+    }
+
+    // durationより長くピン留めされているバッファを、古い順に返す
+    pub fn pinned_longer_than(&self, duration: Duration) -> Vec<PinInfo> {
+        let mut pins = self.pins();
+        pins.retain(|info| info.pinned_for > duration);
+        pins
+    }
+
+    // 記録しているすべてのピン留めを、古い順に返す
+    fn pins(&self) -> Vec<PinInfo> {
+        let detector = match &self.leak_detector {
+            Some(detector) => detector,
+            None => return vec![],
+        };
+        let now = detector.clock.now();
+        let mut pins: Vec<_> = self
+            .pool
+            .buffers
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, frame)| {
+                let buffer = &frame.buffer;
+                buffer
+                    .pins
+                    .borrow()
+                    .iter()
+                    .map(|record| PinInfo {
+                        buffer_id: BufferId(idx),
+                        page_id: buffer.page_id,
+                        pinned_for: now.saturating_duration_since(record.pinned_at),
+                        #[cfg(debug_assertions)]
+                        backtrace: Rc::clone(&record.backtrace),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        pins.sort_by_key(|info| std::cmp::Reverse(info.pinned_for));
+        pins
+    }
+
+    fn no_free_buffer(&self) -> Error {
+        let oldest_pin = self.pins().first().map(|info| info.page_id);
+        Error::NoFreeBuffer { oldest_pin }
+    }
+
+    // バッファを貸し出す
+    fn pin(&mut self, buffer_id: BufferId) -> PinnedBuffer {
+        let record = self.leak_detector.as_mut().map(LeakDetector::record);
+        PinnedBuffer::new(&self.pool[buffer_id].buffer, record)
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            access_seq: self.pool.access_seq,
+            sweeps: self.pool.sweeps,
+            ..self.stats
         }
     }
 
+    pub fn reset_stats(&mut self) {
+        self.stats = Default::default();
+        self.pool.sweeps = 0;
+    }
+
+    pub fn storage_info(&self) -> Result<StorageInfo, Error> {
+        let file_size_bytes = self.disk.file_size_bytes().map_err(|source| Error::Io {
+            page_id: PageId::INVALID_PAGE_ID,
+            op: IoOp::Stat,
+            source,
+        })?;
+        Ok(StorageInfo {
+            num_pages: self.disk.num_pages(),
+            next_page_id: self.disk.next_page_id(),
+            file_size_bytes,
+            cached_pages: self.page_table.len(),
+            dirty_pages: self.dirty.borrow().len(),
+        })
+    }
+
     // ページの貸出
-    pub fn fetch_page(&mut self, page_id: PageId) -> Result<Rc<Buffer>, Error> {
+    // 返したPinnedBufferを呼び出し側が保持している間、そのフレームはピン留めされ追い出されない。
+    // dropするとピン留めが外れる。
+    pub fn fetch_page(&mut self, page_id: PageId) -> Result<PinnedBuffer, Error> {
         // dbg!(page_id);
         // ページがバッファプールにある場合
         if let Some(&buffer_id) = self.page_table.get(&page_id) {
-            let frame = &mut self.pool[buffer_id];
-            frame.usage_count += 1;
-            return Ok(Rc::clone(&frame.buffer));
+            self.pool.touch(buffer_id);
+            self.stats.hits += 1;
+            return Ok(self.pin(buffer_id));
         }
 
         // ページがバッファプールにない場合
-        
+        self.stats.misses += 1;
+
         // 1.捨てるバッファ = 次に読み込むページを格納するバッファを決定
-        let buffer_id = self.pool.evict().ok_or(Error::NoFreeBuffer)?;
+        let buffer_id = self.victim()?;
+        self.load_page(buffer_id, page_id)?;
+        Ok(self.pin(buffer_id))
+    }
+
+    // バッファプールに載っているページの中身を、ピン留めせずに借りる。載っていなければNone
+    // 読んだことは統計に数えず、追い出す順も変えない
+    pub fn peek_page(&self, page_id: PageId) -> Option<Ref<'_, [u8]>> {
+        let &buffer_id = self.page_table.get(&page_id)?;
+        Some(self.pool[buffer_id].buffer.data())
+    }
+
+    // peek_pageと同じく、載っているページの中身をピン留めせずに書き換え用に借り、dirtyにする
+    pub fn peek_page_mut(&mut self, page_id: PageId) -> Result<Option<RefMut<'_, [u8]>>, Error> {
+        self.check_writable()?;
+        let buffer_id = match self.page_table.get(&page_id) {
+            Some(&buffer_id) => buffer_id,
+            None => return Ok(None),
+        };
+        let buffer = &self.pool[buffer_id].buffer;
+        buffer.is_dirty.set(true);
+        self.dirty.borrow_mut().insert(buffer_id);
+        Ok(Some(buffer.data_mut()))
+    }
+
+    // ページの中身のコピーを返す
+    // ピン留めはすぐに外すので、返したあとはフレームを追い出せる
+    pub fn snapshot_page(&mut self, page_id: PageId) -> Result<Page, Error> {
+        let buffer = self.fetch_page(page_id)?;
+        Ok(buffer.snapshot())
+    }
+
+    // 読み込み専用でページを貸し出す
+    pub fn fetch_page_read(&mut self, page_id: PageId) -> Result<ReadGuard, Error> {
+        Ok(ReadGuard::new(self.fetch_page(page_id)?))
+    }
+
+    // 書き込み用にページを貸し出す
+    // 返したWriteGuardから書き込み用に中身を借りると、自動的にdirtyになる
+    pub fn fetch_page_write(&mut self, page_id: PageId) -> Result<WriteGuard, Error> {
+        self.check_writable()?;
+        let buffer = self.fetch_page(page_id)?;
+        let buffer_id = self.page_table[&page_id];
+        Ok(WriteGuard::new(buffer, buffer_id, Rc::clone(&self.dirty)))
+    }
+
+    pub fn fetch_page_with_strategy(
+        &mut self,
+        page_id: PageId,
+        strategy: AccessStrategy,
+    ) -> Result<PinnedBuffer, Error> {
+        if strategy == AccessStrategy::Normal || self.page_table.contains_key(&page_id) {
+            return self.fetch_page(page_id);
+        }
+        self.stats.misses += 1;
+        let buffer_id = match self.ring_victim() {
+            Some(buffer_id) => buffer_id,
+            None => return Err(self.no_free_buffer()),
+        };
+        self.load_page(buffer_id, page_id)?;
+        Ok(self.pin(buffer_id))
+    }
+
+    // 捨てるバッファを決める。すべて貸出中ならon_pool_fullに従う
+    fn victim(&mut self) -> Result<BufferId, Error> {
+        if let Some(buffer_id) = self.pool.evict() {
+            return Ok(buffer_id);
+        }
+        match self.on_pool_full {
+            OnPoolFull::Grow { max } if self.pool.size() < max => {
+                // 追加したフレームは空きリストに入る
+                self.pool.resize(self.pool.size() + 1);
+                Ok(self.pool.evict().unwrap())
+            }
+            _ => Err(self.no_free_buffer()),
+        }
+    }
+
+    // リングの中から次に使うバッファを決める
+    // リングのバッファが貸出中だったり、ほかから利用されていたりすれば通常どおり追い出す
+    fn ring_victim(&mut self) -> Option<BufferId> {
+        if self.ring.len() < BULK_READ_RING_SIZE.min(self.pool.size()) {
+            let buffer_id = self.pool.evict()?;
+            self.ring.push((buffer_id, PageId::INVALID_PAGE_ID));
+            self.next_ring_idx = self.ring.len() - 1;
+            return Some(buffer_id);
+        }
+        self.next_ring_idx = (self.next_ring_idx + 1) % self.ring.len();
+        let (buffer_id, ring_page_id) = self.ring[self.next_ring_idx];
+        let frame = &self.pool[buffer_id];
+        let buffer_id = if frame.is_pinned()
+            || frame.buffer.page_id != ring_page_id
+            || frame.usage_count > 1
+        {
+            self.pool.evict()?
+        } else {
+            buffer_id
+        };
+        self.ring[self.next_ring_idx].0 = buffer_id;
+        Some(buffer_id)
+    }
+
+    // 貸出中でないバッファにページを読み込む
+    fn load_page(&mut self, buffer_id: BufferId, page_id: PageId) -> Result<(), Error> {
+        let priority = self.priority_of(page_id);
         let frame = &mut self.pool[buffer_id];
         let evict_page_id = frame.buffer.page_id;
+        if evict_page_id.valid().is_some() {
+            self.stats.count_eviction(frame.is_dirty());
+        }
         {
             let buffer = Rc::get_mut(&mut frame.buffer).unwrap();
 
             // 2.捨てるバッファのis_dirtyフラグがtrueなら、そのバッファをディスクに書き出す。
             if buffer.is_dirty.get() {
-                self.disk.write_page_data(evict_page_id, buffer.page.get_mut())?;
+                write_page(self.disk.as_mut(), evict_page_id, buffer.page.get_mut())?;
+                self.backup.record(evict_page_id);
+                self.stats.dirty_writes += 1;
+                self.stats.writes += 1;
             }
-            buffer.page_id = page_id;
+            self.dirty.borrow_mut().remove(&buffer_id);
+            // 一度もページを保持していないフレームはページテーブルに載っていない
+            if evict_page_id.valid().is_some() {
+                self.page_table.remove(&evict_page_id);
+            }
+            buffer.page_id = PageId::INVALID_PAGE_ID;
             buffer.is_dirty.set(false);
 
             // 3.ページを読み出し
-            self.disk.read_page_data(page_id, buffer.page.get_mut())?;
-            frame.usage_count = 1;
+            if let Err(err) = read_page(self.disk.as_mut(), page_id, buffer.page.get_mut()) {
+                self.pool.release(buffer_id);
+                return Err(err);
+            }
+            buffer.page_id = page_id;
+            frame.usage_count = 0;
+            frame.priority = priority;
+            frame.sticky_passes = 0;
         }
-        let page = Rc::clone(&frame.buffer);
-        
+        self.pool.touch(buffer_id);
+
         // 4.バッファに入っているページが入れ替わったので、ページテーブルを更新する
-        self.page_table.remove(&evict_page_id);
         self.page_table.insert(page_id, buffer_id);
-        Ok(page)
+        if let Some(entry) = self.ring.iter_mut().find(|(id, _)| *id == buffer_id) {
+            entry.1 = page_id;
+        }
+        Ok(())
+    }
+
+    // ページを先読みしてバッファプールに載せておく(貸出はしない)
+    // 空きバッファがなくなった時点で打ち切り、読み込んだページ数を返す
+    pub fn prefetch(&mut self, page_ids: &[PageId]) -> Result<usize, Error> {
+        let mut loaded = 0;
+        for &page_id in page_ids {
+            if self.page_table.contains_key(&page_id) {
+                continue;
+            }
+            let buffer_id = match self.pool.evict() {
+                Some(buffer_id) => buffer_id,
+                None => break,
+            };
+            // 先読みしたばかりのページを追い出すことになるなら打ち切る
+            if page_ids.contains(&self.pool[buffer_id].buffer.page_id) {
+                break;
+            }
+            self.stats.misses += 1;
+            self.load_page(buffer_id, page_id)?;
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    // キャッシュしているページのうち、最後に利用されてからもっとも時間が経っているn個を古い順に返す
+    pub fn coldest_pages(&self, n: usize) -> Vec<PageId> {
+        let mut frames: Vec<_> = self
+            .pool
+            .buffers
+            .iter()
+            .filter(|frame| frame.buffer.page_id.valid().is_some())
+            .collect();
+        frames.sort_by_key(|frame| frame.last_access);
+        frames
+            .iter()
+            .take(n)
+            .map(|frame| frame.buffer.page_id)
+            .collect()
+    }
+
+    // キャッシュしているページのIDを、利用回数の多い順に返す
+    // 終了時に保存しておき、次回の起動時にwarm_upに渡すことを想定している
+    pub fn save_cached_page_ids(&self) -> Vec<PageId> {
+        let mut frames: Vec<_> = self
+            .pool
+            .buffers
+            .iter()
+            .filter(|frame| frame.buffer.page_id.valid().is_some())
+            .collect();
+        frames.sort_by_key(|frame| std::cmp::Reverse(frame.usage_count));
+        frames.iter().map(|frame| frame.buffer.page_id).collect()
     }
 
-    pub fn create_page(&mut self) -> Result<Rc<Buffer>, Error> {
-        let buffer_id = self.pool.evict().ok_or(Error::NoFreeBuffer)?;
+    // 保存しておいたページをバッファプールに読み込む(貸出はしない)
+    // 未使用のフレームがなくなった時点で打ち切り、読み込んだページ数を返す
+    pub fn warm_up(&mut self, page_ids: &[PageId]) -> Result<usize, Error> {
+        let mut loaded = 0;
+        for &page_id in page_ids {
+            if self.page_table.contains_key(&page_id) {
+                continue;
+            }
+            let buffer_id = match self.pool.pop_free() {
+                Some(buffer_id) => buffer_id,
+                None => break,
+            };
+            self.stats.misses += 1;
+            self.load_page(buffer_id, page_id)?;
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    // 新しいページの作成
+    // ディスクからの読み出しは行わず、ゼロ埋めしたページをdirtyな状態で貸し出す
+    pub fn create_page(&mut self) -> Result<PinnedBuffer, Error> {
+        self.create_page_in(TablespaceId::DEFAULT)
+    }
+
+    // 表領域を指定して新しいページを作成する
+    // ページIDが表領域を含むので、page_tableは表領域ごとに別のページとして扱う
+    pub fn create_page_in(&mut self, tablespace_id: TablespaceId) -> Result<PinnedBuffer, Error> {
+        self.check_writable()?;
+        let buffer_id = self.victim()?;
         let frame = &mut self.pool[buffer_id];
         let evict_page_id = frame.buffer.page_id;
+        if evict_page_id.valid().is_some() {
+            self.stats.count_eviction(frame.is_dirty());
+        }
         let page_id = {
             let buffer = Rc::get_mut(&mut frame.buffer).unwrap();
             if buffer.is_dirty.get() {
-                self.disk.write_page_data(evict_page_id, buffer.page.get_mut())?;
+                write_page(self.disk.as_mut(), evict_page_id, buffer.page.get_mut())?;
+                self.backup.record(evict_page_id);
+                self.stats.dirty_writes += 1;
+                self.stats.writes += 1;
+            }
+            if evict_page_id.valid().is_some() {
+                self.page_table.remove(&evict_page_id);
             }
-            self.page_table.remove(&evict_page_id);
-            let page_id = self.disk.allocate_page();
-            *buffer = Buffer::default();
+            self.dirty.borrow_mut().remove(&buffer_id);
+            buffer.reset();
+            let page_id = match allocate_page(self.disk.as_mut(), tablespace_id) {
+                Ok(page_id) => page_id,
+                Err(err) => {
+                    self.pool.release(buffer_id);
+                    return Err(err);
+                }
+            };
             buffer.page_id = page_id;
             buffer.is_dirty.set(true);
-            frame.usage_count = 1;
+            frame.usage_count = 0;
+            frame.priority = Priority::Normal;
+            frame.sticky_passes = 0;
             page_id
         };
-        let page = Rc::clone(&frame.buffer);
-        self.page_table.remove(&evict_page_id);
+        self.pool.touch(buffer_id);
+        self.dirty.borrow_mut().insert(buffer_id);
+        let page = self.pin(buffer_id);
         self.page_table.insert(page_id, buffer_id);
         Ok(page)
     }
 
-    pub fn flush(&mut self) -> Result<(), Error> {
-        for (&page_id, &buffer_id) in self.page_table.iter() {
-            let frame = &self.pool[buffer_id];
-            let mut page = frame.buffer.page.borrow_mut();
-            self.disk.write_page_data(page_id, page.as_mut())?;
-            frame.buffer.is_dirty.set(false);
+    // ページを解放し、ディスク上で再利用できるようにする
+    // キャッシュされていれば、書き出さずにバッファから取り除く
+    pub fn free_page(&mut self, page_id: PageId) -> Result<(), Error> {
+        self.check_writable()?;
+        if let Some(&buffer_id) = self.page_table.get(&page_id) {
+            let frame = &mut self.pool[buffer_id];
+            if frame.is_pinned() {
+                return Err(Error::PinnedBuffer(page_id));
+            }
+            Rc::get_mut(&mut frame.buffer).unwrap().reset();
+            frame.usage_count = 0;
+            self.dirty.borrow_mut().remove(&buffer_id);
+            self.page_table.remove(&page_id);
+            self.pool.release(buffer_id);
         }
-        self.disk.sync()?;
-        Ok(())
+        self.sticky_pages.remove(&page_id);
+        self.disk.deallocate_page(page_id).map_err(|source| Error::Io {
+            page_id,
+            op: IoOp::Free,
+            source,
+        })
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::tempfile;
-
-    #[test]
-    fn test() {
-        let mut hello = Vec::with_capacity(PAGE_SIZE);
-        hello.extend_from_slice(b"hello");
-        hello.resize(PAGE_SIZE, 0);
-        let mut world = Vec::with_capacity(PAGE_SIZE);
-        world.extend_from_slice(b"world");
-        world.resize(PAGE_SIZE, 0);
 
-        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
-        let pool = BufferPool::new(1);
-        let mut bufmgr = BufferPoolManager::new(disk, pool);
+    // ストレージの末尾の空きページを、すべての表領域で切り詰めて、減ったバイト数を返す
+    // dirtyなページを書き出してから縮める
+    // 切り詰めるのは空きページだけで、解放したページはfree_pageでキャッシュから取り除いてある
+    pub fn compact(&mut self) -> Result<u64, Error> {
+        self.check_writable()?;
+        self.flush()?;
+        self.disk.compact().map_err(|source| Error::Io {
+            page_id: PageId::INVALID_PAGE_ID,
+            op: IoOp::Compact,
+            source,
+        })
+    }
 
-        let page1_id = {
-            let buffer = bufmgr.create_page().unwrap();
-            assert!(bufmgr.create_page().is_err());
-            let mut page = buffer.page.borrow_mut();
-            page.copy_from_slice(&hello);
-            buffer.is_dirty.set(true);
-            buffer.page_id
+    // ページの追い出されにくさを設定する
+    // キャッシュされていないページにも設定でき、追い出されて読み込み直しても維持される
+    pub fn set_priority(&mut self, page_id: PageId, priority: Priority) {
+        match priority {
+            Priority::Normal => self.sticky_pages.remove(&page_id),
+            Priority::Sticky => self.sticky_pages.insert(page_id),
         };
+        if let Some(&buffer_id) = self.page_table.get(&page_id) {
+            self.pool[buffer_id].priority = priority;
+        }
+    }
 
-        {
-            // ページ1のチェック
-            let buffer = bufmgr.fetch_page(page1_id).unwrap();
-            let page = buffer.page.borrow();
-            assert_eq!(&hello, page.as_ref());
+    fn priority_of(&self, page_id: PageId) -> Priority {
+        if self.sticky_pages.contains(&page_id) {
+            Priority::Sticky
+        } else {
+            Priority::Normal
         }
+    }
 
-        // println!("チェック後: {:?}", bufmgr);
+    // 貸し出したバッファを書き換えたことを知らせる
+    pub fn mark_dirty(&mut self, buffer: &Buffer) {
+        buffer.is_dirty.set(true);
+        if let Some(&buffer_id) = self.page_table.get(&buffer.page_id) {
+            self.dirty.borrow_mut().insert(buffer_id);
+        }
+    }
 
-        let page2_id = {
-            let buffer = bufmgr.create_page().unwrap();
-            let mut page = buffer.page.borrow_mut();
-            page.copy_from_slice(&world);
-            buffer.is_dirty.set(true);
-            buffer.page_id
+    // 指定したページがdirtyならディスクに書き出す
+    // キャッシュされていないページやdirtyでないページに対しては何もしない
+    pub fn flush_page(&mut self, page_id: PageId) -> Result<(), Error> {
+        let buffer_id = match self.page_table.get(&page_id) {
+            Some(&buffer_id) => buffer_id,
+            None => return Ok(()),
         };
-
-        // println!("再チェック前: {:?}", bufmgr);
-
-        {
-            // ページ1の再チェック(ディスクに退避させられているのを確認)
-            let buffer = bufmgr.fetch_page(page1_id).unwrap();
-            let page = buffer.page.borrow();
-            assert_eq!(&hello, page.as_ref());
+        let buffer = &self.pool[buffer_id].buffer;
+        if !buffer.is_dirty.get() {
+            return Ok(());
         }
+        let page = buffer.page.borrow();
+        write_page(self.disk.as_mut(), page_id, &page)?;
+        self.backup.record(page_id);
+        buffer.is_dirty.set(false);
+        self.dirty.borrow_mut().remove(&buffer_id);
+        self.stats.writes += 1;
+        Ok(())
+    }
 
-        {
-            // ページ2のチェック
-            let buffer = bufmgr.fetch_page(page2_id).unwrap();
-            let page = buffer.page.borrow();
-            assert_eq!(&world, page.as_ref());
+    // バッファプールのサイズを変更する
+    // 縮小するときは末尾のフレームを追い出す。そのなかに貸出中のものがあれば失敗する
+    pub fn resize(&mut self, new_size: usize) -> Result<(), Error> {
+        if new_size < self.pool.size() {
+            let tail = &self.pool.buffers[new_size..];
+            if let Some(frame) = tail.iter().find(|frame| frame.is_pinned()) {
+                return Err(Error::PinnedBuffer(frame.buffer.page_id));
+            }
+            for frame in tail {
+                let buffer = &frame.buffer;
+                if buffer.page_id.valid().is_none() {
+                    continue;
+                }
+                self.stats.count_eviction(buffer.is_dirty.get());
+                if buffer.is_dirty.get() {
+                    let page = buffer.page.borrow();
+                    write_page(self.disk.as_mut(), buffer.page_id, &page)?;
+                    self.backup.record(buffer.page_id);
+                    buffer.is_dirty.set(false);
+                    self.stats.dirty_writes += 1;
+                    self.stats.writes += 1;
+                }
+                self.page_table.remove(&buffer.page_id);
+            }
         }
+        self.pool.resize(new_size);
+        self.dirty.borrow_mut().retain(|buffer_id| buffer_id.0 < new_size);
+        self.ring.retain(|(buffer_id, _)| buffer_id.0 < new_size);
+        self.next_ring_idx = 0;
+        self.base_pool_size = new_size;
+        Ok(())
+    }
+
+    // OnPoolFull::Growで増やしたフレームを末尾から外し、外した数を返す
+    // 貸出中のフレームに当たったらそこで止まる
+    pub fn shrink_grown_frames(&mut self) -> Result<usize, Error> {
+        let size = self.pool.size();
+        let mut new_size = size;
+        while new_size > self.base_pool_size && !self.pool.buffers[new_size - 1].is_pinned() {
+            new_size -= 1;
+        }
+        if new_size < size {
+            let base_pool_size = self.base_pool_size;
+            self.resize(new_size)?;
+            self.base_pool_size = base_pool_size;
+        }
+        Ok(size - new_size)
+    }
+
+    // dirtyなバッファを書き出してから閉じる
+    // drop時の書き出しと違い、失敗を呼び出し側で確認できる
+    pub fn close(mut self) -> Result<(), Error> {
+        self.flush()
+    }
+
+    // dirtyなバッファをすべてディスクに書き出す
+    // dirtyなバッファの集合だけを見るので、プールの大きさによらない
+    // 書き込みが途中で途切れても壊れたページが残らないよう、まとめてストレージに渡す
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let buffer_ids: Vec<_> = self.dirty.borrow().iter().copied().collect();
+        if !buffer_ids.is_empty() {
+            let pool = &self.pool;
+            let buffers: Vec<_> = buffer_ids
+                .iter()
+                .map(|&buffer_id| &pool[buffer_id].buffer)
+                .collect();
+            let pages: Vec<_> = buffers.iter().map(|buffer| buffer.page.borrow()).collect();
+            let batch: Vec<_> = buffers
+                .iter()
+                .zip(pages.iter())
+                .map(|(buffer, page)| (buffer.page_id, &page[..]))
+                .collect();
+            // どのページで失敗したかはわからないので、先頭のページを報告する
+            self.disk
+                .write_pages_atomic(&batch)
+                .map_err(|source| Error::Io {
+                    page_id: batch[0].0,
+                    op: IoOp::Write,
+                    source,
+                })?;
+            drop(pages);
+            for buffer in buffers {
+                self.backup.record(buffer.page_id);
+                buffer.is_dirty.set(false);
+            }
+            self.dirty.borrow_mut().clear();
+            self.stats.writes += buffer_ids.len() as u64;
+        }
+        sync_disk(self.disk.as_mut(), self.sync_mode)?;
+        Ok(())
+    }
+}
+
+// flushし忘れた変更が失われないよう、drop時にdirtyなバッファを書き出す
+impl Drop for BufferPoolManager {
+    fn drop(&mut self) {
+        if self.dirty.borrow().is_empty() {
+            return;
+        }
+        if let Err(err) = self.flush() {
+            eprintln!("failed to flush buffer pool on drop: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::{DiskManager, MemoryDiskManager};
+    use std::sync::{Arc, Mutex};
+    use tempfile::{tempfile, NamedTempFile};
+
+    // 呼び出された操作を記録するストレージ
+    #[derive(Debug, Default)]
+    pub(super) struct RecordingStorage {
+        disk: MemoryDiskManager,
+        pub(super) log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Storage for RecordingStorage {
+        fn read_page_data(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
+            self.disk.read_page_data(page_id, data)
+        }
+
+        fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
+            self.log.lock().unwrap().push(format!("write {}", page_id.0));
+            self.disk.write_page_data(page_id, data)
+        }
+
+        fn page_size(&self) -> usize {
+            self.disk.page_size()
+        }
+
+        fn allocate_page(&mut self) -> io::Result<PageId> {
+            self.disk.allocate_page()
+        }
+
+        fn deallocate_page(&mut self, page_id: PageId) -> io::Result<()> {
+            self.disk.deallocate_page(page_id)
+        }
+
+        fn meta_page_id(&self) -> PageId {
+            self.disk.meta_page_id()
+        }
+
+        fn set_meta_page_id(&mut self, meta_page_id: PageId) -> io::Result<()> {
+            self.disk.set_meta_page_id(meta_page_id)
+        }
+
+        fn num_pages(&self) -> u64 {
+            self.disk.num_pages()
+        }
+
+        fn next_page_id(&self) -> PageId {
+            self.disk.next_page_id()
+        }
+
+        fn file_size_bytes(&self) -> io::Result<u64> {
+            self.disk.file_size_bytes()
+        }
+
+        fn free_page_ids(&mut self) -> io::Result<Vec<PageId>> {
+            self.disk.free_page_ids()
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.log.lock().unwrap().push("sync".to_string());
+            Ok(())
+        }
+
+        fn sync_data(&mut self) -> io::Result<()> {
+            self.log.lock().unwrap().push("sync_data".to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test() {
+        let mut hello = Vec::with_capacity(PAGE_DATA_SIZE);
+        hello.extend_from_slice(b"hello");
+        hello.resize(PAGE_DATA_SIZE, 0);
+        let mut world = Vec::with_capacity(PAGE_DATA_SIZE);
+        world.extend_from_slice(b"world");
+        world.resize(PAGE_DATA_SIZE, 0);
+
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(1);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+
+        let page1_id = {
+            let buffer = bufmgr.create_page().unwrap();
+            assert!(bufmgr.create_page().is_err());
+            let mut page = buffer.data_mut();
+            page.copy_from_slice(&hello);
+            bufmgr.mark_dirty(&buffer);
+            buffer.page_id
+        };
+
+        {
+            // ページ1のチェック
+            let buffer = bufmgr.fetch_page(page1_id).unwrap();
+            let page = buffer.data();
+            assert_eq!(&hello, &*page);
+        }
+
+        // println!("チェック後: {:?}", bufmgr);
+
+        let page2_id = {
+            let buffer = bufmgr.create_page().unwrap();
+            let mut page = buffer.data_mut();
+            page.copy_from_slice(&world);
+            bufmgr.mark_dirty(&buffer);
+            buffer.page_id
+        };
+
+        // println!("再チェック前: {:?}", bufmgr);
+
+        {
+            // ページ1の再チェック(ディスクに退避させられているのを確認)
+            let buffer = bufmgr.fetch_page(page1_id).unwrap();
+            let page = buffer.data();
+            assert_eq!(&hello, &*page);
+        }
+
+        {
+            // ページ2のチェック
+            let buffer = bufmgr.fetch_page(page2_id).unwrap();
+            let page = buffer.data();
+            assert_eq!(&world, &*page);
+        }
+    }
+
+    #[test]
+    fn test_evict_dirty_page() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(1);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+
+        let page_a_id = {
+            let buffer = bufmgr.create_page().unwrap();
+            buffer.page.borrow_mut()[..5].copy_from_slice(b"hello");
+            buffer.page_id
+        };
+        let page_b_id = {
+            let buffer = bufmgr.create_page().unwrap();
+            buffer.page.borrow_mut()[..5].copy_from_slice(b"world");
+            buffer.page_id
+        };
+
+        // ページAを書き換えてdirtyにする
+        {
+            let buffer = bufmgr.fetch_page(page_a_id).unwrap();
+            buffer.page.borrow_mut()[..5].copy_from_slice(b"HELLO");
+            bufmgr.mark_dirty(&buffer);
+        }
+
+        // ページBを読み込むとページAが追い出される
+        {
+            let buffer = bufmgr.fetch_page(page_b_id).unwrap();
+            assert_eq!(b"world", &buffer.page.borrow()[..5]);
+        }
+
+        // ページAの変更がディスクに書き出されている
+        {
+            let buffer = bufmgr.fetch_page(page_a_id).unwrap();
+            assert_eq!(b"HELLO", &buffer.page.borrow()[..5]);
+        }
+        {
+            let buffer = bufmgr.fetch_page(page_b_id).unwrap();
+            assert_eq!(b"world", &buffer.page.borrow()[..5]);
+        }
+    }
+
+    #[test]
+    fn test_create_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let disk = DiskManager::new(data_file).unwrap();
+        let pool = BufferPool::new(3);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+
+        let mut page_ids = vec![];
+        for i in 0..5u8 {
+            let buffer = bufmgr.create_page().unwrap();
+            assert!(buffer.is_dirty());
+            // 新しいページはゼロ埋めされている
+            assert!(buffer.page.borrow().iter().all(|&b| b == 0));
+            buffer.data_mut().iter_mut().for_each(|b| *b = i + 1);
+            page_ids.push(buffer.page_id);
+        }
+        bufmgr.flush().unwrap();
+        drop(bufmgr);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let pool = BufferPool::new(3);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            let buffer = bufmgr.fetch_page(page_id).unwrap();
+            assert!(buffer.data().iter().all(|&b| b == i as u8 + 1));
+        }
+    }
+
+    #[test]
+    fn test_flush_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let disk = DiskManager::new(data_file).unwrap();
+        let pool = BufferPool::new(3);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let read_from_disk = |page_id: PageId| {
+            let mut disk = DiskManager::open(&data_file_path).unwrap();
+            let mut buf = vec![0; PAGE_SIZE];
+            disk.read_page_data(page_id, &mut buf).unwrap();
+            buf
+        };
+
+        let page_ids: Vec<_> = (0..3)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        bufmgr.flush().unwrap();
+
+        // キャッシュされていてdirtyなページ
+        {
+            let buffer = bufmgr.fetch_page(page_ids[0]).unwrap();
+            buffer.page.borrow_mut()[..5].copy_from_slice(b"hello");
+            bufmgr.mark_dirty(&buffer);
+            bufmgr.flush_page(page_ids[0]).unwrap();
+            assert!(!buffer.is_dirty());
+        }
+        assert_eq!(b"hello", &read_from_disk(page_ids[0])[..5]);
+
+        // キャッシュされていてdirtyでないページは書き出されない
+        {
+            let buffer = bufmgr.fetch_page(page_ids[1]).unwrap();
+            buffer.page.borrow_mut()[..5].copy_from_slice(b"world");
+            bufmgr.flush_page(page_ids[1]).unwrap();
+        }
+        assert_eq!(&[0; 5], &read_from_disk(page_ids[1])[..5]);
+
+        // キャッシュされていないページ
+        bufmgr.flush_page(PageId(100)).unwrap();
+    }
+
+    #[test]
+    fn test_pinned_buffers() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(3);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let page_ids: Vec<_> = (0..4)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+
+        let mut buffers: Vec<_> = page_ids[..3]
+            .iter()
+            .map(|&page_id| bufmgr.fetch_page(page_id).unwrap())
+            .collect();
+        assert!(matches!(
+            bufmgr.fetch_page(page_ids[3]),
+            Err(Error::NoFreeBuffer { .. })
+        ));
+
+        buffers.pop();
+        let buffer = bufmgr.fetch_page(page_ids[3]).unwrap();
+        assert_eq!(page_ids[3], buffer.page_id);
+    }
+
+    #[test]
+    fn test_stats() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(5);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let page_ids: Vec<_> = (0..10)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+
+        // プールに収まるワークロード
+        for &page_id in &page_ids[5..] {
+            bufmgr.fetch_page(page_id).unwrap();
+        }
+        bufmgr.reset_stats();
+        for _ in 0..10 {
+            for &page_id in &page_ids[5..] {
+                bufmgr.fetch_page(page_id).unwrap();
+            }
+        }
+        let stats = bufmgr.stats();
+        assert_eq!(50, stats.hits);
+        assert_eq!(0, stats.misses);
+        assert_eq!(0, stats.evictions);
+
+        // プールに収まらないワークロード
+        bufmgr.reset_stats();
+        for _ in 0..3 {
+            for &page_id in &page_ids {
+                let buffer = bufmgr.fetch_page(page_id).unwrap();
+                bufmgr.mark_dirty(&buffer);
+            }
+        }
+        let stats = bufmgr.stats();
+        assert!(stats.misses > 0);
+        assert!(stats.evictions > 0);
+        assert!(stats.dirty_writes > 0);
+    }
+
+    #[test]
+    fn test_storage_info() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let disk = DiskManager::create(&data_file_path).unwrap();
+        let pool = BufferPool::new(3);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let page_ids: Vec<_> = (0..5)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        bufmgr.free_page(page_ids[4]).unwrap();
+        bufmgr.free_page(page_ids[0]).unwrap();
+        let info = bufmgr.storage_info().unwrap();
+        assert_eq!(3, info.num_pages);
+        assert_eq!(PageId(5), info.next_page_id);
+        assert_eq!(std::fs::metadata(&data_file_path).unwrap().len(), info.file_size_bytes);
+        // 解放したページ4はプールから取り除かれ、2と3がdirtyなまま残っている
+        assert_eq!(2, info.cached_pages);
+        assert_eq!(2, info.dirty_pages);
+
+        bufmgr.flush().unwrap();
+        let info = bufmgr.storage_info().unwrap();
+        assert_eq!(0, info.dirty_pages);
+        // 解放したページを再利用しても、次に採番するページIDは進まない
+        bufmgr.create_page().unwrap();
+        let info = bufmgr.storage_info().unwrap();
+        assert_eq!(4, info.num_pages);
+        assert_eq!(PageId(5), info.next_page_id);
+    }
+
+    #[test]
+    fn test_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_path = dir.path().join("backup");
+        let disk = DiskManager::create(dir.path().join("heap")).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(3));
+        let write = |bufmgr: &mut BufferPoolManager, page_id: PageId, byte: u8| {
+            let buffer = bufmgr.fetch_page(page_id).unwrap();
+            buffer.data_mut().fill(byte);
+            bufmgr.mark_dirty(&buffer);
+        };
+        let page_ids: Vec<_> = (0..8)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            write(&mut bufmgr, page_id, i as u8);
+        }
+        bufmgr.free_page(page_ids[6]).unwrap();
+
+        // コピーしている間にも書き込み、ページを解放して再利用する
+        bufmgr.start_backup(&backup_path).unwrap();
+        assert!(bufmgr.start_backup(&backup_path).is_err());
+        assert!(!bufmgr.backup_step(4).unwrap());
+        write(&mut bufmgr, page_ids[1], 0xA1);
+        write(&mut bufmgr, page_ids[5], 0xA5);
+        bufmgr.free_page(page_ids[2]).unwrap();
+        let new_page_id = bufmgr.create_page().unwrap().page_id;
+        assert_eq!(page_ids[2], new_page_id);
+        let extra_page_id = bufmgr.create_page().unwrap().page_id;
+        assert_eq!(page_ids[6], extra_page_id);
+        assert!(!bufmgr.backup_step(2).unwrap());
+        write(&mut bufmgr, page_ids[3], 0xA3);
+        write(&mut bufmgr, new_page_id, 0xA2);
+        write(&mut bufmgr, extra_page_id, 0xAA);
+        bufmgr.free_page(page_ids[0]).unwrap();
+        bufmgr.finish_backup().unwrap();
+        assert!(bufmgr.backup_step(1).is_err());
+
+        // コピー先は元のファイルと同じ内容、同じ空きページのリストになる
+        let mut source = DiskManager::open(dir.path().join("heap")).unwrap();
+        let mut backup = DiskManager::open(&backup_path).unwrap();
+        assert_eq!(source.num_pages(), backup.num_pages());
+        assert_eq!(source.next_page_id(), backup.next_page_id());
+        assert_eq!(source.free_page_ids().unwrap(), backup.free_page_ids().unwrap());
+        let mut expected = vec![0; PAGE_SIZE];
+        let mut actual = vec![0; PAGE_SIZE];
+        for page_id in (0..source.next_page_id().to_u64()).map(PageId) {
+            source.read_page_data(page_id, &mut expected).unwrap();
+            backup.read_page_data(page_id, &mut actual).unwrap();
+            assert_eq!(expected, actual, "page {}", page_id.to_u64());
+        }
+        backup.read_page_data(page_ids[3], &mut actual).unwrap();
+        assert_eq!(0xA3, actual[0]);
+        assert_eq!(page_ids[0], backup.allocate_page().unwrap());
+        assert_eq!(PageId(8), backup.allocate_page().unwrap());
+    }
+
+    #[test]
+    fn test_backup_tablespaces() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_path = dir.path().join("backup");
+        let mut disk = DiskManager::create(dir.path().join("heap")).unwrap();
+        let tablespace_id = disk.create_tablespace("index").unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(3));
+        let write = |bufmgr: &mut BufferPoolManager, tablespace_id, byte: u8| {
+            let buffer = bufmgr.create_page_in(tablespace_id).unwrap();
+            buffer.data_mut().fill(byte);
+            bufmgr.mark_dirty(&buffer);
+            buffer.page_id
+        };
+        let mut page_ids = vec![];
+        for i in 0..4 {
+            page_ids.push(write(&mut bufmgr, TablespaceId::DEFAULT, i));
+            page_ids.push(write(&mut bufmgr, tablespace_id, 0x10 + i));
+        }
+        bufmgr.free_page(page_ids[3]).unwrap();
+
+        // 表領域のページをコピーしている間にも、どちらの表領域でも書き込み、採番する
+        bufmgr.start_backup(&backup_path).unwrap();
+        assert!(!bufmgr.backup_step(5).unwrap());
+        let buffer = bufmgr.fetch_page(page_ids[1]).unwrap();
+        buffer.data_mut().fill(0xA1);
+        bufmgr.mark_dirty(&buffer);
+        drop(buffer);
+        let reused = write(&mut bufmgr, tablespace_id, 0xA3);
+        assert_eq!(page_ids[3], reused);
+        let extra = write(&mut bufmgr, TablespaceId::DEFAULT, 0xAA);
+        let unwritten = bufmgr.create_page_in(tablespace_id).unwrap().page_id;
+        bufmgr.free_page(page_ids[5]).unwrap();
+        bufmgr.finish_backup().unwrap();
+        drop(bufmgr);
+
+        let mut source = DiskManager::open(dir.path().join("heap")).unwrap();
+        let mut backup = DiskManager::open(&backup_path).unwrap();
+        assert_eq!(Some(tablespace_id), backup.tablespace_id("index"));
+        assert_eq!(source.num_pages(), backup.num_pages());
+        for id in [TablespaceId::DEFAULT, tablespace_id].iter().copied() {
+            let end = source.next_page_id_in(id).unwrap();
+            assert_eq!(end, backup.next_page_id_in(id).unwrap());
+            assert_eq!(source.free_page_ids_in(id).unwrap(), backup.free_page_ids_in(id).unwrap());
+            let free = source.free_page_ids_in(id).unwrap();
+            let mut expected = vec![0; PAGE_SIZE];
+            let mut actual = vec![0; PAGE_SIZE];
+            for page_id in (0..end.page_number()).map(|n| PageId::new(id, n)) {
+                if free.contains(&page_id) || page_id == unwritten {
+                    continue;
+                }
+                source.read_page_data(page_id, &mut expected).unwrap();
+                backup.read_page_data(page_id, &mut actual).unwrap();
+                assert_eq!(expected, actual, "page {}", page_id.to_u64());
+            }
+        }
+        let mut page = vec![0; PAGE_SIZE];
+        backup.read_page_data(page_ids[1], &mut page).unwrap();
+        assert_eq!(0xA1, page[0]);
+        backup.read_page_data(extra, &mut page).unwrap();
+        assert_eq!(0xAA, page[0]);
+    }
+
+    #[test]
+    fn test_backup_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = [0x42; crate::disk::ENCRYPTION_KEY_SIZE];
+        let disk = crate::disk::EncryptedDiskManager::create(MemoryDiskManager::new(), &key);
+        let disk = disk.unwrap();
+        let pool = BufferPool::new(3).with_page_size(disk.page_size());
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        bufmgr.create_page().unwrap();
+        // 平文のバックアップを作らない
+        match bufmgr.backup_to(&dir.path().join("backup")) {
+            Err(Error::Io { op: IoOp::Backup, source, .. }) => {
+                assert_eq!(io::ErrorKind::Unsupported, source.kind())
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert!(!dir.path().join("backup").exists());
+    }
+
+    #[test]
+    fn test_backup_read_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_path = dir.path().join("backup");
+        // 一度も書き込まれていないページは、読めなくても飛ばす
+        let mut disk = MemoryDiskManager::new();
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &[1; PAGE_SIZE]).unwrap();
+        disk.allocate_page().unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(3));
+        bufmgr.backup_to(&backup_path).unwrap();
+        let mut backup = DiskManager::open(&backup_path).unwrap();
+        assert_eq!(PageId(2), backup.next_page_id());
+        let mut page = vec![0; PAGE_SIZE];
+        backup.read_page_data(page_id, &mut page).unwrap();
+        assert_eq!(1, page[0]);
+
+        // 途中で切れたファイルからは、バックアップを作らない
+        let heap_path = dir.path().join("heap");
+        let mut disk = DiskManager::create_compressed(&heap_path).unwrap();
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &[1; PAGE_SIZE]).unwrap();
+        disk.sync().unwrap();
+        drop(disk);
+        let file = std::fs::OpenOptions::new().write(true).open(&heap_path).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 1).unwrap();
+        drop(file);
+        let disk = DiskManager::open(&heap_path).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(3));
+        assert!(bufmgr.backup_to(&dir.path().join("backup2")).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut disk = DiskManager::create(dir.path().join("heap")).unwrap();
+        let tablespace_id = disk.create_tablespace("index").unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(4));
+        let tablespace_page_ids: Vec<_> = (0..4)
+            .map(|_| bufmgr.create_page_in(tablespace_id).unwrap().page_id)
+            .collect();
+        for &page_id in &tablespace_page_ids[1..] {
+            bufmgr.free_page(page_id).unwrap();
+        }
+        let tablespace_path = dir.path().join("heap.index.tbs");
+        let tablespace_size = std::fs::metadata(&tablespace_path).unwrap().len();
+        assert!(bufmgr.compact().unwrap() >= tablespace_size - 2 * PAGE_SIZE as u64);
+        // 表領域のファイルも縮める
+        assert_eq!(2 * PAGE_SIZE as u64, std::fs::metadata(&tablespace_path).unwrap().len());
+        assert_eq!(tablespace_page_ids[1], bufmgr.create_page_in(tablespace_id).unwrap().page_id);
+        bufmgr.free_page(tablespace_page_ids[1]).unwrap();
+        bufmgr.free_page(tablespace_page_ids[0]).unwrap();
+        bufmgr.compact().unwrap();
+
+        let page_ids: Vec<_> = (0..10)
+            .map(|_| {
+                let buffer = bufmgr.create_page().unwrap();
+                buffer.data_mut().fill(buffer.page_id.to_u64() as u8 + 1);
+                buffer.page_id
+            })
+            .collect();
+        for &page_id in &page_ids[4..] {
+            bufmgr.free_page(page_id).unwrap();
+        }
+        assert!(bufmgr.compact().unwrap() > 0);
+        let info = bufmgr.storage_info().unwrap();
+        assert_eq!(4, info.num_pages);
+        assert_eq!(PageId(4), info.next_page_id);
+        // 空になった表領域のファイルには、ヘッダページだけが残る
+        assert_eq!(6 * PAGE_SIZE as u64, info.file_size_bytes);
+        // 残したページは書き出されている
+        for &page_id in &page_ids[..4] {
+            let buffer = bufmgr.fetch_page(page_id).unwrap();
+            assert_eq!(page_id.to_u64() as u8 + 1, buffer.data()[0]);
+        }
+        assert_eq!(PageId(4), bufmgr.create_page().unwrap().page_id);
+    }
+
+    #[test]
+    fn test_eviction_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let disk = DiskManager::new(data_file).unwrap();
+        let pool = BufferPool::new(4);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let page_ids: Vec<_> = (0..4)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        bufmgr.flush().unwrap();
+
+        // 同じアクセスパターンで追い出されたページを返す
+        let run = |pool: BufferPool| {
+            let disk = DiskManager::open(&data_file_path).unwrap();
+            let mut bufmgr = BufferPoolManager::new(disk, pool);
+            for &i in &[0, 1, 2, 0, 0, 0, 1, 2, 3] {
+                bufmgr.fetch_page(page_ids[i]).unwrap();
+            }
+            page_ids[..3]
+                .iter()
+                .copied()
+                .find(|page_id| !bufmgr.page_table.contains_key(page_id))
+                .unwrap()
+        };
+        assert_eq!(page_ids[1], run(BufferPool::new(3)));
+        assert_eq!(page_ids[0], run(BufferPool::new_with_policy(3, Lru)));
+    }
+
+    #[test]
+    fn test_max_usage_count() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(1);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let hot_page_id = bufmgr.create_page().unwrap().page_id;
+        for _ in 0..1000 {
+            bufmgr.fetch_page(hot_page_id).unwrap();
+        }
+        assert_eq!(DEFAULT_MAX_USAGE_COUNT, bufmgr.pool[BufferId(0)].usage_count());
+
+        // 上限回数デクリメントするだけで追い出せる
+        let mut policy = ClockSweep::default();
+        assert_eq!(Some(BufferId(0)), policy.pick_victim(&mut bufmgr.pool.buffers));
+        assert_eq!(0, bufmgr.pool[BufferId(0)].usage_count());
+    }
+
+    #[test]
+    fn test_resize() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(2);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let page_ids: Vec<_> = (0..4u8)
+            .map(|i| {
+                let buffer = bufmgr.create_page().unwrap();
+                buffer.page.borrow_mut()[0] = i;
+                buffer.page_id
+            })
+            .collect();
+
+        // 拡大
+        bufmgr.resize(4).unwrap();
+        assert_eq!(4, bufmgr.pool.size());
+        let buffers: Vec<_> = page_ids
+            .iter()
+            .map(|&page_id| bufmgr.fetch_page(page_id).unwrap())
+            .collect();
+        assert!(matches!(
+            bufmgr.fetch_page(PageId(100)),
+            Err(Error::NoFreeBuffer { .. })
+        ));
+
+        // 貸出中のフレームがあると縮小できない
+        assert!(matches!(bufmgr.resize(1), Err(Error::PinnedBuffer(_))));
+        assert_eq!(4, bufmgr.pool.size());
+        drop(buffers);
+
+        // 縮小
+        bufmgr.resize(1).unwrap();
+        assert_eq!(1, bufmgr.pool.size());
+        assert!(bufmgr.page_table.len() <= 1);
+        assert!(bufmgr.page_table.values().all(|buffer_id| buffer_id.0 < 1));
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            let buffer = bufmgr.fetch_page(page_id).unwrap();
+            assert_eq!(page_id, buffer.page_id);
+            assert_eq!(i as u8, buffer.page.borrow()[0]);
+        }
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(4);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let page_ids: Vec<_> = (0..8)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        bufmgr.flush().unwrap();
+
+        bufmgr.reset_stats();
+        assert_eq!(4, bufmgr.prefetch(&page_ids[..4]).unwrap());
+        assert_eq!(4, bufmgr.stats().misses);
+        for &page_id in &page_ids[..4] {
+            bufmgr.fetch_page(page_id).unwrap();
+        }
+        assert_eq!(4, bufmgr.stats().hits);
+        assert_eq!(4, bufmgr.stats().misses);
+
+        // プールに収まらない分は読み込まない
+        let _pinned = bufmgr.fetch_page(page_ids[0]).unwrap();
+        assert_eq!(3, bufmgr.prefetch(&page_ids[4..]).unwrap());
+    }
+
+    #[test]
+    fn test_bulk_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let disk = DiskManager::new(data_file).unwrap();
+        let pool = BufferPool::new(16);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let page_ids: Vec<_> = (0..104)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        bufmgr.flush().unwrap();
+        drop(bufmgr);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let pool = BufferPool::new(16);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let (hot_page_ids, scan_page_ids) = page_ids.split_at(4);
+        for _ in 0..3 {
+            for &page_id in hot_page_ids {
+                bufmgr.fetch_page(page_id).unwrap();
+            }
+        }
+        for &page_id in scan_page_ids {
+            bufmgr
+                .fetch_page_with_strategy(page_id, AccessStrategy::BulkRead)
+                .unwrap();
+        }
+        bufmgr.reset_stats();
+        for &page_id in hot_page_ids {
+            bufmgr.fetch_page(page_id).unwrap();
+        }
+        assert_eq!(4, bufmgr.stats().hits);
+        assert_eq!(0, bufmgr.stats().misses);
+    }
+
+    #[test]
+    fn test_flush_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let disk = DiskManager::new(data_file).unwrap();
+        let pool = BufferPool::new(4);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let page_ids: Vec<_> = (0..2)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        bufmgr.fetch_page(page_ids[0]).unwrap().page.borrow_mut()[..5].copy_from_slice(b"hello");
+        bufmgr.fetch_page(page_ids[1]).unwrap().page.borrow_mut()[..5].copy_from_slice(b"world");
+        drop(bufmgr);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let pool = BufferPool::new(4);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        assert_eq!(b"hello", &bufmgr.fetch_page(page_ids[0]).unwrap().page.borrow()[..5]);
+        assert_eq!(b"world", &bufmgr.fetch_page(page_ids[1]).unwrap().page.borrow()[..5]);
+
+        let buffer = bufmgr.create_page().unwrap();
+        buffer.page.borrow_mut()[..5].copy_from_slice(b"close");
+        let page_id = buffer.page_id;
+        drop(buffer);
+        bufmgr.close().unwrap();
+
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        let mut buf = vec![0; PAGE_SIZE];
+        disk.read_page_data(page_id, &mut buf).unwrap();
+        assert_eq!(b"close", &buf[..5]);
+    }
+
+    #[test]
+    fn test_pin_unpin() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let pool = BufferPool::new(1);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let page_a_id = bufmgr.create_page().unwrap().page_id;
+        let page_b_id = bufmgr.create_page().unwrap().page_id;
+
+        let buffer1 = bufmgr.fetch_page(page_a_id).unwrap();
+        let buffer2 = bufmgr.fetch_page(page_a_id).unwrap();
+        assert_eq!(2, buffer1.pin_count());
+        assert!(matches!(bufmgr.fetch_page(page_b_id), Err(Error::NoFreeBuffer { .. })));
+
+        // ひとつ外してもまだピン留めされている
+        buffer1.unpin();
+        assert_eq!(1, buffer2.pin_count());
+        assert!(matches!(bufmgr.fetch_page(page_b_id), Err(Error::NoFreeBuffer { .. })));
+
+        // 最後のひとつを外すと追い出せるようになる
+        drop(buffer2);
+        let buffer = bufmgr.fetch_page(page_b_id).unwrap();
+        assert_eq!(page_b_id, buffer.page_id);
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        use std::fs::OpenOptions;
+        use std::io::{prelude::*, SeekFrom};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let disk = DiskManager::new(data_file).unwrap();
+        let pool = BufferPool::new(4);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        let page_ids: Vec<_> = (0..2)
+            .map(|_| {
+                let buffer = bufmgr.create_page().unwrap();
+                buffer.data_mut()[..5].copy_from_slice(b"hello");
+                buffer.page_id
+            })
+            .collect();
+        bufmgr.close().unwrap();
+
+        // 一度も書き込まれていないページはチェックしない
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        let zero_page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(zero_page_id, &[0; PAGE_SIZE]).unwrap();
+        drop(disk);
+
+        // ファイルの1バイトを書き換える。先頭のヘッダページの分ずらす
+        let mut file = OpenOptions::new().write(true).open(&data_file_path).unwrap();
+        file.seek(SeekFrom::Start(PAGE_SIZE as u64 * (page_ids[1].to_u64() + 1) + 1))
+            .unwrap();
+        file.write_all(b"E").unwrap();
+        drop(file);
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let pool = BufferPool::new(4);
+        let mut bufmgr = BufferPoolManager::new(disk, pool);
+        assert_eq!(b"hello", &bufmgr.fetch_page(page_ids[0]).unwrap().data()[..5]);
+        bufmgr.fetch_page(zero_page_id).unwrap();
+        match bufmgr.fetch_page(page_ids[1]) {
+            Err(Error::ChecksumMismatch { page_id }) => assert_eq!(page_ids[1], page_id),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // 壊れたページはキャッシュされない
+        assert!(!bufmgr.page_table.contains_key(&page_ids[1]));
+    }
+
+    #[test]
+    fn test_on_pool_full() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(2));
+        let page_ids: Vec<_> = (0..4)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+
+        let _held: Vec<_> = page_ids[..2]
+            .iter()
+            .map(|&page_id| bufmgr.fetch_page(page_id).unwrap())
+            .collect();
+        assert!(matches!(
+            bufmgr.fetch_page(page_ids[2]),
+            Err(Error::NoFreeBuffer { .. })
+        ));
+
+        // シングルスレッドでは待っても空かないので、すぐに失敗する
+        bufmgr.set_on_pool_full(OnPoolFull::Wait {
+            timeout: Duration::from_secs(10),
+        });
+        assert!(matches!(
+            bufmgr.fetch_page(page_ids[2]),
+            Err(Error::NoFreeBuffer { .. })
+        ));
+
+        bufmgr.set_on_pool_full(OnPoolFull::Grow { max: 3 });
+        let buffer = bufmgr.fetch_page(page_ids[2]).unwrap();
+        assert_eq!(page_ids[2], buffer.page_id);
+        assert_eq!(3, bufmgr.pool.size());
+        assert!(matches!(
+            bufmgr.fetch_page(page_ids[3]),
+            Err(Error::NoFreeBuffer { .. })
+        ));
+        assert!(matches!(bufmgr.create_page(), Err(Error::NoFreeBuffer { .. })));
+
+        // 増やしたフレームは貸出中のあいだは外せない
+        assert_eq!(0, bufmgr.shrink_grown_frames().unwrap());
+        drop(buffer);
+        assert_eq!(1, bufmgr.shrink_grown_frames().unwrap());
+        assert_eq!(2, bufmgr.pool.size());
+        assert_eq!(0, bufmgr.shrink_grown_frames().unwrap());
+        // 外したあとも、必要になればまた増やす
+        let _buffer = bufmgr.fetch_page(page_ids[2]).unwrap();
+        assert_eq!(3, bufmgr.pool.size());
+    }
+
+    #[test]
+    fn test_prefer_clean_victim() {
+        for dirty_idx in 0..2 {
+            let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+            let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(2));
+            let page_ids: Vec<_> = (0..3)
+                .map(|_| bufmgr.create_page().unwrap().page_id)
+                .collect();
+            bufmgr.flush().unwrap();
+            for &page_id in &page_ids[..2] {
+                bufmgr.fetch_page(page_id).unwrap();
+            }
+            let buffer = bufmgr.fetch_page(page_ids[dirty_idx]).unwrap();
+            bufmgr.mark_dirty(&buffer);
+            drop(buffer);
+            bufmgr.reset_stats();
+
+            bufmgr.fetch_page(page_ids[2]).unwrap();
+            assert!(bufmgr.page_table.contains_key(&page_ids[dirty_idx]));
+            assert!(!bufmgr.page_table.contains_key(&page_ids[1 - dirty_idx]));
+            let stats = bufmgr.stats();
+            assert_eq!(1, stats.clean_evictions);
+            assert_eq!(0, stats.dirty_evictions);
+            assert_eq!(0, stats.dirty_writes);
+        }
+
+        // cleanなバッファがなければdirtyなバッファを追い出す
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        bufmgr.create_page().unwrap();
+        bufmgr.create_page().unwrap();
+        let stats = bufmgr.stats();
+        assert_eq!(1, stats.dirty_evictions);
+        assert_eq!(1, stats.dirty_writes);
+    }
+
+    #[test]
+    fn test_mark_dirty() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(100));
+        let page_ids: Vec<_> = (0..100)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        bufmgr.flush().unwrap();
+        assert!(bufmgr.dirty.borrow().is_empty());
+
+        for &page_id in &page_ids[10..13] {
+            let buffer = bufmgr.fetch_page(page_id).unwrap();
+            buffer.data_mut()[0] = 1;
+            bufmgr.mark_dirty(&buffer);
+        }
+        bufmgr.reset_stats();
+        bufmgr.flush().unwrap();
+        assert_eq!(3, bufmgr.stats().writes);
+        assert!(bufmgr.dirty.borrow().is_empty());
+
+        // 追い出しで書き出されたバッファは集合から外れる
+        let mut bufmgr = BufferPoolManager::new(
+            DiskManager::new(tempfile().unwrap()).unwrap(),
+            BufferPool::new(1),
+        );
+        bufmgr.create_page().unwrap();
+        let page_id = bufmgr.create_page().unwrap().page_id;
+        assert_eq!(1, bufmgr.dirty.borrow().len());
+        bufmgr.reset_stats();
+        bufmgr.flush().unwrap();
+        assert_eq!(1, bufmgr.stats().writes);
+        assert!(!bufmgr.fetch_page(page_id).unwrap().is_dirty());
+    }
+
+    #[test]
+    fn test_evict_fresh_frame() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(3));
+        let page0_id = bufmgr.create_page().unwrap().page_id;
+        assert_eq!(PageId(0), page0_id);
+
+        // ページ0をキャッシュしたまま、未使用のフレームを追い出させる
+        bufmgr.create_page().unwrap();
+        bufmgr.create_page().unwrap();
+        assert_eq!(3, bufmgr.page_table.len());
+
+        bufmgr.reset_stats();
+        let buffer = bufmgr.fetch_page(page0_id).unwrap();
+        assert_eq!(page0_id, buffer.page_id);
+        assert_eq!(1, bufmgr.stats().hits);
+        assert_eq!(0, bufmgr.stats().misses);
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let disk = DiskManager::new(data_file).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(4));
+        let page_ids: Vec<_> = (0..8)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        bufmgr.close().unwrap();
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(4));
+        for _ in 0..3 {
+            for &page_id in &page_ids[2..6] {
+                bufmgr.fetch_page(page_id).unwrap();
+            }
+        }
+        let mut hot = bufmgr.save_cached_page_ids();
+        assert_eq!(4, hot.len());
+        hot.sort_by_key(|page_id| page_id.to_u64());
+        assert_eq!(&page_ids[2..6], &hot[..]);
+        bufmgr.close().unwrap();
+
+        // プールより多いページを渡しても、入る分だけ読み込む
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(3));
+        assert_eq!(3, bufmgr.warm_up(&hot).unwrap());
+        assert!(bufmgr
+            .pool
+            .buffers
+            .iter()
+            .all(|frame| !frame.is_pinned() && frame.usage_count() == 1));
+
+        bufmgr.reset_stats();
+        for &page_id in &hot[..3] {
+            bufmgr.fetch_page(page_id).unwrap();
+        }
+        assert_eq!(3, bufmgr.stats().hits);
+        assert_eq!(0, bufmgr.stats().misses);
+    }
+
+    #[test]
+    fn test_leak_detection() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(2));
+        let clock = Rc::new(MockClock::new());
+        bufmgr.enable_leak_detection(clock.clone());
+        let page_ids: Vec<_> = (0..3)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+
+        let leaked = bufmgr.fetch_page(page_ids[1]).unwrap();
+        clock.advance(Duration::from_secs(60));
+        let held = bufmgr.fetch_page(page_ids[2]).unwrap();
+        clock.advance(Duration::from_secs(1));
+        let fresh = bufmgr.fetch_page(page_ids[2]).unwrap();
+
+        let pins = bufmgr.pinned_longer_than(Duration::from_secs(30));
+        assert_eq!(1, pins.len());
+        assert_eq!(page_ids[1], pins[0].page_id);
+        assert_eq!(Duration::from_secs(61), pins[0].pinned_for);
+        // 貸し出したばかりのものは経過時間が0
+        assert_eq!(2, bufmgr.pinned_longer_than(Duration::ZERO).len());
+
+        match bufmgr.fetch_page(page_ids[0]) {
+            Err(err @ Error::NoFreeBuffer { .. }) => {
+                let message = format!("oldest pin: page {}", page_ids[1].to_u64());
+                assert!(err.to_string().contains(&message));
+                assert!(matches!(
+                    err,
+                    Error::NoFreeBuffer { oldest_pin: Some(page_id) } if page_id == page_ids[1]
+                ));
+            }
+            other => panic!("unexpected result: {:?}", other.map(|buffer| buffer.page_id)),
+        }
+
+        // 返したバッファは報告されない
+        drop(leaked);
+        drop(fresh);
+        assert!(bufmgr.pinned_longer_than(Duration::from_secs(30)).is_empty());
+        assert_eq!(1, bufmgr.pinned_longer_than(Duration::ZERO).len());
+        drop(held);
+    }
+
+    #[test]
+    fn test_sticky_priority() {
+        // 1つのページを3回に1回参照しながら、ほかのページを次々に読み込む
+        let hit_rate = |priority| {
+            let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+            let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(3));
+            let page_ids: Vec<_> = (0..100)
+                .map(|_| bufmgr.create_page().unwrap().page_id)
+                .collect();
+            bufmgr.flush().unwrap();
+            let hot_page_id = page_ids[0];
+            bufmgr.set_priority(hot_page_id, priority);
+            let mut hits = 0;
+            for chunk in page_ids[1..].chunks(3) {
+                let before = bufmgr.stats().hits;
+                bufmgr.fetch_page(hot_page_id).unwrap();
+                hits += bufmgr.stats().hits - before;
+                for &page_id in chunk {
+                    bufmgr.fetch_page(page_id).unwrap();
+                }
+            }
+            hits as f64 / page_ids[1..].chunks(3).count() as f64
+        };
+        let normal = hit_rate(Priority::Normal);
+        let sticky = hit_rate(Priority::Sticky);
+        assert!(sticky > 0.9, "sticky hit rate: {}", sticky);
+        assert!(sticky > normal, "sticky: {}, normal: {}", sticky, normal);
+    }
+
+    #[test]
+    fn test_io_error_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &[1; PAGE_SIZE]).unwrap();
+        drop(disk);
+        // 読み込みしかできないファイル
+        let file = std::fs::File::open(&data_file_path).unwrap();
+        let disk = DiskManager::new(file).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+
+        // 範囲外のページはシークする前に弾く
+        match bufmgr.fetch_page(PageId(42)) {
+            Err(err @ Error::Disk(_)) => {
+                assert!(matches!(
+                    err,
+                    Error::Disk(disk::Error::PageOutOfRange { page_id: PageId(42), num_pages: 1 })
+                ));
+                assert_eq!("page 42 is out of range (1 pages allocated)", err.to_string());
+            }
+            other => panic!("unexpected result: {:?}", other.map(|buffer| buffer.page_id)),
+        }
+
+        // ページの採番はヘッダを書けずに失敗する
+        match bufmgr.create_page() {
+            Err(err) => assert!(matches!(err, Error::Io { op: IoOp::Allocate, .. })),
+            Ok(buffer) => panic!("create_page should fail: {:?}", buffer.page_id),
+        }
+
+        // 書き出しに失敗したページのIDと操作がわかる
+        let buffer = bufmgr.fetch_page(page_id).unwrap();
+        buffer.data_mut()[0] = 2;
+        bufmgr.mark_dirty(&buffer);
+        drop(buffer);
+        match bufmgr.flush() {
+            Err(err) => {
+                assert!(matches!(err, Error::Io { op: IoOp::Write, .. }));
+                let message = format!("failed to write page {}: ", page_id.to_u64());
+                assert!(err.to_string().starts_with(&message));
+            }
+            Ok(()) => panic!("flush should fail"),
+        }
+        // Dropでの書き出しも失敗するが、panicはしない
+        drop(bufmgr);
+    }
+
+    #[test]
+    fn test_last_access() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(4));
+        let page_ids: Vec<_> = (0..4)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        for &idx in &[2, 0, 3, 0, 1] {
+            bufmgr.fetch_page(page_ids[idx]).unwrap();
+        }
+        assert_eq!(
+            vec![page_ids[2], page_ids[3], page_ids[0]],
+            bufmgr.coldest_pages(3)
+        );
+        assert_eq!(4, bufmgr.coldest_pages(10).len());
+
+        let seq = bufmgr.stats().access_seq;
+        assert_eq!(9, seq);
+        let frames = bufmgr.dump();
+        let info = frames.iter().find(|info| info.page_id == page_ids[1]).unwrap();
+        assert_eq!(seq, info.last_access);
+    }
+
+    #[test]
+    fn test_snapshot_page() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        let page_id = {
+            let buffer = bufmgr.create_page().unwrap();
+            buffer.data_mut()[..5].copy_from_slice(b"hello");
+            buffer.page_id
+        };
+
+        let snapshot = bufmgr.snapshot_page(page_id).unwrap();
+        assert_eq!(b"hello", &snapshot[..5]);
+        {
+            let buffer = bufmgr.fetch_page_write(page_id).unwrap();
+            buffer.data_mut()[..5].copy_from_slice(b"world");
+            assert_eq!(b"world", &buffer.snapshot()[..5]);
+        }
+        assert_eq!(b"hello", &snapshot[..5]);
+
+        // ピン留めは残っていないので、すぐに追い出せる
+        let snapshot = bufmgr.snapshot_page(page_id).unwrap();
+        assert_eq!(0, bufmgr.pool[bufmgr.page_table[&page_id]].buffer.pin_count());
+        let other_page_id = bufmgr.create_page().unwrap().page_id;
+        assert!(!bufmgr.page_table.contains_key(&page_id));
+        assert_ne!(page_id, other_page_id);
+        assert_eq!(b"world", &snapshot[..5]);
+    }
+
+    #[test]
+    fn test_peek_page() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        let page_id = {
+            let buffer = bufmgr.create_page().unwrap();
+            buffer.data_mut()[..5].copy_from_slice(b"hello");
+            buffer.page_id
+        };
+        bufmgr.reset_stats();
+        assert_eq!(b"hello", &bufmgr.peek_page(page_id).unwrap()[..5]);
+        assert_eq!(0, bufmgr.stats().hits + bufmgr.stats().misses);
+        assert_eq!(0, bufmgr.pool[bufmgr.page_table[&page_id]].buffer.pin_count());
+
+        // 追い出したページは読み込まない
+        bufmgr.create_page().unwrap();
+        assert!(bufmgr.peek_page(page_id).is_none());
+    }
+
+    #[test]
+    fn test_free_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let disk = DiskManager::new(data_file).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(1));
+        let page_ids: Vec<_> = (0..8)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        bufmgr.close().unwrap();
+
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(4));
+        for &page_id in &page_ids[..4] {
+            bufmgr.fetch_page(page_id).unwrap();
+        }
+        assert_eq!(4, bufmgr.stats().misses);
+        assert_eq!(0, bufmgr.stats().sweeps);
+        bufmgr.fetch_page(page_ids[4]).unwrap();
+        assert_eq!(1, bufmgr.stats().sweeps);
+
+        // 読み込みに失敗したフレームは空きに戻る
+        assert!(bufmgr.fetch_page(PageId(100)).is_err());
+        bufmgr.reset_stats();
+        bufmgr.fetch_page(page_ids[5]).unwrap();
+        assert_eq!(0, bufmgr.stats().sweeps);
+
+        // 拡大したフレームも空きリストから使う
+        bufmgr.resize(6).unwrap();
+        for &page_id in &page_ids[6..8] {
+            bufmgr.fetch_page(page_id).unwrap();
+        }
+        assert_eq!(0, bufmgr.stats().sweeps);
+        assert_eq!(6, bufmgr.page_table_len());
+        bufmgr.assert_consistent();
+    }
+
+    #[test]
+    fn test_sync_after_flush() {
+        let storage = RecordingStorage::default();
+        let log = Arc::clone(&storage.log);
+        let mut bufmgr = BufferPoolManager::new(storage, BufferPool::new(4));
+        let page_ids: Vec<_> = (0..2)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        bufmgr.flush().unwrap();
+        // 書き出しがすべて終わってからsyncする
+        assert_eq!(
+            vec!["write 0", "write 1", "sync"],
+            log.lock().unwrap().drain(..).collect::<Vec<_>>()
+        );
+
+        // dirtyなページがなくてもsyncは呼ばれる
+        bufmgr.flush().unwrap();
+        assert_eq!(vec!["sync"], log.lock().unwrap().drain(..).collect::<Vec<_>>());
+
+        bufmgr.set_sync_mode(SyncMode::Data);
+        let buffer = bufmgr.fetch_page(page_ids[1]).unwrap();
+        bufmgr.mark_dirty(&buffer);
+        drop(buffer);
+        bufmgr.close().unwrap();
+        assert_eq!(
+            vec!["write 1", "sync_data"],
+            log.lock().unwrap().drain(..).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_free_page() {
+        let mut bufmgr = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(4));
+        let buffer = bufmgr.create_page().unwrap();
+        let page_id = buffer.page_id;
+        assert!(matches!(bufmgr.free_page(page_id), Err(Error::PinnedBuffer(_))));
+        drop(buffer);
+        let other_page_id = bufmgr.create_page().unwrap().page_id;
+
+        bufmgr.free_page(page_id).unwrap();
+        assert_eq!(1, bufmgr.page_table_len());
+        bufmgr.assert_consistent();
+        // 解放したページは書き出されない
+        bufmgr.flush().unwrap();
+        assert_eq!(1, bufmgr.stats().writes);
+
+        // 解放したページIDが再利用される
+        let buffer = bufmgr.create_page().unwrap();
+        assert_eq!(page_id, buffer.page_id);
+        assert_ne!(other_page_id, buffer.page_id);
+        drop(buffer);
+        assert!(matches!(
+            bufmgr.free_page(PageId(100)),
+            Err(Error::Io { op: IoOp::Free, .. })
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "page size must match")]
+    fn test_page_size_mismatch() {
+        let disk = MemoryDiskManager::with_page_size(512).unwrap();
+        BufferPoolManager::new(disk, BufferPool::new(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid page size 1001")]
+    fn test_invalid_page_size() {
+        // 2のべき乗でも、そこから暗号化の分を引いた大きさでもなければ受け付けない
+        BufferPool::new(4).with_page_size(disk::PAGE_SIZE - disk::ENCRYPTION_OVERHEAD);
+        BufferPool::new(4).with_page_size(1001);
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::{BTreeSet, HashSet};
+use std::io;
+use std::path::Path;
+
+use super::{read_page, BufferPoolManager, Error, IoOp};
+use crate::disk::{self, DiskManager, PageId, Storage, TablespaceId};
+
+// 実行中のオンラインバックアップ
+#[derive(Debug)]
+struct Backup {
+    dest: DiskManager,
+    // 次にコピーするページ
+    next_page_id: u64,
+    // コピーしたあとにディスクへ書き出されたページ。終えるときにコピーし直す
+    written: BTreeSet<PageId>,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct BackupTracker(Option<Backup>);
+
+impl BackupTracker {
+    // ディスクに書き出したページを記録する
+    // まだコピーしていないページは、あとでコピーするときに新しい内容を読むので記録しない
+    pub(super) fn record(&mut self, page_id: PageId) {
+        if let Some(backup) = &mut self.0 {
+            if page_id.to_u64() < backup.next_page_id {
+                backup.written.insert(page_id);
+            }
+        }
+    }
+}
+
+fn backup_error(page_id: PageId, source: io::Error) -> Error {
+    Error::Io {
+        page_id,
+        op: IoOp::Backup,
+        source,
+    }
+}
+
+fn no_backup() -> Error {
+    backup_error(
+        PageId::INVALID_PAGE_ID,
+        io::Error::new(io::ErrorKind::InvalidInput, "no backup in progress"),
+    )
+}
+
+// コピー先の表領域を、page_idの手前まで採番しておく
+fn extend_dest(dest: &mut DiskManager, page_id: PageId) -> Result<(), Error> {
+    let tablespace_id = page_id.tablespace_id();
+    loop {
+        let next_page_id = dest
+            .next_page_id_in(tablespace_id)
+            .map_err(|source| backup_error(page_id, source))?;
+        if next_page_id.to_u64() >= page_id.to_u64() {
+            return Ok(());
+        }
+        dest.allocate_page_in(tablespace_id)
+            .map_err(|source| backup_error(page_id, source))?;
+    }
+}
+
+// ページをコピー先に書き写す
+// 一度も書き込まれていないページは、コピー先でも書き込まないままにする
+fn copy_page(
+    disk: &mut dyn Storage,
+    dest: &mut DiskManager,
+    page_id: PageId,
+    page: &mut [u8],
+) -> Result<(), Error> {
+    extend_dest(dest, PageId(page_id.to_u64() + 1))?;
+    match read_page(disk, page_id, page) {
+        Ok(()) => {}
+        Err(Error::Disk(disk::Error::ShortRead { .. })) => return Ok(()),
+        Err(err) => return Err(err),
+    }
+    dest.write_page_data(page_id, page)
+        .map_err(|source| backup_error(page_id, source))
+}
+
+// 書き込みを止めずにヒープファイルのバックアップを取る
+// デフォルトの表領域から順に、表領域ごとにページを1つずつコピーし、
+// コピーしたあとに書き出されたページは最後にコピーし直す
+// 暗号化したストレージは、平文のバックアップを作らないよう断る
+impl BufferPoolManager {
+    pub fn backup_to(&mut self, path: &Path) -> Result<(), Error> {
+        self.start_backup(path)?;
+        self.finish_backup()
+    }
+
+    // dirtyなページを書き出してから、コピー先のファイルと表領域を作る
+    pub fn start_backup(&mut self, path: &Path) -> Result<(), Error> {
+        if self.backup.0.is_some() {
+            return Err(backup_error(
+                PageId::INVALID_PAGE_ID,
+                io::Error::new(io::ErrorKind::InvalidInput, "backup already in progress"),
+            ));
+        }
+        if self.disk.is_encrypted() {
+            return Err(backup_error(
+                PageId::INVALID_PAGE_ID,
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "encrypted storage cannot be backed up as plaintext",
+                ),
+            ));
+        }
+        self.flush()?;
+        let mut dest = DiskManager::create_with_page_size(path, self.disk.page_size())
+            .map_err(|source| backup_error(PageId::INVALID_PAGE_ID, source))?;
+        for (_, name) in self.disk.tablespaces() {
+            dest.create_tablespace(&name)
+                .map_err(|source| backup_error(PageId::INVALID_PAGE_ID, source))?;
+        }
+        self.backup.0 = Some(Backup {
+            dest,
+            next_page_id: 0,
+            written: BTreeSet::new(),
+        });
+        Ok(())
+    }
+
+    // デフォルトの表領域に続けて、作った順にそれぞれの表領域で次に採番するページID
+    // ページIDは表領域の順に大きくなるので、コピーする位置を1つの数で表せる
+    fn backup_ends(&self) -> Result<Vec<PageId>, Error> {
+        let mut tablespace_ids = vec![TablespaceId::DEFAULT];
+        tablespace_ids.extend(self.disk.tablespaces().into_iter().map(|(id, _)| id));
+        tablespace_ids
+            .into_iter()
+            .map(|tablespace_id| {
+                self.disk
+                    .next_page_id_in(tablespace_id)
+                    .map_err(|source| backup_error(PageId::INVALID_PAGE_ID, source))
+            })
+            .collect()
+    }
+
+    // 最大max_pages個のページをコピーする
+    // すべての表領域で、採番済みのページをコピーし終えたらtrueを返す
+    pub fn backup_step(&mut self, max_pages: usize) -> Result<bool, Error> {
+        let ends = self.backup_ends()?;
+        let backup = self.backup.0.as_mut().ok_or_else(no_backup)?;
+        let mut page = vec![0; self.disk.page_size()];
+        let mut copied = 0;
+        for end in ends.iter() {
+            // 前の表領域をコピーし終えたら、次の表領域の先頭に進む
+            let start = PageId::new(end.tablespace_id(), 0).to_u64();
+            if backup.next_page_id < start {
+                backup.next_page_id = start;
+            }
+            while backup.next_page_id < end.to_u64() {
+                if copied >= max_pages {
+                    return Ok(false);
+                }
+                let page_id = PageId(backup.next_page_id);
+                copy_page(self.disk.as_mut(), &mut backup.dest, page_id, &mut page)?;
+                backup.next_page_id += 1;
+                copied += 1;
+            }
+        }
+        Ok(true)
+    }
+
+    // 残りのページをコピーし、書き出されたページをコピーし直して、
+    // 表領域ごとに採番したページと空きページのリストをそろえ、メタページのIDを書く
+    pub fn finish_backup(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.backup_step(usize::MAX)?;
+        let ends = self.backup_ends()?;
+        let mut backup = self.backup.0.take().ok_or_else(no_backup)?;
+        let mut free_page_ids = vec![];
+        for end in ends.iter() {
+            let page_ids = self
+                .disk
+                .free_page_ids_in(end.tablespace_id())
+                .map_err(|source| backup_error(PageId::INVALID_PAGE_ID, source))?;
+            free_page_ids.push(page_ids);
+        }
+        let free: HashSet<_> = free_page_ids.iter().flatten().copied().collect();
+        let mut page = vec![0; self.disk.page_size()];
+        for &page_id in backup.written.iter().filter(|page_id| !free.contains(page_id)) {
+            copy_page(self.disk.as_mut(), &mut backup.dest, page_id, &mut page)?;
+        }
+        let dest = &mut backup.dest;
+        for (&end, page_ids) in ends.iter().zip(free_page_ids.iter()) {
+            // コピーし終えた表領域でも、そのあとに採番されたページがある
+            extend_dest(dest, end)?;
+            // 空きページのリストの先頭に積んでいくので、末尾から解放する
+            for &page_id in page_ids.iter().rev() {
+                dest.deallocate_page(page_id)
+                    .map_err(|source| backup_error(page_id, source))?;
+            }
+        }
+        dest.set_meta_page_id(self.disk.meta_page_id())
+            .and_then(|()| dest.sync())
+            .map_err(|source| backup_error(PageId::INVALID_PAGE_ID, source))
+    }
+}
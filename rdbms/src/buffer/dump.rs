@@ -0,0 +1,153 @@
+use std::fmt;
+
+use super::{BufferId, BufferPoolManager};
+use crate::disk::PageId;
+
+// デバッグ用のフレームの状態
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FrameInfo {
+    pub buffer_id: BufferId,
+    pub page_id: PageId,
+    pub usage_count: u64,
+    pub last_access: u64,
+    pub pin_count: usize,
+    pub is_dirty: bool,
+}
+
+// FrameInfoの一覧を表として表示する
+pub struct FrameTable<'a>(pub &'a [FrameInfo]);
+
+impl fmt::Display for FrameTable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>9} {:>20} {:>11} {:>11} {:>9} {:>5}",
+            "buffer_id", "page_id", "usage_count", "last_access", "pin_count", "dirty"
+        )?;
+        for info in self.0 {
+            let page_id = match info.page_id.valid() {
+                Some(page_id) => page_id.to_u64().to_string(),
+                None => "-".to_string(),
+            };
+            writeln!(
+                f,
+                "{:>9} {:>20} {:>11} {:>11} {:>9} {:>5}",
+                info.buffer_id.0,
+                page_id,
+                info.usage_count,
+                info.last_access,
+                info.pin_count,
+                info.is_dirty
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl BufferPoolManager {
+    // すべてのフレームの状態を返す
+    pub fn dump(&self) -> Vec<FrameInfo> {
+        self.pool
+            .buffers
+            .iter()
+            .enumerate()
+            .map(|(idx, frame)| FrameInfo {
+                buffer_id: BufferId(idx),
+                page_id: frame.buffer.page_id,
+                usage_count: frame.usage_count,
+                last_access: frame.last_access,
+                pin_count: frame.buffer.pin_count(),
+                is_dirty: frame.is_dirty(),
+            })
+            .collect()
+    }
+
+    pub fn page_table_len(&self) -> usize {
+        self.page_table.len()
+    }
+
+    // ページテーブルとフレームの対応が壊れていればpanicする
+    pub fn assert_consistent(&self) {
+        for (&page_id, &buffer_id) in self.page_table.iter() {
+            assert!(
+                buffer_id.0 < self.pool.size(),
+                "page table entry for {:?} points at nonexistent {:?}",
+                page_id,
+                buffer_id
+            );
+            let frame_page_id = self.pool[buffer_id].buffer.page_id;
+            assert_eq!(
+                page_id, frame_page_id,
+                "page table entry for {:?} points at {:?} holding {:?}",
+                page_id, buffer_id, frame_page_id
+            );
+        }
+        let cached = self
+            .pool
+            .buffers
+            .iter()
+            .filter(|frame| frame.buffer.page_id.valid().is_some())
+            .count();
+        assert_eq!(
+            cached,
+            self.page_table.len(),
+            "some cached pages are missing from the page table"
+        );
+        for &buffer_id in self.dirty.borrow().iter() {
+            assert!(
+                self.pool[buffer_id].is_dirty(),
+                "{:?} is in the dirty set but is clean",
+                buffer_id
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempfile;
+
+    use super::super::BufferPool;
+    use super::*;
+    use crate::disk::DiskManager;
+
+    #[test]
+    fn test_dump() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(3));
+        let page_ids: Vec<_> = (0..2)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        bufmgr.flush_page(page_ids[1]).unwrap();
+        let held = bufmgr.fetch_page(page_ids[0]).unwrap();
+
+        let frames = bufmgr.dump();
+        assert_eq!(3, frames.len());
+        let info = frames.iter().find(|info| info.page_id == page_ids[0]).unwrap();
+        assert_eq!(1, info.pin_count);
+        assert!(info.is_dirty);
+        let info = frames.iter().find(|info| info.page_id == page_ids[1]).unwrap();
+        assert_eq!(0, info.pin_count);
+        assert!(!info.is_dirty);
+        assert_eq!(1, frames.iter().filter(|info| info.page_id.valid().is_none()).count());
+        assert_eq!(2, bufmgr.page_table_len());
+        assert_eq!(4, FrameTable(&frames).to_string().lines().count());
+        bufmgr.assert_consistent();
+        drop(held);
+    }
+
+    #[test]
+    #[should_panic(expected = "page table entry")]
+    fn test_assert_consistent() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let mut bufmgr = BufferPoolManager::new(disk, BufferPool::new(2));
+        let page_id = bufmgr.create_page().unwrap().page_id;
+        bufmgr.create_page().unwrap();
+        bufmgr.assert_consistent();
+
+        // ページテーブルを壊す
+        let buffer_id = bufmgr.page_table[&page_id];
+        bufmgr.page_table.insert(PageId(100), buffer_id);
+        bufmgr.assert_consistent();
+    }
+}
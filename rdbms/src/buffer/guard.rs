@@ -0,0 +1,95 @@
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+use super::{BufferId, Page, PinnedBuffer};
+use crate::disk::PageId;
+
+// 読み込み専用で貸し出したバッファ
+// ページの中身を書き換える手段を持たないので、誤ってdirtyにすることがない
+#[derive(Debug)]
+pub struct ReadGuard {
+    buffer: PinnedBuffer,
+}
+
+impl ReadGuard {
+    pub(super) fn new(buffer: PinnedBuffer) -> Self {
+        Self { buffer }
+    }
+
+    pub fn page_id(&self) -> PageId {
+        self.buffer.page_id
+    }
+
+    pub fn page(&self) -> Ref<'_, Page> {
+        self.buffer.page.borrow()
+    }
+
+    // チェックサムを除いたページの中身
+    pub fn data(&self) -> Ref<'_, [u8]> {
+        self.buffer.data()
+    }
+
+    pub fn snapshot(&self) -> Page {
+        self.buffer.snapshot()
+    }
+}
+
+// 書き込み用に貸し出したバッファ
+// はじめて書き込み用に中身を借りたときにdirtyになる
+#[derive(Debug)]
+pub struct WriteGuard {
+    buffer: PinnedBuffer,
+    buffer_id: BufferId,
+    dirty: Rc<RefCell<BTreeSet<BufferId>>>,
+    marked: Cell<bool>,
+}
+
+impl WriteGuard {
+    pub(super) fn new(
+        buffer: PinnedBuffer,
+        buffer_id: BufferId,
+        dirty: Rc<RefCell<BTreeSet<BufferId>>>,
+    ) -> Self {
+        Self {
+            buffer,
+            buffer_id,
+            dirty,
+            marked: Cell::new(false),
+        }
+    }
+
+    pub fn page_id(&self) -> PageId {
+        self.buffer.page_id
+    }
+
+    pub fn page(&self) -> Ref<'_, Page> {
+        self.buffer.page.borrow()
+    }
+
+    pub fn data(&self) -> Ref<'_, [u8]> {
+        self.buffer.data()
+    }
+
+    pub fn snapshot(&self) -> Page {
+        self.buffer.snapshot()
+    }
+
+    pub fn page_mut(&self) -> RefMut<'_, Page> {
+        self.mark_dirty();
+        self.buffer.page.borrow_mut()
+    }
+
+    pub fn data_mut(&self) -> RefMut<'_, [u8]> {
+        self.mark_dirty();
+        self.buffer.data_mut()
+    }
+
+    fn mark_dirty(&self) {
+        if self.marked.replace(true) {
+            return;
+        }
+        self.buffer.is_dirty.set(true);
+        self.dirty.borrow_mut().insert(self.buffer_id);
+    }
+}
@@ -0,0 +1,106 @@
+#[cfg(any(test, feature = "testing"))]
+use std::cell::Cell;
+#[cfg(debug_assertions)]
+use std::backtrace::Backtrace;
+use std::fmt::Debug;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use super::BufferId;
+use crate::disk::PageId;
+
+// ピン留めの経過時間を測るための時計
+pub trait Clock: Debug {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+// テスト用の、手動で進める時計
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug)]
+pub struct MockClock {
+    base: Instant,
+    elapsed: Cell<Duration>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            base: Instant::now(),
+            elapsed: Cell::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.set(self.elapsed.get() + duration);
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.base + self.elapsed.get()
+    }
+}
+
+// ひとつのピン留めの記録
+#[derive(Debug)]
+pub(super) struct PinRecord {
+    pub(super) pin_id: u64,
+    pub(super) pinned_at: Instant,
+    // デバッグビルドでは、どこでピン留めしたかを残しておく
+    #[cfg(debug_assertions)]
+    pub(super) backtrace: Rc<Backtrace>,
+}
+
+// 長い間ピン留めされているバッファ
+#[derive(Debug, Clone)]
+pub struct PinInfo {
+    pub buffer_id: BufferId,
+    pub page_id: PageId,
+    pub pinned_for: Duration,
+    #[cfg(debug_assertions)]
+    pub backtrace: Rc<Backtrace>,
+}
+
+// ピン留めの漏れを見つけるための記録係
+#[derive(Debug)]
+pub(super) struct LeakDetector {
+    pub(super) clock: Rc<dyn Clock>,
+    next_pin_id: u64,
+}
+
+impl LeakDetector {
+    pub(super) fn new(clock: Rc<dyn Clock>) -> Self {
+        Self {
+            clock,
+            next_pin_id: 0,
+        }
+    }
+
+    pub(super) fn record(&mut self) -> PinRecord {
+        self.next_pin_id += 1;
+        PinRecord {
+            pin_id: self.next_pin_id,
+            pinned_at: self.clock.now(),
+            #[cfg(debug_assertions)]
+            backtrace: Rc::new(Backtrace::force_capture()),
+        }
+    }
+}
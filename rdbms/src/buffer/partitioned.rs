@@ -0,0 +1,247 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use super::sync::{lock_disk, retry_when_full, write_dirty_pages, DirtyPages, SyncPool};
+use super::{allocate_page, sync_disk, BufferPoolStats, Error, IoOp, OnPoolFull, SyncBuffer};
+use crate::disk::{PageId, Storage, SyncMode, TablespaceId};
+
+// ページIDのハッシュで複数のシャードに振り分けるバッファプールマネージャ
+// シャードごとにフレームとページテーブルを持つので、別のシャードへのアクセスは互いに待たない
+#[derive(Debug)]
+pub struct PartitionedBufferPoolManager {
+    shards: Vec<Shard>,
+    disk: Mutex<Box<dyn Storage>>,
+    sync_mode: Mutex<SyncMode>,
+}
+
+#[derive(Debug)]
+struct Shard {
+    pool: Mutex<SyncPool>,
+    dirty: DirtyPages,
+}
+
+impl Shard {
+    fn lock(&self) -> MutexGuard<'_, SyncPool> {
+        self.pool.lock().expect("buffer pool shard poisoned")
+    }
+}
+
+impl PartitionedBufferPoolManager {
+    // num_shards個のシャードに、それぞれshard_size個のバッファを持たせる
+    pub fn new(disk: impl Storage + 'static, num_shards: usize, shard_size: usize) -> Self {
+        assert!(num_shards > 0, "num_shards must be positive");
+        let page_size = disk.page_size();
+        let shards = (0..num_shards)
+            .map(|_| Shard {
+                pool: Mutex::new(SyncPool::new(shard_size, page_size)),
+                dirty: Default::default(),
+            })
+            .collect();
+        Self {
+            shards,
+            disk: Mutex::new(Box::new(disk)),
+            sync_mode: Mutex::new(SyncMode::default()),
+        }
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn set_on_pool_full(&self, on_pool_full: OnPoolFull) {
+        for shard in self.shards.iter() {
+            shard.lock().set_on_pool_full(on_pool_full);
+        }
+    }
+
+    // シャードごとには持たず、すべてのシャードの書き出しのあとの1回のsyncで使う
+    pub fn set_sync_mode(&self, sync_mode: SyncMode) {
+        *self.sync_mode.lock().expect("sync mode poisoned") = sync_mode;
+    }
+
+    // すべてのシャードの統計情報を合計する
+    pub fn stats(&self) -> BufferPoolStats {
+        let mut stats = BufferPoolStats::default();
+        for shard in self.shards.iter() {
+            stats += shard.lock().stats();
+        }
+        stats
+    }
+
+    fn shard_index(&self, page_id: PageId) -> usize {
+        let mut hasher = DefaultHasher::new();
+        page_id.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, page_id: PageId) -> &Shard {
+        &self.shards[self.shard_index(page_id)]
+    }
+
+    pub fn fetch_page(&self, page_id: PageId) -> Result<Arc<SyncBuffer>, Error> {
+        let shard = self.shard(page_id);
+        let on_pool_full = shard.lock().on_pool_full();
+        retry_when_full(on_pool_full, || {
+            shard.lock().fetch_page(&self.disk, &shard.dirty, page_id)
+        })
+    }
+
+    // 新しいページの作成
+    // シャードを決めるためにページIDを先に割り当て、空きバッファがなければそのページIDを解放する
+    pub fn create_page(&self) -> Result<Arc<SyncBuffer>, Error> {
+        let page_id = allocate_page(lock_disk(&self.disk).as_mut(), TablespaceId::DEFAULT)?;
+        let shard = self.shard(page_id);
+        let on_pool_full = shard.lock().on_pool_full();
+        let created = retry_when_full(on_pool_full, || {
+            let mut pool = shard.lock();
+            let buffer_id = pool.take_victim(&self.disk, &shard.dirty)?;
+            Ok(pool.install_new_page(&shard.dirty, buffer_id, page_id))
+        });
+        if created.is_err() {
+            lock_disk(&self.disk).deallocate_page(page_id).map_err(|source| Error::Io {
+                page_id,
+                op: IoOp::Free,
+                source,
+            })?;
+        }
+        created
+    }
+
+    pub fn mark_dirty(&self, buffer: &SyncBuffer) {
+        self.shard(buffer.page_id).dirty.mark(buffer);
+    }
+
+    // すべてのシャードのdirtyなバッファを書き出す
+    pub fn flush(&self) -> Result<(), Error> {
+        for shard in self.shards.iter() {
+            write_dirty_pages(|| shard.lock(), &self.disk, &shard.dirty, usize::MAX, false)?;
+        }
+        let sync_mode = *self.sync_mode.lock().expect("sync mode poisoned");
+        sync_disk(lock_disk(&self.disk).as_mut(), sync_mode)?;
+        Ok(())
+    }
+
+    pub fn dirty_page_count(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().dirty_page_count())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use tempfile::tempfile;
+
+    use super::super::tests::RecordingStorage;
+    use super::super::PAGE_DATA_SIZE;
+    use super::*;
+    use crate::disk::DiskManager;
+
+    #[test]
+    fn test() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = PartitionedBufferPoolManager::new(disk, 4, 4);
+        let page_ids: Vec<_> = (0..32u8)
+            .map(|i| {
+                let buffer = bufmgr.create_page().unwrap();
+                buffer.page.write().unwrap()[..PAGE_DATA_SIZE]
+                    .iter_mut()
+                    .for_each(|b| *b = i);
+                buffer.page_id
+            })
+            .collect();
+        bufmgr.flush().unwrap();
+        assert_eq!(0, bufmgr.dirty_page_count());
+
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            let buffer = bufmgr.fetch_page(page_id).unwrap();
+            assert_eq!(page_id, buffer.page_id);
+            assert_eq!(i as u8, buffer.page.read().unwrap()[0]);
+        }
+        // 統計情報はすべてのシャードの合計
+        let stats = bufmgr.stats();
+        assert_eq!(32, stats.hits + stats.misses);
+    }
+
+    #[test]
+    fn test_create_page_without_free_buffer() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = PartitionedBufferPoolManager::new(disk, 1, 1);
+        let held = bufmgr.create_page().unwrap();
+        assert!(matches!(bufmgr.create_page(), Err(Error::NoFreeBuffer { .. })));
+        // 作れなかったページのIDは解放して、次に作るページで使う
+        let free_page_ids = lock_disk(&bufmgr.disk).free_page_ids().unwrap();
+        assert_eq!(1, free_page_ids.len());
+        assert_eq!(1, lock_disk(&bufmgr.disk).num_pages());
+        drop(held);
+        assert_eq!(free_page_ids[0], bufmgr.create_page().unwrap().page_id);
+    }
+
+    #[test]
+    fn test_independent_shards() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = Arc::new(PartitionedBufferPoolManager::new(disk, 2, 2));
+        let page_ids: Vec<_> = (0..16)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        bufmgr.flush().unwrap();
+        let (shard0, shard1): (Vec<_>, Vec<_>) = page_ids
+            .iter()
+            .partition(|&&page_id| bufmgr.shard_index(page_id) == 0);
+        assert!(shard0.len() > 2 && shard1.len() > 2);
+
+        // シャード0のバッファをすべて貸出中にしておく
+        bufmgr.set_on_pool_full(OnPoolFull::Wait {
+            timeout: Duration::from_secs(10),
+        });
+        let held: Vec<_> = shard0[..2]
+            .iter()
+            .map(|&page_id| bufmgr.fetch_page(page_id).unwrap())
+            .collect();
+
+        let waiter = {
+            let bufmgr = Arc::clone(&bufmgr);
+            let page_id = shard0[2];
+            thread::spawn(move || bufmgr.fetch_page(page_id).unwrap().page_id)
+        };
+        // シャード0で待っている間も、シャード1へのアクセスは進む
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let bufmgr = Arc::clone(&bufmgr);
+                let shard1 = shard1.clone();
+                thread::spawn(move || {
+                    for _ in 0..20 {
+                        for &page_id in &shard1 {
+                            assert_eq!(page_id, bufmgr.fetch_page(page_id).unwrap().page_id);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(!waiter.is_finished());
+
+        drop(held);
+        assert_eq!(shard0[2], waiter.join().unwrap());
+    }
+
+    #[test]
+    fn test_sync_mode() {
+        let storage = RecordingStorage::default();
+        let log = Arc::clone(&storage.log);
+        let bufmgr = PartitionedBufferPoolManager::new(storage, 2, 2);
+        bufmgr.create_page().unwrap();
+        bufmgr.flush().unwrap();
+        assert_eq!(Some("sync"), log.lock().unwrap().last().map(String::as_str));
+        bufmgr.set_sync_mode(SyncMode::Data);
+        bufmgr.flush().unwrap();
+        assert_eq!(Some("sync_data"), log.lock().unwrap().last().map(String::as_str));
+    }
+}
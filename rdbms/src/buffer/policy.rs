@@ -0,0 +1,135 @@
+use std::fmt::Debug;
+
+use super::{BufferId, Frame, Priority};
+
+// 捨てるバッファを決めるアルゴリズム
+pub trait EvictionPolicy: Debug {
+    // バッファが利用されたときに呼ばれる
+    fn touch(&mut self, frames: &mut [Frame], buffer_id: BufferId);
+
+    // 捨てるバッファを決める。すべてのバッファが貸出中ならNoneを返す
+    fn pick_victim(&mut self, frames: &mut [Frame]) -> Option<BufferId>;
+
+    // バッファプールのサイズが変わったときに呼ばれる
+    fn resize(&mut self, _pool_size: usize) {}
+}
+
+// usage_countの上限のデフォルト値(PostgreSQLと同じ)
+pub const DEFAULT_MAX_USAGE_COUNT: u64 = 5;
+
+// Stickyなフレームを追い出すまでに余分に見逃す周回数
+pub const STICKY_PASSES: u8 = 2;
+
+// Clock-sweep
+// PostgreSQLでも採用されているアルゴリズム
+#[derive(Debug)]
+pub struct ClockSweep {
+    next_victim_id: BufferId,
+    // 何度も利用されたバッファが追い出せるようになるまで時間がかかりすぎないよう、
+    // usage_countに上限を設ける
+    max_usage_count: u64,
+}
+
+impl ClockSweep {
+    pub fn with_max_usage_count(max_usage_count: u64) -> Self {
+        Self {
+            next_victim_id: BufferId::default(),
+            max_usage_count,
+        }
+    }
+}
+
+impl Default for ClockSweep {
+    fn default() -> Self {
+        Self::with_max_usage_count(DEFAULT_MAX_USAGE_COUNT)
+    }
+}
+
+impl EvictionPolicy for ClockSweep {
+    fn touch(&mut self, frames: &mut [Frame], buffer_id: BufferId) {
+        let frame = &mut frames[buffer_id.0];
+        if frame.usage_count < self.max_usage_count {
+            frame.usage_count += 1;
+        }
+        frame.sticky_passes = 0;
+    }
+
+    fn pick_victim(&mut self, frames: &mut [Frame]) -> Option<BufferId> {
+        let pool_size = frames.len();
+        if pool_size == 0 {
+            return None;
+        }
+        // 追い出すとディスクへの書き出しが必要になるので、dirtyなバッファは後回しにする
+        let mut dirty_victim_id = None;
+        // usage_countを減らせないバッファが続いた数
+        let mut consecutive_skipped = 0;
+
+        let victim_id = loop {
+            let frame = &mut frames[self.next_victim_id.0];
+            // バッファが貸出中かどうか
+            if !frame.is_pinned() {
+                if frame.usage_count == 0
+                    && frame.priority == Priority::Sticky
+                    && frame.sticky_passes < STICKY_PASSES
+                {
+                    // Stickyなフレームはさらに見逃す
+                    frame.sticky_passes += 1;
+                    consecutive_skipped = 0;
+                } else if frame.usage_count == 0 {
+                    if !frame.is_dirty() {
+                        break self.next_victim_id;
+                    }
+                    dirty_victim_id.get_or_insert(self.next_victim_id);
+                    consecutive_skipped += 1;
+                } else {
+                    // 貸出中でなければデクリメント
+                    frame.usage_count -= 1;
+                    consecutive_skipped = 0;
+                }
+            } else {
+                // 貸出中
+                consecutive_skipped += 1;
+            }
+            // 一周してもcleanなバッファが見つからなければ、dirtyなバッファを捨てる
+            if consecutive_skipped >= pool_size {
+                self.next_victim_id = dirty_victim_id?;
+                return dirty_victim_id;
+            }
+            self.next_victim_id = BufferId((self.next_victim_id.0 + 1) % pool_size);
+        };
+        Some(victim_id)
+    }
+
+    fn resize(&mut self, pool_size: usize) {
+        if self.next_victim_id.0 >= pool_size {
+            self.next_victim_id = BufferId::default();
+        }
+    }
+}
+
+// LRU
+// 最後に利用されてからもっとも時間が経っているバッファを捨てる
+#[derive(Debug, Default)]
+pub struct Lru;
+
+impl EvictionPolicy for Lru {
+    fn touch(&mut self, frames: &mut [Frame], buffer_id: BufferId) {
+        frames[buffer_id.0].usage_count += 1;
+    }
+
+    fn pick_victim(&mut self, frames: &mut [Frame]) -> Option<BufferId> {
+        frames
+            .iter()
+            .enumerate()
+            .filter(|(_, frame)| !frame.is_pinned())
+            // Stickyでない、cleanなバッファを優先する
+            .min_by_key(|(_, frame)| {
+                (
+                    frame.priority == Priority::Sticky,
+                    frame.is_dirty(),
+                    frame.last_access,
+                )
+            })
+            .map(|(idx, _)| BufferId(idx))
+    }
+}
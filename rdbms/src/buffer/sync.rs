@@ -0,0 +1,742 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::{
+    allocate_page, new_page, read_page, sync_disk, write_page, BufferPoolStats, Error, IoOp,
+    OnPoolFull, Page, DEFAULT_MAX_USAGE_COUNT,
+};
+use crate::disk::{PageId, Storage, SyncMode, TablespaceId};
+
+// スレッド間で共有できるバッファ
+#[derive(Debug)]
+pub struct SyncBuffer {
+    pub page_id: PageId,
+    pub page: RwLock<Page>,
+    // mark_dirtyで立てる
+    is_dirty: AtomicBool,
+}
+
+impl SyncBuffer {
+    pub fn is_dirty(&self) -> bool {
+        self.is_dirty.load(Ordering::Acquire)
+    }
+}
+
+impl SyncBuffer {
+    fn new(page_size: usize) -> Self {
+        Self {
+            page_id: Default::default(),
+            page: RwLock::new(new_page(page_size)),
+            is_dirty: AtomicBool::new(false),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SyncFrame {
+    usage_count: u64,
+    buffer: Arc<SyncBuffer>,
+}
+
+impl SyncFrame {
+    fn new(page_size: usize) -> Self {
+        Self {
+            usage_count: 0,
+            buffer: Arc::new(SyncBuffer::new(page_size)),
+        }
+    }
+}
+
+// dirtyなページの集合
+// ページの中身のロックを持ったままでも更新できるよう、フレームとは別のMutexで保護する
+#[derive(Debug, Default)]
+pub(super) struct DirtyPages(Mutex<BTreeSet<PageId>>);
+
+impl DirtyPages {
+    fn lock(&self) -> MutexGuard<'_, BTreeSet<PageId>> {
+        self.0.lock().expect("dirty page set poisoned")
+    }
+
+    pub(super) fn mark(&self, buffer: &SyncBuffer) {
+        buffer.is_dirty.store(true, Ordering::Release);
+        self.lock().insert(buffer.page_id);
+    }
+
+    fn remove(&self, page_id: PageId) {
+        self.lock().remove(&page_id);
+    }
+
+    fn snapshot(&self) -> Vec<PageId> {
+        self.lock().iter().copied().collect()
+    }
+}
+
+// フレームとページテーブル
+// ディスクマネージャは別のMutexで保護し、書き出しや読み込みが必要なときだけロックする
+#[derive(Debug)]
+pub(super) struct SyncPool {
+    frames: Vec<SyncFrame>,
+    next_victim_id: usize,
+    page_table: HashMap<PageId, usize>,
+    on_pool_full: OnPoolFull,
+    stats: BufferPoolStats,
+    page_size: usize,
+}
+
+impl SyncPool {
+    pub(super) fn new(pool_size: usize, page_size: usize) -> Self {
+        let mut frames = vec![];
+        frames.resize_with(pool_size, || SyncFrame::new(page_size));
+        Self {
+            frames,
+            next_victim_id: 0,
+            page_table: HashMap::new(),
+            on_pool_full: OnPoolFull::default(),
+            stats: Default::default(),
+            page_size,
+        }
+    }
+
+    pub(super) fn on_pool_full(&self) -> OnPoolFull {
+        self.on_pool_full
+    }
+
+    pub(super) fn set_on_pool_full(&mut self, on_pool_full: OnPoolFull) {
+        self.on_pool_full = on_pool_full;
+    }
+
+    pub(super) fn stats(&self) -> BufferPoolStats {
+        self.stats
+    }
+
+    // Clock-sweep
+    // ClockSweepと同じく、dirtyなバッファは後回しにする
+    fn evict(&mut self) -> Option<usize> {
+        let pool_size = self.frames.len();
+        if pool_size == 0 {
+            return None;
+        }
+        let mut dirty_victim_id = None;
+        let mut consecutive_skipped = 0;
+        loop {
+            let frame = &mut self.frames[self.next_victim_id];
+            // Arcのクローンがほかにあれば貸出中
+            if Arc::strong_count(&frame.buffer) == 1 {
+                if frame.usage_count == 0 {
+                    if !frame.buffer.is_dirty.load(Ordering::Acquire) {
+                        return Some(self.next_victim_id);
+                    }
+                    dirty_victim_id.get_or_insert(self.next_victim_id);
+                    consecutive_skipped += 1;
+                } else {
+                    frame.usage_count -= 1;
+                    consecutive_skipped = 0;
+                }
+            } else {
+                consecutive_skipped += 1;
+            }
+            if consecutive_skipped >= pool_size {
+                self.next_victim_id = dirty_victim_id?;
+                return dirty_victim_id;
+            }
+            self.next_victim_id = (self.next_victim_id + 1) % pool_size;
+        }
+    }
+
+    fn touch(&mut self, buffer_id: usize) {
+        let frame = &mut self.frames[buffer_id];
+        if frame.usage_count < DEFAULT_MAX_USAGE_COUNT {
+            frame.usage_count += 1;
+        }
+    }
+
+    // 捨てるバッファを決め、dirtyならディスクに書き出してから明け渡す
+    pub(super) fn take_victim(
+        &mut self,
+        disk: &Mutex<Box<dyn Storage>>,
+        dirty: &DirtyPages,
+    ) -> Result<usize, Error> {
+        let buffer_id = match self.evict() {
+            Some(buffer_id) => buffer_id,
+            None => match self.on_pool_full {
+                OnPoolFull::Grow { max } if self.frames.len() < max => {
+                    self.frames.push(SyncFrame::new(self.page_size));
+                    return Ok(self.frames.len() - 1);
+                }
+                _ => return Err(Error::NoFreeBuffer { oldest_pin: None }),
+            },
+        };
+        let frame = &mut self.frames[buffer_id];
+        let evict_page_id = frame.buffer.page_id;
+        let buffer = Arc::get_mut(&mut frame.buffer).unwrap();
+        let is_dirty = buffer.is_dirty.load(Ordering::Acquire);
+        if is_dirty {
+            write_page(lock_disk(disk).as_mut(), evict_page_id, buffer.page.get_mut().unwrap())?;
+            buffer.is_dirty.store(false, Ordering::Release);
+            dirty.remove(evict_page_id);
+            self.stats.dirty_writes += 1;
+            self.stats.writes += 1;
+        }
+        if evict_page_id.valid().is_some() {
+            self.page_table.remove(&evict_page_id);
+            self.stats.count_eviction(is_dirty);
+        }
+        // ページを置くまでに失敗しても、ページテーブルと食い違わないようにする
+        buffer.page_id = PageId::INVALID_PAGE_ID;
+        frame.usage_count = 0;
+        Ok(buffer_id)
+    }
+
+    // ページの貸出
+    pub(super) fn fetch_page(
+        &mut self,
+        disk: &Mutex<Box<dyn Storage>>,
+        dirty: &DirtyPages,
+        page_id: PageId,
+    ) -> Result<Arc<SyncBuffer>, Error> {
+        if let Some(&buffer_id) = self.page_table.get(&page_id) {
+            self.touch(buffer_id);
+            self.stats.hits += 1;
+            return Ok(Arc::clone(&self.frames[buffer_id].buffer));
+        }
+        let buffer_id = self.take_victim(disk, dirty)?;
+        self.stats.misses += 1;
+        let frame = &mut self.frames[buffer_id];
+        let buffer = Arc::get_mut(&mut frame.buffer).unwrap();
+        buffer.page_id = PageId::INVALID_PAGE_ID;
+        read_page(lock_disk(disk).as_mut(), page_id, buffer.page.get_mut().unwrap())?;
+        buffer.page_id = page_id;
+        self.page_table.insert(page_id, buffer_id);
+        self.touch(buffer_id);
+        Ok(Arc::clone(&self.frames[buffer_id].buffer))
+    }
+
+    // take_victimで明け渡したバッファに、ゼロ埋めした新しいページをdirtyな状態で置いて貸し出す
+    pub(super) fn install_new_page(
+        &mut self,
+        dirty: &DirtyPages,
+        buffer_id: usize,
+        page_id: PageId,
+    ) -> Arc<SyncBuffer> {
+        let frame = &mut self.frames[buffer_id];
+        let buffer = Arc::get_mut(&mut frame.buffer).unwrap();
+        *buffer = SyncBuffer::new(self.page_size);
+        buffer.page_id = page_id;
+        dirty.mark(buffer);
+        self.page_table.insert(page_id, buffer_id);
+        self.touch(buffer_id);
+        Arc::clone(&self.frames[buffer_id].buffer)
+    }
+
+    // 解放するページをバッファから外す。貸出中ならError::PinnedBufferを返す
+    fn discard_page(&mut self, dirty: &DirtyPages, page_id: PageId) -> Result<(), Error> {
+        if let Some(&buffer_id) = self.page_table.get(&page_id) {
+            let frame = &mut self.frames[buffer_id];
+            let buffer = Arc::get_mut(&mut frame.buffer).ok_or(Error::PinnedBuffer(page_id))?;
+            *buffer = SyncBuffer::new(self.page_size);
+            frame.usage_count = 0;
+            dirty.remove(page_id);
+            self.page_table.remove(&page_id);
+        }
+        Ok(())
+    }
+
+    pub(super) fn dirty_page_count(&self) -> usize {
+        self.frames
+            .iter()
+            .filter(|frame| frame.buffer.is_dirty.load(Ordering::Acquire))
+            .count()
+    }
+
+    // 書き出すdirtyなバッファを借りる。ページを持っていなければ集合から外してNoneを返す
+    // skip_pinnedなら、貸出中のバッファも飛ばす
+    fn dirty_buffer(
+        &self,
+        dirty: &DirtyPages,
+        page_id: PageId,
+        skip_pinned: bool,
+    ) -> Option<Arc<SyncBuffer>> {
+        let buffer = match self.page_table.get(&page_id) {
+            Some(&buffer_id) => &self.frames[buffer_id].buffer,
+            None => {
+                dirty.remove(page_id);
+                return None;
+            }
+        };
+        if skip_pinned && Arc::strong_count(buffer) > 1 {
+            return None;
+        }
+        Some(Arc::clone(buffer))
+    }
+}
+
+// dirtyなバッファを最大max_pages個書き出し、書き出した数を返す
+// dirtyなページの集合に載っているものだけを見る
+// プールのMutexはバッファを借りる間だけ持ち、ページのラッチを待つ間は外しておく
+// ページのラッチを持ったままfetch_pageやcreate_pageでプールのMutexを待つスレッドがいても、
+// 逆の順で取り合って行き詰まらない。借りるのは1つずつなので、追い出せるバッファも減らさない
+pub(super) fn write_dirty_pages<'a>(
+    lock_pool: impl Fn() -> MutexGuard<'a, SyncPool>,
+    disk: &Mutex<Box<dyn Storage>>,
+    dirty: &DirtyPages,
+    max_pages: usize,
+    skip_pinned: bool,
+) -> Result<usize, Error> {
+    let mut written = 0;
+    for page_id in dirty.snapshot() {
+        if written >= max_pages {
+            break;
+        }
+        let buffer = match lock_pool().dirty_buffer(dirty, page_id, skip_pinned) {
+            Some(buffer) => buffer,
+            None => continue,
+        };
+        // フラグより先に集合から外すので、書き出し中に別スレッドがmark_dirtyすれば集合に戻る
+        dirty.remove(page_id);
+        if !buffer.is_dirty.swap(false, Ordering::AcqRel) {
+            continue;
+        }
+        let page = buffer.page.read().expect("page lock poisoned");
+        if let Err(err) = write_page(lock_disk(disk).as_mut(), page_id, &page) {
+            drop(page);
+            dirty.mark(&buffer);
+            return Err(err);
+        }
+        written += 1;
+    }
+    Ok(written)
+}
+
+pub(super) fn lock_disk(disk: &Mutex<Box<dyn Storage>>) -> MutexGuard<'_, Box<dyn Storage>> {
+    disk.lock().expect("disk manager poisoned")
+}
+
+// OnPoolFull::Waitでバッファが返されるのを待つときの確認間隔
+const WAIT_INTERVAL: Duration = Duration::from_millis(1);
+
+// 空きバッファがなければon_pool_fullに従ってattemptを繰り返す
+// attemptは毎回ロックを取り直すので、待っている間もほかのスレッドは進める
+pub(super) fn retry_when_full<T>(
+    on_pool_full: OnPoolFull,
+    mut attempt: impl FnMut() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let timeout = match on_pool_full {
+        OnPoolFull::Wait { timeout } => timeout,
+        _ => return attempt(),
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        match attempt() {
+            Err(Error::NoFreeBuffer { .. }) if Instant::now() < deadline => {
+                thread::sleep(WAIT_INTERVAL)
+            }
+            result => return result,
+        }
+    }
+}
+
+// バックグラウンドライタが1回の周期で書き出すページ数の上限
+pub const BACKGROUND_WRITER_MAX_PAGES: usize = 64;
+
+// スレッドセーフなバッファプールマネージャ
+// ページテーブルとフレームはひとつのMutexで保護し、ページの中身はRwLockで保護する
+#[derive(Debug)]
+pub struct SyncBufferPoolManager {
+    pool: Mutex<SyncPool>,
+    disk: Mutex<Box<dyn Storage>>,
+    dirty: DirtyPages,
+    sync_mode: Mutex<SyncMode>,
+}
+
+impl SyncBufferPoolManager {
+    pub fn new(disk: impl Storage + 'static, pool_size: usize) -> Self {
+        Self {
+            pool: Mutex::new(SyncPool::new(pool_size, disk.page_size())),
+            disk: Mutex::new(Box::new(disk)),
+            dirty: Default::default(),
+            sync_mode: Mutex::new(SyncMode::default()),
+        }
+    }
+
+    pub fn set_on_pool_full(&self, on_pool_full: OnPoolFull) {
+        self.lock().set_on_pool_full(on_pool_full);
+    }
+
+    // プールのロックを取らずに切り替えられるよう、モードは別のMutexに置く
+    pub fn set_sync_mode(&self, sync_mode: SyncMode) {
+        *self.sync_mode.lock().expect("sync mode poisoned") = sync_mode;
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        self.lock().stats()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SyncPool> {
+        self.pool.lock().expect("buffer pool state poisoned")
+    }
+
+    // ページの貸出
+    // 返したArc<SyncBuffer>をいずれかのスレッドが保持している間、そのフレームは追い出されない
+    pub fn fetch_page(&self, page_id: PageId) -> Result<Arc<SyncBuffer>, Error> {
+        let on_pool_full = self.lock().on_pool_full();
+        retry_when_full(on_pool_full, || {
+            self.lock().fetch_page(&self.disk, &self.dirty, page_id)
+        })
+    }
+
+    // 新しいページの作成
+    pub fn create_page(&self) -> Result<Arc<SyncBuffer>, Error> {
+        let on_pool_full = self.lock().on_pool_full();
+        retry_when_full(on_pool_full, || {
+            let mut pool = self.lock();
+            // 空きバッファがなければページIDを消費しないよう、先に確認する
+            let buffer_id = pool.take_victim(&self.disk, &self.dirty)?;
+            let page_id = allocate_page(lock_disk(&self.disk).as_mut(), TablespaceId::DEFAULT)?;
+            Ok(pool.install_new_page(&self.dirty, buffer_id, page_id))
+        })
+    }
+
+    // ページを解放して、次に作るページで使えるようにする
+    // ほかのスレッドが借りていれば、Error::PinnedBufferを返して何もしない
+    pub fn free_page(&self, page_id: PageId) -> Result<(), Error> {
+        let mut pool = self.lock();
+        pool.discard_page(&self.dirty, page_id)?;
+        lock_disk(&self.disk).deallocate_page(page_id).map_err(|source| Error::Io {
+            page_id,
+            op: IoOp::Free,
+            source,
+        })
+    }
+
+    // 貸し出したバッファを書き換えたことを知らせる
+    // ページの中身のロックを持ったままでも呼べる
+    pub fn mark_dirty(&self, buffer: &SyncBuffer) {
+        self.dirty.mark(buffer);
+    }
+
+    // dirtyなバッファをすべてディスクに書き出す
+    pub fn flush(&self) -> Result<(), Error> {
+        write_dirty_pages(|| self.lock(), &self.disk, &self.dirty, usize::MAX, false)?;
+        let sync_mode = *self.sync_mode.lock().expect("sync mode poisoned");
+        sync_disk(lock_disk(&self.disk).as_mut(), sync_mode)?;
+        Ok(())
+    }
+
+    pub fn dirty_page_count(&self) -> usize {
+        self.lock().dirty_page_count()
+    }
+
+    // 貸出中でないdirtyなバッファを最大max_pages個書き出し、書き出した数を返す
+    pub fn write_dirty_pages(&self, max_pages: usize) -> Result<usize, Error> {
+        write_dirty_pages(|| self.lock(), &self.disk, &self.dirty, max_pages, true)
+    }
+
+    // 一定間隔でdirtyなバッファを書き出すスレッドを起動する
+    // 返したBackgroundWriterをdropすると停止する。書き出しの失敗はBackgroundWriterに記録する
+    pub fn spawn_background_writer(self: &Arc<Self>, interval: Duration) -> BackgroundWriter {
+        let state = Arc::new((Mutex::new(WriterState::default()), Condvar::new()));
+        let handle = {
+            let bufmgr = Arc::clone(self);
+            let state = Arc::clone(&state);
+            thread::spawn(move || {
+                let (lock, cvar) = &*state;
+                let mut guard = lock.lock().unwrap();
+                // 書き出しの間はロックを放すので、待つ前にも止められていないか確かめる
+                while !guard.stopped {
+                    guard = cvar.wait_timeout(guard, interval).unwrap().0;
+                    if guard.stopped {
+                        break;
+                    }
+                    drop(guard);
+                    let result = bufmgr.write_dirty_pages(BACKGROUND_WRITER_MAX_PAGES);
+                    guard = lock.lock().unwrap();
+                    guard.stats.rounds += 1;
+                    match result {
+                        Ok(written) => guard.stats.pages_written += written as u64,
+                        Err(err) => {
+                            guard.stats.errors += 1;
+                            guard.last_error = Some(err);
+                        }
+                    }
+                    cvar.notify_all();
+                }
+            })
+        };
+        BackgroundWriter {
+            state,
+            handle: Some(handle),
+        }
+    }
+}
+
+// バックグラウンドライタの統計情報
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct BackgroundWriterStats {
+    pub rounds: u64,        // 書き出しを試みた回数
+    pub pages_written: u64, // 書き出したページの数
+    pub errors: u64,        // roundsのうち、書き出しに失敗した回数
+}
+
+#[derive(Debug, Default)]
+struct WriterState {
+    stopped: bool,
+    stats: BackgroundWriterStats,
+    last_error: Option<Error>, // 最後に失敗したときのエラー。take_errorで取り出す
+}
+
+// バックグラウンドライタの停止用ハンドル
+#[derive(Debug)]
+pub struct BackgroundWriter {
+    state: Arc<(Mutex<WriterState>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl BackgroundWriter {
+    pub fn stats(&self) -> BackgroundWriterStats {
+        self.state.0.lock().unwrap().stats
+    }
+
+    // 最後の書き出しの失敗を取り出す。次に失敗するまではNoneを返す
+    pub fn take_error(&self) -> Option<Error> {
+        self.state.0.lock().unwrap().last_error.take()
+    }
+
+    // 書き出しを試みた回数がroundsに達するまで、最大timeoutだけ待つ。達しなければfalseを返す
+    pub fn wait_rounds(&self, rounds: u64, timeout: Duration) -> bool {
+        let (lock, cvar) = &*self.state;
+        let guard = lock.lock().unwrap();
+        let guard = cvar
+            .wait_timeout_while(guard, timeout, |state| {
+                !state.stopped && state.stats.rounds < rounds
+            })
+            .unwrap()
+            .0;
+        guard.stats.rounds >= rounds
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        let (lock, cvar) = &*self.state;
+        lock.lock().unwrap().stopped = true;
+        cvar.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tempfile::{tempfile, NamedTempFile};
+
+    use super::super::tests::RecordingStorage;
+    use super::super::PAGE_DATA_SIZE;
+    use super::*;
+    use crate::disk::DiskManager;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_concurrent_fetch() {
+        assert_send_sync::<SyncBufferPoolManager>();
+
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = Arc::new(SyncBufferPoolManager::new(disk, 8));
+        let page_ids: Vec<_> = (0..32u8)
+            .map(|i| {
+                let buffer = bufmgr.create_page().unwrap();
+                buffer.page.write().unwrap()[..PAGE_DATA_SIZE]
+                    .iter_mut()
+                    .for_each(|b| *b = i);
+                buffer.page_id
+            })
+            .collect();
+        bufmgr.flush().unwrap();
+
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let bufmgr = Arc::clone(&bufmgr);
+                let page_ids = page_ids.clone();
+                thread::spawn(move || {
+                    for round in 0..50 {
+                        // スレッドごとに範囲をずらして重なりを持たせる
+                        for i in 0..16 {
+                            let idx = (t * 4 + round + i) % page_ids.len();
+                            let buffer = bufmgr.fetch_page(page_ids[idx]).unwrap();
+                            assert_eq!(page_ids[idx], buffer.page_id);
+                            let page = buffer.page.read().unwrap();
+                            assert!(page[..PAGE_DATA_SIZE].iter().all(|&b| b == idx as u8));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_background_writer() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = Arc::new(SyncBufferPoolManager::new(disk, 100));
+        for _ in 0..100 {
+            bufmgr.create_page().unwrap();
+        }
+        assert_eq!(100, bufmgr.dirty_page_count());
+
+        // 1回に書き出すのはBACKGROUND_WRITER_MAX_PAGESまでなので、2回で書き終える
+        let writer = bufmgr.spawn_background_writer(Duration::from_millis(10));
+        assert!(writer.wait_rounds(2, Duration::from_secs(10)));
+        assert_eq!(0, bufmgr.dirty_page_count());
+        let stats = writer.stats();
+        assert_eq!(100, stats.pages_written);
+        assert_eq!(0, stats.errors);
+        assert!(writer.take_error().is_none());
+        writer.stop();
+
+        // 書き出しに失敗しても止まらず、エラーを記録する
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        let page_id = disk.allocate_page().unwrap();
+        drop(disk);
+        let file = std::fs::File::open(&data_file_path).unwrap();
+        let bufmgr = Arc::new(SyncBufferPoolManager::new(DiskManager::new(file).unwrap(), 10));
+        let buffer = bufmgr.fetch_page(page_id).unwrap();
+        bufmgr.mark_dirty(&buffer);
+        drop(buffer);
+        let writer = bufmgr.spawn_background_writer(Duration::from_millis(1));
+        assert!(writer.wait_rounds(2, Duration::from_secs(10)));
+        let stats = writer.stats();
+        assert!(stats.errors >= 2);
+        assert_eq!(0, stats.pages_written);
+        assert!(matches!(writer.take_error(), Some(Error::Io { .. })));
+        writer.stop();
+    }
+
+    #[test]
+    fn test_on_pool_full() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = Arc::new(SyncBufferPoolManager::new(disk, 2));
+        let page_ids: Vec<_> = (0..4)
+            .map(|_| bufmgr.create_page().unwrap().page_id)
+            .collect();
+        let held: Vec<_> = page_ids[..2]
+            .iter()
+            .map(|&page_id| bufmgr.fetch_page(page_id).unwrap())
+            .collect();
+        assert!(matches!(
+            bufmgr.fetch_page(page_ids[2]),
+            Err(Error::NoFreeBuffer { .. })
+        ));
+
+        // 誰も返さなければタイムアウトする
+        bufmgr.set_on_pool_full(OnPoolFull::Wait {
+            timeout: Duration::from_millis(20),
+        });
+        assert!(matches!(
+            bufmgr.fetch_page(page_ids[2]),
+            Err(Error::NoFreeBuffer { .. })
+        ));
+
+        // 別スレッドが返すまで待つ
+        bufmgr.set_on_pool_full(OnPoolFull::Wait {
+            timeout: Duration::from_secs(10),
+        });
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(held);
+        });
+        let buffer = bufmgr.fetch_page(page_ids[2]).unwrap();
+        assert_eq!(page_ids[2], buffer.page_id);
+        releaser.join().unwrap();
+
+        let _held = bufmgr.fetch_page(page_ids[0]).unwrap();
+        bufmgr.set_on_pool_full(OnPoolFull::Grow { max: 3 });
+        let buffer = bufmgr.fetch_page(page_ids[3]).unwrap();
+        assert_eq!(page_ids[3], buffer.page_id);
+        assert!(matches!(bufmgr.create_page(), Err(Error::NoFreeBuffer { .. })));
+    }
+
+    #[test]
+    fn test_free_page() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = SyncBufferPoolManager::new(disk, 4);
+        let buffer = bufmgr.create_page().unwrap();
+        let page_id = buffer.page_id;
+        assert!(matches!(bufmgr.free_page(page_id), Err(Error::PinnedBuffer(_))));
+        drop(buffer);
+        bufmgr.free_page(page_id).unwrap();
+        // 解放したページは書き出さず、次に作るページで使う
+        assert_eq!(0, bufmgr.dirty_page_count());
+        assert_eq!(page_id, bufmgr.create_page().unwrap().page_id);
+    }
+
+    #[test]
+    fn test_mark_dirty() {
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = SyncBufferPoolManager::new(disk, 100);
+        let buffers: Vec<_> = (0..100).map(|_| bufmgr.create_page().unwrap()).collect();
+        bufmgr.flush().unwrap();
+        assert_eq!(0, bufmgr.dirty_page_count());
+
+        for buffer in &buffers[..3] {
+            // ページのロックを持ったままでもよい
+            let mut page = buffer.page.write().unwrap();
+            page[0] = 1;
+            bufmgr.mark_dirty(buffer);
+        }
+        drop(buffers);
+        assert_eq!(3, bufmgr.write_dirty_pages(usize::MAX).unwrap());
+        assert_eq!(0, bufmgr.write_dirty_pages(usize::MAX).unwrap());
+        assert_eq!(0, bufmgr.dirty_page_count());
+    }
+
+    #[test]
+    fn test_flush_while_latched() {
+        // ページのラッチを持ったままプールを使うスレッドと、flushが互いを待たない
+        let disk = DiskManager::new(tempfile().unwrap()).unwrap();
+        let bufmgr = Arc::new(SyncBufferPoolManager::new(disk, 4));
+        let buffer = bufmgr.create_page().unwrap();
+        let page = buffer.page.write().unwrap();
+        let flusher = {
+            let bufmgr = Arc::clone(&bufmgr);
+            thread::spawn(move || bufmgr.flush())
+        };
+        thread::sleep(Duration::from_millis(20));
+        let other = bufmgr.create_page().unwrap();
+        bufmgr.mark_dirty(&other);
+        drop(page);
+        flusher.join().unwrap().unwrap();
+        bufmgr.flush().unwrap();
+        assert_eq!(0, bufmgr.dirty_page_count());
+    }
+
+    #[test]
+    fn test_sync_mode() {
+        let storage = RecordingStorage::default();
+        let log = Arc::clone(&storage.log);
+        let bufmgr = SyncBufferPoolManager::new(storage, 2);
+        bufmgr.create_page().unwrap();
+        bufmgr.flush().unwrap();
+        assert_eq!(Some("sync"), log.lock().unwrap().last().map(String::as_str));
+        bufmgr.set_sync_mode(SyncMode::Data);
+        bufmgr.flush().unwrap();
+        assert_eq!(Some("sync_data"), log.lock().unwrap().last().map(String::as_str));
+    }
+}
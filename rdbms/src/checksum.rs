@@ -0,0 +1,70 @@
+use std::convert::TryInto;
+
+// ページ末尾に置くチェックサムのサイズ
+pub const CHECKSUM_SIZE: usize = 4;
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 == 1 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// CRC32(IEEE 802.3)
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+// ページの末尾に、それ以外の部分のチェックサムを書き込む
+pub fn stamp(page: &mut [u8]) {
+    let (data, checksum) = page.split_at_mut(page.len() - CHECKSUM_SIZE);
+    checksum.copy_from_slice(&crc32(data).to_le_bytes());
+}
+
+// ページ末尾のチェックサムを検証する
+// 一度も書き込まれていない(すべて0の)ページは正しいものとして扱う
+pub fn verify(page: &[u8]) -> bool {
+    let (data, checksum) = page.split_at(page.len() - CHECKSUM_SIZE);
+    let checksum = u32::from_le_bytes(checksum.try_into().unwrap());
+    if checksum == 0 && data.iter().all(|&b| b == 0) {
+        return true;
+    }
+    crc32(data) == checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+
+        let mut page = vec![0u8; 64];
+        assert!(verify(&page));
+        page[..5].copy_from_slice(b"hello");
+        assert!(!verify(&page));
+        stamp(&mut page);
+        assert!(verify(&page));
+        page[0] ^= 1;
+        assert!(!verify(&page));
+    }
+}
@@ -0,0 +1,126 @@
+// LZ77系の簡単な圧縮
+// 制御バイトの最上位ビットが0ならリテラル、1なら過去のデータのコピー
+//   0xxxxxxx: 続くx+1バイトをそのまま出力する
+//   1xxxxxxx oo: oだけ前(リトルエンディアン)からx+MIN_MATCHバイトをコピーする
+
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = 0x7F + MIN_MATCH;
+const MAX_LITERALS: usize = 0x80;
+const MAX_OFFSET: usize = u16::MAX as usize;
+
+const HASH_BITS: u32 = 12;
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn flush_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    // 4バイトの並びのハッシュから、最後に現れた位置を引く
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut pos = 0;
+    while pos + MIN_MATCH <= input.len() {
+        let h = hash(&input[pos..]);
+        let candidate = table[h];
+        table[h] = pos;
+        let matched = candidate != usize::MAX
+            && pos - candidate <= MAX_OFFSET
+            && input[candidate..candidate + MIN_MATCH] == input[pos..pos + MIN_MATCH];
+        if !matched {
+            pos += 1;
+            continue;
+        }
+        let mut len = MIN_MATCH;
+        while len < MAX_MATCH && pos + len < input.len() && input[candidate + len] == input[pos + len]
+        {
+            len += 1;
+        }
+        flush_literals(&mut out, &input[literal_start..pos]);
+        out.push(0x80 | (len - MIN_MATCH) as u8);
+        out.extend_from_slice(&((pos - candidate) as u16).to_le_bytes());
+        pos += len;
+        literal_start = pos;
+    }
+    flush_literals(&mut out, &input[literal_start..]);
+    out
+}
+
+// 展開したデータをoutputに書き込み、その長さを返す
+// 壊れたデータやoutputに収まらないデータならNoneを返す
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut out_pos = 0;
+    while pos < input.len() {
+        let control = input[pos] as usize;
+        pos += 1;
+        if control & 0x80 == 0 {
+            let len = control + 1;
+            let literals = input.get(pos..pos + len)?;
+            output.get_mut(out_pos..out_pos + len)?.copy_from_slice(literals);
+            pos += len;
+            out_pos += len;
+        } else {
+            let len = (control & 0x7F) + MIN_MATCH;
+            let offset = u16::from_le_bytes([*input.get(pos)?, *input.get(pos + 1)?]) as usize;
+            pos += 2;
+            if offset == 0 || offset > out_pos || out_pos + len > output.len() {
+                return None;
+            }
+            // コピー元とコピー先が重なることがあるので、1バイトずつコピーする
+            for i in out_pos..out_pos + len {
+                output[i] = output[i - offset];
+            }
+            out_pos += len;
+        }
+    }
+    Some(out_pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) -> usize {
+        let compressed = compress(input);
+        let mut output = vec![0; input.len()];
+        assert_eq!(Some(input.len()), decompress(&compressed, &mut output));
+        assert_eq!(input, &output[..]);
+        compressed.len()
+    }
+
+    #[test]
+    fn test() {
+        round_trip(b"");
+        round_trip(b"abc");
+        let text = b"hello, world! ".repeat(300);
+        assert!(round_trip(&text) < text.len() / 10);
+        assert!(round_trip(&[0; 4096]) < 100);
+
+        // 圧縮できないデータは少しだけ大きくなる
+        let mut x = 0x1234_5678_9ABC_DEF0u64;
+        let random: Vec<u8> = (0..4096)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        assert!(round_trip(&random) <= random.len() + random.len() / MAX_LITERALS + 1);
+
+        // 壊れたデータや、出力に収まらないデータは展開できない
+        let mut output = vec![0; 16];
+        assert_eq!(None, decompress(&[0x80, 1, 0], &mut output));
+        assert_eq!(None, decompress(&[3, b'a'], &mut output));
+        assert_eq!(None, decompress(&compress(&text), &mut output));
+    }
+}
@@ -1,19 +1,102 @@
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
+use std::fmt::Debug;
 use std::fs::{File, OpenOptions};
 use std::io::{self, prelude::*, SeekFrom};
-use std::path::Path;
+use std::mem::size_of;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 
 use zerocopy::{AsBytes, FromBytes};
 
+use crate::checksum;
+use crate::compress;
+
+mod compressed;
+mod direct;
+mod double_write;
+mod encrypted;
+mod memory;
+#[cfg(unix)]
+mod mmap;
+mod read_ahead;
+
+use compressed::{TranslationTable, SLOTS_PER_BLOCK};
+#[cfg(unix)]
+use direct::DirectFile;
+pub use direct::{AlignedBuffer, DIRECT_IO_ALIGNMENT};
+use double_write::DoubleWrite;
+pub use encrypted::{
+    AuthenticationFailed, EncryptedDiskManager, ENCRYPTION_KEY_SIZE, ENCRYPTION_OVERHEAD,
+};
+pub use memory::MemoryDiskManager;
+#[cfg(unix)]
+use mmap::Mmap;
+#[cfg(unix)]
+pub use mmap::MmapDiskManager;
+use read_ahead::ReadAhead;
+pub use read_ahead::ReadAheadStats;
+
+// デフォルトのページサイズ
 pub const PAGE_SIZE: usize = 4096;
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, FromBytes, AsBytes)]
+// 設定できるページサイズの範囲
+// スロット付きページのオフセットがu16なので、上限もそれに合わせる
+pub const MIN_PAGE_SIZE: usize = 256;
+pub const MAX_PAGE_SIZE: usize = 32 * 1024;
+
+// ファイルを伸ばすときの単位のデフォルト値
+pub const DEFAULT_EXTENT_SIZE: usize = 1024 * 1024;
+
+// ページサイズが2のべき乗で、範囲に収まっているか確認する
+pub fn check_page_size(page_size: usize) -> io::Result<()> {
+    if !page_size.is_power_of_two() || !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid page size {}", page_size),
+        ));
+    }
+    Ok(())
+}
+
+// 表領域のID
+// 0はヒープファイルそのもので、create_tablespaceで作ったものは1から数える
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct TablespaceId(pub u16);
+
+impl TablespaceId {
+    pub const DEFAULT: TablespaceId = TablespaceId(0);
+}
+
+// 作れる表領域の数の上限。最大の値はINVALID_PAGE_IDと重なるので使わない
+const MAX_TABLESPACES: usize = u16::MAX as usize - 1;
+
+// 上位16ビットが表領域のID、下位48ビットが表領域の中でのページ番号
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, FromBytes, AsBytes)]
 #[repr(C)]
 pub struct PageId(pub u64);
 
+const TABLESPACE_SHIFT: u32 = 48;
+
 impl PageId {
     pub const INVALID_PAGE_ID: PageId = PageId(u64::MAX);
 
+    pub fn new(tablespace_id: TablespaceId, page_number: u64) -> Self {
+        PageId((tablespace_id.0 as u64) << TABLESPACE_SHIFT | page_number)
+    }
+
+    pub fn tablespace_id(self) -> TablespaceId {
+        TablespaceId((self.0 >> TABLESPACE_SHIFT) as u16)
+    }
+
+    // 表領域の中でのページ番号
+    pub fn page_number(self) -> u64 {
+        self.0 & ((1 << TABLESPACE_SHIFT) - 1)
+    }
+
     pub fn valid(self) -> Option<PageId> {
         if self == Self::INVALID_PAGE_ID {
             None
@@ -46,101 +129,2432 @@ impl From<&[u8]> for PageId {
     }
 }
 
+// ページを読み書きする先
+// バッファプールはこのトレイトを通してページを読み書きする
+pub trait Storage: Debug + Send {
+    // ページのデータを読み出す
+    fn read_page_data(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()>;
+
+    // データをページに書き出す
+    fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()>;
+
+    // 複数のページをまとめて書き出す
+    // 途中で失敗したときに、一部のページだけが壊れた状態で残らないようにできるなら、そうする
+    fn write_pages_atomic(&mut self, pages: &[(PageId, &[u8])]) -> io::Result<()> {
+        for (page_id, data) in pages {
+            self.write_page_data(*page_id, data)?;
+        }
+        Ok(())
+    }
+
+    // ページサイズ
+    fn page_size(&self) -> usize;
+
+    // 新しいページIDを採番する。解放されたページがあればそれを再利用する
+    fn allocate_page(&mut self) -> io::Result<PageId>;
+
+    // 表領域を指定してページIDを採番する
+    // 表領域を持たないストレージでは、デフォルトの表領域しか使えない
+    fn allocate_page_in(&mut self, tablespace_id: TablespaceId) -> io::Result<PageId> {
+        if tablespace_id != TablespaceId::DEFAULT {
+            return Err(unknown_tablespace(tablespace_id));
+        }
+        self.allocate_page()
+    }
+
+    // ページを解放し、次の採番で再利用できるようにする
+    fn deallocate_page(&mut self, page_id: PageId) -> io::Result<()>;
+
+    // 最初に開くべきメタページのID
+    fn meta_page_id(&self) -> PageId;
+
+    fn set_meta_page_id(&mut self, meta_page_id: PageId) -> io::Result<()>;
+
+    // 使用中のページの数。解放したページは数えない
+    fn num_pages(&self) -> u64;
+
+    // 空きページがないときに、次に採番するページID
+    fn next_page_id(&self) -> PageId;
+
+    // ストレージが使っているバイト数
+    fn file_size_bytes(&self) -> io::Result<u64>;
+
+    // 解放されたページのID。次に再利用する順に並べる
+    fn free_page_ids(&mut self) -> io::Result<Vec<PageId>>;
+
+    // create_tablespaceで作った表領域のIDと名前。表領域を持たないストレージでは空
+    fn tablespaces(&self) -> Vec<(TablespaceId, String)> {
+        vec![]
+    }
+
+    // 表領域で、空きページがないときに次に採番するページID
+    fn next_page_id_in(&self, tablespace_id: TablespaceId) -> io::Result<PageId> {
+        if tablespace_id != TablespaceId::DEFAULT {
+            return Err(unknown_tablespace(tablespace_id));
+        }
+        Ok(self.next_page_id())
+    }
+
+    // 表領域の解放されたページのID。次に再利用する順に並べる
+    fn free_page_ids_in(&mut self, tablespace_id: TablespaceId) -> io::Result<Vec<PageId>> {
+        if tablespace_id != TablespaceId::DEFAULT {
+            return Err(unknown_tablespace(tablespace_id));
+        }
+        self.free_page_ids()
+    }
+
+    // ページを暗号化して書いているかどうか
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+
+    // 末尾に並んだ空きページを取り除いて小さくし、減ったバイト数を返す
+    // 縮められないストレージでは何もしない
+    fn compact(&mut self) -> io::Result<u64> {
+        Ok(0)
+    }
+
+    // 書き込めないように開いたかどうか
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    // 書き出したデータを永続化する
+    fn sync(&mut self) -> io::Result<()>;
+
+    // データだけを永続化する。メタデータの更新は待たない
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.sync()
+    }
+}
+
+// flush時にどこまで永続化を待つか
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum SyncMode {
+    // ファイルのメタデータまで含めて永続化する(fsync)
+    #[default]
+    All,
+    // データだけを永続化する(fdatasync)
+    Data,
+}
+
+// 読み出したページのチェックサムが合わない
+// read_page_dataはこれをInvalidDataのio::Errorに包んで返す
+#[derive(Debug, thiserror::Error)]
+#[error("checksum mismatch on page {}", .page_id.to_u64())]
+pub struct ChecksumMismatch {
+    pub page_id: PageId,
+}
+
+// ページの読み書きの失敗
+// Storageのメソッドはio::Errorを返すので、io::Errorに包んで返す
+// Error::fromで包んだio::Errorから取り出せる
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("disk is opened read-only")]
+    ReadOnly,
+    #[error("page {} is out of range ({num_pages} pages allocated)", .page_id.to_u64())]
+    PageOutOfRange { page_id: PageId, num_pages: u64 },
+    #[error("short read on page {}: got {got} of {expected} bytes", .page_id.to_u64())]
+    ShortRead {
+        page_id: PageId,
+        got: usize,
+        expected: usize,
+    },
+    #[error(transparent)]
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *err.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            Error::ReadOnly => io::Error::new(io::ErrorKind::PermissionDenied, err),
+            err => io::Error::new(io::ErrorKind::UnexpectedEof, err),
+        }
+    }
+}
+
+// ヒープファイルの先頭(物理的な0ページ目)に置くヘッダ
+// ページIDは、ヘッダページを除いて0から数える
+#[derive(Debug, FromBytes, AsBytes)]
+#[repr(C)]
+struct FileHeader {
+    magic: [u8; 8],
+    // ファイル形式のバージョン
+    version: u32,
+    // ページサイズ
+    page_size: u32,
+    // ファイル形式のフラグ
+    flags: u64,
+    // 解放されたページの連結リストの先頭
+    free_list_head: PageId,
+    // 最初に開くべきメタページ(カタログなど)のID
+    meta_page_id: PageId,
+    // 採番したページの数
+    // ファイルは先に伸ばしておくので、ファイルサイズからは求められない
+    num_pages: u64,
+    // 空きページのリストにつないだページの数
+    num_free_pages: u64,
+}
+
+const HEADER_MAGIC: [u8; 8] = *b"RDBMSHDR";
+
+// ヘッダのうち、free_list_headから後ろの欄の位置
+const HEADER_COUNTS_OFFSET: usize = 24;
+
+pub const FORMAT_VERSION: u32 = 3;
+
+// num_pagesのない形式。ページ数はファイルサイズから求める
+const FORMAT_VERSION_WITHOUT_NUM_PAGES: u32 = 1;
+
+// num_free_pagesのない形式。空きページの数はリストをたどって数える
+const FORMAT_VERSION_WITHOUT_NUM_FREE_PAGES: u32 = 2;
+
+// 各ページの末尾にCRC32を置く形式
+// このフラグのないファイルではチェックサムを書かず、検証もしない
+const FLAG_CHECKSUM: u64 = 1;
+
+// ページを圧縮して物理ブロックのスロットに置く形式
+// 論理ページの置き場所は、ヒープファイルに添えた変換表に記録する
+const FLAG_COMPRESSION: u64 = 2;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn unknown_tablespace(tablespace_id: TablespaceId) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("unknown tablespace {}", tablespace_id.0),
+    )
+}
+
+impl FileHeader {
+    // 検証してファイル形式を確認する
+    fn validate(&self, page_size: usize) -> io::Result<()> {
+        if self.magic != HEADER_MAGIC {
+            return Err(invalid_data("not a heap file: bad magic".to_string()));
+        }
+        if !(FORMAT_VERSION_WITHOUT_NUM_PAGES..=FORMAT_VERSION).contains(&self.version) {
+            return Err(invalid_data(format!(
+                "unsupported format version {}",
+                self.version
+            )));
+        }
+        if self.page_size as usize != page_size {
+            return Err(invalid_data(format!(
+                "page size mismatch: file has {}, expected {}",
+                self.page_size, page_size
+            )));
+        }
+        Ok(())
+    }
+}
+
+// ヒープファイルの読み書きの方法
+#[derive(Debug)]
+enum HeapFile {
+    // read/writeシステムコールで読み書きする
+    File(File),
+    // メモリマップを通して読み書きする
+    #[cfg(unix)]
+    Mmap(Mmap),
+    // O_DIRECTでOSのページキャッシュを通さずに読み書きする
+    #[cfg(unix)]
+    Direct(DirectFile),
+}
+
+impl HeapFile {
+    fn len(&self) -> io::Result<u64> {
+        match self {
+            HeapFile::File(file) => Ok(file.metadata()?.len()),
+            #[cfg(unix)]
+            HeapFile::Mmap(mmap) => Ok(mmap.len()),
+            #[cfg(unix)]
+            HeapFile::Direct(file) => file.len(),
+        }
+    }
+
+    // ファイルをlenまで伸ばし、ディスク上の領域も確保しておく
+    fn extend(&mut self, len: u64) -> io::Result<()> {
+        match self {
+            HeapFile::File(file) => preallocate(file, len),
+            #[cfg(unix)]
+            HeapFile::Mmap(mmap) => mmap.extend(len),
+            #[cfg(unix)]
+            HeapFile::Direct(file) => file.extend(len),
+        }
+    }
+
+    // ファイルをlenまで縮める
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        match self {
+            HeapFile::File(file) => file.set_len(len),
+            #[cfg(unix)]
+            HeapFile::Mmap(mmap) => mmap.truncate(len),
+            #[cfg(unix)]
+            HeapFile::Direct(file) => file.truncate(len),
+        }
+    }
+
+    // ファイルの末尾までを読み、読めたバイト数を返す
+    fn read_partial(&mut self, offset: u64, data: &mut [u8]) -> io::Result<usize> {
+        match self {
+            HeapFile::File(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                let mut len = 0;
+                while len < data.len() {
+                    match file.read(&mut data[len..]) {
+                        Ok(0) => break,
+                        Ok(n) => len += n,
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                        Err(err) => return Err(err),
+                    }
+                }
+                Ok(len)
+            }
+            #[cfg(unix)]
+            HeapFile::Mmap(mmap) => mmap.read_partial(offset, data),
+            #[cfg(unix)]
+            HeapFile::Direct(file) => file.read_partial(offset, data),
+        }
+    }
+
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        if self.read_partial(offset, data)? < data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ));
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        match self {
+            HeapFile::File(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_all(data)
+            }
+            #[cfg(unix)]
+            HeapFile::Mmap(mmap) => mmap.write_at(offset, data),
+            #[cfg(unix)]
+            HeapFile::Direct(file) => file.write_at(offset, data),
+        }
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        match self {
+            HeapFile::File(file) => {
+                file.flush()?;
+                file.sync_all()
+            }
+            #[cfg(unix)]
+            HeapFile::Mmap(mmap) => mmap.sync_all(),
+            #[cfg(unix)]
+            HeapFile::Direct(file) => file.sync_all(),
+        }
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        match self {
+            HeapFile::File(file) => {
+                file.flush()?;
+                file.sync_data()
+            }
+            #[cfg(unix)]
+            HeapFile::Mmap(mmap) => mmap.sync_data(),
+            #[cfg(unix)]
+            HeapFile::Direct(file) => file.sync_data(),
+        }
+    }
+}
+
+// ファイルをlenまで伸ばす
+// fallocateで領域を確保できなければ、set_lenで伸ばすだけにする
+fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    let current = file.metadata()?.len();
+    if len <= current {
+        return Ok(());
+    }
+    if !fallocate(file, current, len) {
+        file.set_len(len)?;
+    }
+    Ok(())
+}
+
+// ファイルのcurrentからlenまでの領域を確保できたかどうか
+#[cfg(unix)]
+fn fallocate(file: &File, current: u64, len: u64) -> bool {
+    let ret = unsafe {
+        libc::posix_fallocate(
+            file.as_raw_fd(),
+            current as libc::off_t,
+            (len - current) as libc::off_t,
+        )
+    };
+    ret == 0
+}
+
+// fallocateのないプラットフォームでは確保しない
+#[cfg(not(unix))]
+fn fallocate(_file: &File, _current: u64, _len: u64) -> bool {
+    false
+}
+
 #[derive(Debug)]
 pub struct DiskManager {
-    // ヒープファイルのファイルディスクリプタ
-    heap_file: File,
-    // 採番するページIDを決めるカウンタ
+    // ヒープファイル
+    heap_file: HeapFile,
+    // ページサイズ。ヘッダに記録する
+    page_size: usize,
+    // 採番するページIDを決めるカウンタ。採番したページの数でもある
     next_page_id: u64,
+    // 空きページのリストにつないだページの数
+    num_free_pages: u64,
+    // ファイルを伸ばすときに一度に確保するページ数
+    extent_pages: u64,
+    // ヘッダページをファイルに書き出したかどうか
+    header_written: bool,
+    // 解放されたページの連結リストの先頭
+    // 各空きページの先頭に次の空きページのIDを書いておく
+    free_list_head: PageId,
+    // 空きページのリストに載っているページ。最初にページを解放するときに、リストをたどって作る
+    free_pages: Option<HashSet<PageId>>,
+    // ヘッダに記録するメタページのID
+    meta_page_id: PageId,
+    // ページ末尾のチェックサムを書き込み・検証するかどうか
+    checksum: bool,
+    // write_pages_atomicで使うダブルライトバッファ
+    double_write: Option<DoubleWrite>,
+    // 圧縮する形式での、論理ページから物理的な置き場所への変換表
+    translation: Option<TranslationTable>,
+    // ヒープファイルのパス。表領域のファイルはこの隣に置く
+    heap_file_path: Option<PathBuf>,
+    // ヒープファイルを開いたときの設定。表領域のファイルも同じ設定で開く
+    options: DiskManagerOptions,
+    // 順に読まれたときの先読み
+    read_ahead: ReadAhead,
+    // create_tablespaceで作った表領域。添字+1が表領域のID
+    tablespaces: Vec<Tablespace>,
+}
+
+// ヒープファイルとは別のファイルに置く表領域
+// ファイルごとにヘッダや空きページのリストを持つので、それぞれDiskManagerで管理する
+#[derive(Debug)]
+struct Tablespace {
+    name: String,
+    disk: DiskManager,
+}
+
+fn default_extent_pages(page_size: usize) -> u64 {
+    (DEFAULT_EXTENT_SIZE / page_size) as u64
+}
+
+// ヒープファイルに添えるダブルライトバッファのパス
+fn double_write_path(heap_file_path: &Path) -> PathBuf {
+    with_suffix(heap_file_path, ".dw")
+}
+
+// 圧縮する形式の変換表のパス
+fn translation_path(heap_file_path: &Path) -> PathBuf {
+    with_suffix(heap_file_path, ".ctt")
+}
+
+// 表領域の名前を1行に1つずつ書いておくファイルのパス
+fn tablespace_list_path(heap_file_path: &Path) -> PathBuf {
+    with_suffix(heap_file_path, ".tablespaces")
+}
+
+// 表領域のファイルのパス
+fn tablespace_path(heap_file_path: &Path, name: &str) -> PathBuf {
+    with_suffix(heap_file_path, &format!(".{}.tbs", name))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+fn plain_heap_file(heap_file: File) -> io::Result<HeapFile> {
+    Ok(HeapFile::File(heap_file))
+}
+
+// ファイルを開いたあと、どう読み書きするか
+type WrapHeapFile = fn(File) -> io::Result<HeapFile>;
+
+// ヒープファイルを開くときの設定
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct DiskManagerOptions {
+    // O_DIRECTで開き、OSのページキャッシュを通さない
+    // 使えないプラットフォームやファイルシステム、揃わないページサイズでは、開くときにエラーを返す
+    pub direct_io: bool,
+    // O_DSYNCで開き、書き込みのたびにディスクへ届くのを待つ。unix系のプラットフォームでだけ使える
+    pub write_through: bool,
+    // 書き込み権限なしで開き、ページの採番や書き込みをError::ReadOnlyで失敗させる
+    pub read_only: bool,
+}
+
+// optionsに従ってヒープファイルを開く
+fn open_heap_file(
+    path: &Path,
+    open_options: &mut OpenOptions,
+    page_size: usize,
+    wrap: WrapHeapFile,
+    options: DiskManagerOptions,
+) -> io::Result<HeapFile> {
+    if options.direct_io && page_size % DIRECT_IO_ALIGNMENT != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "page size {} is not a multiple of {} required by O_DIRECT",
+                page_size, DIRECT_IO_ALIGNMENT
+            ),
+        ));
+    }
+    #[cfg(unix)]
+    {
+        let custom_flags = if options.write_through { libc::O_DSYNC } else { 0 };
+        if options.direct_io {
+            let file = direct::open_direct(path, open_options, custom_flags)?;
+            return Ok(HeapFile::Direct(file));
+        }
+        wrap(open_options.custom_flags(custom_flags).open(path)?)
+    }
+    #[cfg(not(unix))]
+    {
+        if options.direct_io || options.write_through {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "direct_io and write_through are only supported on unix",
+            ));
+        }
+        wrap(open_options.open(path)?)
+    }
 }
 
 
 impl DiskManager {
 
     // コンストラクタ
+    // 空のファイルなら新しいヒープファイルとして使い始め、そうでなければヘッダを検証する
     pub fn new(heap_file: File) -> io::Result<Self> {
+        Self::with_page_size(heap_file, PAGE_SIZE)
+    }
+
+    // ページサイズを指定する。既存のファイルのページサイズと違えば失敗する
+    pub fn with_page_size(heap_file: File, page_size: usize) -> io::Result<Self> {
+        Self::from_heap_file(HeapFile::File(heap_file), page_size, None)
+    }
+
+    // 圧縮する形式のファイルは、変換表を開くためにパスが必要になる
+    fn from_heap_file(
+        mut heap_file: HeapFile,
+        page_size: usize,
+        heap_file_path: Option<&Path>,
+    ) -> io::Result<Self> {
+        check_page_size(page_size)?;
         // ファイルサイズを取得
-        let heap_file_size = heap_file.metadata()?.len();
-        let next_page_id = heap_file_size / PAGE_SIZE as u64;
+        let heap_file_size = heap_file.len()?;
+        if heap_file_size == 0 {
+            // 新しいファイルはチェックサム付きの形式にする
+            // ヘッダは最初の書き込みのときに書き出す
+            return Ok(Self {
+                heap_file,
+                page_size,
+                next_page_id: 0,
+                num_free_pages: 0,
+                extent_pages: default_extent_pages(page_size),
+                header_written: false,
+                free_list_head: PageId::INVALID_PAGE_ID,
+                free_pages: None,
+                meta_page_id: PageId::INVALID_PAGE_ID,
+                checksum: true,
+                double_write: None,
+                translation: None,
+                heap_file_path: None,
+                options: DiskManagerOptions::default(),
+                read_ahead: ReadAhead::default(),
+                tablespaces: vec![],
+            });
+        }
+        // ページサイズはヘッダを読むまでわからないので、ヘッダの大きさだけ確認する
+        if heap_file_size < size_of::<FileHeader>() as u64 {
+            return Err(invalid_data("not a heap file: too short".to_string()));
+        }
+        let mut header = FileHeader {
+            magic: [0; 8],
+            version: 0,
+            page_size: 0,
+            flags: 0,
+            free_list_head: PageId::INVALID_PAGE_ID,
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_pages: 0,
+            num_free_pages: 0,
+        };
+        heap_file.read_at(0, header.as_bytes_mut())?;
+        header.validate(page_size)?;
+        let next_page_id = if header.version == FORMAT_VERSION_WITHOUT_NUM_PAGES {
+            (heap_file_size / page_size as u64).saturating_sub(1)
+        } else {
+            header.num_pages
+        };
+        let translation = if header.flags & FLAG_COMPRESSION != 0 {
+            let heap_file_path = heap_file_path.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "compressed heap files must be opened by path",
+                )
+            })?;
+            Some(TranslationTable::load(translation_path(heap_file_path))?)
+        } else {
+            None
+        };
+
+        let mut disk = Self {
+            heap_file,
+            page_size,
+            next_page_id,
+            num_free_pages: header.num_free_pages,
+            extent_pages: default_extent_pages(page_size),
+            header_written: true,
+            free_list_head: header.free_list_head,
+            free_pages: None,
+            meta_page_id: header.meta_page_id,
+            checksum: header.flags & FLAG_CHECKSUM != 0,
+            double_write: None,
+            translation,
+            heap_file_path: None,
+            options: DiskManagerOptions::default(),
+            read_ahead: ReadAhead::default(),
+            tablespaces: vec![],
+        };
+        if header.version <= FORMAT_VERSION_WITHOUT_NUM_FREE_PAGES {
+            disk.num_free_pages = disk.free_page_ids()?.len() as u64;
+        }
+        Ok(disk)
+    }
+
+    // 空きページのリストを先頭からたどる
+    // 表領域の空きページは含まない
+    pub fn free_page_ids(&mut self) -> io::Result<Vec<PageId>> {
+        let mut page_ids = vec![];
+        let mut page = vec![0; self.page_size];
+        let mut next = self.free_list_head;
+        while let Some(page_id) = next.valid() {
+            // 壊れたリストが輪になっていても止まるようにする
+            if page_ids.len() as u64 >= self.next_page_id {
+                return Err(invalid_data("free list is corrupted".to_string()));
+            }
+            self.read_page_data(page_id, &mut page)?;
+            next = PageId::from(&page[..8]);
+            page_ids.push(page_id);
+        }
+        Ok(page_ids)
+    }
+
+    // ファイルを伸ばすときに一度に確保するページ数を指定する
+    pub fn with_extent_pages(mut self, extent_pages: u64) -> Self {
+        assert!(extent_pages > 0, "extent must have at least one page");
+        self.extent_pages = extent_pages;
+        for tablespace in self.tablespaces.iter_mut() {
+            tablespace.disk.extent_pages = extent_pages;
+        }
+        self
+    }
+
+    // triggerページ続けて順に読まれたら、続くwindowページを先読みするようにする
+    // windowが0なら先読みしない
+    pub fn with_read_ahead(mut self, trigger: u32, window: usize) -> Self {
+        self.read_ahead = ReadAhead::new(trigger, window);
+        for tablespace in self.tablespaces.iter_mut() {
+            tablespace.disk.read_ahead = ReadAhead::new(trigger, window);
+        }
+        self
+    }
+
+    // ダブルライトバッファを使うようにする
+    // 書き出し途中のページが残っていれば、それを使って壊れたページを復旧する
+    pub fn with_double_write(mut self, double_write_file: File) -> io::Result<Self> {
+        let mut double_write = DoubleWrite::new(double_write_file, self.page_size);
+        self.recover(&mut double_write)?;
+        double_write.clear()?;
+        self.double_write = Some(double_write);
+        Ok(self)
+    }
+
+    // 書き込みが途切れたページを、ダブルライトバッファのコピーで書き戻す
+    // チェックサムのない形式では壊れているか判別できないので、残っているものをすべて書き戻す
+    fn recover(&mut self, double_write: &mut DoubleWrite) -> io::Result<()> {
+        let pages = double_write.pages()?;
+        if pages.is_empty() {
+            return Ok(());
+        }
+        let mut home = vec![0; self.page_size];
+        for (page_id, data) in pages.iter() {
+            if self.checksum {
+                // コピーの方が壊れていれば、本来の場所にはまだ書いていない
+                if !checksum::verify(data) {
+                    continue;
+                }
+                let intact = self.read_raw(*page_id, &mut home).is_ok() && checksum::verify(&home);
+                if intact {
+                    continue;
+                }
+            }
+            self.write_raw(*page_id, data)?;
+            self.next_page_id = self.next_page_id.max(page_id.to_u64() + 1);
+        }
+        self.sync()
+    }
+
+    // 新しいヒープファイルを作る。すでにあれば中身を捨てる
+    pub fn create(heap_file_path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::create_with_page_size(heap_file_path, PAGE_SIZE)
+    }
+
+    pub fn create_with_page_size(
+        heap_file_path: impl AsRef<Path>,
+        page_size: usize,
+    ) -> io::Result<Self> {
+        Self::create_in(
+            heap_file_path.as_ref(),
+            page_size,
+            plain_heap_file,
+            DiskManagerOptions::default(),
+        )
+    }
+
+    // 設定を指定して新しいヒープファイルを作る
+    pub fn create_with(
+        heap_file_path: impl AsRef<Path>,
+        options: DiskManagerOptions,
+    ) -> io::Result<Self> {
+        Self::create_in(heap_file_path.as_ref(), PAGE_SIZE, plain_heap_file, options)
+    }
+
+    // ファイルを開いたあと、どう読み書きするかをwrapで選ぶ
+    fn create_in(
+        heap_file_path: &Path,
+        page_size: usize,
+        wrap: WrapHeapFile,
+        options: DiskManagerOptions,
+    ) -> io::Result<Self> {
+        // 書き込めないようにしては作れない
+        if options.read_only {
+            return Err(Error::ReadOnly.into());
+        }
+        let mut disk = Self::create_file(heap_file_path, page_size, wrap, options)?;
+        disk.heap_file_path = Some(heap_file_path.to_path_buf());
+        disk.write_tablespace_list()?;
+        Ok(disk)
+    }
+
+    fn create_file(
+        heap_file_path: &Path,
+        page_size: usize,
+        wrap: WrapHeapFile,
+        options: DiskManagerOptions,
+    ) -> io::Result<Self> {
+        check_page_size(page_size)?;
+        let heap_file = open_heap_file(
+            heap_file_path,
+            OpenOptions::new().read(true).write(true).create(true).truncate(true),
+            page_size,
+            wrap,
+            options,
+        )?;
+        let double_write_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(double_write_path(heap_file_path))?;
+        let mut disk = Self::from_heap_file(heap_file, page_size, Some(heap_file_path))?
+            .with_double_write(double_write_file)?;
+        disk.options = options;
+        disk.write_header()?;
+        Ok(disk)
+    }
 
-        Ok(Self { heap_file, next_page_id })
+    // ページを圧縮して書き出すヒープファイルを作る
+    pub fn create_compressed(heap_file_path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut disk = Self::create(heap_file_path.as_ref())?;
+        disk.translation = Some(TranslationTable::create(translation_path(
+            heap_file_path.as_ref(),
+        ))?);
+        disk.write_header()?;
+        Ok(disk)
     }
 
-    // ファイルパスを指定して開く
+    // ファイルパスを指定して、既存のヒープファイルを開く
     // ? -> エラーが帰ってきたらreturnする
     pub fn open(heap_file_path: impl AsRef<Path>) -> io::Result<Self> {
-        let heap_file = OpenOptions::new()
+        Self::open_with_page_size(heap_file_path, PAGE_SIZE)
+    }
+
+    pub fn open_with_page_size(
+        heap_file_path: impl AsRef<Path>,
+        page_size: usize,
+    ) -> io::Result<Self> {
+        Self::open_in(
+            heap_file_path.as_ref(),
+            page_size,
+            plain_heap_file,
+            DiskManagerOptions::default(),
+        )
+    }
+
+    // 設定を指定して既存のヒープファイルを開く
+    // O_DIRECTを使えなければ、通常の読み書きにせずにエラーを返す
+    pub fn open_with(
+        heap_file_path: impl AsRef<Path>,
+        options: DiskManagerOptions,
+    ) -> io::Result<Self> {
+        Self::open_in(heap_file_path.as_ref(), PAGE_SIZE, plain_heap_file, options)
+    }
+
+    // 書き込めないように既存のヒープファイルを開く
+    // ダブルライトバッファは開かないので、書き込みが途切れたページがあっても復旧しない
+    pub fn open_readonly(heap_file_path: impl AsRef<Path>) -> io::Result<Self> {
+        let options = DiskManagerOptions {
+            read_only: true,
+            ..DiskManagerOptions::default()
+        };
+        Self::open_with(heap_file_path, options)
+    }
+
+    // 表領域のファイルもあわせて開く
+    fn open_in(
+        heap_file_path: &Path,
+        page_size: usize,
+        wrap: WrapHeapFile,
+        options: DiskManagerOptions,
+    ) -> io::Result<Self> {
+        let mut disk = Self::open_file(heap_file_path, page_size, wrap, options)?;
+        // 表領域を作ったことのないファイルには、名前のファイルがない
+        let names = match std::fs::read_to_string(tablespace_list_path(heap_file_path)) {
+            Ok(names) => names,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        for name in names.lines() {
+            let path = tablespace_path(heap_file_path, name);
+            disk.tablespaces.push(Tablespace {
+                name: name.to_string(),
+                disk: Self::open_file(&path, page_size, wrap, options)?,
+            });
+        }
+        disk.heap_file_path = Some(heap_file_path.to_path_buf());
+        Ok(disk)
+    }
+
+    fn open_file(
+        heap_file_path: &Path,
+        page_size: usize,
+        wrap: WrapHeapFile,
+        options: DiskManagerOptions,
+    ) -> io::Result<Self> {
+        let heap_file = open_heap_file(
+            heap_file_path,
+            OpenOptions::new().read(true).write(!options.read_only),
+            page_size,
+            wrap,
+            options,
+        )?;
+        if options.read_only {
+            let mut disk = Self::from_heap_file(heap_file, page_size, Some(heap_file_path))?;
+            disk.options = options;
+            return Ok(disk);
+        }
+        let double_write_file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(heap_file_path)?;
-        Self::new(heap_file)
+            .truncate(false)
+            .open(double_write_path(heap_file_path))?;
+        let mut disk = Self::from_heap_file(heap_file, page_size, Some(heap_file_path))?
+            .with_double_write(double_write_file)?;
+        disk.options = options;
+        Ok(disk)
     }
 
-    // 新しいページIDを採番する
-    pub fn allocate_page(&mut self) -> PageId {
-        let page_id = self.next_page_id;
-        self.next_page_id += 1;
-        PageId(page_id)
+    pub fn page_size(&self) -> usize {
+        self.page_size
     }
 
-    // ページのデータを読み出す
-    pub fn read_page_data(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
-        // オフセットを計算
-        let offset = PAGE_SIZE as u64 * page_id.to_u64();
+    pub fn is_read_only(&self) -> bool {
+        self.options.read_only
+    }
 
-        // ページ先頭へシーク
-        self.heap_file.seek(SeekFrom::Start(offset))?;
+    fn check_writable(&self) -> io::Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly.into());
+        }
+        Ok(())
+    }
 
-        // データを書き込む
-        self.heap_file.read_exact(data)
+    // O_DIRECTで読み書きしているかどうか。unix以外では常にfalse
+    pub fn is_direct_io(&self) -> bool {
+        match self.heap_file {
+            #[cfg(unix)]
+            HeapFile::Direct(_) => true,
+            _ => false,
+        }
     }
 
-    // データをページに書き出す
-    pub fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
-        // オフセットを計算
-        let offset = PAGE_SIZE as u64 * page_id.to_u64();
+    // 新しい表領域を作る
+    // ヒープファイルの隣に表領域のファイルを作り、名前の一覧に加える
+    pub fn create_tablespace(&mut self, name: &str) -> io::Result<TablespaceId> {
+        self.check_writable()?;
+        let heap_file_path = self.heap_file_path.clone().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "tablespaces need a heap file opened by path",
+            )
+        })?;
+        let valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_name {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid tablespace name {:?}", name),
+            ));
+        }
+        if self.tablespace_id(name).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("tablespace {} already exists", name),
+            ));
+        }
+        if self.tablespaces.len() >= MAX_TABLESPACES {
+            return Err(io::Error::other("too many tablespaces"));
+        }
+        // ヒープファイルと同じ方法で読み書きする
+        let wrap = match self.heap_file {
+            HeapFile::File(_) => plain_heap_file,
+            #[cfg(unix)]
+            HeapFile::Direct(_) => plain_heap_file,
+            #[cfg(unix)]
+            HeapFile::Mmap(_) => mmap::mmap_heap_file,
+        };
+        let path = tablespace_path(&heap_file_path, name);
+        let disk = Self::create_file(&path, self.page_size, wrap, self.options)?;
+        self.tablespaces.push(Tablespace {
+            name: name.to_string(),
+            disk: disk.with_extent_pages(self.extent_pages),
+        });
+        self.write_tablespace_list()?;
+        Ok(TablespaceId(self.tablespaces.len() as u16))
+    }
 
-        // ページ先頭へシーク
-        self.heap_file.seek(SeekFrom::Start(offset))?;
+    // 名前から表領域のIDを引く
+    pub fn tablespace_id(&self, name: &str) -> Option<TablespaceId> {
+        self.tablespaces
+            .iter()
+            .position(|tablespace| tablespace.name == name)
+            .map(|idx| TablespaceId(idx as u16 + 1))
+    }
 
-        // データを書き込む
-        self.heap_file.write_all(data)
+    // 作った順に、表領域のIDと名前を返す
+    pub fn tablespaces(&self) -> Vec<(TablespaceId, String)> {
+        self.tablespaces
+            .iter()
+            .enumerate()
+            .map(|(idx, tablespace)| (TablespaceId(idx as u16 + 1), tablespace.name.clone()))
+            .collect()
     }
 
-    pub fn sync(&mut self) -> io::Result<()> {
-        self.heap_file.flush()?;
-        self.heap_file.sync_all()
+    // 表領域で、空きページがないときに次に採番するページID
+    pub fn next_page_id_in(&self, tablespace_id: TablespaceId) -> io::Result<PageId> {
+        if tablespace_id == TablespaceId::DEFAULT {
+            return Ok(self.next_page_id());
+        }
+        match self.tablespaces.get(tablespace_id.0 as usize - 1) {
+            Some(tablespace) => Ok(PageId::new(tablespace_id, tablespace.disk.next_page_id)),
+            None => Err(unknown_tablespace(tablespace_id)),
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::NamedTempFile;
+    // 表領域の空きページのリストを先頭からたどる
+    pub fn free_page_ids_in(&mut self, tablespace_id: TablespaceId) -> io::Result<Vec<PageId>> {
+        if tablespace_id == TablespaceId::DEFAULT {
+            return self.free_page_ids();
+        }
+        let page_ids = match self.tablespaces.get_mut(tablespace_id.0 as usize - 1) {
+            Some(tablespace) => tablespace.disk.free_page_ids()?,
+            None => return Err(unknown_tablespace(tablespace_id)),
+        };
+        Ok(page_ids
+            .into_iter()
+            .map(|page_id| PageId::new(tablespace_id, page_id.to_u64()))
+            .collect())
+    }
 
-    #[test]
-    fn test() {
-        let (data_file, data_file_path) = NamedTempFile::new().unwrap().into_parts();
-        let mut disk = DiskManager::new(data_file).unwrap();
-        let mut hello = Vec::with_capacity(PAGE_SIZE);
-        hello.extend_from_slice(b"hello");
-        hello.resize(PAGE_SIZE, 0);
-        let hello_page_id = disk.allocate_page();
-        disk.write_page_data(hello_page_id, &hello).unwrap();
-        println!("{:?}", disk);
-        let mut world = Vec::with_capacity(PAGE_SIZE);
-        world.extend_from_slice(b"world");
-        world.resize(PAGE_SIZE, 0);
-        let world_page_id = disk.allocate_page();
-        disk.write_page_data(world_page_id, &world).unwrap();
-        drop(disk);
-        let mut disk2 = DiskManager::open(&data_file_path).unwrap();
-        let mut buf = vec![0; PAGE_SIZE];
+    fn write_tablespace_list(&self) -> io::Result<()> {
+        let heap_file_path = match &self.heap_file_path {
+            Some(heap_file_path) => heap_file_path,
+            None => return Ok(()),
+        };
+        let mut names = String::new();
+        for tablespace in self.tablespaces.iter() {
+            names.push_str(&tablespace.name);
+            names.push('\n');
+        }
+        let mut file = File::create(tablespace_list_path(heap_file_path))?;
+        file.write_all(names.as_bytes())?;
+        file.sync_all()
+    }
 
-        disk2.read_page_data(hello_page_id, &mut buf).unwrap();
-        assert_eq!(hello, buf);
-        disk2.read_page_data(world_page_id, &mut buf).unwrap();
+    // デフォルト以外の表領域のページなら、その表領域のDiskManagerを返す
+    // 表領域のDiskManagerには、表領域の中でのページ番号をページIDとして渡す
+    fn tablespace_disk(&mut self, page_id: PageId) -> io::Result<Option<&mut DiskManager>> {
+        let tablespace_id = page_id.tablespace_id();
+        if tablespace_id == TablespaceId::DEFAULT {
+            return Ok(None);
+        }
+        self.tablespaces
+            .get_mut(tablespace_id.0 as usize - 1)
+            .map(|tablespace| Some(&mut tablespace.disk))
+            .ok_or_else(|| unknown_tablespace(tablespace_id))
+    }
+
+    // 表領域を指定してページIDを採番する
+    pub fn allocate_page_in(&mut self, tablespace_id: TablespaceId) -> io::Result<PageId> {
+        if tablespace_id == TablespaceId::DEFAULT {
+            return self.allocate_page();
+        }
+        let page_id = match self.tablespaces.get_mut(tablespace_id.0 as usize - 1) {
+            Some(tablespace) => tablespace.disk.allocate_page()?,
+            None => return Err(unknown_tablespace(tablespace_id)),
+        };
+        Ok(PageId::new(tablespace_id, page_id.to_u64()))
+    }
+
+    // 新しいページIDを採番する
+    // 解放されたページがあれば、ファイルを伸ばさずにそれを再利用する
+    pub fn allocate_page(&mut self) -> io::Result<PageId> {
+        self.check_writable()?;
+        if let Some(page_id) = self.free_list_head.valid() {
+            let mut page = vec![0; self.page_size];
+            self.read_page_data(page_id, &mut page)?;
+            self.free_list_head = PageId::from(&page[..8]);
+            self.num_free_pages -= 1;
+            if let Some(free_pages) = self.free_pages.as_mut() {
+                free_pages.remove(&page_id);
+            }
+            self.write_header_counts()?;
+            return Ok(page_id);
+        }
+        let page_id = self.next_page_id;
+        self.next_page_id += 1;
+        // 確保済みの領域を使い切ったら、エクステント単位でファイルを伸ばす
+        // 圧縮する形式では物理ブロックを変換表が割り当てるので、伸ばさない
+        if self.is_compressed() {
+            self.write_header_counts()?;
+            return Ok(PageId(page_id));
+        }
+        let allocated_pages = (self.heap_file.len()? / self.page_size as u64).saturating_sub(1);
+        if self.next_page_id > allocated_pages {
+            let len = self.offset(PageId(allocated_pages + self.extent_pages));
+            self.heap_file.extend(len)?;
+        }
+        // 採番したページの数をヘッダに残す
+        self.write_header_counts()?;
+        Ok(PageId(page_id))
+    }
+
+    // 使用中のページの数。解放したページは数えない
+    // 表領域のページも含める
+    pub fn num_pages(&self) -> u64 {
+        let tablespace_pages: u64 = self.tablespaces.iter().map(|t| t.disk.num_pages()).sum();
+        self.next_page_id - self.num_free_pages + tablespace_pages
+    }
+
+    // 空きページがないときに、次に採番するページID
+    pub fn next_page_id(&self) -> PageId {
+        PageId(self.next_page_id)
+    }
+
+    // ヘッダページと先に確保した領域を含めた、ファイルの大きさ
+    // 表領域のファイルも含める
+    pub fn file_size_bytes(&self) -> io::Result<u64> {
+        let mut size = self.heap_file.len()?;
+        for tablespace in self.tablespaces.iter() {
+            size += tablespace.disk.file_size_bytes()?;
+        }
+        Ok(size)
+    }
+
+    // ページを解放して空きページのリストにつなぐ
+    // 解放済みのページをもう一度つなぐとリストが輪になり、同じページを二重に採番してしまうので、
+    // 採番していないページと同じくエラーにする。ヘッダページはページIDを持たないので解放できない
+    pub fn deallocate_page(&mut self, page_id: PageId) -> io::Result<()> {
+        self.check_writable()?;
+        if let Some(disk) = self.tablespace_disk(page_id)? {
+            return disk.deallocate_page(PageId(page_id.page_number()));
+        }
+        if page_id.to_u64() >= self.next_page_id || self.is_free(page_id)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("page {} is not allocated", page_id.to_u64()),
+            ));
+        }
+        // 空きページの先頭には、次の空きページのIDを書いておく
+        // 残りはそのままにして、解放する前に書いた印を読めるようにする
+        // 一度も書き込まれていないページは0で埋め、それ以外の読み出しのエラーはそのまま返す
+        let mut page = vec![0; self.page_size];
+        match self.read_page_data(page_id, &mut page) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => page.fill(0),
+            Err(err) => return Err(err),
+        }
+        page[..8].copy_from_slice(self.free_list_head.as_bytes());
+        self.write_page_data(page_id, &page)?;
+        self.free_list_head = page_id;
+        self.num_free_pages += 1;
+        self.write_header_counts()?;
+        if let Some(free_pages) = self.free_pages.as_mut() {
+            free_pages.insert(page_id);
+        }
+        Ok(())
+    }
+
+    // 空きページのリストに載っているかどうか
+    fn is_free(&mut self, page_id: PageId) -> io::Result<bool> {
+        let free_pages = match self.free_pages.take() {
+            Some(free_pages) => free_pages,
+            None => self.free_page_ids()?.into_iter().collect(),
+        };
+        let is_free = free_pages.contains(&page_id);
+        self.free_pages = Some(free_pages);
+        Ok(is_free)
+    }
+
+    // 最後の使用中のページより後ろにある空きページと、先に確保しておいた領域を切り詰める
+    // 使用中のページは動かさない。減ったバイト数を返す
+    pub fn compact(&mut self) -> io::Result<u64> {
+        self.check_writable()?;
+        if self.is_compressed() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "compressed heap files cannot be compacted",
+            ));
+        }
+        let mut reclaimed = 0;
+        for tablespace in self.tablespaces.iter_mut() {
+            reclaimed += tablespace.disk.compact()?;
+        }
+        let free_page_ids = self.free_page_ids()?;
+        // 空きページごとの、リストで次につながっているページ
+        let old_next: HashMap<_, _> = free_page_ids
+            .iter()
+            .enumerate()
+            .map(|(i, &page_id)| {
+                let next = free_page_ids.get(i + 1).copied();
+                (page_id, next.unwrap_or(PageId::INVALID_PAGE_ID))
+            })
+            .collect();
+        let mut live_end = self.next_page_id;
+        while live_end > 0 && old_next.contains_key(&PageId(live_end - 1)) {
+            live_end -= 1;
+        }
+        if live_end < self.next_page_id {
+            // 残る空きページをつなぎ直してから、ヘッダを書き換える
+            // 途中で落ちても、取り除いたページが使われなくなるだけで済む
+            let kept: Vec<_> = free_page_ids
+                .into_iter()
+                .filter(|page_id| page_id.to_u64() < live_end)
+                .collect();
+            let mut page = vec![0; self.page_size];
+            for (i, &page_id) in kept.iter().enumerate() {
+                let next = kept.get(i + 1).copied().unwrap_or(PageId::INVALID_PAGE_ID);
+                if old_next[&page_id] == next {
+                    continue;
+                }
+                // ページのほかの部分(暗号化のカウンタなど)は残す
+                self.read_page_data(page_id, &mut page)?;
+                page[..8].copy_from_slice(next.as_bytes());
+                self.write_page_data(page_id, &page)?;
+            }
+            self.free_list_head = kept.first().copied().unwrap_or(PageId::INVALID_PAGE_ID);
+            self.num_free_pages = kept.len() as u64;
+            self.free_pages = Some(kept.iter().copied().collect());
+            self.next_page_id = live_end;
+            self.write_header()?;
+            self.heap_file.sync_data()?;
+        }
+        self.read_ahead.clear();
+        let len = self.heap_file.len()?;
+        let new_len = self.offset(PageId(live_end));
+        if new_len < len {
+            self.heap_file.truncate(new_len)?;
+            reclaimed += len - new_len;
+        }
+        Ok(reclaimed)
+    }
+
+    // チェックサム付きの形式かどうか
+    pub fn has_checksum(&self) -> bool {
+        self.checksum
+    }
+
+    // ページを圧縮する形式かどうか
+    pub fn is_compressed(&self) -> bool {
+        self.translation.is_some()
+    }
+
+    // チェックサム付きや圧縮する形式では、ページ全体を一度に読み書きする必要がある
+    fn check_page_len(&self, len: usize) -> io::Result<()> {
+        if (self.checksum || self.is_compressed()) && len != self.page_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "checksummed pages must be read and written as a whole",
+            ));
+        }
+        Ok(())
+    }
+
+    // ヘッダに記録したメタページのID
+    pub fn meta_page_id(&self) -> PageId {
+        self.meta_page_id
+    }
+
+    pub fn set_meta_page_id(&mut self, meta_page_id: PageId) -> io::Result<()> {
+        self.check_writable()?;
+        self.meta_page_id = meta_page_id;
+        self.write_header()
+    }
+
+    // ファイル上のオフセットを計算する。先頭のヘッダページの分ずらす
+    fn offset(&self, page_id: PageId) -> u64 {
+        self.page_size as u64 * (page_id.to_u64() + 1)
+    }
+
+    fn header(&self) -> FileHeader {
+        FileHeader {
+            magic: HEADER_MAGIC,
+            version: FORMAT_VERSION,
+            page_size: self.page_size as u32,
+            flags: if self.checksum { FLAG_CHECKSUM } else { 0 }
+                | if self.is_compressed() { FLAG_COMPRESSION } else { 0 },
+            free_list_head: self.free_list_head,
+            meta_page_id: self.meta_page_id,
+            num_pages: self.next_page_id,
+            num_free_pages: self.num_free_pages,
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let header = self.header();
+        let mut page = vec![0; self.page_size];
+        page[..header.as_bytes().len()].copy_from_slice(header.as_bytes());
+        self.heap_file.write_at(0, &page)?;
+        self.header_written = true;
+        Ok(())
+    }
+
+    // ページの採番と解放で変わる、空きページのリストの先頭からページ数までの欄だけを書き直す
+    // 採番のたびにヘッダページ全体を書かないようにする
+    fn write_header_counts(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            return self.write_header();
+        }
+        let header = self.header();
+        let bytes = &header.as_bytes()[HEADER_COUNTS_OFFSET..];
+        self.heap_file.write_at(HEADER_COUNTS_OFFSET as u64, bytes)
+    }
+
+    // ページのデータを読み出す
+    pub fn read_page_data(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
+        if let Some(disk) = self.tablespace_disk(page_id)? {
+            return disk.read_page_data(PageId(page_id.page_number()), data);
+        }
+        self.check_page_len(data.len())?;
+        // ファイルは先に伸ばしてあるので、採番していないページも読めてしまう
+        // シークする前に範囲を確かめる
+        if page_id.to_u64() >= self.next_page_id {
+            return Err(Error::PageOutOfRange {
+                page_id,
+                num_pages: self.next_page_id,
+            }
+            .into());
+        }
+        let page_number = page_id.to_u64();
+        let prefetch = self.read_ahead.record(page_number);
+        match self.read_ahead.take(page_number) {
+            Some(page) => data.copy_from_slice(&page[..data.len()]),
+            None => {
+                self.read_raw(page_id, data)?;
+                self.read_ahead.stats.file_reads += 1;
+            }
+        }
+        self.verify_page(page_id, data)?;
+        if prefetch {
+            self.prefetch(page_number + 1);
+        }
+        Ok(())
+    }
+
+    // 一度も書き込まれていない(すべて0の)ページは検証しない
+    fn verify_page(&self, page_id: PageId, data: &[u8]) -> io::Result<()> {
+        if self.checksum && !checksum::verify(data) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                ChecksumMismatch { page_id },
+            ));
+        }
+        Ok(())
+    }
+
+    // startから続くページをまとめて読み出す
+    // 採番済みのページの分だけ読み、読んだページの数を返す
+    pub fn read_pages(&mut self, start: PageId, bufs: &mut [AlignedBuffer]) -> io::Result<usize> {
+        if let Some(disk) = self.tablespace_disk(start)? {
+            return disk.read_pages(PageId(start.page_number()), bufs);
+        }
+        for buf in bufs.iter() {
+            if buf.len() != self.page_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "read_pages needs whole pages",
+                ));
+            }
+        }
+        let count = (bufs.len() as u64).min(self.next_page_id.saturating_sub(start.to_u64()));
+        let bufs = &mut bufs[..count as usize];
+        // 圧縮する形式ではページが連続して並んでいないので、1ページずつ読む
+        if self.is_compressed() {
+            for (i, buf) in bufs.iter_mut().enumerate() {
+                self.read_page_data(PageId(start.to_u64() + i as u64), buf)?;
+            }
+            return Ok(bufs.len());
+        }
+        let pages = self.read_contiguous(start.to_u64(), bufs.len())?;
+        for (i, (buf, page)) in bufs.iter_mut().zip(pages.chunks(self.page_size)).enumerate() {
+            buf.copy_from_slice(page);
+            self.verify_page(PageId(start.to_u64() + i as u64), buf)?;
+        }
+        Ok(bufs.len())
+    }
+
+    // 連続したcount個のページを1回の読み出しで読む
+    fn read_contiguous(&mut self, start: u64, count: usize) -> io::Result<AlignedBuffer> {
+        let mut pages = AlignedBuffer::new(count * self.page_size);
+        if count > 0 {
+            let len = self.heap_file.read_partial(self.offset(PageId(start)), &mut pages)?;
+            self.read_ahead.stats.file_reads += 1;
+            if len < pages.len() {
+                return Err(Error::ShortRead {
+                    page_id: PageId(start + (len / self.page_size) as u64),
+                    got: len % self.page_size,
+                    expected: self.page_size,
+                }
+                .into());
+            }
+        }
+        Ok(pages)
+    }
+
+    // startから先読みする。先読みは投機的なので、失敗しても読み出し自体は失敗させない
+    fn prefetch(&mut self, start: u64) {
+        if self.is_compressed() {
+            return;
+        }
+        let count = (self.read_ahead.window() as u64).min(self.next_page_id.saturating_sub(start));
+        if count == 0 {
+            return;
+        }
+        if let Ok(pages) = self.read_contiguous(start, count as usize) {
+            let pages = pages.chunks(self.page_size).map(AlignedBuffer::from).collect();
+            self.read_ahead.fill(start, pages);
+        }
+    }
+
+    pub fn read_ahead_stats(&self) -> ReadAheadStats {
+        self.read_ahead.stats
+    }
+
+    // データをページに書き出す
+    pub fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
+        self.check_writable()?;
+        if let Some(disk) = self.tablespace_disk(page_id)? {
+            return disk.write_page_data(PageId(page_id.page_number()), data);
+        }
+        self.check_page_len(data.len())?;
+        // 新しいファイルでは、最初の書き込みの前にヘッダページを置く
+        if !self.header_written {
+            self.write_header()?;
+        }
+        if self.checksum {
+            let mut page = AlignedBuffer::from(data);
+            checksum::stamp(&mut page);
+            self.write_raw(page_id, &page)
+        } else {
+            self.write_raw(page_id, data)
+        }
+    }
+
+    // 複数のページを書き出す
+    // ダブルライトバッファがあれば、先にそこへ書いて永続化してから本来の場所に書く
+    pub fn write_pages_atomic(&mut self, pages: &[(PageId, &[u8])]) -> io::Result<()> {
+        self.check_writable()?;
+        // デフォルト以外の表領域のページは、それぞれの表領域でまとめて書き出す
+        let (pages, other_pages): (Vec<_>, Vec<_>) = pages
+            .iter()
+            .copied()
+            .partition(|(page_id, _)| page_id.tablespace_id() == TablespaceId::DEFAULT);
+        if !other_pages.is_empty() {
+            self.write_tablespace_pages(&other_pages)?;
+        }
+        if pages.is_empty() {
+            return Ok(());
+        }
+        let pages = &pages[..];
+        if self.double_write.is_none() {
+            for (page_id, data) in pages {
+                self.write_page_data(*page_id, data)?;
+            }
+            return Ok(());
+        }
+        if !self.header_written {
+            self.write_header()?;
+        }
+        let mut stamped = Vec::with_capacity(pages.len());
+        for (page_id, data) in pages {
+            self.check_page_len(data.len())?;
+            let mut page = AlignedBuffer::from(*data);
+            if self.checksum {
+                checksum::stamp(&mut page);
+            }
+            stamped.push((*page_id, page));
+        }
+        let stamped: Vec<_> = stamped
+            .iter()
+            .map(|(page_id, page)| (*page_id, &page[..]))
+            .collect();
+        self.double_write.as_mut().unwrap().write(&stamped)?;
+        for (page_id, page) in stamped.iter() {
+            self.write_raw(*page_id, page)?;
+        }
+        self.sync_data()?;
+        self.double_write.as_mut().unwrap().clear()
+    }
+
+    fn write_tablespace_pages(&mut self, pages: &[(PageId, &[u8])]) -> io::Result<()> {
+        for (page_id, _) in pages {
+            self.tablespace_disk(*page_id)?;
+        }
+        for (idx, tablespace) in self.tablespaces.iter_mut().enumerate() {
+            let tablespace_id = TablespaceId(idx as u16 + 1);
+            let batch: Vec<_> = pages
+                .iter()
+                .filter(|(page_id, _)| page_id.tablespace_id() == tablespace_id)
+                .map(|(page_id, data)| (PageId(page_id.page_number()), *data))
+                .collect();
+            if !batch.is_empty() {
+                tablespace.disk.write_pages_atomic(&batch)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_raw(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
+        if self.is_compressed() {
+            return self.read_compressed(page_id, data);
+        }
+        // オフセットを計算
+        let offset = self.offset(page_id);
+
+        // データを読み出す
+        let len = self.heap_file.read_partial(offset, data)?;
+        if len < data.len() {
+            return Err(Error::ShortRead {
+                page_id,
+                got: len,
+                expected: data.len(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn write_raw(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
+        self.read_ahead.invalidate(page_id.to_u64());
+        if self.is_compressed() {
+            return self.write_compressed(page_id, data);
+        }
+        // オフセットを計算
+        let offset = self.offset(page_id);
+
+        // データを書き込む
+        self.heap_file.write_at(offset, data)
+    }
+
+    fn slot_offset(&self, block: u64, slot: u8) -> u64 {
+        self.offset(PageId(block)) + (slot as usize * self.page_size / SLOTS_PER_BLOCK) as u64
+    }
+
+    fn read_compressed(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
+        let location = match self.translation.as_ref().unwrap().lookup(page_id.to_u64()) {
+            Some(location) => location,
+            None => {
+                // 一度も書き込まれていないページ
+                data.fill(0);
+                return Ok(());
+            }
+        };
+        let mut stored = vec![0; location.len as usize];
+        let offset = self.slot_offset(location.block, location.slot);
+        self.heap_file.read_at(offset, &mut stored)?;
+        if location.is_raw() {
+            data.copy_from_slice(&stored);
+            return Ok(());
+        }
+        match compress::decompress(&stored, data) {
+            Some(len) if len == data.len() => Ok(()),
+            _ => Err(invalid_data(format!(
+                "failed to decompress page {}",
+                page_id.to_u64()
+            ))),
+        }
+    }
+
+    // 圧縮して、空いているスロットに書き込む
+    // 圧縮しても1ブロックに収まるほど小さくならなければ、そのままブロック全体に置く
+    fn write_compressed(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
+        let slot_size = self.page_size / SLOTS_PER_BLOCK;
+        let compressed = compress::compress(data);
+        let stored = if compressed.len() <= slot_size * (SLOTS_PER_BLOCK - 1) {
+            &compressed[..]
+        } else {
+            data
+        };
+        let location = self.translation.as_mut().unwrap().assign(
+            page_id.to_u64(),
+            stored.len().div_ceil(slot_size),
+            stored.len(),
+        );
+        let offset = self.slot_offset(location.block, location.slot);
+        self.heap_file.write_at(offset, stored)?;
+        self.translation.as_mut().unwrap().log(page_id.to_u64(), location)
+    }
+
+    // ページを永続化してから、それを指す変換表を永続化する
+    fn persist_translation(&mut self) -> io::Result<()> {
+        match self.translation.as_mut() {
+            Some(translation) => translation.persist(),
+            None => Ok(()),
+        }
+    }
+
+    // 書き込めないように開いたときは、永続化するものがない
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.options.read_only {
+            return Ok(());
+        }
+        for tablespace in self.tablespaces.iter_mut() {
+            tablespace.disk.sync()?;
+        }
+        self.heap_file.sync_all()?;
+        self.persist_translation()
+    }
+
+    // sync_allより安価だが、ファイルサイズ以外のメタデータは永続化されない
+    pub fn sync_data(&mut self) -> io::Result<()> {
+        if self.options.read_only {
+            return Ok(());
+        }
+        for tablespace in self.tablespaces.iter_mut() {
+            tablespace.disk.sync_data()?;
+        }
+        self.heap_file.sync_data()?;
+        self.persist_translation()
+    }
+}
+
+impl Storage for DiskManager {
+    fn read_page_data(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
+        DiskManager::read_page_data(self, page_id, data)
+    }
+
+    fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
+        DiskManager::write_page_data(self, page_id, data)
+    }
+
+    fn write_pages_atomic(&mut self, pages: &[(PageId, &[u8])]) -> io::Result<()> {
+        DiskManager::write_pages_atomic(self, pages)
+    }
+
+    fn page_size(&self) -> usize {
+        DiskManager::page_size(self)
+    }
+
+    fn allocate_page(&mut self) -> io::Result<PageId> {
+        DiskManager::allocate_page(self)
+    }
+
+    fn allocate_page_in(&mut self, tablespace_id: TablespaceId) -> io::Result<PageId> {
+        DiskManager::allocate_page_in(self, tablespace_id)
+    }
+
+    fn deallocate_page(&mut self, page_id: PageId) -> io::Result<()> {
+        DiskManager::deallocate_page(self, page_id)
+    }
+
+    fn meta_page_id(&self) -> PageId {
+        DiskManager::meta_page_id(self)
+    }
+
+    fn set_meta_page_id(&mut self, meta_page_id: PageId) -> io::Result<()> {
+        DiskManager::set_meta_page_id(self, meta_page_id)
+    }
+
+    fn num_pages(&self) -> u64 {
+        DiskManager::num_pages(self)
+    }
+
+    fn next_page_id(&self) -> PageId {
+        DiskManager::next_page_id(self)
+    }
+
+    fn file_size_bytes(&self) -> io::Result<u64> {
+        DiskManager::file_size_bytes(self)
+    }
+
+    fn free_page_ids(&mut self) -> io::Result<Vec<PageId>> {
+        DiskManager::free_page_ids(self)
+    }
+
+    fn tablespaces(&self) -> Vec<(TablespaceId, String)> {
+        DiskManager::tablespaces(self)
+    }
+
+    fn next_page_id_in(&self, tablespace_id: TablespaceId) -> io::Result<PageId> {
+        DiskManager::next_page_id_in(self, tablespace_id)
+    }
+
+    fn free_page_ids_in(&mut self, tablespace_id: TablespaceId) -> io::Result<Vec<PageId>> {
+        DiskManager::free_page_ids_in(self, tablespace_id)
+    }
+
+    fn compact(&mut self) -> io::Result<u64> {
+        DiskManager::compact(self)
+    }
+
+    fn is_read_only(&self) -> bool {
+        DiskManager::is_read_only(self)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        DiskManager::sync(self)
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        DiskManager::sync_data(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn flip_byte(path: &Path, offset: u64) {
+        let mut file = OpenOptions::new().read(true).write(true).open(path).unwrap();
+        let mut byte = [0];
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[byte[0] ^ 0xFF]).unwrap();
+    }
+
+    #[test]
+    fn test() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let mut disk = DiskManager::new(data_file).unwrap();
+        let mut hello = Vec::with_capacity(PAGE_SIZE);
+        hello.extend_from_slice(b"hello");
+        hello.resize(PAGE_SIZE, 0);
+        // 書き出すときに末尾へチェックサムが入る
+        checksum::stamp(&mut hello);
+        let hello_page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(hello_page_id, &hello).unwrap();
+        println!("{:?}", disk);
+        let mut world = Vec::with_capacity(PAGE_SIZE);
+        world.extend_from_slice(b"world");
+        world.resize(PAGE_SIZE, 0);
+        checksum::stamp(&mut world);
+        let world_page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(world_page_id, &world).unwrap();
+        drop(disk);
+        let mut disk2 = DiskManager::open(&data_file_path).unwrap();
+        let mut buf = vec![0; PAGE_SIZE];
+
+        disk2.read_page_data(hello_page_id, &mut buf).unwrap();
+        assert_eq!(hello, buf);
+        disk2.read_page_data(world_page_id, &mut buf).unwrap();
         assert_eq!(world, buf);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_free_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let mut disk = DiskManager::new(data_file).unwrap().with_extent_pages(1);
+        let mut page = vec![0xAB; PAGE_SIZE];
+        checksum::stamp(&mut page);
+        let page_ids: Vec<_> = (0..100).map(|_| disk.allocate_page().unwrap()).collect();
+        for &page_id in &page_ids {
+            disk.write_page_data(page_id, &page).unwrap();
+        }
+        let file_size = || std::fs::metadata(&data_file_path).unwrap().len();
+        let size = file_size();
+        assert_eq!(101 * PAGE_SIZE as u64, size);
+
+        for &page_id in page_ids.iter().step_by(2) {
+            disk.deallocate_page(page_id).unwrap();
+        }
+        let mut reused: Vec<_> = (0..50).map(|_| disk.allocate_page().unwrap()).collect();
+        for &page_id in &reused {
+            disk.write_page_data(page_id, &page).unwrap();
+        }
+        reused.sort();
+        assert_eq!(page_ids.iter().step_by(2).copied().collect::<Vec<_>>(), reused);
+        assert_eq!(size, file_size());
+        // 空きページがなくなれば、ファイルを伸ばして採番する
+        assert_eq!(PageId(100), disk.allocate_page().unwrap());
+
+        // 空きページのリストは開き直しても残る
+        disk.deallocate_page(page_ids[10]).unwrap();
+        disk.deallocate_page(page_ids[20]).unwrap();
+        drop(disk);
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(page_ids[20], disk.allocate_page().unwrap());
+        assert_eq!(page_ids[10], disk.allocate_page().unwrap());
+        // 採番済みのページ数はヘッダに残っているので、書き込む前のページも再利用しない
+        assert_eq!(PageId(101), disk.allocate_page().unwrap());
+        let mut buf = vec![0; PAGE_SIZE];
+        disk.read_page_data(page_ids[99], &mut buf).unwrap();
+        assert_eq!(page, buf);
+
+        assert!(disk.deallocate_page(PageId(1000)).is_err());
+        assert!(disk.deallocate_page(PageId::INVALID_PAGE_ID).is_err());
+
+        // 解放済みのページは、開き直したあとでももう一度解放できない
+        disk.deallocate_page(page_ids[30]).unwrap();
+        let err = disk.deallocate_page(page_ids[30]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        drop(disk);
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        assert!(disk.deallocate_page(page_ids[30]).is_err());
+        assert_eq!(vec![page_ids[30]], disk.free_page_ids().unwrap());
+        assert_eq!(page_ids[30], disk.allocate_page().unwrap());
+        assert_ne!(page_ids[30], disk.allocate_page().unwrap());
+        disk.deallocate_page(page_ids[30]).unwrap();
+    }
+
+    fn header(flags: u64) -> FileHeader {
+        FileHeader {
+            magic: HEADER_MAGIC,
+            version: FORMAT_VERSION,
+            page_size: PAGE_SIZE as u32,
+            flags,
+            free_list_head: PageId::INVALID_PAGE_ID,
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_pages: 0,
+            num_free_pages: 0,
+        }
+    }
+
+    fn write_file(path: &Path, header: &FileHeader, pages: &[&[u8]]) {
+        let mut file = File::create(path).unwrap();
+        let mut page = vec![0; PAGE_SIZE];
+        page[..header.as_bytes().len()].copy_from_slice(header.as_bytes());
+        file.write_all(&page).unwrap();
+        for page in pages {
+            file.write_all(page).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut disk = DiskManager::create(&data_file_path).unwrap();
+        assert_eq!(PAGE_SIZE as u64, std::fs::metadata(&data_file_path).unwrap().len());
+        assert_eq!(PageId::INVALID_PAGE_ID, disk.meta_page_id());
+        let meta_page_id = disk.allocate_page().unwrap();
+        disk.set_meta_page_id(meta_page_id).unwrap();
+        drop(disk);
+        let disk = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(meta_page_id, disk.meta_page_id());
+        drop(disk);
+
+        // ヘッダのない(ヒープファイルでない)ファイルは開けない
+        let expect_invalid = |message: &str| {
+            let err = DiskManager::open(&data_file_path).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            assert!(err.to_string().contains(message), "{}", err);
+        };
+        std::fs::write(&data_file_path, vec![0xAB; PAGE_SIZE * 2]).unwrap();
+        expect_invalid("bad magic");
+        std::fs::write(&data_file_path, b"hello").unwrap();
+        expect_invalid("too short");
+        let mut bad_page_size = header(0);
+        bad_page_size.page_size = 512;
+        write_file(&data_file_path, &bad_page_size, &[]);
+        expect_invalid("page size mismatch");
+        let mut bad_version = header(0);
+        bad_version.version = FORMAT_VERSION + 1;
+        write_file(&data_file_path, &bad_version, &[]);
+        expect_invalid("unsupported format version");
+
+        // 既存のファイルをcreateすると作り直す
+        let disk = DiskManager::create(&data_file_path).unwrap();
+        assert_eq!(PageId::INVALID_PAGE_ID, disk.meta_page_id());
+        drop(disk);
+        DiskManager::open(&data_file_path).unwrap();
+
+        std::fs::remove_file(&data_file_path).unwrap();
+        assert_eq!(
+            io::ErrorKind::NotFound,
+            DiskManager::open(&data_file_path).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data_file, data_file_path) = NamedTempFile::new_in(&dir).unwrap().into_parts();
+        let mut disk = DiskManager::new(data_file).unwrap();
+        assert!(disk.has_checksum());
+        let page_ids: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        disk.write_page_data(page_ids[0], &[1; PAGE_SIZE]).unwrap();
+        disk.write_page_data(page_ids[1], &[2; PAGE_SIZE]).unwrap();
+        // すべて0のページは書き込まれていないものとして扱う
+        disk.write_page_data(page_ids[2], &[0; PAGE_SIZE]).unwrap();
+        let mut zero_page = vec![0; PAGE_SIZE];
+        checksum::stamp(&mut zero_page);
+        drop(disk);
+
+        // ページの途中とチェックサムそのものを書き換える
+        // ヘッダページの分ずらす
+        flip_byte(&data_file_path, PAGE_SIZE as u64 * (page_ids[0].to_u64() + 1) + 100);
+        flip_byte(&data_file_path, PAGE_SIZE as u64 * (page_ids[1].to_u64() + 2) - 1);
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        assert!(disk.has_checksum());
+        let mut buf = vec![0; PAGE_SIZE];
+        for &page_id in &page_ids[..2] {
+            let err = disk.read_page_data(page_id, &mut buf).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            let mismatch = err.get_ref().unwrap().downcast_ref::<ChecksumMismatch>().unwrap();
+            assert_eq!(page_id, mismatch.page_id);
+        }
+        disk.read_page_data(page_ids[2], &mut buf).unwrap();
+
+        // 一部だけの読み書きは検証できない
+        assert!(disk.read_page_data(page_ids[0], &mut buf[..8]).is_err());
+        assert!(disk.write_page_data(page_ids[0], &buf[..8]).is_err());
+
+        // 読めないページを0で埋めて解放したりせず、エラーを返す
+        let err = disk.deallocate_page(page_ids[0]).unwrap_err();
+        assert!(err.get_ref().unwrap().is::<ChecksumMismatch>());
+        assert!(disk.free_page_ids().unwrap().is_empty());
+        disk.deallocate_page(page_ids[2]).unwrap();
+    }
+
+    #[test]
+    fn test_without_checksum_flag() {
+        // チェックサムのフラグがないヘッダのファイルでは検証しない
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut header = header(0);
+        header.num_pages = 1;
+        write_file(&data_file_path, &header, &[&[0xAB; PAGE_SIZE]]);
+
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        assert!(!disk.has_checksum());
+        let mut buf = vec![0; PAGE_SIZE];
+        disk.read_page_data(PageId(0), &mut buf).unwrap();
+        assert_eq!(vec![0xAB; PAGE_SIZE], buf);
+        disk.read_page_data(PageId(0), &mut buf[..8]).unwrap();
+        // 書き出すときもチェックサムを付けない
+        assert_eq!(PageId(1), disk.allocate_page().unwrap());
+        disk.write_page_data(PageId(1), &[0xCD; PAGE_SIZE]).unwrap();
+        disk.read_page_data(PageId(1), &mut buf).unwrap();
+        assert_eq!(vec![0xCD; PAGE_SIZE], buf);
+    }
+
+    #[test]
+    fn test_page_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut disk = DiskManager::create_with_page_size(&data_file_path, 512)
+            .unwrap()
+            .with_extent_pages(1);
+        assert_eq!(512, disk.page_size());
+        let page_id = disk.allocate_page().unwrap();
+        let mut page = vec![0xAB; 512];
+        checksum::stamp(&mut page);
+        disk.write_page_data(page_id, &page).unwrap();
+        drop(disk);
+        assert_eq!(1024, std::fs::metadata(&data_file_path).unwrap().len());
+
+        // ヘッダと異なるページサイズでは開けない
+        let err = DiskManager::open(&data_file_path).unwrap_err();
+        assert!(err.to_string().contains("page size mismatch"), "{}", err);
+        let mut disk = DiskManager::open_with_page_size(&data_file_path, 512).unwrap();
+        let mut buf = vec![0; 512];
+        disk.read_page_data(page_id, &mut buf).unwrap();
+        assert_eq!(page, buf);
+        // ページ全体でない読み書きはできない
+        assert!(disk.read_page_data(page_id, &mut vec![0; PAGE_SIZE]).is_err());
+
+        for page_size in [0, 128, 1000, 64 * 1024] {
+            let err = DiskManager::create_with_page_size(&data_file_path, page_size).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    fn test_double_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut disk = DiskManager::create(&data_file_path).unwrap();
+        let page_ids: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        let old_pages: Vec<_> = (1..=3u8).map(|b| vec![b; PAGE_SIZE]).collect();
+        let batch: Vec<_> = page_ids
+            .iter()
+            .zip(old_pages.iter())
+            .map(|(&page_id, page)| (page_id, &page[..]))
+            .collect();
+        disk.write_pages_atomic(&batch).unwrap();
+        // 書き終えたらコピーは無効になる
+        assert!(disk.double_write.as_mut().unwrap().pages().unwrap().is_empty());
+
+        // ダブルライトバッファに書いたあと、本来の場所への書き込みが途切れたことにする
+        let new_pages: Vec<_> = (0..3)
+            .map(|i| {
+                let mut page = vec![0xA0 + i as u8; PAGE_SIZE];
+                checksum::stamp(&mut page);
+                page
+            })
+            .collect();
+        let batch: Vec<_> = page_ids
+            .iter()
+            .zip(new_pages.iter())
+            .map(|(&page_id, page)| (page_id, &page[..]))
+            .collect();
+        disk.double_write.as_mut().unwrap().write(&batch).unwrap();
+        // 0ページ目は前半だけ書けた
+        disk.write_raw(page_ids[0], &new_pages[0][..PAGE_SIZE / 2]).unwrap();
+        drop(disk);
+        // 2ページ目は途中でファイルが切れた
+        let file = OpenOptions::new().write(true).open(&data_file_path).unwrap();
+        file.set_len(PAGE_SIZE as u64 * 3 + 100).unwrap();
+        drop(file);
+
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        let mut buf = vec![0; PAGE_SIZE];
+        disk.read_page_data(page_ids[0], &mut buf).unwrap();
+        assert_eq!(new_pages[0], buf);
+        // 壊れていないページは書き戻さない
+        disk.read_page_data(page_ids[1], &mut buf).unwrap();
+        let data_size = PAGE_SIZE - checksum::CHECKSUM_SIZE;
+        assert_eq!(old_pages[1][..data_size], buf[..data_size]);
+        disk.read_page_data(page_ids[2], &mut buf).unwrap();
+        assert_eq!(new_pages[2], buf);
+        assert_eq!(PageId(3), disk.allocate_page().unwrap());
+        assert!(disk.double_write.as_mut().unwrap().pages().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_extent() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut disk = DiskManager::create(&data_file_path).unwrap().with_extent_pages(64);
+        let file_size = || std::fs::metadata(&data_file_path).unwrap().len();
+        let page_ids: Vec<_> = (0..10).map(|_| disk.allocate_page().unwrap()).collect();
+        let mut page = vec![0xAB; PAGE_SIZE];
+        checksum::stamp(&mut page);
+        for &page_id in &page_ids {
+            disk.write_page_data(page_id, &page).unwrap();
+        }
+        // ヘッダページと、最初のエクステントの分だけ伸びている
+        assert_eq!(65 * PAGE_SIZE as u64, file_size());
+        assert_eq!(10, disk.num_pages());
+        let mut buf = vec![0; PAGE_SIZE];
+        assert!(disk.read_page_data(PageId(10), &mut buf).is_err());
+        drop(disk);
+
+        // 開き直したときは、ファイルサイズではなくヘッダのページ数から採番を続ける
+        let mut disk = DiskManager::open(&data_file_path).unwrap().with_extent_pages(64);
+        assert_eq!(10, disk.num_pages());
+        assert_eq!(PageId(10), disk.allocate_page().unwrap());
+        disk.read_page_data(page_ids[9], &mut buf).unwrap();
+        assert_eq!(page, buf);
+        // 使い切ると次のエクステントを確保する
+        for _ in 11..64 {
+            disk.allocate_page().unwrap();
+        }
+        assert_eq!(65 * PAGE_SIZE as u64, file_size());
+        assert_eq!(PageId(64), disk.allocate_page().unwrap());
+        assert_eq!(129 * PAGE_SIZE as u64, file_size());
+    }
+
+    #[test]
+    fn test_tablespace() {
+        let dir = tempfile::tempdir().unwrap();
+        let heap_file_path = dir.path().join("heap");
+        let mut disk = DiskManager::create(&heap_file_path).unwrap();
+        let ts = disk.create_tablespace("index").unwrap();
+        assert_eq!(TablespaceId(1), ts);
+        assert_eq!(
+            io::ErrorKind::AlreadyExists,
+            disk.create_tablespace("index").unwrap_err().kind()
+        );
+        for name in ["", "a/b", "."] {
+            let err = disk.create_tablespace(name).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+
+        // 表領域ごとに0から採番する
+        let heap_page_id = disk.allocate_page().unwrap();
+        let ts_page_id = disk.allocate_page_in(ts).unwrap();
+        assert_eq!(PageId(0), heap_page_id);
+        assert_eq!(ts, ts_page_id.tablespace_id());
+        assert_eq!(0, ts_page_id.page_number());
+        assert!(disk.allocate_page_in(TablespaceId(2)).is_err());
+        disk.write_page_data(heap_page_id, &[1; PAGE_SIZE]).unwrap();
+        disk.write_pages_atomic(&[(ts_page_id, &[2; PAGE_SIZE])]).unwrap();
+        drop(disk);
+        let ts_file_size = std::fs::metadata(tablespace_path(&heap_file_path, "index"))
+            .unwrap()
+            .len();
+        assert!(ts_file_size > PAGE_SIZE as u64);
+
+        // 表領域の一覧は開き直しても残る
+        let mut disk = DiskManager::open(&heap_file_path).unwrap();
+        assert_eq!(Some(ts), disk.tablespace_id("index"));
+        assert_eq!(None, disk.tablespace_id("data"));
+        let mut buf = vec![0; PAGE_SIZE];
+        disk.read_page_data(ts_page_id, &mut buf).unwrap();
+        assert_eq!(&[2; 8], &buf[..8]);
+        disk.read_page_data(heap_page_id, &mut buf).unwrap();
+        assert_eq!(&[1; 8], &buf[..8]);
+        disk.deallocate_page(ts_page_id).unwrap();
+        assert_eq!(ts_page_id, disk.allocate_page_in(ts).unwrap());
+        assert!(disk.read_page_data(PageId::new(TablespaceId(5), 0), &mut buf).is_err());
+
+        // パスのわからないヒープファイルには表領域を作れない
+        let mut disk = DiskManager::new(tempfile::tempfile().unwrap()).unwrap();
+        assert_eq!(
+            io::ErrorKind::Unsupported,
+            disk.create_tablespace("index").unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut disk = DiskManager::create_compressed(&data_file_path).unwrap();
+        assert!(disk.is_compressed());
+        let text_page = |i: usize| {
+            let mut page = format!("row {:04}: the quick brown fox. ", i)
+                .repeat(PAGE_SIZE / 32)
+                .into_bytes();
+            page.resize(PAGE_SIZE, 0);
+            checksum::stamp(&mut page);
+            page
+        };
+        let mut x = 0x2545_F491_4F6C_DD1Du64;
+        let mut random_page = || {
+            let mut page: Vec<u8> = (0..PAGE_SIZE)
+                .map(|_| {
+                    x ^= x << 13;
+                    x ^= x >> 7;
+                    x ^= x << 17;
+                    x as u8
+                })
+                .collect();
+            checksum::stamp(&mut page);
+            page
+        };
+        let mut expected = vec![];
+        for i in 0..20 {
+            let page_id = disk.allocate_page().unwrap();
+            // 圧縮できないページも混ぜる
+            let page = if i % 5 == 4 { random_page() } else { text_page(i) };
+            disk.write_page_data(page_id, &page).unwrap();
+            expected.push(page);
+        }
+        // 書き直すと、別の場所に置き直す
+        expected[3] = text_page(100);
+        disk.write_page_data(PageId(3), &expected[3]).unwrap();
+        expected[4] = text_page(101);
+        disk.write_page_data(PageId(4), &expected[4]).unwrap();
+        let mut buf = vec![0; PAGE_SIZE];
+        for (i, page) in expected.iter().enumerate() {
+            disk.read_page_data(PageId(i as u64), &mut buf).unwrap();
+            assert_eq!(page, &buf);
+        }
+        // ページ全体でない読み書きはできない
+        assert!(disk.write_page_data(PageId(0), &buf[..8]).is_err());
+        disk.sync().unwrap();
+        drop(disk);
+        // 圧縮できたページの分だけ、ファイルが小さくなる
+        let file_size = std::fs::metadata(&data_file_path).unwrap().len();
+        assert!(file_size < 12 * PAGE_SIZE as u64, "{}", file_size);
+
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        assert!(disk.is_compressed());
+        for (i, page) in expected.iter().enumerate() {
+            disk.read_page_data(PageId(i as u64), &mut buf).unwrap();
+            assert_eq!(page, &buf);
+        }
+        assert_eq!(PageId(20), disk.allocate_page().unwrap());
+        // syncせずに閉じても、書いたページは変換表のジャーナルから読み直す
+        expected.push(text_page(20));
+        disk.write_page_data(PageId(20), &expected[20]).unwrap();
+        expected[0] = text_page(102);
+        disk.write_page_data(PageId(0), &expected[0]).unwrap();
+        drop(disk);
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        for (i, page) in expected.iter().enumerate() {
+            disk.read_page_data(PageId(i as u64), &mut buf).unwrap();
+            assert_eq!(page, &buf);
+        }
+        drop(disk);
+
+        // 変換表を開けないので、パスなしでは開けない
+        let file = OpenOptions::new().read(true).write(true).open(&data_file_path).unwrap();
+        let err = DiskManager::new(file).unwrap_err();
+        assert_eq!(io::ErrorKind::Unsupported, err.kind());
+    }
+
+    #[test]
+    fn test_num_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut disk = DiskManager::create(&data_file_path).unwrap().with_extent_pages(8);
+        assert_eq!(0, disk.num_pages());
+        assert_eq!(PageId(0), disk.next_page_id());
+        assert_eq!(PAGE_SIZE as u64, disk.file_size_bytes().unwrap());
+        let page_ids: Vec<_> = (0..10).map(|_| disk.allocate_page().unwrap()).collect();
+        disk.deallocate_page(page_ids[3]).unwrap();
+        disk.deallocate_page(page_ids[7]).unwrap();
+        assert_eq!(8, disk.num_pages());
+        assert_eq!(PageId(10), disk.next_page_id());
+        // ヘッダページと、2つのエクステント
+        assert_eq!(17 * PAGE_SIZE as u64, disk.file_size_bytes().unwrap());
+        // 再利用しても次に採番するページIDは変わらない
+        assert_eq!(page_ids[7], disk.allocate_page().unwrap());
+        assert_eq!(9, disk.num_pages());
+        assert_eq!(PageId(10), disk.next_page_id());
+        drop(disk);
+
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(9, disk.num_pages());
+        assert_eq!(PageId(10), disk.next_page_id());
+        assert_eq!(17 * PAGE_SIZE as u64, disk.file_size_bytes().unwrap());
+        disk.deallocate_page(page_ids[0]).unwrap();
+        drop(disk);
+
+        // 空きページの数を持たない古い形式では、空きページのリストをたどって数える
+        let mut header = header(0);
+        {
+            let mut file = File::open(&data_file_path).unwrap();
+            file.read_exact(header.as_bytes_mut()).unwrap();
+        }
+        header.version = FORMAT_VERSION_WITHOUT_NUM_FREE_PAGES;
+        header.num_free_pages = 0;
+        let mut file = OpenOptions::new().write(true).open(&data_file_path).unwrap();
+        file.write_all(header.as_bytes()).unwrap();
+        drop(file);
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(8, disk.num_pages());
+        assert_eq!(page_ids[0], disk.allocate_page().unwrap());
+        assert_eq!(page_ids[3], disk.allocate_page().unwrap());
+        assert_eq!(10, disk.num_pages());
+    }
+
+    #[test]
+    fn test_header_counts() {
+        let header = header(0);
+        let bytes = header.as_bytes();
+        assert_eq!(header.free_list_head.as_bytes(), &bytes[HEADER_COUNTS_OFFSET..][..8]);
+
+        // 採番と解放では、ヘッダページのうちページ数などの欄だけを書き直す
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut disk = DiskManager::create(&data_file_path).unwrap();
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &[1; PAGE_SIZE]).unwrap();
+        drop(disk);
+        flip_byte(&data_file_path, PAGE_SIZE as u64 - 1);
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        let page_ids = [disk.allocate_page().unwrap(), disk.allocate_page().unwrap()];
+        disk.deallocate_page(page_ids[0]).unwrap();
+        drop(disk);
+        let bytes = std::fs::read(&data_file_path).unwrap();
+        assert_eq!(0xFF, bytes[PAGE_SIZE - 1]);
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        assert_eq!(PageId(3), disk.next_page_id());
+        assert_eq!(vec![page_ids[0]], disk.free_page_ids().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("heap");
+        let mut options = DiskManagerOptions {
+            direct_io: true,
+            write_through: true,
+            ..DiskManagerOptions::default()
+        };
+        // O_DIRECTを使えるかどうかはファイルシステムによる
+        // 使えなければ黙って通常の読み書きにせずにエラーを返すので、そのときはO_DIRECTなしで確かめる
+        let mut disk = match DiskManager::create_with(&path, options) {
+            Ok(disk) => disk,
+            Err(err) => {
+                assert_eq!(io::ErrorKind::Unsupported, err.kind());
+                options.direct_io = false;
+                DiskManager::create_with(&path, options).unwrap()
+            }
+        };
+        let direct_io = disk.is_direct_io();
+        assert_eq!(options.direct_io, direct_io);
+        let ts = disk.create_tablespace("index").unwrap();
+        let page_ids = [disk.allocate_page().unwrap(), disk.allocate_page_in(ts).unwrap()];
+        let mut page = AlignedBuffer::new(PAGE_SIZE);
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            page.fill(i as u8 + 1);
+            disk.write_page_data(page_id, &page).unwrap();
+        }
+        // 揃っていないバッファでも読み書きできる
+        let mut unaligned = vec![0; PAGE_SIZE + 1];
+        unaligned[1..].fill(3);
+        disk.write_page_data(page_ids[0], &unaligned[1..]).unwrap();
+        disk.read_page_data(page_ids[0], &mut unaligned[1..]).unwrap();
+        assert_eq!(3, unaligned[100]);
+        disk.sync().unwrap();
+        drop(disk);
+
+        let mut disk = DiskManager::open_with(&path, options).unwrap();
+        assert_eq!(direct_io, disk.is_direct_io());
+        disk.read_page_data(page_ids[1], &mut page).unwrap();
+        assert_eq!(2, page[0]);
+        drop(disk);
+        let mut disk = DiskManager::open(&path).unwrap();
+        assert!(!disk.is_direct_io());
+        disk.read_page_data(page_ids[0], &mut page).unwrap();
+        assert_eq!(3, page[0]);
+
+        // 揃わないページサイズでは、O_DIRECTで開けない
+        let small_path = dir.path().join("small");
+        DiskManager::create_with_page_size(&small_path, 512).unwrap();
+        options.direct_io = true;
+        let err = DiskManager::open_in(&small_path, 512, plain_heap_file, options).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut disk = DiskManager::create(&data_file_path).unwrap();
+        let page_ids: Vec<_> = (0..64).map(|_| disk.allocate_page().unwrap()).collect();
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            disk.write_page_data(page_id, &[i as u8; PAGE_SIZE]).unwrap();
+        }
+        drop(disk);
+
+        // 4ページ続けて読むと、続く16ページずつを先読みする
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        let mut buf = vec![0; PAGE_SIZE];
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            disk.read_page_data(page_id, &mut buf).unwrap();
+            assert_eq!(i as u8, buf[0]);
+        }
+        let stats = disk.read_ahead_stats();
+        assert_eq!(4 + 4, stats.file_reads);
+        assert_eq!(60, stats.hits);
+        assert_eq!(60, stats.prefetched_pages);
+
+        // 先読みしたページが書き換えられたら、ファイルから読み直す
+        for &page_id in &page_ids[..5] {
+            disk.read_page_data(page_id, &mut buf).unwrap();
+        }
+        disk.write_page_data(page_ids[5], &[0xEE; PAGE_SIZE]).unwrap();
+        disk.read_page_data(page_ids[5], &mut buf).unwrap();
+        assert_eq!(0xEE, buf[0]);
+        disk.read_page_data(page_ids[6], &mut buf).unwrap();
+        assert_eq!(6, buf[0]);
+
+        // 飛び飛びに読んでも先読みしない
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        for &i in &[10, 3, 50, 4, 11, 12] {
+            disk.read_page_data(page_ids[i], &mut buf).unwrap();
+        }
+        assert_eq!(6, disk.read_ahead_stats().file_reads);
+        assert_eq!(0, disk.read_ahead_stats().prefetched_pages);
+
+        // まとめて読むときは1回で読み、採番済みのページの分だけ返す
+        let mut disk = DiskManager::open(&data_file_path).unwrap().with_read_ahead(4, 0);
+        let mut bufs: Vec<_> = (0..8).map(|_| AlignedBuffer::new(PAGE_SIZE)).collect();
+        assert_eq!(4, disk.read_pages(page_ids[60], &mut bufs).unwrap());
+        assert_eq!(1, disk.read_ahead_stats().file_reads);
+        for (i, buf) in bufs[..4].iter().enumerate() {
+            assert_eq!(60 + i as u8, buf[0]);
+        }
+        assert_eq!(0, disk.read_pages(PageId(64), &mut bufs).unwrap());
+        assert!(disk.read_pages(page_ids[0], &mut [AlignedBuffer::new(8)]).is_err());
+    }
+
+    #[test]
+    fn test_compact() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut disk = DiskManager::create(&data_file_path).unwrap();
+        let file_size = || std::fs::metadata(&data_file_path).unwrap().len();
+        let page_ids: Vec<_> = (0..1000).map(|_| disk.allocate_page().unwrap()).collect();
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            disk.write_page_data(page_id, &[i as u8; PAGE_SIZE]).unwrap();
+        }
+        // 末尾の900ページと、途中の2ページを、混ぜた順に解放する
+        for (i, &page_id) in page_ids[100..].iter().enumerate() {
+            disk.deallocate_page(page_id).unwrap();
+            if i == 300 {
+                disk.deallocate_page(page_ids[10]).unwrap();
+            }
+        }
+        disk.deallocate_page(page_ids[50]).unwrap();
+        let before = file_size();
+        let reclaimed = disk.compact().unwrap();
+        assert_eq!(101 * PAGE_SIZE as u64, file_size());
+        assert_eq!(before - file_size(), reclaimed);
+        assert_eq!(98, disk.num_pages());
+        assert_eq!(PageId(100), disk.next_page_id());
+        assert_eq!(vec![page_ids[50], page_ids[10]], disk.free_page_ids().unwrap());
+        // もう縮められない
+        assert_eq!(0, disk.compact().unwrap());
+        drop(disk);
+
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        let mut buf = vec![0; PAGE_SIZE];
+        for (i, &page_id) in page_ids[..100].iter().enumerate() {
+            if i == 10 || i == 50 {
+                continue;
+            }
+            disk.read_page_data(page_id, &mut buf).unwrap();
+            assert_eq!(i as u8, buf[0]);
+        }
+        assert!(disk.read_page_data(page_ids[100], &mut buf).is_err());
+        assert_eq!(page_ids[50], disk.allocate_page().unwrap());
+        assert_eq!(page_ids[10], disk.allocate_page().unwrap());
+        assert_eq!(PageId(100), disk.allocate_page().unwrap());
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let mut disk = DiskManager::create(&data_file_path).unwrap();
+        let page_ids: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        for &page_id in &page_ids {
+            disk.write_page_data(page_id, &[1; PAGE_SIZE]).unwrap();
+        }
+        let mut data = vec![0; PAGE_SIZE];
+        let err = disk.read_page_data(PageId(3), &mut data).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        assert!(matches!(
+            Error::from(err),
+            Error::PageOutOfRange { page_id: PageId(3), num_pages: 3 }
+        ));
+        assert!(matches!(
+            Error::from(io::Error::from(io::ErrorKind::Other)),
+            Error::Io(_)
+        ));
+        disk.sync().unwrap();
+        drop(disk);
+
+        // 最後のページの途中でファイルが切れている
+        let file = OpenOptions::new().write(true).open(&data_file_path).unwrap();
+        file.set_len(3 * PAGE_SIZE as u64 + 100).unwrap();
+        drop(file);
+        let mut disk = DiskManager::open(&data_file_path).unwrap();
+        disk.read_page_data(page_ids[1], &mut data).unwrap();
+        let err = disk.read_page_data(page_ids[2], &mut data).unwrap_err();
+        assert!(matches!(
+            Error::from(err),
+            Error::ShortRead { page_id: PageId(2), got: 100, expected: PAGE_SIZE }
+        ));
+        let mut bufs = vec![AlignedBuffer::new(PAGE_SIZE); 3];
+        let err = disk.read_pages(page_ids[0], &mut bufs).unwrap_err();
+        assert_eq!(
+            "short read on page 2: got 100 of 4096 bytes",
+            Error::from(err).to_string()
+        );
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("heap");
+        let mut disk = DiskManager::create(&path).unwrap();
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &[1; PAGE_SIZE]).unwrap();
+        disk.sync().unwrap();
+        drop(disk);
+
+        // 書き込み権限のないファイルも開ける
+        let mut permissions = std::fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&path, permissions).unwrap();
+        let mut disk = DiskManager::open_readonly(&path).unwrap();
+        assert!(disk.is_read_only());
+        let mut data = vec![0; PAGE_SIZE];
+        disk.read_page_data(page_id, &mut data).unwrap();
+        let data_size = PAGE_SIZE - checksum::CHECKSUM_SIZE;
+        assert_eq!(&[1; PAGE_SIZE][..data_size], &data[..data_size]);
+        let errors = vec![
+            disk.allocate_page().unwrap_err(),
+            disk.write_page_data(page_id, &data).unwrap_err(),
+            disk.write_pages_atomic(&[(page_id, &data)]).unwrap_err(),
+            disk.deallocate_page(page_id).unwrap_err(),
+            disk.set_meta_page_id(page_id).unwrap_err(),
+            disk.compact().unwrap_err(),
+            disk.create_tablespace("index").unwrap_err(),
+        ];
+        for err in errors {
+            assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+            assert!(matches!(Error::from(err), Error::ReadOnly));
+        }
+        disk.sync().unwrap();
+        assert_eq!(PageId(1), disk.next_page_id());
+
+        let options = DiskManagerOptions {
+            read_only: true,
+            ..DiskManagerOptions::default()
+        };
+        let err = DiskManager::create_with(dir.path().join("new"), options).unwrap_err();
+        assert!(matches!(Error::from(err), Error::ReadOnly));
+    }
+}
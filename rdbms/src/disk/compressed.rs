@@ -0,0 +1,320 @@
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::PathBuf;
+
+use super::invalid_data;
+
+// 物理ブロックを分けるスロットの数
+// 圧縮したページは連続したスロットに置き、圧縮できないページはブロック全体を使う
+pub(super) const SLOTS_PER_BLOCK: usize = 8;
+
+// 論理ページの物理的な置き場所
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(super) struct Location {
+    pub(super) block: u64,
+    pub(super) slot: u8,
+    pub(super) slots: u8,
+    // 置いたデータの長さ
+    pub(super) len: u16,
+}
+
+impl Location {
+    fn mask(&self) -> u8 {
+        slot_mask(self.slot as usize, self.slots as usize)
+    }
+
+    // 圧縮せずにそのまま置いたかどうか
+    pub(super) fn is_raw(&self) -> bool {
+        self.slots as usize == SLOTS_PER_BLOCK
+    }
+}
+
+fn slot_mask(slot: usize, slots: usize) -> u8 {
+    (((1u16 << slots) - 1) << slot) as u8
+}
+
+const MAGIC: [u8; 8] = *b"RDBMSCTT";
+const HEADER_SIZE: usize = 16;
+// block: u64, slot: u8, slots: u8, len: u16
+const ENTRY_SIZE: usize = 12;
+// ジャーナルの1件。page_number: u64に続けて、変換表と同じ形で置き場所を書く
+const JOURNAL_ENTRY_SIZE: usize = 8 + ENTRY_SIZE;
+// 一度も書き込まれていないページ
+const UNMAPPED: u64 = u64::MAX;
+
+fn encode_entry(location: Option<Location>, bytes: &mut Vec<u8>) {
+    match location {
+        Some(location) => {
+            bytes.extend_from_slice(&location.block.to_le_bytes());
+            bytes.push(location.slot);
+            bytes.push(location.slots);
+            bytes.extend_from_slice(&location.len.to_le_bytes());
+        }
+        None => {
+            bytes.extend_from_slice(&UNMAPPED.to_le_bytes());
+            bytes.extend_from_slice(&[0; ENTRY_SIZE - 8]);
+        }
+    }
+}
+
+fn decode_entry(entry: &[u8]) -> Option<Location> {
+    let block = u64::from_le_bytes(entry[..8].try_into().unwrap());
+    if block == UNMAPPED {
+        return None;
+    }
+    Some(Location {
+        block,
+        slot: entry[8],
+        slots: entry[9],
+        len: u16::from_le_bytes(entry[10..12].try_into().unwrap()),
+    })
+}
+
+// 論理ページIDから物理的な置き場所を引く変換表
+// ページを書き直すたびに新しい場所へ書き、変換表はsyncのときにまとめて永続化する
+// syncまでの割り当てはジャーナルに追記しておき、syncせずに閉じても開くときに読み直す
+#[derive(Debug)]
+pub(super) struct TranslationTable {
+    path: PathBuf,
+    // 初めて追記するときに開く。書き込めないように開いたときは開かない
+    journal: Option<File>,
+    // 添字が論理ページ番号
+    locations: Vec<Option<Location>>,
+    // ブロックごとの、使用中のスロットのビットマップ
+    used: Vec<u8>,
+    // 書き直される前の場所
+    // 永続化した変換表がまだ指しているかもしれないので、永続化するまで再利用しない
+    pending_free: Vec<Location>,
+}
+
+impl TranslationTable {
+    pub(super) fn create(path: PathBuf) -> io::Result<Self> {
+        let mut table = Self {
+            path,
+            journal: None,
+            locations: vec![],
+            used: vec![],
+            pending_free: vec![],
+        };
+        table.persist()?;
+        Ok(table)
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        let mut journal_path = self.path.clone().into_os_string();
+        journal_path.push(".log");
+        journal_path.into()
+    }
+
+    pub(super) fn load(path: PathBuf) -> io::Result<Self> {
+        let bytes = fs::read(&path)?;
+        if bytes.len() < HEADER_SIZE || bytes[..8] != MAGIC {
+            return Err(invalid_data("not a translation table: bad magic".to_string()));
+        }
+        let num_entries = u64::from_le_bytes(bytes[8..16].try_into().unwrap()) as usize;
+        if bytes.len() != HEADER_SIZE + num_entries * ENTRY_SIZE {
+            return Err(invalid_data("translation table is truncated".to_string()));
+        }
+        let mut table = Self {
+            path,
+            journal: None,
+            locations: Vec::with_capacity(num_entries),
+            used: vec![],
+            pending_free: vec![],
+        };
+        for entry in bytes[HEADER_SIZE..].chunks(ENTRY_SIZE) {
+            let location = decode_entry(entry);
+            if let Some(location) = location {
+                table.mark_used(location);
+            }
+            table.locations.push(location);
+        }
+        // 追記の途中で止まった最後の1件は読まない
+        let journal = match fs::read(table.journal_path()) {
+            Ok(journal) => journal,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        for entry in journal.chunks_exact(JOURNAL_ENTRY_SIZE) {
+            let page_number = u64::from_le_bytes(entry[..8].try_into().unwrap());
+            if let Some(location) = decode_entry(&entry[8..]) {
+                table.map(page_number, location);
+            }
+        }
+        Ok(table)
+    }
+
+    fn mark_used(&mut self, location: Location) {
+        let block = location.block as usize;
+        if self.used.len() <= block {
+            self.used.resize(block + 1, 0);
+        }
+        self.used[block] |= location.mask();
+    }
+
+    pub(super) fn lookup(&self, page_number: u64) -> Option<Location> {
+        self.locations.get(page_number as usize).copied().flatten()
+    }
+
+    // ページにslots個のスロットを割り当てる
+    // 空きのあるブロックを先頭から探し、なければブロックを増やす
+    pub(super) fn assign(&mut self, page_number: u64, slots: usize, len: usize) -> Location {
+        let found = self.used.iter().enumerate().find_map(|(block, &used)| {
+            (0..=SLOTS_PER_BLOCK - slots)
+                .find(|&slot| used & slot_mask(slot, slots) == 0)
+                .map(|slot| (block, slot))
+        });
+        let (block, slot) = found.unwrap_or((self.used.len(), 0));
+        let location = Location {
+            block: block as u64,
+            slot: slot as u8,
+            slots: slots as u8,
+            len: len as u16,
+        };
+        self.map(page_number, location);
+        location
+    }
+
+    // 永続化した変換表に入っている割り当てをジャーナルから読み直すこともあるので、
+    // 同じ場所に割り当て直しても、その場所は解放しない
+    fn map(&mut self, page_number: u64, location: Location) {
+        self.mark_used(location);
+        let idx = page_number as usize;
+        if self.locations.len() <= idx {
+            self.locations.resize(idx + 1, None);
+        }
+        match self.locations[idx].replace(location) {
+            Some(old) if old != location => self.pending_free.push(old),
+            _ => {}
+        }
+    }
+
+    // assignした場所にページを書いてから呼ぶ。途中で止まっても、前の場所はまだ解放していない
+    pub(super) fn log(&mut self, page_number: u64, location: Location) -> io::Result<()> {
+        if self.journal.is_none() {
+            let journal = OpenOptions::new().append(true).create(true).open(self.journal_path())?;
+            self.journal = Some(journal);
+        }
+        let mut entry = Vec::with_capacity(JOURNAL_ENTRY_SIZE);
+        entry.extend_from_slice(&page_number.to_le_bytes());
+        encode_entry(Some(location), &mut entry);
+        self.journal.as_mut().unwrap().write_all(&entry)
+    }
+
+    // 一時ファイルに書いてから置き換えるので、途中で失敗しても前の変換表が残る
+    pub(super) fn persist(&mut self) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.locations.len() * ENTRY_SIZE);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&(self.locations.len() as u64).to_le_bytes());
+        for &location in self.locations.iter() {
+            encode_entry(location, &mut bytes);
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        // ジャーナルの割り当てはすべて変換表に入った
+        // 空にしたことを永続化する前に場所を再利用すると、古い割り当てを読み直してしまう
+        let journal = match self.journal.take() {
+            Some(journal) => Some(journal),
+            None => match OpenOptions::new().append(true).open(self.journal_path()) {
+                Ok(journal) => Some(journal),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err),
+            },
+        };
+        if let Some(journal) = journal {
+            journal.set_len(0)?;
+            journal.sync_all()?;
+            self.journal = Some(journal);
+        }
+        // もう誰も指していないので、書き直される前の場所を再利用できる
+        for location in self.pending_free.drain(..) {
+            self.used[location.block as usize] &= !location.mask();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ctt");
+        let mut table = TranslationTable::create(path.clone()).unwrap();
+        let a = table.assign(0, 3, 1000);
+        let b = table.assign(1, 5, 2000);
+        // 同じブロックに詰める
+        assert_eq!((0, 0), (a.block, a.slot));
+        assert_eq!((0, 3), (b.block, b.slot));
+        let c = table.assign(2, SLOTS_PER_BLOCK, 4096);
+        assert_eq!(1, c.block);
+        assert!(c.is_raw());
+
+        // 書き直す前の場所は、永続化するまで再利用しない
+        let a2 = table.assign(0, 2, 500);
+        assert_eq!(2, a2.block);
+        table.persist().unwrap();
+        let a3 = table.assign(0, 1, 100);
+        assert_eq!((0, 0), (a3.block, a3.slot));
+        assert_eq!(None, table.lookup(3));
+
+        table.persist().unwrap();
+        let loaded = TranslationTable::load(path.clone()).unwrap();
+        for page_number in 0..4 {
+            assert_eq!(table.lookup(page_number), loaded.lookup(page_number));
+        }
+        assert_eq!(&table.used[..2], &loaded.used[..]);
+
+        fs::write(&path, b"RDBMSCTT\x01\0\0\0\0\0\0\0").unwrap();
+        assert!(TranslationTable::load(path).is_err());
+    }
+
+    #[test]
+    fn test_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ctt");
+        let mut table = TranslationTable::create(path.clone()).unwrap();
+        let a = table.assign(0, 3, 1000);
+        table.log(0, a).unwrap();
+        table.persist().unwrap();
+        assert_eq!(0, fs::metadata(table.journal_path()).unwrap().len());
+
+        // 永続化していない割り当ても、ジャーナルから読み直す
+        let b = table.assign(1, 2, 500);
+        table.log(1, b).unwrap();
+        let a2 = table.assign(0, 1, 100);
+        table.log(0, a2).unwrap();
+        let unlogged = table.assign(2, 1, 100);
+        let loaded = TranslationTable::load(path.clone()).unwrap();
+        assert_eq!(Some(a2), loaded.lookup(0));
+        assert_eq!(Some(b), loaded.lookup(1));
+        assert_eq!(None, loaded.lookup(2));
+        assert_ne!(Some(unlogged), loaded.lookup(2));
+        // 書き直される前の場所は、永続化するまで再利用しない
+        assert_eq!(vec![a], loaded.pending_free);
+
+        // 追記の途中で切れた1件は読まない
+        let journal_path = table.journal_path();
+        let journal = fs::read(&journal_path).unwrap();
+        fs::write(&journal_path, &journal[..journal.len() - 1]).unwrap();
+        let mut loaded = TranslationTable::load(path.clone()).unwrap();
+        assert_eq!(Some(a), loaded.lookup(0));
+        assert_eq!(Some(b), loaded.lookup(1));
+
+        // 変換表と同じ割り当てを読み直しても、その場所を解放しない
+        loaded.persist().unwrap();
+        fs::write(&journal_path, &journal[..JOURNAL_ENTRY_SIZE]).unwrap();
+        let mut loaded = TranslationTable::load(path).unwrap();
+        assert!(loaded.pending_free.is_empty());
+        loaded.persist().unwrap();
+        assert_eq!(Some(b), loaded.lookup(1));
+        assert_eq!(0b11111, loaded.used[0]);
+    }
+}
@@ -0,0 +1,284 @@
+use std::alloc::{self, Layout};
+use std::fmt;
+#[cfg(unix)]
+use std::fs::{File, OpenOptions};
+#[cfg(unix)]
+use std::io;
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+#[cfg(unix)]
+use std::path::Path;
+use std::ptr::NonNull;
+use std::slice;
+
+#[cfg(unix)]
+use super::preallocate;
+
+// O_DIRECTで読み書きするときに、バッファのアドレス・オフセット・長さを揃える単位
+// 多くのファイルシステムの論理ブロックサイズより大きく取っておく
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+// 先頭がDIRECT_IO_ALIGNMENTに揃ったゼロ埋めのバッファ
+// バッファプールのページに使い、O_DIRECTでもそのまま読み書きできるようにする
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// 確保した領域はこの構造体だけが持っている
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    pub fn new(len: usize) -> Self {
+        let layout = Self::layout(len);
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, len }
+    }
+
+    // 長さ0でも確保できるよう、最低1バイトは確保する
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len.max(1), DIRECT_IO_ALIGNMENT).unwrap()
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl From<&[u8]> for AlignedBuffer {
+    fn from(data: &[u8]) -> Self {
+        let mut buf = Self::new(data.len());
+        buf.copy_from_slice(data);
+        buf
+    }
+}
+
+impl Clone for AlignedBuffer {
+    fn clone(&self) -> Self {
+        Self::from(&self[..])
+    }
+}
+
+impl PartialEq for AlignedBuffer {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for AlignedBuffer {}
+
+impl fmt::Debug for AlignedBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self[..], f)
+    }
+}
+
+// ここから下は、O_DIRECTで開いたファイルの読み書き。unix系のプラットフォームでだけ使う
+#[cfg(unix)]
+fn align_down(offset: u64) -> u64 {
+    offset & !(DIRECT_IO_ALIGNMENT as u64 - 1)
+}
+
+#[cfg(unix)]
+fn align_up(offset: u64) -> u64 {
+    align_down(offset + DIRECT_IO_ALIGNMENT as u64 - 1)
+}
+
+#[cfg(unix)]
+fn is_aligned(offset: u64, data: &[u8]) -> bool {
+    let alignment = DIRECT_IO_ALIGNMENT as u64;
+    offset % alignment == 0
+        && data.len() as u64 % alignment == 0
+        && data.as_ptr() as usize % DIRECT_IO_ALIGNMENT == 0
+}
+
+// O_DIRECTで開いたファイル
+// 揃っていない読み書きは、揃えたバッファを通して範囲を広げて行う
+#[cfg(unix)]
+#[derive(Debug)]
+pub(super) struct DirectFile {
+    file: File,
+}
+
+#[cfg(unix)]
+impl DirectFile {
+    pub(super) fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    pub(super) fn extend(&mut self, len: u64) -> io::Result<()> {
+        preallocate(&self.file, len)
+    }
+
+    pub(super) fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    // ファイルの末尾までを読み、読めたバイト数を返す
+    pub(super) fn read_partial(&mut self, offset: u64, data: &mut [u8]) -> io::Result<usize> {
+        if is_aligned(offset, data) {
+            return read_full_at(&self.file, data, offset);
+        }
+        let start = align_down(offset);
+        let end = align_up(offset + data.len() as u64);
+        let mut buf = AlignedBuffer::new((end - start) as usize);
+        let skip = (offset - start) as usize;
+        let len = read_full_at(&self.file, &mut buf, start)?
+            .saturating_sub(skip)
+            .min(data.len());
+        data[..len].copy_from_slice(&buf[skip..skip + len]);
+        Ok(len)
+    }
+
+    // 揃っていない範囲は、前後の既存のデータを読んでから書き戻す
+    pub(super) fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if is_aligned(offset, data) {
+            return self.file.write_all_at(data, offset);
+        }
+        let start = align_down(offset);
+        let end = align_up(offset + data.len() as u64);
+        let mut buf = AlignedBuffer::new((end - start) as usize);
+        // ファイルの大きさは常に揃っているので、既存の部分も揃った長さで読める
+        let existing = end.min(align_up(self.len()?)).saturating_sub(start) as usize;
+        if existing > 0 {
+            self.file.read_exact_at(&mut buf[..existing], start)?;
+        }
+        let skip = (offset - start) as usize;
+        buf[skip..skip + data.len()].copy_from_slice(data);
+        self.file.write_all_at(&buf, start)
+    }
+
+    pub(super) fn sync_all(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    pub(super) fn sync_data(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(unix)]
+fn read_full_at(file: &File, data: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut len = 0;
+    while len < data.len() {
+        match file.read_at(&mut data[len..], offset + len as u64) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(unix)]
+fn direct_io_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "O_DIRECT is not supported on this platform or file system",
+    )
+}
+
+// custom_flagsにO_DIRECTを加えてファイルを開く
+// O_DIRECTを使えないプラットフォームやファイルシステムでは、黙って通常の読み書きにせず、
+// ErrorKind::Unsupportedのエラーを返す
+#[cfg(unix)]
+pub(super) fn open_direct(
+    path: &Path,
+    options: &OpenOptions,
+    custom_flags: i32,
+) -> io::Result<DirectFile> {
+    #[cfg(target_os = "linux")]
+    {
+        let mut options = options.clone();
+        options.custom_flags(custom_flags | libc::O_DIRECT);
+        match options.open(path) {
+            Ok(file) => Ok(DirectFile { file }),
+            // tmpfsなどはO_DIRECTを受け付けない
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => Err(direct_io_unsupported()),
+            Err(err) => Err(err),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (path, options, custom_flags);
+        Err(direct_io_unsupported())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aligned_buffer() {
+        for &len in &[0, 1, 256, DIRECT_IO_ALIGNMENT, 3 * DIRECT_IO_ALIGNMENT + 5] {
+            let mut buf = AlignedBuffer::new(len);
+            assert_eq!(len, buf.len());
+            assert_eq!(0, buf.as_ptr() as usize % DIRECT_IO_ALIGNMENT);
+            assert!(buf.iter().all(|&b| b == 0));
+            buf.fill(0xAB);
+            let cloned = buf.clone();
+            assert_eq!(0, cloned.as_ptr() as usize % DIRECT_IO_ALIGNMENT);
+            assert_eq!(buf, cloned);
+        }
+        assert_eq!(&b"hello"[..], &AlignedBuffer::from(&b"hello"[..])[..]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unaligned_io() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("direct");
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true);
+        // O_DIRECTを使えない環境では、揃える処理だけを通常のファイルで確かめる
+        let mut file = match open_direct(&path, &options, 0) {
+            Ok(file) => file,
+            Err(err) => {
+                assert_eq!(io::ErrorKind::Unsupported, err.kind());
+                DirectFile {
+                    file: options.open(&path).unwrap(),
+                }
+            }
+        };
+        let page = AlignedBuffer::from(&[1u8; DIRECT_IO_ALIGNMENT][..]);
+        file.write_at(0, &page).unwrap();
+        file.write_at(100, b"hello").unwrap();
+        file.write_at(DIRECT_IO_ALIGNMENT as u64 - 2, b"world").unwrap();
+        assert_eq!(2 * DIRECT_IO_ALIGNMENT as u64, file.len().unwrap());
+
+        let mut buf = [0; 5];
+        assert_eq!(5, file.read_partial(100, &mut buf).unwrap());
+        assert_eq!(b"hello", &buf);
+        assert_eq!(5, file.read_partial(DIRECT_IO_ALIGNMENT as u64 - 2, &mut buf).unwrap());
+        assert_eq!(b"world", &buf);
+        let mut page = AlignedBuffer::new(DIRECT_IO_ALIGNMENT);
+        assert_eq!(DIRECT_IO_ALIGNMENT, file.read_partial(0, &mut page).unwrap());
+        assert_eq!(&[1; 100][..], &page[..100]);
+        assert_eq!(&[1; 10][..], &page[105..115]);
+        // ファイルの末尾を越える分は読まない
+        let end = 2 * DIRECT_IO_ALIGNMENT as u64;
+        assert_eq!(2, file.read_partial(end - 2, &mut buf).unwrap());
+        assert_eq!(0, file.read_partial(end, &mut buf).unwrap());
+    }
+}
@@ -0,0 +1,92 @@
+use std::fs::File;
+use std::io::{self, prelude::*, SeekFrom};
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, FromBytes};
+
+use super::PageId;
+
+#[derive(Debug, FromBytes, AsBytes)]
+#[repr(C)]
+struct Header {
+    magic: [u8; 8],
+    // 書き出し途中のページの数。0なら復旧するものはない
+    num_pages: u64,
+}
+
+const MAGIC: [u8; 8] = *b"RDBMSDWB";
+
+// ダブルライトバッファ
+// ページを本来の場所に書く前に、まとめてこのファイルへ書いて永続化しておく
+// 本来の場所への書き込みが途中で途切れても、ここに残ったコピーから復旧できる
+#[derive(Debug)]
+pub(super) struct DoubleWrite {
+    file: File,
+    page_size: usize,
+}
+
+impl DoubleWrite {
+    pub(super) fn new(file: File, page_size: usize) -> Self {
+        Self { file, page_size }
+    }
+
+    fn entry_size(&self) -> usize {
+        size_of::<PageId>() + self.page_size
+    }
+
+    // ページを順に書き出して永続化する
+    // ページを書き終えてから数を書くので、数が残っていればページはすべて揃っている
+    pub(super) fn write(&mut self, pages: &[(PageId, &[u8])]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(self.entry_size() * pages.len());
+        for (page_id, data) in pages {
+            buf.extend_from_slice(page_id.as_bytes());
+            buf.extend_from_slice(data);
+        }
+        self.file.seek(SeekFrom::Start(size_of::<Header>() as u64))?;
+        self.file.write_all(&buf)?;
+        self.file.sync_data()?;
+        self.write_header(pages.len())
+    }
+
+    // 本来の場所への書き込みが終わったので、コピーを無効にする
+    pub(super) fn clear(&mut self) -> io::Result<()> {
+        self.write_header(0)
+    }
+
+    fn write_header(&mut self, num_pages: usize) -> io::Result<()> {
+        let header = Header {
+            magic: MAGIC,
+            num_pages: num_pages as u64,
+        };
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(header.as_bytes())?;
+        self.file.sync_data()
+    }
+
+    // 書き出し途中だったページを読み出す
+    pub(super) fn pages(&mut self) -> io::Result<Vec<(PageId, Vec<u8>)>> {
+        if self.file.metadata()?.len() < size_of::<Header>() as u64 {
+            return Ok(vec![]);
+        }
+        let mut header = Header {
+            magic: [0; 8],
+            num_pages: 0,
+        };
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_exact(header.as_bytes_mut())?;
+        if header.magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a double-write file: bad magic",
+            ));
+        }
+        let mut entry = vec![0; self.entry_size()];
+        let mut pages = vec![];
+        for _ in 0..header.num_pages {
+            self.file.read_exact(&mut entry)?;
+            let (page_id, data) = entry.split_at(size_of::<PageId>());
+            pages.push((PageId::from(page_id), data.to_vec()));
+        }
+        Ok(pages)
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce, Tag};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use super::{PageId, Storage, TablespaceId};
+use crate::checksum::CHECKSUM_SIZE;
+
+// 呼び出し側が渡す鍵の大きさ。ファイルごとの鍵はこれとソルトから導く
+pub const ENCRYPTION_KEY_SIZE: usize = 32;
+
+const COUNTER_SIZE: usize = 4;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const SALT_SIZE: usize = 32;
+
+// 暗号化したページの末尾に置く、書き込み回数のカウンタと認証タグの大きさ
+// 下のストレージがチェックサムを書き込む場所も空けておく
+pub const ENCRYPTION_OVERHEAD: usize = COUNTER_SIZE + TAG_SIZE + CHECKSUM_SIZE;
+
+// 暗号化のヘッダページ
+// [マジック 8][ソルト 32][呼び出し側のメタページのID 8] を平文で置く
+const HEADER_MAGIC: [u8; 8] = *b"RDBMSENC";
+const HEADER_SALT: usize = HEADER_MAGIC.len();
+const HEADER_META_PAGE_ID: usize = HEADER_SALT + SALT_SIZE;
+const HEADER_SIZE: usize = HEADER_META_PAGE_ID + 8;
+
+// ファイルごとの鍵を導くときに混ぜる文字列
+const KEY_INFO: &[u8] = b"rdbms page encryption v1";
+
+// 暗号化したページの認証に失敗した
+// read_page_dataはこれをInvalidDataのio::Errorに包んで返す
+#[derive(Debug, thiserror::Error)]
+#[error("authentication failed on page {}", .page_id.to_u64())]
+pub struct AuthenticationFailed {
+    pub page_id: PageId,
+}
+
+// ページをAES-256-GCMで暗号化して、下のストレージに書き出す
+// 下のストレージのページは [暗号文][カウンタ][認証タグ][チェックサム用の空き] の形になる
+// 暗号化の鍵は、渡された鍵とcreateで選んだランダムなソルトからHKDFで導く
+// ソルトは下のストレージのメタページとして置くヘッダページに記録するので、
+// 同じ鍵で別のファイルを作っても、同じパスに作り直しても、ファイルごとに鍵が変わる
+// ナンスはページIDとカウンタから作る。同じページを書くたびにカウンタを増やし、ナンスを使い回さない
+pub struct EncryptedDiskManager<S> {
+    inner: S,
+    cipher: Aes256Gcm,
+    // 暗号化のヘッダページのID。下のストレージのメタページになる
+    header_page_id: PageId,
+    // 呼び出し側から見たメタページのID。ヘッダページに記録する
+    meta_page_id: PageId,
+    // 読み書きしたページの最新のカウンタ
+    counters: HashMap<PageId, u32>,
+}
+
+impl<S: fmt::Debug> fmt::Debug for EncryptedDiskManager<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedDiskManager")
+            .field("inner", &self.inner)
+            .field("header_page_id", &self.header_page_id)
+            .field("meta_page_id", &self.meta_page_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: Storage> EncryptedDiskManager<S> {
+    // ランダムなソルトを選んでヘッダページを書き、innerを暗号化したストレージとして使い始める
+    pub fn create(mut inner: S, key: &[u8; ENCRYPTION_KEY_SIZE]) -> io::Result<Self> {
+        let mut salt = [0; SALT_SIZE];
+        getrandom::getrandom(&mut salt).map_err(io::Error::other)?;
+        let header_page_id = inner.allocate_page()?;
+        let mut disk = Self {
+            inner,
+            cipher: derive_cipher(key, &salt),
+            header_page_id,
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            counters: HashMap::new(),
+        };
+        disk.write_header(&salt)?;
+        disk.inner.set_meta_page_id(header_page_id)?;
+        Ok(disk)
+    }
+
+    // createで書いたヘッダページを読み、ソルトから鍵を導き直す
+    pub fn open(mut inner: S, key: &[u8; ENCRYPTION_KEY_SIZE]) -> io::Result<Self> {
+        let not_encrypted =
+            || io::Error::new(io::ErrorKind::InvalidData, "not an encrypted storage");
+        let header_page_id = inner.meta_page_id().valid().ok_or_else(not_encrypted)?;
+        let mut page = vec![0; inner.page_size()];
+        inner.read_page_data(header_page_id, &mut page)?;
+        if page[..HEADER_SALT] != HEADER_MAGIC {
+            return Err(not_encrypted());
+        }
+        Ok(Self {
+            inner,
+            cipher: derive_cipher(key, &page[HEADER_SALT..HEADER_META_PAGE_ID]),
+            header_page_id,
+            meta_page_id: PageId::from(&page[HEADER_META_PAGE_ID..HEADER_SIZE]),
+            counters: HashMap::new(),
+        })
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn write_header(&mut self, salt: &[u8]) -> io::Result<()> {
+        let mut page = vec![0; self.inner.page_size()];
+        page[..HEADER_SALT].copy_from_slice(&HEADER_MAGIC);
+        page[HEADER_SALT..HEADER_META_PAGE_ID].copy_from_slice(salt);
+        page[HEADER_META_PAGE_ID..HEADER_SIZE].copy_from_slice(&self.meta_page_id.0.to_ne_bytes());
+        self.inner.write_page_data(self.header_page_id, &page)
+    }
+
+    // 暗号文の大きさ。呼び出し側から見たページサイズになる
+    fn data_size(&self) -> usize {
+        self.inner.page_size() - ENCRYPTION_OVERHEAD
+    }
+
+    fn check_data_len(&self, len: usize) -> io::Result<()> {
+        if len != self.data_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "encrypted pages must be read and written as a whole",
+            ));
+        }
+        Ok(())
+    }
+
+    fn nonce(page_id: PageId, counter: u32) -> [u8; NONCE_SIZE] {
+        let mut nonce = [0; NONCE_SIZE];
+        nonce[..8].copy_from_slice(&page_id.to_u64().to_le_bytes());
+        nonce[8..].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+
+    // ページに最後に書いたときのカウンタを調べる
+    // 一度も書いていないページは0とする
+    fn stored_counter(&mut self, page_id: PageId) -> io::Result<u32> {
+        if let Some(&counter) = self.counters.get(&page_id) {
+            return Ok(counter);
+        }
+        let mut page = vec![0; self.inner.page_size()];
+        let counter = match self.inner.read_page_data(page_id, &mut page) {
+            Ok(()) => {
+                let offset = self.data_size();
+                u32::from_le_bytes(page[offset..offset + COUNTER_SIZE].try_into().unwrap())
+            }
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => 0,
+            Err(err) => return Err(err),
+        };
+        self.counters.insert(page_id, counter);
+        Ok(counter)
+    }
+
+    fn encrypt(&mut self, page_id: PageId, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.check_data_len(data.len())?;
+        let counter = self.stored_counter(page_id)?.checked_add(1).ok_or_else(|| {
+            io::Error::other(format!("nonce counter exhausted on page {}", page_id.to_u64()))
+        })?;
+        // 書き出しに失敗しても、このナンスはもう使わない
+        self.counters.insert(page_id, counter);
+        let data_size = self.data_size();
+        let mut page = vec![0; self.inner.page_size()];
+        page[..data_size].copy_from_slice(data);
+        let nonce = Self::nonce(page_id, counter);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), &[], &mut page[..data_size])
+            .map_err(|_| io::Error::other("failed to encrypt page"))?;
+        page[data_size..data_size + COUNTER_SIZE].copy_from_slice(&counter.to_le_bytes());
+        page[data_size + COUNTER_SIZE..data_size + COUNTER_SIZE + TAG_SIZE].copy_from_slice(&tag);
+        Ok(page)
+    }
+}
+
+// 渡された鍵とソルトから、このファイルだけで使う鍵を導く
+fn derive_cipher(key: &[u8; ENCRYPTION_KEY_SIZE], salt: &[u8]) -> Aes256Gcm {
+    let mut file_key = [0; ENCRYPTION_KEY_SIZE];
+    Hkdf::<Sha256>::new(Some(salt), key)
+        .expand(KEY_INFO, &mut file_key)
+        .expect("key size is valid for HKDF-SHA256");
+    Aes256Gcm::new(&file_key.into())
+}
+
+impl<S: Storage> Storage for EncryptedDiskManager<S> {
+    // 復号して、認証できたときだけdataに書き込む
+    fn read_page_data(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
+        self.check_data_len(data.len())?;
+        let mut page = vec![0; self.inner.page_size()];
+        self.inner.read_page_data(page_id, &mut page)?;
+        let data_size = self.data_size();
+        let (ciphertext, trailer) = page.split_at_mut(data_size);
+        let counter = u32::from_le_bytes(trailer[..COUNTER_SIZE].try_into().unwrap());
+        let tag = Tag::from_slice(&trailer[COUNTER_SIZE..COUNTER_SIZE + TAG_SIZE]);
+        let nonce = Self::nonce(page_id, counter);
+        if self
+            .cipher
+            .decrypt_in_place_detached(Nonce::from_slice(&nonce), &[], ciphertext, tag)
+            .is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                AuthenticationFailed { page_id },
+            ));
+        }
+        self.counters.insert(page_id, counter);
+        data.copy_from_slice(ciphertext);
+        Ok(())
+    }
+
+    fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
+        let page = self.encrypt(page_id, data)?;
+        self.inner.write_page_data(page_id, &page)
+    }
+
+    fn write_pages_atomic(&mut self, pages: &[(PageId, &[u8])]) -> io::Result<()> {
+        let mut encrypted = Vec::with_capacity(pages.len());
+        for (page_id, data) in pages {
+            encrypted.push((*page_id, self.encrypt(*page_id, data)?));
+        }
+        let encrypted: Vec<_> = encrypted
+            .iter()
+            .map(|(page_id, page)| (*page_id, &page[..]))
+            .collect();
+        self.inner.write_pages_atomic(&encrypted)
+    }
+
+    fn page_size(&self) -> usize {
+        self.data_size()
+    }
+
+    fn allocate_page(&mut self) -> io::Result<PageId> {
+        self.inner.allocate_page()
+    }
+
+    fn allocate_page_in(&mut self, tablespace_id: TablespaceId) -> io::Result<PageId> {
+        self.inner.allocate_page_in(tablespace_id)
+    }
+
+    // 下のストレージは解放したページを空きページのリストとして書き換えるので、
+    // 再利用したときにカウンタを続きから使えるよう、カウンタだけ書き戻しておく
+    fn deallocate_page(&mut self, page_id: PageId) -> io::Result<()> {
+        let counter = self.stored_counter(page_id)?;
+        self.inner.deallocate_page(page_id)?;
+        let mut page = vec![0; self.inner.page_size()];
+        self.inner.read_page_data(page_id, &mut page)?;
+        let offset = self.data_size();
+        page[offset..offset + COUNTER_SIZE].copy_from_slice(&counter.to_le_bytes());
+        self.inner.write_page_data(page_id, &page)
+    }
+
+    // 下のストレージのメタページはヘッダページなので、呼び出し側のメタページはヘッダに記録する
+    fn meta_page_id(&self) -> PageId {
+        self.meta_page_id
+    }
+
+    fn set_meta_page_id(&mut self, meta_page_id: PageId) -> io::Result<()> {
+        let mut page = vec![0; self.inner.page_size()];
+        self.inner.read_page_data(self.header_page_id, &mut page)?;
+        let previous = self.meta_page_id;
+        self.meta_page_id = meta_page_id;
+        let result = self.write_header(&page[HEADER_SALT..HEADER_META_PAGE_ID]);
+        if result.is_err() {
+            self.meta_page_id = previous;
+        }
+        result
+    }
+
+    fn num_pages(&self) -> u64 {
+        self.inner.num_pages()
+    }
+
+    fn next_page_id(&self) -> PageId {
+        self.inner.next_page_id()
+    }
+
+    fn file_size_bytes(&self) -> io::Result<u64> {
+        self.inner.file_size_bytes()
+    }
+
+    fn free_page_ids(&mut self) -> io::Result<Vec<PageId>> {
+        self.inner.free_page_ids()
+    }
+
+    fn tablespaces(&self) -> Vec<(TablespaceId, String)> {
+        self.inner.tablespaces()
+    }
+
+    fn next_page_id_in(&self, tablespace_id: TablespaceId) -> io::Result<PageId> {
+        self.inner.next_page_id_in(tablespace_id)
+    }
+
+    fn free_page_ids_in(&mut self, tablespace_id: TablespaceId) -> io::Result<Vec<PageId>> {
+        self.inner.free_page_ids_in(tablespace_id)
+    }
+
+    fn is_encrypted(&self) -> bool {
+        true
+    }
+
+    // 切り詰めたページのカウンタは失われ、採番し直したときにナンスを使い回してしまうので、縮めない
+    fn compact(&mut self) -> io::Result<u64> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "encrypted storage cannot be compacted",
+        ))
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.inner.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::{DiskManager, MemoryDiskManager, PAGE_SIZE};
+
+    const KEY: [u8; ENCRYPTION_KEY_SIZE] = [0x42; ENCRYPTION_KEY_SIZE];
+    const DATA_SIZE: usize = PAGE_SIZE - ENCRYPTION_OVERHEAD;
+
+    fn text_page(text: &str) -> Vec<u8> {
+        let mut page = text.repeat(DATA_SIZE / text.len()).into_bytes();
+        page.resize(DATA_SIZE, 0);
+        page
+    }
+
+    #[test]
+    fn test() {
+        let mut disk = EncryptedDiskManager::create(MemoryDiskManager::new(), &KEY).unwrap();
+        assert_eq!(DATA_SIZE, disk.page_size());
+        let page_ids: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        let pages: Vec<_> = ["hello ", "world ", "secret "]
+            .iter()
+            .map(|text| text_page(text))
+            .collect();
+        for (page_id, page) in page_ids.iter().zip(pages.iter()) {
+            disk.write_page_data(*page_id, page).unwrap();
+        }
+        let mut buf = vec![0; DATA_SIZE];
+        for (page_id, page) in page_ids.iter().zip(pages.iter()) {
+            disk.read_page_data(*page_id, &mut buf).unwrap();
+            assert_eq!(page, &buf);
+        }
+        assert!(disk.write_page_data(page_ids[0], &buf[..8]).is_err());
+
+        // 同じ内容を書き直しても、ナンスが変わるので暗号文は変わる
+        let mut before = vec![0; PAGE_SIZE];
+        disk.inner.read_page_data(page_ids[0], &mut before).unwrap();
+        disk.write_page_data(page_ids[0], &pages[0]).unwrap();
+        let mut after = vec![0; PAGE_SIZE];
+        disk.inner.read_page_data(page_ids[0], &mut after).unwrap();
+        assert_ne!(before[..DATA_SIZE], after[..DATA_SIZE]);
+
+        // 暗号文を1バイト書き換えると、でたらめなデータを返さずに認証に失敗する
+        let mut tampered = after.clone();
+        tampered[100] ^= 1;
+        disk.inner.write_page_data(page_ids[1], &tampered).unwrap();
+        let err = disk.read_page_data(page_ids[1], &mut buf).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let failed = err.get_ref().unwrap().downcast_ref::<AuthenticationFailed>().unwrap();
+        assert_eq!(page_ids[1], failed.page_id);
+        // ほかのページの暗号文をそのまま持ってきても認証できない
+        disk.inner.write_page_data(page_ids[1], &after).unwrap();
+        assert!(disk.read_page_data(page_ids[1], &mut buf).is_err());
+
+        // 鍵が違えば読めない
+        let inner = disk.into_inner();
+        let mut disk = EncryptedDiskManager::open(inner, &[0x43; ENCRYPTION_KEY_SIZE]).unwrap();
+        assert!(disk.read_page_data(page_ids[2], &mut buf).is_err());
+        let mut disk = EncryptedDiskManager::open(disk.into_inner(), &KEY).unwrap();
+        disk.read_page_data(page_ids[2], &mut buf).unwrap();
+        assert_eq!(pages[2], buf);
+
+        // 暗号化していないストレージは開かない
+        let err = EncryptedDiskManager::open(MemoryDiskManager::new(), &KEY).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn test_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_file_path = tempfile::NamedTempFile::new_in(&dir).unwrap().into_temp_path();
+        let inner = DiskManager::create(&data_file_path).unwrap();
+        let mut disk = EncryptedDiskManager::create(inner, &KEY).unwrap();
+        let page = text_page("credit card number ");
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_pages_atomic(&[(page_id, &page)]).unwrap();
+        disk.set_meta_page_id(page_id).unwrap();
+        disk.sync().unwrap();
+        let mut first = vec![0; PAGE_SIZE];
+        disk.inner.read_page_data(page_id, &mut first).unwrap();
+        drop(disk);
+
+        // ファイルには平文が残らない
+        let bytes = std::fs::read(&data_file_path).unwrap();
+        let needle = b"credit card number";
+        assert!(!bytes.windows(needle.len()).any(|w| w == needle));
+
+        let inner = DiskManager::open(&data_file_path).unwrap();
+        let mut disk = EncryptedDiskManager::open(inner, &KEY).unwrap();
+        assert_eq!(page_id, disk.meta_page_id());
+        let mut buf = vec![0; DATA_SIZE];
+        disk.read_page_data(page_id, &mut buf).unwrap();
+        assert_eq!(page, buf);
+
+        // 解放して再利用したページでも、カウンタは続きから使う
+        let counter = disk.stored_counter(page_id).unwrap();
+        disk.deallocate_page(page_id).unwrap();
+        drop(disk);
+        let inner = DiskManager::open(&data_file_path).unwrap();
+        let mut disk = EncryptedDiskManager::open(inner, &KEY).unwrap();
+        assert_eq!(page_id, disk.allocate_page().unwrap());
+        disk.write_page_data(page_id, &page).unwrap();
+        assert_eq!(counter + 1, disk.stored_counter(page_id).unwrap());
+        disk.read_page_data(page_id, &mut buf).unwrap();
+        assert_eq!(page, buf);
+        // 切り詰めるとカウンタが失われるので、縮められない
+        let err = disk.compact().unwrap_err();
+        assert_eq!(io::ErrorKind::Unsupported, err.kind());
+        drop(disk);
+
+        // 同じ鍵で同じパスに作り直しても、ソルトが変わるので同じナンスで同じ鍵を使わない
+        let inner = DiskManager::create(&data_file_path).unwrap();
+        let mut disk = EncryptedDiskManager::create(inner, &KEY).unwrap();
+        assert_eq!(page_id, disk.allocate_page().unwrap());
+        disk.write_page_data(page_id, &page).unwrap();
+        assert_eq!(1, disk.stored_counter(page_id).unwrap());
+        let mut second = vec![0; PAGE_SIZE];
+        disk.inner.read_page_data(page_id, &mut second).unwrap();
+        let counter_range = DATA_SIZE..DATA_SIZE + COUNTER_SIZE;
+        assert_eq!(first[counter_range.clone()], second[counter_range]);
+        assert_ne!(first[..DATA_SIZE], second[..DATA_SIZE]);
+    }
+}
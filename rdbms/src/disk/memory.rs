@@ -0,0 +1,166 @@
+use std::io;
+
+use super::{check_page_size, Error, PageId, Storage, PAGE_SIZE};
+
+// メモリ上にページを置くストレージ
+// テストなどでファイルを作らずに済ませたいときに使う
+#[derive(Debug)]
+pub struct MemoryDiskManager {
+    page_size: usize,
+    pages: Vec<Box<[u8]>>,
+    // 採番するページIDを決めるカウンタ
+    next_page_id: u64,
+    // 解放されたページ
+    free_pages: Vec<PageId>,
+    meta_page_id: PageId,
+}
+
+impl MemoryDiskManager {
+    pub fn new() -> Self {
+        Self::with_page_size(PAGE_SIZE).unwrap()
+    }
+
+    pub fn with_page_size(page_size: usize) -> io::Result<Self> {
+        check_page_size(page_size)?;
+        Ok(Self {
+            page_size,
+            pages: vec![],
+            next_page_id: 0,
+            free_pages: vec![],
+            meta_page_id: PageId::INVALID_PAGE_ID,
+        })
+    }
+}
+
+impl Default for MemoryDiskManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Storage for MemoryDiskManager {
+    fn read_page_data(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
+        // ファイルと同様に、書き込まれていない範囲は読み出せない
+        let page = self.pages.get(page_id.to_u64() as usize).ok_or(Error::ShortRead {
+            page_id,
+            got: 0,
+            expected: data.len(),
+        })?;
+        data.copy_from_slice(&page[..data.len()]);
+        Ok(())
+    }
+
+    fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
+        let idx = page_id.to_u64() as usize;
+        if self.pages.len() <= idx {
+            let page_size = self.page_size;
+            self.pages
+                .resize_with(idx + 1, || vec![0; page_size].into_boxed_slice());
+        }
+        self.pages[idx][..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn allocate_page(&mut self) -> io::Result<PageId> {
+        if let Some(page_id) = self.free_pages.pop() {
+            return Ok(page_id);
+        }
+        let page_id = self.next_page_id;
+        self.next_page_id += 1;
+        Ok(PageId(page_id))
+    }
+
+    fn deallocate_page(&mut self, page_id: PageId) -> io::Result<()> {
+        if page_id.to_u64() >= self.next_page_id || self.free_pages.contains(&page_id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("page {} is not allocated", page_id.to_u64()),
+            ));
+        }
+        self.free_pages.push(page_id);
+        Ok(())
+    }
+
+    fn meta_page_id(&self) -> PageId {
+        self.meta_page_id
+    }
+
+    fn set_meta_page_id(&mut self, meta_page_id: PageId) -> io::Result<()> {
+        self.meta_page_id = meta_page_id;
+        Ok(())
+    }
+
+    fn num_pages(&self) -> u64 {
+        self.next_page_id - self.free_pages.len() as u64
+    }
+
+    fn next_page_id(&self) -> PageId {
+        PageId(self.next_page_id)
+    }
+
+    // 書き込んだことのあるページの分だけメモリを使う
+    fn file_size_bytes(&self) -> io::Result<u64> {
+        Ok((self.pages.len() * self.page_size) as u64)
+    }
+
+    // 最後に解放したページから再利用する
+    fn free_page_ids(&mut self) -> io::Result<Vec<PageId>> {
+        Ok(self.free_pages.iter().rev().copied().collect())
+    }
+
+    fn compact(&mut self) -> io::Result<u64> {
+        while self.next_page_id > 0 && self.free_pages.contains(&PageId(self.next_page_id - 1)) {
+            self.next_page_id -= 1;
+        }
+        let next_page_id = self.next_page_id;
+        self.free_pages.retain(|page_id| page_id.to_u64() < next_page_id);
+        let len = self.pages.len();
+        self.pages.truncate(next_page_id as usize);
+        Ok(((len - self.pages.len()) * self.page_size) as u64)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test() {
+        let mut disk = MemoryDiskManager::new();
+        let mut hello = vec![0; PAGE_SIZE];
+        hello[..5].copy_from_slice(b"hello");
+        let hello_page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(hello_page_id, &hello).unwrap();
+        let world_page_id = disk.allocate_page().unwrap();
+        let mut buf = vec![0; PAGE_SIZE];
+        // まだ書き込んでいないページは読み出せない
+        let err = disk.read_page_data(world_page_id, &mut buf).unwrap_err();
+        assert!(matches!(Error::from(err), Error::ShortRead { got: 0, .. }));
+
+        let mut world = vec![0; PAGE_SIZE];
+        world[..5].copy_from_slice(b"world");
+        disk.write_page_data(world_page_id, &world).unwrap();
+        disk.read_page_data(hello_page_id, &mut buf).unwrap();
+        assert_eq!(hello, buf);
+        disk.read_page_data(world_page_id, &mut buf).unwrap();
+        assert_eq!(world, buf);
+    }
+
+    #[test]
+    fn test_free_pages() {
+        let mut disk = MemoryDiskManager::new();
+        let page_ids: Vec<_> = (0..3).map(|_| disk.allocate_page().unwrap()).collect();
+        disk.deallocate_page(page_ids[1]).unwrap();
+        assert!(disk.deallocate_page(page_ids[1]).is_err());
+        assert_eq!(page_ids[1], disk.allocate_page().unwrap());
+        assert_eq!(PageId(3), disk.allocate_page().unwrap());
+    }
+}
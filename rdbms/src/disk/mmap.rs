@@ -0,0 +1,400 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+use super::{
+    preallocate, DiskManager, DiskManagerOptions, HeapFile, PageId, Storage, TablespaceId,
+    PAGE_SIZE,
+};
+
+// ファイルをメモリマップして読み書きする
+// マップはファイルより大きく取っておき、ファイルが伸びてもすぐにはマップし直さない
+pub(super) struct Mmap {
+    file: File,
+    ptr: *mut u8,
+    // マップしている大きさ
+    map_len: usize,
+    // ファイルの大きさ。マップのうちここまでしか触らない
+    file_len: u64,
+    // 書き込みのたびにmsyncするかどうか
+    sync_on_write: bool,
+}
+
+// マップはこの構造体だけが持っているので、スレッドをまたいでも問題ない
+unsafe impl Send for Mmap {}
+
+impl fmt::Debug for Mmap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mmap")
+            .field("file", &self.file)
+            .field("map_len", &self.map_len)
+            .field("file_len", &self.file_len)
+            .field("sync_on_write", &self.sync_on_write)
+            .finish()
+    }
+}
+
+fn os_page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+impl Mmap {
+    pub(super) fn new(file: File) -> io::Result<Self> {
+        let file_len = file.metadata()?.len();
+        let mut mmap = Self {
+            file,
+            ptr: ptr::null_mut(),
+            map_len: 0,
+            file_len,
+            sync_on_write: false,
+        };
+        mmap.remap(file_len as usize)?;
+        Ok(mmap)
+    }
+
+    pub(super) fn len(&self) -> u64 {
+        self.file_len
+    }
+
+    // 古いマップを捨てて、map_lenの大きさでマップし直す
+    fn remap(&mut self, map_len: usize) -> io::Result<()> {
+        self.unmap();
+        if map_len == 0 {
+            return Ok(());
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                self.file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        self.ptr = ptr as *mut u8;
+        self.map_len = map_len;
+        Ok(())
+    }
+
+    fn unmap(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe {
+                libc::munmap(self.ptr as *mut libc::c_void, self.map_len);
+            }
+            self.ptr = ptr::null_mut();
+            self.map_len = 0;
+        }
+    }
+
+    // offsetからlenバイトの範囲をmsyncする。先頭はOSのページ境界に揃える
+    fn msync(&self, offset: usize, len: usize) -> io::Result<()> {
+        if self.ptr.is_null() || len == 0 {
+            return Ok(());
+        }
+        let start = offset & !(os_page_size() - 1);
+        let ret = unsafe {
+            libc::msync(
+                self.ptr.add(start) as *mut libc::c_void,
+                offset + len - start,
+                libc::MS_SYNC,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // マップからコピーして読み出す。ファイルの末尾を越える分は読まない
+    pub(super) fn read_partial(&mut self, offset: u64, data: &mut [u8]) -> io::Result<usize> {
+        let len = self.file_len.saturating_sub(offset).min(data.len() as u64) as usize;
+        // 末尾より後ろや空のマップを指すポインタは作らない
+        if len == 0 {
+            return Ok(0);
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(self.ptr.add(offset as usize), data.as_mut_ptr(), len);
+        }
+        Ok(len)
+    }
+
+    // ファイルをlenまで伸ばす。マップに収まらなければマップし直す
+    pub(super) fn extend(&mut self, len: u64) -> io::Result<()> {
+        if len <= self.file_len {
+            return Ok(());
+        }
+        preallocate(&self.file, len)?;
+        self.file_len = len;
+        if len as usize > self.map_len {
+            self.remap((len as usize).max(self.map_len * 2))?;
+        }
+        Ok(())
+    }
+
+    // ファイルをlenまで縮める
+    // マップはそのままにして、ファイルの大きさより後ろには触らないようにする
+    pub(super) fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.file_len = len;
+        Ok(())
+    }
+
+    // マップへコピーして書き込む
+    // ファイルの末尾を越えるなら、先にファイルを伸ばす
+    pub(super) fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.extend(offset + data.len() as u64)?;
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(offset as usize), data.len());
+        }
+        if self.sync_on_write {
+            self.msync(offset as usize, data.len())?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn sync_all(&mut self) -> io::Result<()> {
+        self.msync(0, self.file_len as usize)?;
+        self.file.sync_all()
+    }
+
+    pub(super) fn sync_data(&mut self) -> io::Result<()> {
+        self.msync(0, self.file_len as usize)?;
+        self.file.sync_data()
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        self.unmap();
+    }
+}
+
+pub(super) fn mmap_heap_file(file: File) -> io::Result<HeapFile> {
+    Ok(HeapFile::Mmap(Mmap::new(file)?))
+}
+
+// メモリマップを通して読み書きするDiskManager
+// ファイル形式はDiskManagerと同じなので、どちらで作ったファイルもどちらでも開ける
+#[derive(Debug)]
+pub struct MmapDiskManager(DiskManager);
+
+impl MmapDiskManager {
+    pub fn new(heap_file: File) -> io::Result<Self> {
+        Self::with_page_size(heap_file, PAGE_SIZE)
+    }
+
+    pub fn with_page_size(heap_file: File, page_size: usize) -> io::Result<Self> {
+        let disk = DiskManager::from_heap_file(mmap_heap_file(heap_file)?, page_size, None)?;
+        Ok(Self(disk))
+    }
+
+    pub fn create(heap_file_path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::create_with_page_size(heap_file_path, PAGE_SIZE)
+    }
+
+    pub fn create_with_page_size(
+        heap_file_path: impl AsRef<Path>,
+        page_size: usize,
+    ) -> io::Result<Self> {
+        let disk = DiskManager::create_in(
+            heap_file_path.as_ref(),
+            page_size,
+            mmap_heap_file,
+            DiskManagerOptions::default(),
+        )?;
+        Ok(Self(disk))
+    }
+
+    pub fn open(heap_file_path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with_page_size(heap_file_path, PAGE_SIZE)
+    }
+
+    pub fn open_with_page_size(
+        heap_file_path: impl AsRef<Path>,
+        page_size: usize,
+    ) -> io::Result<Self> {
+        let disk = DiskManager::open_in(
+            heap_file_path.as_ref(),
+            page_size,
+            mmap_heap_file,
+            DiskManagerOptions::default(),
+        )?;
+        Ok(Self(disk))
+    }
+
+    pub fn with_extent_pages(self, extent_pages: u64) -> Self {
+        Self(self.0.with_extent_pages(extent_pages))
+    }
+
+    // 書き込みのたびに、書いた範囲をmsyncするようにする
+    pub fn set_sync_on_write(&mut self, sync_on_write: bool) {
+        if let HeapFile::Mmap(mmap) = &mut self.0.heap_file {
+            mmap.sync_on_write = sync_on_write;
+        }
+    }
+
+    pub fn has_checksum(&self) -> bool {
+        self.0.has_checksum()
+    }
+
+    pub fn create_tablespace(&mut self, name: &str) -> io::Result<TablespaceId> {
+        self.0.create_tablespace(name)
+    }
+
+    pub fn tablespace_id(&self, name: &str) -> Option<TablespaceId> {
+        self.0.tablespace_id(name)
+    }
+}
+
+impl Storage for MmapDiskManager {
+    fn read_page_data(&mut self, page_id: PageId, data: &mut [u8]) -> io::Result<()> {
+        self.0.read_page_data(page_id, data)
+    }
+
+    fn write_page_data(&mut self, page_id: PageId, data: &[u8]) -> io::Result<()> {
+        self.0.write_page_data(page_id, data)
+    }
+
+    fn write_pages_atomic(&mut self, pages: &[(PageId, &[u8])]) -> io::Result<()> {
+        self.0.write_pages_atomic(pages)
+    }
+
+    fn page_size(&self) -> usize {
+        self.0.page_size()
+    }
+
+    fn allocate_page(&mut self) -> io::Result<PageId> {
+        self.0.allocate_page()
+    }
+
+    fn allocate_page_in(&mut self, tablespace_id: TablespaceId) -> io::Result<PageId> {
+        self.0.allocate_page_in(tablespace_id)
+    }
+
+    fn deallocate_page(&mut self, page_id: PageId) -> io::Result<()> {
+        self.0.deallocate_page(page_id)
+    }
+
+    fn meta_page_id(&self) -> PageId {
+        self.0.meta_page_id()
+    }
+
+    fn set_meta_page_id(&mut self, meta_page_id: PageId) -> io::Result<()> {
+        self.0.set_meta_page_id(meta_page_id)
+    }
+
+    fn num_pages(&self) -> u64 {
+        self.0.num_pages()
+    }
+
+    fn next_page_id(&self) -> PageId {
+        self.0.next_page_id()
+    }
+
+    fn file_size_bytes(&self) -> io::Result<u64> {
+        self.0.file_size_bytes()
+    }
+
+    fn free_page_ids(&mut self) -> io::Result<Vec<PageId>> {
+        self.0.free_page_ids()
+    }
+
+    fn tablespaces(&self) -> Vec<(TablespaceId, String)> {
+        self.0.tablespaces()
+    }
+
+    fn next_page_id_in(&self, tablespace_id: TablespaceId) -> io::Result<PageId> {
+        self.0.next_page_id_in(tablespace_id)
+    }
+
+    fn free_page_ids_in(&mut self, tablespace_id: TablespaceId) -> io::Result<Vec<PageId>> {
+        self.0.free_page_ids_in(tablespace_id)
+    }
+
+    fn compact(&mut self) -> io::Result<u64> {
+        self.0.compact()
+    }
+
+    // マップの内容をmsyncしてから、ファイルをfsyncする
+    fn sync(&mut self) -> io::Result<()> {
+        self.0.sync()
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::{tempdir, tempfile};
+
+    fn page(byte: u8) -> Vec<u8> {
+        vec![byte; PAGE_SIZE]
+    }
+
+    #[test]
+    fn test() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("heap");
+        let mut disk = MmapDiskManager::create(&path).unwrap();
+        // ページを書くたびにファイルが伸び、最初のマップを越えていく
+        let page_ids: Vec<_> = (0..100).map(|_| disk.allocate_page().unwrap()).collect();
+        for (i, page_id) in page_ids.iter().enumerate() {
+            disk.write_page_data(*page_id, &page(i as u8)).unwrap();
+        }
+        disk.set_meta_page_id(page_ids[3]).unwrap();
+        let mut buf = page(0);
+        for (i, page_id) in page_ids.iter().enumerate() {
+            disk.read_page_data(*page_id, &mut buf).unwrap();
+            assert_eq!(&page(i as u8)[..8], &buf[..8]);
+        }
+        assert!(disk.read_page_data(PageId(100), &mut buf).is_err());
+        disk.sync().unwrap();
+        drop(disk);
+
+        // 同じ形式なので、DiskManagerでも読める
+        let mut disk = DiskManager::open(&path).unwrap();
+        assert_eq!(page_ids[3], disk.meta_page_id());
+        disk.read_page_data(page_ids[42], &mut buf).unwrap();
+        assert_eq!(&page(42)[..8], &buf[..8]);
+        assert_eq!(PageId(100), disk.allocate_page().unwrap());
+    }
+
+    #[test]
+    fn test_read_partial() {
+        let mut mmap = Mmap::new(tempfile().unwrap()).unwrap();
+        let mut buf = [0xFF; 8];
+        assert_eq!(0, mmap.read_partial(0, &mut buf).unwrap());
+        mmap.extend(5).unwrap();
+        assert_eq!(5, mmap.read_partial(0, &mut buf).unwrap());
+        assert_eq!(2, mmap.read_partial(3, &mut buf).unwrap());
+        assert_eq!(0, mmap.read_partial(5, &mut buf).unwrap());
+        assert_eq!(0, mmap.read_partial(u64::MAX, &mut buf).unwrap());
+    }
+
+    #[test]
+    fn test_sync_on_write() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("heap");
+        let mut disk = MmapDiskManager::create(&path).unwrap();
+        disk.set_sync_on_write(true);
+        let page_id = disk.allocate_page().unwrap();
+        disk.write_page_data(page_id, &page(7)).unwrap();
+        drop(disk);
+
+        let mut disk = MmapDiskManager::open(&path).unwrap();
+        assert!(disk.has_checksum());
+        let mut buf = page(0);
+        disk.read_page_data(page_id, &mut buf).unwrap();
+        assert_eq!(&page(7)[..8], &buf[..8]);
+    }
+}
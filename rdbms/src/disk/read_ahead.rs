@@ -0,0 +1,98 @@
+use super::AlignedBuffer;
+
+// 先読みを始めるまでに、続けて順に読まれるページの数
+pub(super) const DEFAULT_READ_AHEAD_TRIGGER: u32 = 4;
+// 一度に先読みするページの数
+pub(super) const DEFAULT_READ_AHEAD_PAGES: usize = 16;
+
+// 先読みの統計情報
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct ReadAheadStats {
+    pub hits: u64,              // 先読みしたページで読み出しを済ませた回数
+    pub prefetched_pages: u64,  // 先読みしたページの数
+    pub file_reads: u64,        // ページを読むためにファイルを読んだ回数
+}
+
+// 順に読まれていることを検出して、続くページを先読みしておく
+#[derive(Debug)]
+pub(super) struct ReadAhead {
+    trigger: u32,
+    window: usize,
+    // 直前に読まれたページ番号と、そこまで続けて順に読まれたページの数
+    last_page: Option<u64>,
+    sequential: u32,
+    // 先読みしたページ。start番から順に並び、使ったり書き換えられたりしたものはNoneにする
+    start: u64,
+    pages: Vec<Option<AlignedBuffer>>,
+    pub(super) stats: ReadAheadStats,
+}
+
+impl Default for ReadAhead {
+    fn default() -> Self {
+        Self::new(DEFAULT_READ_AHEAD_TRIGGER, DEFAULT_READ_AHEAD_PAGES)
+    }
+}
+
+impl ReadAhead {
+    pub(super) fn new(trigger: u32, window: usize) -> Self {
+        Self {
+            trigger,
+            window,
+            last_page: None,
+            sequential: 0,
+            start: 0,
+            pages: vec![],
+            stats: ReadAheadStats::default(),
+        }
+    }
+
+    pub(super) fn window(&self) -> usize {
+        self.window
+    }
+
+    fn slot(&mut self, page_number: u64) -> Option<&mut Option<AlignedBuffer>> {
+        let idx = page_number.checked_sub(self.start)?;
+        self.pages.get_mut(idx as usize)
+    }
+
+    // ページが読まれたことを記録する
+    // 続くページを先読みするべきならtrueを返す
+    pub(super) fn record(&mut self, page_number: u64) -> bool {
+        self.sequential = match self.last_page {
+            Some(last) if last + 1 == page_number => self.sequential.saturating_add(1),
+            _ => 1,
+        };
+        self.last_page = Some(page_number);
+        self.window > 0
+            && self.sequential >= self.trigger
+            && !matches!(self.slot(page_number + 1), Some(Some(_)))
+    }
+
+    // 先読みしたページがあれば取り出す
+    pub(super) fn take(&mut self, page_number: u64) -> Option<AlignedBuffer> {
+        let page = self.slot(page_number)?.take()?;
+        self.stats.hits += 1;
+        Some(page)
+    }
+
+    // start番から順に並んだページを、先読みしたページとして持っておく
+    pub(super) fn fill(&mut self, start: u64, pages: Vec<AlignedBuffer>) {
+        self.stats.prefetched_pages += pages.len() as u64;
+        self.start = start;
+        self.pages = pages.into_iter().map(Some).collect();
+    }
+
+    // 先読みしたページをすべて捨てる
+    pub(super) fn clear(&mut self) {
+        self.last_page = None;
+        self.sequential = 0;
+        self.pages.clear();
+    }
+
+    // 書き換えられたページを捨てる
+    pub(super) fn invalidate(&mut self, page_number: u64) {
+        if let Some(slot) = self.slot(page_number) {
+            *slot = None;
+        }
+    }
+}
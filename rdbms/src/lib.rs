@@ -5,4 +5,6 @@ pub mod bsearch;
 pub mod slotted;
 pub mod table;
 pub mod tuple;
-pub mod memcmpable;
\ No newline at end of file
+pub mod memcmpable;
+pub mod checksum;
+pub mod compress;
\ No newline at end of file
@@ -35,6 +35,18 @@ pub fn decode(src: &mut &[u8], dst: &mut Vec<u8>) {
     }
 }
 
+// 先頭の要素を符号化したバイト数。終わりの印まで揃っていなければNoneを返す
+pub fn encoded_len(src: &[u8]) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let extra = *src.get(len + ESCAPE_LENGTH - 1)?;
+        len += ESCAPE_LENGTH;
+        if extra < ESCAPE_LENGTH as u8 {
+            return Some(len);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -57,4 +69,17 @@ mod tests {
         decode(&mut rest, &mut dec2);
         assert_eq!(org2, dec2.as_slice());
     }
+
+    #[test]
+    fn test_encoded_len() {
+        let mut enc = vec![];
+        encode(b"helloworld!", &mut enc);
+        let len = enc.len();
+        encode(b"abc", &mut enc);
+        assert_eq!(Some(len), encoded_len(&enc));
+        assert_eq!(Some(9), encoded_len(&enc[len..]));
+        assert_eq!(None, encoded_len(&enc[..len - 1]));
+        assert_eq!(None, encoded_len(&enc[..9]));
+        assert_eq!(None, encoded_len(&[]));
+    }
 }
\ No newline at end of file
@@ -8,20 +8,30 @@ use zerocopy::{AsBytes, ByteSlice, ByteSliceMut, FromBytes, LayoutVerified};
 pub struct Header {
     num_slots: u16,
     free_space_offset: u16,
-    _pad: u32,
+    // スロットを加えたり取り除いたりして、番号がずれるたびに1つ増える
+    // 以前は詰め物だった領域なので、以前のページでは0から数え始める
+    version: u32,
 }
 
 #[derive(Debug, FromBytes, AsBytes, Clone, Copy)]
 #[repr(C)]
 pub struct Pointer {
     offset: u16,
+    // 上位1ビットは墓標の印。ページは32KiBまでなので、長さは下位15ビットに収まる
     len: u16,
 }
 
+const FLAG_TOMBSTONE: u16 = 0x8000;
+const LEN_MASK: u16 = !FLAG_TOMBSTONE;
+
 impl Pointer {
+    fn len(&self) -> usize {
+        (self.len & LEN_MASK) as usize
+    }
+
     fn range(&self) -> Range<usize> {
         let start = self.offset as usize;
-        let end = start + self.len as usize;
+        let end = start + self.len();
         start..end
     }
 }
@@ -48,10 +58,69 @@ impl<B: ByteSlice> Slotted<B> {
         self.header.num_slots as usize
     }
 
+    // 同じ値のあいだは、どのスロットの番号も変わっていない
+    pub fn version(&self) -> u32 {
+        self.header.version
+    }
+
     pub fn free_space(&self) -> usize {
         self.header.free_space_offset as usize - self.pointers_size()
     }
 
+    // スロットのデータとポインタが使っているバイト数
+    pub fn used_space(&self) -> usize {
+        self.capacity() - self.free_space()
+    }
+
+    // lenバイトのスロットを加えても、使う領域が容量のfill_factorの割合に収まるならtrue
+    pub fn fits_within(&self, len: usize, fill_factor: f64) -> bool {
+        let used = self.used_space() + size_of::<Pointer>() + len;
+        used as f64 <= self.capacity() as f64 * fill_factor
+    }
+
+    // ヘッダとポインタが指す範囲が食い違っていないか確かめる
+    // スロットのデータは空き領域の後ろに隙間なく詰めてあるので、長さの合計は使っている領域と一致する
+    pub fn check(&self) -> Result<(), String> {
+        let free_space_offset = self.header.free_space_offset as usize;
+        if free_space_offset > self.capacity() {
+            return Err(format!(
+                "free space offset {} is beyond the capacity {}",
+                free_space_offset,
+                self.capacity()
+            ));
+        }
+        if self.pointers_size() > free_space_offset {
+            return Err(format!(
+                "{} slot pointers overlap the free space offset {}",
+                self.num_slots(),
+                free_space_offset
+            ));
+        }
+        let mut ranges: Vec<_> = self.pointers().iter().map(Pointer::range).collect();
+        ranges.sort_by_key(|range| range.start);
+        let mut end = free_space_offset;
+        for range in ranges.iter().filter(|range| !range.is_empty()) {
+            if range.start < end || range.end > self.capacity() {
+                return Err(format!("slot data {:?} overlaps other data", range));
+            }
+            end = range.end;
+        }
+        let data_len: usize = ranges.iter().map(|range| range.len()).sum();
+        if data_len != self.capacity() - free_space_offset {
+            return Err(format!(
+                "slot data is {} bytes but {} bytes are in use",
+                data_len,
+                self.capacity() - free_space_offset
+            ));
+        }
+        Ok(())
+    }
+
+    // 削除したが、まだ取り除いていないスロット
+    pub fn is_tombstone(&self, index: usize) -> bool {
+        self.pointers()[index].len & FLAG_TOMBSTONE != 0
+    }
+
     fn pointers_size(&self) -> usize {
         size_of::<Pointer>() * self.num_slots()
     }
@@ -66,9 +135,15 @@ impl<B: ByteSlice> Slotted<B> {
 }
 
 impl<B: ByteSliceMut> Slotted<B> {
+    // 版は0に戻さず、初期化もスロットを取り除いたものとして数える
     pub fn initialize(&mut self) {
         self.header.num_slots = 0;
         self.header.free_space_offset = self.body.len() as u16;
+        self.bump_version();
+    }
+
+    fn bump_version(&mut self) {
+        self.header.version = self.header.version.wrapping_add(1);
     }
 
     fn pointers_mut(&mut self) -> Pointers<&mut [u8]> {
@@ -93,18 +168,73 @@ impl<B: ByteSliceMut> Slotted<B> {
         let pointer = &mut pointers_mut[index];
         pointer.offset = free_space_offset;
         pointer.len = len as u16;
+        self.bump_version();
         Some(())
     }
 
+    // 墓標の印はresizeしても残り、removeで消える
+    pub fn set_tombstone(&mut self, index: usize, tombstone: bool) {
+        let pointer = &mut self.pointers_mut()[index];
+        if tombstone {
+            pointer.len |= FLAG_TOMBSTONE;
+        } else {
+            pointer.len &= LEN_MASK;
+        }
+    }
+
     pub fn remove(&mut self, index: usize) {
         self.resize(index, 0);
         self.pointers_mut().copy_within(index + 1.., index);
         self.header.num_slots -= 1;
+        self.bump_version();
+    }
+
+    // 先頭のcount個のスロットを、墓標の印ごとdestの末尾に移す。destに入らなければ何もしない
+    // removeを繰り返すと残りのデータを毎回ずらすので、取り除くのは最後に1回で済ませる
+    pub fn transfer_front(
+        &mut self,
+        dest: &mut Slotted<impl ByteSliceMut>,
+        count: usize,
+    ) -> Option<()> {
+        let moved_len: usize = self.pointers()[..count].iter().map(Pointer::len).sum();
+        if dest.free_space() < moved_len + size_of::<Pointer>() * count {
+            return None;
+        }
+        for index in 0..count {
+            let dest_index = dest.num_slots();
+            dest.insert(dest_index, self[index].len())?;
+            dest[dest_index].copy_from_slice(&self[index]);
+            dest.set_tombstone(dest_index, self.is_tombstone(index));
+        }
+        self.remove_front(count);
+        Some(())
+    }
+
+    // 残すスロットのデータをいったん写し、末尾から詰め直す
+    fn remove_front(&mut self, count: usize) {
+        let mut pointers = self.pointers()[count..].to_vec();
+        let mut data = vec![];
+        for &pointer in pointers.iter() {
+            data.extend_from_slice(self.data(pointer));
+        }
+        let mut offset = self.body.len();
+        let mut data_offset = 0;
+        for pointer in pointers.iter_mut() {
+            let len = pointer.len();
+            offset -= len;
+            self.body[offset..offset + len].copy_from_slice(&data[data_offset..data_offset + len]);
+            data_offset += len;
+            pointer.offset = offset as u16;
+        }
+        self.header.num_slots = pointers.len() as u16;
+        self.header.free_space_offset = offset as u16;
+        self.pointers_mut().copy_from_slice(&pointers);
+        self.bump_version();
     }
 
     pub fn resize(&mut self, index: usize, len_new: usize) -> Option<()> {
         let pointers = self.pointers();
-        let len_orig = pointers[index].len;
+        let len_orig = pointers[index].len();
         let len_incr = len_new as isize - len_orig as isize;
         if len_incr == 0 {
             return Some(());
@@ -127,7 +257,7 @@ impl<B: ByteSliceMut> Slotted<B> {
             }
         }
         let pointer = &mut pointers_mut[index];
-        pointer.len = len_new as u16;
+        pointer.len = (pointer.len & FLAG_TOMBSTONE) | len_new as u16;
         if len_new == 0 {
             pointer.offset = free_space_offset_new as u16;
         }
@@ -162,7 +292,7 @@ mod tests {
             slotted[index].copy_from_slice(buf);
         };
         let push = |slotted: &mut Slotted<&mut [u8]>, buf: &[u8]| {
-            let index = slotted.num_slots() as usize;
+            let index = slotted.num_slots();
             insert(slotted, index, buf);
         };
         slotted.initialize();
@@ -177,4 +307,130 @@ mod tests {
         assert_eq!(&slotted[2], b"world");
         assert_eq!(&slotted[3], b"!");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_transfer_front() {
+        let mut page_data = vec![0u8; 128];
+        let mut slotted = Slotted::new(page_data.as_mut_slice());
+        slotted.initialize();
+        let bufs = [&b"hello"[..], b"", b"world", b"!", b"memcmpable"];
+        for (index, buf) in bufs.iter().enumerate() {
+            slotted.insert(index, buf.len()).unwrap();
+            slotted[index].copy_from_slice(buf);
+        }
+        slotted.set_tombstone(2, true);
+        slotted.set_tombstone(4, true);
+        let mut dest_data = vec![0u8; 128];
+        let mut dest = Slotted::new(dest_data.as_mut_slice());
+        dest.initialize();
+        dest.insert(0, 3).unwrap();
+        dest[0].copy_from_slice(b"abc");
+
+        let version = slotted.version();
+        slotted.transfer_front(&mut dest, 3).unwrap();
+        assert_ne!(version, slotted.version());
+        let slots = |slotted: &Slotted<&mut [u8]>| -> Vec<(Vec<u8>, bool)> {
+            (0..slotted.num_slots())
+                .map(|index| (slotted[index].to_vec(), slotted.is_tombstone(index)))
+                .collect()
+        };
+        let expected = vec![
+            (b"abc".to_vec(), false),
+            (b"hello".to_vec(), false),
+            (vec![], false),
+            (b"world".to_vec(), true),
+        ];
+        assert_eq!(expected, slots(&dest));
+        assert_eq!(vec![(b"!".to_vec(), false), (b"memcmpable".to_vec(), true)], slots(&slotted));
+        assert_eq!(Ok(()), slotted.check());
+        assert_eq!(Ok(()), dest.check());
+        assert_eq!(slotted.capacity() - 11 - 2 * size_of::<Pointer>(), slotted.free_space());
+
+        // destに入らなければ、どちらも変えない
+        let mut small_data = vec![0u8; 16];
+        let mut small = Slotted::new(small_data.as_mut_slice());
+        small.initialize();
+        assert!(slotted.transfer_front(&mut small, 2).is_none());
+        assert_eq!(0, small.num_slots());
+        assert_eq!(2, slotted.num_slots());
+        slotted.transfer_front(&mut dest, 2).unwrap();
+        assert_eq!(0, slotted.num_slots());
+        assert_eq!(slotted.capacity(), slotted.free_space());
+        assert_eq!(6, dest.num_slots());
+    }
+
+    #[test]
+    fn test_check() {
+        let mut page_data = vec![0u8; 128];
+        let mut slotted = Slotted::new(page_data.as_mut_slice());
+        slotted.initialize();
+        for (index, buf) in [&b"hello"[..], b"world", b""].iter().enumerate() {
+            slotted.insert(index, buf.len()).unwrap();
+            slotted[index].copy_from_slice(buf);
+        }
+        slotted.resize(0, 2).unwrap();
+        assert_eq!(Ok(()), slotted.check());
+        slotted.remove(1);
+        assert_eq!(Ok(()), slotted.check());
+
+        slotted.header.free_space_offset -= 1;
+        assert!(slotted.check().is_err());
+        slotted.header.free_space_offset = 200;
+        assert!(slotted.check().is_err());
+        slotted.header.free_space_offset = 126;
+        slotted.header.num_slots = 100;
+        assert!(slotted.check().is_err());
+    }
+
+    #[test]
+    fn test_tombstone() {
+        let mut page_data = vec![0u8; 128];
+        let mut slotted = Slotted::new(page_data.as_mut_slice());
+        slotted.initialize();
+        for (index, buf) in [&b"hello"[..], b"world"].iter().enumerate() {
+            slotted.insert(index, buf.len()).unwrap();
+            slotted[index].copy_from_slice(buf);
+        }
+        slotted.set_tombstone(0, true);
+        assert!(slotted.is_tombstone(0));
+        assert!(!slotted.is_tombstone(1));
+        // 長さを変えても印は残り、データの範囲は変わらない
+        slotted.resize(0, 8).unwrap();
+        assert!(slotted.is_tombstone(0));
+        assert_eq!(8, slotted[0].len());
+        assert_eq!(&slotted[1], b"world");
+        assert_eq!(Ok(()), slotted.check());
+        slotted.set_tombstone(0, false);
+        assert!(!slotted.is_tombstone(0));
+        slotted.set_tombstone(1, true);
+        slotted.remove(0);
+        assert!(slotted.is_tombstone(0));
+        slotted.insert(0, 2).unwrap();
+        assert!(!slotted.is_tombstone(0));
+        assert_eq!(Ok(()), slotted.check());
+    }
+
+    #[test]
+    fn test_version() {
+        let mut page_data = vec![0u8; 128];
+        let mut slotted = Slotted::new(page_data.as_mut_slice());
+        slotted.initialize();
+        let version = slotted.version();
+        slotted.insert(0, 5).unwrap();
+        slotted.insert(1, 5).unwrap();
+        assert_eq!(version.wrapping_add(2), slotted.version());
+        // 書き換えや長さの変更では番号はずれない
+        let version = slotted.version();
+        slotted[0].copy_from_slice(b"hello");
+        slotted.resize(1, 3).unwrap();
+        slotted.set_tombstone(1, true);
+        assert_eq!(version, slotted.version());
+        assert!(slotted.insert(0, 200).is_none());
+        assert_eq!(version, slotted.version());
+        slotted.remove(0);
+        assert_ne!(version, slotted.version());
+        let version = slotted.version();
+        slotted.initialize();
+        assert_ne!(version, slotted.version());
+    }
+}
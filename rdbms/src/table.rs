@@ -1,14 +1,53 @@
+use std::fmt::{self, Debug};
+
 use anyhow::Result;
+use thiserror::Error;
 
-use crate::btree::BTree;
+use crate::btree::{self, BTree};
 use crate::buffer::BufferPoolManager;
 use crate::disk::PageId;
-use crate::tuple;
+use crate::tuple::{self, Schema, Value};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    // 同じプライマリキーのレコードがすでにある。レコードは書き換えない
+    // insertで入れようとしたキーの列はValue::Bytesに、insert_optionalのNULLはValue::Nullになる
+    #[error("primary key violation: {:?} already exists", PrettyValues(.0))]
+    PrimaryKeyViolation(Vec<Value>),
+    // レコードの列が、プライマリキーの列より少ない
+    #[error("record has {len} columns, fewer than {num_key_elems} primary key columns")]
+    TooFewColumns { len: usize, num_key_elems: usize },
+    // スキーマのあるテーブルには、型のない値をinsertで入れられない
+    #[error("the table has a schema; insert typed records with insert_values")]
+    SchemaRequiresValues,
+}
+
+// バイト列の値は、tuple::Prettyと同じく文字列としても表示する
+struct PrettyValues<'a>(&'a [Value]);
+
+impl<'a> Debug for PrettyValues<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_list();
+        for value in self.0 {
+            match value {
+                Value::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                    Ok(s) => d.entry(&format_args!("Bytes({:?} {:02x?})", s, bytes)),
+                    Err(_) => d.entry(value),
+                },
+                _ => d.entry(value),
+            };
+        }
+        d.finish()
+    }
+}
 
+// insertはtuple::encodeで、insert_valuesとinsert_optionalはtuple::encode_valuesで符号化する
+// 読むときに困らないよう、1つのテーブルにはどちらか一方で入れたレコードだけを置く
 #[derive(Debug)]
 pub struct SimpleTable {
     pub meta_page_id: PageId,   // テーブルの内容が入っているB+TreeのメタページのID
     pub num_key_elems: usize,   // 左からいくつの列がプライマリキーなのかを示す
+    pub schema: Option<Schema>, // あれば、入れるレコードの列の数と型を確かめる
 }
 
 impl SimpleTable {
@@ -18,15 +57,362 @@ impl SimpleTable {
         Ok(())
     }
 
+    // レコードをプライマリキーの列とそれ以外の列に分ける
+    fn split_key<'a, T>(&self, record: &'a [T]) -> Result<(&'a [T], &'a [T]), Error> {
+        if record.len() < self.num_key_elems {
+            return Err(Error::TooFewColumns {
+                len: record.len(),
+                num_key_elems: self.num_key_elems,
+            });
+        }
+        Ok(record.split_at(self.num_key_elems))
+    }
+
+    // スキーマがあれば、何も入れずにSchemaRequiresValuesを返す
     pub fn insert(&self, bufmgr: &mut BufferPoolManager, record: &[&[u8]]) -> Result<()> {
+        if self.schema.is_some() {
+            return Err(Error::SchemaRequiresValues.into());
+        }
         let btree = BTree::new(self.meta_page_id);
         // プライマリキーの部分 : record[..self.num_key_elems]
         // それ以外             : record[self.num_key_elems..]
+        let (key_elems, value_elems) = self.split_key(record)?;
         let mut key = vec![];
-        tuple::encode(record[..self.num_key_elems].iter(), &mut key);   // encodeはtuple::encodeを使っている
+        tuple::encode(key_elems.iter(), &mut key);   // encodeはtuple::encodeを使っている
         let mut value = vec![];
-        tuple::encode(record[self.num_key_elems..].iter(), &mut value);
-        btree.insert(bufmgr, &key, &value)?;
-        Ok(())
+        tuple::encode(value_elems.iter(), &mut value);
+        match btree.insert(bufmgr, &key, &value) {
+            Err(btree::Error::DuplicateKey) => {
+                let key = key_elems.iter().map(|elem| Value::Bytes(elem.to_vec()));
+                Err(Error::PrimaryKeyViolation(key.collect()).into())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    // NULLを含むレコードを入れる。NoneをValue::Nullに、ほかをValue::Bytesにしてinsert_valuesで入れる
+    // NULLのキーは、空のキーも含めてどのキーよりも前に並ぶ。スキーマがあれば、Bytesの列にだけ値を入れられる
+    pub fn insert_optional(
+        &self,
+        bufmgr: &mut BufferPoolManager,
+        record: &[Option<&[u8]>],
+    ) -> Result<()> {
+        let record: Vec<_> = record
+            .iter()
+            .map(|elem| elem.map_or(Value::Null, |elem| Value::Bytes(elem.to_vec())))
+            .collect();
+        self.insert_values(bufmgr, &record)
     }
-}
\ No newline at end of file
+
+    // 型のある値でレコードを入れる。キーも値もtuple::encode_valuesで符号化する
+    // 読むときは、プライマリキーとそれ以外の列の型でそれぞれtuple::decode_valuesを使う
+    // スキーマがあれば、合わないレコードは入れずにSchemaErrorを返す
+    pub fn insert_values(&self, bufmgr: &mut BufferPoolManager, record: &[Value]) -> Result<()> {
+        let btree = BTree::new(self.meta_page_id);
+        let mut key = vec![];
+        let mut value = vec![];
+        match &self.schema {
+            Some(schema) => schema.encode_record(record, &mut key, &mut value, self.num_key_elems)?,
+            None => {
+                let (key_values, other_values) = self.split_key(record)?;
+                tuple::encode_values(key_values, &mut key);
+                tuple::encode_values(other_values, &mut value);
+            }
+        }
+        match btree.insert(bufmgr, &key, &value) {
+            Err(btree::Error::DuplicateKey) => {
+                let key = record[..self.num_key_elems].to_vec();
+                Err(Error::PrimaryKeyViolation(key).into())
+            }
+            result => Ok(result?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::BufferPool;
+    use crate::disk::MemoryDiskManager;
+
+    #[test]
+    fn test_primary_key_violation() {
+        let mut bufmgr = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let mut table = SimpleTable {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            schema: None,
+        };
+        table.create(&mut bufmgr).unwrap();
+        table.insert(&mut bufmgr, &[b"z", b"Alice", b"Smith"]).unwrap();
+        let err = table
+            .insert(&mut bufmgr, &[b"z", b"Bob", b"Johnson"])
+            .unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::PrimaryKeyViolation(key)) => {
+                assert_eq!(&vec![Value::Bytes(b"z".to_vec())], key)
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert!(err.to_string().contains("\"z\""), "{}", err);
+
+        // 元のレコードはそのまま残る
+        let btree = BTree::new(table.meta_page_id);
+        let mut key = vec![];
+        tuple::encode([b"z"].iter(), &mut key);
+        let mut value = vec![];
+        tuple::encode([&b"Alice"[..], b"Smith"].iter(), &mut value);
+        assert_eq!(Some(value), btree.get(&mut bufmgr, &key).unwrap());
+        assert_eq!(1, btree.len(&mut bufmgr).unwrap());
+    }
+
+    fn records(bufmgr: &mut BufferPoolManager, table: &SimpleTable) -> Vec<Vec<Vec<u8>>> {
+        let btree = BTree::new(table.meta_page_id);
+        btree
+            .iter(bufmgr)
+            .map(|pair| {
+                let (key, value) = pair.unwrap();
+                let mut record = vec![];
+                tuple::decode(&key, &mut record);
+                tuple::decode(&value, &mut record);
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_empty_columns() {
+        let mut bufmgr = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let mut table = SimpleTable {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            schema: None,
+        };
+        table.create(&mut bufmgr).unwrap();
+        table.insert(&mut bufmgr, &[b"b", b"", b"Smith"]).unwrap();
+        table.insert(&mut bufmgr, &[b"a", b"", b""]).unwrap();
+        // 空のプライマリキーは、どのキーよりも前に並ぶ
+        table.insert(&mut bufmgr, &[b"", b"Alice", b""]).unwrap();
+        let err = table.insert(&mut bufmgr, &[b"", b"Bob", b""]).unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::PrimaryKeyViolation(key)) => assert_eq!(&vec![Value::Bytes(vec![])], key),
+            _ => panic!("unexpected error: {}", err),
+        }
+        let expected = vec![
+            vec![vec![], b"Alice".to_vec(), vec![]],
+            vec![b"a".to_vec(), vec![], vec![]],
+            vec![b"b".to_vec(), vec![], b"Smith".to_vec()],
+        ];
+        assert_eq!(expected, records(&mut bufmgr, &table));
+
+        // プライマリキーの列がなければキーは空になり、レコードは1つしか入らない
+        let mut table = SimpleTable {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 0,
+            schema: None,
+        };
+        table.create(&mut bufmgr).unwrap();
+        table.insert(&mut bufmgr, &[b"", b"x"]).unwrap();
+        let err = table.insert(&mut bufmgr, &[b"y"]).unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::PrimaryKeyViolation(key)) => assert!(key.is_empty()),
+            _ => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(vec![vec![vec![], b"x".to_vec()]], records(&mut bufmgr, &table));
+
+        // プライマリキーの列より短いレコードは入れない
+        let table = SimpleTable {
+            meta_page_id: table.meta_page_id,
+            num_key_elems: 2,
+            schema: None,
+        };
+        let errors = [
+            table.insert(&mut bufmgr, &[b"a"]).unwrap_err(),
+            table.insert_optional(&mut bufmgr, &[None]).unwrap_err(),
+            table.insert_values(&mut bufmgr, &[]).unwrap_err(),
+        ];
+        for err in errors.iter() {
+            match err.downcast_ref::<Error>() {
+                Some(Error::TooFewColumns { num_key_elems: 2, .. }) => {}
+                _ => panic!("unexpected error: {}", err),
+            }
+        }
+        assert_eq!(1, BTree::new(table.meta_page_id).len(&mut bufmgr).unwrap());
+    }
+
+    #[test]
+    fn test_insert_optional() {
+        use crate::tuple::ColumnType;
+
+        let mut bufmgr = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let mut table = SimpleTable {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 2,
+            schema: None,
+        };
+        table.create(&mut bufmgr).unwrap();
+        let records: Vec<Vec<Option<&[u8]>>> = vec![
+            vec![Some(b"b"), None, Some(b"x")],
+            vec![Some(b""), Some(b""), None],
+            vec![None, Some(b"a"), Some(b"")],
+            vec![Some(b"b"), Some(b""), None],
+            vec![None, None, None],
+            vec![Some(b""), None, Some(b"y")],
+        ];
+        for record in &records {
+            table.insert_optional(&mut bufmgr, record).unwrap();
+        }
+        // NULLと空のキーは別のキーになる
+        let err = table.insert_optional(&mut bufmgr, &[Some(b""), None, None]).unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::PrimaryKeyViolation(key)) => {
+                assert_eq!(&vec![Value::Bytes(vec![]), Value::Null], key)
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        // NULLのキーは、空のキーも含めてどのキーよりも前に並ぶ
+        let btree = BTree::new(table.meta_page_id);
+        let actual: Vec<_> = btree
+            .iter(&mut bufmgr)
+            .map(|pair| {
+                let (key, value) = pair.unwrap();
+                let mut record = tuple::decode_values(&key, &[ColumnType::Bytes; 2]).unwrap();
+                record.extend(tuple::decode_values(&value, &[ColumnType::Bytes]).unwrap());
+                record
+            })
+            .collect();
+        let value = |elem: &Option<&[u8]>| elem.map_or(Value::Null, |v| Value::Bytes(v.to_vec()));
+        let expected: Vec<Vec<_>> = [4, 2, 5, 1, 0, 3]
+            .iter()
+            .map(|&i| records[i].iter().map(value).collect())
+            .collect();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_schema() {
+        use crate::tuple::{ColumnDef, ColumnType, SchemaError};
+
+        let mut bufmgr = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let column = |name: &str, ty, nullable| ColumnDef {
+            name: name.to_string(),
+            ty,
+            nullable,
+        };
+        let schema = Schema::new(vec![
+            column("id", ColumnType::Int64, false),
+            column("name", ColumnType::Text, false),
+            column("score", ColumnType::Float64, true),
+            column("photo", ColumnType::Bytes, true),
+            column("active", ColumnType::Bool, true),
+        ]);
+        let mut table = SimpleTable {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 1,
+            schema: Some(schema.clone()),
+        };
+        table.create(&mut bufmgr).unwrap();
+        let record = vec![
+            Value::Int64(7),
+            Value::Text("Alice".to_string()),
+            Value::Float64(-1.5),
+            Value::Bytes(vec![0xFF; 20]),
+            Value::Bool(false),
+        ];
+        table.insert_values(&mut bufmgr, &record).unwrap();
+
+        // 列の数や型が合わないレコードは入れない
+        let mut schema_error = |record: &[Value]| {
+            let err = table.insert_values(&mut bufmgr, record).unwrap_err();
+            err.downcast::<SchemaError>().unwrap()
+        };
+        assert!(matches!(schema_error(&record[..4]), SchemaError::Arity { .. }));
+        let mut other = record.clone();
+        other[0] = Value::Int64(8);
+        other[2] = Value::Text("high".to_string());
+        assert!(matches!(schema_error(&other), SchemaError::TypeMismatch { .. }));
+        other[2] = Value::Null;
+        other[1] = Value::Null;
+        assert!(matches!(schema_error(&other), SchemaError::NullNotAllowed { .. }));
+        // 型のないレコードはinsertで入れられず、insert_optionalではBytesの列にしか入れられない
+        let err = table.insert(&mut bufmgr, &[b"8", b"Bob", b"", b"", b""]).unwrap_err();
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::SchemaRequiresValues)));
+        let err = table.insert_optional(&mut bufmgr, &[Some(b"8"), None, None, None, None]);
+        let err = err.unwrap_err().downcast::<SchemaError>().unwrap();
+        assert!(matches!(err, SchemaError::TypeMismatch { .. }));
+
+        other[1] = Value::Text("Bob".to_string());
+        table.insert_values(&mut bufmgr, &other).unwrap();
+        let btree = BTree::new(table.meta_page_id);
+        assert_eq!(2, btree.len(&mut bufmgr).unwrap());
+        let records: Vec<_> = btree
+            .iter(&mut bufmgr)
+            .map(|pair| {
+                let (key, value) = pair.unwrap();
+                schema.decode_record(&key, &value).unwrap()
+            })
+            .collect();
+        assert_eq!(vec![record, other], records);
+    }
+
+    #[test]
+    fn test_insert_values() {
+        use crate::tuple::ColumnType;
+
+        let mut bufmgr = BufferPoolManager::new(MemoryDiskManager::new(), BufferPool::new(10));
+        let mut table = SimpleTable {
+            meta_page_id: PageId::INVALID_PAGE_ID,
+            num_key_elems: 2,
+            schema: None,
+        };
+        table.create(&mut bufmgr).unwrap();
+        let record = |a: i64, b: &str, score: f64| {
+            vec![Value::Int64(a), Value::Text(b.to_string()), Value::Float64(score)]
+        };
+        let records = [(10, "b", 1.5), (-3, "z", -0.5), (10, "a", f64::NAN), (256, "", 0.0)];
+        for &(a, b, score) in records.iter() {
+            table.insert_values(&mut bufmgr, &record(a, b, score)).unwrap();
+        }
+        table.insert_values(&mut bufmgr, &[Value::Null, Value::Null, Value::Null]).unwrap();
+        let err = table.insert_values(&mut bufmgr, &record(10, "a", 2.0)).unwrap_err();
+        match err.downcast_ref::<Error>() {
+            Some(Error::PrimaryKeyViolation(key)) => {
+                assert_eq!(&record(10, "a", 0.0)[..2], &key[..])
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+
+        // キーは値の順に並び、負の数は正の数より前になる
+        let key_types = [ColumnType::Int64, ColumnType::Text];
+        let btree = BTree::new(table.meta_page_id);
+        let records: Vec<_> = btree
+            .iter(&mut bufmgr)
+            .map(|pair| {
+                let (key, value) = pair.unwrap();
+                let mut record = tuple::decode_values(&key, &key_types).unwrap();
+                record.extend(tuple::decode_values(&value, &[ColumnType::Float64]).unwrap());
+                record
+            })
+            .collect();
+        let expected = vec![
+            vec![Value::Null, Value::Null, Value::Null],
+            record(-3, "z", -0.5),
+            record(10, "a", f64::NAN),
+            record(10, "b", 1.5),
+            record(256, "", 0.0),
+        ];
+        assert_eq!(expected, records);
+
+        // 先頭の列だけで探せる
+        let mut prefix = vec![];
+        tuple::encode_values(&[Value::Int64(10)], &mut prefix);
+        let mut iter = btree.scan_prefix(&mut bufmgr, &prefix).unwrap();
+        let mut second_columns = vec![];
+        while let Some((key, _)) = iter.next(&mut bufmgr).unwrap() {
+            second_columns.push(tuple::decode_values(&key, &key_types).unwrap().remove(1));
+        }
+        let expected = vec![Value::Text("a".to_string()), Value::Text("b".to_string())];
+        assert_eq!(expected, second_columns);
+    }
+}
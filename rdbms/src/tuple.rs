@@ -1,7 +1,20 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
 use std::fmt::{self, Debug};
 
+use thiserror::Error;
+
 use crate::memcmpable;
 
+pub use schema::{ColumnDef, Schema, SchemaError};
+pub use value::{decode_nth_value, decode_values, encode_values, ColumnType, Value};
+
+mod schema;
+mod value;
+
+// 要素ごとに終わりの印まで符号化するので、先頭のいくつかの要素だけを符号化したものは、
+// それらの要素で始まるタプルを符号化したものの前方一致になる
+// 要素の途中までの前方一致にはならない("ab"を符号化したものは、"abc"を符号化したものの前方一致ではない)
 pub fn encode(elems: impl Iterator<Item = impl AsRef<[u8]>>, bytes: &mut Vec<u8>) {
     elems.for_each(|elem| {
         let elem_bytes = elem.as_ref();
@@ -20,6 +33,199 @@ pub fn decode(bytes: &[u8], elems: &mut Vec<Vec<u8>>) {
     }
 }
 
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum DecodeError {
+    // offsetバイト目から始まる要素が、終わりの印の前で切れている
+    #[error("truncated element at offset {offset}")]
+    Truncated { offset: usize },
+    // offsetバイト目に、encode_optionalの書かない印がある
+    #[error("unknown marker {marker:#04x} at offset {offset}")]
+    UnknownMarker { offset: usize, marker: u8 },
+    // offsetバイト目から始まる値が、encode_valuesで書いたものでない
+    #[error("malformed value at offset {offset}")]
+    MalformedValue { offset: usize },
+    // decode_valuesで、column番目(0から数える)の値が列の型と違う
+    #[error("column {column}: expected {expected:?}, got {actual:?}")]
+    TypeMismatch {
+        column: usize,
+        expected: ColumnType,
+        actual: ColumnType,
+    },
+    #[error("expected {expected} values, got {actual}")]
+    ValueCount { expected: usize, actual: usize },
+}
+
+// 先頭の要素を符号化したバイト数
+fn elem_len(bytes: &[u8], offset: usize) -> Result<usize, DecodeError> {
+    memcmpable::encoded_len(&bytes[offset..]).ok_or(DecodeError::Truncated { offset })
+}
+
+// n番目(0から数える)の要素だけをbufに復号する。前の要素は終わりの印を探して読み飛ばし、写さない
+// 要素がn個以下ならfalseを返し、bufは空にする
+pub fn decode_nth(bytes: &[u8], n: usize, buf: &mut Vec<u8>) -> Result<bool, DecodeError> {
+    buf.clear();
+    let mut offset = 0;
+    for _ in 0..n {
+        if offset == bytes.len() {
+            return Ok(false);
+        }
+        offset += elem_len(bytes, offset)?;
+    }
+    if offset == bytes.len() {
+        return Ok(false);
+    }
+    let len = elem_len(bytes, offset)?;
+    memcmpable::decode(&mut &bytes[offset..offset + len], buf);
+    Ok(true)
+}
+
+// 要素を順に復号する。8バイトまでの要素は区切りの印を挟まないので、写さずに入力の一部を返す
+// それより長い要素は印を取り除いて写す。途中で切れた要素があれば、エラーを返して止まる
+pub fn fields(bytes: &[u8]) -> Fields<'_> {
+    Fields { bytes, offset: 0 }
+}
+
+// 先に要素を数えて、外側のVecを一度だけ確保する
+pub fn decode_ref(bytes: &[u8]) -> Result<Vec<Cow<'_, [u8]>>, DecodeError> {
+    let mut elems = Vec::with_capacity(field_count(bytes)?);
+    for field in fields(bytes) {
+        elems.push(field?);
+    }
+    Ok(elems)
+}
+
+pub struct Fields<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<Cow<'a, [u8]>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset == self.bytes.len() {
+            return None;
+        }
+        let len = match elem_len(self.bytes, self.offset) {
+            Ok(len) => len,
+            Err(err) => {
+                self.offset = self.bytes.len();
+                return Some(Err(err));
+            }
+        };
+        let encoded = &self.bytes[self.offset..self.offset + len];
+        self.offset += len;
+        if len == memcmpable::encoded_size(1) {
+            let data_len = encoded[len - 1] as usize;
+            return Some(Ok(Cow::Borrowed(&encoded[..data_len])));
+        }
+        let mut elem = vec![];
+        memcmpable::decode(&mut &encoded[..], &mut elem);
+        Some(Ok(Cow::Owned(elem)))
+    }
+}
+
+pub fn field_count(bytes: &[u8]) -> Result<usize, DecodeError> {
+    let mut offset = 0;
+    let mut count = 0;
+    while offset < bytes.len() {
+        offset += elem_len(bytes, offset)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// 終わりの印まである要素だけを復号し、残りのバイト列を返す
+// 木の枝に置いた区切りのキーのように、要素の途中で切れたものも読める
+pub fn decode_prefix<'a>(bytes: &'a [u8], elems: &mut Vec<Vec<u8>>) -> &'a [u8] {
+    let mut rest = bytes;
+    while let Some(len) = memcmpable::encoded_len(rest) {
+        decode(&rest[..len], elems);
+        rest = &rest[len..];
+    }
+    rest
+}
+
+// encode_optionalで要素の前に置く印。NULLの印が小さいので、NULLはどの値よりも前に並ぶ(NULLS FIRST)
+const NULL_MARKER: u8 = 0;
+const VALUE_MARKER: u8 = 1;
+
+// NULLを書ける版の符号化。要素ごとに印を置くので、encodeとは違うバイト列になる
+// 空のバイト列とNULLは区別され、encodeと同じく先頭のいくつかの要素を符号化したものは前方一致になる
+// encodeで書いたバイト列はdecodeで、encode_optionalで書いたバイト列はdecode_optionalで読む
+pub fn encode_optional<T: AsRef<[u8]>>(
+    elems: impl Iterator<Item = Option<T>>,
+    bytes: &mut Vec<u8>,
+) {
+    elems.for_each(|elem| match elem {
+        Some(elem) => {
+            let elem_bytes = elem.as_ref();
+            bytes.reserve(1 + memcmpable::encoded_size(elem_bytes.len()));
+            bytes.push(VALUE_MARKER);
+            memcmpable::encode(elem_bytes, bytes);
+        }
+        None => bytes.push(NULL_MARKER),
+    });
+}
+
+// 知らない印や途中で切れた要素があれば、そこまでの要素をelemsに入れてエラーを返す
+pub fn decode_optional(
+    bytes: &[u8],
+    elems: &mut Vec<Option<Vec<u8>>>,
+) -> Result<(), DecodeError> {
+    let mut offset = 0;
+    while let Some(&marker) = bytes.get(offset) {
+        match marker {
+            NULL_MARKER => {
+                offset += 1;
+                elems.push(None);
+            }
+            VALUE_MARKER => {
+                let len = elem_len(bytes, offset + 1)?;
+                let mut elem = vec![];
+                memcmpable::decode(&mut &bytes[offset + 1..offset + 1 + len], &mut elem);
+                offset += 1 + len;
+                elems.push(Some(elem));
+            }
+            marker => return Err(DecodeError::UnknownMarker { offset, marker }),
+        }
+    }
+    Ok(())
+}
+
+// 符号の印を反転した8バイトのビッグエンディアンで書き、負の数が正の数より前に並ぶようにする
+// 長さが決まっているので、ほかの値と続けて書いても、encodeの1つの要素にしても順が保たれる
+pub fn encode_i64_ordered(v: i64, bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(&((v as u64) ^ (1 << 63)).to_be_bytes());
+}
+
+// 先頭の8バイトを読み、残りのバイト列に進める。8バイトに足りなければ、進めずにエラーを返す
+pub fn decode_i64_ordered(bytes: &mut &[u8]) -> Result<i64, DecodeError> {
+    Ok((u64::from_be_bytes(take_8(bytes)?) ^ (1 << 63)) as i64)
+}
+
+// f64::total_cmpの順に並ぶように、正の数は符号ビットだけを、負の数はすべてのビットを反転する
+// -0.0は0.0より前に並ぶ。NaNはビット列のまま書き、正のNaNはinfより後ろ、負のNaNは-infより前に並ぶ
+pub fn encode_f64_ordered(v: f64, bytes: &mut Vec<u8>) {
+    let bits = v.to_bits();
+    let bits = if bits >> 63 == 1 { !bits } else { bits ^ (1 << 63) };
+    bytes.extend_from_slice(&bits.to_be_bytes());
+}
+
+// NaNも符号化したときと同じビット列に戻す
+pub fn decode_f64_ordered(bytes: &mut &[u8]) -> Result<f64, DecodeError> {
+    let bits = u64::from_be_bytes(take_8(bytes)?);
+    let bits = if bits >> 63 == 1 { bits ^ (1 << 63) } else { !bits };
+    Ok(f64::from_bits(bits))
+}
+
+fn take_8(bytes: &mut &[u8]) -> Result<[u8; 8], DecodeError> {
+    let head = bytes.get(..8).ok_or(DecodeError::Truncated { offset: 0 })?;
+    let head = head.try_into().unwrap();
+    *bytes = &bytes[8..];
+    Ok(head)
+}
+
 pub struct Pretty<'a, T>(pub &'a [T]);
 
 impl<'a, T: AsRef<[u8]>> Debug for Pretty<'a, T> {
@@ -27,7 +233,7 @@ impl<'a, T: AsRef<[u8]>> Debug for Pretty<'a, T> {
         let mut d = f.debug_tuple("Tuple");
         for elem in self.0 {
             let bytes = elem.as_ref();
-            match std::str::from_utf8(&bytes) {
+            match std::str::from_utf8(bytes) {
                 Ok(s) => {
                     d.field(&format_args!("{:?} {:02x?}", s, bytes));
                 }
@@ -38,4 +244,247 @@ impl<'a, T: AsRef<[u8]>> Debug for Pretty<'a, T> {
         }
         d.finish()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ヒープを確保しないことは、tests/tuple_alloc.rsで確かめる
+    #[test]
+    fn test_fields() {
+        // 8バイトまでの要素は、写さずに入力の一部を返す
+        let elems: [&[u8]; 5] = [b"", b"z", b"Alice", b"Smith", b"12345678"];
+        let mut bytes = vec![];
+        encode(elems.iter(), &mut bytes);
+        for (field, elem) in fields(&bytes).zip(elems.iter()) {
+            let field = field.unwrap();
+            assert!(matches!(field, Cow::Borrowed(_)));
+            assert_eq!(elem, &&field[..]);
+        }
+        assert_eq!(elems.len(), fields(&bytes).count());
+        let decoded = decode_ref(&bytes).unwrap();
+        assert_eq!(&elems[..], &decoded.iter().map(|elem| &elem[..]).collect::<Vec<_>>()[..]);
+
+        // 長い要素は区切りの印を取り除いて写す
+        let elems: [&[u8]; 5] = [b"123456789", b"", b"helloworld!memcmpable", b"\x09", &[9; 16]];
+        let mut bytes = vec![];
+        encode(elems.iter(), &mut bytes);
+        let decoded = decode_ref(&bytes).unwrap();
+        let mut expected = vec![];
+        decode(&bytes, &mut expected);
+        assert_eq!(expected, decoded.iter().map(|elem| elem.to_vec()).collect::<Vec<_>>());
+        let borrowed: Vec<_> =
+            decoded.iter().map(|elem| matches!(elem, Cow::Borrowed(_))).collect();
+        assert_eq!(vec![false, true, false, true, false], borrowed);
+
+        // 途中で切れていれば、そこまでの要素を返してからエラーを返す
+        let truncated = &bytes[..bytes.len() - 1];
+        let results: Vec<_> = fields(truncated).collect();
+        assert_eq!(5, results.len());
+        assert!(results[..4].iter().all(Result::is_ok));
+        assert!(matches!(results[4], Err(DecodeError::Truncated { .. })));
+        assert!(decode_ref(truncated).is_err());
+        assert_eq!(0, fields(&[]).count());
+    }
+
+    fn optional(elems: &[Option<&[u8]>]) -> Vec<u8> {
+        let mut bytes = vec![];
+        encode_optional(elems.iter().copied(), &mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn test_optional() {
+        // NULLと空のバイト列は別のバイト列になり、NULLが前に並ぶ
+        assert_ne!(optional(&[None]), optional(&[Some(b"")]));
+        let mut sorted = vec![
+            optional(&[Some(b"a")]),
+            optional(&[None]),
+            optional(&[Some(b"")]),
+            optional(&[Some(b"\x00")]),
+            optional(&[None, Some(b"z")]),
+            optional(&[Some(b""), None]),
+        ];
+        sorted.sort();
+        let expected = vec![
+            optional(&[None]),
+            optional(&[None, Some(b"z")]),
+            optional(&[Some(b"")]),
+            optional(&[Some(b""), None]),
+            optional(&[Some(b"\x00")]),
+            optional(&[Some(b"a")]),
+        ];
+        assert_eq!(expected, sorted);
+
+        let record: Vec<Option<&[u8]>> =
+            vec![None, Some(b""), Some(b"helloworld!memcmpable"), None, Some(b"\x00\x00")];
+        let mut elems = vec![];
+        decode_optional(&optional(&record), &mut elems).unwrap();
+        let expected: Vec<_> = record.iter().map(|elem| elem.map(<[u8]>::to_vec)).collect();
+        assert_eq!(expected, elems);
+
+        // 知らない印や途中で切れた要素は、パニックせずにエラーになる
+        let mut bytes = optional(&[None, Some(b"ab")]);
+        let mut elems = vec![];
+        let err = decode_optional(&bytes[..bytes.len() - 1], &mut elems);
+        assert_eq!(Err(DecodeError::Truncated { offset: 2 }), err);
+        assert_eq!(vec![None], elems);
+        assert!(decode_optional(&bytes[..2], &mut vec![]).is_err());
+        bytes[1] = 2;
+        let err = decode_optional(&bytes, &mut vec![]);
+        assert_eq!(Err(DecodeError::UnknownMarker { offset: 1, marker: 2 }), err);
+
+        // NULLのない要素は、印のほかはencodeと同じバイト列になる
+        let mut plain = vec![];
+        encode([b"abc"].iter(), &mut plain);
+        assert_eq!(&plain[..], &optional(&[Some(b"abc")])[1..]);
+    }
+
+    #[test]
+    fn test_decode_nth() {
+        let elems: [&[u8]; 4] = [b"", b"Alice", b"", b"helloworld!memcmpable"];
+        let mut bytes = vec![];
+        encode(elems.iter(), &mut bytes);
+        assert_eq!(Ok(4), field_count(&bytes));
+        let mut buf = b"garbage".to_vec();
+        for (n, elem) in elems.iter().enumerate() {
+            assert_eq!(Ok(true), decode_nth(&bytes, n, &mut buf));
+            assert_eq!(elem, &&buf[..]);
+        }
+        // 範囲の外の要素は読めず、bufは空になる
+        assert_eq!(Ok(false), decode_nth(&bytes, 4, &mut buf));
+        assert!(buf.is_empty());
+        assert_eq!(Ok(false), decode_nth(&bytes, usize::MAX, &mut buf));
+        assert_eq!(Ok(0), field_count(&[]));
+        assert_eq!(Ok(false), decode_nth(&[], 0, &mut buf));
+
+        // 途中で切れた要素は、読み飛ばすときも読むときもエラーにする
+        let truncated = &bytes[..bytes.len() - 1];
+        assert_eq!(Ok(true), decode_nth(truncated, 2, &mut buf));
+        let err = DecodeError::Truncated { offset: 27 };
+        assert_eq!(Err(err.clone()), decode_nth(truncated, 3, &mut buf));
+        assert_eq!(Err(err), field_count(truncated));
+        assert_eq!(Err(DecodeError::Truncated { offset: 0 }), decode_nth(&bytes[..3], 1, &mut buf));
+
+        // 無作為なレコードで、すべてを復号した結果と比べる
+        let mut x = 0x9E37_79B9_7F4A_7C15u64;
+        let mut random = || {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        for _ in 0..200 {
+            let record: Vec<Vec<u8>> = (0..random() % 6)
+                .map(|_| (0..random() % 20).map(|_| (random() % 3) as u8 * 9).collect())
+                .collect();
+            let mut bytes = vec![];
+            encode(record.iter(), &mut bytes);
+            let mut decoded = vec![];
+            decode(&bytes, &mut decoded);
+            assert_eq!(record, decoded);
+            assert_eq!(Ok(record.len()), field_count(&bytes));
+            for (n, elem) in record.iter().enumerate() {
+                assert_eq!(Ok(true), decode_nth(&bytes, n, &mut buf));
+                assert_eq!(elem, &buf);
+            }
+            assert_eq!(Ok(false), decode_nth(&bytes, record.len(), &mut buf));
+        }
+    }
+
+    fn i64_bytes(v: i64) -> Vec<u8> {
+        let mut bytes = vec![];
+        encode_i64_ordered(v, &mut bytes);
+        bytes
+    }
+
+    fn f64_bytes(v: f64) -> Vec<u8> {
+        let mut bytes = vec![];
+        encode_f64_ordered(v, &mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn test_i64_ordered() {
+        let ints = [i64::MIN, i64::MIN + 1, -256, -1, 0, 1, 255, 256, i64::MAX - 1, i64::MAX];
+        for pair in ints.windows(2) {
+            assert!(i64_bytes(pair[0]) < i64_bytes(pair[1]), "{:?}", pair);
+        }
+        assert_eq!(vec![0; 8], i64_bytes(i64::MIN));
+        assert_eq!(vec![0x80, 0, 0, 0, 0, 0, 0, 0], i64_bytes(0));
+        for &v in ints.iter() {
+            let bytes = i64_bytes(v);
+            let mut rest = &bytes[..];
+            assert_eq!(Ok(v), decode_i64_ordered(&mut rest));
+            assert!(rest.is_empty());
+        }
+
+        // 続けて書いても、前の数から順に比べる。encodeの要素にしても順は変わらない
+        let pair = |a: i64, b: i64| {
+            let mut bytes = i64_bytes(a);
+            encode_i64_ordered(b, &mut bytes);
+            bytes
+        };
+        assert!(pair(-1, i64::MAX) < pair(0, i64::MIN));
+        assert!(pair(0, -1) < pair(0, 1));
+        let encoded = |a: i64, b: &[u8]| {
+            let mut bytes = vec![];
+            encode([&i64_bytes(a)[..], b].iter(), &mut bytes);
+            bytes
+        };
+        assert!(encoded(-1, b"z") < encoded(1, b"a"));
+        let mut elems = vec![];
+        decode(&encoded(-5, b"x"), &mut elems);
+        assert_eq!(Ok(-5), decode_i64_ordered(&mut &elems[0][..]));
+        let bytes = pair(i64::MIN, -7);
+        let mut rest = &bytes[..];
+        assert_eq!(Ok(i64::MIN), decode_i64_ordered(&mut rest));
+        assert_eq!(Ok(-7), decode_i64_ordered(&mut rest));
+
+        // 8バイトに足りなければ、読み進めずにエラーを返す
+        let mut rest = &bytes[..7];
+        assert_eq!(Err(DecodeError::Truncated { offset: 0 }), decode_i64_ordered(&mut rest));
+        assert_eq!(7, rest.len());
+        assert!(decode_i64_ordered(&mut &[][..]).is_err());
+    }
+
+    #[test]
+    fn test_f64_ordered() {
+        let floats = [
+            -f64::NAN,
+            f64::NEG_INFINITY,
+            f64::MIN,
+            -1.0,
+            -f64::MIN_POSITIVE,
+            -f64::from_bits(1),
+            -0.0,
+            0.0,
+            f64::from_bits(1),
+            f64::MIN_POSITIVE,
+            1.0,
+            f64::MAX,
+            f64::INFINITY,
+            f64::NAN,
+        ];
+        for pair in floats.windows(2) {
+            assert!(f64_bytes(pair[0]) < f64_bytes(pair[1]), "{:?}", pair);
+            assert_eq!(std::cmp::Ordering::Less, pair[0].total_cmp(&pair[1]), "{:?}", pair);
+        }
+        // -0.0と0.0は別のバイト列になり、NaNはペイロードも含めて戻る
+        assert_ne!(f64_bytes(-0.0), f64_bytes(0.0));
+        let payload_nan = f64::from_bits(0x7FF0_0000_0000_0001);
+        assert!(f64_bytes(f64::INFINITY) < f64_bytes(payload_nan));
+        assert!(f64_bytes(payload_nan) < f64_bytes(f64::NAN));
+        for &v in floats.iter().chain(&[payload_nan]) {
+            let bytes = f64_bytes(v);
+            let mut rest = &bytes[..];
+            assert_eq!(v.to_bits(), decode_f64_ordered(&mut rest).unwrap().to_bits());
+            assert!(rest.is_empty());
+        }
+        let bytes = f64_bytes(1.0);
+        let mut rest = &bytes[1..];
+        assert_eq!(Err(DecodeError::Truncated { offset: 0 }), decode_f64_ordered(&mut rest));
+        assert_eq!(7, rest.len());
+    }
+}
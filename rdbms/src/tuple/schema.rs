@@ -0,0 +1,203 @@
+use thiserror::Error;
+
+use super::value::decode_value;
+use super::{encode_values, ColumnType, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnDef {
+    pub name: String,
+    pub ty: ColumnType,
+    // falseなら、この列にNullを入れられない
+    pub nullable: bool,
+}
+
+// テーブルの列の並び。レコードを符号化する前と復号したあとに、列の数と型を確かめる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schema {
+    pub columns: Vec<ColumnDef>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SchemaError {
+    #[error("expected {expected} columns, got {actual}")]
+    Arity { expected: usize, actual: usize },
+    #[error("column {column}: expected {expected:?}, got {actual:?}")]
+    TypeMismatch {
+        column: String,
+        expected: ColumnType,
+        actual: ColumnType,
+    },
+    #[error("column {column}: null is not allowed")]
+    NullNotAllowed { column: String },
+    #[error("{num_key_elems} key columns in a schema of {columns} columns")]
+    KeyColumns { num_key_elems: usize, columns: usize },
+    // 復号しようとしたバイト列が、encode_valuesで書いたものでなかった
+    #[error("malformed record after {decoded} values")]
+    Malformed { decoded: usize },
+}
+
+impl Schema {
+    pub fn new(columns: Vec<ColumnDef>) -> Self {
+        Self { columns }
+    }
+
+    pub fn check(&self, record: &[Value]) -> Result<(), SchemaError> {
+        if record.len() != self.columns.len() {
+            return Err(SchemaError::Arity {
+                expected: self.columns.len(),
+                actual: record.len(),
+            });
+        }
+        for (column, value) in self.columns.iter().zip(record) {
+            match value.column_type() {
+                None if !column.nullable => {
+                    return Err(SchemaError::NullNotAllowed {
+                        column: column.name.clone(),
+                    })
+                }
+                Some(actual) if actual != column.ty => {
+                    return Err(SchemaError::TypeMismatch {
+                        column: column.name.clone(),
+                        expected: column.ty,
+                        actual,
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // 確かめてから、先頭のnum_key_elems列をkeyに、残りをvalueにencode_valuesで書く
+    // 確かめられなければ、keyもvalueも書き換えない
+    pub fn encode_record(
+        &self,
+        record: &[Value],
+        key: &mut Vec<u8>,
+        value: &mut Vec<u8>,
+        num_key_elems: usize,
+    ) -> Result<(), SchemaError> {
+        if num_key_elems > self.columns.len() {
+            return Err(SchemaError::KeyColumns {
+                num_key_elems,
+                columns: self.columns.len(),
+            });
+        }
+        self.check(record)?;
+        encode_values(&record[..num_key_elems], key);
+        encode_values(&record[num_key_elems..], value);
+        Ok(())
+    }
+
+    // keyとvalueの値を続けて読み、書いたときと同じく確かめる
+    pub fn decode_record(&self, key: &[u8], value: &[u8]) -> Result<Vec<Value>, SchemaError> {
+        let mut record = vec![];
+        for bytes in [key, value].iter() {
+            let mut rest = *bytes;
+            while !rest.is_empty() {
+                let decoded = record.len();
+                record.push(decode_value(&mut rest).ok_or(SchemaError::Malformed { decoded })?);
+            }
+        }
+        self.check(&record)?;
+        Ok(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str, ty: ColumnType, nullable: bool) -> ColumnDef {
+        ColumnDef {
+            name: name.to_string(),
+            ty,
+            nullable,
+        }
+    }
+
+    fn schema() -> Schema {
+        Schema::new(vec![
+            column("id", ColumnType::Int64, false),
+            column("name", ColumnType::Text, false),
+            column("active", ColumnType::Bool, true),
+            column("score", ColumnType::Float64, true),
+            column("blob", ColumnType::Bytes, true),
+        ])
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = schema();
+        let record = vec![
+            Value::Int64(-42),
+            Value::Text("Alice".to_string()),
+            Value::Bool(true),
+            Value::Float64(f64::NAN),
+            Value::Bytes(vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]),
+        ];
+        let (mut key, mut value) = (vec![], vec![]);
+        schema.encode_record(&record, &mut key, &mut value, 2).unwrap();
+        assert_eq!(record, schema.decode_record(&key, &value).unwrap());
+        let mut expected_key = vec![];
+        encode_values(&record[..2], &mut expected_key);
+        assert_eq!(expected_key, key);
+
+        let mut nulls = vec![Value::Int64(0), Value::Text(String::new())];
+        nulls.extend(vec![Value::Null; 3]);
+        let (mut key, mut value) = (vec![], vec![]);
+        schema.encode_record(&nulls, &mut key, &mut value, 1).unwrap();
+        assert_eq!(nulls, schema.decode_record(&key, &value).unwrap());
+
+        // 確かめられなければ、何も書かない
+        let (mut key, mut value) = (vec![], vec![]);
+        assert_eq!(
+            Err(SchemaError::Arity {
+                expected: 5,
+                actual: 3
+            }),
+            schema.encode_record(&record[..3], &mut key, &mut value, 1)
+        );
+        let mut mismatched = record.clone();
+        mismatched[3] = Value::Int64(1);
+        assert_eq!(
+            Err(SchemaError::TypeMismatch {
+                column: "score".to_string(),
+                expected: ColumnType::Float64,
+                actual: ColumnType::Int64
+            }),
+            schema.encode_record(&mismatched, &mut key, &mut value, 1)
+        );
+        let mut null_name = record.clone();
+        null_name[1] = Value::Null;
+        assert_eq!(
+            Err(SchemaError::NullNotAllowed {
+                column: "name".to_string()
+            }),
+            schema.encode_record(&null_name, &mut key, &mut value, 1)
+        );
+        assert!(matches!(
+            schema.encode_record(&record, &mut key, &mut value, 6),
+            Err(SchemaError::KeyColumns { .. })
+        ));
+        assert!(key.is_empty() && value.is_empty());
+
+        // 読むときも、列の数と型を確かめる
+        let (mut key, mut value) = (vec![], vec![]);
+        encode_values(&record[..2], &mut key);
+        encode_values(&record[2..4], &mut value);
+        assert!(matches!(schema.decode_record(&key, &value), Err(SchemaError::Arity { .. })));
+        value.clear();
+        encode_values(&mismatched[2..], &mut value);
+        assert!(matches!(
+            schema.decode_record(&key, &value),
+            Err(SchemaError::TypeMismatch { .. })
+        ));
+        value.clear();
+        encode_values(&record[2..], &mut value);
+        assert_eq!(
+            Err(SchemaError::Malformed { decoded: 4 }),
+            schema.decode_record(&key, &value[..value.len() - 1])
+        );
+    }
+}
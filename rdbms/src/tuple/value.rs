@@ -0,0 +1,391 @@
+use std::cmp::Ordering;
+
+use super::{
+    decode_f64_ordered, decode_i64_ordered, encode_f64_ordered, encode_i64_ordered, DecodeError,
+};
+use crate::memcmpable;
+
+// 型のある列の値
+// 符号化したバイト列をmemcmpで比べた順と、Ordの順が同じになる
+// 型の違う値は、Null、Bool、Int64、Float64、Bytes、Textの順に並ぶ
+#[derive(Debug, Clone)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int64(i64),
+    // NaNも含めてf64::total_cmpの順に並べる。-NaNは-infより前、NaNはinfより後ろ
+    Float64(f64),
+    Bytes(Vec<u8>),
+    Text(String),
+}
+
+// 列の型。どの列にもNullを入れられる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Bool,
+    Int64,
+    Float64,
+    Bytes,
+    Text,
+}
+
+// 型ごとに先頭に置く印。型の違う値の順もこれで決まる
+const TAG_NULL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_INT64: u8 = 2;
+const TAG_FLOAT64: u8 = 3;
+const TAG_BYTES: u8 = 4;
+const TAG_TEXT: u8 = 5;
+
+impl Value {
+    fn tag(&self) -> u8 {
+        match self {
+            Value::Null => TAG_NULL,
+            Value::Bool(_) => TAG_BOOL,
+            Value::Int64(_) => TAG_INT64,
+            Value::Float64(_) => TAG_FLOAT64,
+            Value::Bytes(_) => TAG_BYTES,
+            Value::Text(_) => TAG_TEXT,
+        }
+    }
+
+    pub fn column_type(&self) -> Option<ColumnType> {
+        match self {
+            Value::Null => None,
+            Value::Bool(_) => Some(ColumnType::Bool),
+            Value::Int64(_) => Some(ColumnType::Int64),
+            Value::Float64(_) => Some(ColumnType::Float64),
+            Value::Bytes(_) => Some(ColumnType::Bytes),
+            Value::Text(_) => Some(ColumnType::Text),
+        }
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+            (Value::Int64(a), Value::Int64(b)) => a.cmp(b),
+            (Value::Float64(a), Value::Float64(b)) => a.total_cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.cmp(b),
+            (Value::Text(a), Value::Text(b)) => a.cmp(b),
+            (a, b) => a.tag().cmp(&b.tag()),
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// 浮動小数点数もビット列が同じときだけ等しい。NaNどうしも等しく、0.0と-0.0は等しくない
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+// 値ごとに型の印と中身を続けて書く。文字列とバイト列は終わりの印まで符号化するので、
+// encodeと同じく、先頭のいくつかの値を符号化したものは、それらの値で始まるタプルの前方一致になる
+pub fn encode_values(values: &[Value], bytes: &mut Vec<u8>) {
+    for value in values {
+        bytes.push(value.tag());
+        match value {
+            Value::Null => {}
+            Value::Bool(v) => bytes.push(*v as u8),
+            Value::Int64(v) => encode_i64_ordered(*v, bytes),
+            Value::Float64(v) => encode_f64_ordered(*v, bytes),
+            Value::Bytes(v) => memcmpable::encode(v, bytes),
+            Value::Text(v) => memcmpable::encode(v.as_bytes(), bytes),
+        }
+    }
+}
+
+// 列の型のとおりに値を読む。読めない値や型の合わない値があるか、列の数と値の数が違えばエラーを返す
+pub fn decode_values(bytes: &[u8], types: &[ColumnType]) -> Result<Vec<Value>, DecodeError> {
+    let mut rest = bytes;
+    let mut values = Vec::with_capacity(types.len());
+    while !rest.is_empty() {
+        let offset = bytes.len() - rest.len();
+        let value = decode_value(&mut rest).ok_or(DecodeError::MalformedValue { offset })?;
+        if let (Some(&expected), Some(actual)) = (types.get(values.len()), value.column_type()) {
+            if expected != actual {
+                return Err(DecodeError::TypeMismatch {
+                    column: values.len(),
+                    expected,
+                    actual,
+                });
+            }
+        }
+        values.push(value);
+    }
+    if values.len() != types.len() {
+        return Err(DecodeError::ValueCount {
+            expected: types.len(),
+            actual: values.len(),
+        });
+    }
+    Ok(values)
+}
+
+// n番目(0から数える)の値だけを読む。decode_nthと同じく、前の値は長さだけを調べて読み飛ばす
+// 値がn個以下ならNoneを返す
+pub fn decode_nth_value(bytes: &[u8], n: usize) -> Result<Option<Value>, DecodeError> {
+    let mut offset = 0;
+    for _ in 0..n {
+        if offset == bytes.len() {
+            return Ok(None);
+        }
+        offset += value_len(&bytes[offset..]).ok_or(DecodeError::MalformedValue { offset })?;
+    }
+    if offset == bytes.len() {
+        return Ok(None);
+    }
+    let value = decode_value(&mut &bytes[offset..]);
+    value.map(Some).ok_or(DecodeError::MalformedValue { offset })
+}
+
+// 先頭の値を符号化したバイト数。途中で切れていたり、知らない印ならNoneを返す
+fn value_len(bytes: &[u8]) -> Option<usize> {
+    let len = match *bytes.first()? {
+        TAG_NULL => 0,
+        TAG_BOOL => 1,
+        TAG_INT64 | TAG_FLOAT64 => 8,
+        TAG_BYTES | TAG_TEXT => memcmpable::encoded_len(&bytes[1..])?,
+        _ => return None,
+    };
+    if bytes.len() > len {
+        Some(1 + len)
+    } else {
+        None
+    }
+}
+
+// 先頭の型の印のとおりに値を1つ読む。途中で切れていたり、知らない印や文字列でないバイト列ならNoneを返す
+pub(super) fn decode_value(rest: &mut &[u8]) -> Option<Value> {
+    let (&tag, tail) = rest.split_first()?;
+    *rest = tail;
+    let value = match tag {
+        TAG_NULL => Value::Null,
+        TAG_BOOL => {
+            let (&v, tail) = rest.split_first()?;
+            *rest = tail;
+            Value::Bool(v != 0)
+        }
+        TAG_INT64 => Value::Int64(decode_i64_ordered(rest).ok()?),
+        TAG_FLOAT64 => Value::Float64(decode_f64_ordered(rest).ok()?),
+        TAG_BYTES | TAG_TEXT => {
+            let len = memcmpable::encoded_len(rest)?;
+            let mut v = vec![];
+            memcmpable::decode(&mut &rest[..len], &mut v);
+            *rest = &rest[len..];
+            if tag == TAG_BYTES {
+                Value::Bytes(v)
+            } else {
+                Value::Text(String::from_utf8(v).ok()?)
+            }
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(value: &Value) -> Vec<u8> {
+        let mut bytes = vec![];
+        encode_values(std::slice::from_ref(value), &mut bytes);
+        bytes
+    }
+
+    // 符号化したバイト列の順に並べ直すと、値の順になることを確かめる
+    fn assert_memcmp_order(mut values: Vec<Value>) {
+        let mut x = 0x2545_F491_4F6C_DD1Du64;
+        for i in (1..values.len()).rev() {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            values.swap(i, (x % (i as u64 + 1)) as usize);
+        }
+        let mut by_bytes = values.clone();
+        by_bytes.sort_by_key(encoded);
+        values.sort();
+        assert_eq!(values, by_bytes);
+    }
+
+    #[test]
+    fn test_order() {
+        let ints = [i64::MIN, i64::MIN + 1, -1_000_000, -256, -255, -1, 0, 1, 255, 256, i64::MAX];
+        assert_memcmp_order(ints.iter().map(|&v| Value::Int64(v)).collect());
+
+        let floats = [
+            -f64::NAN,
+            f64::NEG_INFINITY,
+            f64::MIN,
+            -1.5,
+            -1.0,
+            -f64::MIN_POSITIVE,
+            -1e-310,
+            -0.0,
+            0.0,
+            1e-310,
+            f64::MIN_POSITIVE,
+            1.0,
+            1.5,
+            f64::MAX,
+            f64::INFINITY,
+            f64::NAN,
+        ];
+        let values: Vec<_> = floats.iter().map(|&v| Value::Float64(v)).collect();
+        for pair in values.windows(2) {
+            assert!(pair[0] < pair[1], "{:?}", pair);
+            assert!(encoded(&pair[0]) < encoded(&pair[1]), "{:?}", pair);
+        }
+        assert_memcmp_order(values);
+
+        let texts = ["", "a", "ab", "abcdefgh", "abcdefghi", "b", "\u{3042}"];
+        assert_memcmp_order(texts.iter().map(|v| Value::Text(v.to_string())).collect());
+        let bytes = [vec![], vec![0], vec![0, 0], vec![0; 9], vec![1], vec![0xFF; 20]];
+        assert_memcmp_order(bytes.iter().map(|v| Value::Bytes(v.clone())).collect());
+
+        // 型の違う値やNullは、型の順に並ぶ
+        assert_memcmp_order(vec![
+            Value::Null,
+            Value::Bool(false),
+            Value::Bool(true),
+            Value::Int64(-1),
+            Value::Float64(-1.0),
+            Value::Bytes(vec![]),
+            Value::Text(String::new()),
+        ]);
+
+        // 前の値が等しければ、後ろの値で比べる
+        let tuple = |a: i64, b: &str| {
+            let mut bytes = vec![];
+            encode_values(&[Value::Int64(a), Value::Text(b.to_string())], &mut bytes);
+            bytes
+        };
+        assert!(tuple(-1, "zzzzzzzzzz") < tuple(0, ""));
+        assert!(tuple(0, "abcdefgh") < tuple(0, "abcdefgha"));
+        assert!(tuple(0, "b") < tuple(1, "a"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let values = vec![
+            Value::Null,
+            Value::Bool(false),
+            Value::Bool(true),
+            Value::Int64(i64::MIN),
+            Value::Int64(-1),
+            Value::Int64(i64::MAX),
+            Value::Float64(-0.0),
+            Value::Float64(f64::NAN),
+            Value::Float64(-f64::NAN),
+            Value::Float64(f64::NEG_INFINITY),
+            Value::Float64(3.25),
+            Value::Bytes(vec![]),
+            Value::Bytes(vec![0, 9, 0xFF, 1, 2, 3, 4, 5, 6, 7]),
+            Value::Text(String::new()),
+            Value::Text("hello, \u{4e16}\u{754c}".to_string()),
+        ];
+        let types: Vec<_> = values
+            .iter()
+            .map(|value| value.column_type().unwrap_or(ColumnType::Text))
+            .collect();
+        let mut bytes = vec![];
+        encode_values(&values, &mut bytes);
+        assert_eq!(Ok(values), decode_values(&bytes, &types));
+        if let Value::Float64(v) = decode_values(&bytes, &types).unwrap()[6] {
+            assert!(v == 0.0 && v.is_sign_negative());
+        }
+        assert_ne!(Value::Float64(0.0), Value::Float64(-0.0));
+
+        // どの型の列にもNullを入れられる
+        let nulls = vec![Value::Null; 5];
+        let types = [
+            ColumnType::Bool,
+            ColumnType::Int64,
+            ColumnType::Float64,
+            ColumnType::Bytes,
+            ColumnType::Text,
+        ];
+        let mut bytes = vec![];
+        encode_values(&nulls, &mut bytes);
+        assert_eq!(Ok(nulls), decode_values(&bytes, &types));
+    }
+
+    #[test]
+    fn test_decode_nth_value() {
+        let values = vec![
+            Value::Null,
+            Value::Bool(true),
+            Value::Int64(-7),
+            Value::Float64(0.5),
+            Value::Bytes(vec![0; 20]),
+            Value::Text("Smith".to_string()),
+            Value::Text(String::new()),
+        ];
+        let mut bytes = vec![];
+        encode_values(&values, &mut bytes);
+        for (n, value) in values.iter().enumerate() {
+            assert_eq!(Ok(Some(value.clone())), decode_nth_value(&bytes, n));
+        }
+        // 範囲の外の値はNoneになる
+        assert_eq!(Ok(None), decode_nth_value(&bytes, values.len()));
+        assert_eq!(Ok(None), decode_nth_value(&bytes, usize::MAX));
+        assert_eq!(Ok(None), decode_nth_value(&[], 0));
+
+        // 読み飛ばす値も読む値も、途中で切れていればエラーになる
+        let truncated = &bytes[..bytes.len() - 1];
+        assert_eq!(Ok(Some(values[5].clone())), decode_nth_value(truncated, 5));
+        let err = |offset| Err(DecodeError::MalformedValue { offset });
+        assert_eq!(err(59), decode_nth_value(truncated, 6));
+        assert_eq!(err(3), decode_nth_value(&bytes[..8], 3));
+        assert_eq!(err(0), decode_nth_value(&[0xFF, 0], 1));
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let values = [Value::Int64(-1), Value::Text("Alice".to_string())];
+        let types = [ColumnType::Int64, ColumnType::Text];
+        let mut bytes = vec![];
+        encode_values(&values, &mut bytes);
+
+        // 途中で切れた値や知らない印は、パニックせずにエラーになる
+        let err = DecodeError::MalformedValue { offset: 9 };
+        assert_eq!(Err(err), decode_values(&bytes[..bytes.len() - 1], &types));
+        let err = DecodeError::MalformedValue { offset: 0 };
+        assert_eq!(Err(err.clone()), decode_values(&bytes[..4], &types));
+        assert_eq!(Err(err.clone()), decode_values(&[0xFF], &types));
+        let mut not_utf8 = vec![];
+        encode_values(&[Value::Bytes(vec![0xFF])], &mut not_utf8);
+        not_utf8[0] = TAG_TEXT;
+        assert_eq!(Err(err), decode_values(&not_utf8, &[ColumnType::Text]));
+
+        // 型や値の数が合わなければエラーになる
+        let err = DecodeError::TypeMismatch {
+            column: 1,
+            expected: ColumnType::Bytes,
+            actual: ColumnType::Text,
+        };
+        assert_eq!(Err(err), decode_values(&bytes, &[ColumnType::Int64, ColumnType::Bytes]));
+        let err = DecodeError::ValueCount {
+            expected: 1,
+            actual: 2,
+        };
+        assert_eq!(Err(err), decode_values(&bytes, &types[..1]));
+        let err = DecodeError::ValueCount {
+            expected: 3,
+            actual: 2,
+        };
+        let types = [ColumnType::Int64, ColumnType::Text, ColumnType::Bool];
+        assert_eq!(Err(err), decode_values(&bytes, &types));
+    }
+}
@@ -0,0 +1,54 @@
+// グローバルアロケータを差し替えるので、ライブラリのテストとは別のバイナリにする
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use rdbms::tuple;
+
+// このスレッドでのヒープの確保を数える
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (result, ALLOCATIONS.with(Cell::get) - before)
+}
+
+#[test]
+fn test_fields_allocations() {
+    // 8バイトまでの要素だけなら、ヒープを確保せずに読める
+    let elems: [&[u8]; 5] = [b"", b"z", b"Alice", b"Smith", b"12345678"];
+    let mut bytes = vec![];
+    tuple::encode(elems.iter(), &mut bytes);
+    let (total_len, count) = allocations(|| {
+        let mut total_len = 0;
+        for field in tuple::fields(&bytes) {
+            total_len += field.unwrap().len();
+        }
+        total_len
+    });
+    assert_eq!(0, count);
+    assert_eq!(elems.iter().map(|elem| elem.len()).sum::<usize>(), total_len);
+
+    // decode_refが確保するのは外側のVecだけ
+    let (decoded, count) = allocations(|| tuple::decode_ref(&bytes).unwrap());
+    assert_eq!(1, count);
+    assert_eq!(elems.len(), decoded.len());
+}